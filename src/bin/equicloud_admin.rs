@@ -0,0 +1,618 @@
+//! Account administration from the command line, talking to the configured
+//! storage directly instead of through the HTTP admin API.
+//!
+//! Usage:
+//!   cargo run --bin equicloud_admin -- usage <USER_ID>
+//!   cargo run --bin equicloud_admin -- manifest <USER_ID> [--deleted]
+//!   cargo run --bin equicloud_admin -- dump <USER_ID> <FILE>
+//!   cargo run --bin equicloud_admin -- restore <USER_ID> <FILE>
+//!   cargo run --bin equicloud_admin -- delete <USER_ID> --yes
+//!   cargo run --bin equicloud_admin -- purge-users [--inactive-before <DATE>] [--smaller-than <SIZE>] [--larger-than <SIZE>] [--yes]
+//!   cargo run --bin equicloud_admin -- quota <USER_ID> [--set <SIZE> | --clear]
+//!   cargo run --bin equicloud_admin -- verify <USER_ID>
+//!   cargo run --bin equicloud_admin -- scrub [--repair] [--json]
+//!   cargo run --bin equicloud_admin -- backup
+//!   cargo run --bin equicloud_admin -- restore-backup <NAME>
+//!
+//! Dumps use the `/v2/export` archive format, so a dump can also be restored
+//! by the user through `/v2/import` and vice versa. Backups cover every user
+//! and are read from and written to `BACKUP_TARGET`.
+
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use dotenv::dotenv;
+use std::path::PathBuf;
+use tar::Builder;
+use tracing::info;
+
+use equicloud::archive::{
+    ArchiveDataEntry, ArchiveManifest, ArchiveSettingsEntry, MANIFEST_PATH, SETTINGS_PATH,
+    append_file, data_path, read_archive,
+};
+use equicloud::audit::{self, AuditAction, AuditActor, AuditEntry};
+use equicloud::backup;
+use equicloud::bulk_purge::{self, PurgeCriteria};
+use equicloud::config::parse_byte_size;
+use equicloud::database::find_corruption;
+use equicloud::scrub::{self, ScrubProblem};
+use equicloud::tenant::DEFAULT_TENANT;
+use equicloud::utils::{CONFIG, hash_user_id};
+use equicloud::{
+    DataUpload, DatabaseService, Datastore, SqliteDatastore, Storage, compute_checksum,
+    connect_with_retry,
+};
+
+#[derive(Parser)]
+#[command(about = "Inspect and manage EquiCloud accounts")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show a user's storage usage and quota.
+    Usage { user_id: String },
+    /// List a user's data keys.
+    Manifest {
+        user_id: String,
+        /// Include tombstones of deleted keys.
+        #[arg(long)]
+        deleted: bool,
+    },
+    /// Write a user's settings and data to a tar archive.
+    Dump { user_id: String, file: PathBuf },
+    /// Write the contents of a tar archive back to a user's account.
+    Restore { user_id: String, file: PathBuf },
+    /// Delete a user's settings, data and tokens.
+    Delete {
+        user_id: String,
+        /// Confirm the deletion.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Delete every user matching all of the given criteria. Without
+    /// `--yes`, only list them.
+    PurgeUsers {
+        /// Users that last wrote before this date, e.g. `2025-01-01`.
+        #[arg(long, value_parser = parse_date)]
+        inactive_before: Option<i64>,
+        /// Users storing less than this, e.g. `1KB`.
+        #[arg(long, value_parser = parse_size)]
+        smaller_than: Option<i64>,
+        /// Users storing more than this, e.g. `100MB`.
+        #[arg(long, value_parser = parse_size)]
+        larger_than: Option<i64>,
+        /// Delete the listed users.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Show or change a user's storage quota.
+    Quota {
+        user_id: String,
+        /// New quota, e.g. `100MB`.
+        #[arg(long, value_parser = parse_size, conflicts_with = "clear")]
+        set: Option<i64>,
+        /// Go back to the default quota.
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Recompute the checksum of every stored value and report mismatches.
+    Verify { user_id: String },
+    /// Check every user's stored values against their checksums and recorded
+    /// sizes, and report what does not match.
+    Scrub {
+        /// Correct recorded sizes that do not match the stored value.
+        #[arg(long)]
+        repair: bool,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Back up every user to the configured backup target now.
+    Backup,
+    /// Write every user in a backup back to storage.
+    RestoreBackup {
+        /// File or object name of the backup, e.g.
+        /// `default-20260101T000000Z.tar.zst.enc`.
+        name: String,
+    },
+}
+
+fn parse_size(s: &str) -> Result<i64, String> {
+    parse_byte_size(s)
+        .and_then(|size| i64::try_from(size).ok())
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("invalid size {:?}", s))
+}
+
+/// A date or RFC 3339 time, in milliseconds. A bare date is midnight UTC.
+fn parse_date(s: &str) -> Result<i64, String> {
+    if let Ok(at) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(at.timestamp_millis());
+    }
+    chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|at| at.and_utc().timestamp_millis())
+        .ok_or_else(|| format!("invalid date {:?}, expected YYYY-MM-DD", s))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("warn")),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    let cli = Cli::parse();
+    let db = open_storage().await?;
+
+    match cli.command {
+        Command::Usage { user_id } => usage(&db, &user_id).await,
+        Command::Manifest { user_id, deleted } => manifest(&db, &user_id, deleted).await,
+        Command::Dump { user_id, file } => dump(&db, &user_id, &file).await,
+        Command::Restore { user_id, file } => restore(&db, &user_id, &file).await,
+        Command::Delete { user_id, yes } => delete(&db, &user_id, yes).await,
+        Command::PurgeUsers {
+            inactive_before,
+            smaller_than,
+            larger_than,
+            yes,
+        } => {
+            let criteria = PurgeCriteria {
+                inactive_before,
+                smaller_than,
+                larger_than,
+            };
+            purge_users(&db, &criteria, yes).await
+        }
+        Command::Quota {
+            user_id,
+            set,
+            clear,
+        } => quota(&db, &user_id, set, clear).await,
+        Command::Verify { user_id } => verify(&db, &user_id).await,
+        Command::Scrub { repair, json } => scrub(&db, repair, json).await,
+        Command::Backup => backup(&db).await,
+        Command::RestoreBackup { name } => restore_backup(&db, &name).await,
+    }
+}
+
+/// Opens the default tenant's storage. Unlike the server, this never
+/// creates the keyspace or runs migrations.
+async fn open_storage() -> Result<Storage> {
+    let config = CONFIG.load();
+    if config.storage_backend == "sqlite" {
+        let store = SqliteDatastore::open_with_config(&config.sqlite_path, CONFIG.clone())
+            .with_context(|| format!("Failed to open {}", config.sqlite_path))?;
+        return Ok(Storage::Sqlite(store));
+    }
+
+    info!("Connecting to database...");
+    let session = connect_with_retry(&config)
+        .await
+        .context("Failed to connect to database")?;
+    let service = DatabaseService::with_config(session, CONFIG.clone())
+        .await
+        .context("Failed to prepare statements")?;
+    Ok(Storage::Scylla(service))
+}
+
+async fn usage(db: &Storage, user_id: &str) -> Result<()> {
+    let usage = db.get_storage_usage(user_id).await?;
+    let quota = db.storage_quota(user_id).await?;
+    let quota_override = db.get_quota_override(user_id).await?;
+
+    println!("user:      {}", user_id);
+    match (usage.settings_size, usage.settings_updated_at) {
+        (Some(size), Some(updated_at)) => {
+            println!(
+                "settings:  {} bytes, updated {}",
+                size,
+                format_ms(updated_at)
+            )
+        }
+        _ => println!("settings:  none"),
+    }
+    println!(
+        "data:      {} keys, {} bytes",
+        usage.data_keys, usage.data_size
+    );
+    println!(
+        "quota:     {} bytes ({})",
+        quota,
+        if quota_override.is_some() {
+            "override"
+        } else {
+            "default"
+        }
+    );
+    Ok(())
+}
+
+async fn manifest(db: &Storage, user_id: &str, include_deleted: bool) -> Result<()> {
+    let mut entries = db.get_data_manifest(user_id).await?;
+    entries.retain(|e| include_deleted || !e.deleted);
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+    for entry in &entries {
+        println!(
+            "{}\tv{}\t{}\t{}\t{}{}",
+            entry.key,
+            entry.version,
+            entry.size_bytes,
+            entry.checksum,
+            format_ms(entry.updated_at),
+            if entry.deleted { "\tdeleted" } else { "" }
+        );
+    }
+    eprintln!("{} keys", entries.len());
+    Ok(())
+}
+
+async fn dump(db: &Storage, user_id: &str, file: &PathBuf) -> Result<()> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut builder = Builder::new(Vec::new());
+    let mut manifest = ArchiveManifest::new(now);
+
+    if let Some((settings, written)) = db.get_user_settings(user_id).await? {
+        let updated_at = written.parse().unwrap_or(0);
+        append_file(&mut builder, SETTINGS_PATH, &settings, updated_at)?;
+        manifest.settings = Some(ArchiveSettingsEntry {
+            checksum: compute_checksum(&settings),
+            size_bytes: settings.len() as i64,
+            updated_at,
+        });
+    }
+
+    for meta in db.get_data_manifest(user_id).await? {
+        if meta.deleted {
+            continue;
+        }
+        let Some(entry) = db
+            .get_data_key(user_id, &meta.key)
+            .await
+            .with_context(|| format!("Failed to read {}", meta.key))?
+        else {
+            continue;
+        };
+        append_file(
+            &mut builder,
+            &data_path(&entry.key),
+            &entry.value,
+            entry.updated_at,
+        )?;
+        manifest.entries.push(ArchiveDataEntry {
+            key: entry.key,
+            version: entry.version,
+            checksum: entry.checksum,
+            size_bytes: entry.size_bytes,
+            updated_at: entry.updated_at,
+            expires_at: None,
+        });
+    }
+
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    append_file(&mut builder, MANIFEST_PATH, &manifest_json, now)?;
+    let archive = builder.into_inner()?;
+    std::fs::write(file, &archive)
+        .with_context(|| format!("Failed to write {}", file.display()))?;
+
+    println!(
+        "Wrote {} ({} keys{}, {} bytes)",
+        file.display(),
+        manifest.entries.len(),
+        if manifest.settings.is_some() {
+            " and settings"
+        } else {
+            ""
+        },
+        archive.len()
+    );
+    Ok(())
+}
+
+/// Restores an archive without the quota and size checks of `/v2/import`;
+/// an administrator may put back more than the user could upload.
+async fn restore(db: &Storage, user_id: &str, file: &PathBuf) -> Result<()> {
+    let bytes =
+        std::fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let staged = read_archive(&bytes).map_err(|e| anyhow::anyhow!(e.message()))?;
+
+    let uploads: Vec<DataUpload> = staged
+        .entries
+        .into_iter()
+        .map(|(entry, value)| DataUpload {
+            key: entry.key,
+            value,
+            checksum: entry.checksum,
+            ttl_secs: None,
+        })
+        .collect();
+
+    let saved = db.save_data_keys_batch(user_id, uploads).await?;
+
+    let settings_restored = match staged.settings {
+        Some(settings) => {
+            db.save_user_settings(user_id, settings).await?;
+            true
+        }
+        None => false,
+    };
+
+    println!(
+        "Restored {} keys{}",
+        saved.len(),
+        if settings_restored {
+            " and settings"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+async fn delete(db: &Storage, user_id: &str, confirmed: bool) -> Result<()> {
+    if !confirmed {
+        bail!("Deleting an account cannot be undone; pass --yes to confirm");
+    }
+
+    let result = db.purge_account(user_id).await;
+    record_audit(
+        db,
+        user_id,
+        AuditAction::AdminDeleteUser,
+        "delete",
+        None,
+        result.is_ok(),
+    )
+    .await;
+    let purged = result?;
+
+    println!(
+        "Deleted user {}: settings {}, {} data keys",
+        user_id,
+        if purged.settings { "removed" } else { "none" },
+        purged.data_keys
+    );
+    Ok(())
+}
+
+async fn purge_users(db: &Storage, criteria: &PurgeCriteria, confirmed: bool) -> Result<()> {
+    if criteria.is_empty() {
+        bail!("Give at least one of --inactive-before, --smaller-than or --larger-than");
+    }
+
+    let report = bulk_purge::run(db, &CONFIG.load(), criteria, !confirmed, |user_hash, ok| {
+        audit_entry(
+            Some(user_hash.to_string()),
+            AuditAction::AdminBulkPurge,
+            "purge-users",
+            None,
+            ok,
+        )
+    })
+    .await?;
+
+    for user in &report.users {
+        println!(
+            "{}\t{}\t{}",
+            user.user,
+            user.size_bytes,
+            if user.last_active > 0 {
+                format_ms(user.last_active)
+            } else {
+                "never".to_string()
+            }
+        );
+    }
+    println!("{} users, {} bytes", report.matched, report.size_bytes);
+    if report.dry_run {
+        eprintln!("Nothing was deleted; pass --yes to delete these users");
+    } else {
+        println!("Deleted {} users, {} failed", report.purged, report.failed);
+        if report.failed > 0 {
+            bail!(
+                "{} of {} users failed to delete",
+                report.failed,
+                report.matched
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn quota(db: &Storage, user_id: &str, set: Option<i64>, clear: bool) -> Result<()> {
+    if set.is_some() || clear {
+        let result = db.set_quota_override(user_id, set).await;
+        record_audit(
+            db,
+            user_id,
+            AuditAction::AdminSetQuota,
+            "quota",
+            Some(match set {
+                Some(bytes) => bytes.to_string(),
+                None => "default".to_string(),
+            }),
+            result.is_ok(),
+        )
+        .await;
+        result?;
+    }
+
+    let quota = db.storage_quota(user_id).await?;
+    let quota_override = db.get_quota_override(user_id).await?;
+    println!(
+        "Quota for {}: {} bytes ({})",
+        user_id,
+        quota,
+        if quota_override.is_some() {
+            "override"
+        } else {
+            "default"
+        }
+    );
+    Ok(())
+}
+
+/// Reads every live key back and compares its checksum with the one stored
+/// alongside it, recording mismatches like a read would. Fails if any value
+/// is corrupt or unreadable.
+async fn verify(db: &Storage, user_id: &str) -> Result<()> {
+    let mut checked = 0;
+    let mut failed = 0;
+
+    for meta in db.get_data_manifest(user_id).await? {
+        if meta.deleted {
+            continue;
+        }
+        checked += 1;
+        match db.get_data_key(user_id, &meta.key).await {
+            Ok(Some(entry)) => {
+                let Some(corrupt) = find_corruption(&entry) else {
+                    continue;
+                };
+                failed += 1;
+                println!(
+                    "MISMATCH {}: stored {}, computed {}",
+                    corrupt.key, corrupt.stored_checksum, corrupt.computed_checksum
+                );
+                db.record_corruption(user_id, &corrupt).await?;
+            }
+            // Expired or deleted since the manifest was read.
+            Ok(None) => checked -= 1,
+            Err(e) => {
+                failed += 1;
+                println!("UNREADABLE {}: {:#}", meta.key, e);
+            }
+        }
+    }
+
+    println!("Checked {} keys, {} failed", checked, failed);
+    if failed > 0 {
+        bail!("{} of {} keys failed verification", failed, checked);
+    }
+    Ok(())
+}
+
+/// Scrubs every user. Fails if anything was found that was not repaired.
+async fn scrub(db: &Storage, repair: bool, json: bool) -> Result<()> {
+    let report = scrub::scrub(db, repair).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for finding in &report.findings {
+            let problem = match &finding.problem {
+                ScrubProblem::Corrupt {
+                    stored_checksum,
+                    computed_checksum,
+                } => format!(
+                    "MISMATCH stored {}, computed {}",
+                    stored_checksum, computed_checksum
+                ),
+                ScrubProblem::SizeMismatch { recorded, actual } => {
+                    format!("SIZE recorded {}, actual {}", recorded, actual)
+                }
+                ScrubProblem::Unreadable { error } => format!("UNREADABLE {}", error),
+            };
+            println!(
+                "{}\t{}\tv{}\t{}{}",
+                finding.user,
+                finding.key,
+                finding.version,
+                problem,
+                if finding.repaired { " (repaired)" } else { "" }
+            );
+        }
+        println!(
+            "Checked {} values of {} users: {} corrupt, {} unreadable, {} with a wrong size, {} repaired",
+            report.checked,
+            report.users,
+            report.corrupt,
+            report.unreadable,
+            report.size_mismatches,
+            report.repaired
+        );
+        if report.size_mismatches > report.repaired && !repair {
+            eprintln!("Sizes were not corrected; pass --repair to correct them");
+        }
+    }
+    if !report.is_clean() {
+        bail!(
+            "{} problems left unrepaired",
+            report.findings.len() as u64 - report.repaired
+        );
+    }
+    Ok(())
+}
+
+async fn backup(db: &Storage) -> Result<()> {
+    let summary = backup::back_up(db, &CONFIG.load(), DEFAULT_TENANT).await?;
+    println!(
+        "Wrote backup {} ({} users, {} keys, {} bytes)",
+        summary.name, summary.users, summary.keys, summary.size_bytes
+    );
+    Ok(())
+}
+
+/// Restores a backup over the current contents, like `restore` does for a
+/// single user: keys in the backup get a new version, others are kept.
+async fn restore_backup(db: &Storage, name: &str) -> Result<()> {
+    let summary = backup::restore_from(db, &CONFIG.load(), name).await?;
+    println!(
+        "Restored {} keys of {} users from {}",
+        summary.keys, summary.users, name
+    );
+    Ok(())
+}
+
+async fn record_audit(
+    db: &Storage,
+    user_id: &str,
+    action: AuditAction,
+    command: &str,
+    detail: Option<String>,
+    succeeded: bool,
+) {
+    let entry = audit_entry(
+        Some(hash_user_id(user_id)),
+        action,
+        command,
+        detail,
+        succeeded,
+    );
+    audit::record(db, &CONFIG.load(), entry).await;
+}
+
+fn audit_entry(
+    user_hash: Option<String>,
+    action: AuditAction,
+    command: &str,
+    detail: Option<String>,
+    succeeded: bool,
+) -> AuditEntry {
+    AuditEntry {
+        at: chrono::Utc::now().timestamp_millis(),
+        request_id: uuid::Uuid::new_v4().to_string(),
+        actor: AuditActor::Admin.as_str().to_string(),
+        user_hash,
+        action: action.as_str().to_string(),
+        route: format!("equicloud_admin {}", command),
+        client_ip: None,
+        detail,
+        outcome: if succeeded { "success" } else { "failure" }.to_string(),
+    }
+}
+
+fn format_ms(ms: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(ms)
+        .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| ms.to_string())
+}
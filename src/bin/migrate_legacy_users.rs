@@ -2,19 +2,41 @@
 //!
 //! Usage:
 //!   cargo run --bin migrate_legacy_users -- [--dry-run] [--delete-legacy]
+//!       [--page-size <ROWS>] [--progress-every <ROWS>] [--checkpoint <FILE>]
 //!
 //! This tool will:
-//! 1. Scan the database for entries using legacy CRC32 hash format
+//! 1. Scan the users and data tables for entries using legacy CRC32 hash format
 //! 2. Report on any legacy entries found
 //! 3. Optionally delete legacy entries (with --delete-legacy flag)
+//!
+//! Tables are read a page at a time. With `--checkpoint`, the scan position
+//! is saved to the file after every page and an interrupted run started
+//! again with the same file carries on from there.
+//!
+//! A legacy hash cannot be traced back to its user, so entries are moved to
+//! the SHA-256 hash only when their user next reads them.
 
 use dotenv::dotenv;
-use equicloud::constants::DEFAULT_SCYLLA_URI;
-use equicloud::hash_migration::is_legacy_key;
-use scylla::client::session::Session;
+use equicloud::hash_migration::{self, ScanOptions};
+use equicloud::utils::CONFIG;
+use equicloud::{DatabaseService, create_database_connection};
 use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
 use tracing::{error, info};
 
+/// The value following `flag`, exiting if it is missing or invalid.
+fn flag_value<T: FromStr>(args: &[String], flag: &str) -> Option<T> {
+    let index = args.iter().position(|a| a == flag)?;
+    match args.get(index + 1).map(|v| v.parse()) {
+        Some(Ok(value)) => Some(value),
+        _ => {
+            error!("{} needs a valid value", flag);
+            std::process::exit(2);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
@@ -31,13 +53,23 @@ async fn main() {
     let delete_legacy = args.contains(&delete_flag);
     let dry_run = !delete_legacy;
 
+    let defaults = ScanOptions::default();
+    let options = ScanOptions {
+        delete: delete_legacy,
+        page_size: flag_value::<i32>(&args, "--page-size")
+            .filter(|size| *size > 0)
+            .unwrap_or(defaults.page_size),
+        progress_every: flag_value(&args, "--progress-every").unwrap_or(defaults.progress_every),
+        checkpoint: flag_value::<PathBuf>(&args, "--checkpoint"),
+    };
+
     if dry_run {
         info!("Running in DRY-RUN mode - no data will be deleted");
         info!("Use --delete-legacy flag to actually delete legacy entries");
     }
 
     info!("Connecting to database...");
-    let session = match create_database_connection().await {
+    let session = match create_database_connection(&CONFIG.load()).await {
         Ok(session) => session,
         Err(e) => {
             error!("Failed to connect to database: {}", e);
@@ -47,108 +79,47 @@ async fn main() {
 
     info!("Connected to database");
 
-    match session.use_keyspace("equicloud", false).await {
-        Ok(_) => info!("Using keyspace: equicloud"),
-        Err(e) => {
-            error!("Failed to use keyspace: {}", e);
-            std::process::exit(1);
-        }
-    }
-
-    let query = "SELECT id FROM users";
-    let result = match session.query_unpaged(query, &[]).await {
-        Ok(result) => result,
-        Err(e) => {
-            error!("Failed to query users: {}", e);
-            std::process::exit(1);
-        }
-    };
-
-    let rows_result = match result.into_rows_result() {
-        Ok(rows) => rows,
+    let db = match DatabaseService::with_config(session, CONFIG.clone()).await {
+        Ok(db) => db,
         Err(e) => {
-            error!("Failed to get rows: {}", e);
+            error!("Failed to prepare database: {}", e);
             std::process::exit(1);
         }
     };
 
-    let mut total_users = 0;
-    let mut legacy_users = 0;
-    let mut deleted_count = 0;
-
     info!("Scanning for legacy entries...");
 
-    let rows = match rows_result.rows::<(String,)>() {
-        Ok(r) => r,
+    let report = match hash_migration::scan_legacy_users(&db, &options).await {
+        Ok(report) => report,
         Err(e) => {
-            error!("Failed to parse rows: {}", e);
+            error!("Failed to scan for legacy entries: {:#}", e);
+            if let Some(path) = &options.checkpoint {
+                error!("Run again with --checkpoint {} to resume", path.display());
+            }
             std::process::exit(1);
         }
     };
 
-    for row in rows {
-        let (user_id,) = match row {
-            Ok(id) => id,
-            Err(e) => {
-                error!("Failed to parse row: {}", e);
-                continue;
-            }
-        };
-
-        total_users += 1;
-
-        if is_legacy_key(&user_id) {
-            legacy_users += 1;
-            info!("Found legacy entry: {}", user_id);
-
-            if delete_legacy {
-                info!("Deleting legacy entry: {}", user_id);
-                let delete_query = "DELETE FROM users WHERE id = ?";
-                match session.query_unpaged(delete_query, (&user_id,)).await {
-                    Ok(_) => {
-                        deleted_count += 1;
-                        info!("Deleted: {}", user_id);
-                    }
-                    Err(e) => {
-                        error!("Failed to delete {}: {}", user_id, e);
-                    }
-                }
-            }
-        }
-    }
-
     info!("Scan complete!");
-    info!("Total entries: {}", total_users);
-    info!("Legacy entries found: {}", legacy_users);
+    info!("Total entries: {}", report.total);
+    info!("Legacy entries found: {}", report.legacy);
+    info!("Users with data: {}", report.data_users);
+    info!("Legacy data found for: {}", report.legacy_data_users);
 
     if delete_legacy {
-        info!("Legacy entries deleted: {}", deleted_count);
+        info!("Legacy entries deleted: {}", report.deleted);
+        info!("Legacy data deleted for: {}", report.deleted_data_users);
     } else {
         info!("Run with --delete-legacy to remove these entries");
     }
 
-    if legacy_users > 0 && !delete_legacy {
+    if (report.legacy > 0 || report.legacy_data_users > 0) && !delete_legacy {
         info!("\nLegacy entries detected. These entries use the old CRC32 hash format.");
-        info!("They will be automatically migrated when users next access their settings.");
+        info!("They will be automatically migrated when users next access their settings or data.");
         info!(
             "To clean up orphaned entries now, run: cargo run --bin migrate_legacy_users -- --delete-legacy"
         );
-    } else if legacy_users == 0 {
+    } else if report.legacy == 0 && report.legacy_data_users == 0 {
         info!("No legacy entries found - migration complete!");
     }
 }
-
-async fn create_database_connection() -> anyhow::Result<Session> {
-    let uri = env::var("SCYLLA_URI").unwrap_or_else(|_| DEFAULT_SCYLLA_URI.to_string());
-    let username = env::var("SCYLLA_USERNAME").ok();
-    let password = env::var("SCYLLA_PASSWORD").ok();
-
-    let session_builder = scylla::client::session_builder::SessionBuilder::new().known_node(uri);
-
-    let session = if let (Some(user), Some(pass)) = (username, password) {
-        session_builder.user(user, pass).build().await?
-    } else {
-        session_builder.build().await?
-    };
-    Ok(session)
-}
@@ -0,0 +1,424 @@
+//! The gRPC API, served on `GRPC_PORT` next to the REST API. It covers the
+//! settings backup, single data keys and a bidirectional sync stream, over
+//! the same storage and token auth; the messages are defined in
+//! `proto/equicloud.proto`.
+//!
+//! Calls are routed by axum, so the REST extractors authenticate them from
+//! the request metadata, and tonic only frames and encodes the messages.
+
+pub mod proto;
+
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::HeaderMap;
+use axum::http::request::Parts;
+use axum::response::Response;
+use axum::routing::post;
+use futures::stream;
+use std::sync::Arc;
+use tonic::server::Grpc;
+use tonic::{Code, Status, Streaming};
+use tower::service_fn;
+
+use equicloud::abuse::{self, Violation};
+use equicloud::error::{AppError, ResultExt};
+use equicloud::integrity::{self, ChecksumStatus};
+use equicloud::key_limit;
+use equicloud::scopes::{Scope, TokenScopes};
+use equicloud::settings::SettingsService;
+use equicloud::write_budget::WriteBudget;
+use equicloud::{Datastore, Event, EventBus, Metrics, Tenant};
+
+use self::proto::{Message, ProtoCodec, conflict_policy};
+use crate::middleware::audit::AuditContext;
+use crate::middleware::auth::ScopedUser;
+use crate::middleware::tenant::CurrentTenant;
+use crate::routes::v2::data::verify_checksum;
+use crate::routes::v2::dto::{self, ConflictPolicy};
+use crate::routes::v2::sync as rest_sync;
+use crate::state::AppState;
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/equicloud.v1.Equicloud/GetSettings", post(get_settings))
+        .route("/equicloud.v1.Equicloud/PutSettings", post(put_settings))
+        .route("/equicloud.v1.Equicloud/GetData", post(get_data))
+        .route("/equicloud.v1.Equicloud/PutData", post(put_data))
+        .route("/equicloud.v1.Equicloud/Sync", post(sync))
+        .fallback(|| async { Status::unimplemented("Unknown method").into_http::<Body>() })
+        .with_state(state)
+}
+
+fn status(e: AppError) -> Status {
+    let code = match &e {
+        AppError::BadRequest(_)
+        | AppError::InvalidKey(_)
+        | AppError::InvalidSettings(_)
+        | AppError::UnsupportedMediaType(_) => Code::InvalidArgument,
+        AppError::ChecksumMismatch => Code::DataLoss,
+        AppError::Unauthorized(_) => Code::Unauthenticated,
+        AppError::Forbidden(_)
+        | AppError::DatastoreDisabled
+        | AppError::NamespaceDisabled(_)
+        | AppError::Banned { .. } => Code::PermissionDenied,
+        AppError::NotFound | AppError::Gone(_) | AppError::Deleted { .. } => Code::NotFound,
+        AppError::Conflict(_) | AppError::VersionConflict { .. } => Code::Aborted,
+        AppError::PreconditionFailed => Code::FailedPrecondition,
+        AppError::PayloadTooLarge(_)
+        | AppError::QuotaExceeded
+        | AppError::KeyLimitExceeded { .. }
+        | AppError::WriteLimitExceeded { .. }
+        | AppError::LockedOut { .. } => Code::ResourceExhausted,
+        AppError::Upstream(_)
+        | AppError::ProviderUnavailable { .. }
+        | AppError::DatabaseUnavailable => Code::Unavailable,
+        AppError::Timeout => Code::DeadlineExceeded,
+        AppError::Internal(_) => Code::Internal,
+    };
+    Status::new(code, e.message())
+}
+
+/// The user a call is made for, authenticated like a REST request.
+#[derive(Clone)]
+struct Caller {
+    tenant: Arc<Tenant>,
+    user_id: String,
+    /// Checked by each call, as REST handlers taking `ScopedUser` do.
+    scopes: TokenScopes,
+    events: EventBus,
+    metrics: Arc<Metrics>,
+    audit: AuditContext,
+}
+
+impl Caller {
+    async fn authenticate(state: &AppState, parts: &mut Parts) -> Result<Self, AppError> {
+        let CurrentTenant(tenant) = CurrentTenant::from_request_parts(parts, state).await?;
+        let ScopedUser(user_id, scopes) = ScopedUser::from_request_parts(parts, state).await?;
+        let Ok(audit) = AuditContext::from_request_parts(parts, state).await;
+        Ok(Self {
+            tenant,
+            user_id,
+            scopes,
+            events: state.events.clone(),
+            metrics: state.metrics.clone(),
+            audit,
+        })
+    }
+}
+
+/// Authenticates a unary call and answers it with `handle`.
+async fn unary<Req, Res, F, Fut>(state: AppState, request: Request, handle: F) -> Response
+where
+    Req: Message,
+    Res: Message,
+    F: Fn(Caller, Req) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<Res, AppError>> + Send,
+{
+    let (mut parts, body) = request.into_parts();
+    let caller = match Caller::authenticate(&state, &mut parts).await {
+        Ok(caller) => caller,
+        Err(e) => return status(e).into_http(),
+    };
+    let service = service_fn(move |request: tonic::Request<Req>| {
+        let (caller, handle) = (caller.clone(), handle.clone());
+        async move {
+            handle(caller, request.into_inner())
+                .await
+                .map(tonic::Response::new)
+                .map_err(status)
+        }
+    });
+    Grpc::new(ProtoCodec::<Res, Req>::default())
+        .unary(service, Request::from_parts(parts, body))
+        .await
+        .map(Body::new)
+}
+
+async fn get_settings(State(state): State<AppState>, request: Request) -> Response {
+    unary(
+        state,
+        request,
+        |caller: Caller, _: proto::GetSettingsRequest| async move {
+            caller.scopes.require_account(Scope::Read)?;
+            let settings = SettingsService::new(&caller.tenant)
+                .get(&caller.user_id)
+                .await?;
+            Ok(proto::Settings {
+                value: settings.value,
+                written: settings.written,
+            })
+        },
+    )
+    .await
+}
+
+async fn put_settings(State(state): State<AppState>, request: Request) -> Response {
+    unary(
+        state,
+        request,
+        |caller: Caller, request: proto::PutSettingsRequest| async move {
+            caller.scopes.require_account(Scope::Write)?;
+            let written = SettingsService::new(&caller.tenant)
+                .save(
+                    &caller.events,
+                    &caller.user_id,
+                    request.value,
+                    &HeaderMap::new(),
+                )
+                .await?;
+            Ok(proto::SettingsWritten { written })
+        },
+    )
+    .await
+}
+
+async fn get_data(State(state): State<AppState>, request: Request) -> Response {
+    unary(
+        state,
+        request,
+        |caller: Caller, request: proto::GetDataRequest| async move {
+            let config = caller.tenant.config.load();
+            config.namespaces.check_key(&request.key)?;
+            caller.scopes.require_key(Scope::Read, &request.key)?;
+
+            let db = &caller.tenant.db;
+            let entry = db
+                .get_data_key(&caller.user_id, &request.key)
+                .await
+                .or_internal("Failed to get data")?
+                .ok_or(AppError::NotFound)?;
+
+            // As over REST, a corrupt value is still served, flagged.
+            let checksum_status =
+                integrity::verify(db, &caller.metrics, &caller.user_id, &entry).await;
+
+            Ok(proto::DataEntry {
+                key: entry.key,
+                value: entry.value,
+                version: entry.version,
+                checksum: entry.checksum,
+                updated_at: entry.updated_at,
+                checksum_verified: checksum_status == ChecksumStatus::Verified,
+            })
+        },
+    )
+    .await
+}
+
+async fn put_data(State(state): State<AppState>, request: Request) -> Response {
+    unary(
+        state,
+        request,
+        |caller: Caller, request: proto::PutDataRequest| async move {
+            let Caller {
+                tenant,
+                user_id,
+                scopes,
+                events,
+                ..
+            } = caller;
+            let proto::PutDataRequest {
+                key,
+                value,
+                checksum,
+                ttl_secs,
+            } = request;
+            let config = tenant.config.load();
+            config.namespaces.check_key(&key)?;
+            scopes.require_key(Scope::Write, &key)?;
+            let ttl_secs = config
+                .namespaces
+                .resolve_ttl(&key, (ttl_secs > 0).then_some(ttl_secs))?;
+            config.namespaces.validate_write(&key, value.len())?;
+
+            let claimed = (!checksum.is_empty()).then_some(checksum.as_str());
+            let checksum = verify_checksum(&tenant, &user_id, claimed, &value).await?;
+            key_limit::check_new_key(&tenant, &user_id, &key).await?;
+
+            WriteBudget::spend(&tenant, &user_id, 1).await?;
+
+            let saved = tenant
+                .db
+                .save_data_key_with_quota_check(&user_id, &key, value, &checksum, ttl_secs)
+                .await
+                .or_internal("Failed to save data")?;
+            let Some((version, updated_at)) = saved else {
+                abuse::report(&tenant, &user_id, Violation::QuotaExceeded).await;
+                events.publish(Event::QuotaExceeded { user_id });
+                return Err(AppError::QuotaExceeded);
+            };
+
+            events.publish(Event::DataWritten {
+                user_id,
+                key,
+                version,
+            });
+
+            Ok(proto::DataWritten {
+                version,
+                checksum,
+                updated_at,
+                expires_at: ttl_secs.map_or(0, |ttl| updated_at + ttl as i64 * 1000),
+            })
+        },
+    )
+    .await
+}
+
+/// Each request on the stream is one sync, answered in order. The stream
+/// ends with the first sync that fails.
+async fn sync(State(state): State<AppState>, request: Request) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let caller = match Caller::authenticate(&state, &mut parts).await {
+        Ok(caller) => caller,
+        Err(e) => return status(e).into_http(),
+    };
+    let service = service_fn(
+        move |request: tonic::Request<Streaming<proto::SyncRequest>>| {
+            let caller = caller.clone();
+            async move {
+                let responses =
+                    stream::unfold(Some((caller, request.into_inner())), |state| async move {
+                        let (caller, mut requests) = state?;
+                        let response = match requests.message().await {
+                            Ok(Some(request)) => sync_once(&caller, request).await.map_err(status),
+                            Ok(None) => return None,
+                            Err(status) => Err(status),
+                        };
+                        let next = response.is_ok().then_some((caller, requests));
+                        Some((response, next))
+                    });
+                Ok::<_, Status>(tonic::Response::new(Box::pin(responses)))
+            }
+        },
+    );
+    Grpc::new(ProtoCodec::<proto::SyncResponse, proto::SyncRequest>::default())
+        .streaming(service, Request::from_parts(parts, body))
+        .await
+        .map(Body::new)
+}
+
+async fn sync_once(
+    caller: &Caller,
+    request: proto::SyncRequest,
+) -> Result<proto::SyncResponse, AppError> {
+    let conflict_policy = match request.conflict_policy {
+        conflict_policy::SERVER_WINS => ConflictPolicy::ServerWins,
+        conflict_policy::CLIENT_WINS => ConflictPolicy::ClientWins,
+        conflict_policy::RECORD_CONFLICT => ConflictPolicy::RecordConflict,
+        _ => return Err(AppError::BadRequest("Unknown conflict policy".into())),
+    };
+    let request = dto::SyncRequest {
+        client_manifest: request
+            .client_manifest
+            .into_iter()
+            .map(|e| dto::ClientManifestEntry {
+                key: e.key,
+                version: e.version,
+                checksum: e.checksum,
+                signature: None,
+            })
+            .collect(),
+        uploads: request
+            .uploads
+            .into_iter()
+            .map(|u| dto::UploadEntry {
+                key: u.key,
+                value: u.value,
+                checksum: (!u.checksum.is_empty()).then_some(u.checksum),
+                ttl: (u.ttl_secs > 0).then_some(u.ttl_secs),
+            })
+            .collect(),
+        deletions: request
+            .deletions
+            .into_iter()
+            .map(|d| dto::ClientDeletionEntry {
+                key: d.key,
+                version: d.version,
+            })
+            .collect(),
+        conflict_policy,
+    };
+
+    rest_sync::check_scopes(&caller.scopes, &request)?;
+    let writes = request.uploads.len() + request.deletions.len();
+    WriteBudget::spend(&caller.tenant, &caller.user_id, writes).await?;
+
+    let (checksum_status, response) = rest_sync::sync(
+        &caller.tenant,
+        &caller.events,
+        &caller.metrics,
+        caller.user_id.clone(),
+        &caller.scopes,
+        &caller.audit,
+        None,
+        request,
+    )
+    .await?;
+
+    Ok(proto::SyncResponse {
+        server_manifest: response
+            .server_manifest
+            .into_iter()
+            .map(|e| proto::ManifestEntry {
+                key: e.key,
+                version: e.version,
+                checksum: e.checksum,
+                size_bytes: e.size_bytes,
+                updated_at: e.updated_at,
+                deleted: e.deleted,
+                deleted_at: e.deleted_at.unwrap_or_default(),
+                expires_at: e.expires_at.unwrap_or_default(),
+            })
+            .collect(),
+        // Without a signature in the manifest, downloads are never patches.
+        downloads: response
+            .downloads
+            .into_iter()
+            .map(|d| proto::Download {
+                key: d.key,
+                value: d.value.unwrap_or_default(),
+                version: d.version,
+                checksum: d.checksum,
+            })
+            .collect(),
+        uploaded: response
+            .uploaded
+            .into_iter()
+            .map(|u| proto::UploadResult {
+                key: u.key,
+                version: u.version,
+                checksum: u.checksum,
+            })
+            .collect(),
+        deleted: response
+            .deleted
+            .into_iter()
+            .map(|d| proto::Deleted {
+                key: d.key,
+                version: d.version,
+                deleted_at: d.deleted_at.unwrap_or_default(),
+            })
+            .collect(),
+        conflicts: response
+            .conflicts
+            .into_iter()
+            .map(|c| proto::Conflict {
+                key: c.key,
+                server_version: c.server_version,
+                server_checksum: c.server_checksum,
+                recorded_as: c.recorded_as.unwrap_or_default(),
+            })
+            .collect(),
+        errors: response
+            .errors
+            .into_iter()
+            .map(|e| proto::SyncError {
+                key: e.key,
+                error: e.error,
+            })
+            .collect(),
+        checksums_verified: checksum_status == ChecksumStatus::Verified,
+    })
+}
@@ -0,0 +1,437 @@
+//! The messages of `proto/equicloud.proto` and their protobuf encoding,
+//! written out by hand for the few field types the API uses: varint
+//! integers and bools, strings, bytes and repeated messages.
+
+use bytes::{Buf, BufMut};
+use std::fmt;
+use std::marker::PhantomData;
+use tonic::Status;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LEN: u64 = 2;
+const FIXED32: u64 = 5;
+
+#[derive(Debug)]
+pub struct DecodeError(&'static str);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// A field as read off the wire.
+pub enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn varint(self) -> Result<u64, DecodeError> {
+        match self {
+            Value::Varint(v) => Ok(v),
+            Value::Bytes(_) => Err(DecodeError("expected a varint field")),
+        }
+    }
+
+    fn bytes(self) -> Result<&'a [u8], DecodeError> {
+        match self {
+            Value::Bytes(v) => Ok(v),
+            Value::Varint(_) => Err(DecodeError("expected a length-delimited field")),
+        }
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or(DecodeError("truncated varint"))?;
+        *buf = rest;
+        v |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Ok(v);
+        }
+    }
+    Err(DecodeError("varint is too long"))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if buf.len() < len {
+        return Err(DecodeError("truncated field"));
+    }
+    let (v, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(v)
+}
+
+fn put_key(buf: &mut Vec<u8>, tag: u32, wire_type: u64) {
+    put_varint(buf, u64::from(tag) << 3 | wire_type);
+}
+
+fn put_len(buf: &mut Vec<u8>, tag: u32, v: &[u8]) {
+    put_key(buf, tag, LEN);
+    put_varint(buf, v.len() as u64);
+    buf.extend_from_slice(v);
+}
+
+/// A protobuf message.
+pub trait Message: Default + Send + 'static {
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Reads one field into the message. Unknown fields are ignored.
+    fn merge(&mut self, tag: u32, value: Value<'_>) -> Result<(), DecodeError>;
+
+    fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut message = Self::default();
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf)?;
+            let tag = u32::try_from(key >> 3)
+                .ok()
+                .filter(|&tag| tag != 0)
+                .ok_or(DecodeError("invalid field number"))?;
+            let value = match key & 7 {
+                VARINT => Value::Varint(read_varint(&mut buf)?),
+                LEN => {
+                    let len = read_varint(&mut buf)? as usize;
+                    Value::Bytes(take(&mut buf, len)?)
+                }
+                FIXED64 => {
+                    take(&mut buf, 8)?;
+                    continue;
+                }
+                FIXED32 => {
+                    take(&mut buf, 4)?;
+                    continue;
+                }
+                _ => return Err(DecodeError("unsupported wire type")),
+            };
+            message.merge(tag, value)?;
+        }
+        Ok(message)
+    }
+}
+
+/// A field of a message. Proto3 leaves out scalars at their default value.
+pub trait Field {
+    fn put(&self, buf: &mut Vec<u8>, tag: u32);
+    fn merge(&mut self, value: Value<'_>) -> Result<(), DecodeError>;
+}
+
+impl Field for i64 {
+    fn put(&self, buf: &mut Vec<u8>, tag: u32) {
+        if *self != 0 {
+            put_key(buf, tag, VARINT);
+            put_varint(buf, *self as u64);
+        }
+    }
+
+    fn merge(&mut self, value: Value<'_>) -> Result<(), DecodeError> {
+        *self = value.varint()? as i64;
+        Ok(())
+    }
+}
+
+impl Field for u64 {
+    fn put(&self, buf: &mut Vec<u8>, tag: u32) {
+        if *self != 0 {
+            put_key(buf, tag, VARINT);
+            put_varint(buf, *self);
+        }
+    }
+
+    fn merge(&mut self, value: Value<'_>) -> Result<(), DecodeError> {
+        *self = value.varint()?;
+        Ok(())
+    }
+}
+
+/// Also used for enums, which are int32 on the wire.
+impl Field for i32 {
+    fn put(&self, buf: &mut Vec<u8>, tag: u32) {
+        if *self != 0 {
+            put_key(buf, tag, VARINT);
+            put_varint(buf, i64::from(*self) as u64);
+        }
+    }
+
+    fn merge(&mut self, value: Value<'_>) -> Result<(), DecodeError> {
+        *self = value.varint()? as i32;
+        Ok(())
+    }
+}
+
+impl Field for bool {
+    fn put(&self, buf: &mut Vec<u8>, tag: u32) {
+        if *self {
+            put_key(buf, tag, VARINT);
+            buf.push(1);
+        }
+    }
+
+    fn merge(&mut self, value: Value<'_>) -> Result<(), DecodeError> {
+        *self = value.varint()? != 0;
+        Ok(())
+    }
+}
+
+impl Field for String {
+    fn put(&self, buf: &mut Vec<u8>, tag: u32) {
+        if !self.is_empty() {
+            put_len(buf, tag, self.as_bytes());
+        }
+    }
+
+    fn merge(&mut self, value: Value<'_>) -> Result<(), DecodeError> {
+        *self = std::str::from_utf8(value.bytes()?)
+            .map_err(|_| DecodeError("string field is not UTF-8"))?
+            .to_string();
+        Ok(())
+    }
+}
+
+impl Field for Vec<u8> {
+    fn put(&self, buf: &mut Vec<u8>, tag: u32) {
+        if !self.is_empty() {
+            put_len(buf, tag, self);
+        }
+    }
+
+    fn merge(&mut self, value: Value<'_>) -> Result<(), DecodeError> {
+        *self = value.bytes()?.to_vec();
+        Ok(())
+    }
+}
+
+/// A repeated message field; every element is written, even empty ones.
+impl<M: Message> Field for Vec<M> {
+    fn put(&self, buf: &mut Vec<u8>, tag: u32) {
+        for message in self {
+            put_len(buf, tag, &message.encode_to_vec());
+        }
+    }
+
+    fn merge(&mut self, value: Value<'_>) -> Result<(), DecodeError> {
+        self.push(M::decode(value.bytes()?)?);
+        Ok(())
+    }
+}
+
+macro_rules! messages {
+    ($(
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $($(#[$field_meta:meta])* pub $field:ident: $ty:ty = $tag:literal,)*
+        }
+    )*) => {$(
+        $(#[$meta])*
+        #[derive(Debug, Clone, Default, PartialEq)]
+        pub struct $name {
+            $($(#[$field_meta])* pub $field: $ty,)*
+        }
+
+        impl Message for $name {
+            fn encode(&self, _buf: &mut Vec<u8>) {
+                $(Field::put(&self.$field, _buf, $tag);)*
+            }
+
+            fn merge(&mut self, tag: u32, _value: Value<'_>) -> Result<(), DecodeError> {
+                match tag {
+                    $($tag => Field::merge(&mut self.$field, _value),)*
+                    _ => Ok(()),
+                }
+            }
+        }
+    )*};
+}
+
+messages! {
+    pub struct GetSettingsRequest {}
+
+    pub struct Settings {
+        pub value: Vec<u8> = 1,
+        /// When the settings were written, in milliseconds since the epoch.
+        pub written: i64 = 2,
+    }
+
+    pub struct PutSettingsRequest {
+        pub value: Vec<u8> = 1,
+    }
+
+    pub struct SettingsWritten {
+        pub written: i64 = 1,
+    }
+
+    pub struct GetDataRequest {
+        pub key: String = 1,
+    }
+
+    pub struct DataEntry {
+        pub key: String = 1,
+        pub value: Vec<u8> = 2,
+        pub version: i64 = 3,
+        pub checksum: String = 4,
+        pub updated_at: i64 = 5,
+        pub checksum_verified: bool = 6,
+    }
+
+    pub struct PutDataRequest {
+        pub key: String = 1,
+        pub value: Vec<u8> = 2,
+        /// Empty to have the server compute one.
+        pub checksum: String = 3,
+        /// 0 for the key's default.
+        pub ttl_secs: u64 = 4,
+    }
+
+    pub struct DataWritten {
+        pub version: i64 = 1,
+        pub checksum: String = 2,
+        pub updated_at: i64 = 3,
+        /// 0 when the value does not expire.
+        pub expires_at: i64 = 4,
+    }
+
+    pub struct SyncRequest {
+        pub client_manifest: Vec<ClientManifestEntry> = 1,
+        pub uploads: Vec<PutDataRequest> = 2,
+        pub deletions: Vec<Deletion> = 3,
+        /// A `ConflictPolicy`.
+        pub conflict_policy: i32 = 4,
+    }
+
+    pub struct ClientManifestEntry {
+        pub key: String = 1,
+        pub version: i64 = 2,
+        pub checksum: String = 3,
+    }
+
+    pub struct Deletion {
+        pub key: String = 1,
+        pub version: i64 = 2,
+    }
+
+    pub struct SyncResponse {
+        pub server_manifest: Vec<ManifestEntry> = 1,
+        pub downloads: Vec<Download> = 2,
+        pub uploaded: Vec<UploadResult> = 3,
+        pub deleted: Vec<Deleted> = 4,
+        pub conflicts: Vec<Conflict> = 5,
+        pub errors: Vec<SyncError> = 6,
+        pub checksums_verified: bool = 7,
+    }
+
+    pub struct ManifestEntry {
+        pub key: String = 1,
+        pub version: i64 = 2,
+        pub checksum: String = 3,
+        pub size_bytes: i32 = 4,
+        pub updated_at: i64 = 5,
+        pub deleted: bool = 6,
+        pub deleted_at: i64 = 7,
+        pub expires_at: i64 = 8,
+    }
+
+    pub struct Download {
+        pub key: String = 1,
+        pub value: Vec<u8> = 2,
+        pub version: i64 = 3,
+        pub checksum: String = 4,
+    }
+
+    pub struct UploadResult {
+        pub key: String = 1,
+        pub version: i64 = 2,
+        pub checksum: String = 3,
+    }
+
+    pub struct Deleted {
+        pub key: String = 1,
+        pub version: i64 = 2,
+        pub deleted_at: i64 = 3,
+    }
+
+    pub struct Conflict {
+        pub key: String = 1,
+        pub server_version: i64 = 2,
+        pub server_checksum: String = 3,
+        pub recorded_as: String = 4,
+    }
+
+    pub struct SyncError {
+        pub key: String = 1,
+        pub error: String = 2,
+    }
+}
+
+/// Values of `SyncRequest::conflict_policy`.
+pub mod conflict_policy {
+    pub const SERVER_WINS: i32 = 0;
+    pub const CLIENT_WINS: i32 = 1;
+    pub const RECORD_CONFLICT: i32 = 2;
+}
+
+/// Encodes `E` and decodes `D` for tonic.
+pub struct ProtoCodec<E, D>(PhantomData<fn(E) -> D>);
+
+impl<E, D> Default for ProtoCodec<E, D> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<E: Message, D: Message> Codec for ProtoCodec<E, D> {
+    type Encode = E;
+    type Decode = D;
+    type Encoder = ProtoEncoder<E>;
+    type Decoder = ProtoDecoder<D>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        ProtoEncoder(PhantomData)
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        ProtoDecoder(PhantomData)
+    }
+}
+
+pub struct ProtoEncoder<E>(PhantomData<fn(E)>);
+
+impl<E: Message> Encoder for ProtoEncoder<E> {
+    type Item = E;
+    type Error = Status;
+
+    fn encode(&mut self, item: E, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        dst.put_slice(&item.encode_to_vec());
+        Ok(())
+    }
+}
+
+pub struct ProtoDecoder<D>(PhantomData<fn() -> D>);
+
+impl<D: Message> Decoder for ProtoDecoder<D> {
+    type Item = D;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<D>, Status> {
+        let buf = src.copy_to_bytes(src.remaining());
+        D::decode(&buf)
+            .map(Some)
+            .map_err(|e| Status::invalid_argument(format!("Malformed message: {}", e)))
+    }
+}
@@ -0,0 +1,156 @@
+//! Ban list and automatic temporary bans.
+//!
+//! Admins ban users by hashed id through `/admin/bans`; a banned user gets
+//! 403 on every authenticated request. Users whose requests keep getting
+//! refused for the same few reasons, such as values that do not match the
+//! checksum they were sent with or writes past their quota, are banned for
+//! `ABUSE_BAN_DURATION` once they reach `ABUSE_MAX_VIOLATIONS` within
+//! `ABUSE_WINDOW`. Violations are counted in memory, per instance.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::database::Ban;
+use crate::datastore::{Datastore, Storage};
+use crate::error::{AppError, ResultExt};
+use crate::tenant::Tenant;
+use crate::utils::hash_user_id;
+
+/// A refused request that counts towards an automatic ban.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A value did not match the checksum the client sent with it.
+    ChecksumMismatch,
+    /// A write was refused because the user's storage is full.
+    QuotaExceeded,
+}
+
+impl Violation {
+    fn describe(self) -> &'static str {
+        match self {
+            Self::ChecksumMismatch => "checksum mismatches",
+            Self::QuotaExceeded => "quota violations",
+        }
+    }
+}
+
+/// Recent violations per hashed user id, for one tenant.
+#[derive(Default)]
+pub struct AbuseMonitor {
+    violations: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl AbuseMonitor {
+    /// Counts a violation by `user_hash` at `now`. True when it is the
+    /// `max`th within `window`, which also starts the count over.
+    fn count(&self, user_hash: &str, now: Instant, max: u32, window: Duration) -> bool {
+        let mut violations = self.violations.lock().unwrap();
+        violations.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = violations.entry(user_hash.to_string()).or_default();
+        times.push_back(now);
+        if times.len() < max as usize {
+            return false;
+        }
+        violations.remove(user_hash);
+        true
+    }
+}
+
+/// Counts a violation by `user_id` and bans them for `ABUSE_BAN_DURATION`
+/// when it is one too many. Failures are logged and never fail the request
+/// that was already refused.
+pub async fn report(tenant: &Tenant, user_id: &str, violation: Violation) {
+    let config = tenant.config.load();
+    if config.abuse_max_violations == 0 {
+        return;
+    }
+
+    let user_hash = hash_user_id(user_id);
+    if !tenant.abuse.count(
+        &user_hash,
+        Instant::now(),
+        config.abuse_max_violations,
+        config.abuse_window,
+    ) {
+        return;
+    }
+
+    // A ban placed by an admin already covers this and may last longer.
+    match tenant.db.get_ban(&user_hash).await {
+        Ok(Some(_)) => return,
+        Ok(None) => {}
+        Err(e) => {
+            warn!("Failed to look up ban: {}", e);
+            return;
+        }
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let ban = Ban {
+        user: user_hash,
+        reason: format!("Too many {}", violation.describe()),
+        banned_at: now,
+        expires_at: Some(now + config.abuse_ban_duration.as_millis() as i64),
+        automatic: true,
+    };
+    match tenant.db.save_ban(&ban).await {
+        Ok(()) => warn!(
+            "Banned user {} for {}s: {}",
+            &ban.user[..16],
+            config.abuse_ban_duration.as_secs(),
+            ban.reason
+        ),
+        Err(e) => warn!("Failed to ban user: {}", e),
+    }
+}
+
+/// Fails with `Banned` when `user_id` has a ban in force.
+pub async fn check_ban(db: &Storage, user_id: &str) -> Result<(), AppError> {
+    let Some(ban) = db
+        .get_ban(&hash_user_id(user_id))
+        .await
+        .or_internal("Failed to look up ban")?
+    else {
+        return Ok(());
+    };
+
+    let now = chrono::Utc::now().timestamp_millis();
+    Err(AppError::Banned {
+        reason: ban.reason,
+        retry_after_secs: ban.expires_at.map(|at| ((at - now) / 1000).max(1) as u64),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_violations_within_window() {
+        let monitor = AbuseMonitor::default();
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+
+        assert!(!monitor.count("a", start, 3, window));
+        assert!(!monitor.count("a", start + Duration::from_secs(10), 3, window));
+        assert!(!monitor.count("b", start + Duration::from_secs(10), 3, window));
+        // The first violation has left the window.
+        assert!(!monitor.count("a", start + Duration::from_secs(65), 3, window));
+        assert!(monitor.count("a", start + Duration::from_secs(66), 3, window));
+
+        // Reaching the limit starts the count over.
+        assert!(!monitor.count("a", start + Duration::from_secs(71), 3, window));
+        assert!(!monitor.violations.lock().unwrap().contains_key("b"));
+    }
+}
@@ -0,0 +1,174 @@
+//! Account deletion with a grace period.
+//!
+//! `DELETE /v1` does not purge an account at once unless
+//! `ACCOUNT_DELETION_GRACE_DAYS` is 0. The account is scheduled for deletion
+//! and refused on every request but `POST /v1/restore`, which cancels it;
+//! the purger deletes it for good once the grace period is over.
+
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::audit::{self, AuditAction, AuditActor, AuditEntry};
+use crate::config::{Config, ConfigHandle};
+use crate::constants::{ACCOUNT_PURGE_INTERVAL_SECS, MS_PER_DAY};
+use crate::database::{AccountPurge, PendingDeletion};
+use crate::datastore::{Datastore, Storage};
+use crate::error::{AppError, ResultExt};
+use crate::events::{Event, EventBus};
+use crate::utils::hash_user_id;
+
+/// Fails with `Gone` when the account of `user_id` is scheduled for
+/// deletion.
+pub async fn check_pending(db: &Storage, user_id: &str) -> Result<(), AppError> {
+    let pending = db
+        .get_pending_deletion(&hash_user_id(user_id))
+        .await
+        .or_internal("Failed to check account deletion")?;
+    match pending {
+        Some(_) => Err(AppError::Gone(
+            "The account is scheduled for deletion; POST /v1/restore to undo it".into(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Schedules the deletion of `user_id`'s account after the grace period.
+/// Deleting an account already scheduled keeps its original date.
+pub async fn schedule(
+    db: &Storage,
+    config: &Config,
+    user_id: &str,
+) -> anyhow::Result<PendingDeletion> {
+    if let Some(pending) = db.get_pending_deletion(&hash_user_id(user_id)).await? {
+        return Ok(pending);
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let pending = PendingDeletion {
+        user_id: user_id.to_string(),
+        requested_at: now,
+        purge_at: now + config.account_deletion_grace_days as i64 * MS_PER_DAY,
+    };
+    db.save_pending_deletion(&pending).await?;
+    Ok(pending)
+}
+
+/// Cancels the scheduled deletion of `user_id`'s account. `false` if none
+/// was scheduled.
+pub async fn restore(db: &Storage, user_id: &str) -> anyhow::Result<bool> {
+    let user_hash = hash_user_id(user_id);
+    if db.get_pending_deletion(&user_hash).await?.is_none() {
+        return Ok(false);
+    }
+    db.delete_pending_deletion(&user_hash).await?;
+    Ok(true)
+}
+
+/// Purges every account whose grace period ended by `now`, returning the
+/// ones purged. An account that fails to purge stays scheduled and is tried
+/// again next time.
+pub async fn purge_due(
+    db: &Storage,
+    config: &Config,
+    events: &EventBus,
+    now: i64,
+) -> anyhow::Result<Vec<(String, AccountPurge)>> {
+    let mut purged = Vec::new();
+    for pending in db.list_pending_deletions().await? {
+        if pending.purge_at > now {
+            break;
+        }
+
+        let result = db.purge_account(&pending.user_id).await;
+        let entry = AuditEntry {
+            at: chrono::Utc::now().timestamp_millis(),
+            request_id: String::new(),
+            actor: AuditActor::System.as_str().to_string(),
+            user_hash: Some(hash_user_id(&pending.user_id)),
+            action: AuditAction::PurgeAccount.as_str().to_string(),
+            route: String::new(),
+            client_ip: None,
+            detail: None,
+            outcome: if result.is_ok() { "ok" } else { "failed" }.to_string(),
+        };
+        audit::record(db, config, entry).await;
+
+        match result {
+            Ok(purge) => {
+                events.publish(Event::AccountDeleted {
+                    user_id: pending.user_id.clone(),
+                });
+                purged.push((pending.user_id, purge));
+            }
+            Err(e) => warn!("Failed to purge a deleted account: {}", e),
+        }
+    }
+    Ok(purged)
+}
+
+/// Runs [`purge_due`] every `ACCOUNT_PURGE_INTERVAL_SECS` until the process
+/// exits.
+pub async fn run_purger(db: Storage, config: ConfigHandle, events: EventBus) {
+    let mut interval = tokio::time::interval(Duration::from_secs(ACCOUNT_PURGE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+
+        let now = chrono::Utc::now().timestamp_millis();
+        match purge_due(&db, &config.load(), &events, now).await {
+            Ok(purged) if !purged.is_empty() => {
+                info!("Purged {} deleted accounts", purged.len());
+            }
+            Ok(_) => {}
+            Err(e) => error!("Account purge failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::SqliteDatastore;
+
+    #[tokio::test]
+    async fn test_schedule_restore_and_purge() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let config = Config::from_lookup(|_| None);
+        let events = EventBus::new();
+        let mut published = events.subscribe();
+        db.save_user_settings("1", b"settings".to_vec())
+            .await
+            .unwrap();
+
+        assert!(check_pending(&db, "1").await.is_ok());
+        let pending = schedule(&db, &config, "1").await.unwrap();
+        assert_eq!(pending.purge_at - pending.requested_at, 14 * MS_PER_DAY);
+        assert!(matches!(
+            check_pending(&db, "1").await,
+            Err(AppError::Gone(_))
+        ));
+        assert_eq!(schedule(&db, &config, "1").await.unwrap(), pending);
+
+        assert!(restore(&db, "1").await.unwrap());
+        assert!(!restore(&db, "1").await.unwrap());
+        assert!(check_pending(&db, "1").await.is_ok());
+
+        let pending = schedule(&db, &config, "1").await.unwrap();
+        let early = purge_due(&db, &config, &events, pending.purge_at - 1)
+            .await
+            .unwrap();
+        assert!(early.is_empty());
+        assert!(db.get_user_settings("1").await.unwrap().is_some());
+
+        let purged = purge_due(&db, &config, &events, pending.purge_at)
+            .await
+            .unwrap();
+        assert_eq!(purged.len(), 1);
+        assert!(purged[0].1.settings);
+        assert!(db.get_user_settings("1").await.unwrap().is_none());
+        assert!(check_pending(&db, "1").await.is_ok());
+        assert!(matches!(
+            published.try_recv(),
+            Ok(Event::AccountDeleted { user_id }) if user_id == "1"
+        ));
+    }
+}
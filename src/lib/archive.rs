@@ -0,0 +1,274 @@
+//! Tar archive layout shared by `/v2/export` and `/v2/import`.
+//!
+//! An archive holds `settings.bin` (the v1 settings blob, if any), one
+//! `data/<key>` file per v2 data key, and a trailing `manifest.json`
+//! describing every file with its checksum.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use tar::{Archive, Builder, Header};
+
+use crate::checksum;
+use crate::validation::{KeyValidationError, validate_key};
+
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+pub const MANIFEST_PATH: &str = "manifest.json";
+pub const SETTINGS_PATH: &str = "settings.bin";
+pub const DATA_PREFIX: &str = "data/";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub format_version: u32,
+    pub exported_at: i64,
+    pub settings: Option<ArchiveSettingsEntry>,
+    pub entries: Vec<ArchiveDataEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveSettingsEntry {
+    pub checksum: String,
+    pub size_bytes: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveDataEntry {
+    pub key: String,
+    pub version: i64,
+    pub checksum: String,
+    pub size_bytes: i32,
+    pub updated_at: i64,
+    /// When the value expires; kept by backups, not by exports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+impl ArchiveManifest {
+    pub fn new(exported_at: i64) -> Self {
+        Self {
+            format_version: ARCHIVE_FORMAT_VERSION,
+            exported_at,
+            settings: None,
+            entries: Vec::new(),
+        }
+    }
+}
+
+pub fn data_path(key: &str) -> String {
+    format!("{}{}", DATA_PREFIX, key)
+}
+
+/// Appends a regular file to the archive. Long key paths are handled by the
+/// GNU long-name extension.
+pub fn append_file<W: Write>(
+    builder: &mut Builder<W>,
+    path: &str,
+    data: &[u8],
+    mtime_ms: i64,
+) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime((mtime_ms / 1000).max(0) as u64);
+    builder.append_data(&mut header, path, data)
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Malformed,
+    MissingManifest,
+    InvalidManifest,
+    UnsupportedVersion,
+    MissingFile(String),
+    UnexpectedFile(String),
+    DuplicateKey(String),
+    InvalidKey(String, KeyValidationError),
+    ChecksumMismatch(String),
+}
+
+impl ArchiveError {
+    pub fn message(&self) -> String {
+        match self {
+            Self::Malformed => "Archive is not a valid tar file".into(),
+            Self::MissingManifest => "Archive is missing manifest.json".into(),
+            Self::InvalidManifest => "Archive manifest is invalid".into(),
+            Self::UnsupportedVersion => "Archive format version is not supported".into(),
+            Self::MissingFile(path) => format!("Archive is missing {}", path),
+            Self::UnexpectedFile(path) => format!("Archive contains unexpected file {}", path),
+            Self::DuplicateKey(key) => format!("Archive lists key {} more than once", key),
+            Self::InvalidKey(key, e) => format!("Invalid key {}: {}", key, e.message()),
+            Self::ChecksumMismatch(path) => format!("Checksum mismatch for {}", path),
+        }
+    }
+}
+
+/// A fully read and verified archive, ready to be written back.
+pub struct StagedArchive {
+    pub settings: Option<Vec<u8>>,
+    pub entries: Vec<(ArchiveDataEntry, Vec<u8>)>,
+}
+
+/// Reads an archive produced by [`append_file`] and checks it against its
+/// manifest: every listed file must be present with a matching checksum, and
+/// nothing unlisted may be present.
+pub fn read_archive(bytes: &[u8]) -> Result<StagedArchive, ArchiveError> {
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut archive = Archive::new(bytes);
+
+    for entry in archive.entries().map_err(|_| ArchiveError::Malformed)? {
+        let mut entry = entry.map_err(|_| ArchiveError::Malformed)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry
+            .path()
+            .map_err(|_| ArchiveError::Malformed)?
+            .to_str()
+            .ok_or(ArchiveError::Malformed)?
+            .to_string();
+        let mut data = Vec::with_capacity((entry.size() as usize).min(bytes.len()));
+        entry
+            .read_to_end(&mut data)
+            .map_err(|_| ArchiveError::Malformed)?;
+        files.insert(path, data);
+    }
+
+    let manifest_bytes = files
+        .remove(MANIFEST_PATH)
+        .ok_or(ArchiveError::MissingManifest)?;
+    let manifest: ArchiveManifest =
+        serde_json::from_slice(&manifest_bytes).map_err(|_| ArchiveError::InvalidManifest)?;
+
+    if manifest.format_version != ARCHIVE_FORMAT_VERSION {
+        return Err(ArchiveError::UnsupportedVersion);
+    }
+
+    let settings = match &manifest.settings {
+        Some(meta) => {
+            let data = files
+                .remove(SETTINGS_PATH)
+                .ok_or_else(|| ArchiveError::MissingFile(SETTINGS_PATH.into()))?;
+            if !checksum::matches(&meta.checksum, &data) {
+                return Err(ArchiveError::ChecksumMismatch(SETTINGS_PATH.into()));
+            }
+            Some(data)
+        }
+        None => None,
+    };
+
+    let mut seen = HashSet::with_capacity(manifest.entries.len());
+    let mut entries = Vec::with_capacity(manifest.entries.len());
+
+    for mut meta in manifest.entries {
+        if let Err(e) = validate_key(&meta.key) {
+            return Err(ArchiveError::InvalidKey(meta.key, e));
+        }
+        if !seen.insert(meta.key.clone()) {
+            return Err(ArchiveError::DuplicateKey(meta.key));
+        }
+
+        let path = data_path(&meta.key);
+        let data = files
+            .remove(&path)
+            .ok_or_else(|| ArchiveError::MissingFile(path.clone()))?;
+        if !checksum::matches(&meta.checksum, &data) {
+            return Err(ArchiveError::ChecksumMismatch(path));
+        }
+        meta.checksum = checksum::normalize(&meta.checksum).unwrap_or(meta.checksum);
+        entries.push((meta, data));
+    }
+
+    if let Some(path) = files.into_keys().next() {
+        return Err(ArchiveError::UnexpectedFile(path));
+    }
+
+    Ok(StagedArchive { settings, entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::compute_checksum;
+
+    fn build_archive(manifest: &ArchiveManifest, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, data) in files {
+            append_file(&mut builder, path, data, 0).unwrap();
+        }
+        let manifest = serde_json::to_vec(manifest).unwrap();
+        append_file(&mut builder, MANIFEST_PATH, &manifest, 0).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn data_entry(key: &str, value: &[u8]) -> ArchiveDataEntry {
+        ArchiveDataEntry {
+            key: key.into(),
+            version: 1,
+            checksum: compute_checksum(value),
+            size_bytes: value.len() as i32,
+            updated_at: 0,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let long_key = format!("dataStore/{}", "k".repeat(200));
+        let mut manifest = ArchiveManifest::new(0);
+        manifest.settings = Some(ArchiveSettingsEntry {
+            checksum: compute_checksum(b"settings"),
+            size_bytes: 8,
+            updated_at: 0,
+        });
+        manifest.entries.push(data_entry("plugins/a", b"alpha"));
+        manifest.entries.push(data_entry(&long_key, b"beta"));
+
+        let bytes = build_archive(
+            &manifest,
+            &[
+                (SETTINGS_PATH, b"settings"),
+                (&data_path("plugins/a"), b"alpha"),
+                (&data_path(&long_key), b"beta"),
+            ],
+        );
+
+        let staged = read_archive(&bytes).unwrap();
+        assert_eq!(staged.settings.as_deref(), Some(&b"settings"[..]));
+        assert_eq!(staged.entries.len(), 2);
+        assert_eq!(staged.entries[1].0.key, long_key);
+        assert_eq!(staged.entries[1].1, b"beta");
+    }
+
+    #[test]
+    fn test_rejects_bad_archives() {
+        let mut manifest = ArchiveManifest::new(0);
+        manifest.entries.push(data_entry("plugins/a", b"alpha"));
+
+        let tampered = build_archive(&manifest, &[(&data_path("plugins/a"), b"omega")]);
+        assert!(matches!(
+            read_archive(&tampered),
+            Err(ArchiveError::ChecksumMismatch(_))
+        ));
+
+        let missing = build_archive(&manifest, &[]);
+        assert!(matches!(
+            read_archive(&missing),
+            Err(ArchiveError::MissingFile(_))
+        ));
+
+        let extra = build_archive(
+            &manifest,
+            &[(&data_path("plugins/a"), b"alpha"), ("data/other", b"x")],
+        );
+        assert!(matches!(
+            read_archive(&extra),
+            Err(ArchiveError::UnexpectedFile(_))
+        ));
+
+        assert!(matches!(
+            read_archive(b"not a tar"),
+            Err(ArchiveError::Malformed | ArchiveError::MissingManifest)
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+//! Audit trail for destructive operations: settings and data deletes,
+//! account deletion and admin actions. Entries are written after the
+//! operation ran, with its outcome, and never block or fail the request.
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::constants::MS_PER_DAY;
+use crate::datastore::{Datastore, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditActor {
+    User,
+    Admin,
+    /// A background task, such as the account purger.
+    System,
+}
+
+impl AuditActor {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Admin => "admin",
+            Self::System => "system",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    DeleteSettings,
+    DeleteAllData,
+    RestoreAccount,
+    PurgeAccount,
+    DeleteDataKey,
+    RenameDataKey,
+    AdminDeleteUser,
+    AdminLegacyCleanup,
+    AdminBulkPurge,
+    AdminSetQuota,
+    AdminBanUser,
+    AdminUnbanUser,
+    AdminReadValue,
+    AdminCreateApiKey,
+    AdminDeleteApiKey,
+    LinkAccount,
+    UnlinkAccount,
+}
+
+impl AuditAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::DeleteSettings => "delete-settings",
+            Self::DeleteAllData => "delete-all-data",
+            Self::RestoreAccount => "restore-account",
+            Self::PurgeAccount => "purge-account",
+            Self::DeleteDataKey => "delete-data-key",
+            Self::RenameDataKey => "rename-data-key",
+            Self::AdminDeleteUser => "admin-delete-user",
+            Self::AdminLegacyCleanup => "admin-legacy-cleanup",
+            Self::AdminBulkPurge => "admin-bulk-purge",
+            Self::AdminSetQuota => "admin-set-quota",
+            Self::AdminBanUser => "admin-ban-user",
+            Self::AdminUnbanUser => "admin-unban-user",
+            Self::AdminReadValue => "admin-read-value",
+            Self::AdminCreateApiKey => "admin-create-api-key",
+            Self::AdminDeleteApiKey => "admin-delete-api-key",
+            Self::LinkAccount => "link-account",
+            Self::UnlinkAccount => "unlink-account",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub at: i64,
+    pub request_id: String,
+    pub actor: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_hash: Option<String>,
+    pub action: String,
+    pub route: String,
+    /// Address the request came from, if it came in over HTTP.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub outcome: String,
+}
+
+/// UTC day an entry is stored under, e.g. `2025-01-31`.
+pub fn day_bucket(at: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(at)
+        .unwrap_or_default()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+pub async fn record(db: &Storage, config: &Config, entry: AuditEntry) {
+    let ttl = config.audit_retention_days as i64 * MS_PER_DAY / 1000;
+    if let Err(e) = db.insert_audit_entry(&entry, ttl as i32).await {
+        warn!(
+            "Failed to write audit entry for {} ({}): {}",
+            entry.action, entry.request_id, e
+        );
+    }
+}
+
+/// Newest entries first, walking back one day bucket at a time until
+/// `limit` matching entries are found or `days` buckets were read.
+pub async fn recent(
+    db: &Storage,
+    days: u32,
+    limit: usize,
+    matches: impl Fn(&AuditEntry) -> bool,
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut entries = Vec::new();
+
+    for back in 0..days as i64 {
+        let day = day_bucket(now - back * MS_PER_DAY);
+        for entry in db.list_audit_entries(&day).await? {
+            if matches(&entry) {
+                entries.push(entry);
+                if entries.len() >= limit {
+                    return Ok(entries);
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_day_bucket() {
+        assert_eq!(day_bucket(0), "1970-01-01");
+        assert_eq!(day_bucket(1_738_367_999_999), "2025-01-31");
+        assert_eq!(day_bucket(1_738_368_000_000), "2025-02-01");
+    }
+}
@@ -0,0 +1,176 @@
+//! Logging and counting of how authenticating requests ends.
+//!
+//! User ids never reach the log. They are replaced by a hash keyed with
+//! `SESSION_SECRET` and cut short, which is enough to tell one user's
+//! requests apart but cannot be matched against a list of known ids without
+//! the secret.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+use tracing::{debug, info, trace};
+
+use crate::metrics::Metrics;
+use crate::utils::Config;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Hex characters kept of a hashed user id.
+const LOG_ID_LEN: usize = 12;
+
+/// Level successful authentications are logged at, per `AUTH_LOG_LEVEL`.
+/// Failures are always logged at info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthLogLevel {
+    Off,
+    Trace,
+    Debug,
+    Info,
+}
+
+impl AuthLogLevel {
+    pub const ALL: [Self; 4] = [Self::Off, Self::Trace, Self::Debug, Self::Info];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Trace => "trace",
+            Self::Debug => "debug",
+            Self::Info => "info",
+        }
+    }
+}
+
+impl FromStr for AuthLogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|level| level.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| format!("unknown log level {:?}", s))
+    }
+}
+
+/// How authenticating a request ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AuthOutcome {
+    /// Signed in with a session secret.
+    Session,
+    /// Signed in with a permanent secret.
+    PermanentSecret,
+    /// Signed in with a permanent secret in the legacy CRC32 format.
+    LegacySecret,
+    /// Signed in with a permanent secret derived without the current
+    /// `SECRET_PEPPER`, during its grace period.
+    OutdatedSecret,
+    /// Signed in with an API key.
+    ApiKey,
+    /// Named by `X-Dev-User-Id` in `DEV_MODE`.
+    DevUser,
+    MissingToken,
+    /// The token is malformed, expired or has the wrong secret.
+    InvalidToken,
+    /// The user is not in `DISCORD_ALLOWED_USER_IDS`.
+    NotAllowed,
+    /// The token lacks a scope the request needs.
+    OutOfScope,
+    Banned,
+    /// The user, API key or address is locked out after failed sign-ins.
+    LockedOut,
+    /// The account is scheduled for deletion.
+    PendingDeletion,
+    /// The account, ban or deletion lookup failed.
+    Error,
+}
+
+impl AuthOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Session => "session",
+            Self::PermanentSecret => "permanent_secret",
+            Self::LegacySecret => "legacy_secret",
+            Self::OutdatedSecret => "outdated_secret",
+            Self::ApiKey => "api_key",
+            Self::DevUser => "dev_user",
+            Self::MissingToken => "missing_token",
+            Self::InvalidToken => "invalid_token",
+            Self::NotAllowed => "not_allowed",
+            Self::OutOfScope => "out_of_scope",
+            Self::Banned => "banned",
+            Self::LockedOut => "locked_out",
+            Self::PendingDeletion => "pending_deletion",
+            Self::Error => "error",
+        }
+    }
+
+    pub fn is_success(self) -> bool {
+        matches!(
+            self,
+            Self::Session
+                | Self::PermanentSecret
+                | Self::LegacySecret
+                | Self::OutdatedSecret
+                | Self::ApiKey
+                | Self::DevUser
+        )
+    }
+}
+
+impl fmt::Display for AuthOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The identifier `user_id` is logged under.
+pub fn log_id(secret: &[u8], user_id: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(user_id.as_bytes());
+    let mut id = hex::encode(mac.finalize().into_bytes());
+    id.truncate(LOG_ID_LEN);
+    id
+}
+
+/// Counts `outcome` and logs it with the hashed id of the user the token
+/// named, if it named one.
+pub fn record(metrics: &Metrics, config: &Config, outcome: AuthOutcome, user_id: Option<&str>) {
+    metrics.record_auth(outcome);
+
+    let user = user_id
+        .map(|id| log_id(config.session_secret.as_bytes(), id))
+        .unwrap_or_else(|| "-".to_string());
+    if !outcome.is_success() {
+        info!(outcome = %outcome, user = %user, "Authentication failed");
+        return;
+    }
+    match config.auth_log_level {
+        AuthLogLevel::Off => {}
+        AuthLogLevel::Trace => trace!(outcome = %outcome, user = %user, "Authenticated"),
+        AuthLogLevel::Debug => debug!(outcome = %outcome, user = %user, "Authenticated"),
+        AuthLogLevel::Info => info!(outcome = %outcome, user = %user, "Authenticated"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_id_is_keyed_and_short() {
+        let id = log_id(b"secret", "123456789");
+        assert_eq!(id.len(), LOG_ID_LEN);
+        assert!(!id.contains("123456789"));
+        assert_eq!(id, log_id(b"secret", "123456789"));
+        assert_ne!(id, log_id(b"other", "123456789"));
+        assert_ne!(id, log_id(b"secret", "987654321"));
+    }
+
+    #[test]
+    fn test_parse_log_level() {
+        assert_eq!("INFO".parse(), Ok(AuthLogLevel::Info));
+        assert_eq!("off".parse(), Ok(AuthLogLevel::Off));
+        assert!("warn".parse::<AuthLogLevel>().is_err());
+    }
+}
@@ -0,0 +1,721 @@
+//! Scheduled backups of a whole tenant to a local directory or an
+//! S3-compatible bucket.
+//!
+//! A backup is a tar holding one `users/<hash>.tar` per user in the
+//! `/v2/export` archive format and then `backup.json`, compressed with zstd
+//! and encrypted under `BACKUP_ENCRYPTION_KEY` with the STREAM construction
+//! over AES-256-GCM: `magic || nonce prefix`, then segments of
+//! `BACKUP_SEGMENT_SIZE` bytes, each sealed on its own. Backups are written
+//! and read a user at a time, so neither needs the whole tenant in memory;
+//! compressing, encrypting and the archives themselves are handled on
+//! blocking threads. Users are stored by their hashed id, the only id the
+//! database knows.
+
+use aes_gcm::aead::Aead;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{Context, Result, anyhow, bail};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor, Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use tar::{Archive, Builder};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+use crate::archive::{
+    ArchiveDataEntry, ArchiveManifest, ArchiveSettingsEntry, MANIFEST_PATH, SETTINGS_PATH,
+    StagedArchive, append_file, data_path, read_archive,
+};
+use crate::blob_store::{BlobStore, S3BlobStore};
+use crate::constants::{BACKUP_COMPRESSION_LEVEL, BACKUP_SEGMENT_SIZE, MAX_RESTORE_USER_SIZE};
+use crate::database::UserSnapshot;
+use crate::utils::{Config, ConfigHandle, aes_cipher, compute_checksum};
+use crate::{DataUpload, Datastore, Storage};
+
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+pub const INDEX_PATH: &str = "backup.json";
+pub const USERS_PREFIX: &str = "users/";
+pub const BACKUP_EXTENSION: &str = ".tar.zst.enc";
+
+const MAGIC: &[u8; 8] = b"EQCBAK02";
+/// Backups sealed in one piece, `magic || nonce || ciphertext`, as they
+/// were before they were sealed in segments. They can still be restored.
+const LEGACY_MAGIC: &[u8; 8] = b"EQCBAK01";
+const LEGACY_NONCE_LEN: usize = 12;
+/// The part of each segment's nonce shared by the whole backup; STREAM
+/// fills in the rest with the segment's position and whether it is last.
+const NONCE_PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupIndex {
+    pub format_version: u32,
+    pub created_at: i64,
+    pub tenant: String,
+    pub users: usize,
+    pub keys: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RestoreSummary {
+    pub users: usize,
+    pub keys: usize,
+}
+
+fn user_path(hash: &str) -> String {
+    format!("{}{}.tar", USERS_PREFIX, hash)
+}
+
+/// Encrypts what is written to it a segment at a time.
+struct SealWriter<W: Write> {
+    inner: W,
+    encryptor: EncryptorBE32<Aes256Gcm>,
+    segment: Vec<u8>,
+}
+
+impl<W: Write> SealWriter<W> {
+    fn new(key_material: &[u8], mut inner: W) -> io::Result<Self> {
+        let mut nonce = [0u8; NONCE_PREFIX_LEN];
+        rand::rng().fill_bytes(&mut nonce);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&nonce)?;
+        Ok(Self {
+            inner,
+            encryptor: EncryptorBE32::from_aead(
+                aes_cipher(key_material),
+                GenericArray::from_slice(&nonce),
+            ),
+            segment: Vec::with_capacity(BACKUP_SEGMENT_SIZE),
+        })
+    }
+
+    /// Seals what is left as the last segment, which may be empty.
+    fn finish(mut self) -> io::Result<W> {
+        let sealed = self
+            .encryptor
+            .encrypt_last(self.segment.as_slice())
+            .map_err(|_| io::Error::other("Failed to encrypt backup"))?;
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full segment is only sealed once more follows, as the last one
+        // is sealed differently.
+        if self.segment.len() == BACKUP_SEGMENT_SIZE {
+            let sealed = self
+                .encryptor
+                .encrypt_next(self.segment.as_slice())
+                .map_err(|_| io::Error::other("Failed to encrypt backup"))?;
+            self.inner.write_all(&sealed)?;
+            self.segment.clear();
+        }
+        let taken = buf.len().min(BACKUP_SEGMENT_SIZE - self.segment.len());
+        self.segment.extend_from_slice(&buf[..taken]);
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reverses [`SealWriter`], failing if a segment was altered, reordered or
+/// left off, the last one included.
+struct OpenReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    /// Sealed bytes read ahead, to tell the last segment from the others.
+    sealed: Vec<u8>,
+    plain: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> OpenReader<R> {
+    fn new(key_material: &[u8], mut inner: R) -> io::Result<Self> {
+        let mut nonce = [0u8; NONCE_PREFIX_LEN];
+        inner.read_exact(&mut nonce)?;
+        Ok(Self {
+            inner,
+            decryptor: Some(DecryptorBE32::from_aead(
+                aes_cipher(key_material),
+                GenericArray::from_slice(&nonce),
+            )),
+            sealed: Vec::new(),
+            plain: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Opens the next segment, or returns `false` after the last one.
+    fn next_segment(&mut self) -> io::Result<bool> {
+        let Some(decryptor) = self.decryptor.as_mut() else {
+            return Ok(false);
+        };
+        // One byte past a full segment shows whether another follows.
+        let wanted = BACKUP_SEGMENT_SIZE + TAG_LEN + 1;
+        while self.sealed.len() < wanted {
+            let filled = self.sealed.len();
+            self.sealed.resize(wanted, 0);
+            let read = self.inner.read(&mut self.sealed[filled..]);
+            self.sealed
+                .truncate(filled + read.as_ref().map_or(0, |read| *read));
+            match read {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let opened = if self.sealed.len() == wanted {
+            let rest = self.sealed.split_off(wanted - 1);
+            let opened = decryptor.decrypt_next(self.sealed.as_slice());
+            self.sealed = rest;
+            opened
+        } else {
+            let last = std::mem::take(&mut self.sealed);
+            self.decryptor
+                .take()
+                .expect("checked above")
+                .decrypt_last(last.as_slice())
+        };
+        self.plain = opened.map_err(|_| {
+            io::Error::other("Failed to decrypt backup; is BACKUP_ENCRYPTION_KEY right?")
+        })?;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plain.len() {
+            if !self.next_segment()? {
+                return Ok(0);
+            }
+        }
+        let read = buf.len().min(self.plain.len() - self.position);
+        buf[..read].copy_from_slice(&self.plain[self.position..][..read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Compresses and encrypts what is written to it.
+fn seal<W: Write>(
+    key_material: &[u8],
+    inner: W,
+) -> io::Result<zstd::Encoder<'static, SealWriter<W>>> {
+    zstd::Encoder::new(
+        SealWriter::new(key_material, inner)?,
+        BACKUP_COMPRESSION_LEVEL,
+    )
+}
+
+/// Reads back the archive sealed into `sealed`, by [`seal`] or in the
+/// legacy format.
+fn open<R: Read + Send + 'static>(
+    key_material: &[u8],
+    mut sealed: R,
+) -> Result<Box<dyn Read + Send>> {
+    let mut magic = [0u8; 8];
+    sealed
+        .read_exact(&mut magic)
+        .map_err(|_| anyhow!("Not an EquiCloud backup"))?;
+    if &magic == MAGIC {
+        let opened = OpenReader::new(key_material, sealed).context("Backup is truncated")?;
+        return Ok(Box::new(zstd::Decoder::new(opened)?));
+    }
+    if &magic != LEGACY_MAGIC {
+        bail!("Not an EquiCloud backup");
+    }
+
+    let mut rest = Vec::new();
+    sealed.read_to_end(&mut rest)?;
+    if rest.len() < LEGACY_NONCE_LEN {
+        bail!("Backup is truncated");
+    }
+    let (nonce, ciphertext) = rest.split_at(LEGACY_NONCE_LEN);
+    let compressed = aes_cipher(key_material)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to decrypt backup; is BACKUP_ENCRYPTION_KEY right?"))?;
+    Ok(Box::new(zstd::Decoder::new(Cursor::new(compressed))?))
+}
+
+/// The archive of one user in the `/v2/export` format, and how many keys it
+/// holds.
+fn user_archive(snapshot: UserSnapshot, now: i64) -> Result<(Vec<u8>, usize)> {
+    let mut builder = Builder::new(Vec::new());
+    let mut manifest = ArchiveManifest::new(now);
+    if let Some((settings, updated_at)) = &snapshot.settings {
+        append_file(&mut builder, SETTINGS_PATH, settings, *updated_at)?;
+        manifest.settings = Some(ArchiveSettingsEntry {
+            checksum: compute_checksum(settings),
+            size_bytes: settings.len() as i64,
+            updated_at: *updated_at,
+        });
+    }
+    for (entry, expires_at) in snapshot.data {
+        append_file(
+            &mut builder,
+            &data_path(&entry.key),
+            &entry.value,
+            entry.updated_at,
+        )?;
+        manifest.entries.push(ArchiveDataEntry {
+            key: entry.key,
+            version: entry.version,
+            checksum: entry.checksum,
+            size_bytes: entry.size_bytes,
+            updated_at: entry.updated_at,
+            expires_at,
+        });
+    }
+    append_file(
+        &mut builder,
+        MANIFEST_PATH,
+        &serde_json::to_vec(&manifest)?,
+        now,
+    )?;
+    Ok((builder.into_inner()?, manifest.entries.len()))
+}
+
+/// Writes a sealed backup of every user in `db` to `out`, one user at a
+/// time, and hands `out` back once the backup is complete.
+pub async fn export_all<W>(
+    db: &Storage,
+    key_material: &[u8],
+    tenant: &str,
+    now: i64,
+    out: W,
+) -> Result<(W, BackupIndex)>
+where
+    W: Write + Send + 'static,
+{
+    let mut index = BackupIndex {
+        format_version: BACKUP_FORMAT_VERSION,
+        created_at: now,
+        tenant: tenant.to_string(),
+        users: 0,
+        keys: 0,
+    };
+    let key_material = key_material.to_vec();
+    let (users, mut snapshots) = mpsc::channel::<(String, UserSnapshot)>(1);
+    let writer = tokio::task::spawn_blocking(move || -> Result<(W, BackupIndex)> {
+        let mut outer = Builder::new(seal(&key_material, out)?);
+        while let Some((hash, snapshot)) = snapshots.blocking_recv() {
+            let (archive, keys) = user_archive(snapshot, now)?;
+            append_file(&mut outer, &user_path(&hash), &archive, now)?;
+            index.users += 1;
+            index.keys += keys;
+        }
+        append_file(
+            &mut outer,
+            INDEX_PATH,
+            &serde_json::to_vec_pretty(&index)?,
+            now,
+        )?;
+        let out = outer.into_inner()?.finish()?.finish()?;
+        Ok((out, index))
+    });
+
+    for hash in db.list_user_hashes().await? {
+        let snapshot = db
+            .snapshot_user_by_hash(&hash)
+            .await
+            .with_context(|| format!("Failed to read user {}", hash))?;
+        if snapshot.settings.is_none() && snapshot.data.is_empty() {
+            continue;
+        }
+        // The writer only hangs up when it failed, which it reports below.
+        if users.send((hash, snapshot)).await.is_err() {
+            break;
+        }
+    }
+    drop(users);
+    writer.await?
+}
+
+/// Writes every user of the sealed backup read from `sealed` back to `db`,
+/// one user at a time. Keys that have expired since the backup was taken
+/// are skipped.
+pub async fn restore_all<R>(
+    db: &Storage,
+    key_material: &[u8],
+    sealed: R,
+    now: i64,
+) -> Result<RestoreSummary>
+where
+    R: Read + Send + 'static,
+{
+    let key_material = key_material.to_vec();
+    let (users, mut staged) = mpsc::channel::<(String, StagedArchive)>(1);
+    let reader = tokio::task::spawn_blocking(move || -> Result<BackupIndex> {
+        let mut outer = Archive::new(open(&key_material, sealed)?);
+        let mut index = None;
+        for entry in outer.entries().context("Backup is not a valid tar file")? {
+            let mut entry = entry.context("Backup is not a valid tar file")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry
+                .path()?
+                .to_str()
+                .ok_or_else(|| anyhow!("Backup contains a non UTF-8 path"))?
+                .to_string();
+            if entry.size() > MAX_RESTORE_USER_SIZE {
+                bail!("{} is too large to restore", path);
+            }
+            let mut bytes = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut bytes)?;
+
+            if path == INDEX_PATH {
+                index = Some(serde_json::from_slice::<BackupIndex>(&bytes)?);
+                continue;
+            }
+            let Some(hash) = path
+                .strip_prefix(USERS_PREFIX)
+                .and_then(|p| p.strip_suffix(".tar"))
+            else {
+                bail!("Backup contains unexpected file {}", path);
+            };
+            let archive = read_archive(&bytes).map_err(|e| anyhow!("{}: {}", path, e.message()))?;
+            // Restoring only hangs up when it failed, which it reports.
+            if users.blocking_send((hash.to_string(), archive)).is_err() {
+                break;
+            }
+        }
+        index.ok_or_else(|| anyhow!("Backup is missing {}", INDEX_PATH))
+    });
+
+    let mut summary = RestoreSummary::default();
+    while let Some((hash, archive)) = staged.recv().await {
+        let uploads = archive
+            .entries
+            .into_iter()
+            .filter_map(|(entry, value)| {
+                let ttl_secs = match entry.expires_at {
+                    Some(expires_at) if expires_at <= now => return None,
+                    Some(expires_at) => {
+                        Some(((expires_at - now) / 1000).clamp(1, i32::MAX as i64) as i32)
+                    }
+                    None => None,
+                };
+                Some(DataUpload {
+                    key: entry.key,
+                    value,
+                    checksum: entry.checksum,
+                    ttl_secs,
+                })
+            })
+            .collect();
+
+        summary.keys += db
+            .restore_user_by_hash(&hash, archive.settings, uploads)
+            .await
+            .with_context(|| format!("Failed to restore user {}", hash))?;
+        summary.users += 1;
+    }
+
+    let index = reader.await??;
+    if index.format_version != BACKUP_FORMAT_VERSION {
+        bail!(
+            "Backup format version {} is not supported",
+            index.format_version
+        );
+    }
+    Ok(summary)
+}
+
+/// Where backups are kept, per `BACKUP_TARGET`.
+pub enum BackupTarget {
+    Local(PathBuf),
+    S3(Box<S3BlobStore>),
+}
+
+impl BackupTarget {
+    pub fn from_config(config: &Config) -> Self {
+        match config.backup_target.as_str() {
+            "s3" => Self::S3(Box::new(
+                S3BlobStore::from_config(config).with_prefix(&config.backup_s3_prefix),
+            )),
+            _ => Self::Local(PathBuf::from(&config.backup_dir)),
+        }
+    }
+
+    /// Writes a backup of `db` called `name` and returns its index and
+    /// size. Local backups stream into a `.partial` file that is renamed
+    /// once complete. S3 takes an object in one request, so a backup bound
+    /// there is held in memory, compressed and sealed, until it is sent.
+    pub async fn write(
+        &self,
+        db: &Storage,
+        key_material: &[u8],
+        tenant: &str,
+        now: i64,
+        name: &str,
+    ) -> Result<(BackupIndex, usize)> {
+        match self {
+            Self::Local(dir) => {
+                tokio::fs::create_dir_all(dir)
+                    .await
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+                let path = dir.join(name);
+                let partial = dir.join(format!("{}.partial", name));
+                let file = tokio::fs::File::create(&partial)
+                    .await
+                    .with_context(|| format!("Failed to write {}", partial.display()))?
+                    .into_std()
+                    .await;
+                let index = match export_all(db, key_material, tenant, now, file).await {
+                    Ok((_, index)) => index,
+                    Err(e) => {
+                        let _ = tokio::fs::remove_file(&partial).await;
+                        return Err(e.context(format!("Failed to write {}", partial.display())));
+                    }
+                };
+                let size = tokio::fs::metadata(&partial).await?.len() as usize;
+                tokio::fs::rename(&partial, &path).await?;
+                Ok((index, size))
+            }
+            Self::S3(store) => {
+                let (sealed, index) = export_all(db, key_material, tenant, now, Vec::new()).await?;
+                store.put(name, &sealed).await?;
+                Ok((index, sealed.len()))
+            }
+        }
+    }
+
+    /// Writes the backup called `name` back to `db`, reading local backups
+    /// as they are restored.
+    pub async fn restore(
+        &self,
+        db: &Storage,
+        key_material: &[u8],
+        name: &str,
+        now: i64,
+    ) -> Result<RestoreSummary> {
+        match self {
+            Self::Local(dir) => {
+                let path = dir.join(name);
+                let file = tokio::fs::File::open(&path)
+                    .await
+                    .with_context(|| format!("Failed to read {}", path.display()))?
+                    .into_std()
+                    .await;
+                restore_all(db, key_material, file, now).await
+            }
+            Self::S3(store) => {
+                let sealed = store
+                    .get(name)
+                    .await?
+                    .ok_or_else(|| anyhow!("Backup {} not found", name))?;
+                restore_all(db, key_material, Cursor::new(sealed), now).await
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BackupSummary {
+    pub name: String,
+    pub users: usize,
+    pub keys: usize,
+    pub size_bytes: usize,
+}
+
+/// Takes a backup of `db` and writes it to the configured target.
+pub async fn back_up(db: &Storage, config: &Config, tenant: &str) -> Result<BackupSummary> {
+    if config.backup_encryption_key.is_empty() {
+        bail!("BACKUP_ENCRYPTION_KEY is not set");
+    }
+    let now = chrono::Utc::now();
+    let name = format!(
+        "{}-{}{}",
+        tenant,
+        now.format("%Y%m%dT%H%M%SZ"),
+        BACKUP_EXTENSION
+    );
+    let (index, size_bytes) = BackupTarget::from_config(config)
+        .write(
+            db,
+            config.backup_encryption_key.as_bytes(),
+            tenant,
+            now.timestamp_millis(),
+            &name,
+        )
+        .await?;
+
+    Ok(BackupSummary {
+        name,
+        users: index.users,
+        keys: index.keys,
+        size_bytes,
+    })
+}
+
+/// Reads the backup called `name` from the configured target and writes it
+/// back to `db`.
+pub async fn restore_from(db: &Storage, config: &Config, name: &str) -> Result<RestoreSummary> {
+    if config.backup_encryption_key.is_empty() {
+        bail!("BACKUP_ENCRYPTION_KEY is not set");
+    }
+    BackupTarget::from_config(config)
+        .restore(
+            db,
+            config.backup_encryption_key.as_bytes(),
+            name,
+            chrono::Utc::now().timestamp_millis(),
+        )
+        .await
+}
+
+/// Backs up the tenant every `interval`, starting one interval after
+/// startup. The target and key are re-read from `config` on each run.
+pub async fn run_scheduler(db: Storage, config: ConfigHandle, tenant: String, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+
+        match back_up(&db, &config.load(), &tenant).await {
+            Ok(summary) => info!(
+                "Backup {} written: {} users, {} keys, {} bytes",
+                summary.name, summary.users, summary.keys, summary.size_bytes
+            ),
+            Err(e) => error!("Backup of tenant {} failed: {:#}", tenant, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SqliteDatastore;
+
+    fn upload(key: &str, value: &[u8], ttl_secs: Option<i32>) -> DataUpload {
+        DataUpload {
+            key: key.into(),
+            value: value.to_vec(),
+            checksum: compute_checksum(value),
+            ttl_secs,
+        }
+    }
+
+    fn sealed(key_material: &[u8], archive: &[u8]) -> Vec<u8> {
+        let mut encoder = seal(key_material, Vec::new()).unwrap();
+        encoder.write_all(archive).unwrap();
+        encoder.finish().unwrap().finish().unwrap()
+    }
+
+    fn opened(key_material: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let mut archive = Vec::new();
+        open(key_material, Cursor::new(sealed.to_vec()))?.read_to_end(&mut archive)?;
+        Ok(archive)
+    }
+
+    #[test]
+    fn test_seal_round_trip() {
+        let small = sealed(b"key", b"archive");
+        assert!(small.starts_with(MAGIC));
+        assert_eq!(opened(b"key", &small).unwrap(), b"archive");
+        assert!(opened(b"wrong", &small).is_err());
+        assert!(opened(b"key", &small[..MAGIC.len() + 4]).is_err());
+        assert!(opened(b"key", b"archive").is_err());
+
+        // Random bytes do not compress, so they take several segments.
+        let mut archive = vec![0u8; 3 * BACKUP_SEGMENT_SIZE];
+        rand::rng().fill_bytes(&mut archive);
+        let large = sealed(b"key", &archive);
+        assert!(large.len() > MAGIC.len() + 3 * (BACKUP_SEGMENT_SIZE + TAG_LEN));
+        assert_eq!(opened(b"key", &large).unwrap(), archive);
+
+        // Cut at a segment boundary, what is left does not end in a last
+        // segment.
+        let boundary = MAGIC.len() + NONCE_PREFIX_LEN + BACKUP_SEGMENT_SIZE + TAG_LEN;
+        assert!(opened(b"key", &large[..boundary]).is_err());
+        let mut tampered = large.clone();
+        tampered[boundary + 10] ^= 1;
+        assert!(opened(b"key", &tampered).is_err());
+    }
+
+    #[test]
+    fn test_open_legacy_backup() {
+        let compressed = zstd::encode_all(&b"archive"[..], BACKUP_COMPRESSION_LEVEL).unwrap();
+        let nonce = [7u8; LEGACY_NONCE_LEN];
+        let ciphertext = aes_cipher(b"key")
+            .encrypt(Nonce::from_slice(&nonce), compressed.as_slice())
+            .unwrap();
+        let legacy = [&LEGACY_MAGIC[..], &nonce, &ciphertext].concat();
+        assert_eq!(opened(b"key", &legacy).unwrap(), b"archive");
+        assert!(opened(b"wrong", &legacy).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backup_round_trip() {
+        let source = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        source
+            .save_user_settings("1", b"settings".to_vec())
+            .await
+            .unwrap();
+        source
+            .save_data_keys_batch(
+                "1",
+                vec![
+                    upload("plugins/a", b"a", None),
+                    upload("themes/b", b"b", Some(3600)),
+                ],
+            )
+            .await
+            .unwrap();
+        source
+            .save_data_keys_batch("2", vec![upload("plugins/c", b"c", None)])
+            .await
+            .unwrap();
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let (sealed, index) = export_all(&source, b"key", "default", now, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!((index.users, index.keys), (2, 3));
+
+        let target = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let summary = restore_all(&target, b"key", Cursor::new(sealed.clone()), now)
+            .await
+            .unwrap();
+        assert_eq!(summary, RestoreSummary { users: 2, keys: 3 });
+
+        let (settings, _) = target.get_user_settings("1").await.unwrap().unwrap();
+        assert_eq!(settings, b"settings");
+        let entry = target
+            .get_data_key("2", "plugins/c")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.value, b"c");
+        let manifest = target.get_data_manifest("1").await.unwrap();
+        let expiring = manifest.iter().find(|e| e.key == "themes/b").unwrap();
+        assert!(expiring.expires_at.is_some());
+
+        let later = now + 7200 * 1000;
+        let target = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let summary = restore_all(&target, b"key", Cursor::new(sealed), later)
+            .await
+            .unwrap();
+        assert_eq!(summary.keys, 2);
+        assert!(
+            target
+                .get_data_key("1", "themes/b")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
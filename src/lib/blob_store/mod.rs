@@ -0,0 +1,124 @@
+//! Storage for the bodies of content-addressed blobs.
+//!
+//! Data rows that point at a blob keep only its hash; reference counts and
+//! collection metadata stay in the Scylla `blobs` and `blob_refs` tables.
+//! The body lives either in the `blobs` table itself (the default) or in an
+//! S3-compatible bucket, selected with `BLOB_STORE`. Large bodies in Scylla
+//! are split into chunk rows, which [`BlobStore::open`] hands out as a
+//! stream that fetches each one only when it is polled.
+
+pub mod s3;
+pub mod scylla;
+
+use ::scylla::client::session::Session;
+use anyhow::Result;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use futures::stream::BoxStream;
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::utils::Config;
+
+pub use self::s3::S3BlobStore;
+pub use self::scylla::ScyllaBlobStore;
+
+/// Pieces of a body, in order.
+pub type ChunkStream = BoxStream<'static, Result<Bytes>>;
+
+/// A body as a store hands it out.
+pub enum BlobBody {
+    Whole(Vec<u8>),
+    Chunks(ChunkStream),
+}
+
+impl BlobBody {
+    /// The whole body, fetching any chunks still to come.
+    pub async fn into_vec(self) -> Result<Vec<u8>> {
+        match self {
+            Self::Whole(value) => Ok(value),
+            Self::Chunks(chunks) => {
+                chunks
+                    .try_fold(Vec::new(), |mut value, chunk| async move {
+                        value.extend_from_slice(&chunk);
+                        Ok(value)
+                    })
+                    .await
+            }
+        }
+    }
+}
+
+pub trait BlobStore {
+    /// Stores `value` under `hash`, replacing any existing body.
+    fn put(&self, hash: &str, value: &[u8]) -> impl Future<Output = Result<()>> + Send;
+
+    /// The body stored under `hash`, or `None` if there is none.
+    fn get(&self, hash: &str) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    /// The body stored under `hash` without reading it all first when it is
+    /// stored in chunks, or `None` if there is none.
+    fn open(&self, hash: &str) -> impl Future<Output = Result<Option<BlobBody>>> + Send;
+
+    /// Removes the body stored under `hash`; missing bodies are not an error.
+    fn delete(&self, hash: &str) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// The configured blob store, dispatched statically.
+pub enum Blobs {
+    Scylla(ScyllaBlobStore),
+    /// Blobs written before switching to S3 are still read from Scylla.
+    S3 {
+        store: Box<S3BlobStore>,
+        inline: ScyllaBlobStore,
+    },
+}
+
+impl Blobs {
+    pub async fn from_config(config: &Config, session: Arc<Session>) -> Result<Self> {
+        let inline = ScyllaBlobStore::new(session).await?;
+        Ok(match config.blob_store.as_str() {
+            "s3" => Self::S3 {
+                store: Box::new(S3BlobStore::from_config(config)),
+                inline,
+            },
+            _ => Self::Scylla(inline),
+        })
+    }
+}
+
+impl BlobStore for Blobs {
+    async fn put(&self, hash: &str, value: &[u8]) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.put(hash, value).await,
+            Self::S3 { store, .. } => store.put(hash, value).await,
+        }
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::Scylla(s) => s.get(hash).await,
+            Self::S3 { store, inline } => match store.get(hash).await? {
+                Some(value) => Ok(Some(value)),
+                None => inline.get(hash).await,
+            },
+        }
+    }
+
+    async fn open(&self, hash: &str) -> Result<Option<BlobBody>> {
+        match self {
+            Self::Scylla(s) => s.open(hash).await,
+            Self::S3 { store, inline } => match store.open(hash).await? {
+                Some(body) => Ok(Some(body)),
+                None => inline.open(hash).await,
+            },
+        }
+    }
+
+    async fn delete(&self, hash: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.delete(hash).await,
+            Self::S3 { store, .. } => store.delete(hash).await,
+        }
+    }
+}
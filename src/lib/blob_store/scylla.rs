@@ -0,0 +1,139 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use futures::StreamExt;
+use futures::stream;
+use scylla::client::session::Session;
+use scylla::statement::prepared::PreparedStatement;
+use std::sync::Arc;
+
+use super::{BlobBody, BlobStore, ChunkStream};
+use crate::constants::BLOB_CHUNK_SIZE;
+
+/// Keeps blob bodies in the `value` column of the `blobs` table, next to
+/// their metadata. Bodies larger than [`BLOB_CHUNK_SIZE`] go to rows of
+/// `blob_chunks` instead, and `chunks` counts them.
+pub struct ScyllaBlobStore {
+    session: Arc<Session>,
+    put: PreparedStatement,
+    put_chunk: PreparedStatement,
+    put_chunked: PreparedStatement,
+    get: PreparedStatement,
+    get_chunk: Arc<PreparedStatement>,
+    delete: PreparedStatement,
+    delete_chunks: PreparedStatement,
+}
+
+impl ScyllaBlobStore {
+    pub async fn new(session: Arc<Session>) -> Result<Self> {
+        let put = session
+            .prepare("UPDATE blobs SET value = ?, chunks = null WHERE hash = ?")
+            .await?;
+        let put_chunk = session
+            .prepare("INSERT INTO blob_chunks (hash, idx, value) VALUES (?, ?, ?)")
+            .await?;
+        let put_chunked = session
+            .prepare("UPDATE blobs SET value = null, chunks = ? WHERE hash = ?")
+            .await?;
+        let mut get = session
+            .prepare("SELECT value, chunks FROM blobs WHERE hash = ?")
+            .await?;
+        get.set_is_idempotent(true);
+        let mut get_chunk = session
+            .prepare("SELECT value FROM blob_chunks WHERE hash = ? AND idx = ?")
+            .await?;
+        get_chunk.set_is_idempotent(true);
+        let delete = session
+            .prepare("DELETE value, chunks FROM blobs WHERE hash = ?")
+            .await?;
+        let delete_chunks = session
+            .prepare("DELETE FROM blob_chunks WHERE hash = ?")
+            .await?;
+
+        Ok(Self {
+            session,
+            put,
+            put_chunk,
+            put_chunked,
+            get,
+            get_chunk: Arc::new(get_chunk),
+            delete,
+            delete_chunks,
+        })
+    }
+
+    /// Fetches chunk `idx` of `hash` when the stream reaches it, so a reader
+    /// never holds more than the chunk it is sending.
+    fn chunks(&self, hash: &str, count: i32) -> ChunkStream {
+        let session = Arc::clone(&self.session);
+        let get_chunk = Arc::clone(&self.get_chunk);
+        let hash = hash.to_string();
+        stream::iter(0..count)
+            .then(move |idx| {
+                let session = Arc::clone(&session);
+                let get_chunk = Arc::clone(&get_chunk);
+                let hash = hash.clone();
+                async move {
+                    let result = session.execute_unpaged(&get_chunk, (&hash, idx)).await?;
+                    let (value,) = result
+                        .into_rows_result()?
+                        .maybe_first_row::<(Vec<u8>,)>()?
+                        .ok_or_else(|| anyhow!("Chunk {} of blob {} is missing", idx, hash))?;
+                    Ok(Bytes::from(value))
+                }
+            })
+            .boxed()
+    }
+}
+
+impl BlobStore for ScyllaBlobStore {
+    /// Chunks are written before the row that counts them, so a reader never
+    /// sees a count with chunks missing. Chunks left from an earlier,
+    /// larger body of the same hash are removed with the blob.
+    async fn put(&self, hash: &str, value: &[u8]) -> Result<()> {
+        if value.len() <= BLOB_CHUNK_SIZE {
+            self.session
+                .execute_unpaged(&self.put, (value, hash))
+                .await?;
+            return Ok(());
+        }
+
+        let mut count = 0i32;
+        for chunk in value.chunks(BLOB_CHUNK_SIZE) {
+            self.session
+                .execute_unpaged(&self.put_chunk, (hash, count, chunk))
+                .await?;
+            count += 1;
+        }
+        self.session
+            .execute_unpaged(&self.put_chunked, (count, hash))
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        match self.open(hash).await? {
+            Some(body) => Ok(Some(body.into_vec().await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn open(&self, hash: &str) -> Result<Option<BlobBody>> {
+        let result = self.session.execute_unpaged(&self.get, (hash,)).await?;
+        let rows_result = result.into_rows_result()?;
+        let Some(row) = rows_result.rows::<(Option<Vec<u8>>, Option<i32>)>()?.next() else {
+            return Ok(None);
+        };
+        Ok(match row? {
+            (_, Some(count)) if count > 0 => Some(BlobBody::Chunks(self.chunks(hash, count))),
+            (value, _) => value.map(BlobBody::Whole),
+        })
+    }
+
+    async fn delete(&self, hash: &str) -> Result<()> {
+        self.session.execute_unpaged(&self.delete, (hash,)).await?;
+        self.session
+            .execute_unpaged(&self.delete_chunks, (hash,))
+            .await?;
+        Ok(())
+    }
+}
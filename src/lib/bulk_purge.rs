@@ -0,0 +1,168 @@
+//! Purging many accounts at once.
+//!
+//! An administrator picks accounts by when they last wrote and how much
+//! they store, through `POST /admin/users/purge` or `equicloud_admin
+//! purge-users`. An account has to match every criterion given. Both list
+//! the matching accounts by hashed id without deleting anything unless told
+//! to go ahead.
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::audit::{self, AuditEntry};
+use crate::config::Config;
+use crate::database::UserActivity;
+use crate::datastore::{Datastore, Storage};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+pub struct PurgeCriteria {
+    /// Accounts that last wrote settings or data before this time, in
+    /// milliseconds.
+    pub inactive_before: Option<i64>,
+    /// Accounts storing fewer bytes than this.
+    pub smaller_than: Option<i64>,
+    /// Accounts storing more bytes than this.
+    pub larger_than: Option<i64>,
+}
+
+impl PurgeCriteria {
+    /// Whether no criterion is given, which would match every account.
+    pub fn is_empty(&self) -> bool {
+        self.inactive_before.is_none() && self.smaller_than.is_none() && self.larger_than.is_none()
+    }
+
+    pub fn matches(&self, activity: &UserActivity) -> bool {
+        self.inactive_before
+            .is_none_or(|before| activity.last_active < before)
+            && self
+                .smaller_than
+                .is_none_or(|size| activity.size_bytes < size)
+            && self
+                .larger_than
+                .is_none_or(|size| activity.size_bytes > size)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct BulkPurgeReport {
+    /// Whether the accounts were only listed.
+    pub dry_run: bool,
+    /// Accounts matching the criteria.
+    pub matched: usize,
+    /// Bytes stored by the matching accounts.
+    pub size_bytes: i64,
+    pub purged: usize,
+    pub failed: usize,
+    pub users: Vec<UserActivity>,
+}
+
+/// Every account matching `criteria`, oldest activity first.
+pub async fn find_matching(
+    db: &Storage,
+    criteria: &PurgeCriteria,
+) -> anyhow::Result<Vec<UserActivity>> {
+    let mut matching = Vec::new();
+    for user_hash in db.list_user_hashes().await? {
+        let activity = db.user_activity_by_hash(&user_hash).await?;
+        if criteria.matches(&activity) {
+            matching.push(activity);
+        }
+    }
+    matching.sort_by(|a, b| a.last_active.cmp(&b.last_active).then(a.user.cmp(&b.user)));
+    Ok(matching)
+}
+
+/// Finds the accounts matching `criteria` and, unless `dry_run`, purges
+/// them, recording the entry `audit_entry` makes for each hashed id and
+/// outcome. An account that fails to purge is counted and skipped.
+pub async fn run(
+    db: &Storage,
+    config: &Config,
+    criteria: &PurgeCriteria,
+    dry_run: bool,
+    audit_entry: impl Fn(&str, bool) -> AuditEntry,
+) -> anyhow::Result<BulkPurgeReport> {
+    let users = find_matching(db, criteria).await?;
+    let mut report = BulkPurgeReport {
+        dry_run,
+        matched: users.len(),
+        size_bytes: users.iter().map(|u| u.size_bytes).sum(),
+        ..Default::default()
+    };
+
+    if !dry_run {
+        for user in &users {
+            let result = db.purge_account_by_hash(&user.user).await;
+            audit::record(db, config, audit_entry(&user.user, result.is_ok())).await;
+            match result {
+                Ok(_) => report.purged += 1,
+                Err(e) => {
+                    report.failed += 1;
+                    warn!("Failed to purge an account in a bulk purge: {}", e);
+                }
+            }
+        }
+    }
+
+    report.users = users;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::SqliteDatastore;
+    use crate::utils::hash_user_id;
+
+    fn entry(user_hash: &str, succeeded: bool) -> AuditEntry {
+        AuditEntry {
+            at: 0,
+            request_id: String::new(),
+            actor: "admin".into(),
+            user_hash: Some(user_hash.to_string()),
+            action: "admin-bulk-purge".into(),
+            route: String::new(),
+            client_ip: None,
+            detail: None,
+            outcome: if succeeded { "success" } else { "failure" }.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_then_purge() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let config = Config::from_lookup(|_| None);
+        db.save_user_settings("small", b"x".to_vec()).await.unwrap();
+        db.save_user_settings("large", vec![0; 1000]).await.unwrap();
+
+        let all = find_matching(&db, &PurgeCriteria::default()).await.unwrap();
+        assert_eq!(all.len(), 2);
+        let after_writes = all.iter().map(|u| u.last_active).max().unwrap() + 1;
+
+        let criteria = PurgeCriteria {
+            inactive_before: Some(after_writes),
+            smaller_than: Some(100),
+            larger_than: None,
+        };
+        assert!(!criteria.is_empty());
+
+        let report = run(&db, &config, &criteria, true, entry).await.unwrap();
+        assert_eq!(report.matched, 1);
+        assert_eq!(report.size_bytes, 1);
+        assert_eq!(report.users[0].user, hash_user_id("small"));
+        assert_eq!(report.purged, 0);
+        assert!(db.get_user_settings("small").await.unwrap().is_some());
+
+        let report = run(&db, &config, &criteria, false, entry).await.unwrap();
+        assert_eq!((report.matched, report.purged, report.failed), (1, 1, 0));
+        assert!(db.get_user_settings("small").await.unwrap().is_none());
+        assert!(db.get_user_settings("large").await.unwrap().is_some());
+
+        let stale = PurgeCriteria {
+            inactive_before: Some(0),
+            ..Default::default()
+        };
+        assert!(find_matching(&db, &stale).await.unwrap().is_empty());
+    }
+}
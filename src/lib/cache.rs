@@ -0,0 +1,213 @@
+//! Read-through cache for v1 settings, keyed by hashed user id.
+//!
+//! The cache is bounded by the total size of the cached values and evicts the
+//! least recently used entry when full. Entries also expire after
+//! `SETTINGS_CACHE_TTL`, which bounds how stale a read can be when several
+//! server instances share one database: invalidation is only local.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+struct CachedSettings {
+    value: Vec<u8>,
+    written: String,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, CachedSettings>,
+    bytes: usize,
+    clock: u64,
+    generation: u64,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.value.len();
+        }
+    }
+
+    fn evict_lru(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.remove(&key);
+        }
+    }
+}
+
+pub struct SettingsCache {
+    max_bytes: usize,
+    ttl: Duration,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl SettingsCache {
+    /// A cache holding at most `max_bytes` of settings; zero disables it.
+    pub fn new(max_bytes: usize, ttl: Duration) -> Self {
+        Self {
+            max_bytes,
+            ttl,
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_bytes > 0 && !self.ttl.is_zero()
+    }
+
+    /// Returns the cached settings and their etag, counting a hit or miss.
+    pub fn get(&self, key: &str) -> Option<(Vec<u8>, String)> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        let found = match inner.entries.get_mut(key) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => {
+                entry.last_used = clock;
+                Some((entry.value.clone(), entry.written.clone()))
+            }
+            Some(_) => {
+                inner.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Token to pass to [`insert`](Self::insert) so a read that raced with a
+    /// write does not cache the value the write replaced.
+    pub fn generation(&self) -> u64 {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .generation
+    }
+
+    pub fn insert(&self, key: &str, value: &[u8], written: &str, generation: u64) {
+        if !self.enabled() || value.len() > self.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if inner.generation != generation {
+            return;
+        }
+
+        inner.remove(key);
+        while inner.bytes + value.len() > self.max_bytes {
+            inner.evict_lru();
+        }
+
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.bytes += value.len();
+        inner.entries.insert(
+            key.to_string(),
+            CachedSettings {
+                value: value.to_vec(),
+                written: written.to_string(),
+                inserted_at: Instant::now(),
+                last_used,
+            },
+        );
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.generation += 1;
+        inner.remove(key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.entries.len() as u64,
+            bytes: inner.bytes as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = SettingsCache::new(8, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert("a", b"aaaa", "1", generation);
+        cache.insert("b", b"bbbb", "2", generation);
+        assert!(cache.get("a").is_some());
+
+        cache.insert("c", b"cccc", "3", generation);
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("a"), Some((b"aaaa".to_vec(), "1".to_string())));
+        assert!(cache.get("c").is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+        assert_eq!((stats.entries, stats.bytes), (2, 8));
+    }
+
+    #[test]
+    fn test_invalidate_discards_racing_insert() {
+        let cache = SettingsCache::new(1024, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.invalidate("a");
+        cache.insert("a", b"stale", "1", generation);
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn test_disabled_and_expired() {
+        let disabled = SettingsCache::new(0, Duration::from_secs(60));
+        disabled.insert("a", b"x", "1", disabled.generation());
+        assert!(disabled.get("a").is_none());
+        assert_eq!(disabled.stats().misses, 0);
+
+        let expiring = SettingsCache::new(1024, Duration::from_nanos(1));
+        expiring.insert("a", b"x", "1", expiring.generation());
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(expiring.get("a").is_none());
+        assert_eq!(expiring.stats().entries, 0);
+    }
+}
@@ -0,0 +1,286 @@
+//! Checksums of stored values, tagged with the algorithm that produced them.
+//!
+//! A checksum is written `<algorithm>:<hex>`. SHA-256, the only algorithm
+//! older servers knew, is stored bare as it always was so existing clients
+//! and manifests keep matching; `sha256:<hex>` is accepted and means the
+//! same. Every other algorithm keeps its tag in the manifest, so a value is
+//! always verified with the algorithm it was uploaded with.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::hash::Hasher;
+use std::str::FromStr;
+use twox_hash::XxHash3_64;
+
+use crate::constants::CHECKSUM_BYTES;
+use crate::error::AppError;
+use crate::utils::compute_checksum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    /// First 8 bytes of SHA-256.
+    Sha256,
+    /// 64-bit XXH3; much cheaper, but not cryptographic.
+    Xxh3,
+}
+
+impl ChecksumAlgorithm {
+    pub const ALL: [Self; 2] = [Self::Sha256, Self::Xxh3];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Xxh3 => "xxh3",
+        }
+    }
+
+    /// The checksum of `data` as it is stored.
+    pub fn compute(self, data: &[u8]) -> String {
+        match self {
+            Self::Sha256 => compute_checksum(data),
+            Self::Xxh3 => format!("xxh3:{:016x}", XxHash3_64::oneshot(data)),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = ChecksumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|a| a.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| ChecksumError::Unsupported(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChecksumError {
+    /// The checksum names an algorithm this server does not know.
+    Unsupported(String),
+    /// The value does not match its checksum.
+    Mismatch,
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported(name) => write!(f, "Unsupported checksum algorithm: {}", name),
+            Self::Mismatch => f.write_str("Checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+impl From<ChecksumError> for AppError {
+    fn from(e: ChecksumError) -> Self {
+        AppError::BadRequest(e.to_string())
+    }
+}
+
+/// The algorithm of a checksum and its digest. An untagged checksum is
+/// SHA-256.
+fn split(checksum: &str) -> Result<(ChecksumAlgorithm, &str), ChecksumError> {
+    match checksum.trim().split_once(':') {
+        Some((name, digest)) => Ok((name.parse()?, digest)),
+        None => Ok((ChecksumAlgorithm::Sha256, checksum.trim())),
+    }
+}
+
+/// The algorithm a checksum was computed with.
+pub fn algorithm_of(checksum: &str) -> Result<ChecksumAlgorithm, ChecksumError> {
+    split(checksum).map(|(algorithm, _)| algorithm)
+}
+
+/// A checksum as it is stored: trimmed, lowercase and without the tag when
+/// it is SHA-256.
+pub fn normalize(checksum: &str) -> Result<String, ChecksumError> {
+    let (algorithm, digest) = split(checksum)?;
+    let digest = digest.to_ascii_lowercase();
+    Ok(match algorithm {
+        ChecksumAlgorithm::Sha256 => digest,
+        algorithm => format!("{}:{}", algorithm.name(), digest),
+    })
+}
+
+/// Recomputes the checksum of `data` with the algorithm of `checksum`.
+/// Checksums of an unknown algorithm are recomputed with SHA-256, so they
+/// never match.
+pub fn recompute(checksum: &str, data: &[u8]) -> String {
+    algorithm_of(checksum)
+        .unwrap_or(ChecksumAlgorithm::Sha256)
+        .compute(data)
+}
+
+/// Whether `data` matches `checksum`.
+pub fn matches(checksum: &str, data: &[u8]) -> bool {
+    normalize(checksum).is_ok_and(|c| c == recompute(&c, data))
+}
+
+/// Whether two checksums are the same, however each was written. Checksums
+/// of different algorithms never are.
+pub fn same(a: &str, b: &str) -> bool {
+    match (normalize(a), normalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Computes a checksum over a value that arrives in pieces, with the
+/// algorithm [`recompute`] would use for `checksum`.
+pub enum StreamingChecksum {
+    Sha256(Sha256),
+    Xxh3(Box<XxHash3_64>),
+}
+
+impl StreamingChecksum {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Xxh3 => Self::Xxh3(Box::new(XxHash3_64::new())),
+        }
+    }
+
+    pub fn for_checksum(checksum: &str) -> Self {
+        Self::new(algorithm_of(checksum).unwrap_or(ChecksumAlgorithm::Sha256))
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Xxh3(hasher) => hasher.write(data),
+        }
+    }
+
+    /// The checksum as [`ChecksumAlgorithm::compute`] writes it.
+    pub fn finish(self) -> String {
+        match self {
+            Self::Sha256(hasher) => hex::encode(&hasher.finalize()[..CHECKSUM_BYTES]),
+            Self::Xxh3(hasher) => format!("xxh3:{:016x}", hasher.finish()),
+        }
+    }
+}
+
+/// Checks an uploaded value against the checksum the client sent and returns
+/// the checksum to store: the client's, or one computed with `default` when
+/// it sent none.
+pub fn verify_upload(
+    claimed: Option<&str>,
+    data: &[u8],
+    default: ChecksumAlgorithm,
+) -> Result<String, ChecksumError> {
+    let Some(claimed) = claimed else {
+        return Ok(default.compute(data));
+    };
+    let claimed = normalize(claimed)?;
+    if recompute(&claimed, data) != claimed {
+        return Err(ChecksumError::Mismatch);
+    }
+    Ok(claimed)
+}
+
+/// The algorithm an upload is hashed with while it streams in: that of the
+/// checksum the client sent, or `default`.
+pub fn upload_algorithm(
+    claimed: Option<&str>,
+    default: ChecksumAlgorithm,
+) -> Result<ChecksumAlgorithm, ChecksumError> {
+    claimed.map_or(Ok(default), algorithm_of)
+}
+
+/// [`verify_upload`] for a value already hashed with [`upload_algorithm`]
+/// into `computed`.
+pub fn verify_streamed(claimed: Option<&str>, computed: String) -> Result<String, ChecksumError> {
+    let Some(claimed) = claimed else {
+        return Ok(computed);
+    };
+    let claimed = normalize(claimed)?;
+    if claimed != computed {
+        return Err(ChecksumError::Mismatch);
+    }
+    Ok(claimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_is_stored_bare() {
+        let bare = ChecksumAlgorithm::Sha256.compute(b"value");
+        assert_eq!(bare, compute_checksum(b"value"));
+        assert_eq!(normalize(&format!("SHA256:{}", bare)).unwrap(), bare);
+        assert!(matches(&bare, b"value"));
+        assert!(same(&bare, &format!("sha256:{}", bare)));
+    }
+
+    #[test]
+    fn test_xxh3_keeps_its_tag() {
+        let checksum = ChecksumAlgorithm::Xxh3.compute(b"value");
+        assert!(checksum.starts_with("xxh3:"));
+        assert_eq!(algorithm_of(&checksum), Ok(ChecksumAlgorithm::Xxh3));
+        assert!(matches(&checksum, b"value"));
+        assert!(!matches(&checksum, b"valuf"));
+        assert!(!same(&checksum, &compute_checksum(b"value")));
+    }
+
+    #[test]
+    fn test_streaming_matches_oneshot() {
+        for algorithm in ChecksumAlgorithm::ALL {
+            let checksum = algorithm.compute(b"streamed value");
+            let mut streaming = StreamingChecksum::for_checksum(&checksum);
+            streaming.update(b"streamed");
+            streaming.update(b" value");
+            assert_eq!(streaming.finish(), checksum);
+        }
+    }
+
+    #[test]
+    fn test_verify_upload_checks_the_claimed_algorithm() {
+        let xxh3 = ChecksumAlgorithm::Xxh3.compute(b"value");
+        assert_eq!(
+            verify_upload(Some(&xxh3), b"value", ChecksumAlgorithm::Sha256),
+            Ok(xxh3.clone())
+        );
+        assert_eq!(
+            verify_upload(None, b"value", ChecksumAlgorithm::Xxh3),
+            Ok(xxh3.clone())
+        );
+        assert_eq!(
+            verify_upload(Some(&xxh3), b"valuf", ChecksumAlgorithm::Sha256),
+            Err(ChecksumError::Mismatch)
+        );
+        assert_eq!(
+            verify_upload(Some("md5:abcd"), b"value", ChecksumAlgorithm::Sha256),
+            Err(ChecksumError::Unsupported("md5".into()))
+        );
+    }
+
+    #[test]
+    fn test_verify_streamed_checks_the_claimed_checksum() {
+        let xxh3 = ChecksumAlgorithm::Xxh3.compute(b"value");
+        let algorithm = upload_algorithm(Some(&xxh3), ChecksumAlgorithm::Sha256).unwrap();
+        assert_eq!(algorithm, ChecksumAlgorithm::Xxh3);
+        assert_eq!(
+            verify_streamed(Some(&xxh3), algorithm.compute(b"value")),
+            Ok(xxh3.clone())
+        );
+        assert_eq!(
+            verify_streamed(Some(&xxh3), algorithm.compute(b"valuf")),
+            Err(ChecksumError::Mismatch)
+        );
+        assert_eq!(
+            upload_algorithm(None, ChecksumAlgorithm::Xxh3),
+            Ok(ChecksumAlgorithm::Xxh3)
+        );
+    }
+}
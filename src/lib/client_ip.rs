@@ -0,0 +1,199 @@
+//! The address a request came from.
+//!
+//! Behind a reverse proxy every request's peer is the proxy. Proxies list
+//! the addresses a request passed through in `Forwarded` or
+//! `X-Forwarded-For`, but a client can send those headers too, so they are
+//! only read from peers in `TRUSTED_PROXIES`. The list is walked from the
+//! right, past every trusted proxy, and the first address that is not one is
+//! the client. `TRUST_PROXY_HEADERS` trusts every peer and every hop, which
+//! is only safe when nothing but the proxy can reach the server.
+
+use axum::http::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::ip_range::IpRange;
+use crate::utils::Config;
+
+/// Request extension with the address the request came from, for rate
+/// limits, lockouts and the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientIp(pub IpAddr);
+
+/// Which peers may name the client.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyTrust<'a> {
+    pub proxies: &'a [IpRange],
+    pub all: bool,
+}
+
+impl<'a> ProxyTrust<'a> {
+    pub fn from_config(config: &'a Config) -> Self {
+        Self {
+            proxies: &config.trusted_proxies,
+            all: config.trust_proxy_headers,
+        }
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.all || self.proxies.iter().any(|range| range.contains(ip))
+    }
+}
+
+/// The client behind `peer`, per the forwarding headers of trusted proxies.
+pub fn resolve(headers: &HeaderMap, peer: IpAddr, trust: ProxyTrust<'_>) -> IpAddr {
+    let mut client = peer.to_canonical();
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        if !trust.trusts(client) {
+            break;
+        }
+        // A hop that names no address, such as `unknown`, ends the walk at
+        // the last proxy known.
+        match hop {
+            Some(ip) => client = ip.to_canonical(),
+            None => break,
+        }
+    }
+    client
+}
+
+/// [`resolve`] for the peer axum records as `ConnectInfo`, which is missing
+/// when the router is called without a listener.
+pub fn from_connect_info(
+    headers: &HeaderMap,
+    peer: Option<&SocketAddr>,
+    trust: ProxyTrust<'_>,
+) -> Option<ClientIp> {
+    peer.map(|peer| ClientIp(resolve(headers, peer.ip(), trust)))
+}
+
+/// The addresses a request was forwarded for, client first: from
+/// `Forwarded` if sent, else `X-Forwarded-For`, else `X-Real-IP`. `None`
+/// stands for a hop that names no address.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|hop| !hop.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    let forwarded_for = values("x-forwarded-for");
+    if !forwarded_for.is_empty() {
+        return forwarded_for.iter().map(|hop| parse_node(hop)).collect();
+    }
+    values("x-real-ip")
+        .iter()
+        .map(|hop| parse_node(hop))
+        .collect()
+}
+
+/// An address as proxies write it: bare, quoted, in brackets or with a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip_range::parse_ip_ranges;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn client(headers: &[(&'static str, &str)], peer: &str, proxies: &str) -> IpAddr {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        let proxies = parse_ip_ranges(proxies).unwrap();
+        let trust = ProxyTrust {
+            proxies: &proxies,
+            all: false,
+        };
+        resolve(&map, ip(peer), trust)
+    }
+
+    #[test]
+    fn test_untrusted_peers_are_the_client() {
+        let spoofed = [("x-forwarded-for", "203.0.113.7")];
+        assert_eq!(client(&spoofed, "198.51.100.1", ""), ip("198.51.100.1"));
+        assert_eq!(
+            client(&spoofed, "198.51.100.1", "10.0.0.0/8"),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn test_walks_past_trusted_proxies() {
+        let chain = [("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.2")];
+        assert_eq!(client(&chain, "10.0.0.1", "10.0.0.0/8"), ip("203.0.113.7"));
+        assert_eq!(
+            client(&chain, "10.0.0.1", "10.0.0.0/8, 203.0.113.0/24"),
+            ip("1.1.1.1")
+        );
+
+        let split = [
+            ("x-forwarded-for", "203.0.113.7"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ];
+        assert_eq!(client(&split, "10.0.0.1", "10.0.0.0/8"), ip("203.0.113.7"));
+        assert_eq!(client(&[], "10.0.0.1", "10.0.0.0/8"), ip("10.0.0.1"));
+        assert_eq!(
+            client(&[("x-real-ip", "203.0.113.9")], "10.0.0.1", "10.0.0.0/8"),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            client(&[("x-forwarded-for", "unknown")], "10.0.0.1", "10.0.0.0/8"),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        let forwarded = [(
+            "forwarded",
+            "for=\"[2001:db8:cafe::17]:4711\", for=192.0.2.60:8080;proto=https;by=10.0.0.2",
+        )];
+        assert_eq!(
+            client(&forwarded, "10.0.0.1", "10.0.0.0/8"),
+            ip("192.0.2.60")
+        );
+        assert_eq!(
+            client(&forwarded, "::ffff:10.0.0.1", "10.0.0.0/8, 192.0.2.0/24"),
+            ip("2001:db8:cafe::17")
+        );
+
+        // Forwarded wins over X-Forwarded-For.
+        let both = [
+            ("forwarded", "for=192.0.2.60"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ];
+        assert_eq!(client(&both, "10.0.0.1", "10.0.0.0/8"), ip("192.0.2.60"));
+    }
+}
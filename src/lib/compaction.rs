@@ -0,0 +1,166 @@
+//! Background compaction of the settings history.
+//!
+//! Every settings write trims that user's history to
+//! `SETTINGS_HISTORY_VERSIONS`, but users who stop writing are never
+//! revisited: versions kept under a larger setting stay after it is lowered,
+//! and nothing expires by age. The compactor walks every user's history each
+//! `HISTORY_COMPACTION_INTERVAL` and drops what [`HistoryPolicy`] no longer
+//! keeps. v2 data keys only store their current value, so they have no old
+//! versions to compact.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::config::{Config, ConfigHandle};
+use crate::constants::MS_PER_DAY;
+use crate::datastore::{Datastore, Storage};
+use crate::metrics::Metrics;
+
+/// Which settings versions are kept. A version is dropped once it is beyond
+/// the newest `keep_versions` or older than `max_age_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryPolicy {
+    pub keep_versions: usize,
+    pub max_age_ms: Option<i64>,
+}
+
+impl HistoryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            keep_versions: config.settings_history_versions,
+            max_age_ms: (config.settings_history_max_age_days > 0)
+                .then(|| config.settings_history_max_age_days as i64 * MS_PER_DAY),
+        }
+    }
+
+    /// How many versions to keep of a history written at `written`, newest
+    /// first. The newest one is the current settings and never expires by
+    /// age while any are kept.
+    pub fn kept(&self, written: &[i64], now: i64) -> usize {
+        let by_count = written.len().min(self.keep_versions);
+        match self.max_age_ms {
+            Some(max_age) => {
+                let fresh = written.iter().take_while(|&&w| w >= now - max_age).count();
+                by_count.min(fresh.max(1))
+            }
+            None => by_count,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub versions_removed: u64,
+    pub bytes_reclaimed: u64,
+}
+
+/// Compacts the settings history every `interval` until the process exits.
+pub async fn run_compactor(
+    db: Storage,
+    config: ConfigHandle,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let policy = HistoryPolicy::from_config(&config.load());
+        let now = chrono::Utc::now().timestamp_millis();
+        match db.compact_settings_history(policy, now).await {
+            Ok(stats) => {
+                if stats.versions_removed > 0 {
+                    info!(
+                        "History compaction removed {} settings versions, {} bytes reclaimed",
+                        stats.versions_removed, stats.bytes_reclaimed
+                    );
+                }
+                metrics.record_compaction(stats);
+            }
+            Err(e) => error!("History compaction failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::SqliteDatastore;
+
+    #[test]
+    fn test_kept() {
+        let now = 100 * MS_PER_DAY;
+        let written = [
+            now - MS_PER_DAY,
+            now - 5 * MS_PER_DAY,
+            now - 40 * MS_PER_DAY,
+        ];
+        let policy = |keep_versions, max_age_days: Option<i64>| HistoryPolicy {
+            keep_versions,
+            max_age_ms: max_age_days.map(|days| days * MS_PER_DAY),
+        };
+
+        assert_eq!(policy(3, None).kept(&written, now), 3);
+        assert_eq!(policy(2, None).kept(&written, now), 2);
+        assert_eq!(policy(5, None).kept(&written, now), 3);
+        assert_eq!(policy(3, Some(30)).kept(&written, now), 2);
+        assert_eq!(policy(3, Some(3)).kept(&written, now), 1);
+        assert_eq!(policy(3, Some(3)).kept(&written[2..], now), 1);
+        assert_eq!(policy(0, Some(30)).kept(&written, now), 0);
+        assert_eq!(policy(3, None).kept(&[], now), 0);
+    }
+
+    #[tokio::test]
+    async fn test_compact_settings_history() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let mut written = Vec::new();
+        for settings in [b"first".as_slice(), b"second", b"third"] {
+            written.push(db.save_user_settings("1", settings.to_vec()).await.unwrap());
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        db.save_user_settings("2", b"other".to_vec()).await.unwrap();
+
+        let policy = HistoryPolicy {
+            keep_versions: 2,
+            max_age_ms: None,
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        let stats = db.compact_settings_history(policy, now).await.unwrap();
+        assert_eq!(
+            stats,
+            CompactionStats {
+                versions_removed: 1,
+                bytes_reclaimed: 5,
+            }
+        );
+        assert!(
+            db.get_settings_version("1", written[0])
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            db.get_settings_version("1", written[1])
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        let policy = HistoryPolicy {
+            keep_versions: 2,
+            max_age_ms: Some(0),
+        };
+        let later = now + MS_PER_DAY;
+        let stats = db.compact_settings_history(policy, later).await.unwrap();
+        assert_eq!(stats.versions_removed, 1);
+        assert_eq!(
+            db.get_settings_version("1", written[2]).await.unwrap(),
+            Some(b"third".to_vec())
+        );
+        assert_eq!(
+            db.compact_settings_history(policy, later).await.unwrap(),
+            CompactionStats::default()
+        );
+    }
+}
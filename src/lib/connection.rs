@@ -0,0 +1,217 @@
+//! Scylla session setup: contact points, load balancing, consistency,
+//! speculative execution and retrying the initial connection.
+
+use anyhow::{Result, bail};
+use scylla::client::PoolSize;
+use scylla::client::execution_profile::ExecutionProfile;
+use scylla::client::session::Session;
+use scylla::client::session_builder::SessionBuilder;
+use scylla::frame::Compression;
+use scylla::policies::load_balancing::DefaultPolicy;
+use scylla::policies::retry::DefaultRetryPolicy;
+use scylla::policies::speculative_execution::SimpleSpeculativeExecutionPolicy;
+use scylla::statement::Consistency;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::constants::{SCYLLA_CONNECT_BACKOFF_BASE_MS, SCYLLA_CONNECT_BACKOFF_MAX_MS};
+use crate::utils::Config;
+
+/// Splits `SCYLLA_URI` into contact points; any one reachable node is enough
+/// for the driver to discover the rest of the cluster.
+pub fn contact_points(uri: &str) -> Vec<String> {
+    uri.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn parse_consistency(s: &str) -> Option<Consistency> {
+    let consistency = match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+        "any" => Consistency::Any,
+        "one" => Consistency::One,
+        "two" => Consistency::Two,
+        "three" => Consistency::Three,
+        "quorum" => Consistency::Quorum,
+        "all" => Consistency::All,
+        "local_quorum" => Consistency::LocalQuorum,
+        "each_quorum" => Consistency::EachQuorum,
+        "local_one" => Consistency::LocalOne,
+        _ => return None,
+    };
+    Some(consistency)
+}
+
+/// The `SCYLLA_CONSISTENCY` value that selects `consistency`.
+pub fn consistency_name(consistency: Consistency) -> &'static str {
+    match consistency {
+        Consistency::Any => "any",
+        Consistency::One => "one",
+        Consistency::Two => "two",
+        Consistency::Three => "three",
+        Consistency::Quorum => "quorum",
+        Consistency::All => "all",
+        Consistency::LocalQuorum => "local_quorum",
+        Consistency::EachQuorum => "each_quorum",
+        Consistency::LocalOne => "local_one",
+        Consistency::Serial => "serial",
+        Consistency::LocalSerial => "local_serial",
+    }
+}
+
+/// A session builder for the cluster `config` describes. Fails on settings
+/// that no number of attempts could connect with; [`Config::validate`]
+/// reports the same ones at startup.
+fn session_builder(config: &Config) -> Result<SessionBuilder> {
+    let nodes = contact_points(&config.scylla_uri);
+    if nodes.is_empty() {
+        bail!("SCYLLA_URI does not contain any contact points");
+    }
+    let Some(pool_size) = NonZeroUsize::new(config.scylla_pool_size) else {
+        bail!("SCYLLA_POOL_SIZE must be greater than zero");
+    };
+
+    let mut load_balancing = DefaultPolicy::builder().token_aware(true);
+    if let Some(datacenter) = &config.scylla_local_dc {
+        info!(
+            "Preferring datacenter {} (failover {})",
+            datacenter,
+            if config.scylla_dc_failover {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
+        load_balancing = load_balancing
+            .prefer_datacenter(datacenter.clone())
+            .permit_dc_failover(config.scylla_dc_failover);
+    }
+
+    let mut profile = ExecutionProfile::builder()
+        .load_balancing_policy(load_balancing.build())
+        .retry_policy(Arc::new(DefaultRetryPolicy::new()))
+        .consistency(config.scylla_consistency)
+        .request_timeout(request_timeout(config.scylla_request_timeout_ms));
+
+    if config.scylla_speculative_retries > 0 {
+        profile = profile.speculative_execution_policy(Some(Arc::new(
+            SimpleSpeculativeExecutionPolicy {
+                max_retry_count: config.scylla_speculative_retries,
+                retry_interval: Duration::from_millis(config.scylla_speculative_delay_ms),
+            },
+        )));
+    }
+
+    let mut session_builder = SessionBuilder::new()
+        .known_nodes(&nodes)
+        .connection_timeout(Duration::from_millis(config.scylla_connection_timeout_ms))
+        .pool_size(PoolSize::PerShard(pool_size))
+        .default_execution_profile_handle(profile.build().into_handle())
+        .compression(Some(Compression::Lz4))
+        .tcp_nodelay(true);
+
+    if let (Some(user), Some(pass)) = (&config.scylla_username, &config.scylla_password) {
+        session_builder = session_builder.user(user, pass);
+    }
+    Ok(session_builder)
+}
+
+pub async fn create_database_connection(config: &Config) -> Result<Session> {
+    let session = session_builder(config)?.build().await?;
+    Ok(session)
+}
+
+/// Per-query timeout from `SCYLLA_REQUEST_TIMEOUT_MS`; a query that takes
+/// longer fails instead of holding its request. Zero disables the timeout.
+pub fn request_timeout(millis: u64) -> Option<Duration> {
+    match millis {
+        0 => None,
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+/// Delay before reconnect attempt `attempt` (1-based): exponential from the
+/// base delay, capped.
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let factor = 1u64 << attempt.saturating_sub(1).min(16);
+    Duration::from_millis(
+        SCYLLA_CONNECT_BACKOFF_BASE_MS
+            .saturating_mul(factor)
+            .min(SCYLLA_CONNECT_BACKOFF_MAX_MS),
+    )
+}
+
+/// Connects with exponential backoff, giving up after
+/// `SCYLLA_CONNECT_MAX_ATTEMPTS` attempts (0 retries forever). Invalid
+/// settings fail right away rather than being retried.
+pub async fn connect_with_retry(config: &Config) -> Result<Session> {
+    let builder = session_builder(config)?;
+    let max_attempts = config.scylla_connect_max_attempts;
+    let mut attempt = 1;
+
+    loop {
+        match builder.build().await {
+            Ok(session) => return Ok(session),
+            Err(e) if max_attempts == 0 || attempt < max_attempts => {
+                let delay = backoff_delay(attempt);
+                warn!(
+                    "Database connection attempt {} failed: {}; retrying in {}ms",
+                    attempt,
+                    e,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contact_points_and_consistency() {
+        assert_eq!(
+            contact_points("10.0.0.1:9042, 10.0.0.2:9042,,"),
+            ["10.0.0.1:9042", "10.0.0.2:9042"]
+        );
+        assert_eq!(
+            parse_consistency("LOCAL_QUORUM"),
+            Some(Consistency::LocalQuorum)
+        );
+        assert_eq!(parse_consistency("local-one"), Some(Consistency::LocalOne));
+        assert_eq!(parse_consistency("most"), None);
+        assert_eq!(
+            parse_consistency(consistency_name(Consistency::EachQuorum)),
+            Some(Consistency::EachQuorum)
+        );
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        assert_eq!(request_timeout(500), Some(Duration::from_millis(500)));
+        assert_eq!(request_timeout(0), None);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(
+            backoff_delay(1),
+            Duration::from_millis(SCYLLA_CONNECT_BACKOFF_BASE_MS)
+        );
+        assert_eq!(
+            backoff_delay(2),
+            Duration::from_millis(SCYLLA_CONNECT_BACKOFF_BASE_MS * 2)
+        );
+        assert_eq!(
+            backoff_delay(40),
+            Duration::from_millis(SCYLLA_CONNECT_BACKOFF_MAX_MS)
+        );
+    }
+}
@@ -1,25 +1,194 @@
+use crate::auth_events::AuthLogLevel;
+use crate::checksum::ChecksumAlgorithm;
+
 pub const DEFAULT_HOST: &str = "0.0.0.0";
-pub const DEFAULT_PORT: &str = "8080";
+pub const DEFAULT_PORT: u16 = 8080;
+pub const DEFAULT_RATE_LIMIT_PER_SECOND: u64 = 50;
+pub const DEFAULT_RATE_LIMIT_BURST: u32 = 150;
 pub const DEFAULT_SCYLLA_URI: &str = "127.0.0.1:9042";
+pub const DEFAULT_SCYLLA_CONNECT_ATTEMPTS: u32 = 10;
+pub const DEFAULT_SCYLLA_POOL_SIZE: usize = 4;
+pub const DEFAULT_SCYLLA_CONNECTION_TIMEOUT_MS: u64 = 5_000;
+pub const DEFAULT_SCYLLA_SPECULATIVE_DELAY_MS: u64 = 100;
+pub const SCYLLA_CONNECT_BACKOFF_BASE_MS: u64 = 500;
+pub const SCYLLA_CONNECT_BACKOFF_MAX_MS: u64 = 30_000;
+pub const KEYSPACE: &str = "equicloud";
+pub const DEFAULT_STORAGE_BACKEND: &str = "scylla";
+pub const DEFAULT_SQLITE_PATH: &str = "equicloud.db";
+/// Where `DEV_MODE` stores data unless `SQLITE_PATH` is set.
+pub const DEV_SQLITE_PATH: &str = ":memory:";
+pub const DEFAULT_CONFIG_FILE: &str = ".env";
+pub const DEFAULT_REPLICATION_STRATEGY: &str = "SimpleStrategy";
+pub const DEFAULT_REPLICATION_FACTOR: u32 = 1;
 
 pub const DEFAULT_MAX_BACKUP_SIZE: usize = 62_914_560; // 60 MB
+pub const DEFAULT_DAILY_WRITE_LIMIT: u64 = 0; // unlimited
+pub const DEFAULT_MAX_KEYS_PER_USER: u64 = 0; // unlimited
+pub const DEFAULT_SETTINGS_JSON_VALIDATION: bool = false;
+pub const DEFAULT_SETTINGS_JSON_MAX_DEPTH: usize = 64;
+pub const DEFAULT_SETTINGS_JSON_MAX_SIZE: usize = 10_485_760; // 10 MB
+/// Deepest nesting `serde_json` parses.
+pub const MAX_SETTINGS_JSON_DEPTH: usize = 128;
+pub const DEFAULT_ABUSE_MAX_VIOLATIONS: u32 = 20;
+pub const DEFAULT_ABUSE_WINDOW_SECS: u64 = 10 * 60;
+pub const DEFAULT_ABUSE_BAN_SECS: u64 = 60 * 60;
+pub const DEFAULT_AUTH_LOCKOUT_MAX_FAILURES: u32 = 10;
+pub const DEFAULT_AUTH_LOCKOUT_IP_MAX_FAILURES: u32 = 50;
+pub const DEFAULT_AUTH_LOCKOUT_WINDOW_SECS: u64 = 15 * 60;
+pub const DEFAULT_AUTH_LOCKOUT_SECS: u64 = 60;
+pub const DEFAULT_AUTH_LOCKOUT_MAX_SECS: u64 = 60 * 60;
+pub const DEFAULT_BODY_LIMIT: usize = 65_536; // 64 KB
+pub const JSON_BODY_OVERHEAD: usize = 4_194_304; // 4 MB of keys and field names
+pub const ARCHIVE_BODY_OVERHEAD: usize = 1_048_576; // 1 MB of tar headers
 
 pub const DISCORD_TOKEN_URL: &str = "https://discord.com/api/oauth2/token";
 pub const DISCORD_USER_URL: &str = "https://discord.com/api/users/@me";
+pub const DISCORD_AUTHORIZE_URL: &str = "https://discord.com/oauth2/authorize";
+pub const PROVIDER_CONNECT_TIMEOUT_SECS: u64 = 5;
+pub const PROVIDER_TIMEOUT_SECS: u64 = 10;
+pub const PROVIDER_MAX_ATTEMPTS: u32 = 3;
+pub const PROVIDER_BACKOFF_BASE_MS: u64 = 200;
+pub const PROVIDER_CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+pub const PROVIDER_CIRCUIT_OPEN_SECS: u64 = 30;
+pub const DEFAULT_OAUTH_PROVIDER: &str = "discord";
+pub const DEFAULT_OIDC_SCOPES: &str = "openid";
+pub const OAUTH_STATE_TTL_SECS: i64 = 600;
+/// How long a client has to exchange the code the OAuth callback handed it.
+pub const OAUTH_CODE_TTL_SECS: i32 = 60;
+pub const DEFAULT_SESSION_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+/// Identities that may be linked to one account besides its own.
+pub const MAX_ACCOUNT_LINKS: usize = 5;
+pub const DEFAULT_PERMANENT_SECRETS_ENABLED: bool = true;
+pub const DEFAULT_SECRET_PEPPER_VERSION: u32 = 1;
+pub const DEFAULT_AUTH_LOG_LEVEL: AuthLogLevel = AuthLogLevel::Debug;
 
 pub const MS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
 pub const MS_PER_WEEK: i64 = 7 * MS_PER_DAY;
 pub const MS_PER_MONTH: i64 = 30 * MS_PER_DAY;
 
 pub const DB_HEALTH_CHECK_INTERVAL_SECS: u64 = 30;
+pub const DEFAULT_DB_CIRCUIT_BREAKER_THRESHOLD: u32 = 3;
+pub const HEALTH_PROBE_TIMEOUT_MS: u64 = 2000;
+
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_BULK_REQUEST_TIMEOUT_SECS: u64 = 5 * 60;
+pub const DEFAULT_SCYLLA_REQUEST_TIMEOUT_MS: u64 = 10_000;
+pub const DEFAULT_SCYLLA_BATCH_PARALLELISM: usize = 32;
+pub const DEFAULT_SLOW_QUERY_THRESHOLD_MS: u64 = 500;
+
+pub const DEFAULT_API_DOCS_ENABLED: bool = false;
+pub const DEFAULT_METRICS_ENABLED: bool = false;
+
+pub const DEFAULT_CORS_MAX_AGE_SECS: u64 = 10 * 60;
+pub const DEFAULT_CORS_ALLOW_CREDENTIALS: bool = false;
+
+pub const DEFAULT_TLS_PORT: u16 = 9443;
+pub const DEFAULT_TLS_REDIRECT_HTTP: bool = true;
+pub const DEFAULT_TLS_RELOAD_INTERVAL_SECS: u64 = 60;
+pub const TLS_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+pub const TLS_ACCEPT_BACKLOG: usize = 128;
+
+pub const DEFAULT_ADMIN_LIST_LIMIT: usize = 50;
+pub const DEFAULT_AUDIT_RETENTION_DAYS: u32 = 90;
+pub const DEFAULT_AUDIT_QUERY_DAYS: u32 = 7;
+pub const MAX_ADMIN_LIST_LIMIT: usize = 500;
+pub const DEFAULT_ADMIN_VALUE_ACCESS: bool = false;
+pub const MAX_API_KEY_NAME_LEN: usize = 100;
 
 pub const MAX_KEY_SIZE: usize = 1_048_576; // 1 MB
 pub const MAX_DATASTORE_KEY_SIZE: usize = 10_485_760; // 10 MB (compresses to <1MB)
 pub const MAX_KEY_NAME_LEN: usize = 256;
+pub const MAX_BATCH_KEYS: usize = 100;
+/// Largest value `/v2/data:check` sends inline unless the client asks for
+/// less, and the most it can ask for.
+pub const DEFAULT_CHECK_INLINE_BYTES: usize = 65_536; // 64 KB
+pub const MAX_CHECK_INLINE_BYTES: usize = MAX_KEY_SIZE;
+pub const MAX_MANIFEST_PAGE_SIZE: usize = 1000;
+/// Value bytes per page of a paged sync's downloads, unless the client asks
+/// for less.
+pub const SYNC_PAGE_BYTES: usize = 4 * 1_048_576; // 4 MB
+pub const MAX_SYNC_PAGE_KEYS: usize = 100;
+/// How long a paged sync's cursor can be used to fetch its downloads.
+pub const SYNC_CURSOR_TTL_SECS: i64 = 60 * 60;
+pub const DEFAULT_SYNC_HISTORY_RETENTION_SECS: u64 = 7 * 24 * 60 * 60;
+/// Syncs `/v2/sync/history` returns unless asked for fewer or more.
+pub const DEFAULT_SYNC_HISTORY_SESSIONS: usize = 20;
+pub const MAX_SYNC_HISTORY_SESSIONS: usize = 100;
+/// Rows read per page when scanning for legacy user hashes.
+pub const DEFAULT_LEGACY_SCAN_PAGE_SIZE: i32 = 1000;
+/// Rows between progress reports of a legacy scan.
+pub const DEFAULT_LEGACY_SCAN_PROGRESS_EVERY: u64 = 10_000;
+pub const DEFAULT_UPLOAD_PART_SIZE: usize = 1_048_576; // 1 MB
+pub const DEFAULT_UPLOAD_SESSION_TTL_SECS: u64 = 24 * 60 * 60;
+/// Unfinished multipart uploads one user may have at a time.
+pub const MAX_OPEN_UPLOADS: usize = 10;
+pub const DEFAULT_SHARE_TTL_SECS: u64 = 24 * 60 * 60;
+pub const MAX_SHARE_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+/// How long the response to an `Idempotency-Key` is replayed.
+pub const IDEMPOTENCY_KEY_TTL_SECS: i32 = 24 * 60 * 60;
+/// How long a key stays claimed by a request that never finished, such as
+/// one on an instance that went down. Outlasts the bulk request timeout.
+pub const IDEMPOTENCY_CLAIM_TTL_SECS: i32 = 10 * 60;
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+pub const MAX_DEVICE_ID_LEN: usize = 64;
+pub const MAX_CLIENT_NAME_LEN: usize = 128;
+pub const CONFLICT_KEY_PREFIX: &str = "conflicts/";
 pub const DEFAULT_DATASTORE_ENABLED: bool = false;
+pub const DEFAULT_TOMBSTONE_RETENTION_DAYS: u32 = 30;
+pub const DEFAULT_INACTIVITY_GRACE_DAYS: u32 = 7;
+pub const DEFAULT_ACCOUNT_DELETION_GRACE_DAYS: u32 = 14;
+pub const ACCOUNT_PURGE_INTERVAL_SECS: u64 = 60 * 60;
+pub const STATS_ROLLUP_INTERVAL_SECS: u64 = 60 * 60;
+pub const DEFAULT_STATS_EXPORT_DAYS: i64 = 30;
+pub const MAX_STATS_EXPORT_DAYS: i64 = 366;
+pub const DEFAULT_RETENTION_SWEEP_INTERVAL_SECS: u64 = 6 * 60 * 60;
+pub const DEFAULT_MAX_DATA_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+pub const SCYLLA_MAX_TTL_SECS: u64 = 20 * 365 * 24 * 60 * 60; // Scylla rejects anything longer
 
 pub const DEFAULT_ZSTD_COMPRESSION_LEVEL: i32 = 3;
 pub const CHECKSUM_BYTES: usize = 8;
+pub const DEFAULT_CHECKSUM_ALGORITHM: ChecksumAlgorithm = ChecksumAlgorithm::Sha256;
+
+pub const DELTA_MIN_BLOCK_SIZE: usize = 256;
+pub const DELTA_MAX_BLOCK_SIZE: usize = 64 * 1024;
+/// Windows whose rolling checksum matches a block but whose strong checksum
+/// does not, after which a sync download is sent whole instead of diffed.
+pub const DELTA_MAX_FALSE_MATCHES: usize = 1024;
 pub const DEFAULT_COMPRESSION_ENABLED: bool = true;
+pub const DEFAULT_RESPONSE_COMPRESSION_ENABLED: bool = true;
+pub const DEFAULT_RESPONSE_COMPRESSION_MIN_SIZE: u16 = 1024;
+pub const DEFAULT_DEDUP_ENABLED: bool = false;
+pub const DEFAULT_SETTINGS_CACHE_TTL_SECS: u64 = 60;
+pub const DEFAULT_SETTINGS_HISTORY_VERSIONS: usize = 3;
+pub const DEFAULT_HISTORY_COMPACTION_INTERVAL_SECS: u64 = 6 * 60 * 60;
+/// Block size of the binary patches served by `GET /v1/settings/diff`.
+pub const SETTINGS_DIFF_BLOCK_SIZE: usize = 1024;
+pub const DEFAULT_BLOB_STORE: &str = "scylla";
+pub const DEFAULT_S3_REGION: &str = "us-east-1";
+pub const DEFAULT_S3_PREFIX: &str = "blobs/";
+pub const DEFAULT_BACKUP_TARGET: &str = "local";
+pub const DEFAULT_BACKUP_DIR: &str = "backups";
+pub const DEFAULT_BACKUP_S3_PREFIX: &str = "backups/";
+pub const BACKUP_COMPRESSION_LEVEL: i32 = 3;
+/// Plaintext bytes per encrypted segment of a backup.
+pub const BACKUP_SEGMENT_SIZE: usize = 64 * 1024;
+/// Largest archive of one user a restore reads into memory.
+pub const MAX_RESTORE_USER_SIZE: u64 = 1024 * 1024 * 1024;
+pub const BLOB_GC_INTERVAL_SECS: u64 = 60 * 60;
+pub const BLOB_GC_GRACE_MS: i64 = 60 * 60 * 1000;
+/// Blob bodies larger than this are stored by the Scylla blob store as rows
+/// of this size, which reads can fetch one at a time.
+pub const BLOB_CHUNK_SIZE: usize = 1024 * 1024;
 
 pub const MAX_DECOMPRESSION_SIZE: usize = 10_485_760; // 10 MB
+
+pub const EVENT_BUS_CAPACITY: usize = 1024;
+
+pub const DEFAULT_WEBHOOK_MAX_ATTEMPTS: u32 = 5;
+pub const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+pub const WEBHOOK_BACKOFF_BASE_MS: u64 = 1000;
+pub const WEBHOOK_BACKOFF_MAX_MS: u64 = 5 * 60 * 1000;
+
+pub const DEFAULT_OUTBOUND_ALLOWED_SCHEMES: [&str; 1] = ["https"];
+pub const DEFAULT_OUTBOUND_ALLOWED_PORTS: [u16; 1] = [443];
+pub const OUTBOUND_MAX_REDIRECTS: usize = 5;
@@ -0,0 +1,351 @@
+//! Request bodies compressed by the client, named by `Content-Encoding`.
+//!
+//! Uploads may arrive as gzip, brotli or zstd and are decoded before any
+//! size check or checksum, so both apply to the value as stored. Decoding
+//! stops at `MAX_DECOMPRESSION_SIZE`, so a small body cannot expand into an
+//! unbounded one. [`read_body`] decodes and hashes a body while it streams
+//! in, so the encoded body is never buffered; the decoded value is still
+//! collected whole, up to the size limit it is read with.
+
+use axum::body::{Body, Bytes};
+use axum::http::HeaderMap;
+use futures::StreamExt;
+use http_body_util::LengthLimitError;
+use std::io::{self, Read, Write};
+
+use crate::checksum::{ChecksumAlgorithm, StreamingChecksum};
+use crate::constants::MAX_DECOMPRESSION_SIZE;
+use crate::error::AppError;
+use crate::validation::format_size;
+
+/// `Content-Encoding`s a request body may be sent with.
+pub const REQUEST_ENCODINGS: [&str; 3] = ["gzip", "br", "zstd"];
+
+/// Buffer size of the brotli decoder.
+const BROTLI_BUFFER: usize = 4096;
+
+/// The body as the client meant it: decoded per its `Content-Encoding`, or
+/// unchanged when it has none.
+pub fn decode_body(headers: &HeaderMap, body: Bytes) -> Result<Bytes, AppError> {
+    let Some(encoding) = headers.get("content-encoding") else {
+        return Ok(body);
+    };
+    let encoding = encoding
+        .to_str()
+        .map(|e| e.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let decoded = match encoding.as_str() {
+        "" | "identity" => return Ok(body),
+        "gzip" | "x-gzip" => read_capped(flate2::read::GzDecoder::new(&body[..])),
+        "br" => read_capped(brotli::Decompressor::new(&body[..], BROTLI_BUFFER)),
+        "zstd" => zstd::stream::Decoder::new(&body[..]).and_then(read_capped),
+        _ => {
+            return Err(AppError::UnsupportedMediaType(
+                "Content-Encoding must be gzip, br or zstd".into(),
+            ));
+        }
+    };
+
+    match decoded {
+        Ok(value) if value.len() > MAX_DECOMPRESSION_SIZE => Err(AppError::PayloadTooLarge(
+            "Body is too large once decompressed".into(),
+        )),
+        Ok(value) => Ok(Bytes::from(value)),
+        Err(_) => Err(AppError::BadRequest(format!(
+            "Body is not valid {}",
+            encoding
+        ))),
+    }
+}
+
+/// A request body as [`read_body`] decoded it.
+pub struct DecodedBody {
+    pub value: Vec<u8>,
+    /// Checksum of `value`, computed while it was decoded.
+    pub checksum: String,
+}
+
+/// Reads `body` chunk by chunk, undoing its `Content-Encoding` and hashing
+/// the decoded bytes with `algorithm` as they arrive. Reading stops as soon
+/// as the value passes `max_size`, so an oversized upload is refused without
+/// being received in full.
+pub async fn read_body(
+    headers: &HeaderMap,
+    body: Body,
+    algorithm: ChecksumAlgorithm,
+    max_size: usize,
+) -> Result<DecodedBody, AppError> {
+    let encoding = headers
+        .get("content-encoding")
+        .and_then(|e| e.to_str().ok())
+        .map(|e| e.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let sink = ValueSink {
+        value: Vec::new(),
+        checksum: StreamingChecksum::new(algorithm),
+        max_size: match encoding.as_str() {
+            "" | "identity" => max_size,
+            _ => max_size.min(MAX_DECOMPRESSION_SIZE),
+        },
+        full: false,
+    };
+    let mut decoder = match encoding.as_str() {
+        "" | "identity" => StreamDecoder::Identity(sink),
+        "gzip" | "x-gzip" => StreamDecoder::Gzip(flate2::write::GzDecoder::new(sink)),
+        "br" => StreamDecoder::Brotli(Box::new(brotli::DecompressorWriter::new(
+            sink,
+            BROTLI_BUFFER,
+        ))),
+        "zstd" => StreamDecoder::Zstd(
+            zstd::stream::write::Decoder::new(sink).map_err(|_| invalid(&encoding))?,
+        ),
+        _ => {
+            return Err(AppError::UnsupportedMediaType(
+                "Content-Encoding must be gzip, br or zstd".into(),
+            ));
+        }
+    };
+
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(read_error)?;
+        if decoder.write_all(&chunk).is_err() {
+            return Err(decoder.error(&encoding));
+        }
+    }
+    let sink = decoder.finish().map_err(|_| invalid(&encoding))?;
+    Ok(DecodedBody {
+        value: sink.value,
+        checksum: sink.checksum.finish(),
+    })
+}
+
+/// Collects and hashes the decoded value, failing once it would pass
+/// `max_size`. The value is kept whole for the write that follows, so
+/// `max_size`, not the size of a chunk, bounds what an upload holds.
+struct ValueSink {
+    value: Vec<u8>,
+    checksum: StreamingChecksum,
+    max_size: usize,
+    full: bool,
+}
+
+impl Write for ValueSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.value.len() + buf.len() > self.max_size {
+            self.full = true;
+            return Err(io::Error::other("value too large"));
+        }
+        self.checksum.update(buf);
+        self.value.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum StreamDecoder {
+    Identity(ValueSink),
+    Gzip(flate2::write::GzDecoder<ValueSink>),
+    Brotli(Box<brotli::DecompressorWriter<ValueSink>>),
+    Zstd(zstd::stream::write::Decoder<'static, ValueSink>),
+}
+
+impl StreamDecoder {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Identity(sink) => sink.write_all(data),
+            Self::Gzip(decoder) => decoder.write_all(data),
+            Self::Brotli(decoder) => decoder.write_all(data),
+            Self::Zstd(decoder) => decoder.write_all(data),
+        }
+    }
+
+    fn sink(&self) -> &ValueSink {
+        match self {
+            Self::Identity(sink) => sink,
+            Self::Gzip(decoder) => decoder.get_ref(),
+            Self::Brotli(decoder) => decoder.get_ref(),
+            Self::Zstd(decoder) => decoder.get_ref(),
+        }
+    }
+
+    /// Why a write failed: the value outgrew its limit, or the body does not
+    /// decode.
+    fn error(&self, encoding: &str) -> AppError {
+        let sink = self.sink();
+        if sink.full {
+            return AppError::PayloadTooLarge(format!(
+                "Value exceeds {} limit",
+                format_size(sink.max_size)
+            ));
+        }
+        invalid(encoding)
+    }
+
+    /// Decodes whatever input is still buffered and fails if the body ends
+    /// in the middle of a compressed stream.
+    fn finish(self) -> io::Result<ValueSink> {
+        match self {
+            Self::Identity(sink) => Ok(sink),
+            Self::Gzip(decoder) => decoder.finish(),
+            Self::Brotli(decoder) => decoder
+                .into_inner()
+                .map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Self::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+fn invalid(encoding: &str) -> AppError {
+    AppError::BadRequest(format!("Body is not valid {}", encoding))
+}
+
+/// A failure to read the body: past the route's body limit, or cut off.
+fn read_error(e: axum::Error) -> AppError {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&e);
+    while let Some(e) = source {
+        if e.is::<LengthLimitError>() {
+            return AppError::PayloadTooLarge("Request body is too large".into());
+        }
+        source = e.source();
+    }
+    AppError::BadRequest("Failed to read request body".into())
+}
+
+/// Reads at most one byte past `MAX_DECOMPRESSION_SIZE`, enough to tell a
+/// body over the limit from one at it.
+fn read_capped(decoder: impl Read) -> std::io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    decoder
+        .take(MAX_DECOMPRESSION_SIZE as u64 + 1)
+        .read_to_end(&mut output)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn encoded(encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-encoding", encoding.parse().unwrap());
+        headers
+    }
+
+    fn gzip(value: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(value).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode_body() {
+        let value = b"settings settings settings".to_vec();
+        let mut br = Vec::new();
+        brotli::CompressorWriter::new(&mut br, BROTLI_BUFFER, 5, 22)
+            .write_all(&value)
+            .unwrap();
+        let zstd = zstd::encode_all(&value[..], 3).unwrap();
+
+        for (encoding, body) in [("gzip", gzip(&value)), ("br", br), ("zstd", zstd)] {
+            let decoded = decode_body(&encoded(encoding), Bytes::from(body)).unwrap();
+            assert_eq!(decoded, value, "{}", encoding);
+        }
+        let plain = decode_body(&HeaderMap::new(), Bytes::from(value.clone())).unwrap();
+        assert_eq!(plain, value);
+    }
+
+    #[test]
+    fn test_decode_body_rejects_bad_bodies() {
+        assert!(matches!(
+            decode_body(&encoded("deflate"), Bytes::new()),
+            Err(AppError::UnsupportedMediaType(_))
+        ));
+        assert!(matches!(
+            decode_body(&encoded("gzip"), Bytes::from_static(b"not gzip")),
+            Err(AppError::BadRequest(_))
+        ));
+
+        let bomb = gzip(&vec![0u8; MAX_DECOMPRESSION_SIZE + 1]);
+        assert!(bomb.len() < 64 * 1024);
+        assert!(matches!(
+            decode_body(&encoded("gzip"), Bytes::from(bomb)),
+            Err(AppError::PayloadTooLarge(_))
+        ));
+    }
+
+    /// `data` as a body that arrives in small chunks.
+    fn chunked(data: Vec<u8>) -> Body {
+        let chunks: Vec<Result<Bytes, io::Error>> = data
+            .chunks(7)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let value = b"settings settings settings".to_vec();
+        let checksum = ChecksumAlgorithm::Xxh3.compute(&value);
+        let zstd = zstd::encode_all(&value[..], 3).unwrap();
+
+        for (headers, body) in [
+            (HeaderMap::new(), value.clone()),
+            (encoded("gzip"), gzip(&value)),
+            (encoded("zstd"), zstd),
+        ] {
+            let decoded = read_body(&headers, chunked(body), ChecksumAlgorithm::Xxh3, 1024)
+                .await
+                .unwrap();
+            assert_eq!(decoded.value, value);
+            assert_eq!(decoded.checksum, checksum);
+        }
+
+        let too_large = read_body(
+            &HeaderMap::new(),
+            chunked(value.clone()),
+            ChecksumAlgorithm::Sha256,
+            10,
+        )
+        .await;
+        assert!(matches!(too_large, Err(AppError::PayloadTooLarge(_))));
+        let bomb = gzip(&vec![0u8; 1024 * 1024]);
+        let bomb = read_body(
+            &encoded("gzip"),
+            chunked(bomb),
+            ChecksumAlgorithm::Sha256,
+            1024,
+        )
+        .await;
+        assert!(matches!(bomb, Err(AppError::PayloadTooLarge(_))));
+
+        let truncated = gzip(&value)[..10].to_vec();
+        let truncated = read_body(
+            &encoded("gzip"),
+            chunked(truncated),
+            ChecksumAlgorithm::Sha256,
+            1024,
+        )
+        .await;
+        assert!(matches!(truncated, Err(AppError::BadRequest(_))));
+        let unsupported = read_body(
+            &encoded("deflate"),
+            Body::empty(),
+            ChecksumAlgorithm::Sha256,
+            1024,
+        )
+        .await;
+        assert!(matches!(
+            unsupported,
+            Err(AppError::UnsupportedMediaType(_))
+        ));
+    }
+}
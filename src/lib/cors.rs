@@ -0,0 +1,140 @@
+//! Which browsers may call which routes.
+//!
+//! Routes are split into groups with their own policy: the API that clients
+//! call follows `CORS_ALLOWED_ORIGINS`, while `/admin` only answers the
+//! origins in `CORS_ADMIN_ALLOWED_ORIGINS` and none when unset.
+
+use http::header::{
+    ACCEPT_RANGES, AUTHORIZATION, CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderName, IF_MATCH,
+    IF_NONE_MATCH, IF_RANGE, RANGE, RETRY_AFTER,
+};
+use http::{HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::tenant::TENANT_HEADER;
+
+/// Routes sharing a CORS policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteGroup {
+    Api,
+    Admin,
+}
+
+impl RouteGroup {
+    fn origins(self, config: &Config) -> Option<&str> {
+        match self {
+            RouteGroup::Api => config.cors_allowed_origins.as_deref(),
+            RouteGroup::Admin => config.cors_admin_allowed_origins.as_deref(),
+        }
+    }
+
+    fn methods(self) -> &'static [Method] {
+        match self {
+            RouteGroup::Api => &[
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::HEAD,
+                Method::OPTIONS,
+            ],
+            RouteGroup::Admin => &[Method::GET, Method::POST, Method::PUT, Method::DELETE],
+        }
+    }
+
+    fn allow_headers(self) -> Vec<HeaderName> {
+        match self {
+            RouteGroup::Api => vec![
+                CONTENT_TYPE,
+                AUTHORIZATION,
+                IF_NONE_MATCH,
+                IF_MATCH,
+                RANGE,
+                IF_RANGE,
+                HeaderName::from_static(TENANT_HEADER),
+                HeaderName::from_static("x-checksum"),
+            ],
+            RouteGroup::Admin => vec![
+                CONTENT_TYPE,
+                AUTHORIZATION,
+                HeaderName::from_static(TENANT_HEADER),
+            ],
+        }
+    }
+
+    /// Response headers scripts may read besides the CORS-safelisted ones.
+    fn expose_headers(self) -> Vec<HeaderName> {
+        match self {
+            RouteGroup::Api => vec![
+                ETAG,
+                HeaderName::from_static("x-version"),
+                ACCEPT_RANGES,
+                CONTENT_RANGE,
+                HeaderName::from_static("x-request-id"),
+                HeaderName::from_static("x-patch-block-size"),
+                HeaderName::from_static("x-write-limit"),
+                HeaderName::from_static("x-write-remaining"),
+                HeaderName::from_static("x-write-reset"),
+                RETRY_AFTER,
+                HeaderName::from_static("x-checksum-status"),
+            ],
+            RouteGroup::Admin => vec![HeaderName::from_static("x-request-id")],
+        }
+    }
+}
+
+/// The CORS layer for `group`.
+///
+/// `*` lets any origin in without credentials, as does leaving
+/// `CORS_ALLOWED_ORIGINS` unset for the API. Credentials are only allowed
+/// for explicit origins, since browsers refuse them with a wildcard.
+pub fn layer(config: &Config, group: RouteGroup) -> CorsLayer {
+    let origins = match (group.origins(config), group) {
+        (None, RouteGroup::Admin) => return CorsLayer::new(),
+        (None, RouteGroup::Api) => {
+            warn!("CORS_ALLOWED_ORIGINS not set - defaulting to permissive for development");
+            return with_max_age(CorsLayer::permissive(), config);
+        }
+        (Some("*"), _) => {
+            warn!(
+                "CORS configured for all origins on {:?} routes - use specific origins in production!",
+                group
+            );
+            return with_max_age(CorsLayer::permissive(), config);
+        }
+        (Some(origins), _) => origins,
+    };
+    let origins: Vec<HeaderValue> = origins
+        .split(',')
+        .filter_map(|s| s.trim().parse().ok())
+        .collect();
+    if origins.is_empty() {
+        warn!(
+            "No valid CORS origins parsed for {:?} routes, CORS will reject cross-origin requests",
+            group
+        );
+        return CorsLayer::new();
+    }
+    info!(
+        "CORS configured for {} origins on {:?} routes",
+        origins.len(),
+        group
+    );
+    let layer = CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods(group.methods().to_vec())
+        .allow_headers(group.allow_headers())
+        .expose_headers(group.expose_headers())
+        .allow_credentials(config.cors_allow_credentials);
+    with_max_age(layer, config)
+}
+
+fn with_max_age(layer: CorsLayer, config: &Config) -> CorsLayer {
+    if config.cors_max_age.is_zero() {
+        layer
+    } else {
+        layer.max_age(config.cors_max_age)
+    }
+}
@@ -1,12 +1,35 @@
-use crate::hash_migration::legacy;
-use crate::utils::{CONFIG, compress, decompress, hash_user_id, validate_key};
+use crate::api_keys::ApiKeyScope;
+use crate::audit::{AuditEntry, day_bucket};
+use crate::blob_store::{BlobBody, BlobStore, Blobs, ChunkStream};
+use crate::cache::{CacheStats, SettingsCache};
+use crate::checksum;
+use crate::compaction::{CompactionStats, HistoryPolicy};
+use crate::constants::HEALTH_PROBE_TIMEOUT_MS;
+use crate::driver_metrics::DriverStats;
+use crate::hash_migration::{self, ScanOptions, ScanPhase, legacy};
+use crate::key_locks::KeyLocks;
+use crate::metrics::{BatchLatency, BatchStats, QueryStats};
+use crate::oauth::{OAuthRedirect, RedirectMode};
+use crate::timed_session::{StatementNames, TimedSession};
+use crate::utils::{
+    Config, ConfigHandle, StreamDecompressor, compress, content_hash, decompress, hash_user_id,
+};
+use crate::validation::{check_size, validate_key};
 use anyhow::Result;
-use futures::{future::join_all, join};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::{TryStreamExt, join};
 use scylla::client::session::Session;
+use scylla::response::query_result::QueryResult;
+use scylla::response::{PagingState, PagingStateResponse};
+use scylla::statement::batch::Batch;
 use scylla::statement::prepared::PreparedStatement;
+use scylla::value::{Counter, Row};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{info, warn};
+use utoipa::ToSchema;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataEntry {
@@ -19,722 +42,4286 @@ pub struct DataEntry {
     pub updated_at: i64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A value read to be served.
+pub enum DataRead {
+    Loaded(DataEntry),
+    /// A value whose blob is stored in chunks. `entry.value` is empty; the
+    /// chunks, decompressed, are fetched one at a time as `chunks` is
+    /// polled.
+    Streamed {
+        entry: DataEntry,
+        chunks: ChunkStream,
+    },
+}
+
+impl DataRead {
+    /// The value's key, version and checksum; its `value` is empty when
+    /// streamed.
+    pub fn entry(&self) -> &DataEntry {
+        match self {
+            Self::Loaded(entry) | Self::Streamed { entry, .. } => entry,
+        }
+    }
+
+    /// The whole entry, fetching any chunks still to come.
+    pub async fn into_entry(self) -> Result<DataEntry> {
+        match self {
+            Self::Loaded(entry) => Ok(entry),
+            Self::Streamed { mut entry, chunks } => {
+                entry.value = BlobBody::Chunks(chunks).into_vec().await?;
+                Ok(entry)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct DataManifestEntry {
     pub key: String,
     pub version: i64,
     pub checksum: String,
     pub size_bytes: i32,
     pub updated_at: i64,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// The device that last wrote the key, if it named itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_writer: Option<LastWriter>,
 }
 
-fn check_key(key: &str) -> Result<()> {
-    validate_key(key).map_err(|e| anyhow::anyhow!(e.message()))
+/// One page of a user's manifest and the opaque cursor for the next one.
+#[derive(Debug, Clone, Default)]
+pub struct ManifestPage {
+    pub entries: Vec<DataManifestEntry>,
+    pub next_cursor: Option<Vec<u8>>,
 }
 
-fn get_legacy_key_if_different(user_id: &str, new_key: &str) -> Option<String> {
-    let legacy_key = legacy::hash_user_id(user_id);
-    if legacy_key != new_key {
-        Some(legacy_key)
-    } else {
-        None
+type ManifestRow = (
+    String,
+    i64,
+    String,
+    i32,
+    i64,
+    Option<bool>,
+    Option<i64>,
+    Option<i64>,
+);
+
+/// A `data` row without its key, with its remaining TTL.
+type DataRow = (
+    Vec<u8>,
+    i64,
+    String,
+    i32,
+    i64,
+    i64,
+    Option<bool>,
+    Option<i64>,
+    Option<String>,
+    Option<i32>,
+);
+
+/// A full `data` row as moved off a legacy hash, with its remaining TTL.
+type LegacyDataRow = (
+    String,
+    Vec<u8>,
+    i64,
+    String,
+    i32,
+    i64,
+    i64,
+    Option<bool>,
+    Option<i64>,
+    Option<i64>,
+    Option<String>,
+    Option<i32>,
+);
+
+fn manifest_entry(row: ManifestRow) -> DataManifestEntry {
+    let (key, version, checksum, size_bytes, updated_at, deleted, deleted_at, expires_at) = row;
+    DataManifestEntry {
+        key,
+        version,
+        checksum,
+        size_bytes,
+        updated_at,
+        deleted: deleted.unwrap_or(false),
+        deleted_at,
+        expires_at,
+        last_writer: None,
     }
 }
 
-struct PreparedStatements {
-    get_user_updated_at: PreparedStatement,
-    get_user_settings: PreparedStatement,
-    insert_user_settings: PreparedStatement,
-    delete_user: PreparedStatement,
-    get_user_created_at: PreparedStatement,
-    get_data_manifest: PreparedStatement,
-    get_data_key: PreparedStatement,
-    get_data_version: PreparedStatement,
-    get_data_version_and_size: PreparedStatement,
-    insert_data_key: PreparedStatement,
-    delete_data_key: PreparedStatement,
-    delete_all_data: PreparedStatement,
-    get_user_total_size: PreparedStatement,
-    get_key_size: PreparedStatement,
-    health_check: PreparedStatement,
+/// A v2 value to be written, with an optional TTL after which Scylla drops
+/// the row.
+#[derive(Debug, Clone)]
+pub struct DataUpload {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub checksum: String,
+    pub ttl_secs: Option<i32>,
 }
 
-#[derive(Clone)]
-pub struct DatabaseService {
-    session: Arc<Session>,
-    prepared: Arc<PreparedStatements>,
+/// Everything stored for one user, read by their hashed id for backups.
+#[derive(Debug, Clone, Default)]
+pub struct UserSnapshot {
+    /// The settings backup and when it was written.
+    pub settings: Option<(Vec<u8>, i64)>,
+    /// Live data keys, each with when it expires if it does.
+    pub data: Vec<(DataEntry, Option<i64>)>,
 }
 
-impl DatabaseService {
-    pub async fn new(session: Session) -> Result<Self> {
-        session.use_keyspace("equicloud", false).await?;
+pub(crate) fn expiry(now: i64, ttl_secs: Option<i32>) -> (Option<i64>, i32) {
+    match ttl_secs {
+        Some(ttl) if ttl > 0 => (Some(now + ttl as i64 * 1000), ttl),
+        _ => (None, 0),
+    }
+}
 
-        let prepared = PreparedStatements {
-            get_user_updated_at: session
-                .prepare("SELECT updated_at FROM users WHERE id = ?")
-                .await?,
-            get_user_settings: session
-                .prepare("SELECT settings, updated_at FROM users WHERE id = ?")
-                .await?,
-            insert_user_settings: session
-                .prepare("INSERT INTO users (id, settings, created_at, updated_at) VALUES (?, ?, ?, ?)")
-                .await?,
-            delete_user: session
-                .prepare("DELETE FROM users WHERE id = ?")
-                .await?,
-            get_user_created_at: session
-                .prepare("SELECT created_at FROM users WHERE id = ?")
-                .await?,
-            get_data_manifest: session
-                .prepare("SELECT key, version, checksum, size_bytes, updated_at FROM data WHERE user_id = ?")
-                .await?,
-            get_data_key: session
-                .prepare("SELECT key, value, version, checksum, size_bytes, created_at, updated_at FROM data WHERE user_id = ? AND key = ?")
-                .await?,
-            get_data_version: session
-                .prepare("SELECT version, created_at FROM data WHERE user_id = ? AND key = ?")
-                .await?,
-            get_data_version_and_size: session
-                .prepare("SELECT version, created_at, size_bytes FROM data WHERE user_id = ? AND key = ?")
-                .await?,
-            insert_data_key: session
-                .prepare("INSERT INTO data (user_id, key, value, version, checksum, size_bytes, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
-                .await?,
-            delete_data_key: session
-                .prepare("DELETE FROM data WHERE user_id = ? AND key = ?")
-                .await?,
-            delete_all_data: session
-                .prepare("DELETE FROM data WHERE user_id = ?")
-                .await?,
-            get_user_total_size: session
-                .prepare("SELECT SUM(size_bytes) FROM data WHERE user_id = ?")
-                .await?,
-            get_key_size: session
-                .prepare("SELECT size_bytes FROM data WHERE user_id = ? AND key = ?")
-                .await?,
-            health_check: session
-                .prepare("SELECT now() FROM system.local")
-                .await?,
-        };
+/// Outcome of a write made conditional on the stored version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConditionalWrite {
+    Saved {
+        version: i64,
+        updated_at: i64,
+    },
+    QuotaExceeded,
+    /// The key was written since its version was read.
+    Changed,
+}
 
-        Ok(Self {
-            session: Arc::new(session),
-            prepared: Arc::new(prepared),
-        })
-    }
+/// Outcome of moving a key to a new name.
+#[derive(Debug, Clone)]
+pub enum RenameOutcome {
+    Renamed {
+        /// Version of the value under its new name.
+        version: i64,
+        updated_at: i64,
+        /// Version of the tombstone left under the old name.
+        tombstone_version: i64,
+    },
+    /// The key to rename does not exist.
+    NotFound,
+    /// The target key holds a value written after the one being moved.
+    TargetNewer(DataManifestEntry),
+    /// Either key was written while the rename was under way.
+    Changed,
+}
 
-    pub fn session(&self) -> &Session {
-        &self.session
-    }
+/// Version a renamed value gets under its new name: its own, unless the
+/// target already has a row, whose version it must go past for devices that
+/// synced that row to notice the change.
+pub(crate) fn renamed_version(source: i64, target: Option<i64>) -> i64 {
+    target.map_or(source, |target| source.max(target + 1))
+}
 
-    pub async fn health_check(&self) -> Result<()> {
-        self.session
-            .execute_unpaged(&self.prepared.health_check, &[])
-            .await?;
-        Ok(())
+/// Version, creation time and blob reference of a stored key.
+pub type ExistingVersions = HashMap<String, (i64, i64, Option<String>)>;
+
+/// Writes `value` to the blob store when dedup or S3 is enabled, returning
+/// what the data row should hold: the compressed value inline, or an empty
+/// value plus the blob hash.
+async fn store_value(
+    config: &Config,
+    session: &TimedSession,
+    prepared: &PreparedStatements,
+    blobs: &Blobs,
+    value: &[u8],
+    ttl: i32,
+    now: i64,
+) -> Result<(Vec<u8>, Option<String>)> {
+    // Rows with a TTL disappear without a delete, so they could never give
+    // their reference back.
+    if !config.values_in_blob_store() || ttl > 0 || value.is_empty() {
+        return Ok((compress(config, value), None));
     }
 
-    pub async fn get_settings_metadata(&self, user_id: &str) -> Result<Option<String>> {
-        let hash_key = hash_user_id(user_id);
+    let hash = content_hash(value);
+    blobs.put(&hash, &compress(config, value)).await?;
+    session
+        .execute_unpaged(&prepared.insert_blob, (&hash, value.len() as i32, now))
+        .await?;
+    session
+        .execute_unpaged(&prepared.increment_blob_refs, (&hash,))
+        .await?;
+    Ok((Vec::new(), Some(hash)))
+}
 
-        if let Some(updated_at) = self.query_updated_at(&hash_key).await? {
-            return Ok(Some(updated_at.to_string()));
-        }
+/// Whether a lightweight transaction was applied, from its `[applied]`
+/// column.
+fn lwt_applied(result: QueryResult) -> Result<bool> {
+    Ok(result
+        .into_rows_result()?
+        .maybe_first_row::<Row>()?
+        .and_then(|row| row.columns.into_iter().next().flatten())
+        .and_then(|applied| applied.as_boolean())
+        == Some(true))
+}
 
-        if let Some(legacy_key) = get_legacy_key_if_different(user_id, &hash_key)
-            && let Some(updated_at) = self.query_updated_at(&legacy_key).await?
-        {
-            warn!("Found legacy data for user, will migrate on next write");
-            return Ok(Some(updated_at.to_string()));
-        }
+/// Drops the reference a data row held before it was overwritten or
+/// deleted. Failures only leak the blob, so they are logged, not returned.
+async fn release_blob(
+    session: &TimedSession,
+    prepared: &PreparedStatements,
+    previous: Option<&str>,
+) {
+    let Some(hash) = previous else {
+        return;
+    };
+    if let Err(e) = session
+        .execute_unpaged(&prepared.decrement_blob_refs, (hash,))
+        .await
+    {
+        warn!("Failed to release blob {}: {}", hash, e);
+    }
+}
 
-        Ok(None)
+async fn load_value(blobs: &Blobs, stored: Vec<u8>, blob_hash: Option<String>) -> Result<Vec<u8>> {
+    let Some(hash) = blob_hash else {
+        return Ok(decompress(&stored));
+    };
+
+    match blobs.get(&hash).await? {
+        Some(value) => Ok(decompress(&value)),
+        None => Err(anyhow::anyhow!("Blob {} is missing", hash)),
     }
+}
 
-    async fn query_updated_at(&self, key: &str) -> Result<Option<i64>> {
-        let result = self
-            .session
-            .execute_unpaged(&self.prepared.get_user_updated_at, (key,))
-            .await?;
-        let rows_result = result.into_rows_result()?;
-        if let Some(row) = rows_result.rows::<(i64,)>()?.next() {
-            let (updated_at,) = row?;
-            return Ok(Some(updated_at));
+/// [`load_value`] that leaves a blob stored in chunks unread, returning a
+/// stream that fetches and decompresses them as it is polled.
+async fn open_value(blobs: &Blobs, stored: Vec<u8>, blob_hash: Option<String>) -> Result<BlobBody> {
+    let Some(hash) = blob_hash else {
+        return Ok(BlobBody::Whole(decompress(&stored)));
+    };
+
+    match blobs.open(&hash).await? {
+        Some(BlobBody::Whole(value)) => Ok(BlobBody::Whole(decompress(&value))),
+        Some(BlobBody::Chunks(chunks)) => {
+            let mut decompressor = StreamDecompressor::new();
+            Ok(BlobBody::Chunks(
+                chunks
+                    .and_then(move |chunk| {
+                        let decoded = decompressor.feed(&chunk).map(bytes::Bytes::from);
+                        async move { Ok(decoded?) }
+                    })
+                    .boxed(),
+            ))
         }
-        Ok(None)
+        None => Err(anyhow::anyhow!("Blob {} is missing", hash)),
     }
+}
 
-    pub async fn get_user_settings(&self, user_id: &str) -> Result<Option<(Vec<u8>, String)>> {
-        let hash_key = hash_user_id(user_id);
+/// Runs `futures` with at most `parallelism` of them in flight and returns
+/// their outputs in the original order, failing on the first error. Every
+/// query of a batch goes to the user's partition, which the token-aware
+/// load balancing sends straight to one of its replicas.
+async fn run_bounded<T, F>(
+    futures: impl IntoIterator<Item = F>,
+    parallelism: usize,
+) -> Result<Vec<T>>
+where
+    F: Future<Output = Result<T>>,
+{
+    let mut pending = futures
+        .into_iter()
+        .enumerate()
+        .map(|(i, future)| async move { (i, future.await) });
+    let mut running: FuturesUnordered<_> = pending.by_ref().take(parallelism).collect();
 
-        if let Some((settings, updated_at)) = self.query_settings(&hash_key).await? {
-            return Ok(Some((settings, updated_at.to_string())));
+    let mut outputs = Vec::new();
+    while let Some((i, output)) = running.next().await {
+        outputs.push((i, output?));
+        if let Some(next) = pending.next() {
+            running.push(next);
         }
+    }
+    outputs.sort_unstable_by_key(|(i, _)| *i);
+    Ok(outputs.into_iter().map(|(_, output)| output).collect())
+}
 
-        if let Some(legacy_key) = get_legacy_key_if_different(user_id, &hash_key)
-            && let Some((settings, updated_at)) = self.query_settings(&legacy_key).await?
-        {
-            info!(
-                "Found legacy settings for user {}, migrating to new hash format",
-                user_id
-            );
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StorageUsage {
+    pub settings_size: Option<i64>,
+    pub settings_updated_at: Option<i64>,
+    pub data_keys: i64,
+    pub data_size: i64,
+}
 
-            if let Err(e) = self
-                .migrate_user_data(user_id, &legacy_key, &hash_key, &settings, updated_at)
-                .await
-            {
-                warn!("Failed to migrate user data: {}", e);
-            }
+/// What a full account purge removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AccountPurge {
+    /// Whether the user had settings stored.
+    pub settings: bool,
+    /// Live data keys removed; tombstones are not counted.
+    pub data_keys: u64,
+    /// Whether a storage quota override was removed.
+    pub quota_override: bool,
+    /// Whether a stored OAuth refresh token was removed.
+    pub refresh_token: bool,
+    /// Linked identities that no longer sign in to the account.
+    pub linked_identities: u64,
+}
 
-            return Ok(Some((settings, updated_at.to_string())));
-        }
+/// How recently a user wrote and how much they store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct UserActivity {
+    /// Hashed id of the user.
+    pub user: String,
+    /// Last write of settings or data, in milliseconds; 0 if never.
+    pub last_active: i64,
+    /// Bytes of settings and live data stored.
+    pub size_bytes: i64,
+}
 
-        Ok(None)
-    }
+/// Another identity that signs in to an account and shares its storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AccountLink {
+    /// Hashed id of the linked identity.
+    pub user: String,
+    pub linked_at: i64,
+}
 
-    async fn query_settings(&self, key: &str) -> Result<Option<(Vec<u8>, i64)>> {
-        let result = self
-            .session
-            .execute_unpaged(&self.prepared.get_user_settings, (key,))
-            .await?;
-        let rows_result = result.into_rows_result()?;
-        if let Some(row) = rows_result.rows::<(Vec<u8>, i64)>()?.next() {
-            let (settings, updated_at) = row?;
-            return Ok(Some((settings, updated_at)));
+/// A user refused on every authenticated request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Ban {
+    /// Hashed id of the banned user.
+    pub user: String,
+    pub reason: String,
+    pub banned_at: i64,
+    /// When the ban lifts; `None` for one that lasts until removed.
+    pub expires_at: Option<i64>,
+    /// Whether the abuse monitor placed it rather than an admin.
+    pub automatic: bool,
+}
+
+/// A subject refused sign-in until `locked_until` after repeated failures;
+/// see [`crate::lockout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthLockout {
+    pub subject: String,
+    pub locked_until: i64,
+    /// Failures counted when it was locked out.
+    pub failures: u32,
+}
+
+/// An account its user deleted, kept until `purge_at` so it can be
+/// restored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct PendingDeletion {
+    #[serde(skip)]
+    pub user_id: String,
+    pub requested_at: i64,
+    /// When the account is purged unless restored first.
+    pub purge_at: i64,
+}
+
+/// Usage aggregated over one UTC day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DailyStats {
+    /// The day, as `YYYY-MM-DD`.
+    pub day: String,
+    /// Users who made an authenticated request that day.
+    pub active_users: i64,
+    /// Users with settings or data stored when the day was rolled up.
+    pub users: i64,
+    /// Bytes of settings and live data stored when the day was rolled up.
+    pub bytes_stored: i64,
+    /// Syncs made that day.
+    pub syncs: i64,
+    /// When the day was last rolled up, in milliseconds.
+    pub computed_at: i64,
+}
+
+/// A device that wrote or synced for a user, named by its `X-Device-Id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Device {
+    pub device_id: String,
+    /// Its `X-Client-Name`, as last sent.
+    pub client_name: Option<String>,
+    pub last_seen_at: i64,
+}
+
+/// The device that last wrote a key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LastWriter {
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    pub written_at: i64,
+}
+
+/// Summary of one sync, kept for `SYNC_HISTORY_RETENTION` so a client can
+/// see what the server made of its recent syncs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SyncSession {
+    pub started_at: i64,
+    pub duration_ms: i64,
+    /// The `X-Device-Id` the sync was sent with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    /// Values stored.
+    pub uploads: u32,
+    /// Values sent back, or for a paged sync, left to fetch page by page.
+    pub downloads: u32,
+    /// Keys deleted.
+    pub deletions: u32,
+    pub conflicts: u32,
+    /// Keys refused or failed.
+    pub errors: u32,
+    pub bytes_uploaded: i64,
+    pub bytes_downloaded: i64,
+}
+
+/// A key a headless client authenticates with as `user_id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// Unhashed id of the user the key acts as.
+    #[serde(skip)]
+    pub user_id: String,
+    /// Hashed id of the user the key acts as.
+    pub user: String,
+    /// SHA-256 of the key's secret.
+    #[serde(skip)]
+    pub key_hash: String,
+    pub scope: ApiKeyScope,
+    pub created_at: i64,
+}
+
+/// A pending authorization started by `/v1/oauth/authorize`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OAuthState {
+    /// PKCE verifier, if the authorization used PKCE.
+    pub code_verifier: Option<String>,
+    /// Where the callback sends the browser back to, if anywhere.
+    pub redirect: Option<OAuthRedirect>,
+}
+
+impl OAuthState {
+    /// The state from its stored columns. A redirect whose mode this version
+    /// does not know is dropped rather than failing the sign-in.
+    pub(crate) fn from_columns(
+        code_verifier: Option<String>,
+        redirect_uri: Option<String>,
+        redirect_mode: Option<String>,
+    ) -> Self {
+        let redirect = redirect_uri.and_then(|uri| {
+            let mode = match redirect_mode {
+                Some(mode) => RedirectMode::parse(&mode)?,
+                None => RedirectMode::Code,
+            };
+            Some(OAuthRedirect { uri, mode })
+        });
+        Self {
+            code_verifier,
+            redirect,
         }
-        Ok(None)
     }
+}
 
-    pub async fn save_user_settings(&self, user_id: &str, settings: Vec<u8>) -> Result<i64> {
-        let hash_key = hash_user_id(user_id);
-        let now = chrono::Utc::now().timestamp_millis();
+/// A one-time code handed to a client by the OAuth callback, exchanged for a
+/// session at `/v1/oauth/token`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OAuthCode {
+    /// Unhashed id of the user who signed in.
+    pub user_id: String,
+    /// Scopes of the session to issue, as `TokenScopes::encode` writes them.
+    pub scopes: String,
+}
 
-        self.session
-            .execute_unpaged(
-                &self.prepared.insert_user_settings,
-                (&hash_key, &settings, now, now),
-            )
-            .await?;
+/// A share link to one data key, looked up by the id in its token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataShare {
+    pub id: String,
+    /// Unhashed id of the owner, whose storage the value is read from.
+    pub user_id: String,
+    pub key: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    /// Downloads allowed before the link stops working; `None` for any.
+    pub max_downloads: Option<i32>,
+}
 
-        self.cleanup_legacy_data(user_id, &hash_key).await;
+/// A response kept for an `Idempotency-Key` and replayed to retries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
 
-        Ok(now)
-    }
+/// What is known about an `Idempotency-Key` already used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencyRecord {
+    /// Identifies the request the key was first used for.
+    pub fingerprint: String,
+    /// `None` while that request is still running.
+    pub response: Option<StoredResponse>,
+}
 
-    pub async fn delete_user_settings(&self, user_id: &str) -> Result<()> {
-        let hash_key = hash_user_id(user_id);
+#[derive(Debug, Clone, Serialize)]
+pub struct UserSummary {
+    pub id: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
 
-        self.session
-            .execute_unpaged(&self.prepared.delete_user, (&hash_key,))
-            .await?;
+/// A users row as seen by the retention sweeper, keyed by the hashed id.
+#[derive(Debug, Clone)]
+pub struct RetentionCandidate {
+    pub user_hash: String,
+    pub updated_at: i64,
+    pub retention_marked_at: Option<i64>,
+}
 
-        self.cleanup_legacy_data(user_id, &hash_key).await;
+/// Value counts from one pass of the scrubber, for `/metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrubStats {
+    pub checked: u64,
+    pub corrupt: u64,
+    pub unreadable: u64,
+}
 
-        Ok(())
+/// The corruption report for `entry` if its value no longer matches the
+/// checksum stored with it.
+pub fn find_corruption(entry: &DataEntry) -> Option<CorruptEntry> {
+    let computed = checksum::recompute(&entry.checksum, &entry.value);
+    (computed != entry.checksum).then(|| CorruptEntry {
+        key: entry.key.clone(),
+        version: entry.version,
+        stored_checksum: entry.checksum.clone(),
+        computed_checksum: computed,
+        detected_at: chrono::Utc::now().timestamp_millis(),
+    })
+}
+
+/// A stored value whose checksum did not match when it was read back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorruptEntry {
+    pub key: String,
+    pub version: i64,
+    pub stored_checksum: String,
+    pub computed_checksum: String,
+    pub detected_at: i64,
+}
+
+/// A multipart upload of one data key that has not been completed yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadSession {
+    pub id: String,
+    pub key: String,
+    /// Size of the assembled value.
+    pub size_bytes: i64,
+    /// Size of every part but the last, fixed when the upload is created.
+    pub part_size: i32,
+    /// Checksum the assembled value must have, if the client gave one.
+    pub checksum: Option<String>,
+    /// TTL the assembled value is written with.
+    pub ttl_secs: Option<i32>,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+impl UploadSession {
+    pub fn part_count(&self) -> i32 {
+        (self.size_bytes.max(0) as u64).div_ceil(self.part_size.max(1) as u64) as i32
     }
 
-    async fn cleanup_legacy_data(&self, user_id: &str, new_key: &str) {
-        if let Some(legacy_key) = get_legacy_key_if_different(user_id, new_key)
-            && let Err(e) = self.delete_legacy_data(&legacy_key).await
-        {
-            warn!("Failed to clean up legacy data: {}", e);
+    /// Length part `number` must have; parts are numbered from 1. `None` if
+    /// the upload has no such part.
+    pub fn part_len(&self, number: i32) -> Option<i64> {
+        let count = self.part_count();
+        if number < 1 || number > count {
+            return None;
         }
+        let before = (number as i64 - 1) * self.part_size as i64;
+        Some((self.size_bytes - before).min(self.part_size as i64))
     }
 
-    async fn migrate_user_data(
-        &self,
-        user_id: &str,
-        legacy_key: &str,
-        new_key: &str,
-        settings: &[u8],
-        updated_at: i64,
-    ) -> Result<()> {
-        info!("Migrating user {} from legacy hash to SHA-256", user_id);
+    /// Seconds until the upload expires, for the TTL of rows written at
+    /// `now`; `None` once it has.
+    pub(crate) fn remaining_ttl(&self, now: i64) -> Option<i32> {
+        let remaining_ms = self.expires_at - now;
+        (remaining_ms > 0).then(|| ((remaining_ms + 999) / 1000) as i32)
+    }
+}
 
-        let result = self
-            .session
-            .execute_unpaged(&self.prepared.get_user_created_at, (legacy_key,))
-            .await?;
-        let rows_result = result.into_rows_result()?;
+/// A part received for an upload, without its bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadPart {
+    pub number: i32,
+    pub size_bytes: i32,
+    pub checksum: String,
+}
 
-        let created_at = rows_result
-            .rows::<(i64,)>()?
-            .next()
-            .transpose()?
-            .map(|row| row.0)
-            .unwrap_or(updated_at);
+type UploadSessionRow = (
+    String,
+    String,
+    i64,
+    i32,
+    Option<String>,
+    Option<i32>,
+    i64,
+    i64,
+);
 
-        self.session
-            .execute_unpaged(
-                &self.prepared.insert_user_settings,
-                (new_key, settings, created_at, updated_at),
-            )
-            .await?;
+fn upload_session(row: UploadSessionRow) -> UploadSession {
+    let (id, key, size_bytes, part_size, checksum, ttl_secs, created_at, expires_at) = row;
+    UploadSession {
+        id,
+        key,
+        size_bytes,
+        part_size,
+        checksum,
+        ttl_secs,
+        created_at,
+        expires_at,
+    }
+}
 
-        self.delete_legacy_data(legacy_key).await?;
+type BanRow = (
+    String,
+    Option<String>,
+    Option<i64>,
+    Option<i64>,
+    Option<bool>,
+);
 
-        info!("Successfully migrated user {}", user_id);
-        Ok(())
+fn ban_from_row(row: BanRow) -> Ban {
+    let (user, reason, banned_at, expires_at, automatic) = row;
+    Ban {
+        user,
+        reason: reason.unwrap_or_default(),
+        banned_at: banned_at.unwrap_or(0),
+        expires_at,
+        automatic: automatic.unwrap_or(false),
     }
+}
 
-    async fn delete_legacy_data(&self, legacy_key: &str) -> Result<()> {
-        self.session
-            .execute_unpaged(&self.prepared.delete_user, (legacy_key,))
-            .await?;
-        Ok(())
+type DeviceRow = (String, Option<String>, Option<i64>);
+
+fn device_from_row(row: DeviceRow) -> Device {
+    let (device_id, client_name, last_seen_at) = row;
+    Device {
+        device_id,
+        client_name,
+        last_seen_at: last_seen_at.unwrap_or(0),
     }
+}
 
-    pub async fn get_data_manifest(&self, user_id: &str) -> Result<Vec<DataManifestEntry>> {
+type SyncSessionRow = (
+    i64,
+    Option<String>,
+    Option<String>,
+    i64,
+    i32,
+    i32,
+    i32,
+    i32,
+    i32,
+    i64,
+    i64,
+);
+
+fn sync_session_from_row(row: SyncSessionRow) -> SyncSession {
+    let (
+        started_at,
+        device_id,
+        client_name,
+        duration_ms,
+        uploads,
+        downloads,
+        deletions,
+        conflicts,
+        errors,
+        bytes_uploaded,
+        bytes_downloaded,
+    ) = row;
+    SyncSession {
+        started_at,
+        duration_ms,
+        device_id,
+        client_name,
+        uploads: uploads as u32,
+        downloads: downloads as u32,
+        deletions: deletions as u32,
+        conflicts: conflicts as u32,
+        errors: errors as u32,
+        bytes_uploaded,
+        bytes_downloaded,
+    }
+}
+
+type PendingDeletionRow = (String, Option<i64>, Option<i64>);
+
+fn pending_deletion_from_row(row: PendingDeletionRow) -> PendingDeletion {
+    let (user_id, requested_at, purge_at) = row;
+    PendingDeletion {
+        user_id,
+        requested_at: requested_at.unwrap_or(0),
+        purge_at: purge_at.unwrap_or(0),
+    }
+}
+
+pub(crate) type ApiKeyRow = (
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<i64>,
+);
+
+/// Rows with a missing user or hash, or a scope this build does not know,
+/// are skipped rather than failing the lookup.
+pub(crate) fn api_key_from_row(row: ApiKeyRow) -> Option<ApiKey> {
+    let (id, name, user_id, key_hash, scope, created_at) = row;
+    let user_id = user_id?;
+    Some(ApiKey {
+        id,
+        name: name.unwrap_or_default(),
+        user: hash_user_id(&user_id),
+        user_id,
+        key_hash: key_hash?,
+        scope: scope?.parse().ok()?,
+        created_at: created_at.unwrap_or(0),
+    })
+}
+
+type ShareRow = (String, String, String, i64, i64, Option<i32>);
+
+fn share_from_row(row: ShareRow) -> DataShare {
+    let (id, user_id, key, created_at, expires_at, max_downloads) = row;
+    DataShare {
+        id,
+        user_id,
+        key,
+        created_at,
+        expires_at,
+        max_downloads,
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LegacyCleanupReport {
+    pub total: u64,
+    pub legacy: u64,
+    pub deleted: u64,
+    /// Users with v2 data, and those of them whose data is still keyed by
+    /// the legacy hash.
+    pub data_users: u64,
+    pub legacy_data_users: u64,
+    pub deleted_data_users: u64,
+}
+
+pub(crate) fn check_key(key: &str) -> Result<()> {
+    validate_key(key).map_err(|e| anyhow::anyhow!(e.message()))
+}
+
+/// Fails if a `size`-byte value is more than `key` accepts under `config`.
+pub(crate) fn check_value_size(config: &Config, key: &str, size: usize) -> Result<()> {
+    check_size(size, config.namespaces.max_size(key), "Value")
+        .map_err(|e| anyhow::anyhow!(e.message()))
+}
+
+fn get_legacy_key_if_different(user_id: &str, new_key: &str) -> Option<String> {
+    let legacy_key = legacy::hash_user_id(user_id);
+    if legacy_key != new_key {
+        Some(legacy_key)
+    } else {
+        None
+    }
+}
+
+struct PreparedStatements {
+    get_user_updated_at: PreparedStatement,
+    get_user_settings: PreparedStatement,
+    insert_user_settings: PreparedStatement,
+    delete_user: PreparedStatement,
+    insert_settings_history: PreparedStatement,
+    get_settings_history_written: PreparedStatement,
+    get_settings_version: PreparedStatement,
+    get_settings_history_user_ids: PreparedStatement,
+    get_settings_history_versions: PreparedStatement,
+    trim_settings_history: PreparedStatement,
+    delete_settings_history: PreparedStatement,
+    get_user_created_at: PreparedStatement,
+    get_data_manifest: PreparedStatement,
+    get_data_manifest_from: PreparedStatement,
+    get_data_key: PreparedStatement,
+    get_data_meta: PreparedStatement,
+    get_data_row: PreparedStatement,
+    get_data_version: PreparedStatement,
+    get_data_version_and_size: PreparedStatement,
+    insert_data_key: PreparedStatement,
+    insert_data_key_if_absent: PreparedStatement,
+    update_data_key_if_version: PreparedStatement,
+    update_data_tombstone_if_version: PreparedStatement,
+    update_data_size_if_version: PreparedStatement,
+    insert_data_tombstone: PreparedStatement,
+    delete_all_data: PreparedStatement,
+    get_legacy_data_rows: PreparedStatement,
+    migrate_data_row: PreparedStatement,
+    get_user_total_size: PreparedStatement,
+    get_key_size: PreparedStatement,
+    get_users_created_since: PreparedStatement,
+    get_all_user_ids: PreparedStatement,
+    get_retention_candidates: PreparedStatement,
+    set_retention_mark: PreparedStatement,
+    probe_keyspace: PreparedStatement,
+    insert_oauth_state: PreparedStatement,
+    get_oauth_state: PreparedStatement,
+    delete_oauth_state: PreparedStatement,
+    insert_oauth_code: PreparedStatement,
+    get_oauth_code: PreparedStatement,
+    delete_oauth_code: PreparedStatement,
+    insert_refresh_token: PreparedStatement,
+    get_refresh_token: PreparedStatement,
+    delete_refresh_token: PreparedStatement,
+    insert_account_link: PreparedStatement,
+    get_account_link: PreparedStatement,
+    get_account_links: PreparedStatement,
+    delete_account_link: PreparedStatement,
+    insert_ban: PreparedStatement,
+    get_ban: PreparedStatement,
+    get_bans: PreparedStatement,
+    delete_ban: PreparedStatement,
+    insert_auth_lockout: PreparedStatement,
+    get_auth_lockout: PreparedStatement,
+    insert_pending_deletion: PreparedStatement,
+    get_pending_deletion: PreparedStatement,
+    get_pending_deletions: PreparedStatement,
+    delete_pending_deletion: PreparedStatement,
+    insert_device: PreparedStatement,
+    get_devices: PreparedStatement,
+    delete_devices: PreparedStatement,
+    insert_key_writer: PreparedStatement,
+    get_key_writers: PreparedStatement,
+    delete_key_writers: PreparedStatement,
+    insert_sync_session: PreparedStatement,
+    get_sync_sessions: PreparedStatement,
+    delete_sync_sessions: PreparedStatement,
+    insert_api_key: PreparedStatement,
+    get_api_key: PreparedStatement,
+    get_api_keys: PreparedStatement,
+    delete_api_key: PreparedStatement,
+    insert_share: PreparedStatement,
+    get_share: PreparedStatement,
+    get_user_shares: PreparedStatement,
+    delete_share: PreparedStatement,
+    add_share_downloads: PreparedStatement,
+    get_share_downloads: PreparedStatement,
+    delete_share_downloads: PreparedStatement,
+    get_user_blob_hashes: PreparedStatement,
+    insert_blob: PreparedStatement,
+    get_blob_last_referenced: PreparedStatement,
+    delete_blob: PreparedStatement,
+    increment_blob_refs: PreparedStatement,
+    decrement_blob_refs: PreparedStatement,
+    get_blob_refs: PreparedStatement,
+    get_user_quota: PreparedStatement,
+    insert_user_quota: PreparedStatement,
+    delete_user_quota: PreparedStatement,
+    add_write_usage: PreparedStatement,
+    get_write_usage: PreparedStatement,
+    insert_active_user: PreparedStatement,
+    count_active_users: PreparedStatement,
+    add_sync_usage: PreparedStatement,
+    get_sync_usage: PreparedStatement,
+    insert_daily_stats: PreparedStatement,
+    get_daily_stats: PreparedStatement,
+    get_data_user_ids: PreparedStatement,
+    insert_corrupt_entry: PreparedStatement,
+    get_corrupt_entries: PreparedStatement,
+    delete_corrupt_entries: PreparedStatement,
+    claim_idempotency_key: PreparedStatement,
+    get_idempotency_key: PreparedStatement,
+    complete_idempotency_key: PreparedStatement,
+    release_idempotency_key: PreparedStatement,
+    delete_idempotency_keys: PreparedStatement,
+    insert_upload_session: PreparedStatement,
+    get_upload_session: PreparedStatement,
+    get_upload_sessions: PreparedStatement,
+    delete_upload_session: PreparedStatement,
+    delete_upload_sessions: PreparedStatement,
+    insert_upload_part: PreparedStatement,
+    get_upload_parts: PreparedStatement,
+    get_upload_part: PreparedStatement,
+    delete_upload_parts: PreparedStatement,
+    insert_audit_entry: PreparedStatement,
+    get_audit_entries: PreparedStatement,
+    health_check: PreparedStatement,
+}
+
+#[derive(Clone)]
+pub struct DatabaseService {
+    session: TimedSession,
+    prepared: Arc<PreparedStatements>,
+    blobs: Arc<Blobs>,
+    settings_cache: Arc<SettingsCache>,
+    batch_reads: Arc<BatchLatency>,
+    batch_writes: Arc<BatchLatency>,
+    key_locks: Arc<KeyLocks>,
+    config: ConfigHandle,
+}
+
+impl DatabaseService {
+    /// Uses `config`'s keyspace, blob store, quota and limits; each tenant
+    /// has a service of its own.
+    pub async fn with_config(session: Session, config: ConfigHandle) -> Result<Self> {
+        let current = config.load();
+        session.use_keyspace(&current.keyspace, false).await?;
+
+        let mut names = StatementNames::default();
+        let mut prepared = PreparedStatements {
+            get_user_updated_at: names
+                .prepare(&session, "get_user_updated_at", "SELECT updated_at FROM users WHERE id = ?")
+                .await?,
+            get_user_settings: names
+                .prepare(&session, "get_user_settings", "SELECT settings, updated_at FROM users WHERE id = ?")
+                .await?,
+            insert_user_settings: names
+                .prepare(&session, "insert_user_settings", "INSERT INTO users (id, settings, created_at, updated_at) VALUES (?, ?, ?, ?)")
+                .await?,
+            delete_user: names
+                .prepare(&session, "delete_user", "DELETE FROM users WHERE id = ?")
+                .await?,
+            insert_settings_history: names
+                .prepare(&session, "insert_settings_history", "INSERT INTO settings_history (user_id, written, settings) VALUES (?, ?, ?)")
+                .await?,
+            get_settings_history_written: names
+                .prepare(&session, "get_settings_history_written", "SELECT written FROM settings_history WHERE user_id = ? LIMIT ?")
+                .await?,
+            get_settings_version: names
+                .prepare(&session, "get_settings_version", "SELECT settings FROM settings_history WHERE user_id = ? AND written = ?")
+                .await?,
+            get_settings_history_user_ids: names
+                .prepare(&session, "get_settings_history_user_ids", "SELECT DISTINCT user_id FROM settings_history")
+                .await?,
+            get_settings_history_versions: names
+                .prepare(&session, "get_settings_history_versions", "SELECT written FROM settings_history WHERE user_id = ?")
+                .await?,
+            trim_settings_history: names
+                .prepare(&session, "trim_settings_history", "DELETE FROM settings_history WHERE user_id = ? AND written < ?")
+                .await?,
+            delete_settings_history: names
+                .prepare(&session, "delete_settings_history", "DELETE FROM settings_history WHERE user_id = ?")
+                .await?,
+            get_user_created_at: names
+                .prepare(&session, "get_user_created_at", "SELECT created_at FROM users WHERE id = ?")
+                .await?,
+            get_data_manifest: names
+                .prepare(&session, "get_data_manifest", "SELECT key, version, checksum, size_bytes, updated_at, deleted, deleted_at, expires_at FROM data WHERE user_id = ?")
+                .await?,
+            get_data_manifest_from: names
+                .prepare(&session, "get_data_manifest_from", "SELECT key, version, checksum, size_bytes, updated_at, deleted, deleted_at, expires_at FROM data WHERE user_id = ? AND key >= ?")
+                .await?,
+            get_data_key: names
+                .prepare(&session, "get_data_key", "SELECT key, value, version, checksum, size_bytes, created_at, updated_at, deleted, blob_hash FROM data WHERE user_id = ? AND key = ?")
+                .await?,
+            get_data_meta: names
+                .prepare(&session, "get_data_meta", "SELECT key, version, checksum, size_bytes, updated_at, deleted, deleted_at, expires_at FROM data WHERE user_id = ? AND key = ?")
+                .await?,
+            get_data_row: names
+                .prepare(&session, "get_data_row", "SELECT value, version, checksum, size_bytes, created_at, updated_at, deleted, expires_at, blob_hash, TTL(version) FROM data WHERE user_id = ? AND key = ?")
+                .await?,
+            get_data_version: names
+                .prepare(&session, "get_data_version", "SELECT version, created_at, blob_hash FROM data WHERE user_id = ? AND key = ?")
+                .await?,
+            get_data_version_and_size: names
+                .prepare(&session, "get_data_version_and_size", "SELECT version, created_at, size_bytes, blob_hash FROM data WHERE user_id = ? AND key = ?")
+                .await?,
+            insert_data_key: names
+                .prepare(&session, "insert_data_key", "INSERT INTO data (user_id, key, value, version, checksum, size_bytes, created_at, updated_at, deleted, deleted_at, expires_at, blob_hash) VALUES (?, ?, ?, ?, ?, ?, ?, ?, false, null, ?, ?) USING TTL ?")
+                .await?,
+            insert_data_key_if_absent: names
+                .prepare(&session, "insert_data_key_if_absent", "INSERT INTO data (user_id, key, value, version, checksum, size_bytes, created_at, updated_at, deleted, deleted_at, expires_at, blob_hash) VALUES (?, ?, ?, ?, ?, ?, ?, ?, false, null, ?, ?) IF NOT EXISTS USING TTL ?")
+                .await?,
+            update_data_key_if_version: names
+                .prepare(&session, "update_data_key_if_version", "UPDATE data USING TTL ? SET value = ?, version = ?, checksum = ?, size_bytes = ?, created_at = ?, updated_at = ?, deleted = false, deleted_at = null, expires_at = ?, blob_hash = ? WHERE user_id = ? AND key = ? IF version = ?")
+                .await?,
+            update_data_tombstone_if_version: names
+                .prepare(&session, "update_data_tombstone_if_version", "UPDATE data USING TTL ? SET value = 0x, version = ?, checksum = '', size_bytes = 0, created_at = ?, updated_at = ?, deleted = true, deleted_at = ?, expires_at = null, blob_hash = null WHERE user_id = ? AND key = ? IF version = ?")
+                .await?,
+            update_data_size_if_version: names
+                .prepare(&session, "update_data_size_if_version", "UPDATE data USING TTL ? SET size_bytes = ? WHERE user_id = ? AND key = ? IF version = ?")
+                .await?,
+            insert_data_tombstone: names
+                .prepare(&session, "insert_data_tombstone", "INSERT INTO data (user_id, key, value, version, checksum, size_bytes, created_at, updated_at, deleted, deleted_at, expires_at, blob_hash) VALUES (?, ?, 0x, ?, '', 0, ?, ?, true, ?, null, null) USING TTL ?")
+                .await?,
+            delete_all_data: names
+                .prepare(&session, "delete_all_data", "DELETE FROM data WHERE user_id = ?")
+                .await?,
+            get_legacy_data_rows: names
+                .prepare(&session, "get_legacy_data_rows", "SELECT key, value, version, checksum, size_bytes, created_at, updated_at, deleted, deleted_at, expires_at, blob_hash, TTL(version) FROM data WHERE user_id = ?")
+                .await?,
+            migrate_data_row: names
+                .prepare(&session, "migrate_data_row", "INSERT INTO data (user_id, key, value, version, checksum, size_bytes, created_at, updated_at, deleted, deleted_at, expires_at, blob_hash) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) IF NOT EXISTS USING TTL ?")
+                .await?,
+            get_user_total_size: names
+                .prepare(&session, "get_user_total_size", "SELECT SUM(size_bytes) FROM data WHERE user_id = ?")
+                .await?,
+            get_key_size: names
+                .prepare(&session, "get_key_size", "SELECT size_bytes FROM data WHERE user_id = ? AND key = ?")
+                .await?,
+            get_users_created_since: names
+                .prepare(&session, "get_users_created_since", "SELECT id, created_at, updated_at FROM users WHERE created_at > ? ALLOW FILTERING")
+                .await?,
+            get_all_user_ids: names
+                .prepare(&session, "get_all_user_ids", "SELECT id FROM users")
+                .await?,
+            get_retention_candidates: names
+                .prepare(&session, "get_retention_candidates", "SELECT id, updated_at, retention_marked_at FROM users")
+                .await?,
+            set_retention_mark: names
+                .prepare(&session, "set_retention_mark", "UPDATE users SET retention_marked_at = ? WHERE id = ?")
+                .await?,
+            probe_keyspace: names
+                .prepare(&session, "probe_keyspace", "SELECT id FROM users LIMIT 1")
+                .await?,
+            insert_oauth_state: names
+                .prepare(&session, "insert_oauth_state", "INSERT INTO oauth_states (state, code_verifier, redirect_uri, redirect_mode) VALUES (?, ?, ?, ?) USING TTL ?")
+                .await?,
+            get_oauth_state: names
+                .prepare(&session, "get_oauth_state", "SELECT code_verifier, redirect_uri, redirect_mode FROM oauth_states WHERE state = ?")
+                .await?,
+            delete_oauth_state: names
+                .prepare(&session, "delete_oauth_state", "DELETE FROM oauth_states WHERE state = ? IF EXISTS")
+                .await?,
+            insert_oauth_code: names
+                .prepare(&session, "insert_oauth_code", "INSERT INTO oauth_codes (code, user_id, scopes) VALUES (?, ?, ?) USING TTL ?")
+                .await?,
+            get_oauth_code: names
+                .prepare(&session, "get_oauth_code", "SELECT user_id, scopes FROM oauth_codes WHERE code = ?")
+                .await?,
+            delete_oauth_code: names
+                .prepare(&session, "delete_oauth_code", "DELETE FROM oauth_codes WHERE code = ? IF EXISTS")
+                .await?,
+            insert_refresh_token: names
+                .prepare(&session, "insert_refresh_token", "INSERT INTO oauth_tokens (user_id, refresh_token, updated_at) VALUES (?, ?, ?)")
+                .await?,
+            get_refresh_token: names
+                .prepare(&session, "get_refresh_token", "SELECT refresh_token FROM oauth_tokens WHERE user_id = ?")
+                .await?,
+            insert_account_link: names
+                .prepare(&session, "insert_account_link", "INSERT INTO account_links (user_id, account_id, account_hash, linked_at) VALUES (?, ?, ?, ?)")
+                .await?,
+            get_account_link: names
+                .prepare(&session, "get_account_link", "SELECT account_id FROM account_links WHERE user_id = ?")
+                .await?,
+            get_account_links: names
+                .prepare(&session, "get_account_links", "SELECT user_id, linked_at FROM account_links WHERE account_hash = ?")
+                .await?,
+            delete_account_link: names
+                .prepare(&session, "delete_account_link", "DELETE FROM account_links WHERE user_id = ?")
+                .await?,
+            insert_ban: names
+                .prepare(&session, "insert_ban", "INSERT INTO banned_users (user_id, reason, banned_at, expires_at, automatic) VALUES (?, ?, ?, ?, ?) USING TTL ?")
+                .await?,
+            get_ban: names
+                .prepare(&session, "get_ban", "SELECT user_id, reason, banned_at, expires_at, automatic FROM banned_users WHERE user_id = ?")
+                .await?,
+            get_bans: names
+                .prepare(&session, "get_bans", "SELECT user_id, reason, banned_at, expires_at, automatic FROM banned_users")
+                .await?,
+            delete_ban: names
+                .prepare(&session, "delete_ban", "DELETE FROM banned_users WHERE user_id = ?")
+                .await?,
+            insert_auth_lockout: names
+                .prepare(&session, "insert_auth_lockout", "INSERT INTO auth_lockouts (subject, locked_until, failures) VALUES (?, ?, ?) USING TTL ?")
+                .await?,
+            get_auth_lockout: names
+                .prepare(&session, "get_auth_lockout", "SELECT subject, locked_until, failures FROM auth_lockouts WHERE subject = ?")
+                .await?,
+            insert_pending_deletion: names
+                .prepare(&session, "insert_pending_deletion", "INSERT INTO pending_deletions (user_hash, user_id, requested_at, purge_at) VALUES (?, ?, ?, ?)")
+                .await?,
+            get_pending_deletion: names
+                .prepare(&session, "get_pending_deletion", "SELECT user_id, requested_at, purge_at FROM pending_deletions WHERE user_hash = ?")
+                .await?,
+            get_pending_deletions: names
+                .prepare(&session, "get_pending_deletions", "SELECT user_id, requested_at, purge_at FROM pending_deletions")
+                .await?,
+            delete_pending_deletion: names
+                .prepare(&session, "delete_pending_deletion", "DELETE FROM pending_deletions WHERE user_hash = ?")
+                .await?,
+            insert_device: names
+                .prepare(&session, "insert_device", "INSERT INTO devices (user_hash, device_id, client_name, last_seen_at) VALUES (?, ?, ?, ?)")
+                .await?,
+            get_devices: names
+                .prepare(&session, "get_devices", "SELECT device_id, client_name, last_seen_at FROM devices WHERE user_hash = ?")
+                .await?,
+            delete_devices: names
+                .prepare(&session, "delete_devices", "DELETE FROM devices WHERE user_hash = ?")
+                .await?,
+            insert_key_writer: names
+                .prepare(&session, "insert_key_writer", "INSERT INTO key_writers (user_hash, key, device_id, client_name, written_at) VALUES (?, ?, ?, ?, ?)")
+                .await?,
+            get_key_writers: names
+                .prepare(&session, "get_key_writers", "SELECT key, device_id, client_name, written_at FROM key_writers WHERE user_hash = ?")
+                .await?,
+            delete_key_writers: names
+                .prepare(&session, "delete_key_writers", "DELETE FROM key_writers WHERE user_hash = ?")
+                .await?,
+            insert_sync_session: names
+                .prepare(&session, "insert_sync_session", "INSERT INTO sync_sessions (user_hash, started_at, id, device_id, client_name, duration_ms, uploads, downloads, deletions, conflicts, errors, bytes_uploaded, bytes_downloaded) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) USING TTL ?")
+                .await?,
+            get_sync_sessions: names
+                .prepare(&session, "get_sync_sessions", "SELECT started_at, device_id, client_name, duration_ms, uploads, downloads, deletions, conflicts, errors, bytes_uploaded, bytes_downloaded FROM sync_sessions WHERE user_hash = ? LIMIT ?")
+                .await?,
+            delete_sync_sessions: names
+                .prepare(&session, "delete_sync_sessions", "DELETE FROM sync_sessions WHERE user_hash = ?")
+                .await?,
+            insert_api_key: names
+                .prepare(&session, "insert_api_key", "INSERT INTO api_keys (id, name, user_id, key_hash, scope, created_at) VALUES (?, ?, ?, ?, ?, ?)")
+                .await?,
+            get_api_key: names
+                .prepare(&session, "get_api_key", "SELECT id, name, user_id, key_hash, scope, created_at FROM api_keys WHERE id = ?")
+                .await?,
+            get_api_keys: names
+                .prepare(&session, "get_api_keys", "SELECT id, name, user_id, key_hash, scope, created_at FROM api_keys")
+                .await?,
+            delete_api_key: names
+                .prepare(&session, "delete_api_key", "DELETE FROM api_keys WHERE id = ?")
+                .await?,
+            insert_share: names
+                .prepare(&session, "insert_share", "INSERT INTO data_shares (id, user_id, user_hash, key, created_at, expires_at, max_downloads) VALUES (?, ?, ?, ?, ?, ?, ?) USING TTL ?")
+                .await?,
+            get_share: names
+                .prepare(&session, "get_share", "SELECT id, user_id, key, created_at, expires_at, max_downloads FROM data_shares WHERE id = ?")
+                .await?,
+            get_user_shares: names
+                .prepare(&session, "get_user_shares", "SELECT id FROM data_shares WHERE user_hash = ?")
+                .await?,
+            delete_share: names
+                .prepare(&session, "delete_share", "DELETE FROM data_shares WHERE id = ?")
+                .await?,
+            add_share_downloads: names
+                .prepare(&session, "add_share_downloads", "UPDATE share_downloads SET downloads = downloads + ? WHERE id = ?")
+                .await?,
+            get_share_downloads: names
+                .prepare(&session, "get_share_downloads", "SELECT downloads FROM share_downloads WHERE id = ?")
+                .await?,
+            delete_share_downloads: names
+                .prepare(&session, "delete_share_downloads", "DELETE FROM share_downloads WHERE id = ?")
+                .await?,
+            delete_refresh_token: names
+                .prepare(&session, "delete_refresh_token", "DELETE FROM oauth_tokens WHERE user_id = ?")
+                .await?,
+            get_user_blob_hashes: names
+                .prepare(&session, "get_user_blob_hashes", "SELECT blob_hash, deleted FROM data WHERE user_id = ?")
+                .await?,
+            insert_blob: names
+                .prepare(&session, "insert_blob", "INSERT INTO blobs (hash, size_bytes, last_referenced_at) VALUES (?, ?, ?)")
+                .await?,
+            get_blob_last_referenced: names
+                .prepare(&session, "get_blob_last_referenced", "SELECT last_referenced_at FROM blobs WHERE hash = ?")
+                .await?,
+            delete_blob: names
+                .prepare(&session, "delete_blob", "DELETE FROM blobs WHERE hash = ?")
+                .await?,
+            increment_blob_refs: names
+                .prepare(&session, "increment_blob_refs", "UPDATE blob_refs SET refs = refs + 1 WHERE hash = ?")
+                .await?,
+            decrement_blob_refs: names
+                .prepare(&session, "decrement_blob_refs", "UPDATE blob_refs SET refs = refs - 1 WHERE hash = ?")
+                .await?,
+            get_blob_refs: names
+                .prepare(&session, "get_blob_refs", "SELECT hash, refs FROM blob_refs")
+                .await?,
+            get_user_quota: names
+                .prepare(&session, "get_user_quota", "SELECT max_bytes FROM user_quotas WHERE user_id = ?")
+                .await?,
+            insert_user_quota: names
+                .prepare(&session, "insert_user_quota", "INSERT INTO user_quotas (user_id, max_bytes, updated_at) VALUES (?, ?, ?)")
+                .await?,
+            delete_user_quota: names
+                .prepare(&session, "delete_user_quota", "DELETE FROM user_quotas WHERE user_id = ?")
+                .await?,
+            add_write_usage: names
+                .prepare(&session, "add_write_usage", "UPDATE write_usage SET writes = writes + ? WHERE user_id = ? AND day = ?")
+                .await?,
+            get_write_usage: names
+                .prepare(&session, "get_write_usage", "SELECT writes FROM write_usage WHERE user_id = ? AND day = ?")
+                .await?,
+            insert_active_user: names
+                .prepare(&session, "insert_active_user", "INSERT INTO active_users (day, user_hash) VALUES (?, ?)")
+                .await?,
+            count_active_users: names
+                .prepare(&session, "count_active_users", "SELECT COUNT(*) FROM active_users WHERE day = ?")
+                .await?,
+            add_sync_usage: names
+                .prepare(&session, "add_sync_usage", "UPDATE sync_usage SET syncs = syncs + ? WHERE day = ?")
+                .await?,
+            get_sync_usage: names
+                .prepare(&session, "get_sync_usage", "SELECT syncs FROM sync_usage WHERE day = ?")
+                .await?,
+            insert_daily_stats: names
+                .prepare(&session, "insert_daily_stats", "INSERT INTO stats_daily (day, active_users, users, bytes_stored, syncs, computed_at) VALUES (?, ?, ?, ?, ?, ?)")
+                .await?,
+            get_daily_stats: names
+                .prepare(&session, "get_daily_stats", "SELECT day, active_users, users, bytes_stored, syncs, computed_at FROM stats_daily WHERE day = ?")
+                .await?,
+            get_data_user_ids: names
+                .prepare(&session, "get_data_user_ids", "SELECT DISTINCT user_id FROM data")
+                .await?,
+            insert_corrupt_entry: names
+                .prepare(&session, "insert_corrupt_entry", "INSERT INTO corrupt_data (user_id, key, version, stored_checksum, computed_checksum, detected_at) VALUES (?, ?, ?, ?, ?, ?)")
+                .await?,
+            get_corrupt_entries: names
+                .prepare(&session, "get_corrupt_entries", "SELECT key, version, stored_checksum, computed_checksum, detected_at FROM corrupt_data WHERE user_id = ?")
+                .await?,
+            delete_corrupt_entries: names
+                .prepare(&session, "delete_corrupt_entries", "DELETE FROM corrupt_data WHERE user_id = ?")
+                .await?,
+            claim_idempotency_key: names
+                .prepare(&session, "claim_idempotency_key", "INSERT INTO idempotency_keys (user_id, key, fingerprint, created_at) VALUES (?, ?, ?, ?) IF NOT EXISTS USING TTL ?")
+                .await?,
+            get_idempotency_key: names
+                .prepare(&session, "get_idempotency_key", "SELECT fingerprint, status, headers, body FROM idempotency_keys WHERE user_id = ? AND key = ?")
+                .await?,
+            complete_idempotency_key: names
+                .prepare(&session, "complete_idempotency_key", "UPDATE idempotency_keys USING TTL ? SET fingerprint = ?, status = ?, headers = ?, body = ?, created_at = ? WHERE user_id = ? AND key = ? IF EXISTS")
+                .await?,
+            release_idempotency_key: names
+                .prepare(&session, "release_idempotency_key", "DELETE FROM idempotency_keys WHERE user_id = ? AND key = ? IF EXISTS")
+                .await?,
+            delete_idempotency_keys: names
+                .prepare(&session, "delete_idempotency_keys", "DELETE FROM idempotency_keys WHERE user_id = ?")
+                .await?,
+            insert_upload_session: names
+                .prepare(&session, "insert_upload_session", "INSERT INTO upload_sessions (user_id, id, key, size_bytes, part_size, checksum, ttl_secs, created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) USING TTL ?")
+                .await?,
+            get_upload_session: names
+                .prepare(&session, "get_upload_session", "SELECT id, key, size_bytes, part_size, checksum, ttl_secs, created_at, expires_at FROM upload_sessions WHERE user_id = ? AND id = ?")
+                .await?,
+            get_upload_sessions: names
+                .prepare(&session, "get_upload_sessions", "SELECT id, key, size_bytes, part_size, checksum, ttl_secs, created_at, expires_at FROM upload_sessions WHERE user_id = ?")
+                .await?,
+            delete_upload_session: names
+                .prepare(&session, "delete_upload_session", "DELETE FROM upload_sessions WHERE user_id = ? AND id = ?")
+                .await?,
+            delete_upload_sessions: names
+                .prepare(&session, "delete_upload_sessions", "DELETE FROM upload_sessions WHERE user_id = ?")
+                .await?,
+            insert_upload_part: names
+                .prepare(&session, "insert_upload_part", "INSERT INTO upload_parts (upload_id, part, value, checksum, size_bytes) VALUES (?, ?, ?, ?, ?) USING TTL ?")
+                .await?,
+            get_upload_parts: names
+                .prepare(&session, "get_upload_parts", "SELECT part, size_bytes, checksum FROM upload_parts WHERE upload_id = ?")
+                .await?,
+            get_upload_part: names
+                .prepare(&session, "get_upload_part", "SELECT value FROM upload_parts WHERE upload_id = ? AND part = ?")
+                .await?,
+            delete_upload_parts: names
+                .prepare(&session, "delete_upload_parts", "DELETE FROM upload_parts WHERE upload_id = ?")
+                .await?,
+            insert_audit_entry: names
+                .prepare(&session, "insert_audit_entry", "INSERT INTO audit_log (day, at, id, request_id, actor, user_hash, action, route, client_ip, detail, outcome) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) USING TTL ?")
+                .await?,
+            get_audit_entries: names
+                .prepare(&session, "get_audit_entries", "SELECT at, request_id, actor, user_hash, action, route, client_ip, detail, outcome FROM audit_log WHERE day = ?")
+                .await?,
+            health_check: names
+                .prepare(&session, "health_check", "SELECT now() FROM system.local")
+                .await?,
+        };
+
+        // Reads are safe to retry and to race with speculative executions.
+        for statement in [
+            &mut prepared.get_user_updated_at,
+            &mut prepared.get_user_settings,
+            &mut prepared.get_user_created_at,
+            &mut prepared.get_settings_history_written,
+            &mut prepared.get_settings_version,
+            &mut prepared.get_settings_history_user_ids,
+            &mut prepared.get_settings_history_versions,
+            &mut prepared.get_data_manifest,
+            &mut prepared.get_data_manifest_from,
+            &mut prepared.get_data_key,
+            &mut prepared.get_data_meta,
+            &mut prepared.get_data_row,
+            &mut prepared.get_data_version,
+            &mut prepared.get_data_version_and_size,
+            &mut prepared.get_legacy_data_rows,
+            &mut prepared.get_user_total_size,
+            &mut prepared.get_key_size,
+            &mut prepared.get_users_created_since,
+            &mut prepared.get_all_user_ids,
+            &mut prepared.get_retention_candidates,
+            &mut prepared.probe_keyspace,
+            &mut prepared.get_refresh_token,
+            &mut prepared.get_account_link,
+            &mut prepared.get_account_links,
+            &mut prepared.get_ban,
+            &mut prepared.get_bans,
+            &mut prepared.get_auth_lockout,
+            &mut prepared.get_api_key,
+            &mut prepared.get_api_keys,
+            &mut prepared.get_share,
+            &mut prepared.get_user_shares,
+            &mut prepared.get_share_downloads,
+            &mut prepared.get_idempotency_key,
+            &mut prepared.get_user_blob_hashes,
+            &mut prepared.get_blob_last_referenced,
+            &mut prepared.get_blob_refs,
+            &mut prepared.get_audit_entries,
+            &mut prepared.get_sync_sessions,
+            &mut prepared.get_user_quota,
+            &mut prepared.get_write_usage,
+            &mut prepared.get_data_user_ids,
+            &mut prepared.get_corrupt_entries,
+            &mut prepared.get_upload_session,
+            &mut prepared.get_upload_sessions,
+            &mut prepared.get_upload_parts,
+            &mut prepared.get_upload_part,
+            &mut prepared.health_check,
+        ] {
+            statement.set_is_idempotent(true);
+        }
+
+        // Probes get a tighter timeout than `SCYLLA_REQUEST_TIMEOUT_MS`; a node
+        // this slow should count as unhealthy.
+        for statement in [&mut prepared.probe_keyspace, &mut prepared.health_check] {
+            statement.set_request_timeout(Some(Duration::from_millis(HEALTH_PROBE_TIMEOUT_MS)));
+        }
+
+        let session = Arc::new(session);
+        let blobs = Blobs::from_config(&current, Arc::clone(&session)).await?;
+
+        Ok(Self {
+            session: TimedSession::new(session, names, config.clone()),
+            prepared: Arc::new(prepared),
+            blobs: Arc::new(blobs),
+            settings_cache: Arc::new(SettingsCache::new(
+                current.settings_cache_size,
+                current.settings_cache_ttl,
+            )),
+            batch_reads: Arc::default(),
+            batch_writes: Arc::default(),
+            key_locks: Arc::default(),
+            config,
+        })
+    }
+
+    pub fn session(&self) -> &Session {
+        self.session.inner()
+    }
+
+    /// Latency of every query, by operation.
+    pub fn query_stats(&self) -> BTreeMap<&'static str, QueryStats> {
+        self.session.latency().stats()
+    }
+
+    /// Requests, connections and errors of the driver, by node.
+    pub fn driver_stats(&self) -> DriverStats {
+        self.session.driver_stats()
+    }
+
+    pub fn settings_cache_stats(&self) -> CacheStats {
+        self.settings_cache.stats()
+    }
+
+    /// Latency of `get_data_keys`.
+    pub fn batch_read_stats(&self) -> BatchStats {
+        self.batch_reads.stats()
+    }
+
+    /// Latency of `save_data_keys_batch`.
+    pub fn batch_write_stats(&self) -> BatchStats {
+        self.batch_writes.stats()
+    }
+
+    fn batch_parallelism(&self) -> usize {
+        self.config.load().scylla_batch_parallelism.max(1)
+    }
+
+    pub async fn health_check(&self) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.health_check, &[])
+            .await?;
+        Ok(())
+    }
+
+    /// Reads a single row from the keyspace, catching a dropped keyspace or
+    /// table that `health_check` alone would miss.
+    pub async fn probe_keyspace(&self) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.probe_keyspace, &[])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_settings_metadata(&self, user_id: &str) -> Result<Option<String>> {
+        let hash_key = hash_user_id(user_id);
+
+        if let Some(updated_at) = self.query_updated_at(&hash_key).await? {
+            return Ok(Some(updated_at.to_string()));
+        }
+
+        if let Some(legacy_key) = get_legacy_key_if_different(user_id, &hash_key)
+            && let Some(updated_at) = self.query_updated_at(&legacy_key).await?
+        {
+            warn!("Found legacy data for user, will migrate on next write");
+            return Ok(Some(updated_at.to_string()));
+        }
+
+        Ok(None)
+    }
+
+    async fn query_updated_at(&self, key: &str) -> Result<Option<i64>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_user_updated_at, (key,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        if let Some(row) = rows_result.rows::<(i64,)>()?.next() {
+            let (updated_at,) = row?;
+            return Ok(Some(updated_at));
+        }
+        Ok(None)
+    }
+
+    pub async fn get_user_settings(&self, user_id: &str) -> Result<Option<(Vec<u8>, String)>> {
+        let hash_key = hash_user_id(user_id);
+
+        if let Some(cached) = self.settings_cache.get(&hash_key) {
+            return Ok(Some(cached));
+        }
+
+        let generation = self.settings_cache.generation();
+        if let Some((settings, updated_at)) = self.query_settings(&hash_key).await? {
+            let written = updated_at.to_string();
+            self.settings_cache
+                .insert(&hash_key, &settings, &written, generation);
+            return Ok(Some((settings, written)));
+        }
+
+        if let Some(legacy_key) = get_legacy_key_if_different(user_id, &hash_key)
+            && let Some((settings, updated_at)) = self.query_settings(&legacy_key).await?
+        {
+            info!(
+                "Found legacy settings for user {}, migrating to new hash format",
+                user_id
+            );
+
+            if let Err(e) = self
+                .migrate_user_data(user_id, &legacy_key, &hash_key, &settings, updated_at)
+                .await
+            {
+                warn!("Failed to migrate user data: {}", e);
+            }
+
+            return Ok(Some((settings, updated_at.to_string())));
+        }
+
+        Ok(None)
+    }
+
+    async fn query_settings(&self, key: &str) -> Result<Option<(Vec<u8>, i64)>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_user_settings, (key,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        if let Some(row) = rows_result.rows::<(Vec<u8>, i64)>()?.next() {
+            let (settings, updated_at) = row?;
+            return Ok(Some((settings, updated_at)));
+        }
+        Ok(None)
+    }
+
+    pub async fn save_user_settings(&self, user_id: &str, settings: Vec<u8>) -> Result<i64> {
+        let hash_key = hash_user_id(user_id);
+        let now = self.save_settings_for_hash(&hash_key, &settings).await?;
+
+        self.cleanup_legacy_data(user_id, &hash_key).await;
+
+        Ok(now)
+    }
+
+    async fn save_settings_for_hash(&self, hash_key: &str, settings: &[u8]) -> Result<i64> {
+        let now = chrono::Utc::now().timestamp_millis();
+
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_user_settings,
+                (hash_key, settings, now, now),
+            )
+            .await?;
+        self.settings_cache.invalidate(hash_key);
+
+        if let Err(e) = self.record_settings_version(hash_key, settings, now).await {
+            warn!("Failed to record settings history: {}", e);
+        }
+
+        Ok(now)
+    }
+
+    /// Adds a just-written version to the settings history and drops the
+    /// versions beyond `SETTINGS_HISTORY_VERSIONS`.
+    async fn record_settings_version(
+        &self,
+        hash_key: &str,
+        settings: &[u8],
+        written: i64,
+    ) -> Result<()> {
+        let keep = self.config.load().settings_history_versions;
+        if keep == 0 {
+            return Ok(());
+        }
+
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_settings_history,
+                (hash_key, written, settings),
+            )
+            .await?;
+
+        let oldest_kept = self
+            .session
+            .execute_unpaged(
+                &self.prepared.get_settings_history_written,
+                (hash_key, keep as i32),
+            )
+            .await?
+            .into_rows_result()?
+            .rows::<(i64,)>()?
+            .collect::<Result<Vec<_>, _>>()?;
+        if oldest_kept.len() == keep
+            && let Some((oldest,)) = oldest_kept.last()
+        {
+            self.session
+                .execute_unpaged(&self.prepared.trim_settings_history, (hash_key, *oldest))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// A version of the user's settings still kept in the history, by the
+    /// time it was written.
+    pub async fn get_settings_version(
+        &self,
+        user_id: &str,
+        written: i64,
+    ) -> Result<Option<Vec<u8>>> {
+        let result = self
+            .session
+            .execute_unpaged(
+                &self.prepared.get_settings_version,
+                (hash_user_id(user_id), written),
+            )
+            .await?;
+        Ok(result
+            .into_rows_result()?
+            .maybe_first_row::<(Vec<u8>,)>()?
+            .map(|(settings,)| settings))
+    }
+
+    /// Walks the settings history of every user and drops the versions
+    /// `policy` no longer keeps, reading each one first to count its size.
+    pub async fn compact_settings_history(
+        &self,
+        policy: HistoryPolicy,
+        now: i64,
+    ) -> Result<CompactionStats> {
+        let mut stats = CompactionStats::default();
+        let mut users = self
+            .session
+            .execute_iter(self.prepared.get_settings_history_user_ids.clone(), &[])
+            .await?
+            .rows_stream::<(String,)>()?;
+
+        while let Some((user_hash,)) = users.try_next().await? {
+            let written = self
+                .session
+                .execute_unpaged(&self.prepared.get_settings_history_versions, (&user_hash,))
+                .await?
+                .into_rows_result()?
+                .rows::<(i64,)>()?
+                .map(|row| row.map(|(written,)| written))
+                .collect::<Result<Vec<_>, _>>()?;
+            let kept = policy.kept(&written, now);
+            if kept == written.len() {
+                continue;
+            }
+
+            for dropped in &written[kept..] {
+                let size = self
+                    .session
+                    .execute_unpaged(&self.prepared.get_settings_version, (&user_hash, dropped))
+                    .await?
+                    .into_rows_result()?
+                    .maybe_first_row::<(Vec<u8>,)>()?
+                    .map_or(0, |(settings,)| settings.len());
+                stats.versions_removed += 1;
+                stats.bytes_reclaimed += size as u64;
+            }
+            match kept.checked_sub(1).map(|oldest| written[oldest]) {
+                Some(oldest_kept) => {
+                    self.session
+                        .execute_unpaged(
+                            &self.prepared.trim_settings_history,
+                            (&user_hash, oldest_kept),
+                        )
+                        .await?;
+                }
+                None => {
+                    self.session
+                        .execute_unpaged(&self.prepared.delete_settings_history, (&user_hash,))
+                        .await?;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    pub async fn delete_user_settings(&self, user_id: &str) -> Result<()> {
+        let hash_key = hash_user_id(user_id);
+
+        self.session
+            .execute_unpaged(&self.prepared.delete_user, (&hash_key,))
+            .await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_settings_history, (&hash_key,))
+            .await?;
+        self.settings_cache.invalidate(&hash_key);
+
+        self.cleanup_legacy_data(user_id, &hash_key).await;
+
+        Ok(())
+    }
+
+    async fn cleanup_legacy_data(&self, user_id: &str, new_key: &str) {
+        if let Some(legacy_key) = get_legacy_key_if_different(user_id, new_key)
+            && let Err(e) = self.delete_legacy_data(&legacy_key).await
+        {
+            warn!("Failed to clean up legacy data: {}", e);
+        }
+    }
+
+    async fn migrate_user_data(
+        &self,
+        user_id: &str,
+        legacy_key: &str,
+        new_key: &str,
+        settings: &[u8],
+        updated_at: i64,
+    ) -> Result<()> {
+        info!("Migrating user {} from legacy hash to SHA-256", user_id);
+
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_user_created_at, (legacy_key,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        let created_at = rows_result
+            .rows::<(i64,)>()?
+            .next()
+            .transpose()?
+            .map(|row| row.0)
+            .unwrap_or(updated_at);
+
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_user_settings,
+                (new_key, settings, created_at, updated_at),
+            )
+            .await?;
+
+        self.delete_legacy_data(legacy_key).await?;
+
+        info!("Successfully migrated user {}", user_id);
+        Ok(())
+    }
+
+    pub(crate) async fn delete_legacy_data(&self, legacy_key: &str) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.delete_user, (legacy_key,))
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the data rows still stored under the user's legacy hash to
+    /// `hash_key`, returning how many were moved. Keys that already have a
+    /// row under `hash_key` keep it; the legacy row is dropped.
+    async fn migrate_legacy_data_keys(&self, user_id: &str, hash_key: &str) -> Result<u64> {
+        let Some(legacy_key) = get_legacy_key_if_different(user_id, hash_key) else {
+            return Ok(0);
+        };
+        let rows = self
+            .session
+            .execute_unpaged(&self.prepared.get_legacy_data_rows, (&legacy_key,))
+            .await?
+            .into_rows_result()?
+            .rows::<LegacyDataRow>()?
+            .collect::<Result<Vec<_>, _>>()?;
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        let found = rows.len();
+        info!(
+            "Migrating {} data keys of a user from legacy hash to SHA-256",
+            found
+        );
+        let mut moved = 0;
+        for (
+            key,
+            value,
+            version,
+            checksum,
+            size_bytes,
+            created_at,
+            updated_at,
+            deleted,
+            deleted_at,
+            expires_at,
+            blob_hash,
+            ttl,
+        ) in rows
+        {
+            let applied = self
+                .session
+                .execute_unpaged(
+                    &self.prepared.migrate_data_row,
+                    (
+                        hash_key,
+                        &key,
+                        value,
+                        version,
+                        checksum,
+                        size_bytes,
+                        created_at,
+                        updated_at,
+                        deleted.unwrap_or(false),
+                        deleted_at,
+                        expires_at,
+                        &blob_hash,
+                        ttl.unwrap_or(0),
+                    ),
+                )
+                .await?
+                .into_rows_result()?
+                .maybe_first_row::<Row>()?
+                .and_then(|row| row.columns.into_iter().next().flatten())
+                .and_then(|applied| applied.as_boolean());
+            if applied == Some(true) {
+                moved += 1;
+            } else {
+                // The blob reference moves with the row, so one that is not
+                // moved gives its reference up.
+                release_blob(&self.session, &self.prepared, blob_hash.as_deref()).await;
+            }
+        }
+
+        self.session
+            .execute_unpaged(&self.prepared.delete_all_data, (&legacy_key,))
+            .await?;
+        info!(
+            "Migrated {} of {} legacy data keys; the rest already had newer rows",
+            moved, found
+        );
+        Ok(moved)
+    }
+
+    /// Whether reading the user's data should be retried because rows were
+    /// just moved from the legacy hash. Failing to move them only logs.
+    async fn moved_legacy_data(&self, user_id: &str, hash_key: &str) -> bool {
+        match self.migrate_legacy_data_keys(user_id, hash_key).await {
+            Ok(moved) => moved > 0,
+            Err(e) => {
+                warn!("Failed to migrate legacy data keys: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Every key of the user, tombstones included. Rows still under the
+    /// legacy hash are moved first, which costs one extra read of the legacy
+    /// partition.
+    pub async fn get_data_manifest(&self, user_id: &str) -> Result<Vec<DataManifestEntry>> {
+        let hash_key = hash_user_id(user_id);
+        self.moved_legacy_data(user_id, &hash_key).await;
+        self.manifest_for_hash(&hash_key).await
+    }
+
+    async fn manifest_for_hash(&self, hash_key: &str) -> Result<Vec<DataManifestEntry>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_data_manifest, (hash_key,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        let mut entries = Vec::new();
+        for row in rows_result.rows::<ManifestRow>()? {
+            entries.push(manifest_entry(row?));
+        }
+        Ok(entries)
+    }
+
+    /// Reads up to `page_size` manifest entries whose key starts with
+    /// `prefix`, resuming from `cursor` when given.
+    pub async fn get_data_manifest_page(
+        &self,
+        user_id: &str,
+        prefix: &str,
+        page_size: i32,
+        cursor: Option<Vec<u8>>,
+    ) -> Result<ManifestPage> {
+        let hash_key = hash_user_id(user_id);
+        if cursor.is_none() {
+            self.moved_legacy_data(user_id, &hash_key).await;
+        }
+        let mut statement = self.prepared.get_data_manifest_from.clone();
+        statement.set_page_size(page_size);
+        let paging_state = match cursor {
+            Some(cursor) => PagingState::new_from_raw_bytes(cursor),
+            None => PagingState::start(),
+        };
+
+        let (result, paging) = self
+            .session
+            .execute_single_page(&statement, (&hash_key, prefix), paging_state)
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        let mut page = ManifestPage::default();
+        for row in rows_result.rows::<ManifestRow>()? {
+            let entry = manifest_entry(row?);
+            // Keys are clustered in order, so the first key past the prefix
+            // ends the listing.
+            if !entry.key.starts_with(prefix) {
+                return Ok(page);
+            }
+            page.entries.push(entry);
+        }
+
+        if let PagingStateResponse::HasMorePages { state } = paging {
+            page.next_cursor = state.as_bytes_slice().map(|bytes| bytes.to_vec());
+        }
+        Ok(page)
+    }
+
+    pub async fn get_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataEntry>> {
+        match self.open_data_key(user_id, key).await? {
+            Some(read) => Ok(Some(read.into_entry().await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// [`Self::get_data_key`] that leaves a value stored in chunks to be
+    /// streamed.
+    pub async fn open_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataRead>> {
+        check_key(key)?;
+        let hash_key = hash_user_id(user_id);
+        let read = self.read_data_key(&hash_key, key).await?;
+        if read.is_none() && self.moved_legacy_data(user_id, &hash_key).await {
+            return self.read_data_key(&hash_key, key).await;
+        }
+        Ok(read)
+    }
+
+    /// Version, checksum and timestamps of one key without its value;
+    /// tombstones included.
+    pub async fn get_data_meta(
+        &self,
+        user_id: &str,
+        key: &str,
+    ) -> Result<Option<DataManifestEntry>> {
+        check_key(key)?;
+        let hash_key = hash_user_id(user_id);
+        let meta = self.data_meta_for_hash(&hash_key, key).await?;
+        if meta.is_none() && self.moved_legacy_data(user_id, &hash_key).await {
+            return self.data_meta_for_hash(&hash_key, key).await;
+        }
+        Ok(meta)
+    }
+
+    async fn data_meta_for_hash(
+        &self,
+        hash_key: &str,
+        key: &str,
+    ) -> Result<Option<DataManifestEntry>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_data_meta, (hash_key, key))
+            .await?;
+        Ok(result
+            .into_rows_result()?
+            .maybe_first_row::<ManifestRow>()?
+            .map(manifest_entry))
+    }
+
+    async fn data_key_for_hash(&self, hash_key: &str, key: &str) -> Result<Option<DataEntry>> {
+        match self.read_data_key(hash_key, key).await? {
+            Some(read) => Ok(Some(read.into_entry().await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn read_data_key(&self, hash_key: &str, key: &str) -> Result<Option<DataRead>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_data_key, (hash_key, key))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        if let Some(row) = rows_result
+            .rows::<(
+                String,
+                Vec<u8>,
+                i64,
+                String,
+                i32,
+                i64,
+                i64,
+                Option<bool>,
+                Option<String>,
+            )>()?
+            .next()
+        {
+            let (
+                key,
+                stored_value,
+                version,
+                checksum,
+                size_bytes,
+                created_at,
+                updated_at,
+                deleted,
+                blob_hash,
+            ) = row?;
+            if deleted.unwrap_or(false) {
+                return Ok(None);
+            }
+            let mut entry = DataEntry {
+                key,
+                value: Vec::new(),
+                version,
+                checksum,
+                size_bytes,
+                created_at,
+                updated_at,
+            };
+            return Ok(Some(
+                match open_value(&self.blobs, stored_value, blob_hash).await? {
+                    BlobBody::Whole(value) => {
+                        entry.value = value;
+                        DataRead::Loaded(entry)
+                    }
+                    BlobBody::Chunks(chunks) => DataRead::Streamed { entry, chunks },
+                },
+            ));
+        }
+        Ok(None)
+    }
+
+    pub async fn get_data_keys(&self, user_id: &str, keys: &[String]) -> Result<Vec<DataEntry>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        for key in keys {
+            check_key(key)?;
+        }
+
+        let started = Instant::now();
+        let hash_key: Arc<str> = hash_user_id(user_id).into();
+
+        let futures = keys.iter().map(|key| {
+            let session = self.session.clone();
+            let prepared = Arc::clone(&self.prepared);
+            let blobs = Arc::clone(&self.blobs);
+            let hash_key = Arc::clone(&hash_key);
+            let key = key.clone();
+            async move {
+                let result = session
+                    .execute_unpaged(&prepared.get_data_key, (hash_key.as_ref(), &key))
+                    .await?;
+                let rows_result = result.into_rows_result()?;
+                if let Some(row) = rows_result
+                    .rows::<(
+                        String,
+                        Vec<u8>,
+                        i64,
+                        String,
+                        i32,
+                        i64,
+                        i64,
+                        Option<bool>,
+                        Option<String>,
+                    )>()?
+                    .next()
+                {
+                    let (
+                        key,
+                        stored_value,
+                        version,
+                        checksum,
+                        size_bytes,
+                        created_at,
+                        updated_at,
+                        deleted,
+                        blob_hash,
+                    ) = row?;
+                    if deleted.unwrap_or(false) {
+                        return Ok(None);
+                    }
+                    let value = load_value(&blobs, stored_value, blob_hash).await?;
+                    return Ok::<_, anyhow::Error>(Some(DataEntry {
+                        key,
+                        value,
+                        version,
+                        checksum,
+                        size_bytes,
+                        created_at,
+                        updated_at,
+                    }));
+                }
+                Ok(None)
+            }
+        });
+
+        // Collected up front so no borrowing closure is held across awaits.
+        let results = run_bounded(futures.collect::<Vec<_>>(), self.batch_parallelism()).await?;
+        self.batch_reads.record(keys.len(), started.elapsed());
+        let entries: Vec<DataEntry> = results.into_iter().flatten().collect();
+        // Moving the legacy rows empties their partition, so this retries
+        // at most once.
+        if entries.len() < keys.len() && self.moved_legacy_data(user_id, &hash_key).await {
+            return Box::pin(self.get_data_keys(user_id, keys)).await;
+        }
+        Ok(entries)
+    }
+
+    pub async fn save_data_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+    ) -> Result<(i64, i64)> {
+        check_key(key)?;
+
+        let config = self.config.load();
+        check_value_size(&config, key, value.len())?;
+
+        let hash_key = hash_user_id(user_id);
+        let _guard = self.key_locks.lock(&hash_key, key).await;
+        let now = chrono::Utc::now().timestamp_millis();
+        let size_bytes = value.len() as i32;
+
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_data_version, (&hash_key, key))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        let (version, created_at, previous_blob) =
+            if let Some(row) = rows_result.rows::<(i64, i64, Option<String>)>()?.next() {
+                let (v, c, b) = row?;
+                (v + 1, c, b)
+            } else {
+                (1, now, None)
+            };
+
+        let (stored_value, blob_hash) = store_value(
+            &config,
+            &self.session,
+            &self.prepared,
+            &self.blobs,
+            &value,
+            0,
+            now,
+        )
+        .await?;
+
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_data_key,
+                (
+                    &hash_key,
+                    key,
+                    &stored_value,
+                    version,
+                    checksum,
+                    size_bytes,
+                    created_at,
+                    now,
+                    None::<i64>,
+                    &blob_hash,
+                    0i32,
+                ),
+            )
+            .await?;
+        release_blob(&self.session, &self.prepared, previous_blob.as_deref()).await;
+
+        Ok((version, now))
+    }
+
+    /// Replaces the key with a tombstone so other devices learn about the
+    /// deletion through the manifest. Returns the tombstone version, or `None`
+    /// if the key never existed.
+    pub async fn delete_data_key(&self, user_id: &str, key: &str) -> Result<Option<i64>> {
+        check_key(key)?;
+        let hash_key = hash_user_id(user_id);
+        let _guard = self.key_locks.lock(&hash_key, key).await;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_data_version, (&hash_key, key))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        let (version, created_at, previous_blob) =
+            match rows_result.rows::<(i64, i64, Option<String>)>()?.next() {
+                Some(row) => {
+                    let (v, c, b) = row?;
+                    (v + 1, c, b)
+                }
+                None => return Ok(None),
+            };
+
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_data_tombstone,
+                (
+                    &hash_key,
+                    key,
+                    version,
+                    created_at,
+                    now,
+                    now,
+                    self.config.load().tombstone_ttl_secs(),
+                ),
+            )
+            .await?;
+        release_blob(&self.session, &self.prepared, previous_blob.as_deref()).await;
+
+        Ok(Some(version))
+    }
+
+    async fn data_row_for_hash(&self, hash_key: &str, key: &str) -> Result<Option<DataRow>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_data_row, (hash_key, key))
+            .await?;
+        Ok(result.into_rows_result()?.maybe_first_row::<DataRow>()?)
+    }
+
+    /// Moves a key's value, version and checksum to `to`, leaving a tombstone
+    /// under `from`. Both rows are written in one conditional batch, so a
+    /// write to either key since they were read fails the rename rather than
+    /// being lost.
+    pub async fn rename_data_key(
+        &self,
+        user_id: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<RenameOutcome> {
+        check_key(from)?;
+        check_key(to)?;
+        let hash_key = hash_user_id(user_id);
+        let _guards = self.key_locks.lock_all(&hash_key, [from, to]).await;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let mut source = self.data_row_for_hash(&hash_key, from).await?;
+        if source.is_none() && self.moved_legacy_data(user_id, &hash_key).await {
+            source = self.data_row_for_hash(&hash_key, from).await?;
+        }
+        let Some((
+            value,
+            version,
+            checksum,
+            size_bytes,
+            created_at,
+            updated_at,
+            deleted,
+            expires_at,
+            blob_hash,
+            ttl,
+        )) = source
+        else {
+            return Ok(RenameOutcome::NotFound);
+        };
+        if deleted.unwrap_or(false) {
+            return Ok(RenameOutcome::NotFound);
+        }
+
+        let (target_version, target_blob) = match self.data_row_for_hash(&hash_key, to).await? {
+            Some((
+                _,
+                version,
+                checksum,
+                size_bytes,
+                _,
+                target_updated_at,
+                deleted,
+                expires_at,
+                blob,
+                _,
+            )) => {
+                if !deleted.unwrap_or(false) && target_updated_at > updated_at {
+                    return Ok(RenameOutcome::TargetNewer(DataManifestEntry {
+                        key: to.to_string(),
+                        version,
+                        checksum,
+                        size_bytes,
+                        updated_at: target_updated_at,
+                        deleted: false,
+                        deleted_at: None,
+                        expires_at,
+                        last_writer: None,
+                    }));
+                }
+                (Some(version), blob)
+            }
+            None => (None, None),
+        };
+
+        let new_version = renamed_version(version, target_version);
+        let tombstone_version = version + 1;
+        let ttl = ttl.unwrap_or(0);
+        let tombstone_ttl = self.config.load().tombstone_ttl_secs();
+        let tombstone = (
+            tombstone_ttl,
+            tombstone_version,
+            created_at,
+            now,
+            now,
+            &hash_key,
+            from,
+            version,
+        );
+
+        let mut batch = Batch::default();
+        let result = match target_version {
+            None => {
+                batch.append_statement(self.prepared.insert_data_key_if_absent.clone());
+                batch.append_statement(self.prepared.update_data_tombstone_if_version.clone());
+                self.session
+                    .batch(
+                        "rename_data_key",
+                        &batch,
+                        (
+                            (
+                                &hash_key,
+                                to,
+                                &value,
+                                new_version,
+                                &checksum,
+                                size_bytes,
+                                created_at,
+                                now,
+                                expires_at,
+                                &blob_hash,
+                                ttl,
+                            ),
+                            tombstone,
+                        ),
+                    )
+                    .await?
+            }
+            Some(target_version) => {
+                batch.append_statement(self.prepared.update_data_key_if_version.clone());
+                batch.append_statement(self.prepared.update_data_tombstone_if_version.clone());
+                self.session
+                    .batch(
+                        "rename_data_key",
+                        &batch,
+                        (
+                            (
+                                ttl,
+                                &value,
+                                new_version,
+                                &checksum,
+                                size_bytes,
+                                created_at,
+                                now,
+                                expires_at,
+                                &blob_hash,
+                                &hash_key,
+                                to,
+                                target_version,
+                            ),
+                            tombstone,
+                        ),
+                    )
+                    .await?
+            }
+        };
+        if !lwt_applied(result)? {
+            return Ok(RenameOutcome::Changed);
+        }
+
+        // Updates leave the row markers of earlier inserts, which would
+        // outlive cells written with a TTL.
+        if target_version.is_some() && ttl > 0 {
+            self.session
+                .execute_unpaged(
+                    &self.prepared.insert_data_key,
+                    (
+                        &hash_key,
+                        to,
+                        &value,
+                        new_version,
+                        &checksum,
+                        size_bytes,
+                        created_at,
+                        now,
+                        expires_at,
+                        &blob_hash,
+                        ttl,
+                    ),
+                )
+                .await?;
+        }
+        if tombstone_ttl > 0 {
+            self.session
+                .execute_unpaged(
+                    &self.prepared.insert_data_tombstone,
+                    (
+                        &hash_key,
+                        from,
+                        tombstone_version,
+                        created_at,
+                        now,
+                        now,
+                        tombstone_ttl,
+                    ),
+                )
+                .await?;
+        }
+        // The moved value keeps its blob reference; the one it replaced gives
+        // its reference up.
+        release_blob(&self.session, &self.prepared, target_blob.as_deref()).await;
+
+        Ok(RenameOutcome::Renamed {
+            version: new_version,
+            updated_at: now,
+            tombstone_version,
+        })
+    }
+
+    /// Deletes every data row of a user, tombstones included, returning the
+    /// number of live keys removed.
+    pub(crate) async fn delete_all_data_by_hash(&self, hash_key: &str) -> Result<u64> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_user_blob_hashes, (hash_key,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        let mut blob_hashes = Vec::new();
+        let mut live_keys = 0;
+        for row in rows_result.rows::<(Option<String>, Option<bool>)>()? {
+            let (hash, deleted) = row?;
+            if deleted != Some(true) {
+                live_keys += 1;
+            }
+            if let Some(hash) = hash {
+                blob_hashes.push(hash);
+            }
+        }
+
+        self.session
+            .execute_unpaged(&self.prepared.delete_all_data, (hash_key,))
+            .await?;
+
+        for hash in &blob_hashes {
+            release_blob(&self.session, &self.prepared, Some(hash)).await;
+        }
+        Ok(live_keys)
+    }
+
+    pub async fn save_data_keys_batch(
+        &self,
+        user_id: &str,
+        entries: Vec<DataUpload>,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        self.save_data_keys_for_hash(hash_user_id(user_id).into(), entries)
+            .await
+    }
+
+    /// Writes `entries` with their keys locked, each as the version after
+    /// the one read once the lock is held.
+    async fn save_data_keys_for_hash(
+        &self,
+        hash_key: Arc<str>,
+        entries: Vec<DataUpload>,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let started = Instant::now();
+        let keys: Vec<String> = entries.iter().map(|upload| upload.key.clone()).collect();
+        let _guards = self
+            .key_locks
+            .lock_all(&hash_key, keys.iter().map(String::as_str))
+            .await;
+        let existing_versions = self.versions_for_hash(Arc::clone(&hash_key), &keys).await?;
+        let now = chrono::Utc::now().timestamp_millis();
+        let config = self.config.load();
+
+        let prepared_entries: Vec<_> = entries
+            .into_iter()
+            .filter_map(|upload| {
+                let DataUpload {
+                    key,
+                    value,
+                    checksum,
+                    ttl_secs,
+                } = upload;
+                if value.len() > config.namespaces.max_size(&key) {
+                    return None;
+                }
+                let (version, created_at, previous_blob) = match existing_versions.get(&key) {
+                    Some((v, c, b)) => (v + 1, *c, b.clone()),
+                    None => (1, now, None),
+                };
+                Some((
+                    key,
+                    value,
+                    checksum,
+                    version,
+                    created_at,
+                    previous_blob,
+                    expiry(now, ttl_secs),
+                ))
+            })
+            .collect();
+
+        let futures = prepared_entries.into_iter().map(
+            |(key, value, checksum, version, created_at, previous_blob, expiry)| {
+                let session = self.session.clone();
+                let prepared = Arc::clone(&self.prepared);
+                let blobs = Arc::clone(&self.blobs);
+                let hash_key = Arc::clone(&hash_key);
+                let config = Arc::clone(&config);
+
+                async move {
+                    let size_bytes = value.len() as i32;
+                    let (stored_value, blob_hash) =
+                        store_value(&config, &session, &prepared, &blobs, &value, expiry.1, now)
+                            .await?;
+                    session
+                        .execute_unpaged(
+                            &prepared.insert_data_key,
+                            (
+                                hash_key.as_ref(),
+                                &key,
+                                &stored_value,
+                                version,
+                                &checksum,
+                                size_bytes,
+                                created_at,
+                                now,
+                                expiry.0,
+                                &blob_hash,
+                                expiry.1,
+                            ),
+                        )
+                        .await?;
+                    release_blob(&session, &prepared, previous_blob.as_deref()).await;
+
+                    Ok::<_, anyhow::Error>((key, version, now))
+                }
+            },
+        );
+
+        let saved = run_bounded(futures, self.batch_parallelism()).await?;
+        self.batch_writes.record(saved.len(), started.elapsed());
+        Ok(saved)
+    }
+
+    pub async fn get_versions_batch(
+        &self,
+        user_id: &str,
+        keys: &[String],
+    ) -> Result<ExistingVersions> {
+        self.versions_for_hash(hash_user_id(user_id).into(), keys)
+            .await
+    }
+
+    async fn versions_for_hash(
+        &self,
+        hash_key: Arc<str>,
+        keys: &[String],
+    ) -> Result<ExistingVersions> {
+        if keys.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let futures = keys.iter().map(|key| {
+            let session = self.session.clone();
+            let prepared = Arc::clone(&self.prepared);
+            let hash_key = Arc::clone(&hash_key);
+            let key = key.clone();
+            async move {
+                let result = session
+                    .execute_unpaged(&prepared.get_data_version, (hash_key.as_ref(), &key))
+                    .await?;
+                let rows_result = result.into_rows_result()?;
+                if let Some(row) = rows_result.rows::<(i64, i64, Option<String>)>()?.next() {
+                    let (version, created_at, blob_hash) = row?;
+                    return Ok::<_, anyhow::Error>(Some((key, (version, created_at, blob_hash))));
+                }
+                Ok(None)
+            }
+        });
+
+        let results = run_bounded(futures.collect::<Vec<_>>(), self.batch_parallelism()).await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    pub async fn get_user_total_size(&self, user_id: &str) -> Result<i64> {
+        let hash_key = hash_user_id(user_id);
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_user_total_size, (&hash_key,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        if let Some(row) = rows_result.rows::<(Option<i32>,)>()?.next() {
+            let (sum,) = row?;
+            return Ok(sum.unwrap_or(0) as i64);
+        }
+        Ok(0)
+    }
+
+    pub async fn get_user_size_and_key_size(&self, user_id: &str, key: &str) -> Result<(i64, i64)> {
+        check_key(key)?;
+        let hash_key: Arc<str> = hash_user_id(user_id).into();
+        let key: Arc<str> = key.into();
+
+        let session1 = self.session.clone();
+        let session2 = self.session.clone();
+        let prepared1 = Arc::clone(&self.prepared);
+        let prepared2 = Arc::clone(&self.prepared);
+        let hash_key1 = Arc::clone(&hash_key);
+        let hash_key2 = hash_key;
+        let key = Arc::clone(&key);
+
+        let total_future = async move {
+            let result = session1
+                .execute_unpaged(&prepared1.get_user_total_size, (hash_key1.as_ref(),))
+                .await?;
+            let rows_result = result.into_rows_result()?;
+            let total = match rows_result.rows::<(Option<i32>,)>()?.next() {
+                Some(row) => row?.0.unwrap_or(0) as i64,
+                None => 0,
+            };
+            Ok::<i64, anyhow::Error>(total)
+        };
+
+        let key_future = async move {
+            let result = session2
+                .execute_unpaged(&prepared2.get_key_size, (hash_key2.as_ref(), key.as_ref()))
+                .await?;
+            let rows_result = result.into_rows_result()?;
+            let size = match rows_result.rows::<(i32,)>()?.next() {
+                Some(row) => row?.0 as i64,
+                None => 0,
+            };
+            Ok::<i64, anyhow::Error>(size)
+        };
+
+        let (total_result, key_result) = join!(total_future, key_future);
+        Ok((total_result?, key_result?))
+    }
+
+    pub async fn save_data_key_with_quota_check(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+    ) -> Result<Option<(i64, i64)>> {
+        match self
+            .save_data_key_guarded(user_id, key, value, checksum, ttl_secs, None)
+            .await?
+        {
+            ConditionalWrite::Saved {
+                version,
+                updated_at,
+            } => Ok(Some((version, updated_at))),
+            ConditionalWrite::QuotaExceeded => Ok(None),
+            ConditionalWrite::Changed => unreachable!("unconditional writes cannot conflict"),
+        }
+    }
+
+    /// Like [`Self::save_data_key_with_quota_check`], but only writes if the
+    /// key's row, tombstones included, is still at `expected` (`None` for no
+    /// row at all). The check is a lightweight transaction, so it holds
+    /// against other conditional writers.
+    pub async fn save_data_key_if_version(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+        expected: Option<i64>,
+    ) -> Result<ConditionalWrite> {
+        self.save_data_key_guarded(user_id, key, value, checksum, ttl_secs, Some(expected))
+            .await
+    }
+
+    async fn save_data_key_guarded(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+        expected: Option<Option<i64>>,
+    ) -> Result<ConditionalWrite> {
+        check_key(key)?;
+
+        let config = self.config.load();
+        check_value_size(&config, key, value.len())?;
+
+        let hash_key: Arc<str> = hash_user_id(user_id).into();
+        let _guard = self.key_locks.lock(&hash_key, key).await;
+        let now = chrono::Utc::now().timestamp_millis();
+        let new_size = value.len() as i32;
+        let key: Arc<str> = key.into();
+
+        let (total_size_result, version_result, quota_result) = {
+            let session1 = self.session.clone();
+            let session2 = self.session.clone();
+            let prepared1 = Arc::clone(&self.prepared);
+            let prepared2 = Arc::clone(&self.prepared);
+            let hash_key1 = Arc::clone(&hash_key);
+            let hash_key2 = Arc::clone(&hash_key);
+            let key_clone = Arc::clone(&key);
+
+            let total_future = async move {
+                let result = session1
+                    .execute_unpaged(&prepared1.get_user_total_size, (hash_key1.as_ref(),))
+                    .await?;
+                let rows_result = result.into_rows_result()?;
+                Ok::<i64, anyhow::Error>(
+                    rows_result
+                        .rows::<(Option<i32>,)>()?
+                        .next()
+                        .transpose()?
+                        .and_then(|r| r.0)
+                        .unwrap_or(0) as i64,
+                )
+            };
+
+            let version_future = async move {
+                let result = session2
+                    .execute_unpaged(
+                        &prepared2.get_data_version_and_size,
+                        (hash_key2.as_ref(), key_clone.as_ref()),
+                    )
+                    .await?;
+                let rows_result = result.into_rows_result()?;
+                Ok::<Option<(i64, i64, i32, Option<String>)>, anyhow::Error>(
+                    rows_result
+                        .rows::<(i64, i64, i32, Option<String>)>()?
+                        .next()
+                        .transpose()?,
+                )
+            };
+
+            join!(
+                total_future,
+                version_future,
+                self.quota_for_hash(hash_key.as_ref())
+            )
+        };
+
+        let total_size = total_size_result?;
+        let existing = version_result?;
+        let max_total_size = quota_result?;
+
+        if expected.is_some_and(|expected| expected != existing.as_ref().map(|e| e.0)) {
+            return Ok(ConditionalWrite::Changed);
+        }
+
+        let (version, created_at, existing_size, previous_blob) = match existing {
+            Some((v, c, s, b)) => (v + 1, c, s as i64, b),
+            None => (1, now, 0, None),
+        };
+
+        let new_total = total_size - existing_size + new_size as i64;
+        if new_total > max_total_size {
+            return Ok(ConditionalWrite::QuotaExceeded);
+        }
+
+        let (expires_at, ttl) = expiry(now, ttl_secs);
+        let (stored_value, blob_hash) = store_value(
+            &config,
+            &self.session,
+            &self.prepared,
+            &self.blobs,
+            &value,
+            ttl,
+            now,
+        )
+        .await?;
+
+        let row = (
+            hash_key.as_ref(),
+            key.as_ref(),
+            &stored_value,
+            version,
+            checksum,
+            new_size,
+            created_at,
+            now,
+            expires_at,
+            &blob_hash,
+            ttl,
+        );
+        let applied = match expected {
+            None => {
+                self.session
+                    .execute_unpaged(&self.prepared.insert_data_key, row)
+                    .await?;
+                true
+            }
+            Some(None) => {
+                let result = self
+                    .session
+                    .execute_unpaged(&self.prepared.insert_data_key_if_absent, row)
+                    .await?;
+                lwt_applied(result)?
+            }
+            Some(Some(expected)) => {
+                let result = self
+                    .session
+                    .execute_unpaged(
+                        &self.prepared.update_data_key_if_version,
+                        (
+                            ttl,
+                            &stored_value,
+                            version,
+                            checksum,
+                            new_size,
+                            created_at,
+                            now,
+                            expires_at,
+                            &blob_hash,
+                            hash_key.as_ref(),
+                            key.as_ref(),
+                            expected,
+                        ),
+                    )
+                    .await?;
+                let applied = lwt_applied(result)?;
+                // An update leaves the row marker of the earlier insert, which
+                // would outlive cells written with a TTL.
+                if applied && ttl > 0 {
+                    self.session
+                        .execute_unpaged(&self.prepared.insert_data_key, row)
+                        .await?;
+                }
+                applied
+            }
+        };
+        if !applied {
+            release_blob(&self.session, &self.prepared, blob_hash.as_deref()).await;
+            return Ok(ConditionalWrite::Changed);
+        }
+        release_blob(&self.session, &self.prepared, previous_blob.as_deref()).await;
+
+        Ok(ConditionalWrite::Saved {
+            version,
+            updated_at: now,
+        })
+    }
+
+    pub async fn get_storage_usage(&self, user_id: &str) -> Result<StorageUsage> {
+        let hash_key = hash_user_id(user_id);
+        let mut usage = StorageUsage::default();
+
+        if let Some((settings, updated_at)) = self.query_settings(&hash_key).await? {
+            usage.settings_size = Some(settings.len() as i64);
+            usage.settings_updated_at = Some(updated_at);
+        }
+
+        for entry in self.get_data_manifest(user_id).await? {
+            if entry.deleted {
+                continue;
+            }
+            usage.data_keys += 1;
+            usage.data_size += entry.size_bytes as i64;
+        }
+
+        Ok(usage)
+    }
+
+    pub async fn list_users_created_since(
+        &self,
+        since: i64,
+        limit: usize,
+    ) -> Result<Vec<UserSummary>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_users_created_since, (since,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        let mut users = Vec::new();
+        for row in rows_result.rows::<(String, Option<i64>, Option<i64>)>()? {
+            let (id, created_at, updated_at) = row?;
+            users.push(UserSummary {
+                id,
+                created_at: created_at.unwrap_or(0),
+                updated_at: updated_at.unwrap_or(0),
+            });
+        }
+
+        users.sort_by_key(|u| std::cmp::Reverse(u.created_at));
+        users.truncate(limit);
+        Ok(users)
+    }
+
+    /// Unlike [`Self::get_data_manifest`], rows under the legacy hash are
+    /// not looked at, as the plain id is not known.
+    pub async fn get_data_manifest_by_hash(
+        &self,
+        user_hash: &str,
+    ) -> Result<Vec<DataManifestEntry>> {
+        self.manifest_for_hash(user_hash).await
+    }
+
+    pub async fn get_data_key_by_hash(
+        &self,
+        user_hash: &str,
+        key: &str,
+    ) -> Result<Option<DataEntry>> {
+        check_key(key)?;
+        self.data_key_for_hash(user_hash, key).await
+    }
+
+    /// Hashed ids of every user with settings or data.
+    pub async fn list_user_hashes(&self) -> Result<Vec<String>> {
+        let mut hashes = BTreeSet::new();
+        for statement in [
+            &self.prepared.get_all_user_ids,
+            &self.prepared.get_data_user_ids,
+        ] {
+            let mut rows = self
+                .session
+                .execute_iter(statement.clone(), &[])
+                .await?
+                .rows_stream::<(String,)>()?;
+            while let Some((id,)) = rows.try_next().await? {
+                hashes.insert(id);
+            }
+        }
+        Ok(hashes.into_iter().collect())
+    }
+
+    /// Settings and live data of the user with this hashed id. Rows under
+    /// the legacy hash are read as they are, like any other id.
+    pub async fn snapshot_user_by_hash(&self, user_hash: &str) -> Result<UserSnapshot> {
+        let settings = self.query_settings(user_hash).await?;
+        let mut data = Vec::new();
+        for meta in self.manifest_for_hash(user_hash).await? {
+            if meta.deleted {
+                continue;
+            }
+            if let Some(entry) = self.data_key_for_hash(user_hash, &meta.key).await? {
+                data.push((entry, meta.expires_at));
+            }
+        }
+        Ok(UserSnapshot { settings, data })
+    }
+
+    /// Writes settings and data back under a hashed id, each key as a new
+    /// version over any already stored. Returns how many keys were written.
+    pub async fn restore_user_by_hash(
+        &self,
+        user_hash: &str,
+        settings: Option<Vec<u8>>,
+        data: Vec<DataUpload>,
+    ) -> Result<usize> {
+        if let Some(settings) = settings {
+            self.save_settings_for_hash(user_hash, &settings).await?;
+        }
+        let saved = self.save_data_keys_for_hash(user_hash.into(), data).await?;
+        Ok(saved.len())
+    }
+
+    /// Scans the users and data tables for rows still keyed by the legacy
+    /// CRC32 hash, deleting them when `delete` is set. The hash cannot be
+    /// traced back to a user, so rows are only ever moved to the new hash
+    /// lazily, when their user next reads them.
+    pub async fn cleanup_legacy_users(&self, delete: bool) -> Result<LegacyCleanupReport> {
+        hash_migration::scan_legacy_users(
+            self,
+            &ScanOptions {
+                delete,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Reads up to `page_size` ids from the table scanned in `phase`,
+    /// resuming from `cursor` when given. Returns the ids and the cursor of
+    /// the next page, if any.
+    pub async fn user_id_page(
+        &self,
+        phase: ScanPhase,
+        page_size: i32,
+        cursor: Option<Vec<u8>>,
+    ) -> Result<(Vec<String>, Option<Vec<u8>>)> {
+        let mut statement = match phase {
+            ScanPhase::Users => self.prepared.get_all_user_ids.clone(),
+            ScanPhase::Data => self.prepared.get_data_user_ids.clone(),
+        };
+        statement.set_page_size(page_size);
+        let paging_state = match cursor {
+            Some(cursor) => PagingState::new_from_raw_bytes(cursor),
+            None => PagingState::start(),
+        };
+
+        let (result, paging) = self
+            .session
+            .execute_single_page(&statement, &[], paging_state)
+            .await?;
+        let ids = result
+            .into_rows_result()?
+            .rows::<(String,)>()?
+            .map(|row| row.map(|(id,)| id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let next = match paging {
+            PagingStateResponse::HasMorePages { state } => {
+                state.as_bytes_slice().map(|bytes| bytes.to_vec())
+            }
+            PagingStateResponse::NoMorePages => None,
+        };
+        Ok((ids, next))
+    }
+
+    pub async fn save_oauth_state(
+        &self,
+        state: &str,
+        code_verifier: Option<&str>,
+        redirect: Option<&OAuthRedirect>,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_oauth_state,
+                (
+                    state,
+                    code_verifier,
+                    redirect.map(|r| r.uri.as_str()),
+                    redirect.map(|r| r.mode.name()),
+                    ttl_secs,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Looks up and deletes a pending OAuth state so it can only be used once.
+    /// Returns `None` for unknown or expired states. The delete is
+    /// conditional, so of two callbacks racing with the same state only one
+    /// gets it.
+    pub async fn consume_oauth_state(&self, state: &str) -> Result<Option<OAuthState>> {
+        let Some((code_verifier, redirect_uri, redirect_mode)) = self
+            .session
+            .execute_unpaged(&self.prepared.get_oauth_state, (state,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<(Option<String>, Option<String>, Option<String>)>()?
+        else {
+            return Ok(None);
+        };
+        let deleted = self
+            .session
+            .execute_unpaged(&self.prepared.delete_oauth_state, (state,))
+            .await?;
+        Ok(lwt_applied(deleted)?
+            .then(|| OAuthState::from_columns(code_verifier, redirect_uri, redirect_mode)))
+    }
+
+    pub async fn save_oauth_code(
+        &self,
+        code: &str,
+        pending: &OAuthCode,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_oauth_code,
+                (code, &pending.user_id, &pending.scopes, ttl_secs),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Looks up and deletes a one-time code. The delete is conditional, so of
+    /// two requests racing with the same code only one gets it.
+    pub async fn consume_oauth_code(&self, code: &str) -> Result<Option<OAuthCode>> {
+        let Some((user_id, scopes)) = self
+            .session
+            .execute_unpaged(&self.prepared.get_oauth_code, (code,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<(String, String)>()?
+        else {
+            return Ok(None);
+        };
+        let deleted = self
+            .session
+            .execute_unpaged(&self.prepared.delete_oauth_code, (code,))
+            .await?;
+        Ok(lwt_applied(deleted)?.then_some(OAuthCode { user_id, scopes }))
+    }
+
+    pub async fn save_refresh_token(&self, user_id: &str, encrypted: &[u8]) -> Result<()> {
+        let hash_key = hash_user_id(user_id);
+        let now = chrono::Utc::now().timestamp_millis();
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_refresh_token,
+                (&hash_key, encrypted, now),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_refresh_token(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        let hash_key = hash_user_id(user_id);
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_refresh_token, (&hash_key,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        Ok(rows_result
+            .rows::<(Option<Vec<u8>>,)>()?
+            .next()
+            .transpose()?
+            .and_then(|row| row.0))
+    }
+
+    pub async fn delete_refresh_token(&self, user_id: &str) -> Result<()> {
         let hash_key = hash_user_id(user_id);
+        self.session
+            .execute_unpaged(&self.prepared.delete_refresh_token, (&hash_key,))
+            .await?;
+        Ok(())
+    }
+
+    /// The account `user_id` signs in to, if it is a linked identity.
+    pub async fn get_account_link(&self, user_id: &str) -> Result<Option<String>> {
         let result = self
             .session
-            .execute_unpaged(&self.prepared.get_data_manifest, (&hash_key,))
+            .execute_unpaged(&self.prepared.get_account_link, (hash_user_id(user_id),))
             .await?;
         let rows_result = result.into_rows_result()?;
+        Ok(rows_result
+            .rows::<(Option<String>,)>()?
+            .next()
+            .transpose()?
+            .and_then(|row| row.0))
+    }
 
-        let mut entries = Vec::new();
-        for row in rows_result.rows::<(String, i64, String, i32, i64)>()? {
-            let (key, version, checksum, size_bytes, updated_at) = row?;
-            entries.push(DataManifestEntry {
-                key,
-                version,
-                checksum,
-                size_bytes,
-                updated_at,
+    pub async fn save_account_link(&self, user_id: &str, account_id: &str) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_account_link,
+                (
+                    hash_user_id(user_id),
+                    account_id,
+                    hash_user_id(account_id),
+                    now,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Identities linked to `account_id`, oldest first.
+    pub async fn list_account_links(&self, account_id: &str) -> Result<Vec<AccountLink>> {
+        self.account_links_for_hash(&hash_user_id(account_id)).await
+    }
+
+    async fn account_links_for_hash(&self, account_hash: &str) -> Result<Vec<AccountLink>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_account_links, (account_hash,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        let mut links = Vec::new();
+        for row in rows_result.rows::<(String, Option<i64>)>()? {
+            let (user, linked_at) = row?;
+            links.push(AccountLink {
+                user,
+                linked_at: linked_at.unwrap_or(0),
             });
         }
-        Ok(entries)
+        links.sort_by_key(|link| link.linked_at);
+        Ok(links)
+    }
+
+    pub async fn delete_account_link(&self, user_id: &str) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.delete_account_link, (hash_user_id(user_id),))
+            .await?;
+        Ok(())
+    }
+
+    /// The ban on `user_hash`, if one is in force.
+    pub async fn get_ban(&self, user_hash: &str) -> Result<Option<Ban>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_ban, (user_hash,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        let now = chrono::Utc::now().timestamp_millis();
+        Ok(rows_result
+            .rows::<BanRow>()?
+            .next()
+            .transpose()?
+            .map(ban_from_row)
+            .filter(|ban| ban.expires_at.is_none_or(|at| at > now)))
+    }
+
+    /// Bans `ban.user`, replacing any ban already on them. A temporary ban
+    /// is written with a TTL so the row goes away when it lifts.
+    pub async fn save_ban(&self, ban: &Ban) -> Result<()> {
+        let ttl = match ban.expires_at {
+            Some(at) => ((at - chrono::Utc::now().timestamp_millis()) / 1000).max(1) as i32,
+            None => 0,
+        };
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_ban,
+                (
+                    &ban.user,
+                    &ban.reason,
+                    ban.banned_at,
+                    ban.expires_at,
+                    ban.automatic,
+                    ttl,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Every ban in force, newest first.
+    pub async fn list_bans(&self) -> Result<Vec<Ban>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_bans, &[])
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let mut bans = Vec::new();
+        for row in rows_result.rows::<BanRow>()? {
+            let ban = ban_from_row(row?);
+            if ban.expires_at.is_none_or(|at| at > now) {
+                bans.push(ban);
+            }
+        }
+        bans.sort_by_key(|ban| std::cmp::Reverse(ban.banned_at));
+        Ok(bans)
+    }
+
+    pub async fn delete_ban(&self, user_hash: &str) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.delete_ban, (user_hash,))
+            .await?;
+        Ok(())
+    }
+
+    /// The lockout on `subject`, if one is in force.
+    pub async fn get_auth_lockout(&self, subject: &str) -> Result<Option<AuthLockout>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_auth_lockout, (subject,))
+            .await?;
+        let now = chrono::Utc::now().timestamp_millis();
+        Ok(result
+            .into_rows_result()?
+            .maybe_first_row::<(String, i64, i32)>()?
+            .map(|(subject, locked_until, failures)| AuthLockout {
+                subject,
+                locked_until,
+                failures: failures.max(0) as u32,
+            })
+            .filter(|lockout| lockout.locked_until > now))
+    }
+
+    /// Stores `lockout` with a TTL so the row goes away when it lifts.
+    pub async fn save_auth_lockout(&self, lockout: &AuthLockout) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let ttl = ((lockout.locked_until - now) / 1000).max(1) as i32;
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_auth_lockout,
+                (
+                    &lockout.subject,
+                    lockout.locked_until,
+                    lockout.failures as i32,
+                    ttl,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_pending_deletion(&self, user_hash: &str) -> Result<Option<PendingDeletion>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_pending_deletion, (user_hash,))
+            .await?;
+        Ok(result
+            .into_rows_result()?
+            .maybe_first_row::<PendingDeletionRow>()?
+            .map(pending_deletion_from_row))
+    }
+
+    /// Schedules the deletion of `pending.user_id`'s account, replacing any
+    /// already scheduled.
+    pub async fn save_pending_deletion(&self, pending: &PendingDeletion) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_pending_deletion,
+                (
+                    hash_user_id(&pending.user_id),
+                    &pending.user_id,
+                    pending.requested_at,
+                    pending.purge_at,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Every scheduled deletion, soonest first.
+    pub async fn list_pending_deletions(&self) -> Result<Vec<PendingDeletion>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_pending_deletions, &[])
+            .await?;
+        let mut pending = result
+            .into_rows_result()?
+            .rows::<PendingDeletionRow>()?
+            .map(|row| row.map(pending_deletion_from_row))
+            .collect::<Result<Vec<_>, _>>()?;
+        pending.sort_by_key(|p| p.purge_at);
+        Ok(pending)
+    }
+
+    pub async fn delete_pending_deletion(&self, user_hash: &str) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.delete_pending_deletion, (user_hash,))
+            .await?;
+        Ok(())
+    }
+
+    /// Records `device` as seen at its `last_seen_at` and as the last writer
+    /// of each of `keys`.
+    pub async fn record_device_write(
+        &self,
+        user_id: &str,
+        device: &Device,
+        keys: &[String],
+    ) -> Result<()> {
+        let hash_key = hash_user_id(user_id);
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_device,
+                (
+                    &hash_key,
+                    &device.device_id,
+                    &device.client_name,
+                    device.last_seen_at,
+                ),
+            )
+            .await?;
+
+        let futures = keys.iter().map(|key| {
+            let hash_key = &hash_key;
+            async move {
+                self.session
+                    .execute_unpaged(
+                        &self.prepared.insert_key_writer,
+                        (
+                            hash_key,
+                            key,
+                            &device.device_id,
+                            &device.client_name,
+                            device.last_seen_at,
+                        ),
+                    )
+                    .await?;
+                Ok(())
+            }
+        });
+        run_bounded(futures.collect::<Vec<_>>(), self.batch_parallelism()).await?;
+        Ok(())
+    }
+
+    /// Every device of the user, most recently seen first.
+    pub async fn list_devices(&self, user_id: &str) -> Result<Vec<Device>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_devices, (hash_user_id(user_id),))
+            .await?;
+        let mut devices = result
+            .into_rows_result()?
+            .rows::<DeviceRow>()?
+            .map(|row| row.map(device_from_row))
+            .collect::<Result<Vec<_>, _>>()?;
+        devices.sort_by_key(|d| std::cmp::Reverse(d.last_seen_at));
+        Ok(devices)
+    }
+
+    /// Records a sync by `user_id`, kept for `ttl_secs`.
+    pub async fn insert_sync_session(
+        &self,
+        user_id: &str,
+        sync: &SyncSession,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_sync_session,
+                (
+                    hash_user_id(user_id),
+                    sync.started_at,
+                    uuid::Uuid::new_v4(),
+                    &sync.device_id,
+                    &sync.client_name,
+                    sync.duration_ms,
+                    sync.uploads as i32,
+                    sync.downloads as i32,
+                    sync.deletions as i32,
+                    sync.conflicts as i32,
+                    sync.errors as i32,
+                    sync.bytes_uploaded,
+                    sync.bytes_downloaded,
+                    ttl_secs,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The user's last `limit` syncs, newest first.
+    pub async fn list_sync_sessions(
+        &self,
+        user_id: &str,
+        limit: usize,
+    ) -> Result<Vec<SyncSession>> {
+        let result = self
+            .session
+            .execute_unpaged(
+                &self.prepared.get_sync_sessions,
+                (hash_user_id(user_id), limit as i32),
+            )
+            .await?;
+        let sessions = result
+            .into_rows_result()?
+            .rows::<SyncSessionRow>()?
+            .map(|row| row.map(sync_session_from_row))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(sessions)
+    }
+
+    /// The device that last wrote each key, for keys a named device wrote.
+    pub async fn get_last_writers(&self, user_id: &str) -> Result<HashMap<String, LastWriter>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_key_writers, (hash_user_id(user_id),))
+            .await?;
+        let mut writers = HashMap::new();
+        for row in result
+            .into_rows_result()?
+            .rows::<(String, String, Option<String>, Option<i64>)>()?
+        {
+            let (key, device_id, client_name, written_at) = row?;
+            writers.insert(
+                key,
+                LastWriter {
+                    device_id,
+                    client_name,
+                    written_at: written_at.unwrap_or(0),
+                },
+            );
+        }
+        Ok(writers)
+    }
+
+    pub async fn save_api_key(&self, key: &ApiKey) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_api_key,
+                (
+                    &key.id,
+                    &key.name,
+                    &key.user_id,
+                    &key.key_hash,
+                    key.scope.as_str(),
+                    key.created_at,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_api_key, (id,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        Ok(rows_result
+            .rows::<ApiKeyRow>()?
+            .next()
+            .transpose()?
+            .and_then(api_key_from_row))
     }
 
-    pub async fn get_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataEntry>> {
-        check_key(key)?;
-        let hash_key = hash_user_id(user_id);
+    /// Every API key, newest first.
+    pub async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
         let result = self
             .session
-            .execute_unpaged(&self.prepared.get_data_key, (&hash_key, key))
+            .execute_unpaged(&self.prepared.get_api_keys, &[])
             .await?;
         let rows_result = result.into_rows_result()?;
 
-        if let Some(row) = rows_result
-            .rows::<(String, Vec<u8>, i64, String, i32, i64, i64)>()?
-            .next()
-        {
-            let (key, compressed_value, version, checksum, size_bytes, created_at, updated_at) =
-                row?;
-            return Ok(Some(DataEntry {
-                key,
-                value: decompress(&compressed_value),
-                version,
-                checksum,
-                size_bytes,
-                created_at,
-                updated_at,
-            }));
+        let mut keys = Vec::new();
+        for row in rows_result.rows::<ApiKeyRow>()? {
+            keys.extend(api_key_from_row(row?));
         }
-        Ok(None)
+        keys.sort_by_key(|key| std::cmp::Reverse(key.created_at));
+        Ok(keys)
     }
 
-    pub async fn get_data_keys(&self, user_id: &str, keys: &[String]) -> Result<Vec<DataEntry>> {
-        if keys.is_empty() {
-            return Ok(Vec::new());
-        }
+    pub async fn delete_api_key(&self, id: &str) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.delete_api_key, (id,))
+            .await?;
+        Ok(())
+    }
 
-        for key in keys {
-            check_key(key)?;
-        }
+    /// Saves a share link, written with a TTL so the row goes away when the
+    /// link expires.
+    pub async fn save_share(&self, share: &DataShare) -> Result<()> {
+        let ttl = ((share.expires_at - chrono::Utc::now().timestamp_millis()) / 1000).max(1);
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_share,
+                (
+                    &share.id,
+                    &share.user_id,
+                    hash_user_id(&share.user_id),
+                    &share.key,
+                    share.created_at,
+                    share.expires_at,
+                    share.max_downloads,
+                    ttl as i32,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
 
-        let hash_key: Arc<str> = hash_user_id(user_id).into();
+    pub async fn get_share(&self, id: &str) -> Result<Option<DataShare>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_share, (id,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        Ok(rows_result
+            .rows::<ShareRow>()?
+            .next()
+            .transpose()?
+            .map(share_from_row))
+    }
 
-        let futures = keys.iter().map(|key| {
-            let session = Arc::clone(&self.session);
-            let prepared = Arc::clone(&self.prepared);
-            let hash_key = Arc::clone(&hash_key);
-            let key = key.clone();
-            async move {
-                let result = session
-                    .execute_unpaged(&prepared.get_data_key, (hash_key.as_ref(), &key))
-                    .await?;
-                let rows_result = result.into_rows_result()?;
-                if let Some(row) = rows_result
-                    .rows::<(String, Vec<u8>, i64, String, i32, i64, i64)>()?
-                    .next()
-                {
-                    let (
-                        key,
-                        compressed_value,
-                        version,
-                        checksum,
-                        size_bytes,
-                        created_at,
-                        updated_at,
-                    ) = row?;
-                    return Ok::<_, anyhow::Error>(Some(DataEntry {
-                        key,
-                        value: decompress(&compressed_value),
-                        version,
-                        checksum,
-                        size_bytes,
-                        created_at,
-                        updated_at,
-                    }));
-                }
-                Ok(None)
-            }
-        });
+    /// Adds `downloads` to the share's download count and returns the new
+    /// count.
+    pub async fn add_share_downloads(&self, id: &str, downloads: i64) -> Result<i64> {
+        self.session
+            .execute_unpaged(&self.prepared.add_share_downloads, (downloads, id))
+            .await?;
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_share_downloads, (id,))
+            .await?;
+        Ok(result
+            .into_rows_result()?
+            .maybe_first_row::<(Option<Counter>,)>()?
+            .and_then(|(count,)| count)
+            .map_or(0, |Counter(count)| count))
+    }
 
-        let results = join_all(futures).await;
-        let mut entries = Vec::with_capacity(keys.len());
-        for result in results {
-            if let Some(entry) = result? {
-                entries.push(entry);
-            }
+    async fn delete_shares_by_hash(&self, user_hash: &str) -> Result<()> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_user_shares, (user_hash,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        for row in rows_result.rows::<(String,)>()? {
+            let (id,) = row?;
+            self.session
+                .execute_unpaged(&self.prepared.delete_share, (&id,))
+                .await?;
+            self.session
+                .execute_unpaged(&self.prepared.delete_share_downloads, (&id,))
+                .await?;
         }
-        Ok(entries)
+        Ok(())
     }
 
-    pub async fn save_data_key(
+    /// Claims `key` for a request identified by `fingerprint` for
+    /// `ttl_secs`. `None` when the claim was taken, otherwise what is known
+    /// about the earlier use of the key.
+    pub async fn claim_idempotency_key(
         &self,
         user_id: &str,
         key: &str,
-        value: Vec<u8>,
-        checksum: &str,
-    ) -> Result<(i64, i64)> {
-        check_key(key)?;
-
-        let max_size = if key.starts_with("dataStore/") {
-            CONFIG.max_datastore_key_size_bytes
-        } else {
-            CONFIG.max_key_size_bytes
-        };
-
-        if value.len() > max_size {
-            let limit_mb = max_size / 1024 / 1024;
-            return Err(anyhow::anyhow!("Value exceeds {}MB limit", limit_mb));
-        }
-
+        fingerprint: &str,
+        ttl_secs: i32,
+    ) -> Result<Option<IdempotencyRecord>> {
         let hash_key = hash_user_id(user_id);
         let now = chrono::Utc::now().timestamp_millis();
-        let size_bytes = value.len() as i32;
-        let compressed_value = compress(&value);
+        let result = self
+            .session
+            .execute_unpaged(
+                &self.prepared.claim_idempotency_key,
+                (&hash_key, key, fingerprint, now, ttl_secs),
+            )
+            .await?;
+        if lwt_applied(result)? {
+            return Ok(None);
+        }
 
         let result = self
             .session
-            .execute_unpaged(&self.prepared.get_data_version, (&hash_key, key))
+            .execute_unpaged(&self.prepared.get_idempotency_key, (&hash_key, key))
             .await?;
-        let rows_result = result.into_rows_result()?;
+        let row = result.into_rows_result()?.maybe_first_row::<(
+            Option<String>,
+            Option<i32>,
+            Option<String>,
+            Option<Vec<u8>>,
+        )>()?;
+        // Expired between the two statements; the client's next retry can
+        // claim it.
+        let Some((stored, status, headers, body)) = row else {
+            return Ok(Some(IdempotencyRecord {
+                fingerprint: fingerprint.to_string(),
+                response: None,
+            }));
+        };
 
-        let (version, created_at) = if let Some(row) = rows_result.rows::<(i64, i64)>()?.next() {
-            let (v, c) = row?;
-            (v + 1, c)
-        } else {
-            (1, now)
+        let response = match (status, headers) {
+            (Some(status), Some(headers)) => Some(StoredResponse {
+                status: status as u16,
+                headers: serde_json::from_str(&headers)?,
+                body: body.unwrap_or_default(),
+            }),
+            _ => None,
         };
+        Ok(Some(IdempotencyRecord {
+            fingerprint: stored.unwrap_or_default(),
+            response,
+        }))
+    }
 
+    /// Keeps `response` for `key` for `ttl_secs`, unless the claim has
+    /// expired meanwhile.
+    pub async fn complete_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        response: &StoredResponse,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
         self.session
             .execute_unpaged(
-                &self.prepared.insert_data_key,
+                &self.prepared.complete_idempotency_key,
                 (
-                    &hash_key,
-                    key,
-                    &compressed_value,
-                    version,
-                    checksum,
-                    size_bytes,
-                    created_at,
+                    ttl_secs,
+                    fingerprint,
+                    response.status as i32,
+                    serde_json::to_string(&response.headers)?,
+                    &response.body,
                     now,
+                    hash_user_id(user_id),
+                    key,
                 ),
             )
             .await?;
-
-        Ok((version, now))
+        Ok(())
     }
 
-    pub async fn delete_data_key(&self, user_id: &str, key: &str) -> Result<()> {
-        check_key(key)?;
-        let hash_key = hash_user_id(user_id);
+    /// Drops the claim on `key` so the request can be retried.
+    pub async fn release_idempotency_key(&self, user_id: &str, key: &str) -> Result<()> {
         self.session
-            .execute_unpaged(&self.prepared.delete_data_key, (&hash_key, key))
+            .execute_unpaged(
+                &self.prepared.release_idempotency_key,
+                (hash_user_id(user_id), key),
+            )
             .await?;
         Ok(())
     }
 
-    pub async fn delete_all_data(&self, user_id: &str) -> Result<()> {
-        let hash_key = hash_user_id(user_id);
+    pub async fn list_retention_candidates(&self) -> Result<Vec<RetentionCandidate>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_retention_candidates, &[])
+            .await?;
+        let rows_result = result.into_rows_result()?;
+
+        let mut candidates = Vec::new();
+        for row in rows_result.rows::<(String, Option<i64>, Option<i64>)>()? {
+            let (user_hash, updated_at, retention_marked_at) = row?;
+            candidates.push(RetentionCandidate {
+                user_hash,
+                updated_at: updated_at.unwrap_or(0),
+                retention_marked_at,
+            });
+        }
+        Ok(candidates)
+    }
+
+    /// Most recent v2 write for a hashed user id, and the bytes it stores.
+    pub async fn data_activity_by_hash(&self, user_hash: &str) -> Result<(Option<i64>, i64)> {
+        let manifest = self.manifest_for_hash(user_hash).await?;
+        let last_write = manifest.iter().map(|e| e.updated_at).max();
+        let size = manifest.iter().map(|e| e.size_bytes as i64).sum();
+        Ok((last_write, size))
+    }
+
+    /// When the user with this hashed id last wrote settings or data, and
+    /// the bytes they store.
+    pub async fn user_activity_by_hash(&self, user_hash: &str) -> Result<UserActivity> {
+        let settings = self.query_settings(user_hash).await?;
+        let manifest = self.manifest_for_hash(user_hash).await?;
+        let last_write = manifest.iter().map(|e| e.updated_at).max();
+        let data_size: i64 = manifest
+            .iter()
+            .filter(|e| !e.deleted)
+            .map(|e| e.size_bytes as i64)
+            .sum();
+        let (settings_size, settings_updated_at) = settings
+            .map(|(settings, updated_at)| (settings.len() as i64, updated_at))
+            .unwrap_or((0, 0));
+        Ok(UserActivity {
+            user: user_hash.to_string(),
+            last_active: last_write.unwrap_or(0).max(settings_updated_at),
+            size_bytes: settings_size + data_size,
+        })
+    }
+
+    pub async fn get_settings_size_by_hash(&self, user_hash: &str) -> Result<i64> {
+        Ok(self
+            .query_settings(user_hash)
+            .await?
+            .map(|(settings, _)| settings.len() as i64)
+            .unwrap_or(0))
+    }
+
+    pub async fn set_retention_mark(&self, user_hash: &str, marked_at: Option<i64>) -> Result<()> {
         self.session
-            .execute_unpaged(&self.prepared.delete_all_data, (&hash_key,))
+            .execute_unpaged(&self.prepared.set_retention_mark, (marked_at, user_hash))
             .await?;
         Ok(())
     }
 
-    pub async fn save_data_keys_batch(
-        &self,
-        user_id: &str,
-        entries: Vec<(String, Vec<u8>, String)>,
-        existing_versions: &std::collections::HashMap<String, (i64, i64)>,
-    ) -> Result<Vec<(String, i64, i64)>> {
-        if entries.is_empty() {
-            return Ok(Vec::new());
+    /// Deletes everything stored for a user: settings (including any copy
+    /// under the legacy hash), data keys, their quota override, refresh
+    /// token and the identities linked to the account.
+    pub async fn purge_account(&self, user_id: &str) -> Result<AccountPurge> {
+        let hash_key = hash_user_id(user_id);
+        let purge = self.purge_account_by_hash(&hash_key).await?;
+        self.cleanup_legacy_data(user_id, &hash_key).await;
+        Ok(purge)
+    }
+
+    /// [`Self::purge_account`] for a hashed user id.
+    pub async fn purge_account_by_hash(&self, user_hash: &str) -> Result<AccountPurge> {
+        let settings = self
+            .row_exists(&self.prepared.get_user_updated_at, user_hash)
+            .await?;
+        let refresh_token = self
+            .row_exists(&self.prepared.get_refresh_token, user_hash)
+            .await?;
+        let quota_override = self.get_quota_override_by_hash(user_hash).await?;
+
+        self.session
+            .execute_unpaged(&self.prepared.delete_user, (user_hash,))
+            .await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_settings_history, (user_hash,))
+            .await?;
+        self.settings_cache.invalidate(user_hash);
+        let data_keys = self.delete_all_data_by_hash(user_hash).await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_user_quota, (user_hash,))
+            .await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_refresh_token, (user_hash,))
+            .await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_corrupt_entries, (user_hash,))
+            .await?;
+        for upload in self.uploads_for_hash(user_hash).await? {
+            self.session
+                .execute_unpaged(&self.prepared.delete_upload_parts, (&upload.id,))
+                .await?;
+        }
+        self.session
+            .execute_unpaged(&self.prepared.delete_upload_sessions, (user_hash,))
+            .await?;
+        let links = self.account_links_for_hash(user_hash).await?;
+        for link in &links {
+            self.session
+                .execute_unpaged(&self.prepared.delete_account_link, (&link.user,))
+                .await?;
         }
+        self.delete_shares_by_hash(user_hash).await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_idempotency_keys, (user_hash,))
+            .await?;
+        self.delete_pending_deletion(user_hash).await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_devices, (user_hash,))
+            .await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_key_writers, (user_hash,))
+            .await?;
+        self.session
+            .execute_unpaged(&self.prepared.delete_sync_sessions, (user_hash,))
+            .await?;
 
-        let hash_key: Arc<str> = hash_user_id(user_id).into();
-        let now = chrono::Utc::now().timestamp_millis();
+        Ok(AccountPurge {
+            settings,
+            data_keys,
+            quota_override: quota_override.is_some(),
+            refresh_token,
+            linked_identities: links.len() as u64,
+        })
+    }
 
-        let prepared_entries: Vec<_> = entries
-            .into_iter()
-            .filter_map(|(key, value, checksum)| {
-                let max_size = if key.starts_with("dataStore/") {
-                    CONFIG.max_datastore_key_size_bytes
-                } else {
-                    CONFIG.max_key_size_bytes
-                };
-                if value.len() > max_size {
-                    return None;
-                }
-                let size_bytes = value.len() as i32;
-                let compressed_value = compress(&value);
-                let (version, created_at) = match existing_versions.get(&key).copied() {
-                    Some((v, c)) => (v + 1, c),
-                    None => (1, now),
-                };
-                Some((
-                    key,
-                    compressed_value,
-                    checksum,
-                    size_bytes,
-                    version,
-                    created_at,
-                ))
-            })
-            .collect();
+    async fn row_exists(&self, statement: &PreparedStatement, user_hash: &str) -> Result<bool> {
+        let result = self
+            .session
+            .execute_unpaged(statement, (user_hash,))
+            .await?;
+        Ok(result.into_rows_result()?.rows_num() > 0)
+    }
 
-        let futures = prepared_entries.into_iter().map(
-            |(key, compressed_value, checksum, size_bytes, version, created_at)| {
-                let session = Arc::clone(&self.session);
-                let prepared = Arc::clone(&self.prepared);
-                let hash_key = Arc::clone(&hash_key);
+    /// Deletes blobs no data row points at anymore, skipping ones referenced
+    /// within the last `grace_ms` so a write racing with the collector keeps
+    /// its value. Returns the number of blobs deleted.
+    pub async fn collect_unreferenced_blobs(&self, grace_ms: i64) -> Result<usize> {
+        let cutoff = chrono::Utc::now().timestamp_millis() - grace_ms;
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_blob_refs, &[])
+            .await?;
+        let rows_result = result.into_rows_result()?;
 
-                async move {
-                    session
-                        .execute_unpaged(
-                            &prepared.insert_data_key,
-                            (
-                                hash_key.as_ref(),
-                                &key,
-                                &compressed_value,
-                                version,
-                                &checksum,
-                                size_bytes,
-                                created_at,
-                                now,
-                            ),
-                        )
-                        .await?;
+        let mut collected = 0;
+        for row in rows_result.rows::<(String, Option<Counter>)>()? {
+            let (hash, refs) = row?;
+            if refs.is_some_and(|Counter(refs)| refs > 0) {
+                continue;
+            }
 
-                    Ok::<_, anyhow::Error>((key, version, now))
-                }
-            },
-        );
+            let result = self
+                .session
+                .execute_unpaged(&self.prepared.get_blob_last_referenced, (&hash,))
+                .await?;
+            let rows_result = result.into_rows_result()?;
+            let last_referenced = match rows_result.rows::<(Option<i64>,)>()?.next() {
+                Some(row) => row?.0.unwrap_or(0),
+                None => continue,
+            };
+            if last_referenced > cutoff {
+                continue;
+            }
 
-        let results = join_all(futures).await;
-        let mut saved = Vec::with_capacity(results.len());
-        for result in results {
-            saved.push(result?);
+            self.blobs.delete(&hash).await?;
+            self.session
+                .execute_unpaged(&self.prepared.delete_blob, (&hash,))
+                .await?;
+            collected += 1;
         }
-        Ok(saved)
+        Ok(collected)
     }
 
-    pub async fn get_versions_batch(
-        &self,
-        user_id: &str,
-        keys: &[String],
-    ) -> Result<std::collections::HashMap<String, (i64, i64)>> {
-        if keys.is_empty() {
-            return Ok(std::collections::HashMap::new());
+    /// The user's storage quota: their override, or `MAX_BACKUP_SIZE_BYTES`.
+    pub async fn storage_quota(&self, user_id: &str) -> Result<i64> {
+        self.quota_for_hash(&hash_user_id(user_id)).await
+    }
+
+    async fn quota_for_hash(&self, hash_key: &str) -> Result<i64> {
+        Ok(self
+            .get_quota_override_by_hash(hash_key)
+            .await?
+            .unwrap_or(self.config.load().max_backup_size_bytes as i64))
+    }
+
+    pub async fn get_quota_override(&self, user_id: &str) -> Result<Option<i64>> {
+        self.get_quota_override_by_hash(&hash_user_id(user_id))
+            .await
+    }
+
+    async fn get_quota_override_by_hash(&self, hash_key: &str) -> Result<Option<i64>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_user_quota, (hash_key,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
+        match rows_result.rows::<(Option<i64>,)>()?.next() {
+            Some(row) => Ok(row?.0),
+            None => Ok(None),
         }
+    }
 
-        let hash_key: Arc<str> = hash_user_id(user_id).into();
-
-        let futures = keys.iter().map(|key| {
-            let session = Arc::clone(&self.session);
-            let prepared = Arc::clone(&self.prepared);
-            let hash_key = Arc::clone(&hash_key);
-            let key = key.clone();
-            async move {
-                let result = session
-                    .execute_unpaged(&prepared.get_data_version, (hash_key.as_ref(), &key))
+    /// Sets or, with `None`, clears a user's quota override.
+    pub async fn set_quota_override(&self, user_id: &str, max_bytes: Option<i64>) -> Result<()> {
+        let hash_key = hash_user_id(user_id);
+        match max_bytes {
+            Some(max_bytes) => {
+                let now = chrono::Utc::now().timestamp_millis();
+                self.session
+                    .execute_unpaged(
+                        &self.prepared.insert_user_quota,
+                        (&hash_key, max_bytes, now),
+                    )
                     .await?;
-                let rows_result = result.into_rows_result()?;
-                if let Some(row) = rows_result.rows::<(i64, i64)>()?.next() {
-                    let (version, created_at) = row?;
-                    return Ok::<_, anyhow::Error>(Some((key, version, created_at)));
-                }
-                Ok(None)
             }
-        });
-
-        let results = join_all(futures).await;
-        let mut versions = std::collections::HashMap::with_capacity(keys.len());
-        for result in results {
-            if let Some((key, version, created_at)) = result? {
-                versions.insert(key, (version, created_at));
+            None => {
+                self.session
+                    .execute_unpaged(&self.prepared.delete_user_quota, (&hash_key,))
+                    .await?;
             }
         }
-        Ok(versions)
+        Ok(())
     }
 
-    pub async fn get_user_total_size(&self, user_id: &str) -> Result<i64> {
+    /// Adds `writes` (possibly negative) to the user's write count for
+    /// `day` and returns the new count.
+    pub async fn add_daily_writes(&self, user_id: &str, day: &str, writes: i64) -> Result<i64> {
         let hash_key = hash_user_id(user_id);
+        self.session
+            .execute_unpaged(&self.prepared.add_write_usage, (writes, &hash_key, day))
+            .await?;
         let result = self
             .session
-            .execute_unpaged(&self.prepared.get_user_total_size, (&hash_key,))
+            .execute_unpaged(&self.prepared.get_write_usage, (&hash_key, day))
             .await?;
-        let rows_result = result.into_rows_result()?;
+        Ok(result
+            .into_rows_result()?
+            .maybe_first_row::<(Option<Counter>,)>()?
+            .and_then(|(count,)| count)
+            .map_or(0, |Counter(count)| count))
+    }
 
-        if let Some(row) = rows_result.rows::<(Option<i32>,)>()?.next() {
-            let (sum,) = row?;
-            return Ok(sum.unwrap_or(0) as i64);
-        }
-        Ok(0)
+    pub async fn mark_user_active(&self, user_id: &str, day: &str) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_active_user,
+                (day, hash_user_id(user_id)),
+            )
+            .await?;
+        Ok(())
     }
 
-    pub async fn get_user_size_and_key_size(&self, user_id: &str, key: &str) -> Result<(i64, i64)> {
-        check_key(key)?;
-        let hash_key: Arc<str> = hash_user_id(user_id).into();
-        let key: Arc<str> = key.into();
+    pub async fn add_daily_syncs(&self, day: &str, syncs: i64) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.add_sync_usage, (syncs, day))
+            .await?;
+        Ok(())
+    }
 
-        let session1 = Arc::clone(&self.session);
-        let session2 = Arc::clone(&self.session);
-        let prepared1 = Arc::clone(&self.prepared);
-        let prepared2 = Arc::clone(&self.prepared);
-        let hash_key1 = Arc::clone(&hash_key);
-        let hash_key2 = hash_key;
-        let key = Arc::clone(&key);
+    /// Users marked active on `day` and syncs counted on it.
+    pub async fn get_daily_activity(&self, day: &str) -> Result<(i64, i64)> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.count_active_users, (day,))
+            .await?;
+        let active_users = result
+            .into_rows_result()?
+            .maybe_first_row::<(i64,)>()?
+            .map_or(0, |(count,)| count);
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_sync_usage, (day,))
+            .await?;
+        let syncs = result
+            .into_rows_result()?
+            .maybe_first_row::<(Option<Counter>,)>()?
+            .and_then(|(count,)| count)
+            .map_or(0, |Counter(count)| count);
+        Ok((active_users, syncs))
+    }
 
-        let total_future = async move {
-            let result = session1
-                .execute_unpaged(&prepared1.get_user_total_size, (hash_key1.as_ref(),))
-                .await?;
-            let rows_result = result.into_rows_result()?;
-            let total = match rows_result.rows::<(Option<i32>,)>()?.next() {
-                Some(row) => row?.0.unwrap_or(0) as i64,
-                None => 0,
-            };
-            Ok::<i64, anyhow::Error>(total)
-        };
+    pub async fn save_daily_stats(&self, stats: &DailyStats) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_daily_stats,
+                (
+                    &stats.day,
+                    stats.active_users,
+                    stats.users,
+                    stats.bytes_stored,
+                    stats.syncs,
+                    stats.computed_at,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
 
-        let key_future = async move {
-            let result = session2
-                .execute_unpaged(&prepared2.get_key_size, (hash_key2.as_ref(), key.as_ref()))
-                .await?;
-            let rows_result = result.into_rows_result()?;
-            let size = match rows_result.rows::<(i32,)>()?.next() {
-                Some(row) => row?.0 as i64,
-                None => 0,
-            };
-            Ok::<i64, anyhow::Error>(size)
-        };
+    pub async fn get_daily_stats(&self, day: &str) -> Result<Option<DailyStats>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_daily_stats, (day,))
+            .await?;
+        let row = result.into_rows_result()?.maybe_first_row::<(
+            String,
+            Option<i64>,
+            Option<i64>,
+            Option<i64>,
+            Option<i64>,
+            Option<i64>,
+        )>()?;
+        Ok(row.map(
+            |(day, active_users, users, bytes_stored, syncs, computed_at)| DailyStats {
+                day,
+                active_users: active_users.unwrap_or(0),
+                users: users.unwrap_or(0),
+                bytes_stored: bytes_stored.unwrap_or(0),
+                syncs: syncs.unwrap_or(0),
+                computed_at: computed_at.unwrap_or(0),
+            },
+        ))
+    }
 
-        let (total_result, key_result) = join!(total_future, key_future);
-        Ok((total_result?, key_result?))
+    pub async fn record_corruption(&self, user_id: &str, entry: &CorruptEntry) -> Result<()> {
+        self.record_corruption_by_hash(&hash_user_id(user_id), entry)
+            .await
     }
 
-    pub async fn save_data_key_with_quota_check(
+    pub async fn record_corruption_by_hash(
         &self,
-        user_id: &str,
-        key: &str,
-        value: Vec<u8>,
-        checksum: &str,
-        max_total_size: i64,
-    ) -> Result<Option<(i64, i64)>> {
-        check_key(key)?;
+        user_hash: &str,
+        entry: &CorruptEntry,
+    ) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_corrupt_entry,
+                (
+                    user_hash,
+                    &entry.key,
+                    entry.version,
+                    &entry.stored_checksum,
+                    &entry.computed_checksum,
+                    entry.detected_at,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
 
-        let max_size = if key.starts_with("dataStore/") {
-            CONFIG.max_datastore_key_size_bytes
-        } else {
-            CONFIG.max_key_size_bytes
-        };
+    pub async fn list_corruption(&self, user_id: &str) -> Result<Vec<CorruptEntry>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_corrupt_entries, (hash_user_id(user_id),))
+            .await?;
+        let rows_result = result.into_rows_result()?;
 
-        if value.len() > max_size {
-            let limit_mb = max_size / 1024 / 1024;
-            return Err(anyhow::anyhow!("Value exceeds {}MB limit", limit_mb));
+        let mut entries = Vec::new();
+        for row in rows_result.rows::<(String, i64, String, String, i64)>()? {
+            let (key, version, stored_checksum, computed_checksum, detected_at) = row?;
+            entries.push(CorruptEntry {
+                key,
+                version,
+                stored_checksum,
+                computed_checksum,
+                detected_at,
+            });
         }
+        Ok(entries)
+    }
 
-        let hash_key: Arc<str> = hash_user_id(user_id).into();
-        let now = chrono::Utc::now().timestamp_millis();
-        let new_size = value.len() as i32;
-        let key: Arc<str> = key.into();
+    pub async fn create_upload(&self, user_id: &str, upload: &UploadSession) -> Result<()> {
+        let ttl = upload
+            .remaining_ttl(upload.created_at)
+            .ok_or_else(|| anyhow::anyhow!("Upload expires before it starts"))?;
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_upload_session,
+                (
+                    hash_user_id(user_id),
+                    &upload.id,
+                    &upload.key,
+                    upload.size_bytes,
+                    upload.part_size,
+                    &upload.checksum,
+                    upload.ttl_secs,
+                    upload.created_at,
+                    upload.expires_at,
+                    ttl,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
 
-        let (total_size_result, version_result) = {
-            let session1 = Arc::clone(&self.session);
-            let session2 = Arc::clone(&self.session);
-            let prepared1 = Arc::clone(&self.prepared);
-            let prepared2 = Arc::clone(&self.prepared);
-            let hash_key1 = Arc::clone(&hash_key);
-            let hash_key2 = Arc::clone(&hash_key);
-            let key_clone = Arc::clone(&key);
+    pub async fn get_upload(&self, user_id: &str, id: &str) -> Result<Option<UploadSession>> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let result = self
+            .session
+            .execute_unpaged(
+                &self.prepared.get_upload_session,
+                (hash_user_id(user_id), id),
+            )
+            .await?;
+        Ok(result
+            .into_rows_result()?
+            .maybe_first_row::<UploadSessionRow>()?
+            .map(upload_session)
+            .filter(|upload| upload.expires_at > now))
+    }
 
-            let total_future = async move {
-                let result = session1
-                    .execute_unpaged(&prepared1.get_user_total_size, (hash_key1.as_ref(),))
-                    .await?;
-                let rows_result = result.into_rows_result()?;
-                Ok::<i64, anyhow::Error>(
-                    rows_result
-                        .rows::<(Option<i32>,)>()?
-                        .next()
-                        .transpose()?
-                        .and_then(|r| r.0)
-                        .unwrap_or(0) as i64,
-                )
-            };
+    pub async fn list_uploads(&self, user_id: &str) -> Result<Vec<UploadSession>> {
+        self.uploads_for_hash(&hash_user_id(user_id)).await
+    }
 
-            let version_future = async move {
-                let result = session2
-                    .execute_unpaged(
-                        &prepared2.get_data_version_and_size,
-                        (hash_key2.as_ref(), key_clone.as_ref()),
-                    )
-                    .await?;
-                let rows_result = result.into_rows_result()?;
-                Ok::<Option<(i64, i64, i32)>, anyhow::Error>(
-                    rows_result.rows::<(i64, i64, i32)>()?.next().transpose()?,
-                )
-            };
+    async fn uploads_for_hash(&self, user_hash: &str) -> Result<Vec<UploadSession>> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_upload_sessions, (user_hash,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
 
-            join!(total_future, version_future)
-        };
+        let mut uploads = Vec::new();
+        for row in rows_result.rows::<UploadSessionRow>()? {
+            let upload = upload_session(row?);
+            if upload.expires_at > now {
+                uploads.push(upload);
+            }
+        }
+        Ok(uploads)
+    }
 
-        let total_size = total_size_result?;
-        let existing = version_result?;
+    /// Stores part `number` of `upload`, replacing an earlier copy. The part
+    /// expires together with the upload.
+    pub async fn save_upload_part(
+        &self,
+        upload: &UploadSession,
+        number: i32,
+        value: Vec<u8>,
+        checksum: &str,
+    ) -> Result<()> {
+        let ttl = upload
+            .remaining_ttl(chrono::Utc::now().timestamp_millis())
+            .ok_or_else(|| anyhow::anyhow!("Upload has expired"))?;
+        let size = value.len() as i32;
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_upload_part,
+                (&upload.id, number, value, checksum, size, ttl),
+            )
+            .await?;
+        Ok(())
+    }
 
-        let (version, created_at, existing_size) = match existing {
-            Some((v, c, s)) => (v + 1, c, s as i64),
-            None => (1, now, 0),
-        };
+    /// The parts received so far, in order.
+    pub async fn list_upload_parts(&self, upload_id: &str) -> Result<Vec<UploadPart>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_upload_parts, (upload_id,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
 
-        let new_total = total_size - existing_size + new_size as i64;
-        if new_total > max_total_size {
-            return Ok(None);
+        let mut parts = Vec::new();
+        for row in rows_result.rows::<(i32, i32, String)>()? {
+            let (number, size_bytes, checksum) = row?;
+            parts.push(UploadPart {
+                number,
+                size_bytes,
+                checksum,
+            });
         }
+        Ok(parts)
+    }
 
-        let compressed_value = compress(&value);
+    pub async fn get_upload_part(&self, upload_id: &str, number: i32) -> Result<Option<Vec<u8>>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_upload_part, (upload_id, number))
+            .await?;
+        Ok(result
+            .into_rows_result()?
+            .maybe_first_row::<(Vec<u8>,)>()?
+            .map(|(value,)| value))
+    }
 
+    pub async fn delete_upload(&self, user_id: &str, id: &str) -> Result<()> {
+        self.session
+            .execute_unpaged(&self.prepared.delete_upload_parts, (id,))
+            .await?;
         self.session
             .execute_unpaged(
-                &self.prepared.insert_data_key,
+                &self.prepared.delete_upload_session,
+                (hash_user_id(user_id), id),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Corrects the recorded size of `key` to `size_bytes` if it is still at
+    /// `version`, keeping the row's TTL. Returns whether it was.
+    pub async fn set_data_size_by_hash(
+        &self,
+        user_hash: &str,
+        key: &str,
+        version: i64,
+        size_bytes: i32,
+    ) -> Result<bool> {
+        let _guard = self.key_locks.lock(user_hash, key).await;
+        let Some(row) = self.data_row_for_hash(user_hash, key).await? else {
+            return Ok(false);
+        };
+        let (_, current, _, _, _, _, deleted, _, _, ttl) = row;
+        if current != version || deleted.unwrap_or(false) {
+            return Ok(false);
+        }
+        let result = self
+            .session
+            .execute_unpaged(
+                &self.prepared.update_data_size_if_version,
+                (ttl.unwrap_or(0), size_bytes, user_hash, key, version),
+            )
+            .await?;
+        lwt_applied(result)
+    }
+
+    pub async fn insert_audit_entry(&self, entry: &AuditEntry, ttl_secs: i32) -> Result<()> {
+        self.session
+            .execute_unpaged(
+                &self.prepared.insert_audit_entry,
                 (
-                    hash_key.as_ref(),
-                    key.as_ref(),
-                    &compressed_value,
-                    version,
-                    checksum,
-                    new_size,
-                    created_at,
-                    now,
+                    day_bucket(entry.at),
+                    entry.at,
+                    uuid::Uuid::new_v4(),
+                    &entry.request_id,
+                    &entry.actor,
+                    &entry.user_hash,
+                    &entry.action,
+                    &entry.route,
+                    &entry.client_ip,
+                    &entry.detail,
+                    &entry.outcome,
+                    ttl_secs,
                 ),
             )
             .await?;
+        Ok(())
+    }
+
+    /// Entries of one UTC day, newest first.
+    pub async fn list_audit_entries(&self, day: &str) -> Result<Vec<AuditEntry>> {
+        let result = self
+            .session
+            .execute_unpaged(&self.prepared.get_audit_entries, (day,))
+            .await?;
+        let rows_result = result.into_rows_result()?;
 
-        Ok(Some((version, now)))
+        let mut entries = Vec::new();
+        for row in rows_result.rows::<(
+            i64,
+            String,
+            String,
+            Option<String>,
+            String,
+            String,
+            Option<String>,
+            Option<String>,
+            String,
+        )>()? {
+            let (at, request_id, actor, user_hash, action, route, client_ip, detail, outcome) =
+                row?;
+            entries.push(AuditEntry {
+                at,
+                request_id,
+                actor,
+                user_hash,
+                action,
+                route,
+                client_ip,
+                detail,
+                outcome,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_run_bounded_limits_parallelism_and_keeps_order() {
+        let in_flight = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let futures = (0..20u64).map(|i| {
+            let (in_flight, peak) = (&in_flight, &peak);
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                // Later futures finish first.
+                tokio::time::sleep(Duration::from_millis(20 - i)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(i)
+            }
+        });
+
+        let outputs = run_bounded(futures, 4).await.unwrap();
+        assert_eq!(outputs, (0..20).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 4);
+
+        let failing = (0..3).map(|i| async move {
+            if i == 1 {
+                Err(anyhow::anyhow!("failed"))
+            } else {
+                Ok(i)
+            }
+        });
+        assert!(run_bounded(failing, 2).await.is_err());
     }
 }
@@ -0,0 +1,1323 @@
+//! Storage backend abstraction used by the route handlers.
+//!
+//! [`Datastore`] covers what serving clients needs: v1 settings, v2 data
+//! keys and their manifest, storage quotas, OAuth state and refresh tokens,
+//! account links and the audit log. Scylla ([`DatabaseService`]) is the
+//! default; an embedded SQLite database is selected with
+//! `STORAGE_BACKEND=sqlite` for development and small installs. Background jobs that scan the whole
+//! cluster (inactive account expiry, blob collection, legacy cleanup) stay
+//! Scylla-only and are reached through [`Storage::scylla`].
+
+pub mod scylla;
+pub mod sqlite;
+
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+
+use crate::audit::AuditEntry;
+use crate::cache::CacheStats;
+use crate::compaction::{CompactionStats, HistoryPolicy};
+use crate::database::{
+    AccountLink, AccountPurge, ApiKey, AuthLockout, Ban, ConditionalWrite, CorruptEntry,
+    DailyStats, DataEntry, DataManifestEntry, DataRead, DataShare, DataUpload, DatabaseService,
+    Device, ExistingVersions, IdempotencyRecord, LastWriter, ManifestPage, OAuthCode, OAuthState,
+    PendingDeletion, RenameOutcome, StorageUsage, StoredResponse, SyncSession, UploadPart,
+    UploadSession, UserActivity, UserSnapshot, UserSummary,
+};
+use crate::driver_metrics::DriverStats;
+use crate::metrics::{BatchStats, QueryStats};
+use crate::oauth::OAuthRedirect;
+
+pub use self::sqlite::SqliteDatastore;
+
+pub trait Datastore {
+    fn health_check(&self) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_settings_metadata(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// The user's settings and the etag they were written with.
+    fn get_user_settings(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<Option<(Vec<u8>, String)>>> + Send;
+
+    fn save_user_settings(
+        &self,
+        user_id: &str,
+        settings: Vec<u8>,
+    ) -> impl Future<Output = Result<i64>> + Send;
+
+    fn delete_user_settings(&self, user_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// A previous (or the current) version of the user's settings, by the
+    /// etag it was written with. Only the last `SETTINGS_HISTORY_VERSIONS`
+    /// writes are kept.
+    fn get_settings_version(
+        &self,
+        user_id: &str,
+        written: i64,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    /// Drops the settings versions of every user that `policy` no longer
+    /// keeps.
+    fn compact_settings_history(
+        &self,
+        policy: HistoryPolicy,
+        now: i64,
+    ) -> impl Future<Output = Result<CompactionStats>> + Send;
+
+    fn get_data_manifest(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<Vec<DataManifestEntry>>> + Send;
+
+    /// Up to `page_size` entries whose key starts with `prefix`, resuming
+    /// from an opaque `cursor` returned by the previous page.
+    fn get_data_manifest_page(
+        &self,
+        user_id: &str,
+        prefix: &str,
+        page_size: i32,
+        cursor: Option<Vec<u8>>,
+    ) -> impl Future<Output = Result<ManifestPage>> + Send;
+
+    fn get_data_key(
+        &self,
+        user_id: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<DataEntry>>> + Send;
+
+    /// [`Self::get_data_key`] that may leave a large value to be streamed
+    /// instead of reading it whole first.
+    fn open_data_key(
+        &self,
+        user_id: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<DataRead>>> + Send;
+
+    fn get_data_keys(
+        &self,
+        user_id: &str,
+        keys: &[String],
+    ) -> impl Future<Output = Result<Vec<DataEntry>>> + Send;
+
+    /// Replaces the key with a tombstone, returning its version, or `None`
+    /// if the key never existed.
+    fn delete_data_key(
+        &self,
+        user_id: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<i64>>> + Send;
+
+    /// Moves a key's value, version and checksum to `to` and leaves a
+    /// tombstone under `from`, both or neither.
+    fn rename_data_key(
+        &self,
+        user_id: &str,
+        from: &str,
+        to: &str,
+    ) -> impl Future<Output = Result<RenameOutcome>> + Send;
+
+    /// Deletes everything stored for the user and reports what was removed.
+    fn purge_account(&self, user_id: &str) -> impl Future<Output = Result<AccountPurge>> + Send;
+
+    /// [`Self::purge_account`] for a hashed user id.
+    fn purge_account_by_hash(
+        &self,
+        user_hash: &str,
+    ) -> impl Future<Output = Result<AccountPurge>> + Send;
+
+    /// Writes every upload within the per-key size limits, returning the
+    /// key, version and write time of each one written. Versions follow the
+    /// ones stored when the write happens, so concurrent writes to a key
+    /// each get their own.
+    fn save_data_keys_batch(
+        &self,
+        user_id: &str,
+        entries: Vec<DataUpload>,
+    ) -> impl Future<Output = Result<Vec<(String, i64, i64)>>> + Send;
+
+    fn get_versions_batch(
+        &self,
+        user_id: &str,
+        keys: &[String],
+    ) -> impl Future<Output = Result<ExistingVersions>> + Send;
+
+    fn get_user_total_size(&self, user_id: &str) -> impl Future<Output = Result<i64>> + Send;
+
+    /// Writes the key unless it would take the user over their quota, in
+    /// which case `None` is returned.
+    fn save_data_key_with_quota_check(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+    ) -> impl Future<Output = Result<Option<(i64, i64)>>> + Send;
+
+    fn get_storage_usage(&self, user_id: &str)
+    -> impl Future<Output = Result<StorageUsage>> + Send;
+
+    fn storage_quota(&self, user_id: &str) -> impl Future<Output = Result<i64>> + Send;
+
+    fn get_quota_override(&self, user_id: &str)
+    -> impl Future<Output = Result<Option<i64>>> + Send;
+
+    fn set_quota_override(
+        &self,
+        user_id: &str,
+        max_bytes: Option<i64>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Adds `writes` (possibly negative) to the user's data write count for
+    /// the UTC `day` and returns the new count.
+    fn add_daily_writes(
+        &self,
+        user_id: &str,
+        day: &str,
+        writes: i64,
+    ) -> impl Future<Output = Result<i64>> + Send;
+
+    /// Marks the user as active on `day`, a `YYYY-MM-DD` UTC day.
+    fn mark_user_active(&self, user_id: &str, day: &str)
+    -> impl Future<Output = Result<()>> + Send;
+
+    /// Adds `syncs` to the syncs counted on `day`.
+    fn add_daily_syncs(&self, day: &str, syncs: i64) -> impl Future<Output = Result<()>> + Send;
+
+    /// Users marked active on `day` and syncs counted on it.
+    fn get_daily_activity(&self, day: &str) -> impl Future<Output = Result<(i64, i64)>> + Send;
+
+    /// Saves the aggregates of a day over any saved before.
+    fn save_daily_stats(&self, stats: &DailyStats) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_daily_stats(&self, day: &str)
+    -> impl Future<Output = Result<Option<DailyStats>>> + Send;
+
+    /// Remembers that a stored value failed checksum verification.
+    fn record_corruption(
+        &self,
+        user_id: &str,
+        entry: &CorruptEntry,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// [`Self::record_corruption`] for a hashed user id.
+    fn record_corruption_by_hash(
+        &self,
+        user_hash: &str,
+        entry: &CorruptEntry,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn list_corruption(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<Vec<CorruptEntry>>> + Send;
+
+    /// Starts a multipart upload that expires at `upload.expires_at`.
+    fn create_upload(
+        &self,
+        user_id: &str,
+        upload: &UploadSession,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// An unexpired upload of the user's; `None` if unknown, expired or
+    /// someone else's.
+    fn get_upload(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> impl Future<Output = Result<Option<UploadSession>>> + Send;
+
+    fn list_uploads(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<Vec<UploadSession>>> + Send;
+
+    /// Stores a part of an upload, replacing an earlier copy of it.
+    fn save_upload_part(
+        &self,
+        upload: &UploadSession,
+        number: i32,
+        value: Vec<u8>,
+        checksum: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// The parts received so far, ordered by number.
+    fn list_upload_parts(
+        &self,
+        upload_id: &str,
+    ) -> impl Future<Output = Result<Vec<UploadPart>>> + Send;
+
+    fn get_upload_part(
+        &self,
+        upload_id: &str,
+        number: i32,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    /// Drops an upload and its parts.
+    fn delete_upload(&self, user_id: &str, id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    fn list_users_created_since(
+        &self,
+        since: i64,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<UserSummary>>> + Send;
+
+    /// Version, checksum and timestamps of one key, tombstones included.
+    fn get_data_meta(
+        &self,
+        user_id: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<DataManifestEntry>>> + Send;
+
+    /// Writes a key only if its row, tombstones included, is still at
+    /// `expected`, or absent for `None`.
+    fn save_data_key_if_version(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+        expected: Option<i64>,
+    ) -> impl Future<Output = Result<ConditionalWrite>> + Send;
+
+    /// Every key of the user with this hashed id, tombstones included, for
+    /// the admin API.
+    fn get_data_manifest_by_hash(
+        &self,
+        user_hash: &str,
+    ) -> impl Future<Output = Result<Vec<DataManifestEntry>>> + Send;
+
+    fn get_data_key_by_hash(
+        &self,
+        user_hash: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<DataEntry>>> + Send;
+
+    /// Corrects the recorded size of a live key if it is still at
+    /// `version`, for the scrubber. Returns whether it was.
+    fn set_data_size_by_hash(
+        &self,
+        user_hash: &str,
+        key: &str,
+        version: i64,
+        size_bytes: i32,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Hashed ids of every user with settings or data, for backups.
+    fn list_user_hashes(&self) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    /// When the user with this hashed id last wrote settings or data, and
+    /// the bytes they store.
+    fn user_activity_by_hash(
+        &self,
+        user_hash: &str,
+    ) -> impl Future<Output = Result<UserActivity>> + Send;
+
+    /// Settings and live data of the user with this hashed id.
+    fn snapshot_user_by_hash(
+        &self,
+        user_hash: &str,
+    ) -> impl Future<Output = Result<UserSnapshot>> + Send;
+
+    /// Writes settings and data back under a hashed id, each key as a new
+    /// version over any already stored. Returns how many keys were written.
+    fn restore_user_by_hash(
+        &self,
+        user_hash: &str,
+        settings: Option<Vec<u8>>,
+        data: Vec<DataUpload>,
+    ) -> impl Future<Output = Result<usize>> + Send;
+
+    fn save_oauth_state(
+        &self,
+        state: &str,
+        code_verifier: Option<&str>,
+        redirect: Option<&OAuthRedirect>,
+        ttl_secs: i32,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Looks up and deletes a pending state; `None` if unknown or expired.
+    fn consume_oauth_state(
+        &self,
+        state: &str,
+    ) -> impl Future<Output = Result<Option<OAuthState>>> + Send;
+
+    /// Keeps a one-time code, stored under `redirect::code_key` of it,
+    /// until it is exchanged or `ttl_secs` pass.
+    fn save_oauth_code(
+        &self,
+        code: &str,
+        pending: &OAuthCode,
+        ttl_secs: i32,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Looks up and deletes a one-time code; `None` if unknown, expired or
+    /// already used.
+    fn consume_oauth_code(
+        &self,
+        code: &str,
+    ) -> impl Future<Output = Result<Option<OAuthCode>>> + Send;
+
+    fn save_refresh_token(
+        &self,
+        user_id: &str,
+        encrypted: &[u8],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_refresh_token(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    fn delete_refresh_token(&self, user_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// The account `user_id` signs in to, if it is a linked identity.
+    fn get_account_link(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// Makes `user_id` sign in to `account_id`'s storage.
+    fn save_account_link(
+        &self,
+        user_id: &str,
+        account_id: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Identities linked to `account_id`, oldest first.
+    fn list_account_links(
+        &self,
+        account_id: &str,
+    ) -> impl Future<Output = Result<Vec<AccountLink>>> + Send;
+
+    fn delete_account_link(&self, user_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// The ban on `user_hash`, if one is in force.
+    fn get_ban(&self, user_hash: &str) -> impl Future<Output = Result<Option<Ban>>> + Send;
+
+    /// Bans `ban.user`, replacing any ban already on them.
+    fn save_ban(&self, ban: &Ban) -> impl Future<Output = Result<()>> + Send;
+
+    /// Every ban in force, newest first.
+    fn list_bans(&self) -> impl Future<Output = Result<Vec<Ban>>> + Send;
+
+    fn delete_ban(&self, user_hash: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// The sign-in lockout on `subject`, if one is in force.
+    fn get_auth_lockout(
+        &self,
+        subject: &str,
+    ) -> impl Future<Output = Result<Option<AuthLockout>>> + Send;
+
+    /// Stores a lockout until it lifts, replacing any on the same subject.
+    fn save_auth_lockout(&self, lockout: &AuthLockout) -> impl Future<Output = Result<()>> + Send;
+
+    /// The deletion scheduled for the account of `user_hash`, if any.
+    fn get_pending_deletion(
+        &self,
+        user_hash: &str,
+    ) -> impl Future<Output = Result<Option<PendingDeletion>>> + Send;
+
+    /// Schedules the deletion of `pending.user_id`'s account, replacing any
+    /// already scheduled.
+    fn save_pending_deletion(
+        &self,
+        pending: &PendingDeletion,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Every scheduled deletion, soonest first.
+    fn list_pending_deletions(&self) -> impl Future<Output = Result<Vec<PendingDeletion>>> + Send;
+
+    fn delete_pending_deletion(&self, user_hash: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Records `device` as seen at its `last_seen_at` and as the last writer
+    /// of each of `keys`.
+    fn record_device_write(
+        &self,
+        user_id: &str,
+        device: &Device,
+        keys: &[String],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Every device of the user, most recently seen first.
+    fn list_devices(&self, user_id: &str) -> impl Future<Output = Result<Vec<Device>>> + Send;
+
+    /// Records a sync by `user_id`, kept for `ttl_secs`.
+    fn insert_sync_session(
+        &self,
+        user_id: &str,
+        sync: &SyncSession,
+        ttl_secs: i32,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// The user's last `limit` syncs, newest first.
+    fn list_sync_sessions(
+        &self,
+        user_id: &str,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<SyncSession>>> + Send;
+
+    /// The device that last wrote each key, for keys a named device wrote.
+    fn get_last_writers(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<HashMap<String, LastWriter>>> + Send;
+
+    /// Saves an API key, replacing one with the same id.
+    fn save_api_key(&self, key: &ApiKey) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_api_key(&self, id: &str) -> impl Future<Output = Result<Option<ApiKey>>> + Send;
+
+    /// Every API key, newest first.
+    fn list_api_keys(&self) -> impl Future<Output = Result<Vec<ApiKey>>> + Send;
+
+    fn delete_api_key(&self, id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Saves a share link until its `expires_at`.
+    fn save_share(&self, share: &DataShare) -> impl Future<Output = Result<()>> + Send;
+
+    /// The share link with `id`, unless it has expired.
+    fn get_share(&self, id: &str) -> impl Future<Output = Result<Option<DataShare>>> + Send;
+
+    /// Adds `downloads` to the share's download count and returns the new
+    /// count.
+    fn add_share_downloads(
+        &self,
+        id: &str,
+        downloads: i64,
+    ) -> impl Future<Output = Result<i64>> + Send;
+
+    /// Claims an `Idempotency-Key` of the user for `ttl_secs`. `None` when
+    /// the claim was taken, otherwise what is known about the key's earlier
+    /// use.
+    fn claim_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        ttl_secs: i32,
+    ) -> impl Future<Output = Result<Option<IdempotencyRecord>>> + Send;
+
+    /// Keeps the response to a claimed key for `ttl_secs`.
+    fn complete_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        response: &StoredResponse,
+        ttl_secs: i32,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Drops a claim so the request can be retried.
+    fn release_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn insert_audit_entry(
+        &self,
+        entry: &AuditEntry,
+        ttl_secs: i32,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Entries of one UTC day, newest first.
+    fn list_audit_entries(&self, day: &str)
+    -> impl Future<Output = Result<Vec<AuditEntry>>> + Send;
+}
+
+/// The configured backend, dispatched statically.
+#[derive(Clone)]
+pub enum Storage {
+    Scylla(DatabaseService),
+    Sqlite(SqliteDatastore),
+}
+
+impl Storage {
+    /// The Scylla backend, for features the SQLite backend does not offer.
+    pub fn scylla(&self) -> Option<&DatabaseService> {
+        match self {
+            Self::Scylla(db) => Some(db),
+            Self::Sqlite(_) => None,
+        }
+    }
+
+    pub fn settings_cache_stats(&self) -> CacheStats {
+        match self {
+            Self::Scylla(db) => db.settings_cache_stats(),
+            Self::Sqlite(_) => CacheStats::default(),
+        }
+    }
+
+    /// Latency of batched reads; SQLite reads batches in one transaction and
+    /// reports zeros.
+    pub fn batch_read_stats(&self) -> BatchStats {
+        match self {
+            Self::Scylla(db) => db.batch_read_stats(),
+            Self::Sqlite(_) => BatchStats::default(),
+        }
+    }
+
+    pub fn batch_write_stats(&self) -> BatchStats {
+        match self {
+            Self::Scylla(db) => db.batch_write_stats(),
+            Self::Sqlite(_) => BatchStats::default(),
+        }
+    }
+
+    /// Latency of every Scylla query by operation; empty on SQLite.
+    pub fn query_stats(&self) -> BTreeMap<&'static str, QueryStats> {
+        match self {
+            Self::Scylla(db) => db.query_stats(),
+            Self::Sqlite(_) => BTreeMap::new(),
+        }
+    }
+
+    /// The Scylla driver's requests and connections by node; `None` on
+    /// SQLite.
+    pub fn driver_stats(&self) -> Option<DriverStats> {
+        self.scylla().map(DatabaseService::driver_stats)
+    }
+}
+
+impl Datastore for Storage {
+    async fn health_check(&self) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.health_check().await,
+            Self::Sqlite(s) => s.health_check().await,
+        }
+    }
+
+    async fn get_settings_metadata(&self, user_id: &str) -> Result<Option<String>> {
+        match self {
+            Self::Scylla(s) => s.get_settings_metadata(user_id).await,
+            Self::Sqlite(s) => s.get_settings_metadata(user_id).await,
+        }
+    }
+
+    async fn get_user_settings(&self, user_id: &str) -> Result<Option<(Vec<u8>, String)>> {
+        match self {
+            Self::Scylla(s) => s.get_user_settings(user_id).await,
+            Self::Sqlite(s) => s.get_user_settings(user_id).await,
+        }
+    }
+
+    async fn save_user_settings(&self, user_id: &str, settings: Vec<u8>) -> Result<i64> {
+        match self {
+            Self::Scylla(s) => s.save_user_settings(user_id, settings).await,
+            Self::Sqlite(s) => s.save_user_settings(user_id, settings).await,
+        }
+    }
+
+    async fn delete_user_settings(&self, user_id: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.delete_user_settings(user_id).await,
+            Self::Sqlite(s) => s.delete_user_settings(user_id).await,
+        }
+    }
+
+    async fn get_settings_version(&self, user_id: &str, written: i64) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::Scylla(s) => s.get_settings_version(user_id, written).await,
+            Self::Sqlite(s) => s.get_settings_version(user_id, written).await,
+        }
+    }
+
+    async fn compact_settings_history(
+        &self,
+        policy: HistoryPolicy,
+        now: i64,
+    ) -> Result<CompactionStats> {
+        match self {
+            Self::Scylla(s) => s.compact_settings_history(policy, now).await,
+            Self::Sqlite(s) => s.compact_settings_history(policy, now).await,
+        }
+    }
+
+    async fn get_data_manifest(&self, user_id: &str) -> Result<Vec<DataManifestEntry>> {
+        match self {
+            Self::Scylla(s) => s.get_data_manifest(user_id).await,
+            Self::Sqlite(s) => s.get_data_manifest(user_id).await,
+        }
+    }
+
+    async fn get_data_manifest_page(
+        &self,
+        user_id: &str,
+        prefix: &str,
+        page_size: i32,
+        cursor: Option<Vec<u8>>,
+    ) -> Result<ManifestPage> {
+        match self {
+            Self::Scylla(s) => {
+                s.get_data_manifest_page(user_id, prefix, page_size, cursor)
+                    .await
+            }
+            Self::Sqlite(s) => {
+                s.get_data_manifest_page(user_id, prefix, page_size, cursor)
+                    .await
+            }
+        }
+    }
+
+    async fn get_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataEntry>> {
+        match self {
+            Self::Scylla(s) => s.get_data_key(user_id, key).await,
+            Self::Sqlite(s) => s.get_data_key(user_id, key).await,
+        }
+    }
+
+    async fn open_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataRead>> {
+        match self {
+            Self::Scylla(s) => s.open_data_key(user_id, key).await,
+            Self::Sqlite(s) => s.open_data_key(user_id, key).await,
+        }
+    }
+
+    async fn get_data_keys(&self, user_id: &str, keys: &[String]) -> Result<Vec<DataEntry>> {
+        match self {
+            Self::Scylla(s) => s.get_data_keys(user_id, keys).await,
+            Self::Sqlite(s) => s.get_data_keys(user_id, keys).await,
+        }
+    }
+
+    async fn delete_data_key(&self, user_id: &str, key: &str) -> Result<Option<i64>> {
+        match self {
+            Self::Scylla(s) => s.delete_data_key(user_id, key).await,
+            Self::Sqlite(s) => s.delete_data_key(user_id, key).await,
+        }
+    }
+
+    async fn rename_data_key(&self, user_id: &str, from: &str, to: &str) -> Result<RenameOutcome> {
+        match self {
+            Self::Scylla(s) => s.rename_data_key(user_id, from, to).await,
+            Self::Sqlite(s) => s.rename_data_key(user_id, from, to).await,
+        }
+    }
+
+    async fn purge_account(&self, user_id: &str) -> Result<AccountPurge> {
+        match self {
+            Self::Scylla(s) => s.purge_account(user_id).await,
+            Self::Sqlite(s) => s.purge_account(user_id).await,
+        }
+    }
+
+    async fn purge_account_by_hash(&self, user_hash: &str) -> Result<AccountPurge> {
+        match self {
+            Self::Scylla(s) => s.purge_account_by_hash(user_hash).await,
+            Self::Sqlite(s) => s.purge_account_by_hash(user_hash).await,
+        }
+    }
+
+    async fn save_data_keys_batch(
+        &self,
+        user_id: &str,
+        entries: Vec<DataUpload>,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        match self {
+            Self::Scylla(s) => s.save_data_keys_batch(user_id, entries).await,
+            Self::Sqlite(s) => s.save_data_keys_batch(user_id, entries).await,
+        }
+    }
+
+    async fn get_versions_batch(&self, user_id: &str, keys: &[String]) -> Result<ExistingVersions> {
+        match self {
+            Self::Scylla(s) => s.get_versions_batch(user_id, keys).await,
+            Self::Sqlite(s) => s.get_versions_batch(user_id, keys).await,
+        }
+    }
+
+    async fn get_user_total_size(&self, user_id: &str) -> Result<i64> {
+        match self {
+            Self::Scylla(s) => s.get_user_total_size(user_id).await,
+            Self::Sqlite(s) => s.get_user_total_size(user_id).await,
+        }
+    }
+
+    async fn save_data_key_with_quota_check(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+    ) -> Result<Option<(i64, i64)>> {
+        match self {
+            Self::Scylla(s) => {
+                s.save_data_key_with_quota_check(user_id, key, value, checksum, ttl_secs)
+                    .await
+            }
+            Self::Sqlite(s) => {
+                s.save_data_key_with_quota_check(user_id, key, value, checksum, ttl_secs)
+                    .await
+            }
+        }
+    }
+
+    async fn get_storage_usage(&self, user_id: &str) -> Result<StorageUsage> {
+        match self {
+            Self::Scylla(s) => s.get_storage_usage(user_id).await,
+            Self::Sqlite(s) => s.get_storage_usage(user_id).await,
+        }
+    }
+
+    async fn storage_quota(&self, user_id: &str) -> Result<i64> {
+        match self {
+            Self::Scylla(s) => s.storage_quota(user_id).await,
+            Self::Sqlite(s) => s.storage_quota(user_id).await,
+        }
+    }
+
+    async fn get_quota_override(&self, user_id: &str) -> Result<Option<i64>> {
+        match self {
+            Self::Scylla(s) => s.get_quota_override(user_id).await,
+            Self::Sqlite(s) => s.get_quota_override(user_id).await,
+        }
+    }
+
+    async fn set_quota_override(&self, user_id: &str, max_bytes: Option<i64>) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.set_quota_override(user_id, max_bytes).await,
+            Self::Sqlite(s) => s.set_quota_override(user_id, max_bytes).await,
+        }
+    }
+
+    async fn add_daily_writes(&self, user_id: &str, day: &str, writes: i64) -> Result<i64> {
+        match self {
+            Self::Scylla(s) => s.add_daily_writes(user_id, day, writes).await,
+            Self::Sqlite(s) => s.add_daily_writes(user_id, day, writes).await,
+        }
+    }
+
+    async fn mark_user_active(&self, user_id: &str, day: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.mark_user_active(user_id, day).await,
+            Self::Sqlite(s) => s.mark_user_active(user_id, day).await,
+        }
+    }
+
+    async fn add_daily_syncs(&self, day: &str, syncs: i64) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.add_daily_syncs(day, syncs).await,
+            Self::Sqlite(s) => s.add_daily_syncs(day, syncs).await,
+        }
+    }
+
+    async fn get_daily_activity(&self, day: &str) -> Result<(i64, i64)> {
+        match self {
+            Self::Scylla(s) => s.get_daily_activity(day).await,
+            Self::Sqlite(s) => s.get_daily_activity(day).await,
+        }
+    }
+
+    async fn save_daily_stats(&self, stats: &DailyStats) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_daily_stats(stats).await,
+            Self::Sqlite(s) => s.save_daily_stats(stats).await,
+        }
+    }
+
+    async fn get_daily_stats(&self, day: &str) -> Result<Option<DailyStats>> {
+        match self {
+            Self::Scylla(s) => s.get_daily_stats(day).await,
+            Self::Sqlite(s) => s.get_daily_stats(day).await,
+        }
+    }
+
+    async fn record_corruption(&self, user_id: &str, entry: &CorruptEntry) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.record_corruption(user_id, entry).await,
+            Self::Sqlite(s) => s.record_corruption(user_id, entry).await,
+        }
+    }
+
+    async fn record_corruption_by_hash(&self, user_hash: &str, entry: &CorruptEntry) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.record_corruption_by_hash(user_hash, entry).await,
+            Self::Sqlite(s) => s.record_corruption_by_hash(user_hash, entry).await,
+        }
+    }
+
+    async fn list_corruption(&self, user_id: &str) -> Result<Vec<CorruptEntry>> {
+        match self {
+            Self::Scylla(s) => s.list_corruption(user_id).await,
+            Self::Sqlite(s) => s.list_corruption(user_id).await,
+        }
+    }
+
+    async fn create_upload(&self, user_id: &str, upload: &UploadSession) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.create_upload(user_id, upload).await,
+            Self::Sqlite(s) => s.create_upload(user_id, upload).await,
+        }
+    }
+
+    async fn get_upload(&self, user_id: &str, id: &str) -> Result<Option<UploadSession>> {
+        match self {
+            Self::Scylla(s) => s.get_upload(user_id, id).await,
+            Self::Sqlite(s) => s.get_upload(user_id, id).await,
+        }
+    }
+
+    async fn list_uploads(&self, user_id: &str) -> Result<Vec<UploadSession>> {
+        match self {
+            Self::Scylla(s) => s.list_uploads(user_id).await,
+            Self::Sqlite(s) => s.list_uploads(user_id).await,
+        }
+    }
+
+    async fn save_upload_part(
+        &self,
+        upload: &UploadSession,
+        number: i32,
+        value: Vec<u8>,
+        checksum: &str,
+    ) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_upload_part(upload, number, value, checksum).await,
+            Self::Sqlite(s) => s.save_upload_part(upload, number, value, checksum).await,
+        }
+    }
+
+    async fn list_upload_parts(&self, upload_id: &str) -> Result<Vec<UploadPart>> {
+        match self {
+            Self::Scylla(s) => s.list_upload_parts(upload_id).await,
+            Self::Sqlite(s) => s.list_upload_parts(upload_id).await,
+        }
+    }
+
+    async fn get_upload_part(&self, upload_id: &str, number: i32) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::Scylla(s) => s.get_upload_part(upload_id, number).await,
+            Self::Sqlite(s) => s.get_upload_part(upload_id, number).await,
+        }
+    }
+
+    async fn delete_upload(&self, user_id: &str, id: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.delete_upload(user_id, id).await,
+            Self::Sqlite(s) => s.delete_upload(user_id, id).await,
+        }
+    }
+
+    async fn list_users_created_since(&self, since: i64, limit: usize) -> Result<Vec<UserSummary>> {
+        match self {
+            Self::Scylla(s) => s.list_users_created_since(since, limit).await,
+            Self::Sqlite(s) => s.list_users_created_since(since, limit).await,
+        }
+    }
+
+    async fn get_data_meta(&self, user_id: &str, key: &str) -> Result<Option<DataManifestEntry>> {
+        match self {
+            Self::Scylla(s) => s.get_data_meta(user_id, key).await,
+            Self::Sqlite(s) => s.get_data_meta(user_id, key).await,
+        }
+    }
+
+    async fn save_data_key_if_version(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+        expected: Option<i64>,
+    ) -> Result<ConditionalWrite> {
+        match self {
+            Self::Scylla(s) => {
+                s.save_data_key_if_version(user_id, key, value, checksum, ttl_secs, expected)
+                    .await
+            }
+            Self::Sqlite(s) => {
+                s.save_data_key_if_version(user_id, key, value, checksum, ttl_secs, expected)
+                    .await
+            }
+        }
+    }
+
+    async fn get_data_manifest_by_hash(&self, user_hash: &str) -> Result<Vec<DataManifestEntry>> {
+        match self {
+            Self::Scylla(s) => s.get_data_manifest_by_hash(user_hash).await,
+            Self::Sqlite(s) => s.get_data_manifest_by_hash(user_hash).await,
+        }
+    }
+
+    async fn get_data_key_by_hash(&self, user_hash: &str, key: &str) -> Result<Option<DataEntry>> {
+        match self {
+            Self::Scylla(s) => s.get_data_key_by_hash(user_hash, key).await,
+            Self::Sqlite(s) => s.get_data_key_by_hash(user_hash, key).await,
+        }
+    }
+
+    async fn set_data_size_by_hash(
+        &self,
+        user_hash: &str,
+        key: &str,
+        version: i64,
+        size_bytes: i32,
+    ) -> Result<bool> {
+        match self {
+            Self::Scylla(s) => {
+                s.set_data_size_by_hash(user_hash, key, version, size_bytes)
+                    .await
+            }
+            Self::Sqlite(s) => {
+                s.set_data_size_by_hash(user_hash, key, version, size_bytes)
+                    .await
+            }
+        }
+    }
+
+    async fn list_user_hashes(&self) -> Result<Vec<String>> {
+        match self {
+            Self::Scylla(s) => s.list_user_hashes().await,
+            Self::Sqlite(s) => s.list_user_hashes().await,
+        }
+    }
+
+    async fn user_activity_by_hash(&self, user_hash: &str) -> Result<UserActivity> {
+        match self {
+            Self::Scylla(s) => s.user_activity_by_hash(user_hash).await,
+            Self::Sqlite(s) => s.user_activity_by_hash(user_hash).await,
+        }
+    }
+
+    async fn snapshot_user_by_hash(&self, user_hash: &str) -> Result<UserSnapshot> {
+        match self {
+            Self::Scylla(s) => s.snapshot_user_by_hash(user_hash).await,
+            Self::Sqlite(s) => s.snapshot_user_by_hash(user_hash).await,
+        }
+    }
+
+    async fn restore_user_by_hash(
+        &self,
+        user_hash: &str,
+        settings: Option<Vec<u8>>,
+        data: Vec<DataUpload>,
+    ) -> Result<usize> {
+        match self {
+            Self::Scylla(s) => s.restore_user_by_hash(user_hash, settings, data).await,
+            Self::Sqlite(s) => s.restore_user_by_hash(user_hash, settings, data).await,
+        }
+    }
+
+    async fn save_oauth_state(
+        &self,
+        state: &str,
+        code_verifier: Option<&str>,
+        redirect: Option<&OAuthRedirect>,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        match self {
+            Self::Scylla(s) => {
+                s.save_oauth_state(state, code_verifier, redirect, ttl_secs)
+                    .await
+            }
+            Self::Sqlite(s) => {
+                s.save_oauth_state(state, code_verifier, redirect, ttl_secs)
+                    .await
+            }
+        }
+    }
+
+    async fn consume_oauth_state(&self, state: &str) -> Result<Option<OAuthState>> {
+        match self {
+            Self::Scylla(s) => s.consume_oauth_state(state).await,
+            Self::Sqlite(s) => s.consume_oauth_state(state).await,
+        }
+    }
+
+    async fn save_oauth_code(&self, code: &str, pending: &OAuthCode, ttl_secs: i32) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_oauth_code(code, pending, ttl_secs).await,
+            Self::Sqlite(s) => s.save_oauth_code(code, pending, ttl_secs).await,
+        }
+    }
+
+    async fn consume_oauth_code(&self, code: &str) -> Result<Option<OAuthCode>> {
+        match self {
+            Self::Scylla(s) => s.consume_oauth_code(code).await,
+            Self::Sqlite(s) => s.consume_oauth_code(code).await,
+        }
+    }
+
+    async fn save_refresh_token(&self, user_id: &str, encrypted: &[u8]) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_refresh_token(user_id, encrypted).await,
+            Self::Sqlite(s) => s.save_refresh_token(user_id, encrypted).await,
+        }
+    }
+
+    async fn get_refresh_token(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::Scylla(s) => s.get_refresh_token(user_id).await,
+            Self::Sqlite(s) => s.get_refresh_token(user_id).await,
+        }
+    }
+
+    async fn delete_refresh_token(&self, user_id: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.delete_refresh_token(user_id).await,
+            Self::Sqlite(s) => s.delete_refresh_token(user_id).await,
+        }
+    }
+
+    async fn get_account_link(&self, user_id: &str) -> Result<Option<String>> {
+        match self {
+            Self::Scylla(s) => s.get_account_link(user_id).await,
+            Self::Sqlite(s) => s.get_account_link(user_id).await,
+        }
+    }
+
+    async fn save_account_link(&self, user_id: &str, account_id: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_account_link(user_id, account_id).await,
+            Self::Sqlite(s) => s.save_account_link(user_id, account_id).await,
+        }
+    }
+
+    async fn list_account_links(&self, account_id: &str) -> Result<Vec<AccountLink>> {
+        match self {
+            Self::Scylla(s) => s.list_account_links(account_id).await,
+            Self::Sqlite(s) => s.list_account_links(account_id).await,
+        }
+    }
+
+    async fn delete_account_link(&self, user_id: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.delete_account_link(user_id).await,
+            Self::Sqlite(s) => s.delete_account_link(user_id).await,
+        }
+    }
+
+    async fn get_ban(&self, user_hash: &str) -> Result<Option<Ban>> {
+        match self {
+            Self::Scylla(s) => s.get_ban(user_hash).await,
+            Self::Sqlite(s) => s.get_ban(user_hash).await,
+        }
+    }
+
+    async fn save_ban(&self, ban: &Ban) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_ban(ban).await,
+            Self::Sqlite(s) => s.save_ban(ban).await,
+        }
+    }
+
+    async fn list_bans(&self) -> Result<Vec<Ban>> {
+        match self {
+            Self::Scylla(s) => s.list_bans().await,
+            Self::Sqlite(s) => s.list_bans().await,
+        }
+    }
+
+    async fn delete_ban(&self, user_hash: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.delete_ban(user_hash).await,
+            Self::Sqlite(s) => s.delete_ban(user_hash).await,
+        }
+    }
+
+    async fn get_auth_lockout(&self, subject: &str) -> Result<Option<AuthLockout>> {
+        match self {
+            Self::Scylla(s) => s.get_auth_lockout(subject).await,
+            Self::Sqlite(s) => s.get_auth_lockout(subject).await,
+        }
+    }
+
+    async fn save_auth_lockout(&self, lockout: &AuthLockout) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_auth_lockout(lockout).await,
+            Self::Sqlite(s) => s.save_auth_lockout(lockout).await,
+        }
+    }
+
+    async fn get_pending_deletion(&self, user_hash: &str) -> Result<Option<PendingDeletion>> {
+        match self {
+            Self::Scylla(s) => s.get_pending_deletion(user_hash).await,
+            Self::Sqlite(s) => s.get_pending_deletion(user_hash).await,
+        }
+    }
+
+    async fn save_pending_deletion(&self, pending: &PendingDeletion) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_pending_deletion(pending).await,
+            Self::Sqlite(s) => s.save_pending_deletion(pending).await,
+        }
+    }
+
+    async fn list_pending_deletions(&self) -> Result<Vec<PendingDeletion>> {
+        match self {
+            Self::Scylla(s) => s.list_pending_deletions().await,
+            Self::Sqlite(s) => s.list_pending_deletions().await,
+        }
+    }
+
+    async fn delete_pending_deletion(&self, user_hash: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.delete_pending_deletion(user_hash).await,
+            Self::Sqlite(s) => s.delete_pending_deletion(user_hash).await,
+        }
+    }
+
+    async fn record_device_write(
+        &self,
+        user_id: &str,
+        device: &Device,
+        keys: &[String],
+    ) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.record_device_write(user_id, device, keys).await,
+            Self::Sqlite(s) => s.record_device_write(user_id, device, keys).await,
+        }
+    }
+
+    async fn list_devices(&self, user_id: &str) -> Result<Vec<Device>> {
+        match self {
+            Self::Scylla(s) => s.list_devices(user_id).await,
+            Self::Sqlite(s) => s.list_devices(user_id).await,
+        }
+    }
+
+    async fn insert_sync_session(
+        &self,
+        user_id: &str,
+        sync: &SyncSession,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.insert_sync_session(user_id, sync, ttl_secs).await,
+            Self::Sqlite(s) => s.insert_sync_session(user_id, sync, ttl_secs).await,
+        }
+    }
+
+    async fn list_sync_sessions(&self, user_id: &str, limit: usize) -> Result<Vec<SyncSession>> {
+        match self {
+            Self::Scylla(s) => s.list_sync_sessions(user_id, limit).await,
+            Self::Sqlite(s) => s.list_sync_sessions(user_id, limit).await,
+        }
+    }
+
+    async fn get_last_writers(&self, user_id: &str) -> Result<HashMap<String, LastWriter>> {
+        match self {
+            Self::Scylla(s) => s.get_last_writers(user_id).await,
+            Self::Sqlite(s) => s.get_last_writers(user_id).await,
+        }
+    }
+
+    async fn save_api_key(&self, key: &ApiKey) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_api_key(key).await,
+            Self::Sqlite(s) => s.save_api_key(key).await,
+        }
+    }
+
+    async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>> {
+        match self {
+            Self::Scylla(s) => s.get_api_key(id).await,
+            Self::Sqlite(s) => s.get_api_key(id).await,
+        }
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        match self {
+            Self::Scylla(s) => s.list_api_keys().await,
+            Self::Sqlite(s) => s.list_api_keys().await,
+        }
+    }
+
+    async fn delete_api_key(&self, id: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.delete_api_key(id).await,
+            Self::Sqlite(s) => s.delete_api_key(id).await,
+        }
+    }
+
+    async fn save_share(&self, share: &DataShare) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.save_share(share).await,
+            Self::Sqlite(s) => s.save_share(share).await,
+        }
+    }
+
+    async fn get_share(&self, id: &str) -> Result<Option<DataShare>> {
+        match self {
+            Self::Scylla(s) => s.get_share(id).await,
+            Self::Sqlite(s) => s.get_share(id).await,
+        }
+    }
+
+    async fn add_share_downloads(&self, id: &str, downloads: i64) -> Result<i64> {
+        match self {
+            Self::Scylla(s) => s.add_share_downloads(id, downloads).await,
+            Self::Sqlite(s) => s.add_share_downloads(id, downloads).await,
+        }
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        ttl_secs: i32,
+    ) -> Result<Option<IdempotencyRecord>> {
+        match self {
+            Self::Scylla(s) => {
+                s.claim_idempotency_key(user_id, key, fingerprint, ttl_secs)
+                    .await
+            }
+            Self::Sqlite(s) => {
+                s.claim_idempotency_key(user_id, key, fingerprint, ttl_secs)
+                    .await
+            }
+        }
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        response: &StoredResponse,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        match self {
+            Self::Scylla(s) => {
+                s.complete_idempotency_key(user_id, key, fingerprint, response, ttl_secs)
+                    .await
+            }
+            Self::Sqlite(s) => {
+                s.complete_idempotency_key(user_id, key, fingerprint, response, ttl_secs)
+                    .await
+            }
+        }
+    }
+
+    async fn release_idempotency_key(&self, user_id: &str, key: &str) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.release_idempotency_key(user_id, key).await,
+            Self::Sqlite(s) => s.release_idempotency_key(user_id, key).await,
+        }
+    }
+
+    async fn insert_audit_entry(&self, entry: &AuditEntry, ttl_secs: i32) -> Result<()> {
+        match self {
+            Self::Scylla(s) => s.insert_audit_entry(entry, ttl_secs).await,
+            Self::Sqlite(s) => s.insert_audit_entry(entry, ttl_secs).await,
+        }
+    }
+
+    async fn list_audit_entries(&self, day: &str) -> Result<Vec<AuditEntry>> {
+        match self {
+            Self::Scylla(s) => s.list_audit_entries(day).await,
+            Self::Sqlite(s) => s.list_audit_entries(day).await,
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use anyhow::Result;
+use std::collections::HashMap;
+
+use super::Datastore;
+use crate::audit::AuditEntry;
+use crate::compaction::{CompactionStats, HistoryPolicy};
+use crate::database::{
+    AccountLink, AccountPurge, ApiKey, AuthLockout, Ban, ConditionalWrite, CorruptEntry,
+    DailyStats, DataEntry, DataManifestEntry, DataRead, DataShare, DataUpload, DatabaseService,
+    Device, ExistingVersions, IdempotencyRecord, LastWriter, ManifestPage, OAuthCode, OAuthState,
+    PendingDeletion, RenameOutcome, StorageUsage, StoredResponse, SyncSession, UploadPart,
+    UploadSession, UserActivity, UserSnapshot, UserSummary,
+};
+use crate::oauth::OAuthRedirect;
+
+impl Datastore for DatabaseService {
+    async fn health_check(&self) -> Result<()> {
+        DatabaseService::health_check(self).await
+    }
+
+    async fn get_settings_metadata(&self, user_id: &str) -> Result<Option<String>> {
+        DatabaseService::get_settings_metadata(self, user_id).await
+    }
+
+    async fn get_user_settings(&self, user_id: &str) -> Result<Option<(Vec<u8>, String)>> {
+        DatabaseService::get_user_settings(self, user_id).await
+    }
+
+    async fn save_user_settings(&self, user_id: &str, settings: Vec<u8>) -> Result<i64> {
+        DatabaseService::save_user_settings(self, user_id, settings).await
+    }
+
+    async fn delete_user_settings(&self, user_id: &str) -> Result<()> {
+        DatabaseService::delete_user_settings(self, user_id).await
+    }
+
+    async fn get_settings_version(&self, user_id: &str, written: i64) -> Result<Option<Vec<u8>>> {
+        DatabaseService::get_settings_version(self, user_id, written).await
+    }
+
+    async fn compact_settings_history(
+        &self,
+        policy: HistoryPolicy,
+        now: i64,
+    ) -> Result<CompactionStats> {
+        DatabaseService::compact_settings_history(self, policy, now).await
+    }
+
+    async fn get_data_manifest(&self, user_id: &str) -> Result<Vec<DataManifestEntry>> {
+        DatabaseService::get_data_manifest(self, user_id).await
+    }
+
+    async fn get_data_manifest_page(
+        &self,
+        user_id: &str,
+        prefix: &str,
+        page_size: i32,
+        cursor: Option<Vec<u8>>,
+    ) -> Result<ManifestPage> {
+        DatabaseService::get_data_manifest_page(self, user_id, prefix, page_size, cursor).await
+    }
+
+    async fn get_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataEntry>> {
+        DatabaseService::get_data_key(self, user_id, key).await
+    }
+
+    async fn open_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataRead>> {
+        DatabaseService::open_data_key(self, user_id, key).await
+    }
+
+    async fn get_data_keys(&self, user_id: &str, keys: &[String]) -> Result<Vec<DataEntry>> {
+        DatabaseService::get_data_keys(self, user_id, keys).await
+    }
+
+    async fn delete_data_key(&self, user_id: &str, key: &str) -> Result<Option<i64>> {
+        DatabaseService::delete_data_key(self, user_id, key).await
+    }
+
+    async fn rename_data_key(&self, user_id: &str, from: &str, to: &str) -> Result<RenameOutcome> {
+        DatabaseService::rename_data_key(self, user_id, from, to).await
+    }
+
+    async fn purge_account(&self, user_id: &str) -> Result<AccountPurge> {
+        DatabaseService::purge_account(self, user_id).await
+    }
+
+    async fn purge_account_by_hash(&self, user_hash: &str) -> Result<AccountPurge> {
+        DatabaseService::purge_account_by_hash(self, user_hash).await
+    }
+
+    async fn save_data_keys_batch(
+        &self,
+        user_id: &str,
+        entries: Vec<DataUpload>,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        DatabaseService::save_data_keys_batch(self, user_id, entries).await
+    }
+
+    async fn get_versions_batch(&self, user_id: &str, keys: &[String]) -> Result<ExistingVersions> {
+        DatabaseService::get_versions_batch(self, user_id, keys).await
+    }
+
+    async fn get_user_total_size(&self, user_id: &str) -> Result<i64> {
+        DatabaseService::get_user_total_size(self, user_id).await
+    }
+
+    async fn save_data_key_with_quota_check(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+    ) -> Result<Option<(i64, i64)>> {
+        DatabaseService::save_data_key_with_quota_check(
+            self, user_id, key, value, checksum, ttl_secs,
+        )
+        .await
+    }
+
+    async fn get_storage_usage(&self, user_id: &str) -> Result<StorageUsage> {
+        DatabaseService::get_storage_usage(self, user_id).await
+    }
+
+    async fn storage_quota(&self, user_id: &str) -> Result<i64> {
+        DatabaseService::storage_quota(self, user_id).await
+    }
+
+    async fn get_quota_override(&self, user_id: &str) -> Result<Option<i64>> {
+        DatabaseService::get_quota_override(self, user_id).await
+    }
+
+    async fn set_quota_override(&self, user_id: &str, max_bytes: Option<i64>) -> Result<()> {
+        DatabaseService::set_quota_override(self, user_id, max_bytes).await
+    }
+
+    async fn add_daily_writes(&self, user_id: &str, day: &str, writes: i64) -> Result<i64> {
+        DatabaseService::add_daily_writes(self, user_id, day, writes).await
+    }
+
+    async fn mark_user_active(&self, user_id: &str, day: &str) -> Result<()> {
+        DatabaseService::mark_user_active(self, user_id, day).await
+    }
+
+    async fn add_daily_syncs(&self, day: &str, syncs: i64) -> Result<()> {
+        DatabaseService::add_daily_syncs(self, day, syncs).await
+    }
+
+    async fn get_daily_activity(&self, day: &str) -> Result<(i64, i64)> {
+        DatabaseService::get_daily_activity(self, day).await
+    }
+
+    async fn save_daily_stats(&self, stats: &DailyStats) -> Result<()> {
+        DatabaseService::save_daily_stats(self, stats).await
+    }
+
+    async fn get_daily_stats(&self, day: &str) -> Result<Option<DailyStats>> {
+        DatabaseService::get_daily_stats(self, day).await
+    }
+
+    async fn record_corruption(&self, user_id: &str, entry: &CorruptEntry) -> Result<()> {
+        DatabaseService::record_corruption(self, user_id, entry).await
+    }
+
+    async fn record_corruption_by_hash(&self, user_hash: &str, entry: &CorruptEntry) -> Result<()> {
+        DatabaseService::record_corruption_by_hash(self, user_hash, entry).await
+    }
+
+    async fn list_corruption(&self, user_id: &str) -> Result<Vec<CorruptEntry>> {
+        DatabaseService::list_corruption(self, user_id).await
+    }
+
+    async fn create_upload(&self, user_id: &str, upload: &UploadSession) -> Result<()> {
+        DatabaseService::create_upload(self, user_id, upload).await
+    }
+
+    async fn get_upload(&self, user_id: &str, id: &str) -> Result<Option<UploadSession>> {
+        DatabaseService::get_upload(self, user_id, id).await
+    }
+
+    async fn list_uploads(&self, user_id: &str) -> Result<Vec<UploadSession>> {
+        DatabaseService::list_uploads(self, user_id).await
+    }
+
+    async fn save_upload_part(
+        &self,
+        upload: &UploadSession,
+        number: i32,
+        value: Vec<u8>,
+        checksum: &str,
+    ) -> Result<()> {
+        DatabaseService::save_upload_part(self, upload, number, value, checksum).await
+    }
+
+    async fn list_upload_parts(&self, upload_id: &str) -> Result<Vec<UploadPart>> {
+        DatabaseService::list_upload_parts(self, upload_id).await
+    }
+
+    async fn get_upload_part(&self, upload_id: &str, number: i32) -> Result<Option<Vec<u8>>> {
+        DatabaseService::get_upload_part(self, upload_id, number).await
+    }
+
+    async fn delete_upload(&self, user_id: &str, id: &str) -> Result<()> {
+        DatabaseService::delete_upload(self, user_id, id).await
+    }
+
+    async fn list_users_created_since(&self, since: i64, limit: usize) -> Result<Vec<UserSummary>> {
+        DatabaseService::list_users_created_since(self, since, limit).await
+    }
+
+    async fn get_data_meta(&self, user_id: &str, key: &str) -> Result<Option<DataManifestEntry>> {
+        DatabaseService::get_data_meta(self, user_id, key).await
+    }
+
+    async fn save_data_key_if_version(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+        expected: Option<i64>,
+    ) -> Result<ConditionalWrite> {
+        DatabaseService::save_data_key_if_version(
+            self, user_id, key, value, checksum, ttl_secs, expected,
+        )
+        .await
+    }
+
+    async fn get_data_manifest_by_hash(&self, user_hash: &str) -> Result<Vec<DataManifestEntry>> {
+        DatabaseService::get_data_manifest_by_hash(self, user_hash).await
+    }
+
+    async fn get_data_key_by_hash(&self, user_hash: &str, key: &str) -> Result<Option<DataEntry>> {
+        DatabaseService::get_data_key_by_hash(self, user_hash, key).await
+    }
+
+    async fn set_data_size_by_hash(
+        &self,
+        user_hash: &str,
+        key: &str,
+        version: i64,
+        size_bytes: i32,
+    ) -> Result<bool> {
+        DatabaseService::set_data_size_by_hash(self, user_hash, key, version, size_bytes).await
+    }
+
+    async fn list_user_hashes(&self) -> Result<Vec<String>> {
+        DatabaseService::list_user_hashes(self).await
+    }
+
+    async fn user_activity_by_hash(&self, user_hash: &str) -> Result<UserActivity> {
+        DatabaseService::user_activity_by_hash(self, user_hash).await
+    }
+
+    async fn snapshot_user_by_hash(&self, user_hash: &str) -> Result<UserSnapshot> {
+        DatabaseService::snapshot_user_by_hash(self, user_hash).await
+    }
+
+    async fn restore_user_by_hash(
+        &self,
+        user_hash: &str,
+        settings: Option<Vec<u8>>,
+        data: Vec<DataUpload>,
+    ) -> Result<usize> {
+        DatabaseService::restore_user_by_hash(self, user_hash, settings, data).await
+    }
+
+    async fn save_oauth_state(
+        &self,
+        state: &str,
+        code_verifier: Option<&str>,
+        redirect: Option<&OAuthRedirect>,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        DatabaseService::save_oauth_state(self, state, code_verifier, redirect, ttl_secs).await
+    }
+
+    async fn consume_oauth_state(&self, state: &str) -> Result<Option<OAuthState>> {
+        DatabaseService::consume_oauth_state(self, state).await
+    }
+
+    async fn save_oauth_code(&self, code: &str, pending: &OAuthCode, ttl_secs: i32) -> Result<()> {
+        DatabaseService::save_oauth_code(self, code, pending, ttl_secs).await
+    }
+
+    async fn consume_oauth_code(&self, code: &str) -> Result<Option<OAuthCode>> {
+        DatabaseService::consume_oauth_code(self, code).await
+    }
+
+    async fn save_refresh_token(&self, user_id: &str, encrypted: &[u8]) -> Result<()> {
+        DatabaseService::save_refresh_token(self, user_id, encrypted).await
+    }
+
+    async fn get_refresh_token(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        DatabaseService::get_refresh_token(self, user_id).await
+    }
+
+    async fn delete_refresh_token(&self, user_id: &str) -> Result<()> {
+        DatabaseService::delete_refresh_token(self, user_id).await
+    }
+
+    async fn get_account_link(&self, user_id: &str) -> Result<Option<String>> {
+        DatabaseService::get_account_link(self, user_id).await
+    }
+
+    async fn save_account_link(&self, user_id: &str, account_id: &str) -> Result<()> {
+        DatabaseService::save_account_link(self, user_id, account_id).await
+    }
+
+    async fn list_account_links(&self, account_id: &str) -> Result<Vec<AccountLink>> {
+        DatabaseService::list_account_links(self, account_id).await
+    }
+
+    async fn delete_account_link(&self, user_id: &str) -> Result<()> {
+        DatabaseService::delete_account_link(self, user_id).await
+    }
+
+    async fn get_ban(&self, user_hash: &str) -> Result<Option<Ban>> {
+        DatabaseService::get_ban(self, user_hash).await
+    }
+
+    async fn save_ban(&self, ban: &Ban) -> Result<()> {
+        DatabaseService::save_ban(self, ban).await
+    }
+
+    async fn list_bans(&self) -> Result<Vec<Ban>> {
+        DatabaseService::list_bans(self).await
+    }
+
+    async fn delete_ban(&self, user_hash: &str) -> Result<()> {
+        DatabaseService::delete_ban(self, user_hash).await
+    }
+
+    async fn get_auth_lockout(&self, subject: &str) -> Result<Option<AuthLockout>> {
+        DatabaseService::get_auth_lockout(self, subject).await
+    }
+
+    async fn save_auth_lockout(&self, lockout: &AuthLockout) -> Result<()> {
+        DatabaseService::save_auth_lockout(self, lockout).await
+    }
+
+    async fn get_pending_deletion(&self, user_hash: &str) -> Result<Option<PendingDeletion>> {
+        DatabaseService::get_pending_deletion(self, user_hash).await
+    }
+
+    async fn save_pending_deletion(&self, pending: &PendingDeletion) -> Result<()> {
+        DatabaseService::save_pending_deletion(self, pending).await
+    }
+
+    async fn list_pending_deletions(&self) -> Result<Vec<PendingDeletion>> {
+        DatabaseService::list_pending_deletions(self).await
+    }
+
+    async fn delete_pending_deletion(&self, user_hash: &str) -> Result<()> {
+        DatabaseService::delete_pending_deletion(self, user_hash).await
+    }
+
+    async fn record_device_write(
+        &self,
+        user_id: &str,
+        device: &Device,
+        keys: &[String],
+    ) -> Result<()> {
+        DatabaseService::record_device_write(self, user_id, device, keys).await
+    }
+
+    async fn list_devices(&self, user_id: &str) -> Result<Vec<Device>> {
+        DatabaseService::list_devices(self, user_id).await
+    }
+
+    async fn insert_sync_session(
+        &self,
+        user_id: &str,
+        sync: &SyncSession,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        DatabaseService::insert_sync_session(self, user_id, sync, ttl_secs).await
+    }
+
+    async fn list_sync_sessions(&self, user_id: &str, limit: usize) -> Result<Vec<SyncSession>> {
+        DatabaseService::list_sync_sessions(self, user_id, limit).await
+    }
+
+    async fn get_last_writers(&self, user_id: &str) -> Result<HashMap<String, LastWriter>> {
+        DatabaseService::get_last_writers(self, user_id).await
+    }
+
+    async fn save_api_key(&self, key: &ApiKey) -> Result<()> {
+        DatabaseService::save_api_key(self, key).await
+    }
+
+    async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>> {
+        DatabaseService::get_api_key(self, id).await
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        DatabaseService::list_api_keys(self).await
+    }
+
+    async fn delete_api_key(&self, id: &str) -> Result<()> {
+        DatabaseService::delete_api_key(self, id).await
+    }
+
+    async fn save_share(&self, share: &DataShare) -> Result<()> {
+        DatabaseService::save_share(self, share).await
+    }
+
+    async fn get_share(&self, id: &str) -> Result<Option<DataShare>> {
+        DatabaseService::get_share(self, id).await
+    }
+
+    async fn add_share_downloads(&self, id: &str, downloads: i64) -> Result<i64> {
+        DatabaseService::add_share_downloads(self, id, downloads).await
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        ttl_secs: i32,
+    ) -> Result<Option<IdempotencyRecord>> {
+        DatabaseService::claim_idempotency_key(self, user_id, key, fingerprint, ttl_secs).await
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        response: &StoredResponse,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        DatabaseService::complete_idempotency_key(
+            self,
+            user_id,
+            key,
+            fingerprint,
+            response,
+            ttl_secs,
+        )
+        .await
+    }
+
+    async fn release_idempotency_key(&self, user_id: &str, key: &str) -> Result<()> {
+        DatabaseService::release_idempotency_key(self, user_id, key).await
+    }
+
+    async fn insert_audit_entry(&self, entry: &AuditEntry, ttl_secs: i32) -> Result<()> {
+        DatabaseService::insert_audit_entry(self, entry, ttl_secs).await
+    }
+
+    async fn list_audit_entries(&self, day: &str) -> Result<Vec<AuditEntry>> {
+        DatabaseService::list_audit_entries(self, day).await
+    }
+}
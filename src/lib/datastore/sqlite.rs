@@ -0,0 +1,2494 @@
+use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::Datastore;
+use crate::audit::{AuditEntry, day_bucket};
+use crate::compaction::{CompactionStats, HistoryPolicy};
+use crate::database::{
+    AccountLink, AccountPurge, ApiKey, ApiKeyRow, AuthLockout, Ban, ConditionalWrite, CorruptEntry,
+    DailyStats, DataEntry, DataManifestEntry, DataRead, DataShare, DataUpload, Device,
+    ExistingVersions, IdempotencyRecord, LastWriter, ManifestPage, OAuthCode, OAuthState,
+    PendingDeletion, RenameOutcome, StorageUsage, StoredResponse, SyncSession, UploadPart,
+    UploadSession, UserActivity, UserSnapshot, UserSummary, api_key_from_row, check_key,
+    check_value_size, expiry, renamed_version,
+};
+use crate::oauth::OAuthRedirect;
+use crate::utils::{CONFIG, Config, ConfigHandle, compress, decompress, hash_user_id};
+
+/// Rows expire through `purge_at`, standing in for Scylla TTLs: reads skip
+/// rows past it and writes delete them.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    settings BLOB NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS data (
+    user_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    checksum TEXT NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    deleted INTEGER NOT NULL DEFAULT 0,
+    deleted_at INTEGER,
+    expires_at INTEGER,
+    purge_at INTEGER,
+    PRIMARY KEY (user_id, key)
+);
+
+CREATE TABLE IF NOT EXISTS settings_history (
+    user_id TEXT NOT NULL,
+    written INTEGER NOT NULL,
+    settings BLOB NOT NULL,
+    PRIMARY KEY (user_id, written)
+);
+
+CREATE TABLE IF NOT EXISTS write_usage (
+    user_id TEXT NOT NULL,
+    day TEXT NOT NULL,
+    writes INTEGER NOT NULL,
+    PRIMARY KEY (user_id, day)
+);
+
+CREATE TABLE IF NOT EXISTS stats_daily (
+    day TEXT PRIMARY KEY,
+    active_users INTEGER NOT NULL,
+    users INTEGER NOT NULL,
+    bytes_stored INTEGER NOT NULL,
+    syncs INTEGER NOT NULL,
+    computed_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS active_users (
+    day TEXT NOT NULL,
+    user_hash TEXT NOT NULL,
+    PRIMARY KEY (day, user_hash)
+);
+
+CREATE TABLE IF NOT EXISTS sync_usage (
+    day TEXT PRIMARY KEY,
+    syncs INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS corrupt_data (
+    user_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    stored_checksum TEXT NOT NULL,
+    computed_checksum TEXT NOT NULL,
+    detected_at INTEGER NOT NULL,
+    PRIMARY KEY (user_id, key)
+);
+
+CREATE TABLE IF NOT EXISTS upload_sessions (
+    user_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    part_size INTEGER NOT NULL,
+    checksum TEXT,
+    ttl_secs INTEGER,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (user_id, id)
+);
+
+CREATE TABLE IF NOT EXISTS upload_parts (
+    upload_id TEXT NOT NULL,
+    part INTEGER NOT NULL,
+    value BLOB NOT NULL,
+    checksum TEXT NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    PRIMARY KEY (upload_id, part)
+);
+
+CREATE TABLE IF NOT EXISTS user_quotas (
+    user_id TEXT PRIMARY KEY,
+    max_bytes INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS oauth_states (
+    state TEXT PRIMARY KEY,
+    code_verifier TEXT,
+    redirect_uri TEXT,
+    redirect_mode TEXT,
+    purge_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS oauth_codes (
+    code TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    purge_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS oauth_tokens (
+    user_id TEXT PRIMARY KEY,
+    refresh_token BLOB NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS account_links (
+    user_id TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    account_hash TEXT NOT NULL,
+    linked_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS account_links_account_hash ON account_links (account_hash);
+
+CREATE TABLE IF NOT EXISTS data_shares (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    user_hash TEXT NOT NULL,
+    key TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    max_downloads INTEGER,
+    downloads INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS data_shares_user_hash ON data_shares (user_hash);
+
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    status INTEGER,
+    headers TEXT,
+    body BLOB,
+    purge_at INTEGER NOT NULL,
+    PRIMARY KEY (user_id, key)
+);
+
+CREATE TABLE IF NOT EXISTS banned_users (
+    user_id TEXT PRIMARY KEY,
+    reason TEXT NOT NULL,
+    banned_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    automatic INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS auth_lockouts (
+    subject TEXT PRIMARY KEY,
+    locked_until INTEGER NOT NULL,
+    failures INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pending_deletions (
+    user_hash TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    requested_at INTEGER NOT NULL,
+    purge_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS devices (
+    user_hash TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    client_name TEXT,
+    last_seen_at INTEGER NOT NULL,
+    PRIMARY KEY (user_hash, device_id)
+);
+
+CREATE TABLE IF NOT EXISTS key_writers (
+    user_hash TEXT NOT NULL,
+    key TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    client_name TEXT,
+    written_at INTEGER NOT NULL,
+    PRIMARY KEY (user_hash, key)
+);
+
+CREATE TABLE IF NOT EXISTS sync_sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_hash TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    device_id TEXT,
+    client_name TEXT,
+    duration_ms INTEGER NOT NULL,
+    uploads INTEGER NOT NULL,
+    downloads INTEGER NOT NULL,
+    deletions INTEGER NOT NULL,
+    conflicts INTEGER NOT NULL,
+    errors INTEGER NOT NULL,
+    bytes_uploaded INTEGER NOT NULL,
+    bytes_downloaded INTEGER NOT NULL,
+    purge_at INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS sync_sessions_user ON sync_sessions (user_hash, started_at);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    key_hash TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    day TEXT NOT NULL,
+    at INTEGER NOT NULL,
+    request_id TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    user_hash TEXT,
+    action TEXT NOT NULL,
+    route TEXT NOT NULL,
+    client_ip TEXT,
+    detail TEXT,
+    outcome TEXT NOT NULL,
+    purge_at INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_day ON audit_log (day, at);
+";
+
+/// Columns added to a table after it was first created. `SCHEMA` leaves
+/// existing tables alone, so files made by an older version get them here.
+const ADDED_COLUMNS: [(&str, &str, &str); 3] = [
+    ("oauth_states", "redirect_uri", "TEXT"),
+    ("oauth_states", "redirect_mode", "TEXT"),
+    ("audit_log", "client_ip", "TEXT"),
+];
+
+fn add_missing_columns(conn: &Connection) -> Result<()> {
+    for (table, column, kind) in ADDED_COLUMNS {
+        let present = conn
+            .prepare(&format!(
+                "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+                table
+            ))?
+            .exists(params![column])?;
+        if !present {
+            conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, kind),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+const LIVE: &str = "(purge_at IS NULL OR purge_at > ?2)";
+
+/// Embedded single-file backend selected with `STORAGE_BACKEND=sqlite`.
+/// Statements run on the blocking thread pool behind one connection, which
+/// is plenty for a handful of users but not meant for larger installs.
+#[derive(Clone)]
+pub struct SqliteDatastore {
+    conn: Arc<Mutex<Connection>>,
+    config: ConfigHandle,
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// When a row written with `ttl_secs` should disappear; 0 keeps it forever.
+fn purge_at(now: i64, ttl_secs: i32) -> Option<i64> {
+    (ttl_secs > 0).then(|| now + ttl_secs as i64 * 1000)
+}
+
+fn manifest_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<DataManifestEntry> {
+    Ok(DataManifestEntry {
+        key: row.get(0)?,
+        version: row.get(1)?,
+        checksum: row.get(2)?,
+        size_bytes: row.get(3)?,
+        updated_at: row.get(4)?,
+        deleted: row.get(5)?,
+        deleted_at: row.get(6)?,
+        expires_at: row.get(7)?,
+        last_writer: None,
+    })
+}
+
+const BAN_COLUMNS: &str = "user_id, reason, banned_at, expires_at, automatic";
+
+fn ban_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Ban> {
+    Ok(Ban {
+        user: row.get(0)?,
+        reason: row.get(1)?,
+        banned_at: row.get(2)?,
+        expires_at: row.get(3)?,
+        automatic: row.get(4)?,
+    })
+}
+
+const PENDING_DELETION_COLUMNS: &str = "user_id, requested_at, purge_at";
+
+fn pending_deletion_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<PendingDeletion> {
+    Ok(PendingDeletion {
+        user_id: row.get(0)?,
+        requested_at: row.get(1)?,
+        purge_at: row.get(2)?,
+    })
+}
+
+const API_KEY_COLUMNS: &str = "id, name, user_id, key_hash, scope, created_at";
+
+fn api_key_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<ApiKeyRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+    ))
+}
+
+const UPLOAD_COLUMNS: &str =
+    "id, key, size_bytes, part_size, checksum, ttl_secs, created_at, expires_at";
+
+fn upload_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<UploadSession> {
+    Ok(UploadSession {
+        id: row.get(0)?,
+        key: row.get(1)?,
+        size_bytes: row.get(2)?,
+        part_size: row.get(3)?,
+        checksum: row.get(4)?,
+        ttl_secs: row.get(5)?,
+        created_at: row.get(6)?,
+        expires_at: row.get(7)?,
+    })
+}
+
+fn read_data_key(tx: &Connection, user: &str, key: &str, now: i64) -> Result<Option<DataEntry>> {
+    let entry = tx
+        .query_row(
+            &format!(
+                "SELECT key, value, version, checksum, size_bytes, created_at, updated_at \
+                 FROM data WHERE user_id = ?1 AND key = ?3 AND deleted = 0 AND {LIVE}"
+            ),
+            params![user, now, key],
+            |row| {
+                Ok(DataEntry {
+                    key: row.get(0)?,
+                    value: row.get(1)?,
+                    version: row.get(2)?,
+                    checksum: row.get(3)?,
+                    size_bytes: row.get(4)?,
+                    created_at: row.get(5)?,
+                    updated_at: row.get(6)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(entry.map(|entry| DataEntry {
+        value: decompress(&entry.value),
+        ..entry
+    }))
+}
+
+/// Version, creation time and size of a live row, tombstones included.
+fn read_version(
+    tx: &Connection,
+    user: &str,
+    key: &str,
+    now: i64,
+) -> Result<Option<(i64, i64, i64)>> {
+    Ok(tx
+        .query_row(
+            &format!(
+                "SELECT version, created_at, size_bytes FROM data \
+                 WHERE user_id = ?1 AND key = ?3 AND {LIVE}"
+            ),
+            params![user, now, key],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?)
+}
+
+fn total_size(tx: &Connection, user: &str, now: i64) -> Result<i64> {
+    Ok(tx.query_row(
+        &format!("SELECT COALESCE(SUM(size_bytes), 0) FROM data WHERE user_id = ?1 AND {LIVE}"),
+        params![user, now],
+        |row| row.get(0),
+    )?)
+}
+
+fn quota(tx: &Connection, user: &str, default: usize) -> Result<i64> {
+    let max_bytes: Option<i64> = tx
+        .query_row(
+            "SELECT max_bytes FROM user_quotas WHERE user_id = ?1",
+            params![user],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(max_bytes.unwrap_or(default as i64))
+}
+
+/// Replaces the settings and adds them to the history, keeping `keep`
+/// versions.
+fn write_settings(
+    tx: &Transaction<'_>,
+    user: &str,
+    settings: &[u8],
+    now: i64,
+    keep: usize,
+) -> Result<()> {
+    tx.execute(
+        "INSERT INTO users (id, settings, created_at, updated_at) VALUES (?1, ?2, ?3, ?3) \
+         ON CONFLICT (id) DO UPDATE SET settings = ?2, updated_at = ?3",
+        params![user, settings, now],
+    )?;
+    if keep > 0 {
+        tx.execute(
+            "INSERT OR REPLACE INTO settings_history (user_id, written, settings) \
+             VALUES (?1, ?2, ?3)",
+            params![user, now, settings],
+        )?;
+        tx.execute(
+            "DELETE FROM settings_history WHERE user_id = ?1 AND written NOT IN \
+             (SELECT written FROM settings_history WHERE user_id = ?1 \
+              ORDER BY written DESC LIMIT ?2)",
+            params![user, keep as i64],
+        )?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_data_key(
+    tx: &Transaction<'_>,
+    config: &Config,
+    user: &str,
+    key: &str,
+    value: &[u8],
+    version: i64,
+    checksum: &str,
+    created_at: i64,
+    now: i64,
+    ttl_secs: Option<i32>,
+) -> Result<()> {
+    let (expires_at, ttl) = expiry(now, ttl_secs);
+    tx.execute(
+        "INSERT OR REPLACE INTO data (user_id, key, value, version, checksum, size_bytes, \
+         created_at, updated_at, deleted, deleted_at, expires_at, purge_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 0, NULL, ?9, ?10)",
+        params![
+            user,
+            key,
+            compress(config, value),
+            version,
+            checksum,
+            value.len() as i64,
+            created_at,
+            now,
+            expires_at,
+            purge_at(now, ttl),
+        ],
+    )?;
+    Ok(())
+}
+
+impl SqliteDatastore {
+    /// Opens `path` with the process-wide configuration, for tools and tests.
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_with_config(path, CONFIG.clone())
+    }
+
+    /// Opens `path` using `config`'s quota instead of the global one, for
+    /// tenants.
+    pub fn open_with_config(path: &str, config: ConfigHandle) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        add_missing_columns(&conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            config,
+        })
+    }
+
+    /// Runs `f` on the blocking pool inside a transaction.
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Transaction<'_>) -> Result<T> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            let tx = conn.transaction()?;
+            let result = f(&tx)?;
+            tx.commit()?;
+            Ok(result)
+        })
+        .await?
+    }
+
+    /// Writes one key after the quota check, and for `Some(expected)` only
+    /// if the key's row is still at that version.
+    async fn save_data_key_guarded(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+        expected: Option<Option<i64>>,
+    ) -> Result<ConditionalWrite> {
+        check_key(key)?;
+        let config = self.config.load();
+        check_value_size(&config, key, value.len())?;
+
+        let user = hash_user_id(user_id);
+        let key = key.to_string();
+        let checksum = checksum.to_string();
+        let now = now_ms();
+        let default_quota = config.max_backup_size_bytes;
+        self.call(move |tx| {
+            let existing = read_version(tx, &user, &key, now)?;
+            if expected.is_some_and(|expected| expected != existing.map(|e| e.0)) {
+                return Ok(ConditionalWrite::Changed);
+            }
+            let (version, created_at, existing_size) = match existing {
+                Some((v, c, s)) => (v + 1, c, s),
+                None => (1, now, 0),
+            };
+            let new_total = total_size(tx, &user, now)? - existing_size + value.len() as i64;
+            if new_total > quota(tx, &user, default_quota)? {
+                return Ok(ConditionalWrite::QuotaExceeded);
+            }
+
+            write_data_key(
+                tx, &config, &user, &key, &value, version, &checksum, created_at, now, ttl_secs,
+            )?;
+            Ok(ConditionalWrite::Saved {
+                version,
+                updated_at: now,
+            })
+        })
+        .await
+    }
+}
+
+impl Datastore for SqliteDatastore {
+    async fn health_check(&self) -> Result<()> {
+        self.call(|tx| Ok(tx.query_row("SELECT 1", [], |_| Ok(()))?))
+            .await
+    }
+
+    async fn get_settings_metadata(&self, user_id: &str) -> Result<Option<String>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            let updated_at: Option<i64> = tx
+                .query_row(
+                    "SELECT updated_at FROM users WHERE id = ?1",
+                    params![user],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(updated_at.map(|updated_at| updated_at.to_string()))
+        })
+        .await
+    }
+
+    async fn get_user_settings(&self, user_id: &str) -> Result<Option<(Vec<u8>, String)>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT settings, updated_at FROM users WHERE id = ?1",
+                    params![user],
+                    |row| Ok((row.get(0)?, row.get::<_, i64>(1)?.to_string())),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn save_user_settings(&self, user_id: &str, settings: Vec<u8>) -> Result<i64> {
+        let user = hash_user_id(user_id);
+        let now = now_ms();
+        let keep = self.config.load().settings_history_versions;
+        self.call(move |tx| {
+            write_settings(tx, &user, &settings, now, keep)?;
+            Ok(now)
+        })
+        .await
+    }
+
+    async fn delete_user_settings(&self, user_id: &str) -> Result<()> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            tx.execute("DELETE FROM users WHERE id = ?1", params![user])?;
+            tx.execute(
+                "DELETE FROM settings_history WHERE user_id = ?1",
+                params![user],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_settings_version(&self, user_id: &str, written: i64) -> Result<Option<Vec<u8>>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT settings FROM settings_history WHERE user_id = ?1 AND written = ?2",
+                    params![user, written],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn compact_settings_history(
+        &self,
+        policy: HistoryPolicy,
+        now: i64,
+    ) -> Result<CompactionStats> {
+        self.call(move |tx| {
+            let mut statement = tx.prepare(
+                "SELECT user_id, written, length(settings) FROM settings_history \
+                 ORDER BY user_id, written DESC",
+            )?;
+            let versions: Vec<(String, i64, i64)> = statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            drop(statement);
+
+            let mut stats = CompactionStats::default();
+            for history in versions.chunk_by(|a, b| a.0 == b.0) {
+                let written: Vec<i64> = history.iter().map(|(_, written, _)| *written).collect();
+                for (user, written, size) in &history[policy.kept(&written, now)..] {
+                    tx.execute(
+                        "DELETE FROM settings_history WHERE user_id = ?1 AND written = ?2",
+                        params![user, written],
+                    )?;
+                    stats.versions_removed += 1;
+                    stats.bytes_reclaimed += *size as u64;
+                }
+            }
+            Ok(stats)
+        })
+        .await
+    }
+
+    async fn get_data_manifest(&self, user_id: &str) -> Result<Vec<DataManifestEntry>> {
+        self.get_data_manifest_by_hash(&hash_user_id(user_id)).await
+    }
+
+    async fn get_data_manifest_by_hash(&self, user_hash: &str) -> Result<Vec<DataManifestEntry>> {
+        let user = user_hash.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            let mut statement = tx.prepare(&format!(
+                "SELECT key, version, checksum, size_bytes, updated_at, deleted, deleted_at, \
+                 expires_at FROM data WHERE user_id = ?1 AND {LIVE} ORDER BY key"
+            ))?;
+            let entries = statement
+                .query_map(params![user, now], manifest_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(entries)
+        })
+        .await
+    }
+
+    /// The cursor is the last key of the previous page.
+    async fn get_data_manifest_page(
+        &self,
+        user_id: &str,
+        prefix: &str,
+        page_size: i32,
+        cursor: Option<Vec<u8>>,
+    ) -> Result<ManifestPage> {
+        let user = hash_user_id(user_id);
+        let prefix = prefix.to_string();
+        let after = cursor.map(String::from_utf8).transpose()?;
+        let now = now_ms();
+        self.call(move |tx| {
+            let mut statement = tx.prepare(&format!(
+                "SELECT key, version, checksum, size_bytes, updated_at, deleted, deleted_at, \
+                 expires_at FROM data WHERE user_id = ?1 AND {LIVE} AND key >= ?3 \
+                 AND (?4 IS NULL OR key > ?4) ORDER BY key LIMIT ?5"
+            ))?;
+            let rows =
+                statement.query_map(params![user, now, prefix, after, page_size], manifest_row)?;
+
+            let mut page = ManifestPage::default();
+            for row in rows {
+                let entry = row?;
+                if !entry.key.starts_with(&prefix) {
+                    return Ok(page);
+                }
+                page.entries.push(entry);
+            }
+            if page.entries.len() == page_size as usize {
+                page.next_cursor = page.entries.last().map(|e| e.key.clone().into_bytes());
+            }
+            Ok(page)
+        })
+        .await
+    }
+
+    async fn get_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataEntry>> {
+        self.get_data_key_by_hash(&hash_user_id(user_id), key).await
+    }
+
+    /// Values are stored whole in SQLite, so they are always loaded.
+    async fn open_data_key(&self, user_id: &str, key: &str) -> Result<Option<DataRead>> {
+        Ok(self.get_data_key(user_id, key).await?.map(DataRead::Loaded))
+    }
+
+    async fn get_data_key_by_hash(&self, user_hash: &str, key: &str) -> Result<Option<DataEntry>> {
+        check_key(key)?;
+        let user = user_hash.to_string();
+        let key = key.to_string();
+        let now = now_ms();
+        self.call(move |tx| read_data_key(tx, &user, &key, now))
+            .await
+    }
+
+    async fn set_data_size_by_hash(
+        &self,
+        user_hash: &str,
+        key: &str,
+        version: i64,
+        size_bytes: i32,
+    ) -> Result<bool> {
+        let user = user_hash.to_string();
+        let key = key.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            let updated = tx.execute(
+                &format!(
+                    "UPDATE data SET size_bytes = ?4 \
+                     WHERE user_id = ?1 AND key = ?3 AND version = ?5 AND deleted = 0 AND {LIVE}"
+                ),
+                params![user, now, key, size_bytes, version],
+            )?;
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    async fn get_data_keys(&self, user_id: &str, keys: &[String]) -> Result<Vec<DataEntry>> {
+        for key in keys {
+            check_key(key)?;
+        }
+        let user = hash_user_id(user_id);
+        let keys = keys.to_vec();
+        let now = now_ms();
+        self.call(move |tx| {
+            let mut entries = Vec::with_capacity(keys.len());
+            for key in &keys {
+                if let Some(entry) = read_data_key(tx, &user, key, now)? {
+                    entries.push(entry);
+                }
+            }
+            Ok(entries)
+        })
+        .await
+    }
+
+    async fn delete_data_key(&self, user_id: &str, key: &str) -> Result<Option<i64>> {
+        check_key(key)?;
+        let user = hash_user_id(user_id);
+        let key = key.to_string();
+        let now = now_ms();
+        let tombstone_purge_at = purge_at(now, self.config.load().tombstone_ttl_secs());
+        self.call(move |tx| {
+            let Some((version, created_at, _)) = read_version(tx, &user, &key, now)? else {
+                return Ok(None);
+            };
+            tx.execute(
+                "INSERT OR REPLACE INTO data (user_id, key, value, version, checksum, size_bytes, \
+                 created_at, updated_at, deleted, deleted_at, expires_at, purge_at) \
+                 VALUES (?1, ?2, x'', ?3, '', 0, ?4, ?5, 1, ?5, NULL, ?6)",
+                params![user, key, version + 1, created_at, now, tombstone_purge_at],
+            )?;
+            Ok(Some(version + 1))
+        })
+        .await
+    }
+
+    async fn rename_data_key(&self, user_id: &str, from: &str, to: &str) -> Result<RenameOutcome> {
+        check_key(from)?;
+        check_key(to)?;
+        let user = hash_user_id(user_id);
+        let from = from.to_string();
+        let to = to.to_string();
+        let now = now_ms();
+        let tombstone_purge_at = purge_at(now, self.config.load().tombstone_ttl_secs());
+        self.call(move |tx| {
+            let source = tx
+                .query_row(
+                    &format!(
+                        "SELECT version, created_at, updated_at FROM data \
+                         WHERE user_id = ?1 AND key = ?3 AND deleted = 0 AND {LIVE}"
+                    ),
+                    params![user, now, from],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)),
+                )
+                .optional()?;
+            let Some((version, created_at, updated_at)) = source else {
+                return Ok(RenameOutcome::NotFound);
+            };
+
+            let target = tx
+                .query_row(
+                    &format!(
+                        "SELECT key, version, checksum, size_bytes, updated_at, deleted, \
+                         deleted_at, expires_at FROM data WHERE user_id = ?1 AND key = ?3 AND {LIVE}"
+                    ),
+                    params![user, now, to],
+                    manifest_row,
+                )
+                .optional()?;
+            if let Some(target) = &target
+                && !target.deleted
+                && target.updated_at > updated_at
+            {
+                return Ok(RenameOutcome::TargetNewer(target.clone()));
+            }
+
+            let new_version = renamed_version(version, target.map(|t| t.version));
+            tx.execute(
+                "INSERT OR REPLACE INTO data (user_id, key, value, version, checksum, size_bytes, \
+                 created_at, updated_at, deleted, deleted_at, expires_at, purge_at) \
+                 SELECT user_id, ?3, value, ?4, checksum, size_bytes, created_at, ?5, 0, NULL, \
+                 expires_at, purge_at FROM data WHERE user_id = ?1 AND key = ?2",
+                params![user, from, to, new_version, now],
+            )?;
+            tx.execute(
+                "INSERT OR REPLACE INTO data (user_id, key, value, version, checksum, size_bytes, \
+                 created_at, updated_at, deleted, deleted_at, expires_at, purge_at) \
+                 VALUES (?1, ?2, x'', ?3, '', 0, ?4, ?5, 1, ?5, NULL, ?6)",
+                params![user, from, version + 1, created_at, now, tombstone_purge_at],
+            )?;
+            Ok(RenameOutcome::Renamed {
+                version: new_version,
+                updated_at: now,
+                tombstone_version: version + 1,
+            })
+        })
+        .await
+    }
+
+    async fn purge_account(&self, user_id: &str) -> Result<AccountPurge> {
+        self.purge_account_by_hash(&hash_user_id(user_id)).await
+    }
+
+    async fn purge_account_by_hash(&self, user_hash: &str) -> Result<AccountPurge> {
+        let user = user_hash.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            let data_keys = tx.query_row(
+                &format!("SELECT COUNT(*) FROM data WHERE user_id = ?1 AND deleted = 0 AND {LIVE}"),
+                params![user, now],
+                |row| row.get::<_, i64>(0),
+            )?;
+            tx.execute("DELETE FROM data WHERE user_id = ?1", params![user])?;
+            let settings = tx.execute("DELETE FROM users WHERE id = ?1", params![user])?;
+            tx.execute(
+                "DELETE FROM settings_history WHERE user_id = ?1",
+                params![user],
+            )?;
+            let quota_override =
+                tx.execute("DELETE FROM user_quotas WHERE user_id = ?1", params![user])?;
+            let refresh_token =
+                tx.execute("DELETE FROM oauth_tokens WHERE user_id = ?1", params![user])?;
+            tx.execute("DELETE FROM corrupt_data WHERE user_id = ?1", params![user])?;
+            tx.execute(
+                "DELETE FROM upload_parts WHERE upload_id IN \
+                 (SELECT id FROM upload_sessions WHERE user_id = ?1)",
+                params![user],
+            )?;
+            tx.execute(
+                "DELETE FROM upload_sessions WHERE user_id = ?1",
+                params![user],
+            )?;
+            let linked_identities = tx.execute(
+                "DELETE FROM account_links WHERE account_hash = ?1",
+                params![user],
+            )?;
+            tx.execute(
+                "DELETE FROM data_shares WHERE user_hash = ?1",
+                params![user],
+            )?;
+            tx.execute(
+                "DELETE FROM idempotency_keys WHERE user_id = ?1",
+                params![user],
+            )?;
+            tx.execute(
+                "DELETE FROM pending_deletions WHERE user_hash = ?1",
+                params![user],
+            )?;
+            tx.execute("DELETE FROM devices WHERE user_hash = ?1", params![user])?;
+            tx.execute(
+                "DELETE FROM active_users WHERE user_hash = ?1",
+                params![user],
+            )?;
+            tx.execute(
+                "DELETE FROM key_writers WHERE user_hash = ?1",
+                params![user],
+            )?;
+            tx.execute(
+                "DELETE FROM sync_sessions WHERE user_hash = ?1",
+                params![user],
+            )?;
+            Ok(AccountPurge {
+                settings: settings > 0,
+                data_keys: data_keys as u64,
+                quota_override: quota_override > 0,
+                refresh_token: refresh_token > 0,
+                linked_identities: linked_identities as u64,
+            })
+        })
+        .await
+    }
+
+    async fn save_data_keys_batch(
+        &self,
+        user_id: &str,
+        entries: Vec<DataUpload>,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        let user = hash_user_id(user_id);
+        let now = now_ms();
+        let config = self.config.load();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter(|upload| upload.value.len() <= config.namespaces.max_size(&upload.key))
+            .collect();
+
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM data WHERE user_id = ?1 AND purge_at <= ?2",
+                params![user, now],
+            )?;
+            let mut saved = Vec::with_capacity(entries.len());
+            for upload in entries {
+                let (version, created_at) = match read_version(tx, &user, &upload.key, now)? {
+                    Some((version, created_at, _)) => (version + 1, created_at),
+                    None => (1, now),
+                };
+                write_data_key(
+                    tx,
+                    &config,
+                    &user,
+                    &upload.key,
+                    &upload.value,
+                    version,
+                    &upload.checksum,
+                    created_at,
+                    now,
+                    upload.ttl_secs,
+                )?;
+                saved.push((upload.key, version, now));
+            }
+            Ok(saved)
+        })
+        .await
+    }
+
+    async fn get_versions_batch(&self, user_id: &str, keys: &[String]) -> Result<ExistingVersions> {
+        let user = hash_user_id(user_id);
+        let keys = keys.to_vec();
+        let now = now_ms();
+        self.call(move |tx| {
+            let mut versions = HashMap::with_capacity(keys.len());
+            for key in keys {
+                if let Some((version, created_at, _)) = read_version(tx, &user, &key, now)? {
+                    versions.insert(key, (version, created_at, None));
+                }
+            }
+            Ok(versions)
+        })
+        .await
+    }
+
+    async fn get_user_total_size(&self, user_id: &str) -> Result<i64> {
+        let user = hash_user_id(user_id);
+        let now = now_ms();
+        self.call(move |tx| total_size(tx, &user, now)).await
+    }
+
+    async fn save_data_key_with_quota_check(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+    ) -> Result<Option<(i64, i64)>> {
+        match self
+            .save_data_key_guarded(user_id, key, value, checksum, ttl_secs, None)
+            .await?
+        {
+            ConditionalWrite::Saved {
+                version,
+                updated_at,
+            } => Ok(Some((version, updated_at))),
+            ConditionalWrite::QuotaExceeded => Ok(None),
+            ConditionalWrite::Changed => unreachable!("unconditional writes cannot conflict"),
+        }
+    }
+
+    async fn save_data_key_if_version(
+        &self,
+        user_id: &str,
+        key: &str,
+        value: Vec<u8>,
+        checksum: &str,
+        ttl_secs: Option<i32>,
+        expected: Option<i64>,
+    ) -> Result<ConditionalWrite> {
+        self.save_data_key_guarded(user_id, key, value, checksum, ttl_secs, Some(expected))
+            .await
+    }
+
+    async fn get_data_meta(&self, user_id: &str, key: &str) -> Result<Option<DataManifestEntry>> {
+        check_key(key)?;
+        let user = hash_user_id(user_id);
+        let key = key.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    &format!(
+                        "SELECT key, version, checksum, size_bytes, updated_at, deleted, \
+                         deleted_at, expires_at FROM data WHERE user_id = ?1 AND key = ?3 AND {LIVE}"
+                    ),
+                    params![user, now, key],
+                    manifest_row,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn get_storage_usage(&self, user_id: &str) -> Result<StorageUsage> {
+        let user = hash_user_id(user_id);
+        let now = now_ms();
+        self.call(move |tx| {
+            let mut usage = StorageUsage::default();
+            if let Some((size, updated_at)) = tx
+                .query_row(
+                    "SELECT LENGTH(settings), updated_at FROM users WHERE id = ?1",
+                    params![user],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?
+            {
+                usage.settings_size = Some(size);
+                usage.settings_updated_at = Some(updated_at);
+            }
+            (usage.data_keys, usage.data_size) = tx.query_row(
+                &format!(
+                    "SELECT COUNT(*), COALESCE(SUM(size_bytes), 0) FROM data \
+                     WHERE user_id = ?1 AND deleted = 0 AND {LIVE}"
+                ),
+                params![user, now],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            Ok(usage)
+        })
+        .await
+    }
+
+    async fn storage_quota(&self, user_id: &str) -> Result<i64> {
+        let user = hash_user_id(user_id);
+        let default = self.config.load().max_backup_size_bytes;
+        self.call(move |tx| quota(tx, &user, default)).await
+    }
+
+    async fn get_quota_override(&self, user_id: &str) -> Result<Option<i64>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT max_bytes FROM user_quotas WHERE user_id = ?1",
+                    params![user],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn set_quota_override(&self, user_id: &str, max_bytes: Option<i64>) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let now = now_ms();
+        self.call(move |tx| {
+            match max_bytes {
+                Some(max_bytes) => tx.execute(
+                    "INSERT OR REPLACE INTO user_quotas (user_id, max_bytes, updated_at) \
+                     VALUES (?1, ?2, ?3)",
+                    params![user, max_bytes, now],
+                )?,
+                None => tx.execute("DELETE FROM user_quotas WHERE user_id = ?1", params![user])?,
+            };
+            Ok(())
+        })
+        .await
+    }
+
+    async fn add_daily_writes(&self, user_id: &str, day: &str, writes: i64) -> Result<i64> {
+        let user = hash_user_id(user_id);
+        let day = day.to_string();
+        self.call(move |tx| {
+            Ok(tx.query_row(
+                "INSERT INTO write_usage (user_id, day, writes) VALUES (?1, ?2, ?3) \
+                 ON CONFLICT (user_id, day) DO UPDATE SET writes = writes + ?3 \
+                 RETURNING writes",
+                params![user, day, writes],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn mark_user_active(&self, user_id: &str, day: &str) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let day = day.to_string();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR IGNORE INTO active_users (day, user_hash) VALUES (?1, ?2)",
+                params![day, user],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn add_daily_syncs(&self, day: &str, syncs: i64) -> Result<()> {
+        let day = day.to_string();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT INTO sync_usage (day, syncs) VALUES (?1, ?2) \
+                 ON CONFLICT (day) DO UPDATE SET syncs = syncs + ?2",
+                params![day, syncs],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_daily_activity(&self, day: &str) -> Result<(i64, i64)> {
+        let day = day.to_string();
+        self.call(move |tx| {
+            Ok(tx.query_row(
+                "SELECT (SELECT COUNT(*) FROM active_users WHERE day = ?1), \
+                 COALESCE((SELECT syncs FROM sync_usage WHERE day = ?1), 0)",
+                params![day],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?)
+        })
+        .await
+    }
+
+    async fn save_daily_stats(&self, stats: &DailyStats) -> Result<()> {
+        let stats = stats.clone();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO stats_daily \
+                 (day, active_users, users, bytes_stored, syncs, computed_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    stats.day,
+                    stats.active_users,
+                    stats.users,
+                    stats.bytes_stored,
+                    stats.syncs,
+                    stats.computed_at
+                ],
+            )?;
+            // Like the TTL of the Scylla table: the users active on a day
+            // are not needed once the day after it has been rolled up.
+            tx.execute(
+                "DELETE FROM active_users WHERE day < date(?1, '-2 days')",
+                params![stats.day],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_daily_stats(&self, day: &str) -> Result<Option<DailyStats>> {
+        let day = day.to_string();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT day, active_users, users, bytes_stored, syncs, computed_at \
+                     FROM stats_daily WHERE day = ?1",
+                    params![day],
+                    |row| {
+                        Ok(DailyStats {
+                            day: row.get(0)?,
+                            active_users: row.get(1)?,
+                            users: row.get(2)?,
+                            bytes_stored: row.get(3)?,
+                            syncs: row.get(4)?,
+                            computed_at: row.get(5)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn record_corruption(&self, user_id: &str, entry: &CorruptEntry) -> Result<()> {
+        self.record_corruption_by_hash(&hash_user_id(user_id), entry)
+            .await
+    }
+
+    async fn record_corruption_by_hash(&self, user_hash: &str, entry: &CorruptEntry) -> Result<()> {
+        let user = user_hash.to_string();
+        let entry = entry.clone();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO corrupt_data \
+                 (user_id, key, version, stored_checksum, computed_checksum, detected_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    user,
+                    entry.key,
+                    entry.version,
+                    entry.stored_checksum,
+                    entry.computed_checksum,
+                    entry.detected_at
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_corruption(&self, user_id: &str) -> Result<Vec<CorruptEntry>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            let mut statement = tx.prepare(
+                "SELECT key, version, stored_checksum, computed_checksum, detected_at \
+                 FROM corrupt_data WHERE user_id = ?1 ORDER BY key",
+            )?;
+            let entries = statement
+                .query_map(params![user], |row| {
+                    Ok(CorruptEntry {
+                        key: row.get(0)?,
+                        version: row.get(1)?,
+                        stored_checksum: row.get(2)?,
+                        computed_checksum: row.get(3)?,
+                        detected_at: row.get(4)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(entries)
+        })
+        .await
+    }
+
+    /// Also drops expired uploads, standing in for the Scylla TTL.
+    async fn create_upload(&self, user_id: &str, upload: &UploadSession) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let upload = upload.clone();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM upload_parts WHERE upload_id IN \
+                 (SELECT id FROM upload_sessions WHERE expires_at <= ?1)",
+                params![now],
+            )?;
+            tx.execute(
+                "DELETE FROM upload_sessions WHERE expires_at <= ?1",
+                params![now],
+            )?;
+            tx.execute(
+                &format!(
+                    "INSERT INTO upload_sessions (user_id, {UPLOAD_COLUMNS}) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
+                ),
+                params![
+                    user,
+                    upload.id,
+                    upload.key,
+                    upload.size_bytes,
+                    upload.part_size,
+                    upload.checksum,
+                    upload.ttl_secs,
+                    upload.created_at,
+                    upload.expires_at
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_upload(&self, user_id: &str, id: &str) -> Result<Option<UploadSession>> {
+        let user = hash_user_id(user_id);
+        let id = id.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    &format!(
+                        "SELECT {UPLOAD_COLUMNS} FROM upload_sessions \
+                         WHERE user_id = ?1 AND id = ?2 AND expires_at > ?3"
+                    ),
+                    params![user, id, now],
+                    upload_row,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn list_uploads(&self, user_id: &str) -> Result<Vec<UploadSession>> {
+        let user = hash_user_id(user_id);
+        let now = now_ms();
+        self.call(move |tx| {
+            let mut statement = tx.prepare(&format!(
+                "SELECT {UPLOAD_COLUMNS} FROM upload_sessions \
+                 WHERE user_id = ?1 AND expires_at > ?2 ORDER BY created_at"
+            ))?;
+            let uploads = statement
+                .query_map(params![user, now], upload_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(uploads)
+        })
+        .await
+    }
+
+    async fn save_upload_part(
+        &self,
+        upload: &UploadSession,
+        number: i32,
+        value: Vec<u8>,
+        checksum: &str,
+    ) -> Result<()> {
+        let upload_id = upload.id.clone();
+        let checksum = checksum.to_string();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO upload_parts (upload_id, part, value, checksum, size_bytes) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![upload_id, number, value, checksum, value.len() as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_upload_parts(&self, upload_id: &str) -> Result<Vec<UploadPart>> {
+        let upload_id = upload_id.to_string();
+        self.call(move |tx| {
+            let mut statement = tx.prepare(
+                "SELECT part, size_bytes, checksum FROM upload_parts \
+                 WHERE upload_id = ?1 ORDER BY part",
+            )?;
+            let parts = statement
+                .query_map(params![upload_id], |row| {
+                    Ok(UploadPart {
+                        number: row.get(0)?,
+                        size_bytes: row.get(1)?,
+                        checksum: row.get(2)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(parts)
+        })
+        .await
+    }
+
+    async fn get_upload_part(&self, upload_id: &str, number: i32) -> Result<Option<Vec<u8>>> {
+        let upload_id = upload_id.to_string();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT value FROM upload_parts WHERE upload_id = ?1 AND part = ?2",
+                    params![upload_id, number],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn delete_upload(&self, user_id: &str, id: &str) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let id = id.to_string();
+        self.call(move |tx| {
+            let deleted = tx.execute(
+                "DELETE FROM upload_sessions WHERE user_id = ?1 AND id = ?2",
+                params![user, id],
+            )?;
+            if deleted > 0 {
+                tx.execute("DELETE FROM upload_parts WHERE upload_id = ?1", params![id])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_users_created_since(&self, since: i64, limit: usize) -> Result<Vec<UserSummary>> {
+        self.call(move |tx| {
+            let mut statement = tx.prepare(
+                "SELECT id, created_at, updated_at FROM users WHERE created_at > ?1 \
+                 ORDER BY created_at DESC LIMIT ?2",
+            )?;
+            let users = statement
+                .query_map(params![since, limit as i64], |row| {
+                    Ok(UserSummary {
+                        id: row.get(0)?,
+                        created_at: row.get(1)?,
+                        updated_at: row.get(2)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn list_user_hashes(&self) -> Result<Vec<String>> {
+        self.call(|tx| {
+            let mut statement =
+                tx.prepare("SELECT id FROM users UNION SELECT user_id FROM data ORDER BY 1")?;
+            let hashes = statement
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(hashes)
+        })
+        .await
+    }
+
+    async fn user_activity_by_hash(&self, user_hash: &str) -> Result<UserActivity> {
+        let user = user_hash.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            let (settings_updated_at, settings_size) = tx
+                .query_row(
+                    "SELECT updated_at, length(settings) FROM users WHERE id = ?1",
+                    params![user],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .optional()?
+                .unwrap_or((0, 0));
+            let (last_write, data_size) = tx.query_row(
+                &format!(
+                    "SELECT MAX(updated_at), \
+                     COALESCE(SUM(CASE WHEN deleted = 0 AND {LIVE} THEN size_bytes END), 0) \
+                     FROM data WHERE user_id = ?1"
+                ),
+                params![user, now],
+                |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, i64>(1)?)),
+            )?;
+            Ok(UserActivity {
+                last_active: last_write.unwrap_or(0).max(settings_updated_at),
+                size_bytes: settings_size + data_size,
+                user,
+            })
+        })
+        .await
+    }
+
+    async fn snapshot_user_by_hash(&self, user_hash: &str) -> Result<UserSnapshot> {
+        let user = user_hash.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            let settings = tx
+                .query_row(
+                    "SELECT settings, updated_at FROM users WHERE id = ?1",
+                    params![user],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            let mut statement = tx.prepare(&format!(
+                "SELECT key, expires_at FROM data WHERE user_id = ?1 AND deleted = 0 AND {LIVE} \
+                 ORDER BY key"
+            ))?;
+            let keys = statement
+                .query_map(params![user, now], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(String, Option<i64>)>>>()?;
+
+            let mut data = Vec::with_capacity(keys.len());
+            for (key, expires_at) in keys {
+                if let Some(entry) = read_data_key(tx, &user, &key, now)? {
+                    data.push((entry, expires_at));
+                }
+            }
+            Ok(UserSnapshot { settings, data })
+        })
+        .await
+    }
+
+    async fn restore_user_by_hash(
+        &self,
+        user_hash: &str,
+        settings: Option<Vec<u8>>,
+        data: Vec<DataUpload>,
+    ) -> Result<usize> {
+        let user = user_hash.to_string();
+        let now = now_ms();
+        let config = self.config.load();
+        let keep = config.settings_history_versions;
+        self.call(move |tx| {
+            if let Some(settings) = settings {
+                write_settings(tx, &user, &settings, now, keep)?;
+            }
+            let mut written = 0;
+            for upload in data {
+                if upload.value.len() > config.namespaces.max_size(&upload.key) {
+                    continue;
+                }
+                let (version, created_at) = match read_version(tx, &user, &upload.key, now)? {
+                    Some((version, created_at, _)) => (version + 1, created_at),
+                    None => (1, now),
+                };
+                write_data_key(
+                    tx,
+                    &config,
+                    &user,
+                    &upload.key,
+                    &upload.value,
+                    version,
+                    &upload.checksum,
+                    created_at,
+                    now,
+                    upload.ttl_secs,
+                )?;
+                written += 1;
+            }
+            Ok(written)
+        })
+        .await
+    }
+
+    async fn save_oauth_state(
+        &self,
+        state: &str,
+        code_verifier: Option<&str>,
+        redirect: Option<&OAuthRedirect>,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        let state = state.to_string();
+        let code_verifier = code_verifier.map(str::to_string);
+        let redirect_uri = redirect.map(|r| r.uri.clone());
+        let redirect_mode = redirect.map(|r| r.mode.name());
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM oauth_states WHERE purge_at <= ?1",
+                params![now],
+            )?;
+            tx.execute(
+                "INSERT OR REPLACE INTO oauth_states \
+                 (state, code_verifier, redirect_uri, redirect_mode, purge_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    state,
+                    code_verifier,
+                    redirect_uri,
+                    redirect_mode,
+                    now + ttl_secs as i64 * 1000
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn consume_oauth_state(&self, state: &str) -> Result<Option<OAuthState>> {
+        let state = state.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            let pending = tx
+                .query_row(
+                    "SELECT code_verifier, redirect_uri, redirect_mode FROM oauth_states \
+                     WHERE state = ?1 AND purge_at > ?2",
+                    params![state, now],
+                    |row| {
+                        Ok(OAuthState::from_columns(
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                        ))
+                    },
+                )
+                .optional()?;
+            tx.execute("DELETE FROM oauth_states WHERE state = ?1", params![state])?;
+            Ok(pending)
+        })
+        .await
+    }
+
+    async fn save_oauth_code(&self, code: &str, pending: &OAuthCode, ttl_secs: i32) -> Result<()> {
+        let code = code.to_string();
+        let pending = pending.clone();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute("DELETE FROM oauth_codes WHERE purge_at <= ?1", params![now])?;
+            tx.execute(
+                "INSERT OR REPLACE INTO oauth_codes (code, user_id, scopes, purge_at) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    code,
+                    pending.user_id,
+                    pending.scopes,
+                    now + ttl_secs as i64 * 1000
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn consume_oauth_code(&self, code: &str) -> Result<Option<OAuthCode>> {
+        let code = code.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            let pending = tx
+                .query_row(
+                    "SELECT user_id, scopes FROM oauth_codes WHERE code = ?1 AND purge_at > ?2",
+                    params![code, now],
+                    |row| {
+                        Ok(OAuthCode {
+                            user_id: row.get(0)?,
+                            scopes: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()?;
+            tx.execute("DELETE FROM oauth_codes WHERE code = ?1", params![code])?;
+            Ok(pending)
+        })
+        .await
+    }
+
+    async fn save_refresh_token(&self, user_id: &str, encrypted: &[u8]) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let encrypted = encrypted.to_vec();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO oauth_tokens (user_id, refresh_token, updated_at) \
+                 VALUES (?1, ?2, ?3)",
+                params![user, encrypted, now],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_refresh_token(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT refresh_token FROM oauth_tokens WHERE user_id = ?1",
+                    params![user],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn delete_refresh_token(&self, user_id: &str) -> Result<()> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            tx.execute("DELETE FROM oauth_tokens WHERE user_id = ?1", params![user])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_account_link(&self, user_id: &str) -> Result<Option<String>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT account_id FROM account_links WHERE user_id = ?1",
+                    params![user],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn save_account_link(&self, user_id: &str, account_id: &str) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let account_hash = hash_user_id(account_id);
+        let account_id = account_id.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO account_links (user_id, account_id, account_hash, \
+                 linked_at) VALUES (?1, ?2, ?3, ?4)",
+                params![user, account_id, account_hash, now],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_account_links(&self, account_id: &str) -> Result<Vec<AccountLink>> {
+        let account_hash = hash_user_id(account_id);
+        self.call(move |tx| {
+            let mut statement = tx.prepare(
+                "SELECT user_id, linked_at FROM account_links WHERE account_hash = ?1 \
+                 ORDER BY linked_at",
+            )?;
+            let links = statement
+                .query_map(params![account_hash], |row| {
+                    Ok(AccountLink {
+                        user: row.get(0)?,
+                        linked_at: row.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(links)
+        })
+        .await
+    }
+
+    async fn delete_account_link(&self, user_id: &str) -> Result<()> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM account_links WHERE user_id = ?1",
+                params![user],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_ban(&self, user_hash: &str) -> Result<Option<Ban>> {
+        let user = user_hash.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    &format!(
+                        "SELECT {BAN_COLUMNS} FROM banned_users WHERE user_id = ?1 \
+                         AND (expires_at IS NULL OR expires_at > ?2)"
+                    ),
+                    params![user, now],
+                    ban_row,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn save_ban(&self, ban: &Ban) -> Result<()> {
+        let ban = ban.clone();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO banned_users (user_id, reason, banned_at, expires_at, \
+                 automatic) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    ban.user,
+                    ban.reason,
+                    ban.banned_at,
+                    ban.expires_at,
+                    ban.automatic
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_bans(&self) -> Result<Vec<Ban>> {
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM banned_users WHERE expires_at <= ?1",
+                params![now],
+            )?;
+            let mut statement = tx.prepare(&format!(
+                "SELECT {BAN_COLUMNS} FROM banned_users ORDER BY banned_at DESC"
+            ))?;
+            let bans = statement
+                .query_map([], ban_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(bans)
+        })
+        .await
+    }
+
+    async fn delete_ban(&self, user_hash: &str) -> Result<()> {
+        let user = user_hash.to_string();
+        self.call(move |tx| {
+            tx.execute("DELETE FROM banned_users WHERE user_id = ?1", params![user])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_auth_lockout(&self, subject: &str) -> Result<Option<AuthLockout>> {
+        let subject = subject.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT subject, locked_until, failures FROM auth_lockouts \
+                     WHERE subject = ?1 AND locked_until > ?2",
+                    params![subject, now],
+                    |row| {
+                        Ok(AuthLockout {
+                            subject: row.get(0)?,
+                            locked_until: row.get(1)?,
+                            failures: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn save_auth_lockout(&self, lockout: &AuthLockout) -> Result<()> {
+        let lockout = lockout.clone();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM auth_lockouts WHERE locked_until <= ?1",
+                params![now],
+            )?;
+            tx.execute(
+                "INSERT OR REPLACE INTO auth_lockouts (subject, locked_until, failures) \
+                 VALUES (?1, ?2, ?3)",
+                params![lockout.subject, lockout.locked_until, lockout.failures],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_pending_deletion(&self, user_hash: &str) -> Result<Option<PendingDeletion>> {
+        let user = user_hash.to_string();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    &format!(
+                        "SELECT {PENDING_DELETION_COLUMNS} FROM pending_deletions \
+                         WHERE user_hash = ?1"
+                    ),
+                    params![user],
+                    pending_deletion_row,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn save_pending_deletion(&self, pending: &PendingDeletion) -> Result<()> {
+        let pending = pending.clone();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO pending_deletions (user_hash, user_id, requested_at, \
+                 purge_at) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    hash_user_id(&pending.user_id),
+                    pending.user_id,
+                    pending.requested_at,
+                    pending.purge_at
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_pending_deletions(&self) -> Result<Vec<PendingDeletion>> {
+        self.call(move |tx| {
+            let mut statement = tx.prepare(&format!(
+                "SELECT {PENDING_DELETION_COLUMNS} FROM pending_deletions ORDER BY purge_at"
+            ))?;
+            let pending = statement
+                .query_map([], pending_deletion_row)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(pending)
+        })
+        .await
+    }
+
+    async fn delete_pending_deletion(&self, user_hash: &str) -> Result<()> {
+        let user = user_hash.to_string();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM pending_deletions WHERE user_hash = ?1",
+                params![user],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn record_device_write(
+        &self,
+        user_id: &str,
+        device: &Device,
+        keys: &[String],
+    ) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let device = device.clone();
+        let keys = keys.to_vec();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO devices (user_hash, device_id, client_name, last_seen_at) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    user,
+                    device.device_id,
+                    device.client_name,
+                    device.last_seen_at
+                ],
+            )?;
+            let mut statement = tx.prepare(
+                "INSERT OR REPLACE INTO key_writers (user_hash, key, device_id, client_name, \
+                 written_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for key in keys {
+                statement.execute(params![
+                    user,
+                    key,
+                    device.device_id,
+                    device.client_name,
+                    device.last_seen_at
+                ])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_devices(&self, user_id: &str) -> Result<Vec<Device>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            let mut statement = tx.prepare(
+                "SELECT device_id, client_name, last_seen_at FROM devices WHERE user_hash = ?1 \
+                 ORDER BY last_seen_at DESC",
+            )?;
+            let devices = statement
+                .query_map(params![user], |row| {
+                    Ok(Device {
+                        device_id: row.get(0)?,
+                        client_name: row.get(1)?,
+                        last_seen_at: row.get(2)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(devices)
+        })
+        .await
+    }
+
+    async fn insert_sync_session(
+        &self,
+        user_id: &str,
+        sync: &SyncSession,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let sync = sync.clone();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM sync_sessions WHERE purge_at <= ?1",
+                params![now],
+            )?;
+            tx.execute(
+                "INSERT INTO sync_sessions (user_hash, started_at, device_id, client_name, \
+                 duration_ms, uploads, downloads, deletions, conflicts, errors, bytes_uploaded, \
+                 bytes_downloaded, purge_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                params![
+                    user,
+                    sync.started_at,
+                    sync.device_id,
+                    sync.client_name,
+                    sync.duration_ms,
+                    sync.uploads,
+                    sync.downloads,
+                    sync.deletions,
+                    sync.conflicts,
+                    sync.errors,
+                    sync.bytes_uploaded,
+                    sync.bytes_downloaded,
+                    purge_at(sync.started_at, ttl_secs),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_sync_sessions(&self, user_id: &str, limit: usize) -> Result<Vec<SyncSession>> {
+        let user = hash_user_id(user_id);
+        let now = now_ms();
+        self.call(move |tx| {
+            let mut statement = tx.prepare(&format!(
+                "SELECT started_at, device_id, client_name, duration_ms, uploads, downloads, \
+                 deletions, conflicts, errors, bytes_uploaded, bytes_downloaded \
+                 FROM sync_sessions WHERE user_hash = ?1 AND {LIVE} \
+                 ORDER BY started_at DESC, id DESC LIMIT ?3"
+            ))?;
+            let sessions = statement
+                .query_map(params![user, now, limit as i64], |row| {
+                    Ok(SyncSession {
+                        started_at: row.get(0)?,
+                        device_id: row.get(1)?,
+                        client_name: row.get(2)?,
+                        duration_ms: row.get(3)?,
+                        uploads: row.get(4)?,
+                        downloads: row.get(5)?,
+                        deletions: row.get(6)?,
+                        conflicts: row.get(7)?,
+                        errors: row.get(8)?,
+                        bytes_uploaded: row.get(9)?,
+                        bytes_downloaded: row.get(10)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(sessions)
+        })
+        .await
+    }
+
+    async fn get_last_writers(&self, user_id: &str) -> Result<HashMap<String, LastWriter>> {
+        let user = hash_user_id(user_id);
+        self.call(move |tx| {
+            let mut statement = tx.prepare(
+                "SELECT key, device_id, client_name, written_at FROM key_writers \
+                 WHERE user_hash = ?1",
+            )?;
+            let writers = statement
+                .query_map(params![user], |row| {
+                    Ok((
+                        row.get(0)?,
+                        LastWriter {
+                            device_id: row.get(1)?,
+                            client_name: row.get(2)?,
+                            written_at: row.get(3)?,
+                        },
+                    ))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(writers)
+        })
+        .await
+    }
+
+    async fn save_api_key(&self, key: &ApiKey) -> Result<()> {
+        let key = key.clone();
+        self.call(move |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO api_keys (id, name, user_id, key_hash, scope, created_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    key.id,
+                    key.name,
+                    key.user_id,
+                    key.key_hash,
+                    key.scope.as_str(),
+                    key.created_at
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>> {
+        let id = id.to_string();
+        self.call(move |tx| {
+            let row = tx
+                .query_row(
+                    &format!("SELECT {API_KEY_COLUMNS} FROM api_keys WHERE id = ?1"),
+                    params![id],
+                    api_key_row,
+                )
+                .optional()?;
+            Ok(row.and_then(api_key_from_row))
+        })
+        .await
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        self.call(move |tx| {
+            let mut statement = tx.prepare(&format!(
+                "SELECT {API_KEY_COLUMNS} FROM api_keys ORDER BY created_at DESC"
+            ))?;
+            let rows = statement
+                .query_map([], api_key_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(rows.into_iter().filter_map(api_key_from_row).collect())
+        })
+        .await
+    }
+
+    async fn delete_api_key(&self, id: &str) -> Result<()> {
+        let id = id.to_string();
+        self.call(move |tx| {
+            tx.execute("DELETE FROM api_keys WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn save_share(&self, share: &DataShare) -> Result<()> {
+        let share = share.clone();
+        let user_hash = hash_user_id(&share.user_id);
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM data_shares WHERE expires_at <= ?1",
+                params![now],
+            )?;
+            tx.execute(
+                "INSERT OR REPLACE INTO data_shares (id, user_id, user_hash, key, created_at, \
+                 expires_at, max_downloads) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    share.id,
+                    share.user_id,
+                    user_hash,
+                    share.key,
+                    share.created_at,
+                    share.expires_at,
+                    share.max_downloads
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_share(&self, id: &str) -> Result<Option<DataShare>> {
+        let id = id.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "SELECT id, user_id, key, created_at, expires_at, max_downloads \
+                     FROM data_shares WHERE id = ?1 AND expires_at > ?2",
+                    params![id, now],
+                    |row| {
+                        Ok(DataShare {
+                            id: row.get(0)?,
+                            user_id: row.get(1)?,
+                            key: row.get(2)?,
+                            created_at: row.get(3)?,
+                            expires_at: row.get(4)?,
+                            max_downloads: row.get(5)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn add_share_downloads(&self, id: &str, downloads: i64) -> Result<i64> {
+        let id = id.to_string();
+        self.call(move |tx| {
+            Ok(tx
+                .query_row(
+                    "UPDATE data_shares SET downloads = downloads + ?2 WHERE id = ?1 \
+                     RETURNING downloads",
+                    params![id, downloads],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .unwrap_or(0))
+        })
+        .await
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        ttl_secs: i32,
+    ) -> Result<Option<IdempotencyRecord>> {
+        let user = hash_user_id(user_id);
+        let key = key.to_string();
+        let fingerprint = fingerprint.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM idempotency_keys WHERE purge_at <= ?1",
+                params![now],
+            )?;
+            let claimed = tx.execute(
+                "INSERT OR IGNORE INTO idempotency_keys (user_id, key, fingerprint, purge_at) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![user, key, fingerprint, now + ttl_secs as i64 * 1000],
+            )?;
+            if claimed > 0 {
+                return Ok(None);
+            }
+
+            let (fingerprint, status, headers, body): (
+                String,
+                Option<u16>,
+                Option<String>,
+                Option<Vec<u8>>,
+            ) = tx.query_row(
+                "SELECT fingerprint, status, headers, body FROM idempotency_keys \
+                 WHERE user_id = ?1 AND key = ?2",
+                params![user, key],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?;
+            let response = match (status, headers) {
+                (Some(status), Some(headers)) => Some(StoredResponse {
+                    status,
+                    headers: serde_json::from_str(&headers)?,
+                    body: body.unwrap_or_default(),
+                }),
+                _ => None,
+            };
+            Ok(Some(IdempotencyRecord {
+                fingerprint,
+                response,
+            }))
+        })
+        .await
+    }
+
+    async fn complete_idempotency_key(
+        &self,
+        user_id: &str,
+        key: &str,
+        fingerprint: &str,
+        response: &StoredResponse,
+        ttl_secs: i32,
+    ) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let key = key.to_string();
+        let fingerprint = fingerprint.to_string();
+        let status = response.status;
+        let headers = serde_json::to_string(&response.headers)?;
+        let body = response.body.clone();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute(
+                "UPDATE idempotency_keys SET fingerprint = ?3, status = ?4, headers = ?5, \
+                 body = ?6, purge_at = ?7 WHERE user_id = ?1 AND key = ?2",
+                params![
+                    user,
+                    key,
+                    fingerprint,
+                    status,
+                    headers,
+                    body,
+                    now + ttl_secs as i64 * 1000
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn release_idempotency_key(&self, user_id: &str, key: &str) -> Result<()> {
+        let user = hash_user_id(user_id);
+        let key = key.to_string();
+        self.call(move |tx| {
+            tx.execute(
+                "DELETE FROM idempotency_keys WHERE user_id = ?1 AND key = ?2",
+                params![user, key],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_audit_entry(&self, entry: &AuditEntry, ttl_secs: i32) -> Result<()> {
+        let entry = entry.clone();
+        let now = now_ms();
+        self.call(move |tx| {
+            tx.execute("DELETE FROM audit_log WHERE purge_at <= ?1", params![now])?;
+            tx.execute(
+                "INSERT INTO audit_log (day, at, request_id, actor, user_hash, action, route, \
+                 client_ip, detail, outcome, purge_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    day_bucket(entry.at),
+                    entry.at,
+                    entry.request_id,
+                    entry.actor,
+                    entry.user_hash,
+                    entry.action,
+                    entry.route,
+                    entry.client_ip,
+                    entry.detail,
+                    entry.outcome,
+                    purge_at(entry.at, ttl_secs),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_audit_entries(&self, day: &str) -> Result<Vec<AuditEntry>> {
+        let day = day.to_string();
+        let now = now_ms();
+        self.call(move |tx| {
+            let mut statement = tx.prepare(&format!(
+                "SELECT at, request_id, actor, user_hash, action, route, client_ip, detail, \
+                 outcome FROM audit_log WHERE day = ?1 AND {LIVE} ORDER BY at DESC"
+            ))?;
+            let entries = statement
+                .query_map(params![day, now], |row| {
+                    Ok(AuditEntry {
+                        at: row.get(0)?,
+                        request_id: row.get(1)?,
+                        actor: row.get(2)?,
+                        user_hash: row.get(3)?,
+                        action: row.get(4)?,
+                        route: row.get(5)?,
+                        client_ip: row.get(6)?,
+                        detail: row.get(7)?,
+                        outcome: row.get(8)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(entries)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(key: &str, value: &[u8]) -> DataUpload {
+        DataUpload {
+            key: key.to_string(),
+            value: value.to_vec(),
+            checksum: "sum".to_string(),
+            ttl_secs: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_data_round_trip() {
+        let store = SqliteDatastore::open(":memory:").unwrap();
+        let saved = store
+            .save_data_keys_batch(
+                "user",
+                vec![
+                    upload("a/1", b"one"),
+                    upload("a/2", b"two"),
+                    upload("b", b"3"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(saved.len(), 3);
+
+        let entry = store.get_data_key("user", "a/1").await.unwrap().unwrap();
+        assert_eq!((entry.value.as_slice(), entry.version), (&b"one"[..], 1));
+        assert_eq!(store.get_user_total_size("user").await.unwrap(), 7);
+
+        assert_eq!(store.delete_data_key("user", "a/1").await.unwrap(), Some(2));
+        assert!(store.get_data_key("user", "a/1").await.unwrap().is_none());
+        assert_eq!(store.delete_data_key("user", "c").await.unwrap(), None);
+
+        let first = store
+            .get_data_manifest_page("user", "a/", 1, None)
+            .await
+            .unwrap();
+        assert_eq!(first.entries[0].key, "a/1");
+        assert!(first.entries[0].deleted);
+        let second = store
+            .get_data_manifest_page("user", "a/", 1, first.next_cursor)
+            .await
+            .unwrap();
+        assert_eq!(second.entries[0].key, "a/2");
+        let last = store
+            .get_data_manifest_page("user", "a/", 1, second.next_cursor)
+            .await
+            .unwrap();
+        assert!(last.entries.is_empty() && last.next_cursor.is_none());
+
+        let user_hash = hash_user_id("user");
+        let manifest = store.get_data_manifest_by_hash(&user_hash).await.unwrap();
+        assert_eq!(manifest.len(), 3);
+        let entry = store.get_data_key_by_hash(&user_hash, "b").await.unwrap();
+        assert_eq!(entry.unwrap().value, b"3");
+    }
+
+    #[tokio::test]
+    async fn test_quota_and_oauth_state() {
+        let store = SqliteDatastore::open(":memory:").unwrap();
+        store.set_quota_override("user", Some(4)).await.unwrap();
+        let put = |value: &'static [u8]| {
+            store.save_data_key_with_quota_check("user", "k", value.to_vec(), "sum", None)
+        };
+        assert_eq!(put(b"1234").await.unwrap().map(|(v, _)| v), Some(1));
+        assert_eq!(put(b"12345").await.unwrap(), None);
+        assert_eq!(put(b"4321").await.unwrap().map(|(v, _)| v), Some(2));
+
+        let redirect = OAuthRedirect {
+            uri: "https://app.example.com/auth".into(),
+            mode: crate::oauth::RedirectMode::Fragment,
+        };
+        store
+            .save_oauth_state("state", Some("verifier"), Some(&redirect), 600)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.consume_oauth_state("state").await.unwrap(),
+            Some(OAuthState {
+                code_verifier: Some("verifier".to_string()),
+                redirect: Some(redirect),
+            })
+        );
+        assert_eq!(store.consume_oauth_state("state").await.unwrap(), None);
+
+        let pending = OAuthCode {
+            user_id: "user".into(),
+            scopes: "rwd".into(),
+        };
+        store.save_oauth_code("code", &pending, 60).await.unwrap();
+        assert_eq!(
+            store.consume_oauth_code("code").await.unwrap(),
+            Some(pending)
+        );
+        assert_eq!(store.consume_oauth_code("code").await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_add_missing_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE oauth_states \
+             (state TEXT PRIMARY KEY, code_verifier TEXT, purge_at INTEGER NOT NULL);",
+        )
+        .unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        add_missing_columns(&conn).unwrap();
+        add_missing_columns(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO oauth_states (state, redirect_uri, redirect_mode, purge_at) \
+             VALUES ('s', 'u', 'code', 0)",
+            [],
+        )
+        .unwrap();
+    }
+}
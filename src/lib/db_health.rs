@@ -0,0 +1,181 @@
+//! Database health monitoring and the circuit breaker built on it.
+//!
+//! A background task checks every tenant's storage each
+//! `DB_HEALTH_CHECK_INTERVAL`. Once `DB_CIRCUIT_BREAKER_THRESHOLD` checks in
+//! a row have failed, the circuit opens: requests that need the database are
+//! answered with 503 at once instead of queueing behind query timeouts. The
+//! first successful check closes it again.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::constants::HEALTH_PROBE_TIMEOUT_MS;
+use crate::datastore::Datastore;
+use crate::tenant::Tenants;
+
+/// Outcome of the recent health checks, shared by the monitor, the request
+/// extractors and `/health`.
+pub struct DbHealth {
+    threshold: u32,
+    consecutive_failures: AtomicU32,
+    /// Milliseconds since the epoch; 0 until the first check.
+    last_checked_at: AtomicI64,
+    last_healthy_at: AtomicI64,
+    last_error: Mutex<Option<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DbHealthStatus {
+    pub healthy: bool,
+    pub circuit_open: bool,
+    pub consecutive_failures: u32,
+    pub last_checked_at: Option<i64>,
+    pub last_healthy_at: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+impl DbHealth {
+    /// A monitor that opens the circuit after `threshold` failed checks in a
+    /// row; zero never opens it.
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            consecutive_failures: AtomicU32::new(0),
+            last_checked_at: AtomicI64::new(0),
+            last_healthy_at: AtomicI64::new(0),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        self.last_checked_at.store(now, Ordering::Relaxed);
+        self.last_healthy_at.store(now, Ordering::Relaxed);
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Returns how many checks in a row have now failed.
+    pub fn record_failure(&self, error: String) -> u32 {
+        self.last_checked_at
+            .store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(error);
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    /// Whether requests that need the database should fail fast.
+    pub fn circuit_open(&self) -> bool {
+        self.threshold > 0 && self.consecutive_failures() >= self.threshold
+    }
+
+    pub fn status(&self) -> DbHealthStatus {
+        let at = |value: &AtomicI64| Some(value.load(Ordering::Relaxed)).filter(|&at| at > 0);
+        let consecutive_failures = self.consecutive_failures();
+        DbHealthStatus {
+            healthy: consecutive_failures == 0,
+            circuit_open: self.circuit_open(),
+            consecutive_failures,
+            last_checked_at: at(&self.last_checked_at),
+            last_healthy_at: at(&self.last_healthy_at),
+            last_error: self
+                .last_error
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        }
+    }
+}
+
+/// Checks every tenant's storage, failing on the first that is unreachable
+/// or slower than `HEALTH_PROBE_TIMEOUT_MS`.
+pub async fn check_tenants(tenants: &Tenants) -> Result<()> {
+    for tenant in tenants.all() {
+        tokio::time::timeout(
+            Duration::from_millis(HEALTH_PROBE_TIMEOUT_MS),
+            tenant.db.health_check(),
+        )
+        .await
+        .context("timed out")
+        .and_then(|result| result)
+        .with_context(|| format!("tenant {}", tenant.id))?;
+    }
+    Ok(())
+}
+
+/// Checks the storage every `interval` and records the outcome in `health`.
+pub async fn run_monitor(tenants: Arc<Tenants>, health: Arc<DbHealth>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick fires at once; startup has just checked the storage.
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        match check_tenants(&tenants).await {
+            Ok(()) => {
+                if health.circuit_open() {
+                    info!("Database reachable again, closing the circuit");
+                } else if health.consecutive_failures() > 0 {
+                    info!("Database connection restored");
+                }
+                health.record_success();
+            }
+            Err(e) => {
+                let failures = health.record_failure(format!("{:#}", e));
+                error!(
+                    "Database health check failed ({} in a row): {:#}",
+                    failures, e
+                );
+                if failures == health.threshold {
+                    error!("Circuit open: requests needing the database get 503 until it recovers");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_opens_and_closes() {
+        let health = DbHealth::new(2);
+        assert!(health.status().healthy);
+        assert_eq!(health.status().last_checked_at, None);
+
+        assert_eq!(health.record_failure("down".into()), 1);
+        assert!(!health.circuit_open());
+        assert_eq!(health.record_failure("still down".into()), 2);
+        assert!(health.circuit_open());
+
+        let status = health.status();
+        assert!(!status.healthy);
+        assert_eq!(status.last_error.as_deref(), Some("still down"));
+        assert_eq!(status.last_healthy_at, None);
+
+        health.record_success();
+        assert!(!health.circuit_open());
+        let status = health.status();
+        assert!(status.healthy);
+        assert_eq!(status.last_error, None);
+        assert!(status.last_healthy_at.is_some());
+    }
+
+    #[test]
+    fn test_zero_threshold_never_opens() {
+        let health = DbHealth::new(0);
+        for _ in 0..10 {
+            health.record_failure("down".into());
+        }
+        assert!(!health.circuit_open());
+    }
+}
@@ -0,0 +1,26 @@
+//! Garbage collection for the shared blob store behind `DEDUP_ENABLED` and
+//! `BLOB_STORE=s3`.
+//!
+//! Data rows take a reference on a blob when they are written and give it
+//! back when they are overwritten or deleted. Blobs whose count has dropped
+//! to zero are removed here, body and metadata alike. The collector also
+//! runs with dedup turned off so blobs written earlier are still reclaimed.
+
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::constants::{BLOB_GC_GRACE_MS, BLOB_GC_INTERVAL_SECS};
+use crate::database::DatabaseService;
+
+pub async fn run_collector(db: DatabaseService) {
+    let mut interval = tokio::time::interval(Duration::from_secs(BLOB_GC_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+
+        match db.collect_unreferenced_blobs(BLOB_GC_GRACE_MS).await {
+            Ok(0) => {}
+            Ok(collected) => info!("Blob collector removed {} unreferenced blobs", collected),
+            Err(e) => error!("Blob collection failed: {}", e),
+        }
+    }
+}
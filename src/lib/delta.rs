@@ -0,0 +1,424 @@
+//! Binary patches for sync downloads, computed rsync style.
+//!
+//! A client holding an older copy of a value describes it with a
+//! [`Signature`]: the copy cut into fixed-size blocks, each with a rolling
+//! checksum and a strong one. The server slides a window over the current
+//! value and, wherever it matches one of those blocks, refers to the block
+//! instead of sending its bytes. The client rebuilds the value from its copy
+//! with [`apply`].
+//!
+//! A patch is a sequence of instructions, with lengths as unsigned LEB128:
+//! - `0x00 <len> <bytes>` appends `len` literal bytes;
+//! - `0x01 <index> <count>` appends `count` blocks of the copy, starting at
+//!   block `index`.
+//!
+//! For JSON values there is also [`json_patch`], an RFC 6902 patch.
+
+use anyhow::{Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use std::cell::Cell;
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+use crate::constants::{DELTA_MAX_BLOCK_SIZE, DELTA_MAX_FALSE_MATCHES, DELTA_MIN_BLOCK_SIZE};
+use crate::utils::compute_checksum;
+
+const OP_LITERAL: u8 = 0x00;
+const OP_COPY: u8 = 0x01;
+
+/// Block checksums of the copy a client already has.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Signature {
+    /// Bytes per block; only the last block may be shorter.
+    pub block_size: usize,
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct BlockSignature {
+    /// Rolling checksum of the block, see [`weak_checksum`].
+    pub weak: u32,
+    /// `compute_checksum` of the block.
+    pub strong: String,
+}
+
+impl Signature {
+    pub fn of(data: &[u8], block_size: usize) -> Self {
+        Self {
+            block_size,
+            blocks: data
+                .chunks(block_size.max(1))
+                .map(|block| BlockSignature {
+                    weak: weak_checksum(block),
+                    strong: compute_checksum(block),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (DELTA_MIN_BLOCK_SIZE..=DELTA_MAX_BLOCK_SIZE).contains(&self.block_size)
+    }
+}
+
+/// The rsync rolling checksum: two 16-bit sums packed into a `u32`.
+pub fn weak_checksum(data: &[u8]) -> u32 {
+    Rolling::new(data).value()
+}
+
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in data.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    /// Slides the window one byte: `out` leaves at the front, `next` enters.
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | ((self.b & 0xffff) << 16)
+    }
+}
+
+/// A patch that turns the copy described by `signature` into `target`, or
+/// `None` if too many windows matched a rolling checksum but not the strong
+/// one, which would make diffing slower than sending the value.
+pub fn diff(signature: &Signature, target: &[u8]) -> Option<Vec<u8>> {
+    let block_size = signature.block_size;
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, block) in signature.blocks.iter().enumerate() {
+        by_weak.entry(block.weak).or_default().push(index);
+    }
+    let false_matches = Cell::new(0);
+    let find = |window: &[u8], weak: u32| {
+        let candidates = by_weak.get(&weak)?;
+        let strong = compute_checksum(window);
+        let found = candidates
+            .iter()
+            .copied()
+            .find(|&index| signature.blocks[index].strong == strong);
+        if found.is_none() {
+            false_matches.set(false_matches.get() + 1);
+        }
+        found
+    };
+
+    let mut patch = PatchWriter::default();
+    let mut literal_from = 0;
+    let mut pos = 0;
+    let mut rolling: Option<Rolling> = None;
+
+    while block_size > 0 && pos + block_size <= target.len() {
+        let window = &target[pos..pos + block_size];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).value();
+
+        if let Some(index) = find(window, weak) {
+            patch.literal(&target[literal_from..pos]);
+            patch.copy(index);
+            pos += block_size;
+            literal_from = pos;
+            rolling = None;
+            continue;
+        }
+        if false_matches.get() > DELTA_MAX_FALSE_MATCHES {
+            return None;
+        }
+
+        if let (Some(rolling), Some(&next)) = (rolling.as_mut(), target.get(pos + block_size)) {
+            rolling.roll(target[pos], next);
+        }
+        pos += 1;
+    }
+
+    // The copy's last block may be shorter than the others; it can only
+    // match what remains at the very end.
+    let tail = &target[literal_from.max(pos)..];
+    let last = signature.blocks.len().checked_sub(1);
+    match last.filter(|_| !tail.is_empty() && tail.len() < block_size) {
+        Some(last) if find(tail, weak_checksum(tail)) == Some(last) => {
+            patch.literal(&target[literal_from..target.len() - tail.len()]);
+            patch.copy(last);
+        }
+        _ => patch.literal(&target[literal_from..]),
+    }
+
+    Some(patch.finish())
+}
+
+/// A patch that carries all of `target`, for when diffing was given up.
+pub fn literal(target: &[u8]) -> Vec<u8> {
+    let mut patch = PatchWriter::default();
+    patch.literal(target);
+    patch.finish()
+}
+
+/// Rebuilds a value from `base`, the copy the signature was made of, and a
+/// patch produced by [`diff`].
+pub fn apply(base: &[u8], block_size: usize, patch: &[u8]) -> Result<Vec<u8>> {
+    ensure!(block_size > 0, "block size must be positive");
+    let mut output = Vec::new();
+    let mut reader = patch;
+
+    while let Some((&op, rest)) = reader.split_first() {
+        reader = rest;
+        match op {
+            OP_LITERAL => {
+                let len = read_varint(&mut reader)? as usize;
+                ensure!(
+                    len <= reader.len(),
+                    "literal runs past the end of the patch"
+                );
+                output.extend_from_slice(&reader[..len]);
+                reader = &reader[len..];
+            }
+            OP_COPY => {
+                let index = read_varint(&mut reader)? as usize;
+                let count = read_varint(&mut reader)? as usize;
+                let start = index.saturating_mul(block_size);
+                let end = start
+                    .saturating_add(count.saturating_mul(block_size))
+                    .min(base.len());
+                ensure!(start < end, "copy refers past the end of the base");
+                output.extend_from_slice(&base[start..end]);
+            }
+            op => bail!("unknown patch instruction {:#04x}", op),
+        }
+    }
+
+    Ok(output)
+}
+
+/// An RFC 6902 patch turning `old` into `new`. Objects are compared member
+/// by member; anything else that changed, arrays included, is replaced whole.
+pub fn json_patch(old: &Value, new: &Value) -> Vec<Value> {
+    let mut ops = Vec::new();
+    json_diff(&mut String::new(), old, new, &mut ops);
+    ops
+}
+
+fn json_diff(path: &mut String, old: &Value, new: &Value, ops: &mut Vec<Value>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => json_diff_members(path, old, new, ops),
+        _ if old == new => {}
+        _ => ops.push(json!({"op": "replace", "path": path, "value": new})),
+    }
+}
+
+fn json_diff_members(
+    path: &mut String,
+    old: &Map<String, Value>,
+    new: &Map<String, Value>,
+    ops: &mut Vec<Value>,
+) {
+    let parent = path.len();
+    for (key, old_value) in old {
+        push_pointer_token(path, key);
+        match new.get(key) {
+            Some(new_value) => json_diff(path, old_value, new_value, ops),
+            None => ops.push(json!({"op": "remove", "path": path})),
+        }
+        path.truncate(parent);
+    }
+    for (key, new_value) in new {
+        if !old.contains_key(key) {
+            push_pointer_token(path, key);
+            ops.push(json!({"op": "add", "path": path, "value": new_value}));
+            path.truncate(parent);
+        }
+    }
+}
+
+/// Appends `/key` to a JSON pointer, escaping `~` and `/` per RFC 6901.
+fn push_pointer_token(path: &mut String, key: &str) {
+    path.push('/');
+    path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+}
+
+#[derive(Default)]
+struct PatchWriter {
+    out: Vec<u8>,
+    /// A run of consecutive blocks not yet written, as (first, count).
+    pending_copy: Option<(usize, usize)>,
+}
+
+impl PatchWriter {
+    fn literal(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.flush_copy();
+        self.out.push(OP_LITERAL);
+        write_varint(&mut self.out, bytes.len() as u64);
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn copy(&mut self, index: usize) {
+        match &mut self.pending_copy {
+            Some((first, count)) if *first + *count == index => *count += 1,
+            _ => {
+                self.flush_copy();
+                self.pending_copy = Some((index, 1));
+            }
+        }
+    }
+
+    fn flush_copy(&mut self) {
+        if let Some((first, count)) = self.pending_copy.take() {
+            self.out.push(OP_COPY);
+            write_varint(&mut self.out, first as u64);
+            write_varint(&mut self.out, count as u64);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.flush_copy();
+        self.out
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(reader: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let Some((&byte, rest)) = reader.split_first() else {
+            bail!("patch ends inside a length");
+        };
+        *reader = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("length in patch is too long")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(base: &[u8], target: &[u8], block_size: usize) -> Vec<u8> {
+        let patch = diff(&Signature::of(base, block_size), target).unwrap();
+        assert_eq!(apply(base, block_size, &patch).unwrap(), target);
+        patch
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 13) as u8).collect()
+    }
+
+    #[test]
+    fn test_rolling_matches_fresh_checksum() {
+        let data = sample(300);
+        let mut rolling = Rolling::new(&data[..64]);
+        for pos in 1..=data.len() - 64 {
+            rolling.roll(data[pos - 1], data[pos + 63]);
+            assert_eq!(rolling.value(), weak_checksum(&data[pos..pos + 64]));
+        }
+    }
+
+    #[test]
+    fn test_small_edit_gives_small_patch() {
+        let base = sample(64 * 1024);
+        let mut target = base.clone();
+        target[30_000] ^= 0xff;
+        target.splice(50_000..50_000, *b"inserted");
+
+        let patch = roundtrip(&base, &target, 1024);
+        assert!(patch.len() < 3 * 1024, "patch is {} bytes", patch.len());
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        let base = sample(5000);
+        assert!(roundtrip(&base, &base, 1024).len() < 8);
+        roundtrip(&base, &base[..4500], 1024);
+        roundtrip(&base, &base[100..], 1024);
+        roundtrip(&base, b"", 1024);
+        roundtrip(b"", &base, 1024);
+        roundtrip(&base, b"short", 1024);
+        roundtrip(&base[..10], &base[..10], 1024);
+    }
+
+    #[test]
+    fn test_apply_rejects_bad_patches() {
+        let base = sample(100);
+        assert!(apply(&base, 64, &[OP_COPY, 5, 1]).is_err());
+        assert!(apply(&base, 64, &[OP_LITERAL, 10, 1]).is_err());
+        assert!(apply(&base, 64, &[0x07]).is_err());
+        assert!(apply(&base, 64, &[OP_LITERAL, 0x80]).is_err());
+    }
+
+    #[test]
+    fn test_gives_up_on_weak_collisions() {
+        let block = BlockSignature {
+            weak: weak_checksum(&[0; 64]),
+            strong: String::new(),
+        };
+        let signature = Signature {
+            block_size: 64,
+            blocks: vec![block],
+        };
+        assert_eq!(diff(&signature, &vec![0; 64 * 1024]), None);
+    }
+
+    #[test]
+    fn test_literal_patch() {
+        let base = sample(100);
+        let target = sample(3000);
+        assert_eq!(apply(&base, 64, &literal(&target)).unwrap(), target);
+        assert!(literal(b"").is_empty());
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let old = json!({"a": 1, "b": {"c": [1, 2], "d/e": true, "gone": null}, "same": "x"});
+        let new = json!({"a": 2, "b": {"c": [1, 2, 3], "d/e": true, "~new": {}}, "same": "x"});
+        assert_eq!(
+            json_patch(&old, &new),
+            vec![
+                json!({"op": "replace", "path": "/a", "value": 2}),
+                json!({"op": "replace", "path": "/b/c", "value": [1, 2, 3]}),
+                json!({"op": "remove", "path": "/b/gone"}),
+                json!({"op": "add", "path": "/b/~0new", "value": {}}),
+            ]
+        );
+        assert!(json_patch(&new, &new).is_empty());
+        assert_eq!(
+            json_patch(&old, &json!([])),
+            vec![json!({"op": "replace", "path": "", "value": []})]
+        );
+    }
+
+    #[test]
+    fn test_signature_bounds() {
+        assert!(Signature::of(b"", DELTA_MIN_BLOCK_SIZE).is_valid());
+        assert!(!Signature::of(b"", 1).is_valid());
+        assert!(!Signature::of(b"", DELTA_MAX_BLOCK_SIZE + 1).is_valid());
+    }
+}
@@ -0,0 +1,112 @@
+//! Developer mode, for working on handlers without an OAuth application or
+//! Scylla.
+//!
+//! With `DEV_MODE=true` everything is stored in SQLite, in memory unless
+//! `SQLITE_PATH` is set, and a request may name the user it is made as in
+//! [`DEV_USER_HEADER`] instead of sending a token. A few sample users are
+//! seeded at startup. The mode does not turn on when `SERVER_FQDN` names
+//! anything but the local machine, so a production configuration cannot
+//! enable it by accident.
+
+use anyhow::Result;
+use reqwest::Url;
+use std::net::IpAddr;
+
+use crate::checksum::ChecksumAlgorithm;
+use crate::datastore::{Datastore, Storage};
+
+/// Header naming the user a request is made as, with every scope.
+pub const DEV_USER_HEADER: &str = "x-dev-user-id";
+
+/// Users seeded at startup.
+pub const SAMPLE_USERS: [&str; 3] = [
+    "100000000000000001",
+    "100000000000000002",
+    "100000000000000003",
+];
+
+/// Data keys every sample user gets.
+const SAMPLE_DATA: [(&str, &[u8]); 3] = [
+    ("plugins/notes", b"{\"notes\":{}}"),
+    ("plugins/favorites", b"[\"general\",\"memes\"]"),
+    (
+        "themes/dark.css",
+        b":root { --background-primary: #1e1f22; }",
+    ),
+];
+
+/// Whether `url` points at the local machine: `localhost`, a name below it
+/// or a loopback address.
+pub fn is_local(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    host == "localhost"
+        || host.ends_with(".localhost")
+        || host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Gives every sample user settings and [`SAMPLE_DATA`], stored with
+/// `algorithm`'s checksums. Users that already have settings, as in a
+/// SQLite file kept from an earlier run, are left alone. Returns how many
+/// were seeded.
+pub async fn seed(db: &Storage, algorithm: ChecksumAlgorithm) -> Result<usize> {
+    let mut seeded = 0;
+    for user_id in SAMPLE_USERS {
+        if db.get_user_settings(user_id).await?.is_some() {
+            continue;
+        }
+        let settings = serde_json::json!({
+            "settings": { "plugins": { "NoTrack": { "enabled": true } } },
+            "quickCss": "",
+        });
+        db.save_user_settings(user_id, settings.to_string().into_bytes())
+            .await?;
+        for (key, value) in SAMPLE_DATA {
+            let checksum = algorithm.compute(value);
+            db.save_data_key_with_quota_check(user_id, key, value.to_vec(), &checksum, None)
+                .await?;
+        }
+        seeded += 1;
+    }
+    Ok(seeded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::SqliteDatastore;
+
+    #[test]
+    fn test_is_local() {
+        let local = |url: &str| is_local(&Url::parse(url).unwrap());
+        assert!(local("http://localhost:8080"));
+        assert!(local("http://equicloud.localhost"));
+        assert!(local("http://127.0.0.1:8080"));
+        assert!(local("http://[::1]:8080"));
+        assert!(!local("https://cloud.example.com"));
+        assert!(!local("https://localhost.example.com"));
+        assert!(!local("http://10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_seed() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        assert_eq!(seed(&db, ChecksumAlgorithm::Sha256).await.unwrap(), 3);
+        let meta = db
+            .get_data_meta(SAMPLE_USERS[0], "plugins/notes")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            meta.checksum,
+            ChecksumAlgorithm::Sha256.compute(SAMPLE_DATA[0].1)
+        );
+
+        assert_eq!(seed(&db, ChecksumAlgorithm::Sha256).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,165 @@
+//! Which device wrote what.
+//!
+//! Clients may name themselves on writes and syncs with `X-Device-Id`, a
+//! stable id of the install, and `X-Client-Name`, a label to show for it.
+//! The device is then recorded as the last writer of every key the request
+//! wrote and listed by `GET /v2/devices` with when it was last seen.
+//! Requests without `X-Device-Id` are not attributed.
+
+use axum::http::HeaderMap;
+use tracing::warn;
+
+use crate::constants::{MAX_CLIENT_NAME_LEN, MAX_DEVICE_ID_LEN};
+use crate::database::Device;
+use crate::datastore::{Datastore, Storage};
+use crate::error::AppError;
+
+pub const DEVICE_ID_HEADER: &str = "x-device-id";
+pub const CLIENT_NAME_HEADER: &str = "x-client-name";
+
+/// The device a request was sent from, as it named itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientDevice {
+    pub device_id: String,
+    pub client_name: Option<String>,
+}
+
+impl ClientDevice {
+    /// The device named by `headers`, `None` without `X-Device-Id`. An
+    /// `X-Client-Name` alone is ignored.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, AppError> {
+        let header = |name: &str| -> Result<Option<&str>, AppError> {
+            headers
+                .get(name)
+                .map(|v| {
+                    v.to_str()
+                        .map(str::trim)
+                        .map_err(|_| AppError::BadRequest(format!("Invalid {} header", name)))
+                })
+                .transpose()
+        };
+
+        let Some(device_id) = header(DEVICE_ID_HEADER)? else {
+            return Ok(None);
+        };
+        if device_id.is_empty()
+            || device_id.len() > MAX_DEVICE_ID_LEN
+            || !device_id.bytes().all(|b| b.is_ascii_graphic())
+        {
+            return Err(AppError::BadRequest(format!(
+                "X-Device-Id must be 1 to {} visible ASCII characters",
+                MAX_DEVICE_ID_LEN
+            )));
+        }
+
+        let client_name = header(CLIENT_NAME_HEADER)?.filter(|name| !name.is_empty());
+        if client_name.is_some_and(|name| name.len() > MAX_CLIENT_NAME_LEN) {
+            return Err(AppError::BadRequest(format!(
+                "X-Client-Name must be at most {} characters",
+                MAX_CLIENT_NAME_LEN
+            )));
+        }
+
+        Ok(Some(Self {
+            device_id: device_id.to_string(),
+            client_name: client_name.map(str::to_string),
+        }))
+    }
+}
+
+/// Records `device` as seen at `now` and as the last writer of `keys`. The
+/// writes were already made, so a failure is logged rather than returned.
+pub async fn record_write(
+    db: &Storage,
+    user_id: &str,
+    device: &ClientDevice,
+    keys: &[String],
+    now: i64,
+) {
+    let seen = Device {
+        device_id: device.device_id.clone(),
+        client_name: device.client_name.clone(),
+        last_seen_at: now,
+    };
+    if let Err(e) = db.record_device_write(user_id, &seen, keys).await {
+        warn!("Failed to record device write: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::SqliteDatastore;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_from_headers() {
+        assert_eq!(ClientDevice::from_headers(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(
+            ClientDevice::from_headers(&headers(&[(CLIENT_NAME_HEADER, "Desktop")])).unwrap(),
+            None
+        );
+        assert_eq!(
+            ClientDevice::from_headers(&headers(&[
+                (DEVICE_ID_HEADER, "abc-123"),
+                (CLIENT_NAME_HEADER, "Vencord on Desktop")
+            ]))
+            .unwrap(),
+            Some(ClientDevice {
+                device_id: "abc-123".into(),
+                client_name: Some("Vencord on Desktop".into()),
+            })
+        );
+
+        let long = "a".repeat(MAX_DEVICE_ID_LEN + 1);
+        for bad in ["", "has space", long.as_str()] {
+            assert!(matches!(
+                ClientDevice::from_headers(&headers(&[(DEVICE_ID_HEADER, bad)])),
+                Err(AppError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_write() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let laptop = ClientDevice {
+            device_id: "laptop".into(),
+            client_name: Some("Laptop".into()),
+        };
+        let phone = ClientDevice {
+            device_id: "phone".into(),
+            client_name: None,
+        };
+
+        record_write(&db, "1", &laptop, &["a".into(), "b".into()], 1000).await;
+        record_write(&db, "1", &phone, &["b".into()], 2000).await;
+        record_write(&db, "1", &laptop, &[], 3000).await;
+
+        let devices = db.list_devices("1").await.unwrap();
+        assert_eq!(
+            devices
+                .iter()
+                .map(|d| (d.device_id.as_str(), d.last_seen_at))
+                .collect::<Vec<_>>(),
+            [("laptop", 3000), ("phone", 2000)]
+        );
+
+        let writers = db.get_last_writers("1").await.unwrap();
+        assert_eq!(writers["a"].device_id, "laptop");
+        assert_eq!(writers["a"].written_at, 1000);
+        assert_eq!(writers["b"].device_id, "phone");
+        assert!(db.list_devices("2").await.unwrap().is_empty());
+
+        db.purge_account("1").await.unwrap();
+        assert!(db.list_devices("1").await.unwrap().is_empty());
+        assert!(db.get_last_writers("1").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+//! What the Scylla driver is doing with its connections.
+//!
+//! Every statement `DatabaseService` prepares reports to a [`NodeTracker`],
+//! which counts requests and, per node, the attempts in flight, the
+//! attempts made, the ones that failed and the ones retried. Together with
+//! which nodes the driver holds connections to, they make up
+//! [`DriverStats`], served by `/metrics` and summarised in `/health` when a
+//! node is unreachable.
+
+use scylla::cluster::ClusterState;
+use scylla::errors::{DbError, RequestAttemptError, RequestError};
+use scylla::observability::history::{AttemptId, HistoryListener, RequestId, SpeculativeId};
+use scylla::policies::retry::RetryDecision;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct NodeCounters {
+    in_flight: u64,
+    attempts: u64,
+    errors: u64,
+    retries: u64,
+    timeouts: u64,
+}
+
+#[derive(Debug, Default)]
+struct Attempts {
+    /// Node of every attempt still waiting for an answer.
+    in_flight: HashMap<usize, SocketAddr>,
+    nodes: BTreeMap<SocketAddr, NodeCounters>,
+}
+
+/// Counts what happens to the requests of the statements it listens to.
+#[derive(Debug, Default)]
+pub struct NodeTracker {
+    next_id: AtomicUsize,
+    requests: AtomicU64,
+    failed_requests: AtomicU64,
+    attempts: Mutex<Attempts>,
+}
+
+impl NodeTracker {
+    fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn finish_attempt(&self, attempt_id: AttemptId, update: impl FnOnce(&mut NodeCounters)) {
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(node) = attempts.in_flight.remove(&attempt_id.0) else {
+            return;
+        };
+        let counters = attempts.nodes.entry(node).or_default();
+        counters.in_flight = counters.in_flight.saturating_sub(1);
+        update(counters);
+    }
+
+    /// The driver's view of its nodes with what was counted for each.
+    /// Nodes the cluster no longer lists are left out.
+    pub fn stats(&self, cluster: &ClusterState) -> DriverStats {
+        let attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        let nodes = cluster
+            .get_nodes_info()
+            .iter()
+            .map(|node| {
+                let address = SocketAddr::new(node.address.ip(), node.address.port());
+                let counters = attempts.nodes.get(&address).copied().unwrap_or_default();
+                NodeStats {
+                    address: address.to_string(),
+                    datacenter: node.datacenter.clone(),
+                    enabled: node.is_enabled(),
+                    connected: node.is_connected(),
+                    in_flight: counters.in_flight,
+                    attempts: counters.attempts,
+                    errors: counters.errors,
+                    retries: counters.retries,
+                    timeouts: counters.timeouts,
+                    error_rate: error_rate(counters.errors, counters.attempts),
+                }
+            })
+            .collect();
+        DriverStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            failed_requests: self.failed_requests.load(Ordering::Relaxed),
+            nodes,
+        }
+    }
+}
+
+fn error_rate(errors: u64, attempts: u64) -> f64 {
+    if attempts == 0 {
+        0.0
+    } else {
+        errors as f64 / attempts as f64
+    }
+}
+
+impl HistoryListener for NodeTracker {
+    fn log_request_start(&self) -> RequestId {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        RequestId(self.next_id())
+    }
+
+    fn log_request_success(&self, _request_id: RequestId) {}
+
+    fn log_request_error(&self, _request_id: RequestId, _error: &RequestError) {
+        self.failed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn log_new_speculative_fiber(&self, _request_id: RequestId) -> SpeculativeId {
+        SpeculativeId(self.next_id())
+    }
+
+    fn log_attempt_start(
+        &self,
+        _request_id: RequestId,
+        _speculative_id: Option<SpeculativeId>,
+        node_addr: SocketAddr,
+    ) -> AttemptId {
+        let id = self.next_id();
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        attempts.in_flight.insert(id, node_addr);
+        let counters = attempts.nodes.entry(node_addr).or_default();
+        counters.in_flight += 1;
+        counters.attempts += 1;
+        AttemptId(id)
+    }
+
+    fn log_attempt_success(&self, attempt_id: AttemptId) {
+        self.finish_attempt(attempt_id, |_| {});
+    }
+
+    fn log_attempt_error(
+        &self,
+        attempt_id: AttemptId,
+        error: &RequestAttemptError,
+        retry_decision: &RetryDecision,
+    ) {
+        let timed_out = matches!(
+            error,
+            RequestAttemptError::DbError(
+                DbError::ReadTimeout { .. } | DbError::WriteTimeout { .. },
+                _
+            )
+        );
+        let retried = matches!(
+            retry_decision,
+            RetryDecision::RetrySameTarget(_) | RetryDecision::RetryNextTarget(_)
+        );
+        self.finish_attempt(attempt_id, |counters| {
+            counters.errors += 1;
+            counters.retries += u64::from(retried);
+            counters.timeouts += u64::from(timed_out);
+        });
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DriverStats {
+    /// Requests sent since startup, retries and later pages not counted
+    /// again.
+    pub requests: u64,
+    /// Requests that failed after any retries.
+    pub failed_requests: u64,
+    pub nodes: Vec<NodeStats>,
+}
+
+impl DriverStats {
+    /// Nodes the driver should be connected to but is not.
+    pub fn unreachable_nodes(&self) -> impl Iterator<Item = &NodeStats> {
+        self.nodes.iter().filter(|n| n.enabled && !n.connected)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeStats {
+    pub address: String,
+    pub datacenter: Option<String>,
+    /// Whether the load balancing policy may send requests to the node;
+    /// the driver only opens connections to enabled nodes.
+    pub enabled: bool,
+    /// Whether the driver has at least one open connection to the node.
+    pub connected: bool,
+    /// Attempts sent to the node and not yet answered.
+    pub in_flight: u64,
+    pub attempts: u64,
+    pub errors: u64,
+    /// Failed attempts the retry policy sent again.
+    pub retries: u64,
+    /// Failed attempts the node answered with a read or write timeout.
+    pub timeouts: u64,
+    /// `errors` out of `attempts`.
+    pub error_rate: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_tracker_counts_attempts() {
+        let tracker = NodeTracker::default();
+        let a: SocketAddr = "10.0.0.1:9042".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:9042".parse().unwrap();
+
+        let request = tracker.log_request_start();
+        let first = tracker.log_attempt_start(request, None, a);
+        let second = tracker.log_attempt_start(request, None, b);
+        let third = tracker.log_attempt_start(request, None, b);
+        tracker.log_attempt_error(
+            first,
+            &RequestAttemptError::UnableToAllocStreamId,
+            &RetryDecision::RetryNextTarget(None),
+        );
+        tracker.log_attempt_success(second);
+        tracker.log_request_success(request);
+        // An unknown attempt, e.g. a late speculative one, is ignored.
+        tracker.log_attempt_success(AttemptId(usize::MAX));
+
+        let attempts = tracker.attempts.lock().unwrap();
+        assert_eq!(
+            attempts.nodes[&a],
+            NodeCounters {
+                in_flight: 0,
+                attempts: 1,
+                errors: 1,
+                retries: 1,
+                timeouts: 0,
+            }
+        );
+        assert_eq!(attempts.nodes[&b].in_flight, 1);
+        assert_eq!(attempts.nodes[&b].attempts, 2);
+        assert!(attempts.in_flight.contains_key(&third.0));
+        assert_eq!(tracker.requests.load(Ordering::Relaxed), 1);
+        assert_eq!(tracker.failed_requests.load(Ordering::Relaxed), 0);
+        assert_eq!(error_rate(1, 4), 0.25);
+        assert_eq!(error_rate(0, 0), 0.0);
+    }
+}
@@ -0,0 +1,280 @@
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::fmt;
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::oauth::ProviderError;
+use crate::validation::KeyValidationError;
+
+/// Error type returned by every handler. Responses always have the shape
+/// `{"error": "<human readable>", "code": "<machine readable>"}`.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    InvalidKey(KeyValidationError),
+    Unauthorized(String),
+    Forbidden(String),
+    DatastoreDisabled,
+    /// The key is under a prefix that `KEY_NAMESPACES` turns off.
+    NamespaceDisabled(String),
+    /// The user is on the ban list; temporary bans say when they lift.
+    Banned {
+        reason: String,
+        retry_after_secs: Option<u64>,
+    },
+    NotFound,
+    /// The resource existed but is no longer available, e.g. an expired
+    /// share link.
+    Gone(String),
+    /// The data key existed but was deleted; its tombstone says when.
+    Deleted {
+        deleted_at: i64,
+    },
+    /// The request clashes with the current state, e.g. linking an identity
+    /// that already belongs to an account.
+    Conflict(String),
+    PreconditionFailed,
+    /// The body does not match the checksum sent with it, so it was most
+    /// likely damaged on the way and should be sent again.
+    ChecksumMismatch,
+    /// With `SETTINGS_JSON_VALIDATION`, a settings backup that is not
+    /// well-formed JSON within the configured limits.
+    InvalidSettings(String),
+    /// A conditional write found the key changed; carries what is stored
+    /// now, `None` when the key does not exist.
+    VersionConflict {
+        version: Option<i64>,
+        checksum: Option<String>,
+    },
+    PayloadTooLarge(String),
+    QuotaExceeded,
+    /// The write would create a key beyond the user's `MAX_KEYS_PER_USER`.
+    KeyLimitExceeded {
+        limit: u64,
+    },
+    /// The user's `DAILY_WRITE_LIMIT` is used up until the next UTC day.
+    WriteLimitExceeded {
+        retry_after_secs: u64,
+    },
+    /// Too many failed sign-ins for the user, API key or address; see
+    /// `lockout`.
+    LockedOut {
+        retry_after_secs: u64,
+    },
+    UnsupportedMediaType(String),
+    Upstream(String),
+    /// The identity provider failed too many requests in a row and is not
+    /// called again until `retry_after_secs` have passed.
+    ProviderUnavailable {
+        retry_after_secs: u64,
+    },
+    /// The database failed its recent health checks; see `DbHealth`.
+    DatabaseUnavailable,
+    Timeout,
+    Internal(&'static str),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) | Self::InvalidKey(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_)
+            | Self::DatastoreDisabled
+            | Self::NamespaceDisabled(_)
+            | Self::Banned { .. } => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Gone(_) | Self::Deleted { .. } => StatusCode::GONE,
+            Self::Conflict(_) | Self::VersionConflict { .. } => StatusCode::CONFLICT,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::ChecksumMismatch | Self::InvalidSettings(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::PayloadTooLarge(_) | Self::QuotaExceeded | Self::KeyLimitExceeded { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Self::WriteLimitExceeded { .. } | Self::LockedOut { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Upstream(_) | Self::ProviderUnavailable { .. } => StatusCode::BAD_GATEWAY,
+            Self::DatabaseUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::InvalidKey(_) => "invalid_key",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::DatastoreDisabled => "datastore_disabled",
+            Self::NamespaceDisabled(_) => "namespace_disabled",
+            Self::Banned { .. } => "banned",
+            Self::NotFound => "not_found",
+            Self::Gone(_) => "gone",
+            Self::Deleted { .. } => "deleted",
+            Self::Conflict(_) => "conflict",
+            Self::PreconditionFailed => "precondition_failed",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::InvalidSettings(_) => "invalid_settings",
+            Self::VersionConflict { .. } => "version_conflict",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::KeyLimitExceeded { .. } => "key_limit_exceeded",
+            Self::WriteLimitExceeded { .. } => "write_limit_exceeded",
+            Self::LockedOut { .. } => "locked_out",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::Upstream(_) => "upstream_error",
+            Self::ProviderUnavailable { .. } => "provider_unavailable",
+            Self::DatabaseUnavailable => "database_unavailable",
+            Self::Timeout => "timeout",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::BadRequest(m)
+            | Self::Unauthorized(m)
+            | Self::Forbidden(m)
+            | Self::Gone(m)
+            | Self::Conflict(m)
+            | Self::InvalidSettings(m)
+            | Self::PayloadTooLarge(m)
+            | Self::UnsupportedMediaType(m)
+            | Self::Upstream(m) => m.clone(),
+            Self::InvalidKey(e) => e.message().to_string(),
+            Self::DatastoreDisabled => "DataStore sync is disabled".into(),
+            Self::NamespaceDisabled(prefix) => format!("Keys under {} are disabled", prefix),
+            Self::Banned { reason, .. } => format!("Banned: {}", reason),
+            Self::NotFound => "Not found".into(),
+            Self::Deleted { .. } => "The key was deleted".into(),
+            Self::PreconditionFailed => "The resource has changed".into(),
+            Self::ChecksumMismatch => "The body does not match its checksum".into(),
+            Self::VersionConflict { .. } => "The key has changed since it was read".into(),
+            Self::QuotaExceeded => "Total storage limit exceeded".into(),
+            Self::KeyLimitExceeded { limit } => format!("At most {} keys can be stored", limit),
+            Self::WriteLimitExceeded { .. } => "Daily write limit reached".into(),
+            Self::LockedOut { retry_after_secs } => format!(
+                "Too many failed sign-ins, try again in {}s",
+                retry_after_secs
+            ),
+            Self::ProviderUnavailable { .. } => {
+                "The identity provider is unavailable, try again later".into()
+            }
+            Self::DatabaseUnavailable => "The database is unavailable, try again later".into(),
+            Self::Timeout => "The request took too long".into(),
+            Self::Internal(m) => (*m).to_string(),
+        }
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::Unauthorized("Unauthorized".into())
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message())
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// JSON body of every error response.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+    #[schema(example = "not_found")]
+    pub code: &'static str,
+    /// On `version_conflict`, the version stored now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_version: Option<i64>,
+    /// On `version_conflict`, the checksum stored now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_checksum: Option<String>,
+    /// On `deleted`, when the key was deleted, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (current_version, current_checksum) = match &self {
+            Self::VersionConflict { version, checksum } => (*version, checksum.clone()),
+            _ => (None, None),
+        };
+        let deleted_at = match &self {
+            Self::Deleted { deleted_at } => Some(*deleted_at),
+            _ => None,
+        };
+        let body = ErrorBody {
+            error: self.message(),
+            code: self.code(),
+            current_version,
+            current_checksum,
+            deleted_at,
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        let retry_after_secs = match self {
+            Self::WriteLimitExceeded { retry_after_secs }
+            | Self::LockedOut { retry_after_secs }
+            | Self::ProviderUnavailable { retry_after_secs } => Some(retry_after_secs),
+            Self::Banned {
+                retry_after_secs, ..
+            } => retry_after_secs,
+            _ => None,
+        };
+        if let Some(secs) = retry_after_secs {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(e: anyhow::Error) -> Self {
+        error!("Internal error: {:#}", e);
+        Self::Internal("Internal server error")
+    }
+}
+
+impl From<ProviderError> for AppError {
+    fn from(e: ProviderError) -> Self {
+        match e {
+            ProviderError::Unavailable { retry_after_secs } => {
+                Self::ProviderUnavailable { retry_after_secs }
+            }
+            e => Self::Upstream(e.message().into()),
+        }
+    }
+}
+
+impl From<KeyValidationError> for AppError {
+    fn from(e: KeyValidationError) -> Self {
+        Self::InvalidKey(e)
+    }
+}
+
+/// Maps storage and other unexpected failures to `AppError::Internal`,
+/// logging the underlying error and exposing only `message` to the client.
+pub trait ResultExt<T> {
+    fn or_internal(self, message: &'static str) -> Result<T, AppError>;
+}
+
+impl<T, E: fmt::Display> ResultExt<T> for Result<T, E> {
+    fn or_internal(self, message: &'static str) -> Result<T, AppError> {
+        self.map_err(|e| {
+            error!("{}: {}", message, e);
+            AppError::Internal(message)
+        })
+    }
+}
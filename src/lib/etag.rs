@@ -0,0 +1,294 @@
+//! Entity tags and the `If-Match` / `If-None-Match` preconditions.
+//! `Last-Modified` dates and `If-Modified-Since` are handled here too.
+
+use axum::http::{HeaderMap, HeaderValue};
+use std::fmt;
+
+/// An entity tag as sent in an `ETag` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag {
+    tag: String,
+    weak: bool,
+}
+
+impl ETag {
+    pub fn strong(tag: impl Into<String>) -> Self {
+        Self {
+            tag: tag.into(),
+            weak: false,
+        }
+    }
+
+    pub fn weak(tag: impl Into<String>) -> Self {
+        Self {
+            tag: tag.into(),
+            weak: true,
+        }
+    }
+
+    /// The opaque tag without quotes or weakness prefix.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// Parses a single tag such as `"abc"` or `W/"abc"`. Bare tokens are
+    /// accepted as strong tags because older clients echo back the unquoted
+    /// values this server used to send.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (weak, rest) = match value.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+
+        let tag = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.strip_suffix('"')?,
+            None if !weak && !rest.is_empty() => rest,
+            None => return None,
+        };
+        if tag.contains('"') {
+            return None;
+        }
+
+        Some(Self {
+            tag: tag.to_string(),
+            weak,
+        })
+    }
+
+    /// Strong comparison: both tags are strong and identical.
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Weak comparison: the tags are identical, ignoring weakness.
+    pub fn weak_eq(&self, other: &Self) -> bool {
+        self.tag == other.tag
+    }
+
+    pub fn to_header(&self) -> Option<HeaderValue> {
+        HeaderValue::from_str(&self.to_string()).ok()
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/\"{}\"", self.tag)
+        } else {
+            write!(f, "\"{}\"", self.tag)
+        }
+    }
+}
+
+/// The value of an `If-Match` or `If-None-Match` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagList {
+    /// `*`, matching any current representation.
+    Any,
+    Tags(Vec<ETag>),
+}
+
+impl TagList {
+    /// Parses a comma-separated list of tags, or `*`. Unparseable entries are
+    /// skipped so one bad tag does not void the rest.
+    pub fn parse(value: &str) -> Self {
+        if value.trim() == "*" {
+            return Self::Any;
+        }
+        Self::Tags(value.split(',').filter_map(ETag::parse).collect())
+    }
+
+    /// Reads every occurrence of `name`, treating repeated headers as one list.
+    pub fn from_headers(headers: &HeaderMap, name: &str) -> Option<Self> {
+        let mut values = headers.get_all(name).iter().peekable();
+        values.peek()?;
+
+        let mut tags = Vec::new();
+        for value in values {
+            match Self::parse(value.to_str().unwrap_or("")) {
+                Self::Any => return Some(Self::Any),
+                Self::Tags(parsed) => tags.extend(parsed),
+            }
+        }
+        Some(Self::Tags(tags))
+    }
+}
+
+/// Whether an `If-None-Match` header says the client's copy of `current` is
+/// up to date, i.e. the request should get a 304. Uses weak comparison.
+pub fn not_modified(headers: &HeaderMap, current: &ETag) -> bool {
+    match TagList::from_headers(headers, "if-none-match") {
+        Some(TagList::Any) => true,
+        Some(TagList::Tags(tags)) => tags.iter().any(|t| t.weak_eq(current)),
+        None => false,
+    }
+}
+
+/// Whether a `GET` or `HEAD` should get a 304 for a resource tagged
+/// `current` and last written at `updated_at_ms`. `If-None-Match` decides
+/// when sent; otherwise `If-Modified-Since` does, to the second, as dates
+/// are. An unparseable date is ignored.
+pub fn fresh(headers: &HeaderMap, current: &ETag, updated_at_ms: i64) -> bool {
+    if headers.contains_key("if-none-match") {
+        return not_modified(headers, current);
+    }
+    let mut since = headers.get_all("if-modified-since").iter();
+    match (since.next(), since.next()) {
+        (Some(value), None) => value
+            .to_str()
+            .ok()
+            .and_then(parse_http_date)
+            .is_some_and(|since| updated_at_ms.div_euclid(1000) <= since),
+        _ => false,
+    }
+}
+
+/// Whether an `If-Match` header allows modifying a resource whose current tag
+/// is `current` (`None` if it does not exist). Uses strong comparison; a
+/// missing header always passes.
+pub fn precondition_holds(headers: &HeaderMap, current: Option<&ETag>) -> bool {
+    match TagList::from_headers(headers, "if-match") {
+        None => true,
+        Some(TagList::Any) => current.is_some(),
+        Some(TagList::Tags(tags)) => {
+            current.is_some_and(|current| tags.iter().any(|t| t.strong_eq(current)))
+        }
+    }
+}
+
+/// `Last-Modified` for a time in milliseconds, as an HTTP date.
+pub fn last_modified(updated_at_ms: i64) -> Option<HeaderValue> {
+    let time = chrono::DateTime::from_timestamp_millis(updated_at_ms)?;
+    HeaderValue::from_str(&time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()).ok()
+}
+
+/// Seconds since the epoch of an HTTP date such as
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn parse_http_date(value: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|time| time.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    #[test]
+    fn test_parse_and_format() {
+        assert_eq!(ETag::parse("\"abc\""), Some(ETag::strong("abc")));
+        assert_eq!(ETag::parse(" W/\"abc\" "), Some(ETag::weak("abc")));
+        assert_eq!(ETag::parse("abc"), Some(ETag::strong("abc")));
+        assert_eq!(ETag::parse("\"\""), Some(ETag::strong("")));
+        assert_eq!(ETag::parse("W/abc"), None);
+        assert_eq!(ETag::parse("\"abc"), None);
+        assert_eq!(ETag::parse(""), None);
+
+        assert_eq!(ETag::strong("abc").to_string(), "\"abc\"");
+        assert_eq!(ETag::weak("abc").to_string(), "W/\"abc\"");
+    }
+
+    #[test]
+    fn test_tag_list() {
+        assert_eq!(TagList::parse(" * "), TagList::Any);
+        assert_eq!(
+            TagList::parse("\"a\", W/\"b\",,\"c"),
+            TagList::Tags(vec![ETag::strong("a"), ETag::weak("b")])
+        );
+        assert_eq!(TagList::from_headers(&HeaderMap::new(), "if-match"), None);
+        assert_eq!(
+            TagList::from_headers(
+                &headers(&[("if-match", "\"a\""), ("if-match", "\"b\"")]),
+                "if-match"
+            ),
+            Some(TagList::Tags(vec![ETag::strong("a"), ETag::strong("b")]))
+        );
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let current = ETag::strong("abc");
+        assert!(!not_modified(&HeaderMap::new(), &current));
+        assert!(not_modified(&headers(&[("if-none-match", "*")]), &current));
+        assert!(not_modified(
+            &headers(&[("if-none-match", "\"x\", W/\"abc\"")]),
+            &current
+        ));
+        assert!(not_modified(
+            &headers(&[("if-none-match", "abc")]),
+            &current
+        ));
+        assert!(!not_modified(
+            &headers(&[("if-none-match", "\"x\", \"y\"")]),
+            &current
+        ));
+    }
+
+    #[test]
+    fn test_precondition_holds() {
+        let current = ETag::strong("abc");
+        assert!(precondition_holds(&HeaderMap::new(), None));
+        assert!(precondition_holds(
+            &headers(&[("if-match", "*")]),
+            Some(&current)
+        ));
+        assert!(!precondition_holds(&headers(&[("if-match", "*")]), None));
+        assert!(precondition_holds(
+            &headers(&[("if-match", "\"x\", \"abc\"")]),
+            Some(&current)
+        ));
+        assert!(!precondition_holds(
+            &headers(&[("if-match", "W/\"abc\"")]),
+            Some(&current)
+        ));
+        assert!(!precondition_holds(
+            &headers(&[("if-match", "\"abc\"")]),
+            None
+        ));
+    }
+
+    #[test]
+    fn test_fresh() {
+        let current = ETag::strong("abc");
+        let written = 784_111_777_123;
+        let since = |date| fresh(&headers(&[("if-modified-since", date)]), &current, written);
+        assert!(!fresh(&HeaderMap::new(), &current, written));
+        assert!(since("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(since("Sun, 06 Nov 1994 09:00:00 GMT"));
+        assert!(!since("Sun, 06 Nov 1994 08:49:36 GMT"));
+        assert!(!since("yesterday"));
+
+        // If-None-Match takes precedence over the date.
+        let both = headers(&[
+            ("if-none-match", "\"x\""),
+            ("if-modified-since", "Sun, 06 Nov 1994 09:00:00 GMT"),
+        ]);
+        assert!(!fresh(&both, &current, written));
+        let both = headers(&[
+            ("if-none-match", "\"abc\""),
+            ("if-modified-since", "Sun, 06 Nov 1994 08:00:00 GMT"),
+        ]);
+        assert!(fresh(&both, &current, written));
+    }
+
+    #[test]
+    fn test_last_modified() {
+        assert_eq!(
+            last_modified(784_111_777_123).unwrap(),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+    }
+}
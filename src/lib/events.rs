@@ -0,0 +1,93 @@
+//! In-process broadcast bus for account and data changes. Publishing never
+//! blocks or fails; events are dropped when nobody is subscribed and slow
+//! subscribers observe a `Lagged` error instead of stalling writers.
+
+use tokio::sync::broadcast;
+
+use crate::constants::EVENT_BUS_CAPACITY;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    DataWritten {
+        user_id: String,
+        key: String,
+        version: i64,
+    },
+    DataDeleted {
+        user_id: String,
+        key: String,
+        version: i64,
+    },
+    SettingsWritten {
+        user_id: String,
+        written: i64,
+    },
+    SettingsDeleted {
+        user_id: String,
+    },
+    /// First sign-in of a user with nothing stored yet.
+    UserSignedUp {
+        user_id: String,
+    },
+    /// A write was refused because it would exceed the user's storage quota.
+    QuotaExceeded {
+        user_id: String,
+    },
+    AccountDeleted {
+        user_id: String,
+    },
+    /// Inactive accounts purged by the retention sweeper, which only knows
+    /// hashed ids.
+    AccountsExpired {
+        count: u64,
+    },
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_reaches_subscribers() {
+        let bus = EventBus::new();
+        bus.publish(Event::SettingsDeleted {
+            user_id: "dropped".into(),
+        });
+
+        let mut receiver = bus.subscribe();
+        bus.publish(Event::SettingsDeleted {
+            user_id: "123".into(),
+        });
+
+        match receiver.recv().await.unwrap() {
+            Event::SettingsDeleted { user_id } => assert_eq!(user_id, "123"),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+}
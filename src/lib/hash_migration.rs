@@ -1,4 +1,11 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::constants::{DEFAULT_LEGACY_SCAN_PAGE_SIZE, DEFAULT_LEGACY_SCAN_PROGRESS_EVERY};
+use crate::database::{DatabaseService, LegacyCleanupReport};
 
 /// CRC32-based hash functions (kept for migration compatibility)
 pub mod legacy {
@@ -44,6 +51,164 @@ pub fn is_legacy_key(key: &str) -> bool {
     }
 }
 
+/// The table a legacy scan is reading: settings rows first, then the users
+/// with v2 data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanPhase {
+    Users,
+    Data,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Delete the legacy entries found rather than only counting them.
+    pub delete: bool,
+    pub page_size: i32,
+    /// Log progress every this many rows; 0 turns progress reports off.
+    pub progress_every: u64,
+    /// File the scan position is saved to after every page, so an
+    /// interrupted scan picks up where it stopped. Removed once the scan
+    /// completes.
+    pub checkpoint: Option<PathBuf>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            delete: false,
+            page_size: DEFAULT_LEGACY_SCAN_PAGE_SIZE,
+            progress_every: DEFAULT_LEGACY_SCAN_PROGRESS_EVERY,
+            checkpoint: None,
+        }
+    }
+}
+
+/// Where an interrupted scan stopped, and what it had counted by then.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanCheckpoint {
+    pub phase: ScanPhase,
+    /// Hex-encoded driver paging state of the next page; `None` at the
+    /// start of `phase`.
+    pub cursor: Option<String>,
+    pub report: LegacyCleanupReport,
+}
+
+impl Default for ScanCheckpoint {
+    fn default() -> Self {
+        Self {
+            phase: ScanPhase::Users,
+            cursor: None,
+            report: LegacyCleanupReport::default(),
+        }
+    }
+}
+
+impl ScanCheckpoint {
+    /// Reads the checkpoint at `path`, or `None` if there is none.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .with_context(|| format!("Checkpoint {} is not valid", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Writes the checkpoint to `path`, replacing the previous one only
+    /// once it is complete.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        std::fs::write(&partial, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+/// Scans the users and data tables page by page for entries keyed by the
+/// legacy CRC32 hash, deleting them if `options.delete` is set.
+pub async fn scan_legacy_users(
+    db: &DatabaseService,
+    options: &ScanOptions,
+) -> Result<LegacyCleanupReport> {
+    let checkpoint_path = options.checkpoint.as_deref();
+    let mut checkpoint = match checkpoint_path {
+        Some(path) => ScanCheckpoint::load(path)?.unwrap_or_default(),
+        None => ScanCheckpoint::default(),
+    };
+    if checkpoint_path.is_some() && (checkpoint.cursor.is_some() || checkpoint.report.total > 0) {
+        info!(
+            "Resuming {:?} scan after {} rows",
+            checkpoint.phase,
+            checkpoint.report.total + checkpoint.report.data_users
+        );
+    }
+
+    loop {
+        let cursor = checkpoint
+            .cursor
+            .as_deref()
+            .map(hex::decode)
+            .transpose()
+            .context("Checkpoint cursor is not valid hex")?;
+        let (ids, next) = db
+            .user_id_page(checkpoint.phase, options.page_size, cursor)
+            .await?;
+
+        let report = &mut checkpoint.report;
+        for id in ids {
+            let (scanned, legacy) = match checkpoint.phase {
+                ScanPhase::Users => (&mut report.total, &mut report.legacy),
+                ScanPhase::Data => (&mut report.data_users, &mut report.legacy_data_users),
+            };
+            *scanned += 1;
+            let scanned = *scanned;
+
+            if is_legacy_key(&id) {
+                *legacy += 1;
+                if options.delete {
+                    match checkpoint.phase {
+                        ScanPhase::Users => match db.delete_legacy_data(&id).await {
+                            Ok(()) => report.deleted += 1,
+                            Err(e) => warn!("Failed to delete legacy entry {}: {}", id, e),
+                        },
+                        ScanPhase::Data => match db.delete_all_data_by_hash(&id).await {
+                            Ok(_) => report.deleted_data_users += 1,
+                            Err(e) => warn!("Failed to delete legacy data of {}: {}", id, e),
+                        },
+                    }
+                }
+            }
+
+            if options.progress_every > 0 && scanned % options.progress_every == 0 {
+                info!("Scanned {} {:?} rows", scanned, checkpoint.phase);
+            }
+        }
+
+        checkpoint.cursor = next.map(hex::encode);
+        if checkpoint.cursor.is_none() {
+            match checkpoint.phase {
+                ScanPhase::Users => checkpoint.phase = ScanPhase::Data,
+                ScanPhase::Data => break,
+            }
+        }
+        if let Some(path) = checkpoint_path {
+            checkpoint.save(path)?;
+        }
+    }
+
+    if let Some(path) = checkpoint_path
+        && let Err(e) = std::fs::remove_file(path)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove checkpoint {}: {}", path.display(), e);
+    }
+    Ok(checkpoint.report)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,4 +236,25 @@ mod tests {
         assert!(is_legacy_key(&legacy_key), "Legacy key should be detected");
         assert!(!is_legacy_key(&new_key), "New key should not be legacy");
     }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("equicloud-scan-{}.json", uuid::Uuid::new_v4()));
+        assert!(ScanCheckpoint::load(&path).unwrap().is_none());
+
+        let mut checkpoint = ScanCheckpoint {
+            phase: ScanPhase::Data,
+            cursor: Some(hex::encode([1, 2, 3])),
+            ..Default::default()
+        };
+        checkpoint.report.total = 5;
+        checkpoint.save(&path).unwrap();
+
+        let loaded = ScanCheckpoint::load(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.phase, ScanPhase::Data);
+        assert_eq!(loaded.cursor.as_deref(), Some("010203"));
+        assert_eq!(loaded.report.total, 5);
+    }
 }
@@ -0,0 +1,250 @@
+//! Checksum verification of stored values.
+//!
+//! Checksums are checked on upload, but a value can still rot afterwards in
+//! the database or the blob store. Reads recompute the checksum of what they
+//! are about to serve and report the result in `X-Checksum-Status`; values
+//! that no longer match are logged, counted and recorded. The scrubber in
+//! [`crate::scrub`] does the same for every value every `SCRUB_INTERVAL`, so
+//! corruption is found before a client asks for it. Values streamed from chunks are checked as
+//! they are sent, and cut off at the end if they do not match.
+
+use axum::http::HeaderValue;
+use axum::response::{IntoResponseParts, ResponseParts};
+use futures::StreamExt;
+use futures::stream;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+use crate::blob_store::ChunkStream;
+use crate::checksum::StreamingChecksum;
+use crate::database::{CorruptEntry, DataEntry, find_corruption};
+use crate::datastore::{Datastore, Storage};
+use crate::metrics::Metrics;
+use crate::utils::hash_user_id;
+
+pub const CHECKSUM_STATUS_HEADER: &str = "x-checksum-status";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    /// Every value served matched its checksum.
+    Verified,
+    /// At least one value did not.
+    Mismatch,
+}
+
+impl ChecksumStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Verified => "verified",
+            Self::Mismatch => "mismatch",
+        }
+    }
+}
+
+impl IntoResponseParts for ChecksumStatus {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.headers_mut().insert(
+            CHECKSUM_STATUS_HEADER,
+            HeaderValue::from_static(self.as_str()),
+        );
+        Ok(res)
+    }
+}
+
+/// Recomputes the checksum of a value read for `user_id`. A mismatch is
+/// logged, counted and recorded; failing to record it only logs.
+pub async fn verify(
+    db: &Storage,
+    metrics: &Metrics,
+    user_id: &str,
+    entry: &DataEntry,
+) -> ChecksumStatus {
+    let Some(corrupt) = find_corruption(entry) else {
+        return ChecksumStatus::Verified;
+    };
+    report(db, metrics, user_id, &corrupt).await;
+    ChecksumStatus::Mismatch
+}
+
+async fn report(db: &Storage, metrics: &Metrics, user_id: &str, corrupt: &CorruptEntry) {
+    metrics.record_checksum_mismatch();
+    error!(
+        "Checksum mismatch on read for user {} (version {}): stored {}, computed {}",
+        &hash_user_id(user_id)[..16],
+        corrupt.version,
+        corrupt.stored_checksum,
+        corrupt.computed_checksum
+    );
+    if let Err(e) = db.record_corruption(user_id, corrupt).await {
+        warn!("Failed to record corrupt value: {}", e);
+    }
+}
+
+struct StreamCheck {
+    chunks: ChunkStream,
+    checksum: StreamingChecksum,
+    db: Storage,
+    metrics: Arc<Metrics>,
+    user_id: String,
+    entry: DataEntry,
+}
+
+/// Passes `chunks` of the value described by `entry` through while
+/// computing its checksum. A mismatch is handled as [`verify`] handles one,
+/// and ends the stream with an error instead of its last piece, so the
+/// response is cut off rather than completed.
+pub fn verify_stream(
+    db: Storage,
+    metrics: Arc<Metrics>,
+    user_id: String,
+    entry: DataEntry,
+    chunks: ChunkStream,
+) -> ChunkStream {
+    let check = StreamCheck {
+        chunks,
+        checksum: StreamingChecksum::for_checksum(&entry.checksum),
+        db,
+        metrics,
+        user_id,
+        entry,
+    };
+    stream::unfold(Some(check), |check| async move {
+        let mut check = check?;
+        match check.chunks.next().await {
+            Some(Ok(chunk)) => {
+                check.checksum.update(&chunk);
+                Some((Ok(chunk), Some(check)))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None => {
+                let computed = check.checksum.finish();
+                if computed == check.entry.checksum {
+                    return None;
+                }
+                let corrupt = CorruptEntry {
+                    key: check.entry.key,
+                    version: check.entry.version,
+                    stored_checksum: check.entry.checksum,
+                    computed_checksum: computed,
+                    detected_at: chrono::Utc::now().timestamp_millis(),
+                };
+                report(&check.db, &check.metrics, &check.user_id, &corrupt).await;
+                Some((Err(anyhow::anyhow!("Checksum mismatch")), None))
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Verifies several values, splitting them into the ones that match their
+/// checksum and the keys of the ones that do not.
+pub async fn verify_all(
+    db: &Storage,
+    metrics: &Metrics,
+    user_id: &str,
+    entries: Vec<DataEntry>,
+) -> (Vec<DataEntry>, Vec<String>) {
+    let mut verified = Vec::with_capacity(entries.len());
+    let mut corrupt = Vec::new();
+    for entry in entries {
+        match verify(db, metrics, user_id, &entry).await {
+            ChecksumStatus::Verified => verified.push(entry),
+            ChecksumStatus::Mismatch => corrupt.push(entry.key),
+        }
+    }
+    (verified, corrupt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SqliteDatastore;
+    use crate::utils::compute_checksum;
+
+    fn entry(value: &[u8], checksum: String) -> DataEntry {
+        DataEntry {
+            key: "plugins/a".into(),
+            value: value.to_vec(),
+            version: 3,
+            checksum,
+            size_bytes: value.len() as i32,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_records_mismatches() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let metrics = Metrics::new();
+
+        let intact = entry(b"value", compute_checksum(b"value"));
+        assert_eq!(
+            verify(&db, &metrics, "1", &intact).await,
+            ChecksumStatus::Verified
+        );
+        assert!(db.list_corruption("1").await.unwrap().is_empty());
+
+        let rotten = entry(b"valuf", compute_checksum(b"value"));
+        let (verified, corrupt) = verify_all(&db, &metrics, "1", vec![intact, rotten]).await;
+        assert_eq!(verified.len(), 1);
+        assert_eq!(corrupt, ["plugins/a"]);
+        assert_eq!(metrics.checksum_mismatches(), 1);
+
+        let recorded = db.list_corruption("1").await.unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].version, 3);
+        assert_eq!(recorded[0].stored_checksum, compute_checksum(b"value"));
+        assert_eq!(recorded[0].computed_checksum, compute_checksum(b"valuf"));
+
+        db.purge_account("1").await.unwrap();
+        assert!(db.list_corruption("1").await.unwrap().is_empty());
+    }
+
+    async fn drain(chunks: ChunkStream) -> (Vec<u8>, bool) {
+        let mut value = Vec::new();
+        let mut failed = false;
+        let mut chunks = chunks;
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => value.extend_from_slice(&chunk),
+                Err(_) => failed = true,
+            }
+        }
+        (value, failed)
+    }
+
+    #[tokio::test]
+    async fn test_verify_stream_cuts_off_mismatches() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let metrics = Arc::new(Metrics::new());
+        let chunks = |pieces: [&'static [u8]; 2]| -> ChunkStream {
+            stream::iter(pieces.map(|p| Ok(bytes::Bytes::from_static(p)))).boxed()
+        };
+
+        let mut meta = entry(b"", compute_checksum(b"value"));
+        let intact = verify_stream(
+            db.clone(),
+            Arc::clone(&metrics),
+            "1".into(),
+            meta.clone(),
+            chunks([b"val", b"ue"]),
+        );
+        assert_eq!(drain(intact).await, (b"value".to_vec(), false));
+
+        meta.key = "plugins/b".into();
+        let rotten = verify_stream(
+            db.clone(),
+            Arc::clone(&metrics),
+            "1".into(),
+            meta,
+            chunks([b"val", b"uf"]),
+        );
+        assert_eq!(drain(rotten).await, (b"valuf".to_vec(), true));
+        assert_eq!(metrics.checksum_mismatches(), 1);
+        assert_eq!(db.list_corruption("1").await.unwrap()[0].key, "plugins/b");
+    }
+}
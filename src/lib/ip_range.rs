@@ -0,0 +1,109 @@
+//! IP addresses and CIDR ranges, for allowlists read from the environment.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address, or a network written `address/prefix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Clients on a dual-stack socket show up as IPv4-mapped IPv6.
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{:?} is not an IP address", addr))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("{:?} is not a prefix length up to {}", prefix, max))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Parses a comma-separated list of ranges; `None` if any is invalid.
+pub fn parse_ip_ranges(s: &str) -> Option<Vec<IpRange>> {
+    s.split(',')
+        .filter(|range| !range.trim().is_empty())
+        .map(|range| range.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_contains() {
+        let ranges = parse_ip_ranges("10.0.0.0/8, 192.168.1.5, fd00::/8").unwrap();
+        let allowed = |addr: &str| ranges.iter().any(|r| r.contains(ip(addr)));
+
+        assert!(allowed("10.1.2.3"));
+        assert!(allowed("::ffff:10.1.2.3"));
+        assert!(allowed("192.168.1.5"));
+        assert!(!allowed("192.168.1.6"));
+        assert!(allowed("fd12::1"));
+        assert!(!allowed("11.0.0.1"));
+        assert!(!allowed("fe80::1"));
+
+        assert!(
+            "0.0.0.0/0"
+                .parse::<IpRange>()
+                .unwrap()
+                .contains(ip("8.8.8.8"))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_ip_ranges("10.0.0.0/33").is_none());
+        assert!(parse_ip_ranges("localhost").is_none());
+        assert_eq!(parse_ip_ranges(""), Some(Vec::new()));
+        assert_eq!(
+            "10.0.0.1".parse::<IpRange>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+    }
+}
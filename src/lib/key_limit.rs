@@ -0,0 +1,92 @@
+//! Caps on the number of keys per user.
+//!
+//! `MAX_KEYS_PER_USER` limits the live data keys one user may store, so a
+//! client cannot bloat its manifest with millions of tiny keys. Only writes
+//! that create a key count against it; overwriting a key never does, and
+//! deleting keys makes room again.
+
+use crate::datastore::Datastore;
+use crate::error::{AppError, ResultExt};
+use crate::tenant::Tenant;
+
+/// The keys a user may still create within one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyAllowance {
+    limit: u64,
+    remaining: u64,
+}
+
+impl KeyAllowance {
+    /// The allowance of a user storing `live_keys` under `limit`, zero for
+    /// no limit.
+    pub fn new(limit: u64, live_keys: u64) -> Self {
+        Self {
+            limit,
+            remaining: limit.saturating_sub(live_keys),
+        }
+    }
+
+    /// Counts one key that does not exist yet, failing with
+    /// `KeyLimitExceeded` once the allowance is used up.
+    pub fn take(&mut self) -> Result<(), AppError> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+        if self.remaining == 0 {
+            return Err(AppError::KeyLimitExceeded { limit: self.limit });
+        }
+        self.remaining -= 1;
+        Ok(())
+    }
+}
+
+/// Fails with `KeyLimitExceeded` when writing `key` would create a key
+/// beyond the user's limit. The key is only looked up once the user is at
+/// the limit.
+pub async fn check_new_key(tenant: &Tenant, user_id: &str, key: &str) -> Result<(), AppError> {
+    let limit = tenant.config.load().max_keys_per_user;
+    if limit == 0 {
+        return Ok(());
+    }
+    let usage = tenant
+        .db
+        .get_storage_usage(user_id)
+        .await
+        .or_internal("Failed to count keys")?;
+    if (usage.data_keys as u64) < limit {
+        return Ok(());
+    }
+    let exists = tenant
+        .db
+        .get_data_meta(user_id, key)
+        .await
+        .or_internal("Failed to get data")?
+        .is_some_and(|meta| !meta.deleted);
+    if exists {
+        Ok(())
+    } else {
+        Err(AppError::KeyLimitExceeded { limit })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowance() {
+        let mut unlimited = KeyAllowance::new(0, 1000);
+        assert!(unlimited.take().is_ok());
+
+        let mut allowance = KeyAllowance::new(3, 1);
+        assert!(allowance.take().is_ok());
+        assert!(allowance.take().is_ok());
+        assert!(matches!(
+            allowance.take(),
+            Err(AppError::KeyLimitExceeded { limit: 3 })
+        ));
+
+        let mut over = KeyAllowance::new(3, 5);
+        assert!(over.take().is_err());
+    }
+}
@@ -0,0 +1,129 @@
+//! Serializes writes to the same data key within one process.
+//!
+//! Writes read a key's version and store the next one. Two of them running
+//! at once would both read the same version and write the same next one,
+//! the later silently replacing the earlier. [`KeyLocks`] makes such writes
+//! take turns per user and key. Writes from other instances are not
+//! covered: conditional writes are lightweight transactions for that.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+type LockMap = HashMap<(String, String), Arc<AsyncMutex<()>>>;
+
+/// One lock per (user hash, key) being written. Locks are created on first
+/// use and dropped once nobody holds or waits for them.
+#[derive(Default)]
+pub struct KeyLocks {
+    locks: Arc<Mutex<LockMap>>,
+}
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits until no other write to `key` of `user_hash` is in progress.
+    pub async fn lock(&self, user_hash: &str, key: &str) -> KeyGuard {
+        let entry = (user_hash.to_string(), key.to_string());
+        let lock = Arc::clone(
+            self.locks
+                .lock()
+                .expect("key lock map poisoned")
+                .entry(entry.clone())
+                .or_default(),
+        );
+        KeyGuard {
+            guard: Some(lock.lock_owned().await),
+            locks: Arc::clone(&self.locks),
+            entry,
+        }
+    }
+
+    /// [`Self::lock`] for several keys, taken in sorted order so writers
+    /// with overlapping keys cannot deadlock.
+    pub async fn lock_all<'a>(
+        &self,
+        user_hash: &str,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<KeyGuard> {
+        let mut keys: Vec<&str> = keys.into_iter().collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let mut guards = Vec::with_capacity(keys.len());
+        for key in keys {
+            guards.push(self.lock(user_hash, key).await);
+        }
+        guards
+    }
+
+    /// Keys with a lock held or waited for.
+    pub fn len(&self) -> usize {
+        self.locks.lock().expect("key lock map poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Held while a key is written; the next writer goes once it is dropped.
+pub struct KeyGuard {
+    guard: Option<OwnedMutexGuard<()>>,
+    locks: Arc<Mutex<LockMap>>,
+    entry: (String, String),
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let mut locks = self.locks.lock().expect("key lock map poisoned");
+        // Waiters cloned the lock while holding the map, so a count of one
+        // means the map's own reference is the last.
+        if locks
+            .get(&self.entry)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_writers_take_turns() {
+        let locks = Arc::new(KeyLocks::new());
+        let version = Arc::new(Mutex::new(0));
+        let writers = (0..8).map(|_| {
+            let locks = Arc::clone(&locks);
+            let version = Arc::clone(&version);
+            tokio::spawn(async move {
+                let _guard = locks.lock("user", "key").await;
+                let read = *version.lock().unwrap();
+                tokio::time::sleep(Duration::from_millis(2)).await;
+                *version.lock().unwrap() = read + 1;
+            })
+        });
+        for writer in writers.collect::<Vec<_>>() {
+            writer.await.unwrap();
+        }
+        assert_eq!(*version.lock().unwrap(), 8);
+        assert!(locks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_other_keys_are_not_blocked() {
+        let locks = KeyLocks::new();
+        let _held = locks.lock_all("user", ["b", "a", "b"]).await;
+        assert_eq!(locks.len(), 2);
+        let other = tokio::time::timeout(Duration::from_secs(1), locks.lock("user", "c")).await;
+        assert!(other.is_ok());
+        let same = tokio::time::timeout(Duration::from_millis(20), locks.lock("user", "a")).await;
+        assert!(same.is_err());
+    }
+}
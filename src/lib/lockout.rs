@@ -0,0 +1,278 @@
+//! Lockouts after repeated failed sign-ins.
+//!
+//! A request whose token or API key is refused counts as a failure against
+//! the user the token names from the client address, the API key and the
+//! address itself. Once a user or key has `AUTH_LOCKOUT_MAX_FAILURES`, or an
+//! address `AUTH_LOCKOUT_IP_MAX_FAILURES`, it is locked out: sign-ins are
+//! refused with 429 for `AUTH_LOCKOUT_DURATION`, even with the right secret.
+//! Every further failure doubles that, up to `AUTH_LOCKOUT_MAX_DURATION`.
+//! User ids are public, so a user is only locked out from the addresses the
+//! failures came from; anyone else guessing gets their own address locked
+//! out instead. Failures are forgotten after `AUTH_LOCKOUT_WINDOW` without
+//! one, and a successful sign-in forgets the user's or key's. They are
+//! counted in memory, per instance; with `AUTH_LOCKOUT_PERSIST` lockouts are
+//! stored as well, so every instance honours them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::database::AuthLockout;
+use crate::datastore::Datastore;
+use crate::error::AppError;
+use crate::tenant::Tenant;
+use crate::utils::{Config, hash_user_id};
+
+/// What failed sign-ins are counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    /// Hashed user id named by a token, and the address the token came
+    /// from, if known.
+    User(String, Option<IpAddr>),
+    /// Id of an API key.
+    ApiKey(String),
+    Ip(IpAddr),
+}
+
+impl Subject {
+    pub fn user(user_id: &str, ip: Option<IpAddr>) -> Self {
+        Self::User(hash_user_id(user_id), ip)
+    }
+
+    /// Identifies the subject in storage.
+    pub fn key(&self) -> String {
+        match self {
+            Self::User(hash, None) => format!("user:{}", hash),
+            Self::User(hash, Some(ip)) => format!("user:{}@{}", hash, ip),
+            Self::ApiKey(id) => format!("key:{}", id),
+            Self::Ip(ip) => format!("ip:{}", ip),
+        }
+    }
+}
+
+/// The lockout settings of a tenant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    pub max_failures: u32,
+    pub ip_max_failures: u32,
+    pub window: Duration,
+    pub duration: Duration,
+    pub max_duration: Duration,
+}
+
+impl LockoutPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_failures: config.auth_lockout_max_failures,
+            ip_max_failures: config.auth_lockout_ip_max_failures,
+            window: config.auth_lockout_window,
+            duration: config.auth_lockout_duration,
+            max_duration: config.auth_lockout_max_duration,
+        }
+    }
+
+    /// Failures after which `subject` is locked out; zero never locks it.
+    fn threshold(&self, subject: &Subject) -> u32 {
+        match subject {
+            Subject::User(..) | Subject::ApiKey(_) => self.max_failures,
+            Subject::Ip(_) => self.ip_max_failures,
+        }
+    }
+
+    /// How long `failures` lock a subject out once they reach `threshold`.
+    fn lock_duration(&self, failures: u32, threshold: u32) -> Duration {
+        let doublings = (failures - threshold).min(31);
+        self.duration
+            .saturating_mul(1 << doublings)
+            .min(self.max_duration)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Failures {
+    count: u32,
+    /// Milliseconds.
+    last_failure: i64,
+    /// Milliseconds; zero when never locked out.
+    locked_until: i64,
+}
+
+impl Failures {
+    /// Whether the failures have been forgotten at `now`.
+    fn expired(&self, now: i64, window: Duration) -> bool {
+        now - self.last_failure.max(self.locked_until) >= window.as_millis() as i64
+    }
+}
+
+/// Recent failed sign-ins per subject, for one tenant.
+#[derive(Default)]
+pub struct AuthLockouts {
+    failures: Mutex<HashMap<Subject, Failures>>,
+}
+
+impl AuthLockouts {
+    /// When the lockout of `subject` in force at `now` ends, if there is one.
+    fn locked_until(&self, subject: &Subject, now: i64) -> Option<i64> {
+        let failures = self.failures.lock().unwrap();
+        failures
+            .get(subject)
+            .map(|failures| failures.locked_until)
+            .filter(|until| *until > now)
+    }
+
+    /// Remembers a lockout of `subject` found in storage.
+    fn lock(&self, subject: &Subject, lockout: &AuthLockout) {
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(subject.clone()).or_default();
+        entry.count = entry.count.max(lockout.failures);
+        entry.locked_until = entry.locked_until.max(lockout.locked_until);
+    }
+
+    /// Counts a failure of `subject` at `now`. Returns the lockout it
+    /// starts or extends, if it reaches the policy's threshold.
+    fn fail(&self, subject: &Subject, now: i64, policy: &LockoutPolicy) -> Option<AuthLockout> {
+        let threshold = policy.threshold(subject);
+        if threshold == 0 {
+            return None;
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, failures| !failures.expired(now, policy.window));
+        let entry = failures.entry(subject.clone()).or_default();
+        entry.count = entry.count.saturating_add(1);
+        entry.last_failure = now;
+        if entry.count < threshold {
+            return None;
+        }
+
+        let duration = policy.lock_duration(entry.count, threshold);
+        entry.locked_until = now + duration.as_millis() as i64;
+        Some(AuthLockout {
+            subject: subject.key(),
+            locked_until: entry.locked_until,
+            failures: entry.count,
+        })
+    }
+
+    fn clear(&self, subject: &Subject) {
+        self.failures.lock().unwrap().remove(subject);
+    }
+}
+
+/// Fails with `LockedOut` while any of `subjects` is locked out. With
+/// `AUTH_LOCKOUT_PERSIST`, lockouts placed by other instances count too;
+/// failing to look them up does not refuse the sign-in.
+pub async fn check(tenant: &Tenant, subjects: &[Subject]) -> Result<(), AppError> {
+    let persist = tenant.config.load().auth_lockout_persist;
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut until = None;
+    for subject in subjects {
+        let mut locked = tenant.lockouts.locked_until(subject, now);
+        if locked.is_none() && persist {
+            match tenant.db.get_auth_lockout(&subject.key()).await {
+                Ok(Some(lockout)) => {
+                    tenant.lockouts.lock(subject, &lockout);
+                    locked = Some(lockout.locked_until).filter(|until| *until > now);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to look up sign-in lockout: {}", e),
+            }
+        }
+        until = until.max(locked);
+    }
+
+    match until {
+        Some(until) => Err(AppError::LockedOut {
+            retry_after_secs: ((until - now + 999) / 1000).max(1) as u64,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Counts a failed sign-in against each of `subjects`, locking out those
+/// that reach their threshold.
+pub async fn record_failure(tenant: &Tenant, subjects: &[Subject]) {
+    let config = tenant.config.load();
+    let policy = LockoutPolicy::from_config(&config);
+    let now = chrono::Utc::now().timestamp_millis();
+    for subject in subjects {
+        let Some(lockout) = tenant.lockouts.fail(subject, now, &policy) else {
+            continue;
+        };
+        warn!(
+            "Locked out {} after {} failed sign-ins",
+            match subject {
+                Subject::User(hash, None) => format!("user {}", &hash[..16]),
+                Subject::User(hash, Some(ip)) => format!("user {} from {}", &hash[..16], ip),
+                Subject::ApiKey(id) => format!("API key {}", id),
+                Subject::Ip(ip) => format!("address {}", ip),
+            },
+            lockout.failures
+        );
+        if config.auth_lockout_persist
+            && let Err(e) = tenant.db.save_auth_lockout(&lockout).await
+        {
+            warn!("Failed to store sign-in lockout: {}", e);
+        }
+    }
+}
+
+/// Forgets the failures of the users and API keys among `subjects` after
+/// they signed in. Addresses keep theirs, so signing in to one account does
+/// not make up for guessing at others.
+pub fn record_success(tenant: &Tenant, subjects: &[Subject]) {
+    for subject in subjects {
+        if !matches!(subject, Subject::Ip(_)) {
+            tenant.lockouts.clear(subject);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: LockoutPolicy = LockoutPolicy {
+        max_failures: 3,
+        ip_max_failures: 0,
+        window: Duration::from_secs(60),
+        duration: Duration::from_secs(10),
+        max_duration: Duration::from_secs(30),
+    };
+
+    #[test]
+    fn test_lockout_backs_off() {
+        let lockouts = AuthLockouts::default();
+        let user = Subject::user("1", None);
+
+        assert!(lockouts.fail(&user, 0, &POLICY).is_none());
+        assert!(lockouts.fail(&user, 1_000, &POLICY).is_none());
+        let first = lockouts.fail(&user, 2_000, &POLICY).unwrap();
+        assert_eq!((first.locked_until, first.failures), (12_000, 3));
+        assert_eq!(lockouts.locked_until(&user, 11_999), Some(12_000));
+        assert_eq!(lockouts.locked_until(&user, 12_000), None);
+
+        // Failures after the lockout lock out for longer, up to the maximum.
+        let second = lockouts.fail(&user, 13_000, &POLICY).unwrap();
+        assert_eq!(second.locked_until, 33_000);
+        let third = lockouts.fail(&user, 34_000, &POLICY).unwrap();
+        assert_eq!(third.locked_until, 64_000);
+
+        // A window after the lockout ends the count starts over.
+        assert!(lockouts.fail(&user, 124_000, &POLICY).is_none());
+        lockouts.clear(&user);
+        assert!(lockouts.failures.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_disabled_subjects_are_not_counted() {
+        let lockouts = AuthLockouts::default();
+        let ip = Subject::Ip("192.0.2.1".parse().unwrap());
+        for at in 0..10 {
+            assert!(lockouts.fail(&ip, at, &POLICY).is_none());
+        }
+        assert!(lockouts.failures.lock().unwrap().is_empty());
+    }
+}
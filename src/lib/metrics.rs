@@ -0,0 +1,303 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::auth_events::AuthOutcome;
+use crate::compaction::CompactionStats;
+use crate::database::ScrubStats;
+
+/// Process-wide metrics shared through the application state.
+pub struct Metrics {
+    started_at: Instant,
+    retention_accounts_marked: AtomicU64,
+    retention_accounts_purged: AtomicU64,
+    retention_bytes_reclaimed: AtomicU64,
+    history_versions_removed: AtomicU64,
+    history_bytes_reclaimed: AtomicU64,
+    checksum_mismatches: AtomicU64,
+    scrub_values_checked: AtomicU64,
+    scrub_values_corrupt: AtomicU64,
+    scrub_values_unreadable: AtomicU64,
+    tenant_requests: Mutex<BTreeMap<String, u64>>,
+    auth_outcomes: Mutex<BTreeMap<AuthOutcome, u64>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionStats {
+    pub accounts_marked: u64,
+    pub accounts_purged: u64,
+    pub bytes_reclaimed: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            retention_accounts_marked: AtomicU64::new(0),
+            retention_accounts_purged: AtomicU64::new(0),
+            retention_bytes_reclaimed: AtomicU64::new(0),
+            history_versions_removed: AtomicU64::new(0),
+            history_bytes_reclaimed: AtomicU64::new(0),
+            checksum_mismatches: AtomicU64::new(0),
+            scrub_values_checked: AtomicU64::new(0),
+            scrub_values_corrupt: AtomicU64::new(0),
+            scrub_values_unreadable: AtomicU64::new(0),
+            tenant_requests: Mutex::new(BTreeMap::new()),
+            auth_outcomes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    pub fn record_retention(&self, stats: RetentionStats) {
+        self.retention_accounts_marked
+            .fetch_add(stats.accounts_marked, Ordering::Relaxed);
+        self.retention_accounts_purged
+            .fetch_add(stats.accounts_purged, Ordering::Relaxed);
+        self.retention_bytes_reclaimed
+            .fetch_add(stats.bytes_reclaimed, Ordering::Relaxed);
+    }
+
+    pub fn retention(&self) -> RetentionStats {
+        RetentionStats {
+            accounts_marked: self.retention_accounts_marked.load(Ordering::Relaxed),
+            accounts_purged: self.retention_accounts_purged.load(Ordering::Relaxed),
+            bytes_reclaimed: self.retention_bytes_reclaimed.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_compaction(&self, stats: CompactionStats) {
+        self.history_versions_removed
+            .fetch_add(stats.versions_removed, Ordering::Relaxed);
+        self.history_bytes_reclaimed
+            .fetch_add(stats.bytes_reclaimed, Ordering::Relaxed);
+    }
+
+    /// Settings versions dropped by the compactor since startup.
+    pub fn compaction(&self) -> CompactionStats {
+        CompactionStats {
+            versions_removed: self.history_versions_removed.load(Ordering::Relaxed),
+            bytes_reclaimed: self.history_bytes_reclaimed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Values found not to match their checksum, on read or by the scrubber.
+    pub fn record_checksum_mismatch(&self) {
+        self.checksum_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn checksum_mismatches(&self) -> u64 {
+        self.checksum_mismatches.load(Ordering::Relaxed)
+    }
+
+    pub fn record_scrub(&self, stats: ScrubStats) {
+        self.scrub_values_checked
+            .fetch_add(stats.checked, Ordering::Relaxed);
+        self.scrub_values_corrupt
+            .fetch_add(stats.corrupt, Ordering::Relaxed);
+        self.scrub_values_unreadable
+            .fetch_add(stats.unreadable, Ordering::Relaxed);
+        self.checksum_mismatches
+            .fetch_add(stats.corrupt, Ordering::Relaxed);
+    }
+
+    pub fn scrub(&self) -> ScrubStats {
+        ScrubStats {
+            checked: self.scrub_values_checked.load(Ordering::Relaxed),
+            corrupt: self.scrub_values_corrupt.load(Ordering::Relaxed),
+            unreadable: self.scrub_values_unreadable.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_tenant_request(&self, tenant: &str) {
+        let mut requests = self
+            .tenant_requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        match requests.get_mut(tenant) {
+            Some(count) => *count += 1,
+            None => {
+                requests.insert(tenant.to_string(), 1);
+            }
+        }
+    }
+
+    /// Requests served per tenant id.
+    pub fn tenant_requests(&self) -> BTreeMap<String, u64> {
+        self.tenant_requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn record_auth(&self, outcome: AuthOutcome) {
+        *self
+            .auth_outcomes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(outcome)
+            .or_default() += 1;
+    }
+
+    /// Authentication attempts per outcome.
+    pub fn auth_outcomes(&self) -> BTreeMap<AuthOutcome, u64> {
+        self.auth_outcomes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Batched reads or writes of many keys, such as those of a sync, and how
+/// long they took.
+#[derive(Default)]
+pub struct BatchLatency {
+    batches: AtomicU64,
+    keys: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BatchStats {
+    pub batches: u64,
+    pub keys: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+impl BatchLatency {
+    pub fn record(&self, keys: usize, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.keys.fetch_add(keys as u64, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> BatchStats {
+        let batches = self.batches.load(Ordering::Relaxed);
+        let total_micros = self.total_micros.load(Ordering::Relaxed);
+        BatchStats {
+            batches,
+            keys: self.keys.load(Ordering::Relaxed),
+            mean_ms: if batches == 0 {
+                0.0
+            } else {
+                total_micros as f64 / batches as f64 / 1000.0
+            },
+            max_ms: self.max_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+/// Upper bounds, in milliseconds, of the query latency histogram buckets.
+/// Slower queries land in a final unbounded bucket.
+pub const QUERY_LATENCY_BUCKETS_MS: [u64; 10] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+#[derive(Clone, Copy, Default)]
+struct Histogram {
+    count: u64,
+    total_micros: u64,
+    max_micros: u64,
+    buckets: [u64; QUERY_LATENCY_BUCKETS_MS.len() + 1],
+}
+
+/// Latency histograms of database queries, by operation.
+#[derive(Default)]
+pub struct QueryLatency {
+    operations: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryStats {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    /// Upper bounds of the buckets the median and 99th percentile fall in;
+    /// `None` past the last bucket.
+    pub p50_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+    /// Queries per bucket of [`QUERY_LATENCY_BUCKETS_MS`], then the slower
+    /// ones.
+    pub buckets: Vec<u64>,
+}
+
+impl QueryLatency {
+    pub fn record(&self, operation: &'static str, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = QUERY_LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&ms| micros <= ms * 1000)
+            .unwrap_or(QUERY_LATENCY_BUCKETS_MS.len());
+
+        let mut operations = self.operations.lock().unwrap_or_else(|e| e.into_inner());
+        let histogram = operations.entry(operation).or_default();
+        histogram.count += 1;
+        histogram.total_micros = histogram.total_micros.saturating_add(micros);
+        histogram.max_micros = histogram.max_micros.max(micros);
+        histogram.buckets[bucket] += 1;
+    }
+
+    pub fn stats(&self) -> BTreeMap<&'static str, QueryStats> {
+        let operations = self.operations.lock().unwrap_or_else(|e| e.into_inner());
+        operations
+            .iter()
+            .map(|(operation, h)| {
+                let percentile = |fraction: f64| {
+                    let rank = (h.count as f64 * fraction).ceil().max(1.0) as u64;
+                    let mut seen = 0;
+                    h.buckets
+                        .iter()
+                        .position(|&n| {
+                            seen += n;
+                            seen >= rank
+                        })
+                        .and_then(|i| QUERY_LATENCY_BUCKETS_MS.get(i).copied())
+                };
+                let stats = QueryStats {
+                    count: h.count,
+                    mean_ms: h.total_micros as f64 / h.count as f64 / 1000.0,
+                    max_ms: h.max_micros as f64 / 1000.0,
+                    p50_ms: percentile(0.5),
+                    p99_ms: percentile(0.99),
+                    buckets: h.buckets.to_vec(),
+                };
+                (*operation, stats)
+            })
+            .collect()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_latency_histogram() {
+        let latency = QueryLatency::default();
+        for ms in [1, 3, 3, 40, 2000] {
+            latency.record("get_data_key", Duration::from_millis(ms));
+        }
+        latency.record("insert_data_key", Duration::from_micros(200));
+
+        let stats = latency.stats();
+        let reads = &stats["get_data_key"];
+        assert_eq!(reads.count, 5);
+        assert_eq!(reads.buckets, [1, 0, 2, 0, 0, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(reads.p50_ms, Some(5));
+        assert_eq!(reads.p99_ms, None);
+        assert_eq!(reads.max_ms, 2000.0);
+        assert_eq!(stats["insert_data_key"].p99_ms, Some(1));
+    }
+}
@@ -4,13 +4,72 @@ use std::fs;
 use std::path::Path;
 use tracing::{debug, info, warn};
 
+use crate::constants::KEYSPACE;
+
+/// Tables the server cannot start without, created by [`MigrationRunner::bootstrap`]
+/// even when the migrations directory is missing.
+const CORE_SCHEMA: [(&str, &str); 2] = [
+    (
+        "001_create_users_table.cql",
+        include_str!("../../migrations/001_create_users_table.cql"),
+    ),
+    (
+        "003_create_data_table.cql",
+        include_str!("../../migrations/003_create_data_table.cql"),
+    ),
+];
+
 pub struct MigrationRunner<'a> {
     session: &'a Session,
+    keyspace: &'a str,
 }
 
 impl<'a> MigrationRunner<'a> {
     pub fn new(session: &'a Session) -> Self {
-        Self { session }
+        Self::for_keyspace(session, KEYSPACE)
+    }
+
+    /// Runs the migrations against `keyspace` instead of the default one.
+    /// The scripts name the default keyspace, which is substituted.
+    pub fn for_keyspace(session: &'a Session, keyspace: &'a str) -> Self {
+        Self { session, keyspace }
+    }
+
+    pub async fn keyspace_exists(&self) -> Result<bool> {
+        let rows = self
+            .session
+            .query_unpaged(
+                "SELECT keyspace_name FROM system_schema.keyspaces WHERE keyspace_name = ?",
+                (self.keyspace,),
+            )
+            .await?
+            .into_rows_result()?;
+        Ok(rows.rows_num() > 0)
+    }
+
+    /// Creates the keyspace with the given replication map and the core
+    /// tables when they are missing. An existing keyspace is left as it is,
+    /// even if its replication differs.
+    pub async fn bootstrap(&self, replication: &str) -> Result<()> {
+        if self.keyspace_exists().await? {
+            debug!("Keyspace {} already exists", self.keyspace);
+        } else {
+            info!(
+                "Creating keyspace {} with replication {}",
+                self.keyspace, replication
+            );
+            let statement = format!(
+                "CREATE KEYSPACE IF NOT EXISTS {} WITH REPLICATION = {} AND DURABLE_WRITES = true",
+                self.keyspace, replication
+            );
+            self.session.query_unpaged(statement, &[]).await?;
+        }
+
+        for (name, content) in CORE_SCHEMA {
+            self.run_script(name, content).await?;
+        }
+
+        Ok(())
     }
 
     pub async fn run_migrations(&self) -> Result<()> {
@@ -52,10 +111,18 @@ impl<'a> MigrationRunner<'a> {
             .and_then(|name| name.to_str())
             .unwrap_or("unknown");
 
-        debug!("Running migration: {}", filename);
-
         let content = fs::read_to_string(file_path)?;
+        self.run_script(filename, &content).await
+    }
 
+    async fn run_script(&self, filename: &str, content: &str) -> Result<()> {
+        debug!("Running migration: {}", filename);
+
+        let content = if self.keyspace == KEYSPACE {
+            content.to_string()
+        } else {
+            content.replace(&format!("{}.", KEYSPACE), &format!("{}.", self.keyspace))
+        };
         let cleaned_content = content
             .lines()
             .filter(|line| !line.trim().starts_with("--") && !line.trim().is_empty())
@@ -70,10 +137,25 @@ impl<'a> MigrationRunner<'a> {
 
         for statement in statements {
             debug!("Executing: {}", statement);
-            self.session.query_unpaged(statement, &[]).await?;
+            if let Err(e) = self.session.query_unpaged(statement, &[]).await {
+                if is_column_already_added(statement, &e.to_string()) {
+                    debug!("Column already present, skipping: {}", statement);
+                    continue;
+                }
+                return Err(e.into());
+            }
         }
 
         debug!("Completed migration: {}", filename);
         Ok(())
     }
 }
+
+/// Migrations are replayed on every startup and CQL has no
+/// `ADD COLUMN IF NOT EXISTS`, so re-adding an existing column is not an error.
+fn is_column_already_added(statement: &str, error: &str) -> bool {
+    let statement = statement.to_ascii_uppercase();
+    statement.starts_with("ALTER TABLE")
+        && statement.contains(" ADD ")
+        && (error.contains("conflicts with an existing column") || error.contains("already exists"))
+}
@@ -1,63 +1,76 @@
-use anyhow::Result;
-use scylla::client::PoolSize;
-use scylla::client::execution_profile::ExecutionProfile;
-use scylla::client::session::Session;
-use scylla::client::session_builder::SessionBuilder;
-use scylla::frame::Compression;
-use scylla::policies::load_balancing::DefaultPolicy;
-use scylla::policies::retry::DefaultRetryPolicy;
-use std::env;
-use std::sync::Arc;
-use std::time::Duration;
-
+pub mod abuse;
+pub mod account_deletion;
+pub mod api_keys;
+pub mod archive;
+pub mod audit;
+pub mod auth_events;
+pub mod backup;
+pub mod blob_store;
+pub mod bulk_purge;
+pub mod cache;
+pub mod checksum;
+pub mod client_ip;
+pub mod compaction;
+pub mod config;
+pub mod connection;
 pub mod constants;
+pub mod content_encoding;
+pub mod cors;
 pub mod database;
+pub mod datastore;
+pub mod db_health;
+pub mod dedup;
+pub mod delta;
+pub mod dev;
+pub mod devices;
+pub mod driver_metrics;
+pub mod error;
+pub mod etag;
+pub mod events;
 pub mod hash_migration;
+pub mod integrity;
+pub mod ip_range;
+pub mod key_limit;
+pub mod key_locks;
+pub mod lockout;
+pub mod metrics;
 pub mod migrations;
+pub mod namespaces;
+pub mod oauth;
+pub mod outbound;
+pub mod reload;
+pub mod retention;
+pub mod scopes;
+pub mod scrub;
+pub mod settings;
+pub mod share;
+pub mod stats;
+pub mod sync_cursor;
+pub mod sync_history;
+pub mod tenant;
+pub mod timed_session;
+pub mod tls;
+pub mod user_secrets;
 pub mod utils;
+pub mod validation;
+pub mod webhooks;
+pub mod write_budget;
 
-pub use database::{DataEntry, DataManifestEntry, DatabaseService};
+pub use cache::{CacheStats, SettingsCache};
+pub use connection::{connect_with_retry, create_database_connection};
+pub use database::{
+    AccountLink, AccountPurge, ApiKey, AuthLockout, Ban, ConditionalWrite, CorruptEntry, DataEntry,
+    DataManifestEntry, DataRead, DataShare, DataUpload, DatabaseService, Device, ExistingVersions,
+    IdempotencyRecord, LastWriter, LegacyCleanupReport, ManifestPage, OAuthCode, OAuthState,
+    PendingDeletion, RenameOutcome, RetentionCandidate, ScrubStats, StorageUsage, StoredResponse,
+    SyncSession, UploadPart, UploadSession, UserSummary,
+};
+pub use datastore::{Datastore, SqliteDatastore, Storage};
+pub use db_health::DbHealth;
+pub use error::{AppError, ResultExt};
+pub use events::{Event, EventBus};
+pub use metrics::{BatchLatency, BatchStats, Metrics, QueryLatency, QueryStats, RetentionStats};
 pub use migrations::MigrationRunner;
-pub use utils::{KeyValidationError, compress, compute_checksum, decompress, validate_key};
-
-pub async fn create_database_connection() -> Result<Session> {
-    let uri = env::var("SCYLLA_URI").unwrap_or_else(|_| constants::DEFAULT_SCYLLA_URI.to_string());
-    let username = env::var("SCYLLA_USERNAME").ok();
-    let password = env::var("SCYLLA_PASSWORD").ok();
-
-    let pool_size: usize = env::var("SCYLLA_POOL_SIZE")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(4);
-
-    let connection_timeout: u64 = env::var("SCYLLA_CONNECTION_TIMEOUT_MS")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(5000);
-
-    let load_balancing = DefaultPolicy::builder().build();
-
-    let mut session_builder = SessionBuilder::new()
-        .known_node(&uri)
-        .connection_timeout(Duration::from_millis(connection_timeout))
-        .pool_size(PoolSize::PerShard(
-            std::num::NonZeroUsize::new(pool_size).expect("pool size must be > 0"),
-        ))
-        .default_execution_profile_handle(
-            ExecutionProfile::builder()
-                .load_balancing_policy(load_balancing)
-                .retry_policy(Arc::new(DefaultRetryPolicy::new()))
-                .request_timeout(Some(Duration::from_secs(30)))
-                .build()
-                .into_handle(),
-        )
-        .compression(Some(Compression::Lz4))
-        .tcp_nodelay(true);
-
-    if let (Some(user), Some(pass)) = (username, password) {
-        session_builder = session_builder.user(user, pass);
-    }
-
-    let session = session_builder.build().await?;
-    Ok(session)
-}
+pub use tenant::{Tenant, Tenants};
+pub use utils::{ByteRange, compress, compute_checksum, content_hash, decompress, parse_range};
+pub use validation::{KeyValidationError, validate_key};
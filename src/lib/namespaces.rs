@@ -0,0 +1,251 @@
+//! Per-prefix rules for v2 data keys.
+//!
+//! A namespace is every key under a prefix. `KEY_NAMESPACES` can turn a
+//! namespace off, give it its own size limit, or make the values written to
+//! it expire; keys outside every namespace follow `MAX_KEY_SIZE_BYTES` and
+//! `MAX_DATA_TTL`. `dataStore/` is always a namespace, set up from
+//! `DATASTORE_ENABLED` and `MAX_DATASTORE_KEY_SIZE_BYTES` unless
+//! `KEY_NAMESPACES` lists it as well.
+
+use serde::Deserialize;
+
+use crate::error::AppError;
+use crate::validation::{check_size, validate_key};
+
+pub const DATASTORE_PREFIX: &str = "dataStore/";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespacePolicy {
+    pub prefix: String,
+    /// Keys in a disabled namespace can be neither read nor written and are
+    /// left out of listings.
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// Largest value accepted; `MAX_KEY_SIZE_BYTES` when unset.
+    #[serde(default)]
+    pub max_size_bytes: Option<usize>,
+    /// TTL given to values written without one.
+    #[serde(default)]
+    pub default_ttl_secs: Option<u64>,
+    /// Longest TTL a client may ask for; `MAX_DATA_TTL` when unset.
+    #[serde(default)]
+    pub max_ttl_secs: Option<u64>,
+    /// Variable the policy came from, for configuration issues.
+    #[serde(skip, default = "configured_in")]
+    pub source: &'static str,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn configured_in() -> &'static str {
+    "KEY_NAMESPACES"
+}
+
+impl NamespacePolicy {
+    /// The `dataStore/` namespace as `DATASTORE_ENABLED` and
+    /// `MAX_DATASTORE_KEY_SIZE_BYTES` describe it.
+    pub fn datastore(enabled: bool, max_size_bytes: usize) -> Self {
+        Self {
+            prefix: DATASTORE_PREFIX.to_string(),
+            enabled,
+            max_size_bytes: Some(max_size_bytes),
+            default_ttl_secs: None,
+            max_ttl_secs: None,
+            source: "MAX_DATASTORE_KEY_SIZE_BYTES",
+        }
+    }
+}
+
+/// Parses the JSON array in `KEY_NAMESPACES`, e.g.
+/// `[{"prefix": "cache/", "max_size_bytes": 65536, "default_ttl_secs": 86400}]`.
+pub fn parse_policies(s: &str) -> Option<Vec<NamespacePolicy>> {
+    serde_json::from_str(s).ok()
+}
+
+/// The configured namespaces and the limits of keys outside them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespaces {
+    /// Longest prefix first, so the most specific namespace wins.
+    policies: Vec<NamespacePolicy>,
+    default_max_size: usize,
+    default_max_ttl_secs: u64,
+}
+
+impl Namespaces {
+    /// `configured` plus the built-in `datastore` policy, unless `configured`
+    /// has its own for `dataStore/`.
+    pub fn new(
+        datastore: NamespacePolicy,
+        configured: Vec<NamespacePolicy>,
+        default_max_size: usize,
+        default_max_ttl_secs: u64,
+    ) -> Self {
+        let mut policies = configured;
+        if !policies.iter().any(|p| p.prefix == datastore.prefix) {
+            policies.push(datastore);
+        }
+        policies.sort_by_key(|p| std::cmp::Reverse(p.prefix.len()));
+        Self {
+            policies,
+            default_max_size,
+            default_max_ttl_secs,
+        }
+    }
+
+    pub fn policies(&self) -> &[NamespacePolicy] {
+        &self.policies
+    }
+
+    /// The namespace `key` belongs to, if any.
+    pub fn policy_for(&self, key: &str) -> Option<&NamespacePolicy> {
+        self.policies.iter().find(|p| key.starts_with(&p.prefix))
+    }
+
+    /// Whether `key` may be used, and so shows up in manifests, listings and
+    /// exports.
+    pub fn is_enabled(&self, key: &str) -> bool {
+        self.policy_for(key).is_none_or(|p| p.enabled)
+    }
+
+    /// Largest value accepted for `key`.
+    pub fn max_size(&self, key: &str) -> usize {
+        self.policy_for(key)
+            .and_then(|p| p.max_size_bytes)
+            .unwrap_or(self.default_max_size)
+    }
+
+    /// Largest value accepted for any key.
+    pub fn largest_max_size(&self) -> usize {
+        self.policies
+            .iter()
+            .filter_map(|p| p.max_size_bytes)
+            .fold(self.default_max_size, usize::max)
+    }
+
+    /// Rejects malformed keys and keys in a disabled namespace.
+    pub fn check_key(&self, key: &str) -> Result<(), AppError> {
+        validate_key(key)?;
+
+        match self.policy_for(key) {
+            Some(policy) if !policy.enabled => Err(disabled(policy)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a `size`-byte value may be written to `key`.
+    pub fn validate_write(&self, key: &str, size: usize) -> Result<(), AppError> {
+        self.check_key(key)?;
+
+        check_size(size, self.max_size(key), "Value")
+    }
+
+    /// The TTL a value written to `key` gets: the one requested, checked
+    /// against the namespace's maximum, or else the namespace's default.
+    /// Converted to the seconds Scylla expects.
+    pub fn resolve_ttl(&self, key: &str, requested: Option<u64>) -> Result<Option<i32>, AppError> {
+        let policy = self.policy_for(key);
+        let Some(ttl) = requested.or_else(|| policy.and_then(|p| p.default_ttl_secs)) else {
+            return Ok(None);
+        };
+
+        let max = policy
+            .and_then(|p| p.max_ttl_secs)
+            .unwrap_or(self.default_max_ttl_secs);
+        match i32::try_from(ttl) {
+            Ok(ttl) if ttl > 0 && ttl as u64 <= max => Ok(Some(ttl)),
+            _ => Err(AppError::BadRequest(format!(
+                "TTL must be between 1 and {} seconds",
+                max
+            ))),
+        }
+    }
+}
+
+fn disabled(policy: &NamespacePolicy) -> AppError {
+    // Clients predating namespaces look for `datastore_disabled`.
+    if policy.prefix == DATASTORE_PREFIX {
+        AppError::DatastoreDisabled
+    } else {
+        AppError::NamespaceDisabled(policy.prefix.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespaces() -> Namespaces {
+        let configured = parse_policies(
+            r#"[
+                {"prefix": "cache/", "max_size_bytes": 8, "default_ttl_secs": 60, "max_ttl_secs": 120},
+                {"prefix": "cache/pinned/", "max_size_bytes": 16},
+                {"prefix": "legacy/", "enabled": false}
+            ]"#,
+        )
+        .unwrap();
+        Namespaces::new(NamespacePolicy::datastore(false, 32), configured, 4, 1000)
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let namespaces = namespaces();
+        assert_eq!(namespaces.max_size("cache/a"), 8);
+        assert_eq!(namespaces.max_size("cache/pinned/a"), 16);
+        assert_eq!(namespaces.max_size("dataStore/a"), 32);
+        assert_eq!(namespaces.max_size("plugins/a"), 4);
+        assert_eq!(namespaces.largest_max_size(), 32);
+
+        assert!(namespaces.validate_write("cache/a", 8).is_ok());
+        assert_eq!(
+            namespaces.validate_write("cache/a", 9).unwrap_err().code(),
+            "payload_too_large"
+        );
+        assert!(namespaces.validate_write("cache/pinned/a", 9).is_ok());
+    }
+
+    #[test]
+    fn test_disabled_namespaces() {
+        let namespaces = namespaces();
+        assert!(!namespaces.is_enabled("legacy/a"));
+        assert_eq!(
+            namespaces.check_key("legacy/a").unwrap_err().code(),
+            "namespace_disabled"
+        );
+        assert_eq!(
+            namespaces.check_key("dataStore/a").unwrap_err().code(),
+            "datastore_disabled"
+        );
+        assert!(namespaces.check_key("legacyish").is_ok());
+
+        let enabled = Namespaces::new(
+            NamespacePolicy::datastore(false, 32),
+            parse_policies(r#"[{"prefix": "dataStore/"}]"#).unwrap(),
+            4,
+            1000,
+        );
+        assert!(enabled.check_key("dataStore/a").is_ok());
+        assert_eq!(enabled.max_size("dataStore/a"), 4);
+    }
+
+    #[test]
+    fn test_namespace_ttls() {
+        let namespaces = namespaces();
+        assert_eq!(namespaces.resolve_ttl("cache/a", None).unwrap(), Some(60));
+        assert_eq!(
+            namespaces.resolve_ttl("cache/a", Some(90)).unwrap(),
+            Some(90)
+        );
+        assert!(namespaces.resolve_ttl("cache/a", Some(500)).is_err());
+        assert_eq!(namespaces.resolve_ttl("plugins/a", None).unwrap(), None);
+        assert_eq!(
+            namespaces.resolve_ttl("plugins/a", Some(500)).unwrap(),
+            Some(500)
+        );
+        assert!(namespaces.resolve_ttl("plugins/a", Some(0)).is_err());
+
+        assert_eq!(parse_policies(r#"[{"prefix": "a/", "size": 1}]"#), None);
+    }
+}
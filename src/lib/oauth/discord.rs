@@ -0,0 +1,98 @@
+use serde::Deserialize;
+
+use super::http::ProviderClient;
+use super::provider::{
+    OAuthProvider, ProviderError, ProviderTokens, build_url, request_identity, request_token,
+};
+use crate::constants::{DISCORD_AUTHORIZE_URL, DISCORD_TOKEN_URL, DISCORD_USER_URL};
+use crate::utils::ConfigHandle;
+
+const DISCORD_SCOPE: &str = "identify";
+
+#[derive(Deserialize)]
+struct DiscordUserResult {
+    id: String,
+}
+
+pub struct DiscordProvider {
+    client: ProviderClient,
+    client_id: String,
+    client_secret: String,
+}
+
+impl DiscordProvider {
+    pub fn from_config(config: &ConfigHandle) -> Self {
+        let current = config.load();
+        Self {
+            client: ProviderClient::new(config.clone()),
+            client_id: current.discord_client_id.clone(),
+            client_secret: current.discord_client_secret.clone(),
+        }
+    }
+}
+
+impl OAuthProvider for DiscordProvider {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    async fn authorize_url(
+        &self,
+        state: &str,
+        redirect_uri: &str,
+        code_challenge: Option<&str>,
+    ) -> Result<String, ProviderError> {
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("response_type", "code"),
+            ("scope", DISCORD_SCOPE),
+            ("state", state),
+        ];
+        if let Some(challenge) = code_challenge {
+            params.push(("code_challenge", challenge));
+            params.push(("code_challenge_method", "S256"));
+        }
+        Ok(build_url(DISCORD_AUTHORIZE_URL, &params))
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: Option<&str>,
+    ) -> Result<ProviderTokens, ProviderError> {
+        let mut form = vec![
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("scope", DISCORD_SCOPE),
+        ];
+        if let Some(verifier) = code_verifier {
+            form.push(("code_verifier", verifier));
+        }
+        request_token(&self.client, DISCORD_TOKEN_URL, &form).await
+    }
+
+    async fn refresh(&self, refresh_token: &str) -> Result<ProviderTokens, ProviderError> {
+        let form = [
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ];
+        request_token(&self.client, DISCORD_TOKEN_URL, &form).await
+    }
+
+    async fn fetch_user_id(&self, access_token: &str) -> Result<String, ProviderError> {
+        let user: DiscordUserResult =
+            request_identity(&self.client, DISCORD_USER_URL, access_token).await?;
+        Ok(user.id)
+    }
+}
@@ -0,0 +1,197 @@
+//! The HTTP client identity providers are called through.
+//!
+//! Each provider has a connection pool with connect and overall timeouts,
+//! so a slow provider cannot hold a sign-in open indefinitely.
+//! Network errors and 5xx responses are retried up to
+//! `PROVIDER_MAX_ATTEMPTS` times with jittered backoff. Once
+//! `PROVIDER_CIRCUIT_BREAKER_THRESHOLD` requests in a row have failed, the
+//! circuit opens and calls fail at once for `PROVIDER_CIRCUIT_OPEN_SECS`;
+//! the first call after that probes the provider and closes it on success.
+//! Requests follow the outbound policy of `outbound` for the tenant's
+//! configuration; one it refuses fails at once.
+
+use reqwest::{Client, RequestBuilder, Response};
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use std::time::Duration;
+use tracing::{error, warn};
+
+use super::provider::ProviderError;
+use crate::constants::{
+    PROVIDER_BACKOFF_BASE_MS, PROVIDER_CIRCUIT_BREAKER_THRESHOLD, PROVIDER_CIRCUIT_OPEN_SECS,
+    PROVIDER_CONNECT_TIMEOUT_SECS, PROVIDER_MAX_ATTEMPTS, PROVIDER_TIMEOUT_SECS,
+};
+use crate::outbound;
+use crate::utils::ConfigHandle;
+
+fn build_client(config: ConfigHandle) -> Client {
+    outbound::client_builder(config)
+        .connect_timeout(Duration::from_secs(PROVIDER_CONNECT_TIMEOUT_SECS))
+        .timeout(Duration::from_secs(PROVIDER_TIMEOUT_SECS))
+        .build()
+        .unwrap_or_else(|e| {
+            error!(
+                "Failed to create provider HTTP client, using defaults: {}",
+                e
+            );
+            Client::new()
+        })
+}
+
+/// A client with a circuit breaker of its own, one per provider.
+pub struct ProviderClient {
+    client: Client,
+    config: ConfigHandle,
+    consecutive_failures: AtomicU32,
+    /// Milliseconds since the epoch; 0 while the circuit is closed.
+    open_until: AtomicI64,
+}
+
+impl ProviderClient {
+    pub fn new(config: ConfigHandle) -> Self {
+        Self {
+            client: build_client(config.clone()),
+            config,
+            consecutive_failures: AtomicU32::new(0),
+            open_until: AtomicI64::new(0),
+        }
+    }
+
+    /// Sends the request `build` makes, retrying network errors and 5xx
+    /// responses. The last response is returned whatever its status; a
+    /// network error on the last attempt becomes `failure`.
+    pub async fn send(
+        &self,
+        failure: ProviderError,
+        build: impl Fn(&Client) -> RequestBuilder,
+    ) -> Result<Response, ProviderError> {
+        self.check_circuit(chrono::Utc::now().timestamp_millis())?;
+
+        for attempt in 1..=PROVIDER_MAX_ATTEMPTS {
+            let policy = &self.config.load().outbound;
+            let error = match outbound::send(policy, build(&self.client)).await {
+                Ok(response) if !response.status().is_server_error() => {
+                    self.record_success();
+                    return Ok(response);
+                }
+                Ok(response) if attempt == PROVIDER_MAX_ATTEMPTS => {
+                    self.record_failure(chrono::Utc::now().timestamp_millis());
+                    return Ok(response);
+                }
+                Ok(response) => response.status().to_string(),
+                Err(e) if e.is_refused() || attempt == PROVIDER_MAX_ATTEMPTS => {
+                    error!("{}: {}", failure.message(), e);
+                    self.record_failure(chrono::Utc::now().timestamp_millis());
+                    return Err(failure);
+                }
+                Err(e) => e.to_string(),
+            };
+
+            let delay = backoff_delay(attempt);
+            warn!(
+                "Provider request failed (attempt {}/{}), retrying in {}ms: {}",
+                attempt,
+                PROVIDER_MAX_ATTEMPTS,
+                delay.as_millis(),
+                error
+            );
+            tokio::time::sleep(delay).await;
+        }
+        Err(failure)
+    }
+
+    /// Fails with `Unavailable` while the circuit is open. Once it has been
+    /// open long enough, one call is let through and the circuit stays open
+    /// for everyone else until that call is done.
+    fn check_circuit(&self, now: i64) -> Result<(), ProviderError> {
+        let open_until = self.open_until.load(Ordering::Relaxed);
+        if open_until == 0 {
+            return Ok(());
+        }
+        if now < open_until {
+            return Err(ProviderError::Unavailable {
+                retry_after_secs: ((open_until - now) as u64).div_ceil(1000),
+            });
+        }
+        let probe_until = now + PROVIDER_CIRCUIT_OPEN_SECS as i64 * 1000;
+        match self.open_until.compare_exchange(
+            open_until,
+            probe_until,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ProviderError::Unavailable {
+                retry_after_secs: PROVIDER_CIRCUIT_OPEN_SECS,
+            }),
+        }
+    }
+
+    fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        self.open_until.store(0, Ordering::Relaxed);
+    }
+
+    fn record_failure(&self, now: i64) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= PROVIDER_CIRCUIT_BREAKER_THRESHOLD {
+            if failures == PROVIDER_CIRCUIT_BREAKER_THRESHOLD {
+                error!(
+                    "Provider failed {} requests in a row, failing sign-ins for {}s",
+                    failures, PROVIDER_CIRCUIT_OPEN_SECS
+                );
+            }
+            self.open_until.store(
+                now + PROVIDER_CIRCUIT_OPEN_SECS as i64 * 1000,
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+/// Delay before retry `attempt` (1-based): exponential, plus up to as much
+/// again at random so callers that failed together do not retry together.
+fn backoff_delay(attempt: u32) -> Duration {
+    let base = PROVIDER_BACKOFF_BASE_MS << attempt.saturating_sub(1).min(8);
+    Duration::from_millis(base + rand::random_range(0..=base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CONFIG;
+
+    #[test]
+    fn test_circuit_opens_and_probes() {
+        let client = ProviderClient::new(CONFIG.clone());
+        let now = 1_000_000;
+        for _ in 1..PROVIDER_CIRCUIT_BREAKER_THRESHOLD {
+            client.record_failure(now);
+        }
+        assert!(client.check_circuit(now).is_ok());
+
+        client.record_failure(now);
+        assert_eq!(
+            client.check_circuit(now + 1),
+            Err(ProviderError::Unavailable {
+                retry_after_secs: PROVIDER_CIRCUIT_OPEN_SECS
+            })
+        );
+
+        // Once open long enough, a single call probes the provider.
+        let later = now + PROVIDER_CIRCUIT_OPEN_SECS as i64 * 1000;
+        assert!(client.check_circuit(later).is_ok());
+        assert!(client.check_circuit(later).is_err());
+
+        client.record_success();
+        assert!(client.check_circuit(later).is_ok());
+    }
+
+    #[test]
+    fn test_backoff_delay_is_jittered() {
+        for attempt in 1..=3 {
+            let base = PROVIDER_BACKOFF_BASE_MS << (attempt - 1);
+            let delay = backoff_delay(attempt).as_millis() as u64;
+            assert!((base..=2 * base).contains(&delay));
+        }
+    }
+}
@@ -0,0 +1,17 @@
+pub mod discord;
+pub mod http;
+pub mod oidc;
+pub mod provider;
+pub mod redirect;
+pub mod session;
+pub mod state;
+pub mod tokens;
+
+pub use provider::{OAuthProvider, Provider, ProviderError, ProviderTokens};
+pub use redirect::{OAuthRedirect, RedirectMode};
+pub use session::{
+    issue_scoped_session_secret, issue_session_secret, parse_token, verify_scoped_session_secret,
+    verify_session_secret,
+};
+pub use state::{PkcePair, issue_state, verify_state};
+pub use tokens::{decrypt_token, encrypt_token};
@@ -0,0 +1,140 @@
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+use tracing::error;
+
+use super::http::ProviderClient;
+use super::provider::{
+    OAuthProvider, ProviderError, ProviderTokens, build_url, request_identity, request_token,
+};
+use crate::utils::ConfigHandle;
+
+#[derive(Debug, Clone, Deserialize)]
+struct DiscoveryDocument {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+}
+
+/// Generic OpenID Connect provider. Endpoints are resolved lazily from the
+/// issuer's `.well-known/openid-configuration` and cached for the process
+/// lifetime; the user id is the `sub` claim from the userinfo endpoint.
+pub struct OidcProvider {
+    client: ProviderClient,
+    issuer_url: String,
+    client_id: String,
+    client_secret: String,
+    scopes: String,
+    discovery: OnceCell<DiscoveryDocument>,
+}
+
+impl OidcProvider {
+    pub fn from_config(config: &ConfigHandle) -> Self {
+        let current = config.load();
+        Self {
+            client: ProviderClient::new(config.clone()),
+            issuer_url: current
+                .oidc_issuer_url
+                .as_ref()
+                .map(|url| url.as_str().trim_end_matches('/').to_string())
+                .unwrap_or_default(),
+            client_id: current.oidc_client_id.clone(),
+            client_secret: current.oidc_client_secret.clone(),
+            scopes: current.oidc_scopes.clone(),
+            discovery: OnceCell::new(),
+        }
+    }
+
+    async fn discovery(&self) -> Result<&DiscoveryDocument, ProviderError> {
+        self.discovery
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.issuer_url);
+                let response = self
+                    .client
+                    .send(ProviderError::Discovery, |c| c.get(&url))
+                    .await?;
+                if !response.status().is_success() {
+                    error!("OIDC discovery returned {}", response.status());
+                    return Err(ProviderError::Discovery);
+                }
+                response.json().await.map_err(|err| {
+                    error!("Failed to parse OIDC discovery document: {}", err);
+                    ProviderError::Discovery
+                })
+            })
+            .await
+    }
+}
+
+impl OAuthProvider for OidcProvider {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    async fn authorize_url(
+        &self,
+        state: &str,
+        redirect_uri: &str,
+        code_challenge: Option<&str>,
+    ) -> Result<String, ProviderError> {
+        let discovery = self.discovery().await?;
+        let mut params = vec![
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("response_type", "code"),
+            ("scope", self.scopes.as_str()),
+            ("state", state),
+        ];
+        if let Some(challenge) = code_challenge {
+            params.push(("code_challenge", challenge));
+            params.push(("code_challenge_method", "S256"));
+        }
+        Ok(build_url(&discovery.authorization_endpoint, &params))
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: Option<&str>,
+    ) -> Result<ProviderTokens, ProviderError> {
+        let discovery = self.discovery().await?;
+        let mut form = vec![
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+        ];
+        if let Some(verifier) = code_verifier {
+            form.push(("code_verifier", verifier));
+        }
+        request_token(&self.client, &discovery.token_endpoint, &form).await
+    }
+
+    async fn refresh(&self, refresh_token: &str) -> Result<ProviderTokens, ProviderError> {
+        let discovery = self.discovery().await?;
+        let form = [
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ];
+        request_token(&self.client, &discovery.token_endpoint, &form).await
+    }
+
+    async fn fetch_user_id(&self, access_token: &str) -> Result<String, ProviderError> {
+        let discovery = self.discovery().await?;
+        let info: UserInfo =
+            request_identity(&self.client, &discovery.userinfo_endpoint, access_token).await?;
+        Ok(info.sub)
+    }
+}
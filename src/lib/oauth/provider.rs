@@ -0,0 +1,221 @@
+//! Identity provider abstraction used by the `/v1/oauth/*` routes.
+//!
+//! A provider knows how to build an authorization URL, exchange an
+//! authorization code or refresh token for tokens, and resolve an access
+//! token to a stable user id. Discord is the default; a generic OIDC provider
+//! is selected with `OAUTH_PROVIDER=oidc`.
+
+use serde::Deserialize;
+use std::future::Future;
+
+use super::discord::DiscordProvider;
+use super::http::ProviderClient;
+use super::oidc::OidcProvider;
+use crate::utils::ConfigHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderError {
+    Discovery,
+    TokenRequest,
+    TokenRejected,
+    TokenParse,
+    IdentityRequest,
+    IdentityParse,
+    /// The provider failed too many requests in a row; calls fail at once
+    /// until it is tried again.
+    Unavailable {
+        retry_after_secs: u64,
+    },
+}
+
+impl ProviderError {
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::Discovery => "Failed to load provider configuration",
+            Self::TokenRequest => "Failed to request access token",
+            Self::TokenRejected => "Token request was rejected",
+            Self::TokenParse => "Failed to parse token response",
+            Self::IdentityRequest => "Failed to request user",
+            Self::IdentityParse => "Failed to parse user response",
+            Self::Unavailable { .. } => "The provider is unavailable, try again later",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderTokens {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+pub trait OAuthProvider {
+    fn name(&self) -> &'static str;
+
+    fn client_id(&self) -> &str;
+
+    fn authorize_url(
+        &self,
+        state: &str,
+        redirect_uri: &str,
+        code_challenge: Option<&str>,
+    ) -> impl Future<Output = Result<String, ProviderError>> + Send;
+
+    fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: Option<&str>,
+    ) -> impl Future<Output = Result<ProviderTokens, ProviderError>> + Send;
+
+    fn refresh(
+        &self,
+        refresh_token: &str,
+    ) -> impl Future<Output = Result<ProviderTokens, ProviderError>> + Send;
+
+    fn fetch_user_id(
+        &self,
+        access_token: &str,
+    ) -> impl Future<Output = Result<String, ProviderError>> + Send;
+}
+
+/// The configured provider, dispatched statically.
+pub enum Provider {
+    Discord(DiscordProvider),
+    Oidc(OidcProvider),
+}
+
+impl Provider {
+    pub fn from_config(config: &ConfigHandle) -> Self {
+        match config.load().oauth_provider.as_str() {
+            "oidc" => Self::Oidc(OidcProvider::from_config(config)),
+            _ => Self::Discord(DiscordProvider::from_config(config)),
+        }
+    }
+}
+
+impl OAuthProvider for Provider {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Discord(p) => p.name(),
+            Self::Oidc(p) => p.name(),
+        }
+    }
+
+    fn client_id(&self) -> &str {
+        match self {
+            Self::Discord(p) => p.client_id(),
+            Self::Oidc(p) => p.client_id(),
+        }
+    }
+
+    async fn authorize_url(
+        &self,
+        state: &str,
+        redirect_uri: &str,
+        code_challenge: Option<&str>,
+    ) -> Result<String, ProviderError> {
+        match self {
+            Self::Discord(p) => p.authorize_url(state, redirect_uri, code_challenge).await,
+            Self::Oidc(p) => p.authorize_url(state, redirect_uri, code_challenge).await,
+        }
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: Option<&str>,
+    ) -> Result<ProviderTokens, ProviderError> {
+        match self {
+            Self::Discord(p) => p.exchange_code(code, redirect_uri, code_verifier).await,
+            Self::Oidc(p) => p.exchange_code(code, redirect_uri, code_verifier).await,
+        }
+    }
+
+    async fn refresh(&self, refresh_token: &str) -> Result<ProviderTokens, ProviderError> {
+        match self {
+            Self::Discord(p) => p.refresh(refresh_token).await,
+            Self::Oidc(p) => p.refresh(refresh_token).await,
+        }
+    }
+
+    async fn fetch_user_id(&self, access_token: &str) -> Result<String, ProviderError> {
+        match self {
+            Self::Discord(p) => p.fetch_user_id(access_token).await,
+            Self::Oidc(p) => p.fetch_user_id(access_token).await,
+        }
+    }
+}
+
+/// Builds `<base>?<params>` with every value URL-encoded.
+pub(super) fn build_url(base: &str, params: &[(&str, &str)]) -> String {
+    let query = params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, urlencoding::encode(v)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", base, separator, query)
+}
+
+/// Shared token endpoint call for providers using form-encoded grants.
+pub(super) async fn request_token(
+    client: &ProviderClient,
+    token_url: &str,
+    form: &[(&str, &str)],
+) -> Result<ProviderTokens, ProviderError> {
+    let response = client
+        .send(ProviderError::TokenRequest, |c| {
+            c.post(token_url).form(form)
+        })
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(ProviderError::TokenRejected);
+    }
+
+    response.json().await.map_err(|err| {
+        tracing::error!("Failed to parse token response: {}", err);
+        ProviderError::TokenParse
+    })
+}
+
+/// Fetches a JSON identity document with a bearer access token.
+pub(super) async fn request_identity<T: for<'de> Deserialize<'de>>(
+    client: &ProviderClient,
+    url: &str,
+    access_token: &str,
+) -> Result<T, ProviderError> {
+    let response = client
+        .send(ProviderError::IdentityRequest, |c| {
+            c.get(url).bearer_auth(access_token)
+        })
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(ProviderError::IdentityRequest);
+    }
+
+    response.json().await.map_err(|err| {
+        tracing::error!("Failed to parse user response: {}", err);
+        ProviderError::IdentityParse
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_url_encodes_values() {
+        assert_eq!(
+            build_url("https://idp/auth", &[("a", "x y"), ("b", "1/2")]),
+            "https://idp/auth?a=x%20y&b=1%2F2"
+        );
+        assert_eq!(
+            build_url("https://idp/auth?tenant=1", &[("a", "b")]),
+            "https://idp/auth?tenant=1&a=b"
+        );
+    }
+}
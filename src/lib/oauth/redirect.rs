@@ -0,0 +1,154 @@
+//! Sending the browser back to the client once it has signed in.
+//!
+//! `/v1/oauth/authorize` accepts a return URL when it is covered by
+//! `OAUTH_REDIRECT_ALLOWLIST` and keeps it with the state. The callback then
+//! redirects there with either a one-time code, exchanged for the session at
+//! `/v1/oauth/token`, or the session itself in the URL fragment.
+//!
+//! Codes are stored under their SHA-256 only and work once, so neither the
+//! code table nor a URL that ends up in a log or history hands out a
+//! session. The permanent secret, which never expires, is only given out
+//! through the exchange.
+
+use base64::prelude::*;
+use rand::RngCore;
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+/// How the callback hands the session to the return URL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RedirectMode {
+    /// A `code` query parameter to exchange at `/v1/oauth/token`.
+    #[default]
+    Code,
+    /// The session fields in the URL fragment, which browsers do not send
+    /// to servers.
+    Fragment,
+}
+
+impl RedirectMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            RedirectMode::Code => "code",
+            RedirectMode::Fragment => "fragment",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "code" => Some(RedirectMode::Code),
+            "fragment" => Some(RedirectMode::Fragment),
+            _ => None,
+        }
+    }
+}
+
+/// Where a pending authorization returns to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OAuthRedirect {
+    pub uri: String,
+    pub mode: RedirectMode,
+}
+
+/// Whether `redirect` is covered by an allowlist entry: same scheme, host
+/// and port, and a path equal to or below the entry's. Return URLs with
+/// credentials or a fragment are refused.
+pub fn is_allowed(redirect: &Url, allowlist: &[Url]) -> bool {
+    if redirect.fragment().is_some()
+        || !redirect.username().is_empty()
+        || redirect.password().is_some()
+    {
+        return false;
+    }
+    allowlist.iter().any(|allowed| {
+        allowed.scheme() == redirect.scheme()
+            && allowed.host() == redirect.host()
+            && allowed.port_or_known_default() == redirect.port_or_known_default()
+            && path_within(allowed.path(), redirect.path())
+    })
+}
+
+fn path_within(allowed: &str, path: &str) -> bool {
+    path == allowed
+        || path.starts_with(allowed)
+            && (allowed.ends_with('/') || path[allowed.len()..].starts_with('/'))
+}
+
+/// A one-time code for `/v1/oauth/token`.
+pub fn issue_code() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// What a code is stored under.
+pub fn code_key(code: &str) -> String {
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+/// `redirect` with `params` added to its query.
+pub fn with_query(redirect: &str, params: &[(&str, &str)]) -> Option<String> {
+    let mut url = Url::parse(redirect).ok()?;
+    url.query_pairs_mut().extend_pairs(params);
+    Some(url.into())
+}
+
+/// `redirect` with `params` as its fragment, encoded like a query.
+pub fn with_fragment(redirect: &str, params: &[(&str, &str)]) -> Option<String> {
+    let mut url = Url::parse(redirect).ok()?;
+    let fragment = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    url.set_fragment(Some(&fragment));
+    Some(url.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let allowlist = [
+            Url::parse("https://app.example.com/auth").unwrap(),
+            Url::parse("http://localhost:3000/").unwrap(),
+        ];
+        let allowed = |url: &str| is_allowed(&Url::parse(url).unwrap(), &allowlist);
+
+        assert!(allowed("https://app.example.com/auth"));
+        assert!(allowed("https://app.example.com/auth/done?from=settings"));
+        assert!(allowed("http://localhost:3000/callback"));
+        assert!(!allowed("https://app.example.com/authority"));
+        assert!(!allowed("https://app.example.com/"));
+        assert!(!allowed("http://app.example.com/auth"));
+        assert!(!allowed("https://app.example.com:8443/auth"));
+        assert!(!allowed("https://evil.example.com/auth"));
+        assert!(!allowed("https://user@app.example.com/auth"));
+        assert!(!allowed("https://app.example.com/auth#x"));
+        assert!(!allowed("http://localhost:3001/callback"));
+    }
+
+    #[test]
+    fn test_redirect_locations() {
+        assert_eq!(
+            with_query("https://app.example.com/auth?from=x", &[("code", "a b")]).unwrap(),
+            "https://app.example.com/auth?from=x&code=a+b"
+        );
+        assert_eq!(
+            with_fragment("https://app.example.com/auth", &[("secret", "s/1")]).unwrap(),
+            "https://app.example.com/auth#secret=s%2F1"
+        );
+        assert_eq!(issue_code().len(), 43);
+        assert_ne!(code_key("code"), "code");
+        assert_eq!(code_key("code").len(), 64);
+        assert_eq!(
+            RedirectMode::parse(RedirectMode::Fragment.name()),
+            Some(RedirectMode::Fragment)
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Expiring session secrets.
+//!
+//! Clients keep building `base64("<secret>:<discord id>")` tokens exactly as
+//! before; only the secret changes. A session secret has the form
+//! `<expires_at_ms>.<signature>` where the signature is an HMAC-SHA256 over
+//! the user id and expiry, so sessions can be verified without storage. A
+//! session limited to some scopes has the form
+//! `<expires_at_ms>.<scopes>.<signature>`, with the scopes signed as well.
+
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::scopes::TokenScopes;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &[u8], user_id: &str, expires_at: i64, scopes: Option<&str>) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(b"session:");
+    mac.update(user_id.as_bytes());
+    mac.update(b":");
+    mac.update(expires_at.to_string().as_bytes());
+    if let Some(scopes) = scopes {
+        mac.update(b":");
+        mac.update(scopes.as_bytes());
+    }
+    mac
+}
+
+pub fn issue_session_secret(key: &[u8], user_id: &str, expires_at: i64) -> String {
+    let signature = mac(key, user_id, expires_at, None).finalize().into_bytes();
+    format!("{}.{}", expires_at, hex::encode(signature))
+}
+
+/// A session secret limited to `scopes`; one holding every scope takes the
+/// unscoped form.
+pub fn issue_scoped_session_secret(
+    key: &[u8],
+    user_id: &str,
+    expires_at: i64,
+    scopes: &TokenScopes,
+) -> String {
+    if scopes.is_full() {
+        return issue_session_secret(key, user_id, expires_at);
+    }
+    let encoded = scopes.encode();
+    let signature = mac(key, user_id, expires_at, Some(&encoded))
+        .finalize()
+        .into_bytes();
+    format!("{}.{}.{}", expires_at, encoded, hex::encode(signature))
+}
+
+/// Checks the session signature for `user_id` and returns its expiry. The
+/// caller decides whether an expired session is still acceptable (the refresh
+/// endpoint accepts them, regular routes do not).
+pub fn verify_session_secret(key: &[u8], secret: &str, user_id: &str) -> Option<i64> {
+    verify_scoped_session_secret(key, secret, user_id).map(|(expires_at, _)| expires_at)
+}
+
+/// [`verify_session_secret`] that also returns the session's scopes.
+pub fn verify_scoped_session_secret(
+    key: &[u8],
+    secret: &str,
+    user_id: &str,
+) -> Option<(i64, TokenScopes)> {
+    let (expires_at, rest) = secret.split_once('.')?;
+    let expires_at: i64 = expires_at.parse().ok()?;
+    let (scopes, signature) = match rest.split_once('.') {
+        Some((scopes, signature)) => (Some(scopes), signature),
+        None => (None, rest),
+    };
+    let signature = hex::decode(signature).ok()?;
+
+    mac(key, user_id, expires_at, scopes)
+        .verify_slice(&signature)
+        .ok()?;
+    let scopes = match scopes {
+        Some(encoded) => TokenScopes::decode(encoded)?,
+        None => TokenScopes::full(),
+    };
+    Some((expires_at, scopes))
+}
+
+/// Splits an `Authorization` header value into `(secret, discord user id)`.
+pub fn parse_token(header: &str) -> Option<(String, String)> {
+    let token = header.strip_prefix("Bearer ").unwrap_or(header);
+    let decoded = BASE64_STANDARD.decode(token).ok()?;
+    let token_str = String::from_utf8(decoded).ok()?;
+    let (secret, user_id) = token_str.split_once(':')?;
+    Some((secret.to_string(), user_id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_round_trip() {
+        let secret = issue_session_secret(b"key", "1234", 5_000);
+        assert_eq!(verify_session_secret(b"key", &secret, "1234"), Some(5_000));
+        assert_eq!(verify_session_secret(b"key", &secret, "4321"), None);
+        assert_eq!(verify_session_secret(b"other", &secret, "1234"), None);
+    }
+
+    #[test]
+    fn test_session_expiry_is_signed() {
+        let secret = issue_session_secret(b"key", "1234", 5_000);
+        let (_, signature) = secret.split_once('.').unwrap();
+        let extended = format!("{}.{}", 9_999_999, signature);
+        assert_eq!(verify_session_secret(b"key", &extended, "1234"), None);
+    }
+
+    #[test]
+    fn test_scoped_session_round_trip() {
+        let scopes = TokenScopes::from_request("read", Some("plugins/")).unwrap();
+        let secret = issue_scoped_session_secret(b"key", "1234", 5_000, &scopes);
+        assert_eq!(
+            verify_scoped_session_secret(b"key", &secret, "1234"),
+            Some((5_000, scopes))
+        );
+
+        let (_, signature) = secret.rsplit_once('.').unwrap();
+        let widened = format!("5000.{}.{}", TokenScopes::full().encode(), signature);
+        assert_eq!(verify_session_secret(b"key", &widened, "1234"), None);
+
+        let full = issue_scoped_session_secret(b"key", "1234", 5_000, &TokenScopes::full());
+        assert_eq!(full, issue_session_secret(b"key", "1234", 5_000));
+    }
+
+    #[test]
+    fn test_parse_token() {
+        let token = BASE64_STANDARD.encode("abc.def:1234");
+        assert_eq!(
+            parse_token(&format!("Bearer {}", token)),
+            Some(("abc.def".to_string(), "1234".to_string()))
+        );
+        assert_eq!(parse_token("not base64!"), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! Signed OAuth `state` values and PKCE helpers.
+//!
+//! A state has the form `<nonce>.<expires_at_ms>.<signature>` where the
+//! signature is an HMAC-SHA256 over `<nonce>.<expires_at_ms>`. The signature
+//! rejects forged values without a database round trip; single use is
+//! enforced separately by the `oauth_states` table.
+
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct PkcePair {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl PkcePair {
+    pub fn generate() -> Self {
+        let verifier = BASE64_URL_SAFE_NO_PAD.encode(random_bytes::<32>());
+        let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Self {
+            verifier,
+            challenge,
+        }
+    }
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn sign(secret: &[u8], payload: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn issue_state(secret: &[u8], expires_at: i64) -> String {
+    let payload = format!("{}.{}", hex::encode(random_bytes::<16>()), expires_at);
+    let signature = sign(secret, &payload);
+    format!("{}.{}", payload, signature)
+}
+
+/// Returns true if the state was signed with `secret` and has not expired.
+pub fn verify_state(secret: &[u8], state: &str, now: i64) -> bool {
+    let Some((payload, signature)) = state.rsplit_once('.') else {
+        return false;
+    };
+    let Some(expires_at) = payload
+        .split_once('.')
+        .and_then(|(_, exp)| exp.parse::<i64>().ok())
+    else {
+        return false;
+    };
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).is_ok() && now < expires_at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let state = issue_state(b"secret", 2_000);
+        assert!(verify_state(b"secret", &state, 1_000));
+        assert!(!verify_state(b"secret", &state, 2_000), "expired state");
+        assert!(!verify_state(b"other", &state, 1_000), "wrong secret");
+    }
+
+    #[test]
+    fn test_state_tampering() {
+        let state = issue_state(b"secret", 2_000);
+        let (nonce, rest) = state.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let extended = format!("{}.{}.{}", nonce, 9_999, signature);
+
+        assert!(!verify_state(b"secret", &extended, 1_000));
+        assert!(!verify_state(b"secret", "garbage", 1_000));
+        assert!(!verify_state(b"secret", "", 1_000));
+    }
+
+    #[test]
+    fn test_pkce_challenge_matches_verifier() {
+        let pair = PkcePair::generate();
+        let expected = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(pair.verifier.as_bytes()));
+        assert_eq!(pair.challenge, expected);
+        assert_eq!(pair.verifier.len(), 43);
+    }
+}
@@ -0,0 +1,50 @@
+//! At-rest encryption for provider refresh tokens (AES-256-GCM).
+//! Stored blobs are `nonce || ciphertext`.
+
+use aes_gcm::Nonce;
+use aes_gcm::aead::Aead;
+use anyhow::{Result, anyhow};
+use rand::RngCore;
+
+use crate::utils::aes_cipher;
+
+const NONCE_LEN: usize = 12;
+
+pub fn encrypt_token(key_material: &[u8], token: &str) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+
+    let ciphertext = aes_cipher(key_material)
+        .encrypt(Nonce::from_slice(&nonce), token.as_bytes())
+        .map_err(|_| anyhow!("Failed to encrypt token"))?;
+
+    let mut blob = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+    Ok(blob)
+}
+
+pub fn decrypt_token(key_material: &[u8], blob: &[u8]) -> Result<String> {
+    if blob.len() < NONCE_LEN {
+        return Err(anyhow!("Encrypted token is truncated"));
+    }
+    let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+
+    let plaintext = aes_cipher(key_material)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to decrypt token"))?;
+    Ok(String::from_utf8(plaintext)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_round_trip() {
+        let blob = encrypt_token(b"key", "refresh-token").unwrap();
+        assert_eq!(decrypt_token(b"key", &blob).unwrap(), "refresh-token");
+        assert!(decrypt_token(b"wrong", &blob).is_err());
+        assert!(decrypt_token(b"key", &blob[..4]).is_err());
+    }
+}
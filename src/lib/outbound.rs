@@ -0,0 +1,277 @@
+//! Requests to URLs the server does not choose itself: webhooks, OIDC
+//! discovery and the endpoints a discovery document names.
+//!
+//! Whoever controls such a URL could otherwise point the server at itself or
+//! at its private network. Requests go through [`send`] on a client from
+//! [`client_builder`]: the scheme and port must be in
+//! `OUTBOUND_ALLOWED_SCHEMES` and `OUTBOUND_ALLOWED_PORTS`, and the host may
+//! only be reached at public addresses unless `OUTBOUND_IP_ALLOWLIST` covers
+//! it. Names are checked as they are resolved for the connection, so one
+//! that resolves differently the second time cannot slip past, and every
+//! redirect is checked like the first request.
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::{Client, ClientBuilder, RequestBuilder, Response, Url};
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock};
+
+use crate::constants::OUTBOUND_MAX_REDIRECTS;
+use crate::ip_range::{IpRange, parse_ip_ranges};
+use crate::utils::ConfigHandle;
+
+/// Loopback, private, link-local, shared, multicast, documentation and
+/// reserved ranges, and the NAT64 and 6to4 prefixes, through which IPv6
+/// addresses reach IPv4 ones.
+static INTERNAL_RANGES: LazyLock<Vec<IpRange>> = LazyLock::new(|| {
+    parse_ip_ranges(
+        "0.0.0.0/8, 10.0.0.0/8, 100.64.0.0/10, 127.0.0.0/8, 169.254.0.0/16, \
+         172.16.0.0/12, 192.0.0.0/24, 192.0.2.0/24, 192.168.0.0/16, 198.18.0.0/15, \
+         198.51.100.0/24, 203.0.113.0/24, 224.0.0.0/4, 240.0.0.0/4, \
+         ::/128, ::1/128, 64:ff9b::/96, 64:ff9b:1::/48, 100::/64, 2001:db8::/32, \
+         2002::/16, fc00::/7, fe80::/10, ff00::/8",
+    )
+    .expect("internal ranges are valid")
+});
+
+/// Where outbound requests may go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundPolicy {
+    pub schemes: Vec<String>,
+    pub ports: Vec<u16>,
+    /// Addresses that may be reached although they are not public.
+    pub allowed_ips: Vec<IpRange>,
+}
+
+impl OutboundPolicy {
+    /// Refuses `url` if its scheme or port is not allowed, or its host is an
+    /// address that may not be reached. Names are checked when resolved.
+    pub fn check_url(&self, url: &Url) -> Result<(), OutboundError> {
+        if !self.schemes.iter().any(|s| s == url.scheme()) {
+            return Err(OutboundError::Refused(format!(
+                "scheme {} is not in OUTBOUND_ALLOWED_SCHEMES",
+                url.scheme()
+            )));
+        }
+        match url.port_or_known_default() {
+            Some(port) if self.ports.contains(&port) => {}
+            port => {
+                return Err(OutboundError::Refused(format!(
+                    "port {} is not in OUTBOUND_ALLOWED_PORTS",
+                    port.unwrap_or_default()
+                )));
+            }
+        }
+        let host = url.host_str().unwrap_or_default();
+        match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => self.check_ip(ip),
+            Err(_) => Ok(()),
+        }
+    }
+
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), OutboundError> {
+        if INTERNAL_RANGES.iter().any(|range| range.contains(ip))
+            && !self.allowed_ips.iter().any(|range| range.contains(ip))
+        {
+            return Err(OutboundError::Refused(format!(
+                "{} is not a public address",
+                ip
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum OutboundError {
+    /// The policy does not allow the destination.
+    Refused(String),
+    Http(reqwest::Error),
+}
+
+impl OutboundError {
+    /// Whether the policy refused the request, possibly only once a name
+    /// was resolved or a redirect followed. Such requests are not retried.
+    pub fn is_refused(&self) -> bool {
+        self.refusal().is_some()
+    }
+
+    fn refusal(&self) -> Option<&str> {
+        match self {
+            Self::Refused(reason) => Some(reason),
+            Self::Http(e) => {
+                let mut source = e.source();
+                while let Some(e) = source {
+                    if let Some(Self::Refused(reason)) = e.downcast_ref::<Self>() {
+                        return Some(reason);
+                    }
+                    source = e.source();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.refusal(), self) {
+            (Some(reason), _) => write!(f, "Refused outbound request: {}", reason),
+            (None, Self::Http(e)) => e.fmt(f),
+            (None, Self::Refused(_)) => unreachable!("refusals have a reason"),
+        }
+    }
+}
+
+impl Error for OutboundError {}
+
+/// A client builder that only connects to addresses the policy of `config`
+/// allows and checks every redirect. The policy is read per connection, so
+/// reloads apply to clients already built.
+pub fn client_builder(config: ConfigHandle) -> ClientBuilder {
+    let redirects = config.clone();
+    Client::builder()
+        .dns_resolver(Arc::new(PolicyResolver(config)))
+        .redirect(Policy::custom(move |attempt| {
+            if attempt.previous().len() >= OUTBOUND_MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            match redirects.load().outbound.check_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        }))
+}
+
+/// Sends `request`, made on a client from [`client_builder`], once its URL
+/// passes `policy`.
+pub async fn send(
+    policy: &OutboundPolicy,
+    request: RequestBuilder,
+) -> Result<Response, OutboundError> {
+    let (client, request) = request.build_split();
+    let request = request.map_err(OutboundError::Http)?;
+    policy.check_url(request.url())?;
+    client.execute(request).await.map_err(OutboundError::Http)
+}
+
+/// Resolves names with the system resolver and keeps the addresses the
+/// policy allows, failing if none are left.
+struct PolicyResolver(ConfigHandle);
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let config = self.0.clone();
+        Box::pin(async move {
+            let resolved = tokio::net::lookup_host((name.as_str(), 0)).await?;
+            let config = config.load();
+            let policy = &config.outbound;
+            let mut refused = None;
+            let allowed: Vec<_> = resolved
+                .filter(|addr| match policy.check_ip(addr.ip()) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        refused = Some(e);
+                        false
+                    }
+                })
+                .collect();
+            if allowed.is_empty() {
+                let e = refused.unwrap_or_else(|| {
+                    OutboundError::Refused(format!("{} has no addresses", name.as_str()))
+                });
+                return Err(Box::new(e) as Box<dyn Error + Send + Sync>);
+            }
+            Ok(Box::new(allowed.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Parses a comma-separated list of `http` and `https`.
+pub fn parse_schemes(s: &str) -> Option<Vec<String>> {
+    s.split(',')
+        .map(|scheme| scheme.trim().to_ascii_lowercase())
+        .filter(|scheme| !scheme.is_empty())
+        .map(|scheme| matches!(scheme.as_str(), "http" | "https").then_some(scheme))
+        .collect()
+}
+
+/// Parses a comma-separated list of ports.
+pub fn parse_ports(s: &str) -> Option<Vec<u16>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|port| !port.is_empty())
+        .map(|port| port.parse().ok().filter(|port| *port > 0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CONFIG;
+
+    fn policy(allowed_ips: &str) -> OutboundPolicy {
+        OutboundPolicy {
+            schemes: vec!["https".into()],
+            ports: vec![443, 8443],
+            allowed_ips: parse_ip_ranges(allowed_ips).unwrap(),
+        }
+    }
+
+    fn allowed(policy: &OutboundPolicy, url: &str) -> bool {
+        policy.check_url(&Url::parse(url).unwrap()).is_ok()
+    }
+
+    #[test]
+    fn test_check_url() {
+        let strict = policy("");
+        assert!(allowed(&strict, "https://hooks.example.com/a"));
+        assert!(allowed(&strict, "https://hooks.example.com:8443/a"));
+        assert!(allowed(&strict, "https://93.184.215.14/a"));
+        assert!(!allowed(&strict, "http://hooks.example.com/a"));
+        assert!(!allowed(&strict, "https://hooks.example.com:9000/a"));
+        assert!(!allowed(&strict, "https://127.0.0.1/a"));
+        assert!(!allowed(&strict, "https://10.1.2.3/a"));
+        assert!(!allowed(&strict, "https://169.254.169.254/latest"));
+        assert!(!allowed(&strict, "https://[::1]/a"));
+        assert!(!allowed(&strict, "https://[::ffff:192.168.0.1]/a"));
+        assert!(!allowed(&strict, "https://[fd00::1]/a"));
+        // NAT64 and 6to4 addresses that embed internal IPv4 ones.
+        assert!(!allowed(&strict, "https://[64:ff9b::a9fe:a9fe]/latest"));
+        assert!(!allowed(&strict, "https://[2002:a9fe:a9fe::1]/latest"));
+
+        let internal = policy("10.0.0.0/8");
+        assert!(allowed(&internal, "https://10.1.2.3/a"));
+        assert!(!allowed(&internal, "https://192.168.0.1/a"));
+    }
+
+    #[test]
+    fn test_parse_lists() {
+        assert_eq!(
+            parse_schemes("HTTPS, http"),
+            Some(vec!["https".to_string(), "http".to_string()])
+        );
+        assert_eq!(parse_schemes("https,ftp"), None);
+        assert_eq!(parse_ports("443, 8443"), Some(vec![443, 8443]));
+        assert_eq!(parse_ports("443,0"), None);
+        assert_eq!(parse_ports("https"), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_refuses_internal_hosts() {
+        let client = client_builder(CONFIG.clone()).build().unwrap();
+        let config = CONFIG.load();
+        let policy = &config.outbound;
+        let literal = send(policy, client.get("https://127.0.0.1/"))
+            .await
+            .unwrap_err();
+        assert!(literal.is_refused());
+        let resolved = send(policy, client.get("https://localhost/"))
+            .await
+            .unwrap_err();
+        assert!(resolved.is_refused(), "{}", resolved);
+        assert!(resolved.to_string().contains("not a public address"));
+    }
+}
@@ -0,0 +1,85 @@
+//! Reloading the configuration while the server runs.
+//!
+//! On SIGHUP, and whenever `CONFIG_FILE` changes on disk if
+//! `CONFIG_RELOAD_INTERVAL` is set, the file is read again on top of the
+//! environment, validated and handed to every tenant with its overrides.
+//! Quotas, allowlists, limits and secrets apply to the next request. A file
+//! that fails validation is logged and the running configuration kept.
+//! Settings only read at startup, such as the storage backend, listeners and
+//! OAuth applications, keep their value until the next restart.
+
+use anyhow::{Context, Result, bail};
+use std::fs;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::tenant::Tenants;
+use crate::tls::Hangup;
+
+/// Reads `CONFIG_FILE` and applies it to `tenants`. Returns the variables
+/// whose change needs a restart.
+pub fn reload(tenants: &Tenants) -> Result<Vec<&'static str>> {
+    let path = tenants.default_tenant().config.load().config_file.clone();
+    let next = Config::from_env_file(&path).with_context(|| format!("cannot read {}", path))?;
+
+    if let Err(issues) = next.validate() {
+        if !next.allow_invalid_config {
+            let issues: Vec<String> = issues.iter().map(|i| i.to_string()).collect();
+            bail!("{}", issues.join("; "));
+        }
+        for issue in &issues {
+            warn!("Configuration: {}", issue);
+        }
+    }
+
+    tenants.reload(&next)
+}
+
+/// Reloads on SIGHUP, and when `CONFIG_FILE`'s modification time changes,
+/// checked every `CONFIG_RELOAD_INTERVAL` (zero disables polling).
+pub async fn watch_config(tenants: Arc<Tenants>) {
+    let config = tenants.default_tenant().config.load();
+    let (path, interval) = (config.config_file.clone(), config.config_reload_interval);
+    let modified = || fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+    let mut hangup = Hangup::listen();
+    let mut last_modified: Option<SystemTime> = modified();
+
+    loop {
+        let poll = async {
+            if interval.is_zero() {
+                std::future::pending::<()>().await;
+            }
+            tokio::time::sleep(interval).await;
+        };
+
+        tokio::select! {
+            _ = hangup.recv() => info!("Received SIGHUP, reloading configuration"),
+            _ = poll => {
+                if modified() == last_modified {
+                    continue;
+                }
+                info!("{} changed on disk, reloading configuration", path);
+            }
+        }
+
+        last_modified = modified();
+        match reload(&tenants) {
+            Ok(ignored) => {
+                info!("Reloaded configuration");
+                if !ignored.is_empty() {
+                    warn!(
+                        "Changes to {} take effect after a restart",
+                        ignored.join(", ")
+                    );
+                }
+            }
+            Err(e) => warn!(
+                "Failed to reload configuration, keeping the current one: {:#}",
+                e
+            ),
+        }
+    }
+}
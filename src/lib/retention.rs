@@ -0,0 +1,152 @@
+//! Optional expiry of inactive accounts.
+//!
+//! An account is inactive when neither its v1 settings nor any v2 data key
+//! has been written for `INACTIVITY_TTL_DAYS`. The sweeper first marks such
+//! accounts and only purges them once they are still inactive after
+//! `INACTIVITY_GRACE_DAYS`; any write in between clears the mark. Accounts
+//! with v2 data but no settings row are not visited.
+
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::config::{Config, ConfigHandle};
+use crate::constants::MS_PER_DAY;
+use crate::database::DatabaseService;
+use crate::events::{Event, EventBus};
+use crate::metrics::{Metrics, RetentionStats};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionAction {
+    Keep,
+    Mark,
+    Unmark,
+    Purge,
+}
+
+pub fn decide(
+    last_active: i64,
+    marked_at: Option<i64>,
+    now: i64,
+    ttl_ms: i64,
+    grace_ms: i64,
+) -> RetentionAction {
+    let inactive = last_active < now - ttl_ms;
+    match (inactive, marked_at) {
+        (false, None) => RetentionAction::Keep,
+        (false, Some(_)) => RetentionAction::Unmark,
+        (true, None) => RetentionAction::Mark,
+        (true, Some(marked_at)) if marked_at <= now - grace_ms => RetentionAction::Purge,
+        (true, Some(_)) => RetentionAction::Keep,
+    }
+}
+
+pub async fn sweep_once(db: &DatabaseService, config: &Config) -> anyhow::Result<RetentionStats> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let ttl_ms = config.inactivity_ttl_days as i64 * MS_PER_DAY;
+    let grace_ms = config.inactivity_grace_days as i64 * MS_PER_DAY;
+    let mut stats = RetentionStats::default();
+
+    for candidate in db.list_retention_candidates().await? {
+        if candidate.updated_at >= now - ttl_ms && candidate.retention_marked_at.is_none() {
+            continue;
+        }
+
+        let (last_write, data_size) = db.data_activity_by_hash(&candidate.user_hash).await?;
+        let last_active = last_write.unwrap_or(0).max(candidate.updated_at);
+        let marked_at = candidate.retention_marked_at;
+
+        let result = match decide(last_active, marked_at, now, ttl_ms, grace_ms) {
+            RetentionAction::Keep => Ok(()),
+            RetentionAction::Mark => {
+                stats.accounts_marked += 1;
+                db.set_retention_mark(&candidate.user_hash, Some(now)).await
+            }
+            RetentionAction::Unmark => db.set_retention_mark(&candidate.user_hash, None).await,
+            RetentionAction::Purge => {
+                let settings_size = db
+                    .get_settings_size_by_hash(&candidate.user_hash)
+                    .await
+                    .unwrap_or(0);
+                let purged = db.purge_account_by_hash(&candidate.user_hash).await;
+                if purged.is_ok() {
+                    stats.accounts_purged += 1;
+                    stats.bytes_reclaimed += (settings_size + data_size).max(0) as u64;
+                }
+                purged.map(|_| ())
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("Retention sweep failed for one account: {}", e);
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Runs [`sweep_once`] every `RETENTION_SWEEP_INTERVAL` until the process
+/// exits.
+pub async fn run_sweeper(
+    db: DatabaseService,
+    config: ConfigHandle,
+    metrics: Arc<Metrics>,
+    events: EventBus,
+) {
+    let current = config.load();
+    info!(
+        "Inactive account expiry enabled: {} days plus {} days grace",
+        current.inactivity_ttl_days, current.inactivity_grace_days
+    );
+
+    let mut interval = tokio::time::interval(current.retention_sweep_interval);
+    loop {
+        interval.tick().await;
+
+        match sweep_once(&db, &config.load()).await {
+            Ok(stats) => {
+                if stats.accounts_marked > 0 || stats.accounts_purged > 0 {
+                    info!(
+                        "Retention sweep: {} marked, {} purged, {} bytes reclaimed",
+                        stats.accounts_marked, stats.accounts_purged, stats.bytes_reclaimed
+                    );
+                }
+                if stats.accounts_purged > 0 {
+                    events.publish(Event::AccountsExpired {
+                        count: stats.accounts_purged,
+                    });
+                }
+                metrics.record_retention(stats);
+            }
+            Err(e) => error!("Retention sweep failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let now = 100 * MS_PER_DAY;
+        let ttl = 30 * MS_PER_DAY;
+        let grace = 7 * MS_PER_DAY;
+        let stale = now - 31 * MS_PER_DAY;
+        let fresh = now - MS_PER_DAY;
+
+        assert_eq!(decide(fresh, None, now, ttl, grace), RetentionAction::Keep);
+        assert_eq!(
+            decide(fresh, Some(stale), now, ttl, grace),
+            RetentionAction::Unmark
+        );
+        assert_eq!(decide(stale, None, now, ttl, grace), RetentionAction::Mark);
+        assert_eq!(
+            decide(stale, Some(now - MS_PER_DAY), now, ttl, grace),
+            RetentionAction::Keep
+        );
+        assert_eq!(
+            decide(stale, Some(now - grace), now, ttl, grace),
+            RetentionAction::Purge
+        );
+    }
+}
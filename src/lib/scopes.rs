@@ -0,0 +1,223 @@
+//! What a token may do.
+//!
+//! Sessions can be issued with fewer than every scope and limited to keys
+//! under one prefix. Permanent secrets, sessions issued before scopes existed
+//! and sessions issued without asking for less hold every scope and reach
+//! every key.
+
+use axum::http::Method;
+use base64::prelude::*;
+use std::collections::BTreeSet;
+
+use crate::constants::MAX_KEY_NAME_LEN;
+use crate::error::AppError;
+
+/// Separates the granted scopes from the encoded key prefix.
+const PREFIX_SEPARATOR: char = '~';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Read,
+    Write,
+    Delete,
+    /// Account-wide operations: deleting the account and linking
+    /// identities.
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Self; 4] = [Self::Read, Self::Write, Self::Delete, Self::Admin];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Delete => "delete",
+            Self::Admin => "admin",
+        }
+    }
+
+    fn letter(self) -> char {
+        match self {
+            Self::Read => 'r',
+            Self::Write => 'w',
+            Self::Delete => 'd',
+            Self::Admin => 'a',
+        }
+    }
+
+    /// The scope every request with `method` needs, whatever else its
+    /// handler checks.
+    pub fn for_method(method: &Method) -> Self {
+        match *method {
+            Method::GET | Method::HEAD => Self::Read,
+            Method::DELETE => Self::Delete,
+            _ => Self::Write,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenScopes {
+    granted: BTreeSet<Scope>,
+    prefix: Option<String>,
+}
+
+impl TokenScopes {
+    pub fn full() -> Self {
+        Self::new(Scope::ALL, None)
+    }
+
+    pub fn new(granted: impl IntoIterator<Item = Scope>, prefix: Option<String>) -> Self {
+        Self {
+            granted: granted.into_iter().collect(),
+            prefix,
+        }
+    }
+
+    /// Scopes a client asked for: a comma-separated list of scope names and
+    /// an optional key prefix.
+    pub fn from_request(scopes: &str, prefix: Option<&str>) -> Result<Self, String> {
+        let granted = scopes
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Scope::ALL
+                    .into_iter()
+                    .find(|scope| scope.name() == name)
+                    .ok_or_else(|| format!("unknown scope {:?}", name))
+            })
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        if granted.is_empty() {
+            return Err("at least one scope is needed".into());
+        }
+        if let Some(prefix) = prefix
+            && (prefix.is_empty() || prefix.len() > MAX_KEY_NAME_LEN)
+        {
+            return Err(format!(
+                "prefix must be between 1 and {} bytes",
+                MAX_KEY_NAME_LEN
+            ));
+        }
+        Ok(Self::new(granted, prefix.map(str::to_string)))
+    }
+
+    /// Whether the token may do everything, as tokens without scopes may.
+    pub fn is_full(&self) -> bool {
+        self.prefix.is_none() && Scope::ALL.iter().all(|s| self.granted.contains(s))
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.granted.iter().map(|s| s.name()).collect()
+    }
+
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    pub fn has(&self, scope: Scope) -> bool {
+        self.granted.contains(&scope)
+    }
+
+    /// Whether `key` lies under the token's prefix, if it has one.
+    pub fn covers(&self, key: &str) -> bool {
+        self.prefix.as_deref().is_none_or(|p| key.starts_with(p))
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), AppError> {
+        if self.has(scope) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "Token lacks the {} scope",
+                scope.name()
+            )))
+        }
+    }
+
+    /// [`Self::require`] for an operation on one data key.
+    pub fn require_key(&self, scope: Scope, key: &str) -> Result<(), AppError> {
+        self.require(scope)?;
+        if self.covers(key) {
+            Ok(())
+        } else {
+            Err(self.outside_prefix())
+        }
+    }
+
+    /// [`Self::require`] for an operation on more than keys under one
+    /// prefix, such as settings or the whole account.
+    pub fn require_account(&self, scope: Scope) -> Result<(), AppError> {
+        self.require(scope)?;
+        match self.prefix {
+            None => Ok(()),
+            Some(_) => Err(self.outside_prefix()),
+        }
+    }
+
+    fn outside_prefix(&self) -> AppError {
+        AppError::Forbidden(format!(
+            "Token is limited to keys under {:?}",
+            self.prefix.as_deref().unwrap_or_default()
+        ))
+    }
+
+    /// The form scopes take inside a session secret: a letter per scope,
+    /// then the prefix in URL-safe base64 if there is one.
+    pub fn encode(&self) -> String {
+        let mut encoded: String = self.granted.iter().map(|s| s.letter()).collect();
+        if let Some(prefix) = &self.prefix {
+            encoded.push(PREFIX_SEPARATOR);
+            encoded.push_str(&BASE64_URL_SAFE_NO_PAD.encode(prefix));
+        }
+        encoded
+    }
+
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let (letters, prefix) = match encoded.split_once(PREFIX_SEPARATOR) {
+            Some((letters, prefix)) => {
+                let prefix = BASE64_URL_SAFE_NO_PAD.decode(prefix).ok()?;
+                (letters, Some(String::from_utf8(prefix).ok()?))
+            }
+            None => (encoded, None),
+        };
+        let granted = letters
+            .chars()
+            .map(|c| Scope::ALL.into_iter().find(|s| s.letter() == c))
+            .collect::<Option<BTreeSet<_>>>()?;
+        Some(Self { granted, prefix })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_round_trip() {
+        let scopes = TokenScopes::from_request("read, write", Some("plugins/x.y:z")).unwrap();
+        assert_eq!(TokenScopes::decode(&scopes.encode()), Some(scopes.clone()));
+        assert!(!scopes.encode().contains(['.', ':']));
+        assert!(!scopes.is_full());
+        assert!(TokenScopes::full().is_full());
+        assert_eq!(TokenScopes::decode("rx"), None);
+    }
+
+    #[test]
+    fn test_from_request_rejects_bad_input() {
+        assert!(TokenScopes::from_request("read,owner", None).is_err());
+        assert!(TokenScopes::from_request(" , ", None).is_err());
+        assert!(TokenScopes::from_request("read", Some("")).is_err());
+    }
+
+    #[test]
+    fn test_prefix_limits_keys() {
+        let scopes = TokenScopes::from_request("read,write", Some("plugins/")).unwrap();
+        assert!(scopes.require_key(Scope::Write, "plugins/a").is_ok());
+        assert!(scopes.require_key(Scope::Write, "themes/a").is_err());
+        assert!(scopes.require_key(Scope::Delete, "plugins/a").is_err());
+        assert!(scopes.require_account(Scope::Read).is_err());
+        assert!(TokenScopes::full().require_account(Scope::Admin).is_ok());
+    }
+}
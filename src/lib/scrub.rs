@@ -0,0 +1,258 @@
+//! Consistency scrub of stored data keys.
+//!
+//! The manifest clients sync against is read from the data rows: each row
+//! records a key's version, checksum and size next to the value, which may
+//! live in the blob store. A scrub reads every live value back and checks it
+//! against its row: that it can still be read, that its checksum matches
+//! and that its size does. Checksum mismatches are recorded as a read would
+//! record them. Sizes are what quotas add up, so a repairing scrub corrects
+//! recorded sizes that disagree with the value; corrupt and unreadable values
+//! can only come back from a backup and are reported.
+//!
+//! `equicloud_admin scrub` runs one pass. With `SCRUB_INTERVAL` set the
+//! server runs one every interval, repairing when `SCRUB_REPAIR` is set.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::config::ConfigHandle;
+use crate::database::{ScrubStats, find_corruption};
+use crate::datastore::{Datastore, Storage};
+use crate::metrics::Metrics;
+
+/// What is wrong with a stored value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum ScrubProblem {
+    /// The value no longer matches its checksum.
+    Corrupt {
+        stored_checksum: String,
+        computed_checksum: String,
+    },
+    /// The recorded size is not the size of the value.
+    SizeMismatch { recorded: i64, actual: i64 },
+    /// The value could not be read, e.g. because its blob is gone.
+    Unreadable { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScrubFinding {
+    /// Hashed user id.
+    pub user: String,
+    pub key: String,
+    pub version: i64,
+    #[serde(flatten)]
+    pub problem: ScrubProblem,
+    /// Whether the scrub fixed it.
+    pub repaired: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrubReport {
+    /// Whether findings were repaired where possible.
+    pub repair: bool,
+    pub users: u64,
+    /// Live values read back.
+    pub checked: u64,
+    pub corrupt: u64,
+    pub size_mismatches: u64,
+    pub unreadable: u64,
+    pub repaired: u64,
+    pub findings: Vec<ScrubFinding>,
+}
+
+impl ScrubReport {
+    /// Whether nothing was found, or everything found was repaired.
+    pub fn is_clean(&self) -> bool {
+        self.findings.iter().all(|finding| finding.repaired)
+    }
+
+    pub fn stats(&self) -> ScrubStats {
+        ScrubStats {
+            checked: self.checked,
+            corrupt: self.corrupt,
+            unreadable: self.unreadable,
+        }
+    }
+
+    fn add(&mut self, user: &str, key: &str, version: i64, problem: ScrubProblem, repaired: bool) {
+        match problem {
+            ScrubProblem::Corrupt { .. } => self.corrupt += 1,
+            ScrubProblem::SizeMismatch { .. } => self.size_mismatches += 1,
+            ScrubProblem::Unreadable { .. } => self.unreadable += 1,
+        }
+        if repaired {
+            self.repaired += 1;
+        }
+        self.findings.push(ScrubFinding {
+            user: user.to_string(),
+            key: key.to_string(),
+            version,
+            problem,
+            repaired,
+        });
+    }
+}
+
+/// Scrubs every user. A user whose manifest cannot be read fails the scrub;
+/// a value that cannot be read is a finding.
+pub async fn scrub(db: &Storage, repair: bool) -> Result<ScrubReport> {
+    let mut report = ScrubReport {
+        repair,
+        ..ScrubReport::default()
+    };
+    for user_hash in db.list_user_hashes().await? {
+        scrub_user(db, &user_hash, repair, &mut report).await?;
+    }
+    Ok(report)
+}
+
+/// Scrubs the live keys of the user with this hashed id into `report`.
+pub async fn scrub_user(
+    db: &Storage,
+    user_hash: &str,
+    repair: bool,
+    report: &mut ScrubReport,
+) -> Result<()> {
+    report.users += 1;
+    for meta in db.get_data_manifest_by_hash(user_hash).await? {
+        if meta.deleted {
+            continue;
+        }
+        let entry = match db.get_data_key_by_hash(user_hash, &meta.key).await {
+            Ok(Some(entry)) => entry,
+            // Deleted or expired since the manifest was read.
+            Ok(None) => continue,
+            Err(e) => {
+                let error = format!("{:#}", e);
+                let problem = ScrubProblem::Unreadable { error };
+                report.add(user_hash, &meta.key, meta.version, problem, false);
+                continue;
+            }
+        };
+        report.checked += 1;
+
+        if let Some(corrupt) = find_corruption(&entry) {
+            if let Err(e) = db.record_corruption_by_hash(user_hash, &corrupt).await {
+                warn!("Failed to record corrupt value: {}", e);
+            }
+            let problem = ScrubProblem::Corrupt {
+                stored_checksum: corrupt.stored_checksum,
+                computed_checksum: corrupt.computed_checksum,
+            };
+            report.add(user_hash, &entry.key, entry.version, problem, false);
+        }
+
+        let actual = entry.value.len() as i64;
+        if actual != entry.size_bytes as i64 {
+            let repaired = repair
+                && db
+                    .set_data_size_by_hash(user_hash, &entry.key, entry.version, actual as i32)
+                    .await?;
+            let problem = ScrubProblem::SizeMismatch {
+                recorded: entry.size_bytes as i64,
+                actual,
+            };
+            report.add(user_hash, &entry.key, entry.version, problem, repaired);
+        }
+    }
+    Ok(())
+}
+
+/// Scrubs every user every `interval`, repairing when `SCRUB_REPAIR` is set
+/// at the time of the pass.
+pub async fn run_scrubber(
+    db: Storage,
+    config: ConfigHandle,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let repair = config.load().scrub_repair;
+        match scrub(&db, repair).await {
+            Ok(report) => {
+                metrics.record_scrub(report.stats());
+                if report.findings.is_empty() {
+                    info!("Scrub checked {} values, all intact", report.checked);
+                } else {
+                    error!(
+                        "Scrub checked {} values: {} corrupt, {} unreadable, {} with a wrong size, {} repaired",
+                        report.checked,
+                        report.corrupt,
+                        report.unreadable,
+                        report.size_mismatches,
+                        report.repaired
+                    );
+                }
+            }
+            Err(e) => error!("Scrub failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DataUpload;
+    use crate::datastore::SqliteDatastore;
+    use crate::utils::{compute_checksum, hash_user_id};
+
+    fn upload(key: &str, value: &[u8], checksum: &[u8]) -> DataUpload {
+        DataUpload {
+            key: key.into(),
+            value: value.to_vec(),
+            checksum: compute_checksum(checksum),
+            ttl_secs: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scrub() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        db.save_data_keys_batch(
+            "1",
+            vec![
+                upload("plugins/good", b"good", b"good"),
+                upload("plugins/rotten", b"rotten", b"pristine"),
+                upload("plugins/resized", b"resized", b"resized"),
+            ],
+        )
+        .await
+        .unwrap();
+        db.delete_data_key("1", "plugins/good").await.unwrap();
+        let user = hash_user_id("1");
+        assert!(
+            db.set_data_size_by_hash(&user, "plugins/resized", 1, 3)
+                .await
+                .unwrap()
+        );
+
+        let report = scrub(&db, false).await.unwrap();
+        assert_eq!((report.users, report.checked), (1, 2));
+        assert_eq!((report.corrupt, report.size_mismatches), (1, 1));
+        assert!(!report.is_clean());
+        assert_eq!(
+            report.findings[0].problem,
+            ScrubProblem::SizeMismatch {
+                recorded: 3,
+                actual: 7
+            }
+        );
+        assert_eq!(db.list_corruption("1").await.unwrap().len(), 1);
+        assert_eq!(db.get_user_total_size("1").await.unwrap(), 9);
+
+        let report = scrub(&db, true).await.unwrap();
+        assert_eq!(report.repaired, 1);
+        assert!(report.findings[0].repaired);
+        assert_eq!(db.get_user_total_size("1").await.unwrap(), 13);
+
+        let report = scrub(&db, true).await.unwrap();
+        assert_eq!((report.size_mismatches, report.corrupt), (0, 1));
+    }
+}
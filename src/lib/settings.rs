@@ -0,0 +1,287 @@
+//! The rules of the settings backup, shared by `/v1/settings` and gRPC.
+//!
+//! Each user has one settings blob, identified by when it was written: that
+//! time in milliseconds is its version and, quoted, its ETag. Writes must
+//! not be empty, are capped at `MAX_BACKUP_SIZE_BYTES`, are checked to be
+//! JSON with `SETTINGS_JSON_VALIDATION` and may be made conditional with
+//! `If-Match`; the last `SETTINGS_HISTORY_VERSIONS` versions are kept to
+//! diff from.
+
+use axum::http::HeaderMap;
+
+use crate::datastore::Datastore;
+use crate::error::{AppError, ResultExt};
+use crate::etag::{self, ETag};
+use crate::events::{Event, EventBus};
+use crate::tenant::Tenant;
+use crate::validation;
+
+/// A user's stored settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub value: Vec<u8>,
+    /// When they were written, in milliseconds.
+    pub written: i64,
+}
+
+impl Settings {
+    pub fn etag(&self) -> ETag {
+        ETag::strong(self.written.to_string())
+    }
+}
+
+/// What changed since a version of the settings a client has.
+#[derive(Debug)]
+pub enum SettingsSince {
+    /// The client's version is the current one.
+    Unchanged(Settings),
+    Changed {
+        base: Vec<u8>,
+        current: Settings,
+    },
+}
+
+/// Reads and writes one tenant's settings.
+pub struct SettingsService<'a> {
+    tenant: &'a Tenant,
+}
+
+impl<'a> SettingsService<'a> {
+    pub fn new(tenant: &'a Tenant) -> Self {
+        Self { tenant }
+    }
+
+    /// When the user's settings were written, without reading them.
+    pub async fn written(&self, user_id: &str) -> Result<Option<i64>, AppError> {
+        let written = self
+            .tenant
+            .db
+            .get_settings_metadata(user_id)
+            .await
+            .or_internal("Failed to retrieve settings")?;
+        Ok(written.and_then(|w| w.parse().ok()))
+    }
+
+    /// The user's settings; `NotFound` if they have none.
+    pub async fn get(&self, user_id: &str) -> Result<Settings, AppError> {
+        let (value, written) = self
+            .tenant
+            .db
+            .get_user_settings(user_id)
+            .await
+            .or_internal("Failed to retrieve settings")?
+            .ok_or(AppError::NotFound)?;
+        Ok(Settings {
+            value,
+            written: written.parse().unwrap_or_default(),
+        })
+    }
+
+    /// The checksum of `value` in the tenant's `CHECKSUM_ALGORITHM`.
+    pub fn checksum(&self, value: &[u8]) -> String {
+        self.tenant.config.load().checksum_algorithm.compute(value)
+    }
+
+    /// The current settings and, unless it is current, the version the
+    /// client has, named by its ETag. `NotFound` when that version is no
+    /// longer kept.
+    pub async fn since(&self, user_id: &str, from_etag: &str) -> Result<SettingsSince, AppError> {
+        let from = ETag::parse(from_etag)
+            .and_then(|etag| etag.tag().parse::<i64>().ok())
+            .ok_or_else(|| AppError::BadRequest("from_etag is not a settings ETag".into()))?;
+
+        let current = self.get(user_id).await?;
+        if current.written == from {
+            return Ok(SettingsSince::Unchanged(current));
+        }
+
+        let base = self
+            .tenant
+            .db
+            .get_settings_version(user_id, from)
+            .await
+            .or_internal("Failed to retrieve settings")?
+            .ok_or(AppError::NotFound)?;
+        Ok(SettingsSince::Changed { base, current })
+    }
+
+    /// Saves `value` as the user's settings and returns when they were
+    /// written. `preconditions` are the request's headers, of which only
+    /// `If-Match` is checked; callers without any pass an empty map.
+    pub async fn save(
+        &self,
+        events: &EventBus,
+        user_id: &str,
+        value: Vec<u8>,
+        preconditions: &HeaderMap,
+    ) -> Result<i64, AppError> {
+        validation::check_settings(&value, &self.tenant.config.load())?;
+
+        if preconditions.contains_key("if-match") {
+            let current = self
+                .written(user_id)
+                .await?
+                .map(|written| ETag::strong(written.to_string()));
+            if !etag::precondition_holds(preconditions, current.as_ref()) {
+                return Err(AppError::PreconditionFailed);
+            }
+        }
+
+        let written = self
+            .tenant
+            .db
+            .save_user_settings(user_id, value)
+            .await
+            .or_internal("Failed to save settings")?;
+
+        events.publish(Event::SettingsWritten {
+            user_id: user_id.to_string(),
+            written,
+        });
+        Ok(written)
+    }
+
+    pub async fn delete(&self, events: &EventBus, user_id: &str) -> Result<(), AppError> {
+        self.tenant
+            .db
+            .delete_user_settings(user_id)
+            .await
+            .or_internal("Failed to delete settings")?;
+
+        events.publish(Event::SettingsDeleted {
+            user_id: user_id.to_string(),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ConfigHandle};
+    use crate::datastore::{SqliteDatastore, Storage};
+    use crate::tenant::DEFAULT_TENANT;
+    use axum::http::HeaderValue;
+
+    fn tenant() -> Tenant {
+        let config =
+            Config::from_lookup(|var| (var == "MAX_BACKUP_SIZE_BYTES").then(|| "16".to_string()));
+        Tenant::new(
+            DEFAULT_TENANT,
+            ConfigHandle::new(config),
+            Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()),
+        )
+    }
+
+    fn if_match(etag: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("if-match", HeaderValue::from_str(etag).unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_save_and_get() {
+        let tenant = tenant();
+        let settings = SettingsService::new(&tenant);
+        let events = EventBus::new();
+        let mut published = events.subscribe();
+
+        assert!(matches!(settings.get("1").await, Err(AppError::NotFound)));
+        let written = settings
+            .save(&events, "1", b"settings".to_vec(), &HeaderMap::new())
+            .await
+            .unwrap();
+
+        let stored = settings.get("1").await.unwrap();
+        assert_eq!(stored.value, b"settings");
+        assert_eq!(stored.written, written);
+        assert_eq!(stored.etag().to_string(), format!("\"{}\"", written));
+        assert_eq!(settings.written("1").await.unwrap(), Some(written));
+        assert!(matches!(
+            published.try_recv(),
+            Ok(Event::SettingsWritten { written: w, .. }) if w == written
+        ));
+
+        settings.delete(&events, "1").await.unwrap();
+        assert_eq!(settings.written("1").await.unwrap(), None);
+        assert!(matches!(
+            published.try_recv(),
+            Ok(Event::SettingsDeleted { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_save_checks_size_and_if_match() {
+        let tenant = tenant();
+        let settings = SettingsService::new(&tenant);
+        let events = EventBus::new();
+
+        assert!(matches!(
+            settings
+                .save(&events, "1", vec![0; 17], &HeaderMap::new())
+                .await,
+            Err(AppError::PayloadTooLarge(_))
+        ));
+        assert!(matches!(
+            settings
+                .save(&events, "1", b"a".to_vec(), &if_match("*"))
+                .await,
+            Err(AppError::PreconditionFailed)
+        ));
+
+        let written = settings
+            .save(&events, "1", b"a".to_vec(), &HeaderMap::new())
+            .await
+            .unwrap();
+        assert!(matches!(
+            settings
+                .save(&events, "1", b"b".to_vec(), &if_match("\"0\""))
+                .await,
+            Err(AppError::PreconditionFailed)
+        ));
+        let current = format!("\"{}\"", written);
+        settings
+            .save(&events, "1", b"b".to_vec(), &if_match(&current))
+            .await
+            .unwrap();
+        assert_eq!(settings.get("1").await.unwrap().value, b"b");
+    }
+
+    #[tokio::test]
+    async fn test_since() {
+        let tenant = tenant();
+        let settings = SettingsService::new(&tenant);
+        let events = EventBus::new();
+
+        let first = settings
+            .save(&events, "1", b"one".to_vec(), &HeaderMap::new())
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        settings
+            .save(&events, "1", b"two".to_vec(), &HeaderMap::new())
+            .await
+            .unwrap();
+
+        match settings.since("1", &format!("\"{}\"", first)).await {
+            Ok(SettingsSince::Changed { base, current }) => {
+                assert_eq!(base, b"one");
+                assert_eq!(current.value, b"two");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let current = settings.get("1").await.unwrap().etag().to_string();
+        assert!(matches!(
+            settings.since("1", &current).await,
+            Ok(SettingsSince::Unchanged(_))
+        ));
+        assert!(matches!(
+            settings.since("1", "\"1\"").await,
+            Err(AppError::NotFound)
+        ));
+        assert!(matches!(
+            settings.since("1", "\"abc\"").await,
+            Err(AppError::BadRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+//! Daily usage statistics.
+//!
+//! Every user making an authenticated request is marked active for the UTC
+//! day, once per instance, and every sync is counted. Each hour the current
+//! day is rolled up into a row of aggregates, together with the users and
+//! bytes stored at that time; the day before is rolled up once more after
+//! midnight so its row covers the whole day. `GET /admin/stats` exports the
+//! rows of a range of days as JSON or CSV.
+
+use chrono::{NaiveDate, NaiveTime};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::audit::day_bucket;
+use crate::constants::{MS_PER_DAY, STATS_ROLLUP_INTERVAL_SECS};
+use crate::database::DailyStats;
+use crate::datastore::{Datastore, Storage};
+use crate::tenant::Tenant;
+
+/// Users this process has marked active today, for one tenant.
+#[derive(Default)]
+pub struct ActivityTracker {
+    seen: Mutex<(String, HashSet<String>)>,
+}
+
+impl ActivityTracker {
+    /// True the first time `user_id` is seen on `day`.
+    fn first_seen(&self, day: &str, user_id: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.0 != day {
+            *seen = (day.to_string(), HashSet::new());
+        }
+        seen.1.insert(user_id.to_string())
+    }
+
+    fn forget(&self, user_id: &str) {
+        self.seen.lock().unwrap().1.remove(user_id);
+    }
+}
+
+/// Marks `user_id` active today unless this process already has. A failure
+/// is logged and retried on the user's next request.
+pub async fn mark_active(tenant: &Tenant, user_id: &str) {
+    let day = day_bucket(chrono::Utc::now().timestamp_millis());
+    if !tenant.activity.first_seen(&day, user_id) {
+        return;
+    }
+    if let Err(e) = tenant.db.mark_user_active(user_id, &day).await {
+        tenant.activity.forget(user_id);
+        warn!("Failed to mark a user active: {}", e);
+    }
+}
+
+/// Counts a sync made today. A failure is logged rather than returned.
+pub async fn count_sync(db: &Storage) {
+    let day = day_bucket(chrono::Utc::now().timestamp_millis());
+    if let Err(e) = db.add_daily_syncs(&day, 1).await {
+        warn!("Failed to count a sync: {}", e);
+    }
+}
+
+/// Rolls up `day` as of `now` and saves it over any earlier rollup.
+pub async fn roll_up(db: &Storage, day: &str, now: i64) -> anyhow::Result<DailyStats> {
+    let (active_users, syncs) = db.get_daily_activity(day).await?;
+    let mut users = 0;
+    let mut bytes_stored = 0;
+    for user_hash in db.list_user_hashes().await? {
+        users += 1;
+        bytes_stored += db.user_activity_by_hash(&user_hash).await?.size_bytes;
+    }
+
+    let stats = DailyStats {
+        day: day.to_string(),
+        active_users,
+        users,
+        bytes_stored,
+        syncs,
+        computed_at: now,
+    };
+    db.save_daily_stats(&stats).await?;
+    Ok(stats)
+}
+
+/// Rolls up today and, unless that was already done after midnight,
+/// yesterday.
+pub async fn roll_up_due(db: &Storage, now: i64) -> anyhow::Result<()> {
+    let today = day_bucket(now);
+    let midnight = now - now.rem_euclid(MS_PER_DAY);
+    let yesterday = day_bucket(midnight - 1);
+    let final_done = db
+        .get_daily_stats(&yesterday)
+        .await?
+        .is_some_and(|stats| stats.computed_at >= midnight);
+    if !final_done {
+        roll_up(db, &yesterday, now).await?;
+    }
+    roll_up(db, &today, now).await?;
+    Ok(())
+}
+
+/// Runs [`roll_up_due`] every `STATS_ROLLUP_INTERVAL_SECS` until the process
+/// exits.
+pub async fn run_rollup(db: Storage) {
+    let mut interval = tokio::time::interval(Duration::from_secs(STATS_ROLLUP_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = roll_up_due(&db, chrono::Utc::now().timestamp_millis()).await {
+            error!("Stats rollup failed: {}", e);
+        }
+    }
+}
+
+/// Parses a `YYYY-MM-DD` day into milliseconds at its start.
+pub fn parse_day(day: &str) -> Option<i64> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_time(NaiveTime::MIN).and_utc().timestamp_millis())
+}
+
+/// The rolled-up rows of the days from `from` to `to`, both inclusive and
+/// in milliseconds at their start. Days never rolled up are left out.
+pub async fn export(db: &Storage, from: i64, to: i64) -> anyhow::Result<Vec<DailyStats>> {
+    let mut days = Vec::new();
+    let mut at = from;
+    while at <= to {
+        if let Some(stats) = db.get_daily_stats(&day_bucket(at)).await? {
+            days.push(stats);
+        }
+        at += MS_PER_DAY;
+    }
+    Ok(days)
+}
+
+/// `days` as CSV with a header line.
+pub fn to_csv(days: &[DailyStats]) -> String {
+    let mut csv = String::from("day,active_users,users,bytes_stored,syncs,computed_at\n");
+    for d in days {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{}",
+            d.day, d.active_users, d.users, d.bytes_stored, d.syncs, d.computed_at
+        );
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigHandle;
+    use crate::datastore::SqliteDatastore;
+
+    #[tokio::test]
+    async fn test_roll_up_and_export() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let tenant = Tenant::new(
+            "default",
+            ConfigHandle::new(crate::config::Config::from_lookup(|_| None)),
+            db.clone(),
+        );
+        db.save_user_settings("1", vec![0; 10]).await.unwrap();
+        db.save_user_settings("2", vec![0; 5]).await.unwrap();
+
+        mark_active(&tenant, "1").await;
+        mark_active(&tenant, "1").await;
+        count_sync(&db).await;
+        count_sync(&db).await;
+
+        let now = chrono::Utc::now().timestamp_millis();
+        roll_up_due(&db, now).await.unwrap();
+        let today = db.get_daily_stats(&day_bucket(now)).await.unwrap().unwrap();
+        assert_eq!(
+            (
+                today.active_users,
+                today.users,
+                today.bytes_stored,
+                today.syncs
+            ),
+            (1, 2, 15, 2)
+        );
+        let yesterday = db
+            .get_daily_stats(&day_bucket(now - MS_PER_DAY))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(yesterday.active_users, 0);
+
+        let start = parse_day(&today.day).unwrap();
+        let days = export(&db, start - 2 * MS_PER_DAY, start).await.unwrap();
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[1], today);
+        let csv = to_csv(&days);
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.ends_with(&format!("{},1,2,15,2,{}\n", today.day, now)));
+    }
+}
@@ -0,0 +1,138 @@
+//! Continuation cursors for paged sync downloads.
+//!
+//! A cursor has the form `<expires_at_ms>.<payload>.<signature>`. The payload
+//! is the keys still to download with their sizes, as compressed JSON in
+//! URL-safe base64, and the signature is an HMAC-SHA256 over the user id,
+//! expiry and payload, keyed with the tenant's `SESSION_SECRET`. The server keeps nothing
+//! between pages, and a cursor cannot be replayed by another user or edited
+//! to reach keys the sync did not hand out.
+
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::utils::{Config, compress, decompress};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A key a sync found the client missing, with its size when the sync ran.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingKey {
+    #[serde(rename = "k")]
+    pub key: String,
+    #[serde(rename = "s")]
+    pub size_bytes: i32,
+}
+
+fn mac(key: &[u8], user_id: &str, expires_at: i64, payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(b"sync-cursor:");
+    mac.update(user_id.as_bytes());
+    mac.update(b":");
+    mac.update(expires_at.to_string().as_bytes());
+    mac.update(b":");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+pub fn issue_sync_cursor(
+    config: &Config,
+    user_id: &str,
+    expires_at: i64,
+    pending: &[PendingKey],
+) -> String {
+    let json = serde_json::to_vec(pending).expect("pending keys serialize");
+    let payload = BASE64_URL_SAFE_NO_PAD.encode(compress(config, &json));
+    let key = config.session_secret.as_bytes();
+    let signature = mac(key, user_id, expires_at, &payload)
+        .finalize()
+        .into_bytes();
+    format!("{}.{}.{}", expires_at, payload, hex::encode(signature))
+}
+
+/// Checks the signature of a cursor for `user_id` and returns its expiry and
+/// the keys it holds. The caller checks the expiry.
+pub fn verify_sync_cursor(
+    config: &Config,
+    cursor: &str,
+    user_id: &str,
+) -> Option<(i64, Vec<PendingKey>)> {
+    let mut parts = cursor.splitn(3, '.');
+    let expires_at: i64 = parts.next()?.parse().ok()?;
+    let payload = parts.next()?;
+    let signature = hex::decode(parts.next()?).ok()?;
+
+    mac(
+        config.session_secret.as_bytes(),
+        user_id,
+        expires_at,
+        payload,
+    )
+    .verify_slice(&signature)
+    .ok()?;
+    let json = decompress(&BASE64_URL_SAFE_NO_PAD.decode(payload).ok()?);
+    let pending = serde_json::from_slice(&json).ok()?;
+    Some((expires_at, pending))
+}
+
+/// How many of `pending` make up the next page: as many as fit in
+/// `max_bytes` and `max_keys`, and at least one so a value larger than a
+/// page is still sent.
+pub fn page_len(pending: &[PendingKey], max_bytes: usize, max_keys: usize) -> usize {
+    let mut bytes = 0usize;
+    let mut len = 0;
+    for entry in pending.iter().take(max_keys) {
+        bytes += entry.size_bytes.max(0) as usize;
+        if len > 0 && bytes > max_bytes {
+            break;
+        }
+        len += 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::CONFIG;
+
+    fn pending(sizes: &[i32]) -> Vec<PendingKey> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size_bytes)| PendingKey {
+                key: format!("plugins/{}", i),
+                size_bytes,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let keys = pending(&[10, 20]);
+        let config = CONFIG.load();
+        let mut other = (*config).clone();
+        other.session_secret = "other".into();
+        let cursor = issue_sync_cursor(&config, "1", 5_000, &keys);
+        assert_eq!(
+            verify_sync_cursor(&config, &cursor, "1"),
+            Some((5_000, keys))
+        );
+        assert_eq!(verify_sync_cursor(&config, &cursor, "2"), None);
+        assert_eq!(verify_sync_cursor(&other, &cursor, "1"), None);
+
+        let signature = cursor.rsplit('.').next().unwrap();
+        let extended = cursor.replacen("5000", "9999999", 1);
+        assert!(extended.ends_with(signature));
+        assert_eq!(verify_sync_cursor(&config, &extended, "1"), None);
+    }
+
+    #[test]
+    fn test_page_len() {
+        assert_eq!(page_len(&pending(&[40, 40, 40]), 100, 10), 2);
+        assert_eq!(page_len(&pending(&[40, 40, 40]), 100, 1), 1);
+        assert_eq!(page_len(&pending(&[500, 1]), 100, 10), 1);
+        assert_eq!(page_len(&[], 100, 10), 0);
+    }
+}
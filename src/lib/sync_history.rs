@@ -0,0 +1,67 @@
+//! Summaries of recent syncs, for client developers debugging their sync.
+//!
+//! Every sync records how many values it stored, sent back and deleted, its
+//! conflicts and errors, the bytes that went each way and how long it took.
+//! Summaries are kept for `SYNC_HISTORY_RETENTION` and listed, newest first,
+//! by `GET /v2/sync/history`. Like audit entries they are written after the
+//! sync ran and never fail it.
+
+use tracing::warn;
+
+use crate::config::Config;
+use crate::database::SyncSession;
+use crate::datastore::{Datastore, Storage};
+
+/// Records `sync` by `user_id`, unless `SYNC_HISTORY_RETENTION` is zero.
+pub async fn record(db: &Storage, config: &Config, user_id: &str, sync: &SyncSession) {
+    let ttl = config.sync_history_retention.as_secs();
+    if ttl == 0 {
+        return;
+    }
+    if let Err(e) = db.insert_sync_session(user_id, sync, ttl as i32).await {
+        warn!("Failed to record sync session: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::SqliteDatastore;
+    use std::time::Duration;
+
+    fn session(started_at: i64) -> SyncSession {
+        SyncSession {
+            started_at,
+            duration_ms: 5,
+            device_id: None,
+            client_name: None,
+            uploads: 1,
+            downloads: 0,
+            deletions: 0,
+            conflicts: 0,
+            errors: 0,
+            bytes_uploaded: 3,
+            bytes_downloaded: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let mut config = Config::from_lookup(|_| None);
+        config.sync_history_retention = Duration::from_secs(60);
+        let now = chrono::Utc::now().timestamp_millis();
+        for started_at in [now - 2, now - 1, now] {
+            record(&db, &config, "1", &session(started_at)).await;
+        }
+
+        let sessions = db.list_sync_sessions("1", 2).await.unwrap();
+        let started: Vec<i64> = sessions.iter().map(|s| s.started_at).collect();
+        assert_eq!(started, [now, now - 1]);
+        assert!(db.list_sync_sessions("2", 10).await.unwrap().is_empty());
+
+        config.sync_history_retention = Duration::ZERO;
+        record(&db, &config, "2", &session(now)).await;
+        assert!(db.list_sync_sessions("2", 10).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,460 @@
+//! Tenants: separate communities served by one process.
+//!
+//! Each tenant listed in `TENANTS_FILE` gets its own storage (a Scylla
+//! keyspace, or a SQLite file) and may override the quota, the users allowed
+//! to sign in and the OAuth application. Requests pick a tenant with the
+//! `X-Tenant` header or by the hostname they were sent to; everything else
+//! goes to the default tenant, which is configured by the environment alone.
+
+use anyhow::{Context, Result, bail};
+use axum::http::HeaderMap;
+use axum::http::header::HOST;
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::Arc;
+
+use crate::abuse::AbuseMonitor;
+use crate::config::ConfigHandle;
+use crate::datastore::Storage;
+use crate::dev::is_local;
+use crate::lockout::AuthLockouts;
+use crate::oauth::Provider;
+use crate::stats::ActivityTracker;
+use crate::utils::Config;
+
+/// Id of the tenant serving requests that name no other.
+pub const DEFAULT_TENANT: &str = "default";
+
+pub const TENANT_HEADER: &str = "x-tenant";
+
+/// One entry of `TENANTS_FILE`. Every field but `id` is optional and falls
+/// back to the environment.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantSpec {
+    pub id: String,
+    /// Hostnames, without port, whose requests belong to this tenant.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Defaults to `equicloud_<id>`.
+    pub keyspace: Option<String>,
+    /// Defaults to `equicloud_<id>.db`.
+    pub sqlite_path: Option<String>,
+    /// Defaults to `S3_PREFIX` followed by `<id>/`.
+    pub s3_prefix: Option<String>,
+    pub max_backup_size_bytes: Option<usize>,
+    pub daily_write_limit: Option<u64>,
+    pub allowed_user_ids: Option<Vec<String>>,
+    pub server_fqdn: Option<String>,
+    pub oauth_provider: Option<String>,
+    pub discord_client_id: Option<String>,
+    pub discord_client_secret: Option<String>,
+    pub oidc_issuer_url: Option<String>,
+    pub oidc_client_id: Option<String>,
+    pub oidc_client_secret: Option<String>,
+    pub oidc_scopes: Option<String>,
+}
+
+impl TenantSpec {
+    /// `base` with this tenant's storage location and overrides applied.
+    pub fn apply(&self, base: &Config) -> Result<Config> {
+        let mut config = base.clone();
+        config.keyspace = self
+            .keyspace
+            .clone()
+            .unwrap_or_else(|| format!("{}_{}", base.keyspace, self.id.replace('-', "_")));
+        config.sqlite_path = self
+            .sqlite_path
+            .clone()
+            .unwrap_or_else(|| format!("equicloud_{}.db", self.id));
+        config.s3_prefix = self
+            .s3_prefix
+            .clone()
+            .unwrap_or_else(|| format!("{}{}/", base.s3_prefix, self.id));
+
+        if let Some(bytes) = self.max_backup_size_bytes {
+            config.max_backup_size_bytes = bytes;
+        }
+        if let Some(limit) = self.daily_write_limit {
+            config.daily_write_limit = limit;
+        }
+        if let Some(users) = &self.allowed_user_ids {
+            config.discord_allowed_user_ids = Some(users.join(","));
+        }
+        if let Some(fqdn) = &self.server_fqdn {
+            config.server_fqdn = Some(Url::parse(fqdn).context("invalid server_fqdn")?);
+        }
+        if config.dev_mode
+            && config
+                .server_fqdn
+                .as_ref()
+                .is_some_and(|url| !is_local(url))
+        {
+            bail!("DEV_MODE cannot be used with a server_fqdn other than localhost");
+        }
+        if let Some(provider) = &self.oauth_provider {
+            config.oauth_provider = provider.to_ascii_lowercase();
+        }
+        if let Some(url) = &self.oidc_issuer_url {
+            config.oidc_issuer_url = Some(Url::parse(url).context("invalid oidc_issuer_url")?);
+        }
+        for (value, target) in [
+            (&self.discord_client_id, &mut config.discord_client_id),
+            (
+                &self.discord_client_secret,
+                &mut config.discord_client_secret,
+            ),
+            (&self.oidc_client_id, &mut config.oidc_client_id),
+            (&self.oidc_client_secret, &mut config.oidc_client_secret),
+            (&self.oidc_scopes, &mut config.oidc_scopes),
+        ] {
+            if let Some(value) = value {
+                target.clone_from(value);
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantsFile {
+    tenants: Vec<TenantSpec>,
+}
+
+/// Reads and checks `TENANTS_FILE`.
+pub fn load_tenant_specs(path: &str) -> Result<Vec<TenantSpec>> {
+    let contents = fs::read_to_string(path).with_context(|| format!("cannot read {}", path))?;
+    parse_tenant_specs(&contents)
+}
+
+fn parse_tenant_specs(contents: &str) -> Result<Vec<TenantSpec>> {
+    let file: TenantsFile = serde_json::from_str(contents)?;
+
+    let mut ids = HashSet::new();
+    let mut hosts = HashSet::new();
+    for spec in &file.tenants {
+        if spec.id.is_empty()
+            || !spec
+                .id
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+        {
+            bail!(
+                "tenant id {:?} must be lowercase letters, digits, '-' or '_'",
+                spec.id
+            );
+        }
+        if spec.id == DEFAULT_TENANT || !ids.insert(spec.id.as_str()) {
+            bail!("tenant id {:?} is reserved or used twice", spec.id);
+        }
+        if let Some(keyspace) = &spec.keyspace
+            && !is_keyspace_name(keyspace)
+        {
+            bail!(
+                "tenant {}: keyspace {:?} is not a valid name",
+                spec.id,
+                keyspace
+            );
+        }
+        for host in &spec.hosts {
+            if !hosts.insert(host.to_ascii_lowercase()) {
+                bail!("host {} is assigned to more than one tenant", host);
+            }
+        }
+    }
+
+    Ok(file.tenants)
+}
+
+/// Scylla keyspace names: up to 48 letters, digits and underscores.
+fn is_keyspace_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 48
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// A tenant with its resolved configuration, storage and identity provider.
+pub struct Tenant {
+    pub id: String,
+    pub config: ConfigHandle,
+    pub db: Storage,
+    pub provider: Provider,
+    pub abuse: AbuseMonitor,
+    pub lockouts: AuthLockouts,
+    pub activity: ActivityTracker,
+    /// Overrides re-applied when the configuration is reloaded; `None` for
+    /// the default tenant.
+    spec: Option<TenantSpec>,
+}
+
+impl Tenant {
+    pub fn new(id: impl Into<String>, config: ConfigHandle, db: Storage) -> Self {
+        Self {
+            id: id.into(),
+            provider: Provider::from_config(&config),
+            config,
+            db,
+            abuse: AbuseMonitor::default(),
+            lockouts: AuthLockouts::default(),
+            activity: ActivityTracker::default(),
+            spec: None,
+        }
+    }
+
+    /// A tenant from `TENANTS_FILE`; `config` is `spec` applied to the
+    /// base configuration.
+    pub fn from_spec(spec: TenantSpec, config: ConfigHandle, db: Storage) -> Self {
+        let mut tenant = Self::new(spec.id.clone(), config, db);
+        tenant.spec = Some(spec);
+        tenant
+    }
+}
+
+/// Every tenant of the process, looked up per request.
+pub struct Tenants {
+    default: Arc<Tenant>,
+    by_id: HashMap<String, Arc<Tenant>>,
+    by_host: HashMap<String, Arc<Tenant>>,
+}
+
+impl Tenants {
+    pub fn new(default: Tenant) -> Self {
+        let default = Arc::new(default);
+        Self {
+            by_id: HashMap::from([(default.id.clone(), default.clone())]),
+            default,
+            by_host: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, tenant: Tenant, hosts: &[String]) {
+        let tenant = Arc::new(tenant);
+        for host in hosts {
+            self.by_host
+                .insert(host.to_ascii_lowercase(), tenant.clone());
+        }
+        self.by_id.insert(tenant.id.clone(), tenant);
+    }
+
+    pub fn default_tenant(&self) -> &Arc<Tenant> {
+        &self.default
+    }
+
+    /// All tenants, the default one first.
+    pub fn all(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        std::iter::once(&self.default).chain(
+            self.by_id
+                .values()
+                .filter(|tenant| !Arc::ptr_eq(tenant, &self.default)),
+        )
+    }
+
+    /// Gives every tenant `base` with its own overrides applied again. Either
+    /// all tenants switch or, when an override no longer applies, none does.
+    /// Returns the variables whose change needs a restart.
+    pub fn reload(&self, base: &Config) -> Result<Vec<&'static str>> {
+        let mut next = Vec::new();
+        for tenant in self.all() {
+            let config = match &tenant.spec {
+                Some(spec) => spec
+                    .apply(base)
+                    .with_context(|| format!("tenant {}", tenant.id))?,
+                None => base.clone(),
+            };
+            next.push((tenant, config));
+        }
+
+        let mut ignored = Vec::new();
+        for (tenant, config) in next {
+            for var in tenant.config.update(config) {
+                if !ignored.contains(&var) {
+                    ignored.push(var);
+                }
+            }
+        }
+        Ok(ignored)
+    }
+
+    /// The tenant named by `X-Tenant`, else the one owning the `Host`, else
+    /// the default. `None` when `X-Tenant` names an unknown tenant.
+    pub fn resolve(&self, headers: &HeaderMap) -> Option<Arc<Tenant>> {
+        if let Some(id) = headers.get(TENANT_HEADER) {
+            return id
+                .to_str()
+                .ok()
+                .and_then(|id| self.by_id.get(id.trim()))
+                .cloned();
+        }
+
+        let host = headers
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .map(strip_port)
+            .map(str::to_ascii_lowercase);
+        let tenant = host.and_then(|host| self.by_host.get(&host));
+        Some(tenant.unwrap_or(&self.default).clone())
+    }
+
+    /// [`Tenants::resolve`], falling back to the default tenant, for the
+    /// middleware that runs before an unknown `X-Tenant` is rejected.
+    pub fn resolve_or_default(&self, headers: &HeaderMap) -> Arc<Tenant> {
+        self.resolve(headers)
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next().unwrap_or(host);
+    }
+    host.rsplit_once(':').map_or(host, |(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::SqliteDatastore;
+    use crate::utils::CONFIG;
+    use axum::http::HeaderValue;
+
+    fn tenant(id: &str) -> Tenant {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        Tenant::new(id, CONFIG.clone(), db)
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.parse().unwrap(), HeaderValue::from_static(v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_tenant_specs() {
+        let specs = parse_tenant_specs(
+            r#"{"tenants": [
+                {"id": "acme", "hosts": ["cloud.acme.org"], "max_backup_size_bytes": 1024},
+                {"id": "other-1", "keyspace": "other_ks"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].max_backup_size_bytes, Some(1024));
+
+        for invalid in [
+            r#"{"tenants": [{"id": "default"}]}"#,
+            r#"{"tenants": [{"id": "a"}, {"id": "a"}]}"#,
+            r#"{"tenants": [{"id": "Bad Id"}]}"#,
+            r#"{"tenants": [{"id": "a", "keyspace": "x; DROP"}]}"#,
+            r#"{"tenants": [{"id": "a", "hosts": ["h"]}, {"id": "b", "hosts": ["H"]}]}"#,
+            r#"{"tenants": [{"id": "a", "quota": 1}]}"#,
+        ] {
+            assert!(parse_tenant_specs(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let spec = TenantSpec {
+            id: "acme-eu".into(),
+            max_backup_size_bytes: Some(1024),
+            allowed_user_ids: Some(vec!["1".into(), "2".into()]),
+            discord_client_id: Some("acme".into()),
+            server_fqdn: Some("https://cloud.acme.org".into()),
+            ..Default::default()
+        };
+        let config = spec.apply(&CONFIG.load()).unwrap();
+        assert_eq!(config.keyspace, "equicloud_acme_eu");
+        assert_eq!(config.sqlite_path, "equicloud_acme-eu.db");
+        assert_eq!(config.max_backup_size_bytes, 1024);
+        assert_eq!(config.discord_client_id, "acme");
+        assert_eq!(
+            config.redirect_uri(),
+            "https://cloud.acme.org/v1/oauth/callback"
+        );
+        assert!(config.user_allowed("2"));
+        assert!(!config.user_allowed("3"));
+        assert_eq!(config.session_secret, CONFIG.load().session_secret);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut tenants = Tenants::new(tenant(DEFAULT_TENANT));
+        tenants.add(tenant("acme"), &["Cloud.Acme.org".into()]);
+
+        let id = |h: &HeaderMap| tenants.resolve(h).map(|t| t.id.clone());
+        assert_eq!(id(&headers(&[])).as_deref(), Some("default"));
+        assert_eq!(
+            id(&headers(&[("host", "cloud.acme.org:8443")])).as_deref(),
+            Some("acme")
+        );
+        assert_eq!(
+            id(&headers(&[("host", "elsewhere.org")])).as_deref(),
+            Some("default")
+        );
+        assert_eq!(
+            id(&headers(&[("x-tenant", "acme"), ("host", "elsewhere.org")])).as_deref(),
+            Some("acme")
+        );
+        assert_eq!(id(&headers(&[("x-tenant", "nope")])), None);
+        assert_eq!(tenants.all().count(), 2);
+        assert_eq!(tenants.all().next().unwrap().id, "default");
+    }
+
+    #[test]
+    fn test_reload() {
+        let config = |vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            Config::from_lookup(|var| vars.get(var).cloned())
+        };
+        let db = || Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+
+        let base = config(&[
+            ("MAX_BACKUP_SIZE_BYTES", "1000"),
+            ("DISCORD_CLIENT_ID", "a"),
+        ]);
+        let spec = TenantSpec {
+            id: "acme".into(),
+            daily_write_limit: Some(5),
+            ..Default::default()
+        };
+        let mut tenants = Tenants::new(Tenant::new(
+            DEFAULT_TENANT,
+            ConfigHandle::new(base.clone()),
+            db(),
+        ));
+        let acme = ConfigHandle::new(spec.apply(&base).unwrap());
+        tenants.add(Tenant::from_spec(spec, acme, db()), &[]);
+
+        let reloaded = config(&[
+            ("MAX_BACKUP_SIZE_BYTES", "2000"),
+            ("DAILY_WRITE_LIMIT", "9"),
+            ("DISCORD_CLIENT_ID", "b"),
+        ]);
+        assert_eq!(tenants.reload(&reloaded).unwrap(), ["DISCORD_CLIENT_ID"]);
+
+        let default = tenants.default_tenant().config.load();
+        assert_eq!(default.max_backup_size_bytes, 2000);
+        assert_eq!(default.daily_write_limit, 9);
+        assert_eq!(default.discord_client_id, "a");
+        let acme = tenants.by_id["acme"].config.load();
+        assert_eq!(acme.max_backup_size_bytes, 2000);
+        assert_eq!(acme.daily_write_limit, 5);
+        assert_eq!(acme.keyspace, "equicloud_acme");
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.org:443"), "example.org");
+        assert_eq!(strip_port("example.org"), "example.org");
+        assert_eq!(strip_port("[::1]:443"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+}
@@ -0,0 +1,155 @@
+//! Timing of every query `DatabaseService` sends.
+//!
+//! Statements are named after their field in `PreparedStatements` when they
+//! are prepared, so latency is recorded, and slow queries are logged, per
+//! operation rather than per CQL string. They also report every attempt to
+//! a [`NodeTracker`] for the driver's per-node statistics.
+
+use anyhow::Result;
+use bytes::Bytes;
+use scylla::client::pager::QueryPager;
+use scylla::client::session::Session;
+use scylla::errors::{ExecutionError, PagerExecutionError};
+use scylla::response::query_result::QueryResult;
+use scylla::response::{PagingState, PagingStateResponse};
+use scylla::serialize::batch::BatchValues;
+use scylla::serialize::row::SerializeRow;
+use scylla::statement::batch::Batch;
+use scylla::statement::prepared::PreparedStatement;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::driver_metrics::{DriverStats, NodeTracker};
+use crate::metrics::QueryLatency;
+use crate::utils::ConfigHandle;
+
+/// Name recorded for statements prepared without one.
+const UNNAMED: &str = "query";
+
+/// Prepares statements while remembering their names.
+#[derive(Default)]
+pub struct StatementNames {
+    names: HashMap<Bytes, &'static str>,
+    tracker: Arc<NodeTracker>,
+}
+
+impl StatementNames {
+    pub async fn prepare(
+        &mut self,
+        session: &Session,
+        name: &'static str,
+        cql: &str,
+    ) -> Result<PreparedStatement> {
+        let mut statement = session.prepare(cql).await?;
+        statement.set_history_listener(self.tracker.clone());
+        self.names.insert(statement.get_id().clone(), name);
+        Ok(statement)
+    }
+}
+
+/// A session that times its queries.
+#[derive(Clone)]
+pub struct TimedSession {
+    session: Arc<Session>,
+    names: Arc<HashMap<Bytes, &'static str>>,
+    latency: Arc<QueryLatency>,
+    tracker: Arc<NodeTracker>,
+    config: ConfigHandle,
+}
+
+impl TimedSession {
+    pub fn new(session: Arc<Session>, names: StatementNames, config: ConfigHandle) -> Self {
+        Self {
+            session,
+            names: Arc::new(names.names),
+            latency: Arc::default(),
+            tracker: names.tracker,
+            config,
+        }
+    }
+
+    pub fn inner(&self) -> &Arc<Session> {
+        &self.session
+    }
+
+    pub fn latency(&self) -> &QueryLatency {
+        &self.latency
+    }
+
+    pub fn driver_stats(&self) -> DriverStats {
+        self.tracker.stats(&self.session.get_cluster_state())
+    }
+
+    fn name(&self, statement: &PreparedStatement) -> &'static str {
+        self.names
+            .get(statement.get_id())
+            .copied()
+            .unwrap_or(UNNAMED)
+    }
+
+    fn record(&self, name: &'static str, elapsed: Duration) {
+        self.latency.record(name, elapsed);
+
+        let threshold = self.config.load().slow_query_threshold_ms;
+        if threshold > 0 && elapsed >= Duration::from_millis(threshold) {
+            warn!("Slow query {} took {} ms", name, elapsed.as_millis());
+        }
+    }
+
+    pub async fn execute_unpaged(
+        &self,
+        statement: &PreparedStatement,
+        values: impl SerializeRow,
+    ) -> Result<QueryResult, ExecutionError> {
+        let started = Instant::now();
+        let result = self.session.execute_unpaged(statement, values).await;
+        self.record(self.name(statement), started.elapsed());
+        result
+    }
+
+    pub async fn execute_single_page(
+        &self,
+        statement: &PreparedStatement,
+        values: impl SerializeRow,
+        paging_state: PagingState,
+    ) -> Result<(QueryResult, PagingStateResponse), ExecutionError> {
+        let started = Instant::now();
+        let result = self
+            .session
+            .execute_single_page(statement, values, paging_state)
+            .await;
+        self.record(self.name(statement), started.elapsed());
+        result
+    }
+
+    /// Batches hold several statements, so they are recorded under `name`.
+    pub async fn batch(
+        &self,
+        name: &'static str,
+        batch: &Batch,
+        values: impl BatchValues,
+    ) -> Result<QueryResult, ExecutionError> {
+        let mut batch = batch.clone();
+        batch.set_history_listener(self.tracker.clone());
+        let started = Instant::now();
+        let result = self.session.batch(&batch, values).await;
+        self.record(name, started.elapsed());
+        result
+    }
+
+    /// Times the query until its first page arrives; later pages are
+    /// fetched as the pager is read.
+    pub async fn execute_iter(
+        &self,
+        statement: PreparedStatement,
+        values: impl SerializeRow,
+    ) -> Result<QueryPager, PagerExecutionError> {
+        let name = self.name(&statement);
+        let started = Instant::now();
+        let result = self.session.execute_iter(statement, values).await;
+        self.record(name, started.elapsed());
+        result
+    }
+}
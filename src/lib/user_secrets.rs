@@ -0,0 +1,176 @@
+//! Permanent secrets derived from the user id.
+//!
+//! Without `SECRET_PEPPER` a permanent secret is a hash of the user id alone,
+//! so anyone who knows the derivation can compute every user's secret. With
+//! it, secrets are an HMAC of the id keyed with the pepper and tagged
+//! `v<SECRET_PEPPER_VERSION>.`, and are handed out with each new session.
+//! Secrets derived without the current pepper, from before it was set or
+//! from `SECRET_PEPPER_PREVIOUS`, keep working until
+//! `SECRET_PEPPER_GRACE_UNTIL`, so clients can move over on their next
+//! sign-in.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::auth_events::AuthOutcome;
+use crate::config::Config;
+use crate::hash_migration::{legacy, sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A way of deriving a user's permanent secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Derivation<'a> {
+    /// CRC32 of the user id, from before SHA-256.
+    Legacy,
+    /// SHA-256 of the user id.
+    Unpeppered,
+    /// HMAC-SHA256 of the user id keyed with a server-side pepper.
+    Peppered { version: u32, pepper: &'a str },
+}
+
+impl Derivation<'_> {
+    pub fn derive(&self, user_id: &str) -> String {
+        match self {
+            Self::Legacy => legacy::get_user_secret(user_id),
+            Self::Unpeppered => sha256::get_user_secret(user_id),
+            Self::Peppered { version, pepper } => {
+                let mut mac = HmacSha256::new_from_slice(pepper.as_bytes())
+                    .expect("HMAC accepts keys of any length");
+                mac.update(b"secret:");
+                mac.update(user_id.as_bytes());
+                let digest = mac.finalize().into_bytes();
+                format!("v{}.{}", version, hex::encode(&digest[..16]))
+            }
+        }
+    }
+}
+
+/// The derivation new permanent secrets are issued with.
+pub fn current(config: &Config) -> Derivation<'_> {
+    if config.secret_pepper.is_empty() {
+        Derivation::Unpeppered
+    } else {
+        Derivation::Peppered {
+            version: config.secret_pepper_version,
+            pepper: &config.secret_pepper,
+        }
+    }
+}
+
+/// The secret to hand out with a new session, if a pepper is set; without
+/// one clients derive it themselves.
+pub fn reissue(config: &Config, user_id: &str) -> Option<String> {
+    let derivation = current(config);
+    matches!(derivation, Derivation::Peppered { .. }).then(|| derivation.derive(user_id))
+}
+
+/// The derivations a permanent secret is accepted from at `now`, with the
+/// outcome a sign-in with each counts as.
+pub fn accepted(config: &Config, now: i64) -> Vec<(Derivation<'_>, AuthOutcome)> {
+    let current = current(config);
+    let mut accepted = vec![(current, AuthOutcome::PermanentSecret)];
+    let in_grace = config
+        .secret_pepper_grace_until
+        .is_none_or(|until| now < until);
+
+    if let Derivation::Peppered { version, .. } = current {
+        if !in_grace {
+            return accepted;
+        }
+        if !config.previous_secret_pepper.is_empty() {
+            accepted.push((
+                Derivation::Peppered {
+                    version: version - 1,
+                    pepper: &config.previous_secret_pepper,
+                },
+                AuthOutcome::OutdatedSecret,
+            ));
+        }
+        accepted.push((Derivation::Unpeppered, AuthOutcome::OutdatedSecret));
+    }
+    accepted.push((Derivation::Legacy, AuthOutcome::LegacySecret));
+    accepted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Config {
+        Config::from_lookup(|var| {
+            vars.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    fn outcome(config: &Config, secret: &str, now: i64) -> Option<AuthOutcome> {
+        accepted(config, now)
+            .into_iter()
+            .find(|(derivation, _)| derivation.derive("1") == secret)
+            .map(|(_, outcome)| outcome)
+    }
+
+    #[test]
+    fn test_without_pepper() {
+        let config = config(&[]);
+        assert_eq!(current(&config), Derivation::Unpeppered);
+        assert_eq!(reissue(&config, "1"), None);
+
+        let secret = sha256::get_user_secret("1");
+        assert_eq!(
+            outcome(&config, &secret, 0),
+            Some(AuthOutcome::PermanentSecret)
+        );
+        assert_eq!(
+            outcome(&config, &legacy::get_user_secret("1"), 0),
+            Some(AuthOutcome::LegacySecret)
+        );
+    }
+
+    #[test]
+    fn test_pepper_rotation_and_grace() {
+        let old = config(&[("SECRET_PEPPER", "old")]);
+        let old_secret = reissue(&old, "1").unwrap();
+        assert!(old_secret.starts_with("v1."));
+        assert_ne!(
+            old_secret,
+            current(&config(&[("SECRET_PEPPER", "other")])).derive("1")
+        );
+
+        let rotated = config(&[
+            ("SECRET_PEPPER", "new"),
+            ("SECRET_PEPPER_VERSION", "2"),
+            ("SECRET_PEPPER_PREVIOUS", "old"),
+            ("SECRET_PEPPER_GRACE_UNTIL", "2030-01-01T00:00:00Z"),
+        ]);
+        let new_secret = reissue(&rotated, "1").unwrap();
+        assert!(new_secret.starts_with("v2."));
+
+        let during = rotated.secret_pepper_grace_until.unwrap() - 1;
+        assert_eq!(
+            outcome(&rotated, &new_secret, during),
+            Some(AuthOutcome::PermanentSecret)
+        );
+        for outdated in [old_secret.clone(), sha256::get_user_secret("1")] {
+            assert_eq!(
+                outcome(&rotated, &outdated, during),
+                Some(AuthOutcome::OutdatedSecret)
+            );
+        }
+
+        let after = during + 1;
+        assert_eq!(
+            outcome(&rotated, &new_secret, after),
+            Some(AuthOutcome::PermanentSecret)
+        );
+        for outdated in [
+            old_secret,
+            sha256::get_user_secret("1"),
+            legacy::get_user_secret("1"),
+        ] {
+            assert_eq!(outcome(&rotated, &outdated, after), None);
+        }
+    }
+}
@@ -1,19 +1,18 @@
-use once_cell::sync::Lazy;
-use serde_json::{Value, json};
+use aes_gcm::aead::KeyInit;
+use aes_gcm::{Aes256Gcm, Key};
 use sha2::{Digest, Sha256};
-use std::env;
+use std::ops::Range;
 
-use crate::constants::{
-    CHECKSUM_BYTES, DEFAULT_COMPRESSION_ENABLED, DEFAULT_DATASTORE_ENABLED,
-    DEFAULT_MAX_BACKUP_SIZE, DEFAULT_ZSTD_COMPRESSION_LEVEL, MAX_DATASTORE_KEY_SIZE,
-    MAX_DECOMPRESSION_SIZE, MAX_KEY_NAME_LEN, MAX_KEY_SIZE,
-};
+pub use crate::config::{CONFIG, Config, ConfigHandle};
+use crate::constants::{CHECKSUM_BYTES, MAX_DECOMPRESSION_SIZE};
 use crate::hash_migration::sha256;
 
 pub fn hash_user_id(user_id: &str) -> String {
     sha256::hash_user_id(user_id)
 }
 
+/// The permanent secret of `user_id` derived without `SECRET_PEPPER`; see
+/// [`crate::user_secrets`] for the secrets actually accepted.
 pub fn get_user_secret(user_id: &str) -> String {
     sha256::get_user_secret(user_id)
 }
@@ -24,16 +23,31 @@ pub fn compute_checksum(data: &[u8]) -> String {
     hex::encode(&hasher.finalize()[..CHECKSUM_BYTES])
 }
 
+/// Full SHA-256 of a value, used to address shared blobs.
+pub fn content_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// AES-256-GCM keyed by the SHA-256 of `key_material`, so keys can be
+/// configured as passphrases of any length. Refresh tokens and backups are
+/// encrypted with it.
+pub fn aes_cipher(key_material: &[u8]) -> Aes256Gcm {
+    let key = Sha256::digest(key_material);
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
 const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
 
-pub fn compress(data: &[u8]) -> Vec<u8> {
-    if !CONFIG.compression_enabled || data.is_empty() {
+/// `data` compressed with `COMPRESSION_LEVEL`, or as is when compression is
+/// off or does not make it smaller.
+pub fn compress(config: &Config, data: &[u8]) -> Vec<u8> {
+    if !config.compression_enabled || data.is_empty() {
         return data.to_vec();
     }
     let capacity = zstd::zstd_safe::compress_bound(data.len());
     let mut output = Vec::with_capacity(capacity);
 
-    if zstd::stream::copy_encode(data, &mut output, CONFIG.compression_level).is_err() {
+    if zstd::stream::copy_encode(data, &mut output, config.compression_level).is_err() {
         return data.to_vec();
     }
 
@@ -71,100 +85,139 @@ pub fn decompress(data: &[u8]) -> Vec<u8> {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum KeyValidationError {
-    Empty,
-    TooLong,
-    InvalidChars,
+/// Output buffer of [`StreamDecompressor`] per call into zstd.
+const STREAM_DECODE_BUFFER: usize = 128 * 1024;
+
+/// [`decompress`] for a value that arrives in pieces. Whether it is
+/// compressed at all is decided by the first piece, and unlike
+/// [`decompress`] a corrupt frame is an error, since earlier pieces may
+/// already have been sent on.
+#[derive(Default)]
+pub struct StreamDecompressor {
+    decoder: Option<Box<zstd::stream::raw::Decoder<'static>>>,
+    started: bool,
 }
 
-impl KeyValidationError {
-    pub fn message(self) -> &'static str {
-        match self {
-            Self::Empty => "Key cannot be empty",
-            Self::TooLong => "Key name exceeds 256 characters",
-            Self::InvalidChars => {
-                "Key contains invalid characters (allowed: alphanumeric, _, -, ., /)"
-            }
-        }
+impl StreamDecompressor {
+    pub fn new() -> Self {
+        Self::default()
     }
-}
 
-pub fn validate_key(key: &str) -> Result<(), KeyValidationError> {
-    if key.is_empty() {
-        return Err(KeyValidationError::Empty);
-    }
-    if key.len() > MAX_KEY_NAME_LEN {
-        return Err(KeyValidationError::TooLong);
-    }
-    if !key
-        .bytes()
-        .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.' || b == b'/')
-    {
-        return Err(KeyValidationError::InvalidChars);
+    pub fn feed(&mut self, mut input: &[u8]) -> std::io::Result<Vec<u8>> {
+        use zstd::stream::raw::Operation;
+
+        if !self.started {
+            self.started = true;
+            if input.len() >= 4 && input[..4] == ZSTD_MAGIC {
+                self.decoder = Some(Box::new(zstd::stream::raw::Decoder::new()?));
+            }
+        }
+        let Some(decoder) = &mut self.decoder else {
+            return Ok(input.to_vec());
+        };
+
+        let mut output = Vec::new();
+        let mut buffer = vec![0u8; STREAM_DECODE_BUFFER];
+        loop {
+            let status = decoder.run_on_buffers(input, &mut buffer)?;
+            output.extend_from_slice(&buffer[..status.bytes_written]);
+            input = &input[status.bytes_read..];
+            if input.is_empty() && status.bytes_written < buffer.len() {
+                return Ok(output);
+            }
+        }
     }
-    Ok(())
 }
 
-#[derive(Clone)]
-pub struct Config {
-    pub max_backup_size_bytes: usize,
-    pub max_key_size_bytes: usize,
-    pub max_datastore_key_size_bytes: usize,
-    pub compression_enabled: bool,
-    pub compression_level: i32,
-    pub datastore_enabled: bool,
-    pub discord_client_id: String,
-    pub discord_client_secret: String,
-    pub server_fqdn: String,
-    pub discord_allowed_user_ids: Option<String>,
-    pub cors_allowed_origins: Option<String>,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No usable range; serve the whole value.
+    Full,
+    Partial(Range<usize>),
+    Unsatisfiable,
 }
 
-impl Config {
-    pub fn from_env() -> Self {
-        Self {
-            max_backup_size_bytes: env::var("MAX_BACKUP_SIZE_BYTES")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(DEFAULT_MAX_BACKUP_SIZE),
-            max_key_size_bytes: env::var("MAX_KEY_SIZE_BYTES")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(MAX_KEY_SIZE),
-            max_datastore_key_size_bytes: env::var("MAX_DATASTORE_KEY_SIZE_BYTES")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(MAX_DATASTORE_KEY_SIZE),
-            compression_enabled: env::var("COMPRESSION_ENABLED")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(DEFAULT_COMPRESSION_ENABLED),
-            compression_level: env::var("COMPRESSION_LEVEL")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(DEFAULT_ZSTD_COMPRESSION_LEVEL),
-            datastore_enabled: env::var("DATASTORE_ENABLED")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(DEFAULT_DATASTORE_ENABLED),
-            discord_client_id: env::var("DISCORD_CLIENT_ID").unwrap_or_default(),
-            discord_client_secret: env::var("DISCORD_CLIENT_SECRET").unwrap_or_default(),
-            server_fqdn: env::var("SERVER_FQDN").unwrap_or_default(),
-            discord_allowed_user_ids: env::var("DISCORD_ALLOWED_USER_IDS").ok(),
-            cors_allowed_origins: env::var("CORS_ALLOWED_ORIGINS").ok(),
-        }
+/// Interprets a `Range` header against a value of `len` bytes. Only a single
+/// `bytes=` range is honoured; malformed or multi-range headers fall back to
+/// the full value, as RFC 9110 allows.
+pub fn parse_range(header: &str, len: usize) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
     }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
 
-    pub fn redirect_uri(&self) -> String {
-        format!("{}/v1/oauth/callback", self.server_fqdn)
+    let range = match (start.parse::<usize>(), end) {
+        (Ok(start), "") => start..len,
+        (Ok(start), end) => match end.parse::<usize>() {
+            Ok(end) if end >= start => start..end.saturating_add(1).min(len),
+            _ => return ByteRange::Full,
+        },
+        (Err(_), suffix) if start.is_empty() => match suffix.parse::<usize>() {
+            Ok(suffix) if suffix > 0 => len.saturating_sub(suffix)..len,
+            Ok(_) => return ByteRange::Unsatisfiable,
+            Err(_) => return ByteRange::Full,
+        },
+        (Err(_), _) => return ByteRange::Full,
+    };
+
+    if range.start >= len {
+        return ByteRange::Unsatisfiable;
     }
+    ByteRange::Partial(range)
 }
 
-pub static CONFIG: Lazy<Config> = Lazy::new(Config::from_env);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_decompressor_matches_decompress() {
+        let value: Vec<u8> = (0..300_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        let mut compressed = Vec::new();
+        zstd::stream::copy_encode(&value[..], &mut compressed, 3).unwrap();
+        assert!(compressed.len() < value.len());
+
+        for stored in [compressed, value.clone()] {
+            let mut decompressor = StreamDecompressor::new();
+            let mut output = Vec::new();
+            for piece in stored.chunks(1000) {
+                output.extend(decompressor.feed(piece).unwrap());
+            }
+            assert_eq!(output, value);
+        }
+    }
 
-pub fn error_response(message: &str) -> Value {
-    json!({
-        "error": message
-    })
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0..100));
+        assert_eq!(
+            parse_range("bytes=900-", 1000),
+            ByteRange::Partial(900..1000)
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            ByteRange::Partial(900..1000)
+        );
+        assert_eq!(
+            parse_range("bytes=-5000", 1000),
+            ByteRange::Partial(0..1000)
+        );
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            ByteRange::Partial(500..1000)
+        );
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=9-1", 1000), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 1000), ByteRange::Full);
+    }
 }
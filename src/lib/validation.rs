@@ -0,0 +1,381 @@
+//! Checks on what clients send, shared by every route that writes.
+//!
+//! `/v1`, `/v2`, `/v3` and gRPC check keys, sizes, content types and
+//! namespaces here, so the same input is rejected the same way everywhere:
+//! `invalid_key` for a malformed key, `payload_too_large` for a value over
+//! its limit, `unsupported_media_type` for the wrong `Content-Type`, and
+//! `namespace_disabled` or `datastore_disabled` for a key that is turned
+//! off. With `SETTINGS_JSON_VALIDATION`, settings backups that are not JSON
+//! are refused with `invalid_settings`.
+
+use axum::http::{HeaderMap, header::CONTENT_TYPE};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use serde::de::IgnoredAny;
+use std::io::Read;
+
+use crate::config::Config;
+use crate::constants::MAX_KEY_NAME_LEN;
+use crate::error::AppError;
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+pub const TAR: &str = "application/x-tar";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyValidationError {
+    Empty,
+    TooLong,
+    InvalidChars,
+    /// A `.` or `..` path segment, which storage backends addressing values
+    /// by path could resolve outside the user's keys.
+    Traversal,
+}
+
+impl KeyValidationError {
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::Empty => "Key cannot be empty",
+            Self::TooLong => "Key name exceeds 256 characters",
+            Self::InvalidChars => {
+                "Key contains invalid characters (allowed: alphanumeric, _, -, ., /)"
+            }
+            Self::Traversal => "Key cannot contain . or .. segments",
+        }
+    }
+}
+
+pub fn validate_key(key: &str) -> Result<(), KeyValidationError> {
+    if key.is_empty() {
+        return Err(KeyValidationError::Empty);
+    }
+    if key.len() > MAX_KEY_NAME_LEN {
+        return Err(KeyValidationError::TooLong);
+    }
+    if !key
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.' || b == b'/')
+    {
+        return Err(KeyValidationError::InvalidChars);
+    }
+    if key
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return Err(KeyValidationError::Traversal);
+    }
+    Ok(())
+}
+
+/// Rejects `size` bytes of `what` when over `max_size`.
+pub fn check_size(size: usize, max_size: usize, what: &str) -> Result<(), AppError> {
+    if size > max_size {
+        return Err(AppError::PayloadTooLarge(format!(
+            "{} exceeds {} limit",
+            what,
+            format_size(max_size)
+        )));
+    }
+    Ok(())
+}
+
+/// Checks a settings backup about to be saved: it has to have content, fit
+/// in `MAX_BACKUP_SIZE_BYTES` and, with `SETTINGS_JSON_VALIDATION`, be JSON.
+pub fn check_settings(value: &[u8], config: &Config) -> Result<(), AppError> {
+    if value.is_empty() {
+        return Err(AppError::BadRequest("Settings cannot be empty".into()));
+    }
+    check_size(value.len(), config.max_backup_size_bytes, "Settings")?;
+    if config.settings_json_validation {
+        check_settings_json(
+            value,
+            config.settings_json_max_depth,
+            config.settings_json_max_size_bytes,
+        )?;
+    }
+    Ok(())
+}
+
+/// Requires `value` to be a JSON object, sent as is or deflate, zlib or
+/// gzip compressed, of at most `max_size` bytes and `max_depth` levels of
+/// nesting.
+pub fn check_settings_json(
+    value: &[u8],
+    max_depth: usize,
+    max_size: usize,
+) -> Result<(), AppError> {
+    let json = decompress_settings(value, max_size)?;
+    if json.len() > max_size {
+        return Err(AppError::PayloadTooLarge(format!(
+            "Settings exceed {} limit once decompressed",
+            format_size(max_size)
+        )));
+    }
+    if json.trim_ascii_start().first() != Some(&b'{') {
+        return Err(AppError::InvalidSettings(
+            "Settings must be a JSON object".into(),
+        ));
+    }
+    if nests_deeper_than(&json, max_depth) {
+        return Err(AppError::InvalidSettings(format!(
+            "Settings nest deeper than {} levels",
+            max_depth
+        )));
+    }
+    serde_json::from_slice::<IgnoredAny>(&json)
+        .map_err(|e| AppError::InvalidSettings(format!("Settings are not valid JSON: {}", e)))?;
+    Ok(())
+}
+
+/// `value` as is when it already looks like JSON, or else decompressed by
+/// its header; Vencord sends raw deflate, which has none. Reads at most one
+/// byte past `max_size`, enough to tell the limit was exceeded.
+pub fn decompress_settings(value: &[u8], max_size: usize) -> Result<Vec<u8>, AppError> {
+    let trimmed = value.trim_ascii();
+    if matches!(
+        (trimmed.first(), trimmed.last()),
+        (Some(b'{'), Some(b'}')) | (Some(b'['), Some(b']'))
+    ) {
+        return Ok(value.to_vec());
+    }
+
+    let limit = max_size as u64 + 1;
+    let mut json = Vec::new();
+    let read = match value {
+        [0x1f, 0x8b, ..] => GzDecoder::new(value).take(limit).read_to_end(&mut json),
+        [cmf, flg, ..] if cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0 => {
+            ZlibDecoder::new(value).take(limit).read_to_end(&mut json)
+        }
+        _ => DeflateDecoder::new(value)
+            .take(limit)
+            .read_to_end(&mut json),
+    };
+    read.map_err(|_| {
+        AppError::InvalidSettings("Settings are neither JSON nor compressed JSON".into())
+    })?;
+    Ok(json)
+}
+
+/// Whether objects and arrays in `json` nest more than `max_depth` deep,
+/// counted before parsing so deep input cannot exhaust the parser.
+fn nests_deeper_than(json: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in json {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Requires the body to be `expected`; media type parameters such as
+/// `charset` are ignored.
+pub fn require_content_type(headers: &HeaderMap, expected: &str) -> Result<(), AppError> {
+    let media_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.split(';').next())
+        .map(str::trim);
+    if !media_type.is_some_and(|m| m.eq_ignore_ascii_case(expected)) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "Content type must be {}",
+            expected
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn format_size(bytes: usize) -> String {
+    const MB: usize = 1024 * 1024;
+    const KB: usize = 1024;
+    if bytes >= MB && bytes.is_multiple_of(MB) {
+        format!("{}MB", bytes / MB)
+    } else if bytes >= KB && bytes.is_multiple_of(KB) {
+        format!("{}KB", bytes / KB)
+    } else {
+        format!("{} byte", bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_allowed_keys_are_accepted(key in "[A-Za-z0-9_./-]{1,256}") {
+            prop_assume!(!key.split('/').any(|s| s == "." || s == ".."));
+            prop_assert_eq!(validate_key(&key), Ok(()));
+        }
+
+        #[test]
+        fn test_accepted_keys_are_allowed(key in any::<String>()) {
+            if validate_key(&key).is_ok() {
+                prop_assert!(!key.is_empty() && key.len() <= MAX_KEY_NAME_LEN);
+                prop_assert!(key.is_ascii());
+                prop_assert!(!key.split('/').any(|s| s == "." || s == ".."));
+            }
+        }
+
+        #[test]
+        fn test_long_keys_are_rejected(key in "[A-Za-z0-9_./-]{257,400}") {
+            prop_assert_eq!(validate_key(&key), Err(KeyValidationError::TooLong));
+        }
+
+        #[test]
+        fn test_non_ascii_is_rejected(
+            before in "[a-z]{0,20}",
+            c in any::<char>().prop_filter("non-ASCII", |c| !c.is_ascii()),
+            after in "[a-z]{0,20}",
+        ) {
+            let key = format!("{}{}{}", before, c, after);
+            prop_assert_eq!(validate_key(&key), Err(KeyValidationError::InvalidChars));
+        }
+
+        #[test]
+        fn test_control_characters_are_rejected(
+            before in "[a-z]{0,20}",
+            c in prop_oneof![0u8..0x20, Just(0x7f)],
+            after in "[a-z]{0,20}",
+        ) {
+            let key = format!("{}{}{}", before, c as char, after);
+            prop_assert_eq!(validate_key(&key), Err(KeyValidationError::InvalidChars));
+        }
+
+        #[test]
+        fn test_traversal_is_rejected(
+            before in prop::collection::vec("[a-z]{1,8}", 0..4),
+            dots in prop_oneof![Just("."), Just("..")],
+            after in prop::collection::vec("[a-z]{1,8}", 0..4),
+        ) {
+            let segments: Vec<&str> = before
+                .iter()
+                .map(String::as_str)
+                .chain([dots])
+                .chain(after.iter().map(String::as_str))
+                .collect();
+            prop_assert_eq!(
+                validate_key(&segments.join("/")),
+                Err(KeyValidationError::Traversal)
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_edge_cases() {
+        assert_eq!(validate_key(""), Err(KeyValidationError::Empty));
+        assert_eq!(validate_key("dataStore/a.b"), Ok(()));
+        assert_eq!(validate_key("a/...b/.c"), Ok(()));
+        assert_eq!(validate_key("a/../b"), Err(KeyValidationError::Traversal));
+        assert_eq!(validate_key(".."), Err(KeyValidationError::Traversal));
+        assert_eq!(
+            validate_key("a\\..\\b"),
+            Err(KeyValidationError::InvalidChars)
+        );
+        assert_eq!(
+            validate_key("a%2F..%2Fb"),
+            Err(KeyValidationError::InvalidChars)
+        );
+        assert_eq!(validate_key("ａ"), Err(KeyValidationError::InvalidChars));
+    }
+
+    #[test]
+    fn test_sizes_and_settings() {
+        assert!(check_size(8, 8, "Value").is_ok());
+        assert_eq!(
+            check_size(9, 8, "Value").unwrap_err().message(),
+            "Value exceeds 8 byte limit"
+        );
+        assert_eq!(
+            check_size(usize::MAX, 2 * 1024 * 1024, "Value")
+                .unwrap_err()
+                .message(),
+            "Value exceeds 2MB limit"
+        );
+
+        let config =
+            Config::from_lookup(|var| (var == "MAX_BACKUP_SIZE_BYTES").then(|| "8".to_string()));
+        assert_eq!(
+            check_settings(b"", &config).unwrap_err().code(),
+            "bad_request"
+        );
+        assert_eq!(
+            check_settings(&[0; 9], &config).unwrap_err().code(),
+            "payload_too_large"
+        );
+        assert!(check_settings(b"settings", &config).is_ok());
+    }
+
+    #[test]
+    fn test_settings_json() {
+        use flate2::Compression;
+        use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+        use std::io::Write;
+
+        let json = br#"{"plugins": {"a": [1, 2, {"b": "[[{{"}]}, "c": "\\"}"#;
+        let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(json).unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(json).unwrap();
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(json).unwrap();
+        for value in [
+            json.to_vec(),
+            deflate.finish().unwrap(),
+            zlib.finish().unwrap(),
+            gzip.finish().unwrap(),
+        ] {
+            assert!(check_settings_json(&value, 4, 1024).is_ok());
+            assert_eq!(
+                check_settings_json(&value, 3, 1024).unwrap_err().message(),
+                "Settings nest deeper than 3 levels"
+            );
+            assert_eq!(
+                check_settings_json(&value, 4, 16).unwrap_err().code(),
+                "payload_too_large"
+            );
+        }
+
+        for bad in [&b"{\"a\": }"[..], b"[1, 2]", b"{} {}", b"\x00\x01garbage"] {
+            assert_eq!(
+                check_settings_json(bad, 4, 1024).unwrap_err().code(),
+                "invalid_settings"
+            );
+        }
+        let deep = format!("{}{}", "{\"a\":".repeat(1000), "}".repeat(1000));
+        assert!(check_settings_json(deep.as_bytes(), 128, 1 << 20).is_err());
+    }
+
+    #[test]
+    fn test_require_content_type() {
+        let mut headers = HeaderMap::new();
+        assert!(require_content_type(&headers, OCTET_STREAM).is_err());
+        headers.insert(
+            CONTENT_TYPE,
+            "Application/Octet-Stream; x=1".parse().unwrap(),
+        );
+        assert!(require_content_type(&headers, OCTET_STREAM).is_ok());
+        assert_eq!(
+            require_content_type(&headers, TAR).unwrap_err().code(),
+            "unsupported_media_type"
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! Notifies operators of account events over HTTP.
+//!
+//! Every URL in `WEBHOOK_URLS` receives the events listed in
+//! `WEBHOOK_EVENTS`. Discord webhook URLs get a chat message; anything else
+//! gets a JSON body signed with `WEBHOOK_SECRET` so the receiver can check it
+//! came from this server. Failed deliveries are retried with exponential
+//! backoff in the background and never hold up the request that caused them.
+
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::config::{Config, ConfigHandle};
+use crate::constants::{WEBHOOK_BACKOFF_BASE_MS, WEBHOOK_BACKOFF_MAX_MS, WEBHOOK_TIMEOUT_SECS};
+use crate::events::{Event, EventBus};
+use crate::outbound;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "x-equicloud-signature";
+pub const TIMESTAMP_HEADER: &str = "x-equicloud-timestamp";
+pub const EVENT_HEADER: &str = "x-equicloud-event";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum WebhookEvent {
+    #[serde(rename = "user.signed_up")]
+    UserSignedUp,
+    #[serde(rename = "user.quota_exceeded")]
+    QuotaExceeded,
+    #[serde(rename = "user.deleted")]
+    AccountDeleted,
+}
+
+impl WebhookEvent {
+    pub const ALL: [Self; 3] = [
+        Self::UserSignedUp,
+        Self::QuotaExceeded,
+        Self::AccountDeleted,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UserSignedUp => "user.signed_up",
+            Self::QuotaExceeded => "user.quota_exceeded",
+            Self::AccountDeleted => "user.deleted",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.name() == name)
+    }
+
+    /// The webhook event for a bus event and the user it concerns, if any
+    /// webhook cares about it.
+    fn from_event(event: &Event) -> Option<(Self, &str)> {
+        match event {
+            Event::UserSignedUp { user_id } => Some((Self::UserSignedUp, user_id)),
+            Event::QuotaExceeded { user_id } => Some((Self::QuotaExceeded, user_id)),
+            Event::AccountDeleted { user_id } => Some((Self::AccountDeleted, user_id)),
+            _ => None,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Self::UserSignedUp => "New user signed up",
+            Self::QuotaExceeded => "User exceeded their storage quota",
+            Self::AccountDeleted => "User deleted their account",
+        }
+    }
+}
+
+/// Parses a comma-separated list of event names, e.g.
+/// `user.signed_up,user.deleted`.
+pub fn parse_events(s: &str) -> Option<Vec<WebhookEvent>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(WebhookEvent::parse)
+        .collect()
+}
+
+/// Parses a comma-separated list of `http`/`https` URLs.
+pub fn parse_urls(s: &str) -> Option<Vec<Url>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            Url::parse(url)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    user_id: &'a str,
+    timestamp: i64,
+}
+
+/// Whether `url` is a Discord webhook, which only accepts its own message
+/// format.
+pub fn is_discord(url: &Url) -> bool {
+    matches!(
+        url.host_str(),
+        Some("discord.com" | "discordapp.com" | "ptb.discord.com" | "canary.discord.com")
+    ) && url.path().starts_with("/api/webhooks/")
+}
+
+/// The body posted to `url` for an event.
+fn body_for(url: &Url, event: WebhookEvent, user_id: &str, timestamp: i64) -> Vec<u8> {
+    let body = if is_discord(url) {
+        json!({
+            "content": format!("{}: `{}`", event.describe(), user_id),
+            "allowed_mentions": { "parse": [] },
+        })
+    } else {
+        json!(Payload {
+            event,
+            user_id,
+            timestamp,
+        })
+    };
+    body.to_string().into_bytes()
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `{timestamp}.{body}`. Covering the
+/// timestamp lets receivers reject replayed deliveries.
+pub fn sign(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delay before retry `attempt` (1-based): exponential, capped.
+fn backoff_delay(attempt: u32) -> Duration {
+    let factor = 1u64 << attempt.saturating_sub(1).min(16);
+    Duration::from_millis(
+        WEBHOOK_BACKOFF_BASE_MS
+            .saturating_mul(factor)
+            .min(WEBHOOK_BACKOFF_MAX_MS),
+    )
+}
+
+struct Delivery {
+    url: Url,
+    event: WebhookEvent,
+    body: Vec<u8>,
+    timestamp: i64,
+}
+
+/// Posts one delivery, retrying network errors, 429s and 5xx responses up to
+/// `WEBHOOK_MAX_ATTEMPTS` times. Other 4xx responses, and URLs the outbound
+/// policy refuses, are not retried.
+async fn deliver(client: reqwest::Client, config: Arc<Config>, delivery: Delivery) {
+    let host = delivery.url.host_str().unwrap_or_default().to_string();
+    let max_attempts = config.webhook_max_attempts;
+
+    for attempt in 1..=max_attempts {
+        let mut request = client
+            .post(delivery.url.clone())
+            .header("content-type", "application/json")
+            .header(EVENT_HEADER, delivery.event.name())
+            .header(TIMESTAMP_HEADER, delivery.timestamp.to_string());
+        if let Some(secret) = &config.webhook_secret {
+            request = request.header(
+                SIGNATURE_HEADER,
+                sign(secret.as_bytes(), delivery.timestamp, &delivery.body),
+            );
+        }
+
+        let error = match outbound::send(&config.outbound, request.body(delivery.body.clone()))
+            .await
+        {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => {
+                let status = response.status();
+                if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    warn!(
+                        "Webhook {} to {} rejected with {}, not retrying",
+                        delivery.event.name(),
+                        host,
+                        status
+                    );
+                    return;
+                }
+                status.to_string()
+            }
+            Err(e) if e.is_refused() => {
+                warn!(
+                    "Webhook {} to {} not sent: {}",
+                    delivery.event.name(),
+                    host,
+                    e
+                );
+                return;
+            }
+            Err(e) => e.to_string(),
+        };
+
+        if attempt == max_attempts {
+            warn!(
+                "Giving up on webhook {} to {} after {} attempts: {}",
+                delivery.event.name(),
+                host,
+                attempt,
+                error
+            );
+            return;
+        }
+
+        let delay = backoff_delay(attempt);
+        warn!(
+            "Webhook {} to {} failed (attempt {}/{}), retrying in {}ms: {}",
+            delivery.event.name(),
+            host,
+            attempt,
+            max_attempts,
+            delay.as_millis(),
+            error
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Forwards matching events from `events` to every configured webhook until
+/// the bus is dropped. Each delivery runs in its own task so one slow
+/// endpoint does not delay the others. `WEBHOOK_EVENTS`, `WEBHOOK_URLS` and
+/// the other settings are read per event, so reloads apply to the next one.
+pub async fn run_dispatcher(events: EventBus, config: ConfigHandle) {
+    let client = match outbound::client_builder(config.clone())
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Webhooks disabled, failed to create HTTP client: {}", e);
+            return;
+        }
+    };
+    let mut receiver = events.subscribe();
+
+    let current = config.load();
+    if !current.webhook_urls.is_empty() {
+        info!(
+            "Sending webhooks for {} to {} endpoint(s)",
+            current
+                .webhook_events
+                .iter()
+                .map(|e| e.name())
+                .collect::<Vec<_>>()
+                .join(", "),
+            current.webhook_urls.len()
+        );
+    }
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Webhook dispatcher fell behind, {} events dropped", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let Some((event, user_id)) = WebhookEvent::from_event(&event) else {
+            continue;
+        };
+        let config = config.load();
+        if !config.webhook_events.contains(&event) {
+            continue;
+        }
+
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        for url in &config.webhook_urls {
+            let delivery = Delivery {
+                url: url.clone(),
+                event,
+                body: body_for(url, event, user_id, timestamp),
+                timestamp,
+            };
+            tokio::spawn(deliver(client.clone(), config.clone(), delivery));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_events() {
+        assert_eq!(
+            parse_events("user.signed_up, user.deleted,"),
+            Some(vec![
+                WebhookEvent::UserSignedUp,
+                WebhookEvent::AccountDeleted
+            ])
+        );
+        assert_eq!(parse_events("user.signed_up,user.renamed"), None);
+        assert_eq!(parse_events(""), Some(vec![]));
+    }
+
+    #[test]
+    fn test_parse_urls() {
+        let urls = parse_urls("https://example.com/hook, http://10.0.0.1:8080/x").unwrap();
+        assert_eq!(urls.len(), 2);
+        assert_eq!(parse_urls("ftp://example.com"), None);
+        assert_eq!(parse_urls("not a url"), None);
+    }
+
+    #[test]
+    fn test_sign() {
+        let signature = sign(b"secret", 1700000000000, b"{}");
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), "sha256=".len() + 64);
+        assert_eq!(signature, sign(b"secret", 1700000000000, b"{}"));
+        assert_ne!(signature, sign(b"secret", 1700000000001, b"{}"));
+        assert_ne!(signature, sign(b"other", 1700000000000, b"{}"));
+    }
+
+    #[test]
+    fn test_body_format() {
+        let discord = Url::parse("https://discord.com/api/webhooks/1/abc").unwrap();
+        let generic = Url::parse("https://example.com/discord").unwrap();
+        assert!(is_discord(&discord));
+        assert!(!is_discord(&generic));
+
+        let body: serde_json::Value =
+            serde_json::from_slice(&body_for(&discord, WebhookEvent::UserSignedUp, "42", 7))
+                .unwrap();
+        assert_eq!(body["content"], "New user signed up: `42`");
+        assert_eq!(body["allowed_mentions"]["parse"], json!([]));
+
+        let body: serde_json::Value =
+            serde_json::from_slice(&body_for(&generic, WebhookEvent::QuotaExceeded, "42", 7))
+                .unwrap();
+        assert_eq!(
+            body,
+            json!({ "event": "user.quota_exceeded", "user_id": "42", "timestamp": 7 })
+        );
+    }
+
+    #[test]
+    fn test_only_account_events_are_sent() {
+        let deleted = Event::AccountDeleted {
+            user_id: "42".into(),
+        };
+        assert_eq!(
+            WebhookEvent::from_event(&deleted),
+            Some((WebhookEvent::AccountDeleted, "42"))
+        );
+        let written = Event::SettingsDeleted {
+            user_id: "42".into(),
+        };
+        assert_eq!(WebhookEvent::from_event(&written), None);
+    }
+}
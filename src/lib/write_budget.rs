@@ -0,0 +1,86 @@
+//! Daily write budgets.
+//!
+//! `DAILY_WRITE_LIMIT` caps the data writes and deletions one user can make
+//! per UTC day, so a runaway client cannot flood the database. The counts are
+//! kept in the datastore and shared by every instance.
+
+use axum::response::{IntoResponseParts, ResponseParts};
+use chrono::{Days, NaiveTime, Utc};
+use std::convert::Infallible;
+use tracing::warn;
+
+use crate::audit::day_bucket;
+use crate::datastore::Datastore;
+use crate::error::{AppError, ResultExt};
+use crate::tenant::Tenant;
+
+pub const WRITE_LIMIT_HEADER: &str = "x-write-limit";
+pub const WRITE_REMAINING_HEADER: &str = "x-write-remaining";
+pub const WRITE_RESET_HEADER: &str = "x-write-reset";
+
+/// What is left of a user's budget after a request, sent back as
+/// `X-Write-Limit`, `X-Write-Remaining` and `X-Write-Reset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteBudget {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Unix time in seconds of the next UTC midnight.
+    pub resets_at: i64,
+}
+
+impl WriteBudget {
+    /// Charges `writes` operations to the user's budget for today. `None`
+    /// when the tenant sets no limit or nothing is charged. Fails with
+    /// `WriteLimitExceeded`, charging nothing, when the rest of the budget
+    /// cannot cover all of them.
+    pub async fn spend(
+        tenant: &Tenant,
+        user_id: &str,
+        writes: usize,
+    ) -> Result<Option<Self>, AppError> {
+        let limit = tenant.config.load().daily_write_limit;
+        if limit == 0 || writes == 0 {
+            return Ok(None);
+        }
+
+        let now = Utc::now();
+        let day = day_bucket(now.timestamp_millis());
+        let resets_at = (now.date_naive() + Days::new(1))
+            .and_time(NaiveTime::MIN)
+            .and_utc()
+            .timestamp();
+
+        let writes = writes as i64;
+        let used = tenant
+            .db
+            .add_daily_writes(user_id, &day, writes)
+            .await
+            .or_internal("Failed to count writes")?;
+        if used > limit as i64 {
+            if let Err(e) = tenant.db.add_daily_writes(user_id, &day, -writes).await {
+                warn!("Failed to give back refused writes: {}", e);
+            }
+            return Err(AppError::WriteLimitExceeded {
+                retry_after_secs: (resets_at - now.timestamp()).max(1) as u64,
+            });
+        }
+
+        Ok(Some(Self {
+            limit,
+            remaining: limit.saturating_sub(used as u64),
+            resets_at,
+        }))
+    }
+}
+
+impl IntoResponseParts for WriteBudget {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        let headers = res.headers_mut();
+        headers.insert(WRITE_LIMIT_HEADER, self.limit.into());
+        headers.insert(WRITE_REMAINING_HEADER, self.remaining.into());
+        headers.insert(WRITE_RESET_HEADER, self.resets_at.into());
+        Ok(res)
+    }
+}
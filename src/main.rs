@@ -1,120 +1,55 @@
-use axum::extract::DefaultBodyLimit;
 use axum::http::HeaderValue;
+use axum::serve::ListenerExt;
 use dotenv::dotenv;
-use equicloud::constants::{DB_HEALTH_CHECK_INTERVAL_SECS, DEFAULT_HOST, DEFAULT_PORT};
-use equicloud::utils::CONFIG;
-use equicloud::{DatabaseService, MigrationRunner, create_database_connection};
+use equicloud::tenant::{DEFAULT_TENANT, load_tenant_specs};
+use equicloud::tls::{self, ReloadingCert, TlsListener};
+use equicloud::utils::{CONFIG, ConfigHandle};
+use equicloud::{
+    DatabaseService, MigrationRunner, SqliteDatastore, Storage, Tenant, Tenants, connect_with_retry,
+};
 use governor::middleware::NoOpMiddleware;
-use http::Method;
-use http::header::{CONTENT_TYPE, HeaderName};
+use http::header::HeaderName;
 use std::env;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower_governor::GovernorLayer;
 use tower_governor::governor::GovernorConfigBuilder;
-use tower_governor::key_extractor::{PeerIpKeyExtractor, SmartIpKeyExtractor};
-use tower_http::cors::CorsLayer;
-use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::set_header::SetResponseHeaderLayer;
 use tracing::{error, info, warn};
 
+use crate::middleware::client_ip::ClientIpKeyExtractor;
+
+mod grpc;
 mod middleware;
 mod routes;
+mod state;
+#[cfg(test)]
+mod tests;
 
 type SecurityHeaderLayer =
     SetResponseHeaderLayer<fn(&http::Response<axum::body::Body>) -> Option<HeaderValue>>;
 
-fn configure_cors() -> CorsLayer {
-    let origins = env::var("CORS_ALLOWED_ORIGINS").ok();
-
-    match origins.as_deref() {
-        Some("*") => {
-            warn!("CORS configured for all origins - use specific origins in production!");
-            CorsLayer::permissive()
-        }
-        Some(origins_str) => {
-            let valid_origins: Vec<HeaderValue> = origins_str
-                .split(',')
-                .filter_map(|s| s.trim().parse().ok())
-                .collect();
-
-            if valid_origins.is_empty() {
-                warn!("No valid CORS origins parsed, CORS will reject cross-origin requests");
-                CorsLayer::new()
-            } else {
-                info!("CORS configured for {} origins", valid_origins.len());
-                CorsLayer::new()
-                    .allow_origin(valid_origins)
-                    .allow_methods([
-                        Method::GET,
-                        Method::POST,
-                        Method::PUT,
-                        Method::DELETE,
-                        Method::HEAD,
-                        Method::OPTIONS,
-                    ])
-                    .allow_headers([
-                        CONTENT_TYPE,
-                        HeaderName::from_static("authorization"),
-                        HeaderName::from_static("if-none-match"),
-                    ])
-                    .expose_headers([
-                        HeaderName::from_static("etag"),
-                        HeaderName::from_static("x-version"),
-                    ])
-            }
-        }
-        None => {
-            warn!("CORS_ALLOWED_ORIGINS not set - defaulting to permissive for development");
-            CorsLayer::permissive()
-        }
-    }
-}
-
-fn configure_rate_limiter_peer() -> GovernorLayer<PeerIpKeyExtractor, NoOpMiddleware, axum::body::Body>
-{
-    let (per_second, burst_size) = rate_limit_params();
-
-    let config = GovernorConfigBuilder::default()
-        .per_second(per_second)
-        .burst_size(burst_size)
-        .finish()
-        .expect("Failed to build rate limiter config");
-
-    GovernorLayer::new(config)
-}
-
-fn configure_rate_limiter_proxy(
-) -> GovernorLayer<SmartIpKeyExtractor, NoOpMiddleware, axum::body::Body> {
-    let (per_second, burst_size) = rate_limit_params();
+fn configure_rate_limiter(
+    config: ConfigHandle,
+) -> GovernorLayer<ClientIpKeyExtractor, NoOpMiddleware, axum::body::Body> {
+    let (per_second, burst_size) = {
+        let current = config.load();
+        (current.rate_limit_per_second, current.rate_limit_burst)
+    };
+    info!("Rate limiting: {} req/s, burst: {}", per_second, burst_size);
 
     let config = GovernorConfigBuilder::default()
         .per_second(per_second)
         .burst_size(burst_size)
-        .key_extractor(SmartIpKeyExtractor)
+        .key_extractor(ClientIpKeyExtractor(config))
         .finish()
         .expect("Failed to build rate limiter config");
 
     GovernorLayer::new(config)
 }
 
-fn rate_limit_params() -> (u64, u32) {
-    let per_second = env::var("RATE_LIMIT_PER_SECOND")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(50u64);
-
-    let burst_size = env::var("RATE_LIMIT_BURST")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(150u32);
-
-    info!("Rate limiting: {} req/s, burst: {}", per_second, burst_size);
-
-    (per_second, burst_size)
-}
-
 fn security_headers_layer() -> SecurityHeaderLayer {
     SetResponseHeaderLayer::overriding(
         HeaderName::from_static("x-content-type-options"),
@@ -147,7 +82,11 @@ fn referrer_policy_layer() -> SecurityHeaderLayer {
 
 #[tokio::main]
 async fn main() {
-    dotenv().ok();
+    if let Ok(path) = env::var("CONFIG_FILE") {
+        dotenv::from_path(&path).ok();
+    } else {
+        dotenv().ok();
+    }
 
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -157,126 +96,309 @@ async fn main() {
         .init();
 
     info!("Starting EquiCloud server");
-    info!("Connecting to database...");
 
-    let session = match create_database_connection().await {
-        Ok(session) => {
-            info!("Database connection successful");
-            session
+    let config = CONFIG.load();
+    if let Err(issues) = config.validate() {
+        for issue in &issues {
+            if config.allow_invalid_config {
+                warn!("Configuration: {}", issue);
+            } else {
+                error!("Configuration: {}", issue);
+            }
         }
-        Err(e) => {
-            error!("Failed to connect to database: {}", e);
+        if !config.allow_invalid_config {
+            error!(
+                "Refusing to start with {} configuration problem(s)",
+                issues.len()
+            );
             std::process::exit(1);
         }
-    };
-
-    info!("Running migrations...");
-    let migration_runner = MigrationRunner::new(&session);
-    if let Err(e) = migration_runner.run_migrations().await {
-        error!("Failed to run migrations: {}", e);
-        std::process::exit(1);
     }
-    info!("Migrations completed");
 
-    let db_service = match DatabaseService::new(session).await {
-        Ok(service) => service,
-        Err(e) => {
-            error!("Failed to create database service: {}", e);
-            std::process::exit(1);
+    let tenants = load_tenants(open_storage(CONFIG.clone()).await).await;
+    if config.dev_mode {
+        warn!("Developer mode: requests may name their user in X-Dev-User-Id without a token");
+        let tenant = tenants.default_tenant();
+        match equicloud::dev::seed(&tenant.db, config.checksum_algorithm).await {
+            Ok(seeded) => info!("Seeded {} sample users", seeded),
+            Err(e) => warn!("Failed to seed sample users: {}", e),
         }
-    };
-
-    let server_host = env::var("SERVER_HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string());
-    let server_port = env::var("SERVER_PORT").unwrap_or_else(|_| DEFAULT_PORT.to_string());
-    let bind_address = format!("{}:{}", server_host, server_port);
+    }
 
-    let rate_limit_enabled = env::var("RATE_LIMIT_ENABLED")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(true);
+    let bind_address = config.bind_address(config.server_port);
 
-    let trust_proxy_headers = env::var("TRUST_PROXY_HEADERS")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(false);
+    let app_state = state::AppState::with_tenants(tenants);
 
-    let cors = configure_cors();
+    if config.dedup_enabled {
+        info!("Blob deduplication enabled");
+    }
+    for tenant in app_state.tenants.all() {
+        let backup_interval = tenant.config.load().backup_interval;
+        if !backup_interval.is_zero() {
+            tokio::spawn(equicloud::backup::run_scheduler(
+                tenant.db.clone(),
+                tenant.config.clone(),
+                tenant.id.clone(),
+                backup_interval,
+            ));
+        }
+        tokio::spawn(equicloud::account_deletion::run_purger(
+            tenant.db.clone(),
+            tenant.config.clone(),
+            app_state.events.clone(),
+        ));
+        tokio::spawn(equicloud::stats::run_rollup(tenant.db.clone()));
+        let compaction_interval = tenant.config.load().history_compaction_interval;
+        if !compaction_interval.is_zero() {
+            tokio::spawn(equicloud::compaction::run_compactor(
+                tenant.db.clone(),
+                tenant.config.clone(),
+                compaction_interval,
+                app_state.metrics.clone(),
+            ));
+        }
+        let scrub_interval = tenant.config.load().scrub_interval;
+        if !scrub_interval.is_zero() {
+            tokio::spawn(equicloud::scrub::run_scrubber(
+                tenant.db.clone(),
+                tenant.config.clone(),
+                scrub_interval,
+                app_state.metrics.clone(),
+            ));
+        }
+        let Some(scylla) = tenant.db.scylla() else {
+            continue;
+        };
+        let config = tenant.config.load();
+        if config.inactivity_ttl_days > 0 {
+            tokio::spawn(equicloud::retention::run_sweeper(
+                scylla.clone(),
+                tenant.config.clone(),
+                app_state.metrics.clone(),
+                app_state.events.clone(),
+            ));
+        }
+        tokio::spawn(equicloud::dedup::run_collector(scylla.clone()));
+    }
 
-    let max_body_size = CONFIG.max_backup_size_bytes + 4096;
+    tokio::spawn(equicloud::webhooks::run_dispatcher(
+        app_state.events.clone(),
+        CONFIG.clone(),
+    ));
+    tokio::spawn(equicloud::reload::watch_config(app_state.tenants.clone()));
+
+    let health_check_tenants = app_state.tenants.clone();
+    let db_health = app_state.db_health.clone();
+
+    if let Some(grpc_port) = config.grpc_port {
+        let grpc_address = config.bind_address(grpc_port);
+        let listener = bind(&grpc_address).await;
+        info!("gRPC API running on {}", grpc_address);
+        let routes = tonic::service::Routes::from(grpc::router(app_state.clone()));
+        tokio::spawn(async move {
+            let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_routes(routes)
+                .serve_with_incoming(incoming)
+                .await
+            {
+                error!("gRPC server failed: {}", e);
+            }
+        });
+    }
 
-    let app = routes::register_routes()
-        .layer(axum::extract::Extension(db_service.clone()))
-        .layer(cors)
+    let app = routes::register_routes(&app_state)
+        .with_state(app_state)
         .layer(security_headers_layer())
         .layer(frame_options_layer())
         .layer(cache_control_layer())
         .layer(referrer_policy_layer())
-        .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(max_body_size));
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+    if config.trust_proxy_headers {
+        warn!("TRUST_PROXY_HEADERS is set: every peer may name the client address");
+    } else if !config.trusted_proxies.is_empty() {
+        info!(
+            "Reading client addresses from {} trusted proxy ranges",
+            config.trusted_proxies.len()
+        );
+    }
 
-    let app = match (rate_limit_enabled, trust_proxy_headers) {
-        (true, true) => {
-            info!("Rate limiting enabled (trusting proxy headers)");
-            app.layer(configure_rate_limiter_proxy())
-        }
-        (true, false) => {
-            info!("Rate limiting enabled (using peer IP)");
-            app.layer(configure_rate_limiter_peer())
-        }
-        (false, _) => {
-            warn!("Rate limiting disabled");
-            app
-        }
+    let app = if config.rate_limit_enabled {
+        info!("Rate limiting enabled");
+        app.layer(configure_rate_limiter(CONFIG.clone()))
+    } else {
+        warn!("Rate limiting disabled");
+        app
     };
 
-    let listener = TcpListener::bind(&bind_address).await.unwrap_or_else(|e| {
-        error!("Failed to bind to address {}: {}", bind_address, e);
-        std::process::exit(1);
-    });
+    tokio::spawn(equicloud::db_health::run_monitor(
+        health_check_tenants,
+        db_health,
+        config.db_health_check_interval,
+    ));
+
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    let result =
+        if let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path) {
+            let cert = ReloadingCert::load(cert_path, key_path).unwrap_or_else(|e| {
+                error!("Failed to load TLS certificate: {:#}", e);
+                std::process::exit(1);
+            });
+            let cert = Arc::new(cert);
+            tokio::spawn(tls::watch_certificate(
+                cert.clone(),
+                config.tls_reload_interval,
+            ));
+            let server_config = tls::server_config(cert).unwrap_or_else(|e| {
+                error!("Invalid TLS configuration: {:#}", e);
+                std::process::exit(1);
+            });
+
+            let tls_address = config.bind_address(config.tls_port);
+            let listener = TlsListener::new(bind(&tls_address).await, server_config)
+                .unwrap_or_else(|e| {
+                    error!("Failed to start TLS listener on {}: {}", tls_address, e);
+                    std::process::exit(1);
+                })
+                .tap_io(|tls| {
+                    let _ = tls.get_ref().0.set_nodelay(true);
+                });
+
+            if config.tls_redirect_http {
+                let redirect = bind(&bind_address).await;
+                info!("Redirecting http://{} to HTTPS", bind_address);
+                let tls_port = config.tls_port;
+                tokio::spawn(async move {
+                    if let Err(e) = axum::serve(redirect, tls::redirect_router(tls_port)).await {
+                        error!("HTTP redirect server failed: {}", e);
+                    }
+                });
+            }
 
-    info!("Server running on http://{}", bind_address);
+            info!("Server running on https://{}", tls_address);
+            axum::serve(listener, app).await
+        } else {
+            let listener = bind(&bind_address).await;
+            info!("Server running on http://{}", bind_address);
+            axum::serve(listener, app).await
+        };
 
-    let health_check_db = db_service;
-    tokio::spawn(async move {
-        let mut consecutive_failures = 0;
-        const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+    if let Err(e) = result {
+        error!("Server failed to start: {}", e);
+        std::process::exit(1);
+    }
+}
 
-        loop {
-            tokio::time::sleep(Duration::from_secs(DB_HEALTH_CHECK_INTERVAL_SECS)).await;
+/// Binds a TCP listener, exiting if the address is unavailable.
+async fn bind(address: &str) -> TcpListener {
+    TcpListener::bind(address).await.unwrap_or_else(|e| {
+        error!("Failed to bind to address {}: {}", address, e);
+        std::process::exit(1);
+    })
+}
 
-            match health_check_db.health_check().await {
-                Ok(_) => {
-                    if consecutive_failures > 0 {
-                        info!("Database connection restored");
-                        consecutive_failures = 0;
-                    }
-                }
-                Err(e) => {
-                    consecutive_failures += 1;
-                    error!(
-                        "Database health check failed ({}/{}): {}",
-                        consecutive_failures, MAX_CONSECUTIVE_FAILURES, e
-                    );
-
-                    if consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
-                        error!(
-                            "Database connection lost after {} consecutive failures, shutting down",
-                            MAX_CONSECUTIVE_FAILURES
-                        );
-                        std::process::exit(1);
-                    }
-                }
+/// Opens the storage backend `config` selects. Exits on failure.
+async fn open_storage(handle: ConfigHandle) -> Storage {
+    let config = handle.load();
+    if config.storage_backend == "sqlite" {
+        info!("Opening SQLite database at {}", config.sqlite_path);
+        match SqliteDatastore::open_with_config(&config.sqlite_path, handle) {
+            Ok(store) => Storage::Sqlite(store),
+            Err(e) => {
+                error!("Failed to open SQLite database: {}", e);
+                std::process::exit(1);
             }
         }
+    } else {
+        Storage::Scylla(connect_scylla(handle).await)
+    }
+}
+
+/// The default tenant, stored in `default`, plus every tenant listed in
+/// `TENANTS_FILE` with its own storage opened. Exits on failure.
+async fn load_tenants(default: Storage) -> Tenants {
+    let mut tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, CONFIG.clone(), default));
+    let base = CONFIG.load();
+    let Some(path) = &base.tenants_file else {
+        return tenants;
+    };
+
+    let specs = load_tenant_specs(path).unwrap_or_else(|e| {
+        error!("Failed to load tenants from {}: {:#}", path, e);
+        std::process::exit(1);
     });
+    for spec in specs {
+        let config = match spec.apply(&base) {
+            Ok(config) => ConfigHandle::new(config),
+            Err(e) => {
+                error!("Tenant {}: {:#}", spec.id, e);
+                std::process::exit(1);
+            }
+        };
+        info!("Adding tenant {}", spec.id);
+        let db = open_storage(config.clone()).await;
+        let hosts = spec.hosts.clone();
+        tenants.add(Tenant::from_spec(spec, config, db), &hosts);
+    }
+    tenants
+}
 
-    if let Err(e) = axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
+/// Connects to Scylla, bootstraps the keyspace unless `--no-bootstrap` is
+/// given, runs migrations and prepares the database service. Exits on failure.
+async fn connect_scylla(handle: ConfigHandle) -> DatabaseService {
+    let config = handle.load();
+    info!("Connecting to database...");
+
+    let session = match connect_with_retry(&config).await {
+        Ok(session) => {
+            info!("Database connection successful");
+            session
+        }
+        Err(e) => {
+            error!("Failed to connect to database: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let migration_runner = MigrationRunner::for_keyspace(&session, &config.keyspace);
+    if env::args().any(|arg| arg == "--no-bootstrap") {
+        match migration_runner.keyspace_exists().await {
+            Ok(true) => {}
+            Ok(false) => {
+                error!(
+                    "Keyspace {} does not exist; create it or start without --no-bootstrap",
+                    config.keyspace
+                );
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!("Failed to check for keyspace: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Err(e) = migration_runner
+        .bootstrap(&config.keyspace_replication())
+        .await
     {
-        error!("Server failed to start: {}", e);
+        error!("Failed to bootstrap schema: {}", e);
         std::process::exit(1);
     }
+
+    info!("Running migrations...");
+    if let Err(e) = migration_runner.run_migrations().await {
+        error!("Failed to run migrations: {}", e);
+        std::process::exit(1);
+    }
+    info!("Migrations completed");
+
+    match DatabaseService::with_config(session, handle.clone()).await {
+        Ok(service) => service,
+        Err(e) => {
+            error!("Failed to create database service: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,36 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use equicloud::error::AppError;
+use equicloud::utils::ConfigHandle;
+use tracing::warn;
+
+use super::auth::constant_time_eq;
+
+/// Guards `/admin/*` with the `ADMIN_TOKEN` bearer token. The routes are
+/// hidden entirely when no token is configured.
+pub async fn admin_middleware(
+    State(config): State<ConfigHandle>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let config = config.load();
+    let expected = config.admin_token.as_deref().ok_or(AppError::NotFound)?;
+
+    let auth_header = request
+        .headers()
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(AppError::unauthorized)?;
+
+    let token = auth_header.strip_prefix("Bearer ").unwrap_or(auth_header);
+
+    if !constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+        warn!("Rejected admin request with invalid token");
+        return Err(AppError::unauthorized());
+    }
+
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,116 @@
+use axum::{
+    extract::{FromRef, FromRequestParts, MatchedPath},
+    http::request::Parts,
+};
+use equicloud::audit::{self, AuditAction, AuditActor, AuditEntry};
+use equicloud::client_ip::ClientIp;
+use equicloud::utils::{ConfigHandle, hash_user_id};
+use equicloud::{Storage, Tenants};
+use std::convert::Infallible;
+use std::sync::Arc;
+
+/// Where a request came in, for audit entries: the matched route, the
+/// `x-request-id` assigned at the edge and the client address. Entries are
+/// kept for the `AUDIT_RETENTION_DAYS` of the request's tenant.
+#[derive(Clone)]
+pub struct AuditContext {
+    route: String,
+    request_id: String,
+    client_ip: Option<String>,
+    config: ConfigHandle,
+}
+
+impl<S> FromRequestParts<S> for AuditContext
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let path = parts
+            .extensions
+            .get::<MatchedPath>()
+            .map(|p| p.as_str())
+            .unwrap_or_else(|| parts.uri.path());
+        let request_id = parts
+            .headers
+            .get("x-request-id")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default();
+
+        Ok(Self {
+            route: format!("{} {}", parts.method, path),
+            request_id: request_id.to_string(),
+            client_ip: parts
+                .extensions
+                .get::<ClientIp>()
+                .map(|ClientIp(ip)| ip.to_string()),
+            config: Arc::<Tenants>::from_ref(state)
+                .resolve_or_default(&parts.headers)
+                .config
+                .clone(),
+        })
+    }
+}
+
+impl AuditContext {
+    /// Writes an audit entry about `user_id` (unhashed) for an operation
+    /// that has already run.
+    pub async fn record(
+        &self,
+        db: &Storage,
+        actor: AuditActor,
+        user_id: Option<&str>,
+        action: AuditAction,
+        detail: Option<String>,
+        succeeded: bool,
+    ) {
+        self.record_hashed(
+            db,
+            actor,
+            user_id.map(hash_user_id),
+            action,
+            detail,
+            succeeded,
+        )
+        .await;
+    }
+
+    /// [`Self::record`] for a user known only by hashed id.
+    pub async fn record_hashed(
+        &self,
+        db: &Storage,
+        actor: AuditActor,
+        user_hash: Option<String>,
+        action: AuditAction,
+        detail: Option<String>,
+        succeeded: bool,
+    ) {
+        let entry = self.entry(actor, user_hash, action, detail, succeeded);
+        audit::record(db, &self.config.load(), entry).await;
+    }
+
+    /// The entry [`Self::record_hashed`] writes, for operations that record
+    /// their own.
+    pub fn entry(
+        &self,
+        actor: AuditActor,
+        user_hash: Option<String>,
+        action: AuditAction,
+        detail: Option<String>,
+        succeeded: bool,
+    ) -> AuditEntry {
+        AuditEntry {
+            at: chrono::Utc::now().timestamp_millis(),
+            request_id: self.request_id.clone(),
+            actor: actor.as_str().to_string(),
+            user_hash,
+            action: action.as_str().to_string(),
+            route: self.route.clone(),
+            client_ip: self.client_ip.clone(),
+            detail,
+            outcome: if succeeded { "success" } else { "failure" }.to_string(),
+        }
+    }
+}
@@ -1,9 +1,27 @@
-use axum::{extract::Request, http::StatusCode, middleware::Next, response::Response};
-use base64::prelude::*;
-use tracing::warn;
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+};
+use equicloud::abuse;
+use equicloud::account_deletion;
+use equicloud::api_keys::{hash_api_key_secret, parse_api_key};
+use equicloud::auth_events::{self, AuthOutcome};
+use equicloud::client_ip::ClientIp;
+use equicloud::dev::DEV_USER_HEADER;
+use equicloud::error::{AppError, ResultExt};
+use equicloud::lockout::{self, Subject};
+use equicloud::oauth::{parse_token, verify_scoped_session_secret};
+use equicloud::scopes::{Scope, TokenScopes};
+use equicloud::stats;
+use equicloud::user_secrets;
+use equicloud::utils::Config;
+use equicloud::{Datastore, DbHealth, Metrics, Storage, Tenant, Tenants};
+use std::sync::Arc;
+
+use super::tenant::CurrentTenant;
 
 #[inline]
-fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
     if a.len() != b.len() {
         return false;
     }
@@ -13,36 +31,391 @@ fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
         == 0
 }
 
-pub async fn auth_middleware(mut request: Request, next: Next) -> Result<Response, StatusCode> {
-    let auth_header = request
-        .headers()
-        .get("authorization")
+/// The authenticated user id. Taking this extractor is what makes a handler
+/// require authentication; requests without a valid token are rejected with
+/// 401 before the handler runs, and users outside the tenant's allowed list
+/// with 403, as are banned users and tokens without the scope the request's
+/// method needs. Users, API keys and addresses with too many failed sign-ins
+/// are refused with 429 until their lockout ends. Accounts scheduled for
+/// deletion are refused with 410. Tokens limited to a key prefix are refused
+/// too, as the handler does not check keys against it. A token of an
+/// identity linked to another account resolves to that account, so this is
+/// always the id storage is keyed by.
+pub struct AuthUser(pub String);
+
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let needed = Scope::for_method(&parts.method);
+        let (user_id, _) = authenticate_recorded(parts, state, needed, false, false).await?;
+        Ok(AuthUser(user_id))
+    }
+}
+
+/// [`AuthUser`] for handlers that check the token's scopes themselves, per
+/// key where they deal in keys, and so also accept tokens limited to a key
+/// prefix.
+pub struct ScopedUser(pub String, pub TokenScopes);
+
+impl<S> FromRequestParts<S> for ScopedUser
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let needed = Scope::for_method(&parts.method);
+        let (user_id, scopes) = authenticate_recorded(parts, state, needed, true, false).await?;
+        Ok(ScopedUser(user_id, scopes))
+    }
+}
+
+/// [`ScopedUser`] that also accepts accounts scheduled for deletion, for
+/// the one route that can restore them.
+pub struct RestoringUser(pub String, pub TokenScopes);
+
+impl<S> FromRequestParts<S> for RestoringUser
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let needed = Scope::for_method(&parts.method);
+        let (user_id, scopes) = authenticate_recorded(parts, state, needed, true, true).await?;
+        Ok(RestoringUser(user_id, scopes))
+    }
+}
+
+/// [`ScopedUser`] for `POST` routes that only read, which need the read
+/// scope rather than the write scope.
+pub struct ReadingUser(pub String, pub TokenScopes);
+
+impl<S> FromRequestParts<S> for ReadingUser
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let (user_id, scopes) =
+            authenticate_recorded(parts, state, Scope::Read, true, false).await?;
+        Ok(ReadingUser(user_id, scopes))
+    }
+}
+
+async fn authenticate_recorded<S>(
+    parts: &mut Parts,
+    state: &S,
+    needed: Scope,
+    allow_prefix: bool,
+    allow_pending: bool,
+) -> Result<(String, TokenScopes), AppError>
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    if let Some(Authenticated(user_id, scopes)) = parts.extensions.get::<Authenticated>() {
+        check_scope(scopes, needed, allow_prefix)?;
+        return Ok((user_id.clone(), scopes.clone()));
+    }
+
+    let mut attempt = Attempt {
+        user_id: None,
+        outcome: AuthOutcome::MissingToken,
+    };
+    let result = authenticate(
+        parts,
+        state,
+        needed,
+        allow_prefix,
+        allow_pending,
+        &mut attempt,
+    )
+    .await;
+    if let (Ok((user_id, scopes)), false) = (&result, allow_pending) {
+        parts
+            .extensions
+            .insert(Authenticated(user_id.clone(), scopes.clone()));
+    }
+    let tenant = Arc::<Tenants>::from_ref(state).resolve_or_default(&parts.headers);
+    auth_events::record(
+        &Arc::<Metrics>::from_ref(state),
+        &tenant.config.load(),
+        attempt.outcome,
+        attempt.user_id.as_deref(),
+    );
+    result
+}
+
+/// A request's identity once authenticated, so middleware and the handler
+/// behind it check the token, and count the sign-in, only once. Only kept
+/// for accounts not scheduled for deletion.
+#[derive(Clone)]
+struct Authenticated(String, TokenScopes);
+
+/// Who a request's token named and how authenticating it ended, filled in
+/// as far as authentication got.
+struct Attempt {
+    user_id: Option<String>,
+    outcome: AuthOutcome,
+}
+
+async fn authenticate<S>(
+    parts: &mut Parts,
+    state: &S,
+    needed: Scope,
+    allow_prefix: bool,
+    allow_pending: bool,
+    attempt: &mut Attempt,
+) -> Result<(String, TokenScopes), AppError>
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    let (user_id, method, scopes) = match dev_user(parts, state).await? {
+        Some(user_id) => {
+            attempt.user_id = Some(user_id.clone());
+            (user_id, AuthOutcome::DevUser, TokenScopes::full())
+        }
+        None => {
+            let auth_header = parts
+                .headers
+                .get("authorization")
+                .and_then(|h| h.to_str().ok())
+                .ok_or_else(AppError::unauthorized)?
+                .to_string();
+
+            attempt.outcome = AuthOutcome::Error;
+            let CurrentTenant(tenant) = CurrentTenant::from_request_parts(parts, state).await?;
+            verify_credential(&tenant, parts, &auth_header, attempt).await?
+        }
+    };
+
+    attempt.outcome = AuthOutcome::Error;
+    let CurrentTenant(tenant) = CurrentTenant::from_request_parts(parts, state).await?;
+
+    if let Err(e) = check_scope(&scopes, needed, allow_prefix) {
+        attempt.outcome = AuthOutcome::OutOfScope;
+        return Err(e);
+    }
+
+    if !tenant.config.load().user_allowed(&user_id) {
+        attempt.outcome = AuthOutcome::NotAllowed;
+        return Err(AppError::Forbidden("User is not whitelisted".into()));
+    }
+
+    let account = tenant
+        .db
+        .get_account_link(&user_id)
+        .await
+        .or_internal("Failed to resolve account")?;
+
+    for id in [Some(&user_id), account.as_ref()].into_iter().flatten() {
+        abuse::check_ban(&tenant.db, id).await.inspect_err(|e| {
+            if matches!(e, AppError::Banned { .. }) {
+                attempt.outcome = AuthOutcome::Banned;
+            }
+        })?;
+    }
+
+    let user_id = account.unwrap_or(user_id);
+    if !allow_pending {
+        account_deletion::check_pending(&tenant.db, &user_id)
+            .await
+            .inspect_err(|e| {
+                if matches!(e, AppError::Gone(_)) {
+                    attempt.outcome = AuthOutcome::PendingDeletion;
+                }
+            })?;
+    }
+
+    stats::mark_active(&tenant, &user_id).await;
+    attempt.outcome = method;
+    Ok((user_id, scopes))
+}
+
+/// Whether `scopes` hold `needed`, and reach every key unless the handler
+/// checks keys against the prefix itself.
+fn check_scope(scopes: &TokenScopes, needed: Scope, allow_prefix: bool) -> Result<(), AppError> {
+    if allow_prefix {
+        scopes.require(needed)
+    } else {
+        scopes.require_account(needed)
+    }
+}
+
+/// The user named by `X-Dev-User-Id`, if the request's tenant is in
+/// `DEV_MODE`.
+async fn dev_user<S>(parts: &mut Parts, state: &S) -> Result<Option<String>, AppError>
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    let Some(user_id) = parts
+        .headers
+        .get(DEV_USER_HEADER)
         .and_then(|h| h.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+    else {
+        return Ok(None);
+    };
+    let CurrentTenant(tenant) = CurrentTenant::from_request_parts(parts, state).await?;
+    Ok(tenant.config.load().dev_mode.then_some(user_id))
+}
+
+/// The user id an `Authorization` header holding a token or API key signs
+/// in as, how it signed in and its scopes. While the key, the address or the
+/// user from that address is locked out it is refused unchecked; otherwise a
+/// wrong secret counts towards a lockout.
+async fn verify_credential(
+    tenant: &Tenant,
+    parts: &Parts,
+    auth_header: &str,
+    attempt: &mut Attempt,
+) -> Result<(String, AuthOutcome, TokenScopes), AppError> {
+    let api_key = parse_api_key(auth_header);
+    let ip = parts.extensions.get::<ClientIp>().map(|ClientIp(ip)| *ip);
+    let mut subjects = Vec::new();
+    match api_key {
+        Some((id, _)) => subjects.push(Subject::ApiKey(id.to_string())),
+        None => {
+            if let Some((_, user_id)) = parse_token(auth_header) {
+                subjects.push(Subject::user(&user_id, ip));
+                attempt.user_id = Some(user_id);
+            }
+        }
+    }
+    subjects.extend(ip.map(Subject::Ip));
 
-    let token = auth_header.strip_prefix("Bearer ").unwrap_or(auth_header);
+    if let Err(e) = lockout::check(tenant, &subjects).await {
+        attempt.outcome = AuthOutcome::LockedOut;
+        return Err(e);
+    }
 
-    let user_id = verify_token(token).ok_or(StatusCode::UNAUTHORIZED)?;
-    request.extensions_mut().insert(user_id);
-    Ok(next.run(request).await)
+    let verified = match api_key {
+        Some((id, secret)) => verify_api_key(&tenant.db, id, secret, attempt)
+            .await
+            .map(|(user_id, scopes)| (user_id, AuthOutcome::ApiKey, scopes)),
+        None => {
+            attempt.outcome = AuthOutcome::InvalidToken;
+            verify_token(&tenant.config.load(), auth_header).ok_or_else(AppError::unauthorized)
+        }
+    };
+    match &verified {
+        Ok(_) => lockout::record_success(tenant, &subjects),
+        Err(AppError::Unauthorized(_)) => lockout::record_failure(tenant, &subjects).await,
+        Err(_) => {}
+    }
+    verified
 }
 
-#[inline]
-fn verify_token(token: &str) -> Option<String> {
-    let decoded = BASE64_STANDARD.decode(token).ok()?;
-    let token_str = String::from_utf8(decoded).ok()?;
-    let (provided_secret, discord_user_id) = token_str.split_once(':')?;
+/// The user id an API key acts as and its scopes, if the key exists and its
+/// secret matches.
+async fn verify_api_key(
+    db: &Storage,
+    id: &str,
+    secret: &str,
+    attempt: &mut Attempt,
+) -> Result<(String, TokenScopes), AppError> {
+    let key = db
+        .get_api_key(id)
+        .await
+        .or_internal("Failed to look up API key")?;
+
+    attempt.outcome = AuthOutcome::InvalidToken;
+    let provided_hash = hash_api_key_secret(secret);
+    let key = key
+        .filter(|key| constant_time_eq(provided_hash.as_bytes(), key.key_hash.as_bytes()))
+        .ok_or_else(AppError::unauthorized)?;
+    attempt.user_id = Some(key.user_id.clone());
+    Ok((key.user_id, key.scope.token_scopes()))
+}
+
+/// The user id and scopes of a token holding an unexpired session secret.
+fn session_claims(config: &Config, token: &str) -> Option<(String, TokenScopes)> {
+    let (provided_secret, discord_user_id) = parse_token(token)?;
+    let (expires_at, scopes) = verify_scoped_session_secret(
+        config.session_secret.as_bytes(),
+        &provided_secret,
+        &discord_user_id,
+    )?;
+    let now = chrono::Utc::now().timestamp_millis();
+    (now < expires_at).then_some((discord_user_id, scopes))
+}
 
-    let expected_secret = equicloud::utils::get_user_secret(discord_user_id);
-    if constant_time_eq(provided_secret.as_bytes(), expected_secret.as_bytes()) {
-        return Some(discord_user_id.to_string());
+/// The user id of a token holding an unexpired session secret. Sessions are
+/// only issued after signing in with the provider, so unlike a permanent
+/// secret this proves the holder controls the identity.
+pub(crate) fn verify_session(config: &Config, token: &str) -> Option<String> {
+    session_claims(config, token).map(|(user_id, _)| user_id)
+}
+
+/// The user id of a valid token, the kind of secret it held and its scopes.
+/// Permanent secrets hold every scope.
+#[inline]
+fn verify_token(config: &Config, token: &str) -> Option<(String, AuthOutcome, TokenScopes)> {
+    if let Some((user_id, scopes)) = session_claims(config, token) {
+        return Some((user_id, AuthOutcome::Session, scopes));
     }
 
-    let legacy_secret = equicloud::hash_migration::legacy::get_user_secret(discord_user_id);
-    if constant_time_eq(provided_secret.as_bytes(), legacy_secret.as_bytes()) {
-        warn!("User authenticated with legacy secret format");
-        return Some(discord_user_id.to_string());
+    let (provided_secret, discord_user_id) = parse_token(token)?;
+
+    if config.permanent_secrets_enabled {
+        let method = permanent_secret_kind(config, &provided_secret, &discord_user_id)?;
+        return Some((discord_user_id, method, TokenScopes::full()));
     }
 
     None
 }
+
+/// Checks the non-expiring secrets derived from the user id, in every
+/// format `user_secrets::accepted` still takes.
+pub(crate) fn verify_permanent_secret(
+    config: &Config,
+    provided_secret: &str,
+    discord_user_id: &str,
+) -> bool {
+    permanent_secret_kind(config, provided_secret, discord_user_id).is_some()
+}
+
+/// Which format of permanent secret `provided_secret` is, if it is one.
+/// Logins with legacy or outdated formats are counted under their own
+/// outcomes.
+fn permanent_secret_kind(
+    config: &Config,
+    provided_secret: &str,
+    discord_user_id: &str,
+) -> Option<AuthOutcome> {
+    let now = chrono::Utc::now().timestamp_millis();
+    user_secrets::accepted(config, now)
+        .into_iter()
+        .find(|(derivation, _)| {
+            let expected_secret = derivation.derive(discord_user_id);
+            constant_time_eq(provided_secret.as_bytes(), expected_secret.as_bytes())
+        })
+        .map(|(_, outcome)| outcome)
+}
@@ -0,0 +1,86 @@
+use axum::{
+    Router,
+    body::Body,
+    extract::{DefaultBodyLimit, Request, State},
+    http::header::CONTENT_LENGTH,
+    middleware::{self, Next},
+    response::Response,
+};
+use equicloud::Tenants;
+use equicloud::constants::{ARCHIVE_BODY_OVERHEAD, DEFAULT_BODY_LIMIT, JSON_BODY_OVERHEAD};
+use equicloud::error::AppError;
+use equicloud::utils::Config;
+use http_body_util::Limited;
+use std::sync::Arc;
+
+/// The body limit of a route group under a tenant's configuration.
+pub type BodyLimit = fn(&Config) -> usize;
+
+/// Caps request bodies for every route in `router`. `limit` is asked for each
+/// request with the configuration of its tenant, so a configuration reload
+/// applies without rebuilding the router.
+/// A declared `Content-Length` over the limit is rejected before the handler
+/// runs; chunked bodies are cut off by the extractors once they pass it, so
+/// nothing larger than the limit is ever buffered.
+pub fn limit_body<S>(router: Router<S>, tenants: &Arc<Tenants>, limit: BodyLimit) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router
+        .layer(middleware::from_fn_with_state(
+            (tenants.clone(), limit),
+            reject_oversized,
+        ))
+        .layer(DefaultBodyLimit::disable())
+}
+
+async fn reject_oversized(
+    State((tenants, limit)): State<(Arc<Tenants>, BodyLimit)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let tenant = tenants.resolve_or_default(request.headers());
+    let limit = limit(&tenant.config.load());
+    let declared = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if declared.is_some_and(|len| len > limit as u64) {
+        return Err(AppError::PayloadTooLarge(format!(
+            "Request body exceeds {} bytes",
+            limit
+        )));
+    }
+
+    let request = request.map(|body| Body::new(Limited::new(body, limit)));
+    Ok(next.run(request).await)
+}
+
+/// Routes that take no meaningful body: OAuth, admin, metrics, listings.
+pub fn default_limit(_: &Config) -> usize {
+    DEFAULT_BODY_LIMIT
+}
+
+pub fn settings_limit(config: &Config) -> usize {
+    config.max_backup_size_bytes
+}
+
+pub fn data_limit(config: &Config) -> usize {
+    config.namespaces.largest_max_size()
+}
+
+pub fn upload_part_limit(config: &Config) -> usize {
+    config.upload_part_size
+}
+
+/// Batch and sync bodies carry base64 values bounded by the storage quota.
+pub fn json_upload_limit(config: &Config) -> usize {
+    config.max_backup_size_bytes.div_ceil(3) * 4 + JSON_BODY_OVERHEAD
+}
+
+/// An archive holds the settings and the data, each bounded by the quota.
+pub fn archive_limit(config: &Config) -> usize {
+    config.max_backup_size_bytes * 2 + ARCHIVE_BODY_OVERHEAD
+}
@@ -0,0 +1,53 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::net::{IpAddr, SocketAddr};
+use tower_governor::GovernorError;
+use tower_governor::key_extractor::KeyExtractor;
+
+use equicloud::client_ip::{self, ClientIp, ProxyTrust};
+use equicloud::utils::ConfigHandle;
+
+/// Records the address the request came from as a `ClientIp` extension,
+/// reading forwarding headers only from `TRUSTED_PROXIES`. Requests without
+/// a peer address, as in tests that call the router directly, get none.
+pub async fn resolve_client_ip(
+    State(config): State<ConfigHandle>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(ip) = client_ip_of(&request, &config) {
+        request.extensions_mut().insert(ip);
+    }
+    next.run(request).await
+}
+
+fn client_ip_of<T>(request: &http::Request<T>, config: &ConfigHandle) -> Option<ClientIp> {
+    if let Some(ip) = request.extensions().get::<ClientIp>() {
+        return Some(*ip);
+    }
+    let config = config.load();
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr);
+    client_ip::from_connect_info(request.headers(), peer, ProxyTrust::from_config(&config))
+}
+
+/// Rate limits by the client address. The rate limiter wraps the routes, so
+/// it resolves the address itself rather than waiting for
+/// [`resolve_client_ip`].
+#[derive(Clone)]
+pub struct ClientIpKeyExtractor(pub ConfigHandle);
+
+impl KeyExtractor for ClientIpKeyExtractor {
+    type Key = IpAddr;
+
+    fn extract<T>(&self, request: &http::Request<T>) -> Result<Self::Key, GovernorError> {
+        client_ip_of(request, &self.0)
+            .map(|ClientIp(ip)| ip)
+            .ok_or(GovernorError::UnableToExtractKey)
+    }
+}
@@ -0,0 +1,20 @@
+use tower_http::compression::CompressionLayer;
+use tower_http::compression::predicate::{And, DefaultPredicate, Predicate, SizeAbove};
+
+use equicloud::utils::Config;
+
+pub type ResponseCompression = CompressionLayer<And<DefaultPredicate, SizeAbove>>;
+
+/// gzip, brotli or zstd for large responses, picked from `Accept-Encoding`.
+/// Ranged responses are never compressed.
+pub fn response_compression(config: &Config) -> ResponseCompression {
+    let layer = if config.response_compression_enabled {
+        CompressionLayer::new()
+    } else {
+        CompressionLayer::new().no_gzip().no_br().no_zstd()
+    };
+
+    layer.compress_when(
+        DefaultPredicate::new().and(SizeAbove::new(config.response_compression_min_size)),
+    )
+}
@@ -0,0 +1,20 @@
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+
+use equicloud::devices::ClientDevice;
+use equicloud::error::AppError;
+
+/// The device named by `X-Device-Id` and `X-Client-Name`, if any. A
+/// malformed header is rejected with 400 rather than ignored.
+pub struct RequestDevice(pub Option<ClientDevice>);
+
+impl<S> FromRequestParts<S> for RequestDevice
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        ClientDevice::from_headers(&parts.headers).map(RequestDevice)
+    }
+}
@@ -0,0 +1,212 @@
+//! `Idempotency-Key` for writes a client may have to retry.
+//!
+//! The first request with a key claims it and runs; its response is kept
+//! for `IDEMPOTENCY_KEY_TTL_SECS` and replayed, with `Idempotent-Replayed`,
+//! to any later request of the same user with the same key. A key reused for
+//! a different request, or while its first request is still running, is
+//! refused with 409. Server errors are not kept, so those can be retried.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use http_body_util::LengthLimitError;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use equicloud::constants::{
+    IDEMPOTENCY_CLAIM_TTL_SECS, IDEMPOTENCY_KEY_TTL_SECS, MAX_IDEMPOTENCY_KEY_LEN,
+};
+use equicloud::error::{AppError, ResultExt};
+use equicloud::{Datastore, Storage, StoredResponse};
+
+use crate::middleware::auth::ScopedUser;
+use crate::middleware::tenant::CurrentTenant;
+use crate::state::AppState;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+fn check_idempotency_key(key: &str) -> Result<(), AppError> {
+    if !key.is_empty()
+        && key.len() <= MAX_IDEMPOTENCY_KEY_LEN
+        && key.bytes().all(|b| b.is_ascii_graphic())
+    {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(format!(
+            "Idempotency-Key must be 1 to {} visible ASCII characters",
+            MAX_IDEMPOTENCY_KEY_LEN
+        )))
+    }
+}
+
+/// Tells one request apart from another sent with the same key.
+fn fingerprint(request: &axum::http::request::Parts, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(request.method.as_str().as_bytes());
+    hasher.update(b" ");
+    hasher.update(request.uri.to_string().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Holds a claimed key and gives it up when dropped without a response,
+/// which is also what happens when the request times out.
+struct Claim {
+    db: Storage,
+    user_id: String,
+    key: String,
+    settled: bool,
+}
+
+impl Claim {
+    async fn release(mut self) {
+        self.settled = true;
+        if let Err(e) = self
+            .db
+            .release_idempotency_key(&self.user_id, &self.key)
+            .await
+        {
+            warn!("Failed to release idempotency key: {}", e);
+        }
+    }
+
+    async fn complete(mut self, fingerprint: &str, response: &StoredResponse) {
+        self.settled = true;
+        if let Err(e) = self
+            .db
+            .complete_idempotency_key(
+                &self.user_id,
+                &self.key,
+                fingerprint,
+                response,
+                IDEMPOTENCY_KEY_TTL_SECS,
+            )
+            .await
+        {
+            warn!("Failed to store idempotent response: {}", e);
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        let db = self.db.clone();
+        let user_id = std::mem::take(&mut self.user_id);
+        let key = std::mem::take(&mut self.key);
+        tokio::spawn(async move {
+            if let Err(e) = db.release_idempotency_key(&user_id, &key).await {
+                warn!("Failed to release idempotency key: {}", e);
+            }
+        });
+    }
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let mut response = Response::new(Body::from(stored.body));
+    *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let headers = response.headers_mut();
+    for (name, value) in stored.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            headers.append(name, value);
+        }
+    }
+    headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+/// Middleware for a write handler. Requests without an `Idempotency-Key`
+/// pass straight through. Layer it inside response compression so what is
+/// kept does not depend on the first client's `Accept-Encoding`.
+pub async fn replay_or_run(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(next.run(request).await);
+    };
+    let key = key
+        .to_str()
+        .map_err(|_| AppError::BadRequest("Idempotency-Key must be visible ASCII".into()))?
+        .to_string();
+    check_idempotency_key(&key)?;
+
+    let (mut parts, body) = request.into_parts();
+    let CurrentTenant(tenant) = CurrentTenant::from_request_parts(&mut parts, &state).await?;
+    let ScopedUser(user_id, _) = ScopedUser::from_request_parts(&mut parts, &state).await?;
+
+    // The body limit of the route still applies while buffering.
+    let body = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&e);
+        while let Some(e) = source {
+            if e.is::<LengthLimitError>() {
+                return AppError::PayloadTooLarge("Request body is too large".into());
+            }
+            source = e.source();
+        }
+        AppError::BadRequest("Failed to read request body".into())
+    })?;
+    let fingerprint = fingerprint(&parts, &body);
+
+    let db = &tenant.db;
+    if let Some(record) = db
+        .claim_idempotency_key(&user_id, &key, &fingerprint, IDEMPOTENCY_CLAIM_TTL_SECS)
+        .await
+        .or_internal("Failed to claim idempotency key")?
+    {
+        if record.fingerprint != fingerprint {
+            return Err(AppError::Conflict(
+                "Idempotency-Key was already used for a different request".into(),
+            ));
+        }
+        return match record.response {
+            Some(stored) => Ok(replay(stored)),
+            None => Err(AppError::Conflict(
+                "A request with this Idempotency-Key is still in progress".into(),
+            )),
+        };
+    }
+
+    let claim = Claim {
+        db: db.clone(),
+        user_id,
+        key,
+        settled: false,
+    };
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if response.status().is_server_error() {
+        claim.release().await;
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body: Bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to buffer idempotent response: {}", e);
+            claim.release().await;
+            return Err(AppError::Internal("Failed to read response"));
+        }
+    };
+    let stored = StoredResponse {
+        status: parts.status.as_u16(),
+        headers: parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: body.to_vec(),
+    };
+    claim.complete(&fingerprint, &stored).await;
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
@@ -0,0 +1,55 @@
+use axum::extract::{Request, State};
+use axum::{middleware::Next, response::Response};
+use equicloud::client_ip::ClientIp;
+use equicloud::error::AppError;
+use equicloud::utils::ConfigHandle;
+use tracing::warn;
+
+use super::auth::constant_time_eq;
+
+/// Guards `/metrics` with `METRICS_TOKEN` and `METRICS_ALLOWED_IPS`; a
+/// request must pass whichever of the two are set. The allowlist is checked
+/// against the client address, which is read from forwarding headers only
+/// when the peer is one of `TRUSTED_PROXIES`.
+pub async fn metrics_middleware(
+    State(config): State<ConfigHandle>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let config = config.load();
+
+    if !config.metrics_allowed_ips.is_empty() {
+        let client = request
+            .extensions()
+            .get::<ClientIp>()
+            .map(|ClientIp(ip)| *ip);
+        let allowed = client.is_some_and(|client| {
+            config
+                .metrics_allowed_ips
+                .iter()
+                .any(|range| range.contains(client))
+        });
+        if !allowed {
+            warn!("Rejected metrics request from {:?}", client);
+            return Err(AppError::Forbidden(
+                "Metrics are not served to this address".into(),
+            ));
+        }
+    }
+
+    if let Some(expected) = config.metrics_token.as_deref() {
+        let auth_header = request
+            .headers()
+            .get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .ok_or_else(AppError::unauthorized)?;
+        let token = auth_header.strip_prefix("Bearer ").unwrap_or(auth_header);
+
+        if !constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+            warn!("Rejected metrics request with invalid token");
+            return Err(AppError::unauthorized());
+        }
+    }
+
+    Ok(next.run(request).await)
+}
@@ -1 +1,11 @@
+pub mod admin;
+pub mod audit;
 pub mod auth;
+pub mod body_limit;
+pub mod client_ip;
+pub mod compression;
+pub mod device;
+pub mod idempotency;
+pub mod metrics;
+pub mod tenant;
+pub mod timeout;
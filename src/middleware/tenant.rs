@@ -0,0 +1,59 @@
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::request::Parts;
+use std::sync::Arc;
+
+use equicloud::error::AppError;
+use equicloud::{DbHealth, Metrics, Storage, Tenant, Tenants};
+
+/// The tenant a request is for, chosen by `X-Tenant` or the `Host` header.
+/// Resolved once per request and counted in the per-tenant metrics; an
+/// unknown `X-Tenant` is rejected with 404. Every handler that touches user
+/// data goes through here, so this is also where requests fail fast with 503
+/// while the database circuit is open.
+#[derive(Clone)]
+pub struct CurrentTenant(pub Arc<Tenant>);
+
+impl<S> FromRequestParts<S> for CurrentTenant
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(tenant) = parts.extensions.get::<CurrentTenant>() {
+            return Ok(tenant.clone());
+        }
+        if Arc::<DbHealth>::from_ref(state).circuit_open() {
+            return Err(AppError::DatabaseUnavailable);
+        }
+
+        let tenant = Arc::<Tenants>::from_ref(state)
+            .resolve(&parts.headers)
+            .map(CurrentTenant)
+            .ok_or(AppError::NotFound)?;
+        Arc::<Metrics>::from_ref(state).record_tenant_request(&tenant.0.id);
+        parts.extensions.insert(tenant.clone());
+        Ok(tenant)
+    }
+}
+
+/// Storage of the request's tenant.
+pub struct TenantDb(pub Storage);
+
+impl<S> FromRequestParts<S> for TenantDb
+where
+    S: Send + Sync,
+    Arc<Tenants>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<DbHealth>: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let CurrentTenant(tenant) = CurrentTenant::from_request_parts(parts, state).await?;
+        Ok(TenantDb(tenant.db.clone()))
+    }
+}
@@ -0,0 +1,72 @@
+use axum::{
+    Router,
+    extract::{MatchedPath, Request, State},
+    middleware::{self, Next},
+    response::Response,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use equicloud::Tenants;
+use equicloud::error::AppError;
+use equicloud::utils::Config;
+
+/// The timeout of a route group under a tenant's configuration.
+pub type RequestTimeout = fn(&Config) -> Duration;
+
+/// Cancels requests to `router` that are not answered within `timeout`,
+/// responding 504 instead. Dropping the handler also drops any query it was
+/// waiting on. Streamed response bodies are not limited once they have
+/// started. `timeout` is asked for each request with the configuration of
+/// its tenant, so a configuration reload applies to the next one; a zero
+/// timeout leaves the request unlimited.
+pub fn with_timeout<S>(
+    router: Router<S>,
+    tenants: &Arc<Tenants>,
+    timeout: RequestTimeout,
+) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.layer(middleware::from_fn_with_state(
+        (tenants.clone(), timeout),
+        cancel_after,
+    ))
+}
+
+async fn cancel_after(
+    State((tenants, timeout)): State<(Arc<Tenants>, RequestTimeout)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let tenant = tenants.resolve_or_default(request.headers());
+    let timeout = timeout(&tenant.config.load());
+    if timeout.is_zero() {
+        return Ok(next.run(request).await);
+    }
+
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+
+    tokio::time::timeout(timeout, next.run(request))
+        .await
+        .map_err(|_| {
+            warn!("{} {} cancelled after {:?}", method, route, timeout);
+            AppError::Timeout
+        })
+}
+
+/// Everything that handles a single key or settings blob.
+pub fn default_timeout(config: &Config) -> Duration {
+    config.request_timeout
+}
+
+/// Sync, batch, import and export, which can move a whole backup.
+pub fn bulk_timeout(config: &Config) -> Duration {
+    config.bulk_request_timeout
+}
@@ -0,0 +1,118 @@
+use axum::{Json, extract::Path, http::StatusCode};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
+
+use equicloud::api_keys::{ApiKeyScope, format_api_key, hash_api_key_secret, new_api_key};
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::constants::MAX_API_KEY_NAME_LEN;
+use equicloud::error::{AppError, ResultExt};
+use equicloud::utils::hash_user_id;
+use equicloud::{ApiKey, Datastore};
+
+use crate::middleware::audit::AuditContext;
+use crate::middleware::tenant::TenantDb;
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    /// Discord id of the user the key acts as.
+    user_id: String,
+    scope: ApiKeyScope,
+}
+
+pub async fn list_api_keys(TenantDb(db): TenantDb) -> Result<Json<Value>, AppError> {
+    let keys = db
+        .list_api_keys()
+        .await
+        .or_internal("Failed to list API keys")?;
+    Ok(Json(json!({ "api_keys": keys })))
+}
+
+/// Mints a key for a user. The key is in the response and nowhere else;
+/// only a hash of its secret is stored.
+pub async fn create_api_key(
+    TenantDb(db): TenantDb,
+    audit: AuditContext,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> Result<Json<Value>, AppError> {
+    let name = request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_API_KEY_NAME_LEN {
+        return Err(AppError::BadRequest(format!(
+            "name must be between 1 and {} characters",
+            MAX_API_KEY_NAME_LEN
+        )));
+    }
+    let user_id = request.user_id.trim();
+    if user_id.is_empty() {
+        return Err(AppError::BadRequest("user_id must not be empty".into()));
+    }
+
+    let (id, secret) = new_api_key();
+    let key = ApiKey {
+        id,
+        name: name.to_string(),
+        user_id: user_id.to_string(),
+        user: hash_user_id(user_id),
+        key_hash: hash_api_key_secret(&secret),
+        scope: request.scope,
+        created_at: chrono::Utc::now().timestamp_millis(),
+    };
+    let result = db
+        .save_api_key(&key)
+        .await
+        .or_internal("Failed to save API key");
+    audit
+        .record_hashed(
+            &db,
+            AuditActor::Admin,
+            Some(key.user.clone()),
+            AuditAction::AdminCreateApiKey,
+            Some(format!("{} ({})", key.id, key.scope)),
+            result.is_ok(),
+        )
+        .await;
+    result?;
+
+    info!(
+        "Admin created {} API key {} for user {}",
+        key.scope,
+        key.id,
+        &key.user[..16]
+    );
+    Ok(Json(json!({
+        "api_key": key,
+        "key": format_api_key(&key.id, &secret)
+    })))
+}
+
+/// Revokes a key. Requests already authenticated with it finish.
+pub async fn delete_api_key(
+    TenantDb(db): TenantDb,
+    Path(id): Path<String>,
+    audit: AuditContext,
+) -> Result<StatusCode, AppError> {
+    let key = db
+        .get_api_key(&id)
+        .await
+        .or_internal("Failed to look up API key")?
+        .ok_or(AppError::NotFound)?;
+
+    let result = db
+        .delete_api_key(&id)
+        .await
+        .or_internal("Failed to delete API key");
+    audit
+        .record_hashed(
+            &db,
+            AuditActor::Admin,
+            Some(key.user),
+            AuditAction::AdminDeleteApiKey,
+            Some(id),
+            result.is_ok(),
+        )
+        .await;
+    result?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,51 @@
+use axum::{Json, extract::Query};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use equicloud::audit;
+use equicloud::constants::{
+    DEFAULT_ADMIN_LIST_LIMIT, DEFAULT_AUDIT_QUERY_DAYS, MAX_ADMIN_LIST_LIMIT,
+};
+use equicloud::error::{AppError, ResultExt};
+use equicloud::utils::hash_user_id;
+
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    /// Unhashed user id to filter on.
+    user: Option<String>,
+    action: Option<String>,
+    days: Option<u32>,
+    limit: Option<usize>,
+}
+
+pub async fn list_audit_entries(
+    CurrentTenant(tenant): CurrentTenant,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Value>, AppError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_ADMIN_LIST_LIMIT)
+        .min(MAX_ADMIN_LIST_LIMIT);
+    let mut days = query.days.unwrap_or(DEFAULT_AUDIT_QUERY_DAYS).max(1);
+    let retention_days = tenant.config.load().audit_retention_days;
+    if retention_days > 0 {
+        days = days.min(retention_days);
+    }
+    let user_hash = query.user.as_deref().map(hash_user_id);
+
+    let entries = audit::recent(&tenant.db, days, limit, |entry| {
+        user_hash
+            .as_ref()
+            .is_none_or(|hash| entry.user_hash.as_ref() == Some(hash))
+            && query.action.as_ref().is_none_or(|a| entry.action == *a)
+    })
+    .await
+    .or_internal("Failed to list audit entries")?;
+
+    Ok(Json(json!({
+        "days": days,
+        "entries": entries
+    })))
+}
@@ -0,0 +1,107 @@
+use axum::{Json, extract::Path, http::StatusCode};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
+
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::constants::SCYLLA_MAX_TTL_SECS;
+use equicloud::error::{AppError, ResultExt};
+use equicloud::{Ban, Datastore};
+
+use super::check_user_hash;
+use crate::middleware::audit::AuditContext;
+use crate::middleware::tenant::TenantDb;
+
+#[derive(Deserialize)]
+pub struct BanRequest {
+    reason: String,
+    /// How long the ban lasts; `null` keeps it until it is removed.
+    duration_secs: Option<u64>,
+}
+
+pub async fn list_bans(TenantDb(db): TenantDb) -> Result<Json<Value>, AppError> {
+    let bans = db.list_bans().await.or_internal("Failed to list bans")?;
+    Ok(Json(json!({ "bans": bans })))
+}
+
+/// Bans a user, replacing any ban already on them, including one placed
+/// automatically.
+pub async fn ban_user(
+    TenantDb(db): TenantDb,
+    Path(user_hash): Path<String>,
+    audit: AuditContext,
+    Json(request): Json<BanRequest>,
+) -> Result<Json<Ban>, AppError> {
+    check_user_hash(&user_hash)?;
+    let reason = request.reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::BadRequest("reason must not be empty".into()));
+    }
+    if request
+        .duration_secs
+        .is_some_and(|secs| secs == 0 || secs > SCYLLA_MAX_TTL_SECS)
+    {
+        return Err(AppError::BadRequest(format!(
+            "duration_secs must be between 1 and {}",
+            SCYLLA_MAX_TTL_SECS
+        )));
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let ban = Ban {
+        user: user_hash,
+        reason: reason.to_string(),
+        banned_at: now,
+        expires_at: request.duration_secs.map(|secs| now + secs as i64 * 1000),
+        automatic: false,
+    };
+    let result = db.save_ban(&ban).await.or_internal("Failed to ban user");
+    audit
+        .record_hashed(
+            &db,
+            AuditActor::Admin,
+            Some(ban.user.clone()),
+            AuditAction::AdminBanUser,
+            Some(ban.reason.clone()),
+            result.is_ok(),
+        )
+        .await;
+    result?;
+
+    info!("Admin banned user {}: {}", &ban.user[..16], ban.reason);
+    Ok(Json(ban))
+}
+
+pub async fn unban_user(
+    TenantDb(db): TenantDb,
+    Path(user_hash): Path<String>,
+    audit: AuditContext,
+) -> Result<StatusCode, AppError> {
+    check_user_hash(&user_hash)?;
+    if db
+        .get_ban(&user_hash)
+        .await
+        .or_internal("Failed to look up ban")?
+        .is_none()
+    {
+        return Err(AppError::NotFound);
+    }
+
+    let result = db
+        .delete_ban(&user_hash)
+        .await
+        .or_internal("Failed to unban user");
+    audit
+        .record_hashed(
+            &db,
+            AuditActor::Admin,
+            Some(user_hash),
+            AuditAction::AdminUnbanUser,
+            None,
+            result.is_ok(),
+        )
+        .await;
+    result?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
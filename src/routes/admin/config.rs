@@ -0,0 +1,10 @@
+use axum::Json;
+use serde_json::Value;
+
+use crate::middleware::tenant::CurrentTenant;
+
+/// The configuration the tenant is running with, after any reloads. Secrets
+/// only show whether they are set.
+pub async fn get_config(CurrentTenant(tenant): CurrentTenant) -> Json<Value> {
+    Json(tenant.config.load().redacted())
+}
@@ -0,0 +1,154 @@
+use axum::{
+    Json,
+    extract::{Path, Query},
+};
+use base64::prelude::*;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::constants::{DEFAULT_ADMIN_LIST_LIMIT, MAX_ADMIN_LIST_LIMIT};
+use equicloud::error::{AppError, ResultExt};
+use equicloud::{DataManifestEntry, Datastore, validate_key};
+
+use super::check_user_hash;
+use crate::middleware::audit::AuditContext;
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySort {
+    #[default]
+    Key,
+    UpdatedAt,
+    Size,
+    Version,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize)]
+pub struct KeysQuery {
+    #[serde(default)]
+    sort: KeySort,
+    #[serde(default)]
+    order: SortOrder,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    /// Include tombstones of deleted keys.
+    #[serde(default)]
+    deleted: bool,
+}
+
+/// Sorts `entries` by `sort`, breaking ties by key so pages stay stable.
+fn sort_entries(entries: &mut [DataManifestEntry], sort: KeySort, order: SortOrder) {
+    entries.sort_by(|a, b| {
+        let ordering = match sort {
+            KeySort::Key => a.key.cmp(&b.key),
+            KeySort::UpdatedAt => a.updated_at.cmp(&b.updated_at),
+            KeySort::Size => a.size_bytes.cmp(&b.size_bytes),
+            KeySort::Version => a.version.cmp(&b.version),
+        }
+        .then_with(|| a.key.cmp(&b.key));
+        match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    });
+}
+
+/// Lists the names, sizes, versions and timestamps of a user's keys, never
+/// their values.
+pub async fn list_user_keys(
+    CurrentTenant(tenant): CurrentTenant,
+    Path(user_hash): Path<String>,
+    Query(query): Query<KeysQuery>,
+) -> Result<Json<Value>, AppError> {
+    check_user_hash(&user_hash)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_ADMIN_LIST_LIMIT)
+        .min(MAX_ADMIN_LIST_LIMIT);
+
+    let mut entries = tenant
+        .db
+        .get_data_manifest_by_hash(&user_hash)
+        .await
+        .or_internal("Failed to list keys")?;
+    if !query.deleted {
+        entries.retain(|e| !e.deleted);
+    }
+    let total = entries.len();
+    sort_entries(&mut entries, query.sort, query.order);
+    let keys: Vec<DataManifestEntry> = entries.into_iter().skip(query.offset).take(limit).collect();
+
+    let next_offset = (query.offset + keys.len() < total).then(|| query.offset + keys.len());
+    Ok(Json(json!({
+        "user": user_hash,
+        "total": total,
+        "keys": keys,
+        "next_offset": next_offset
+    })))
+}
+
+/// Returns one stored value, base64-encoded. Off unless `ADMIN_VALUE_ACCESS`
+/// is set, and audited either way.
+pub async fn get_user_value(
+    CurrentTenant(tenant): CurrentTenant,
+    Path((user_hash, key)): Path<(String, String)>,
+    audit: AuditContext,
+) -> Result<Json<Value>, AppError> {
+    check_user_hash(&user_hash)?;
+    validate_key(&key)?;
+    let db = &tenant.db;
+
+    if !tenant.config.load().admin_value_access {
+        audit
+            .record_hashed(
+                db,
+                AuditActor::Admin,
+                Some(user_hash),
+                AuditAction::AdminReadValue,
+                Some(key),
+                false,
+            )
+            .await;
+        return Err(AppError::Forbidden(
+            "Reading values through the admin API is disabled".into(),
+        ));
+    }
+
+    let result = db
+        .get_data_key_by_hash(&user_hash, &key)
+        .await
+        .or_internal("Failed to read value");
+    audit
+        .record_hashed(
+            db,
+            AuditActor::Admin,
+            Some(user_hash.clone()),
+            AuditAction::AdminReadValue,
+            Some(key),
+            result.is_ok(),
+        )
+        .await;
+    let entry = result?.ok_or(AppError::NotFound)?;
+
+    Ok(Json(json!({
+        "user": user_hash,
+        "key": entry.key,
+        "version": entry.version,
+        "checksum": entry.checksum,
+        "size_bytes": entry.size_bytes,
+        "created_at": entry.created_at,
+        "updated_at": entry.updated_at,
+        "value": BASE64_STANDARD.encode(&entry.value)
+    })))
+}
@@ -0,0 +1,61 @@
+use axum::{Json, extract::Query};
+use serde::Deserialize;
+use tracing::info;
+
+use equicloud::LegacyCleanupReport;
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::error::{AppError, ResultExt};
+
+use crate::middleware::audit::AuditContext;
+use crate::middleware::tenant::TenantDb;
+
+#[derive(Deserialize)]
+pub struct LegacyCleanupQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+pub async fn cleanup_legacy_users(
+    TenantDb(db): TenantDb,
+    Query(query): Query<LegacyCleanupQuery>,
+    audit: AuditContext,
+) -> Result<Json<LegacyCleanupReport>, AppError> {
+    // Legacy CRC32-keyed rows only ever existed in Scylla.
+    let result = match db.scylla() {
+        Some(scylla) => scylla
+            .cleanup_legacy_users(!query.dry_run)
+            .await
+            .or_internal("Failed to clean up legacy users"),
+        None => Ok(LegacyCleanupReport::default()),
+    };
+    let detail = match &result {
+        Ok(_) if query.dry_run => Some("dry run".to_string()),
+        Ok(report) => Some(format!(
+            "{} users and data of {} users deleted",
+            report.deleted, report.deleted_data_users
+        )),
+        Err(_) => None,
+    };
+    audit
+        .record(
+            &db,
+            AuditActor::Admin,
+            None,
+            AuditAction::AdminLegacyCleanup,
+            detail,
+            result.is_ok(),
+        )
+        .await;
+    let report = result?;
+
+    info!(
+        "Legacy cleanup: {} entries scanned, {} legacy, {} deleted; data of {} users scanned, {} legacy, {} deleted",
+        report.total,
+        report.legacy,
+        report.deleted,
+        report.data_users,
+        report.legacy_data_users,
+        report.deleted_data_users
+    );
+    Ok(Json(report))
+}
@@ -0,0 +1,75 @@
+use axum::{
+    Router, middleware,
+    routing::{delete, get, post, put},
+};
+
+use equicloud::error::AppError;
+
+use crate::state::AppState;
+
+pub mod api_keys;
+pub mod audit;
+pub mod bans;
+pub mod config;
+pub mod keys;
+pub mod legacy;
+pub mod stats;
+pub mod users;
+
+pub fn register(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/admin/users/recent", get(users::list_recent_users))
+        .route("/admin/users/purge", post(users::purge_users))
+        .route(
+            "/admin/users/{discord_id}/usage",
+            get(users::get_user_usage),
+        )
+        .route(
+            "/admin/users/{discord_id}/corruption",
+            get(users::get_user_corruption),
+        )
+        .route(
+            "/admin/users/{discord_id}/quota",
+            put(users::set_user_quota),
+        )
+        .route("/admin/users/{discord_id}", delete(users::delete_user))
+        .route("/admin/users/{user_hash}/keys", get(keys::list_user_keys))
+        .route(
+            "/admin/users/{user_hash}/keys/{*key}",
+            get(keys::get_user_value),
+        )
+        .route("/admin/legacy-cleanup", post(legacy::cleanup_legacy_users))
+        .route("/admin/audit", get(audit::list_audit_entries))
+        .route("/admin/stats", get(stats::export_stats))
+        .route("/admin/bans", get(bans::list_bans))
+        .route(
+            "/admin/bans/{user_hash}",
+            put(bans::ban_user).delete(bans::unban_user),
+        )
+        .route(
+            "/admin/api-keys",
+            get(api_keys::list_api_keys).post(api_keys::create_api_key),
+        )
+        .route("/admin/api-keys/{id}", delete(api_keys::delete_api_key))
+        .route("/admin/config", get(config::get_config))
+        .route_layer(middleware::from_fn_with_state(
+            state.config.clone(),
+            crate::middleware::admin::admin_middleware,
+        ))
+}
+
+/// Users are looked up by hashed id, the form they appear in throughout the
+/// admin API and the audit log.
+fn check_user_hash(user_hash: &str) -> Result<(), AppError> {
+    if user_hash.len() == 64
+        && user_hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(
+            "User must be a hashed id: 64 lowercase hex characters".into(),
+        ))
+    }
+}
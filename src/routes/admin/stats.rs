@@ -0,0 +1,76 @@
+use axum::{
+    Json,
+    extract::Query,
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use equicloud::constants::{DEFAULT_STATS_EXPORT_DAYS, MAX_STATS_EXPORT_DAYS, MS_PER_DAY};
+use equicloud::error::{AppError, ResultExt};
+use equicloud::stats;
+
+use crate::middleware::tenant::TenantDb;
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    /// First day, `YYYY-MM-DD`; `DEFAULT_STATS_EXPORT_DAYS` before `to` by
+    /// default.
+    from: Option<String>,
+    /// Last day, `YYYY-MM-DD`; today by default.
+    to: Option<String>,
+    #[serde(default)]
+    format: StatsFormat,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Daily usage aggregates of a range of days, both ends included.
+pub async fn export_stats(
+    TenantDb(db): TenantDb,
+    Query(query): Query<StatsQuery>,
+) -> Result<Response, AppError> {
+    let day = |value: &Option<String>, name: &str| {
+        value
+            .as_deref()
+            .map(|d| {
+                stats::parse_day(d)
+                    .ok_or_else(|| AppError::BadRequest(format!("{} must be YYYY-MM-DD", name)))
+            })
+            .transpose()
+    };
+    let to = match day(&query.to, "to")? {
+        Some(to) => to,
+        None => {
+            let now = chrono::Utc::now().timestamp_millis();
+            now - now.rem_euclid(MS_PER_DAY)
+        }
+    };
+    let from =
+        day(&query.from, "from")?.unwrap_or(to - (DEFAULT_STATS_EXPORT_DAYS - 1) * MS_PER_DAY);
+    if from > to {
+        return Err(AppError::BadRequest("from must not be after to".into()));
+    }
+    if (to - from) / MS_PER_DAY >= MAX_STATS_EXPORT_DAYS {
+        return Err(AppError::BadRequest(format!(
+            "At most {} days can be exported at once",
+            MAX_STATS_EXPORT_DAYS
+        )));
+    }
+
+    let days = stats::export(&db, from, to)
+        .await
+        .or_internal("Failed to export stats")?;
+
+    Ok(match query.format {
+        StatsFormat::Json => Json(json!({ "days": days })).into_response(),
+        StatsFormat::Csv => ([(CONTENT_TYPE, "text/csv")], stats::to_csv(&days)).into_response(),
+    })
+}
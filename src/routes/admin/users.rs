@@ -0,0 +1,221 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
+
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::bulk_purge::{self, BulkPurgeReport, PurgeCriteria};
+use equicloud::constants::{DEFAULT_ADMIN_LIST_LIMIT, MAX_ADMIN_LIST_LIMIT, MS_PER_WEEK};
+use equicloud::error::{AppError, ResultExt};
+use equicloud::utils::hash_user_id;
+use equicloud::{AccountPurge, Datastore, Event, EventBus};
+
+use crate::middleware::audit::AuditContext;
+use crate::middleware::tenant::{CurrentTenant, TenantDb};
+
+#[derive(Deserialize)]
+pub struct QuotaRequest {
+    /// New quota in bytes; `null` falls back to `MAX_BACKUP_SIZE_BYTES`.
+    max_bytes: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct PurgeUsersRequest {
+    #[serde(flatten)]
+    criteria: PurgeCriteria,
+    /// Only list the matching accounts; purging needs `false` explicitly.
+    #[serde(default = "default_dry_run")]
+    dry_run: bool,
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct RecentUsersQuery {
+    since: Option<i64>,
+    limit: Option<usize>,
+}
+
+pub async fn get_user_usage(
+    CurrentTenant(tenant): CurrentTenant,
+    Path(discord_id): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let db = &tenant.db;
+    let usage = db
+        .get_storage_usage(&discord_id)
+        .await
+        .or_internal("Failed to get storage usage")?;
+    let quota_override = db
+        .get_quota_override(&discord_id)
+        .await
+        .or_internal("Failed to get storage quota")?;
+
+    Ok(Json(json!({
+        "user": hash_user_id(&discord_id),
+        "usage": usage,
+        "quota": quota_override.unwrap_or(tenant.config.load().max_backup_size_bytes as i64),
+        "quota_override": quota_override
+    })))
+}
+
+/// Grants a user more (or less) storage than the global default. Request
+/// body limits on sync and import still follow `MAX_BACKUP_SIZE_BYTES`.
+/// Values of the user found not to match their checksum, by reads or the
+/// scrubber.
+pub async fn get_user_corruption(
+    TenantDb(db): TenantDb,
+    Path(discord_id): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let entries = db
+        .list_corruption(&discord_id)
+        .await
+        .or_internal("Failed to list corrupt values")?;
+
+    Ok(Json(json!({
+        "user": hash_user_id(&discord_id),
+        "entries": entries
+    })))
+}
+
+pub async fn set_user_quota(
+    CurrentTenant(tenant): CurrentTenant,
+    Path(discord_id): Path<String>,
+    audit: AuditContext,
+    Json(request): Json<QuotaRequest>,
+) -> Result<Json<Value>, AppError> {
+    if request.max_bytes.is_some_and(|bytes| bytes <= 0) {
+        return Err(AppError::BadRequest(
+            "max_bytes must be greater than zero".into(),
+        ));
+    }
+
+    let db = &tenant.db;
+    let result = db
+        .set_quota_override(&discord_id, request.max_bytes)
+        .await
+        .or_internal("Failed to set storage quota");
+    audit
+        .record(
+            db,
+            AuditActor::Admin,
+            Some(&discord_id),
+            AuditAction::AdminSetQuota,
+            Some(match request.max_bytes {
+                Some(bytes) => bytes.to_string(),
+                None => "default".to_string(),
+            }),
+            result.is_ok(),
+        )
+        .await;
+    result?;
+
+    Ok(Json(json!({
+        "user": hash_user_id(&discord_id),
+        "quota": request.max_bytes.unwrap_or(tenant.config.load().max_backup_size_bytes as i64),
+        "quota_override": request.max_bytes
+    })))
+}
+
+pub async fn delete_user(
+    TenantDb(db): TenantDb,
+    State(events): State<EventBus>,
+    Path(discord_id): Path<String>,
+    audit: AuditContext,
+) -> Result<Json<AccountPurge>, AppError> {
+    let result = db
+        .purge_account(&discord_id)
+        .await
+        .or_internal("Failed to delete user data");
+    audit
+        .record(
+            &db,
+            AuditActor::Admin,
+            Some(&discord_id),
+            AuditAction::AdminDeleteUser,
+            None,
+            result.is_ok(),
+        )
+        .await;
+    let purged = result?;
+
+    let user_hash = hash_user_id(&discord_id);
+    info!(
+        "Admin deleted all data for user {} ({} data keys)",
+        &user_hash[..16],
+        purged.data_keys
+    );
+
+    events.publish(Event::AccountDeleted {
+        user_id: discord_id,
+    });
+
+    Ok(Json(purged))
+}
+
+/// Purges every account matching all of the given criteria, or with
+/// `dry_run` (the default) lists them by hashed id.
+pub async fn purge_users(
+    CurrentTenant(tenant): CurrentTenant,
+    audit: AuditContext,
+    Json(request): Json<PurgeUsersRequest>,
+) -> Result<Json<BulkPurgeReport>, AppError> {
+    if request.criteria.is_empty() {
+        return Err(AppError::BadRequest(
+            "Give at least one of inactive_before, smaller_than or larger_than".into(),
+        ));
+    }
+
+    let report = bulk_purge::run(
+        &tenant.db,
+        &tenant.config.load(),
+        &request.criteria,
+        request.dry_run,
+        |user_hash, succeeded| {
+            audit.entry(
+                AuditActor::Admin,
+                Some(user_hash.to_string()),
+                AuditAction::AdminBulkPurge,
+                None,
+                succeeded,
+            )
+        },
+    )
+    .await
+    .or_internal("Failed to purge users")?;
+
+    if !report.dry_run {
+        info!(
+            "Admin bulk purge: {} matched, {} purged, {} failed",
+            report.matched, report.purged, report.failed
+        );
+    }
+    Ok(Json(report))
+}
+
+pub async fn list_recent_users(
+    TenantDb(db): TenantDb,
+    Query(query): Query<RecentUsersQuery>,
+) -> Result<Json<Value>, AppError> {
+    let since = query
+        .since
+        .unwrap_or_else(|| chrono::Utc::now().timestamp_millis() - MS_PER_WEEK);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_ADMIN_LIST_LIMIT)
+        .min(MAX_ADMIN_LIST_LIMIT);
+
+    let users = db
+        .list_users_created_since(since, limit)
+        .await
+        .or_internal("Failed to list recent users")?;
+
+    Ok(Json(json!({
+        "since": since,
+        "users": users
+    })))
+}
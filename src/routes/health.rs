@@ -1,46 +1,152 @@
 use axum::{
     Router,
+    extract::State,
+    http::StatusCode,
     response::{IntoResponse, Json, Redirect, Response},
     routing::get,
 };
 use serde_json::json;
-use std::env;
-use tracing::debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
 
-pub fn register() -> Router {
+use equicloud::constants::HEALTH_PROBE_TIMEOUT_MS;
+use equicloud::driver_metrics::DriverStats;
+use equicloud::utils::ConfigHandle;
+use equicloud::{Datastore, DbHealth, Storage};
+
+use crate::routes::openapi;
+use crate::state::AppState;
+
+pub fn register() -> Router<AppState> {
     Router::new()
         .route("/health", get(health_check))
+        .route("/health/live", get(liveness))
+        .route("/health/ready", get(readiness))
         .route("/", get(root_redirect))
 }
 
-async fn health_check() -> &'static str {
+async fn probe_database(db: &Storage) -> Result<(), String> {
+    let probe = async {
+        db.health_check().await?;
+        match db.scylla() {
+            Some(scylla) => scylla.probe_keyspace().await,
+            None => Ok(()),
+        }
+    };
+
+    match tokio::time::timeout(Duration::from_millis(HEALTH_PROBE_TIMEOUT_MS), probe).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => {
+            warn!("Health probe failed: {}", e);
+            Err("unreachable".into())
+        }
+        Err(_) => {
+            warn!("Health probe timed out after {}ms", HEALTH_PROBE_TIMEOUT_MS);
+            Err("timeout".into())
+        }
+    }
+}
+
+/// Probes the database unless the monitor has already opened the circuit,
+/// in which case the probe would only add to the load.
+async fn check_database(db: &Storage, health: &DbHealth) -> Result<(), String> {
+    if health.circuit_open() {
+        return Err("circuit open".into());
+    }
+    probe_database(db).await
+}
+
+/// What `/health` says about the driver when something is wrong: how many
+/// nodes it reaches and how its requests fare.
+fn driver_summary(driver: &DriverStats) -> serde_json::Value {
+    let attempts: u64 = driver.nodes.iter().map(|n| n.attempts).sum();
+    let errors: u64 = driver.nodes.iter().map(|n| n.errors).sum();
+    json!({
+        "nodes": driver.nodes.len(),
+        "nodes_connected": driver.nodes.iter().filter(|n| n.connected).count(),
+        "unreachable": driver.unreachable_nodes().map(|n| &n.address).collect::<Vec<_>>(),
+        "in_flight": driver.nodes.iter().map(|n| n.in_flight).sum::<u64>(),
+        "failed_requests": driver.failed_requests,
+        "error_rate": if attempts == 0 { 0.0 } else { errors as f64 / attempts as f64 },
+    })
+}
+
+async fn health_check(
+    State(db): State<Storage>,
+    State(config): State<ConfigHandle>,
+    State(health): State<Arc<DbHealth>>,
+) -> Response {
+    let database = check_database(&db, &health).await;
+    let oauth_configured = config.load().oauth_configured();
+    let driver = db.driver_stats();
+    let nodes_reachable = driver
+        .as_ref()
+        .is_none_or(|d| d.unreachable_nodes().next().is_none());
+
+    let (status, code) = match (&database, oauth_configured && nodes_reachable) {
+        (Err(_), _) => ("unhealthy", StatusCode::SERVICE_UNAVAILABLE),
+        (Ok(()), false) => ("degraded", StatusCode::OK),
+        (Ok(()), true) => ("ok", StatusCode::OK),
+    };
+
+    let monitor = health.status();
+    let mut database = match database {
+        Ok(()) => json!({"status": "ok", "monitor": monitor}),
+        Err(reason) => json!({"status": "error", "reason": reason, "monitor": monitor}),
+    };
+    if let Some(driver) = driver.filter(|_| status != "ok") {
+        database["driver"] = driver_summary(&driver);
+    }
+    let oauth_config = if oauth_configured {
+        json!({"status": "ok"})
+    } else {
+        json!({"status": "error", "reason": "missing credentials"})
+    };
+
+    (
+        code,
+        Json(json!({
+            "status": status,
+            "checks": {
+                "database": database,
+                "oauth_config": oauth_config
+            }
+        })),
+    )
+        .into_response()
+}
+
+async fn liveness() -> &'static str {
     r#"{"status":"ok"}"#
 }
 
-async fn root_redirect() -> Response {
-    if let Ok(redirect_url) = env::var("API_ROOT_REDIRECT_URL")
-        && !redirect_url.is_empty()
-    {
+async fn readiness(State(db): State<Storage>, State(health): State<Arc<DbHealth>>) -> Response {
+    match check_database(&db, &health).await {
+        Ok(()) => Json(json!({"status": "ok"})).into_response(),
+        Err(reason) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"status": "unavailable", "reason": reason})),
+        )
+            .into_response(),
+    }
+}
+
+async fn root_redirect(State(config): State<ConfigHandle>) -> Response {
+    if let Some(redirect_url) = &config.load().api_root_redirect_url {
         debug!("Redirecting to: {}", redirect_url);
-        if redirect_url.starts_with("http://") || redirect_url.starts_with("https://") {
-            return Redirect::permanent(&redirect_url).into_response();
-        } else {
-            debug!("Invalid redirect URL format: {}", redirect_url);
-        }
+        return Redirect::permanent(redirect_url.as_str()).into_response();
     }
 
+    let endpoints: Vec<&str> = ["/health", "/health/live", "/health/ready"]
+        .into_iter()
+        .chain(openapi::endpoints("/v1"))
+        .chain(openapi::endpoints("/v2"))
+        .collect();
     Json(json!({
         "message": "EquiCloud",
         "version": "2.0.0",
-        "endpoints": [
-            "/health",
-            "/v1/oauth/callback",
-            "/v1/oauth/settings",
-            "/v1/settings",
-            "/v2/manifest",
-            "/v2/data/{key}",
-            "/v2/sync"
-        ]
+        "endpoints": endpoints
     }))
     .into_response()
 }
@@ -1,48 +1,81 @@
-use axum::{
-    Extension, Router,
-    http::StatusCode,
-    response::{IntoResponse, Json},
-    routing::get,
-};
-use serde_json::json;
-use std::env;
-use std::sync::OnceLock;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tracing::error;
-
-use equicloud::DatabaseService;
-use equicloud::constants::{MS_PER_DAY, MS_PER_MONTH, MS_PER_WEEK};
-
-static START_TIME: OnceLock<u64> = OnceLock::new();
+use axum::{Router, extract::State, middleware::from_fn_with_state, response::Json, routing::get};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use tracing::{error, warn};
 
-pub fn register() -> Router {
-    START_TIME.get_or_init(|| {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("system time before UNIX epoch")
-            .as_secs()
-    });
+use equicloud::constants::{MS_PER_DAY, MS_PER_MONTH, MS_PER_WEEK};
+use equicloud::error::AppError;
+use equicloud::metrics::QUERY_LATENCY_BUCKETS_MS;
+use equicloud::{DatabaseService, DbHealth, Metrics, Storage, Tenants};
+
+use crate::middleware::metrics::metrics_middleware;
+use crate::state::AppState;
+
+/// Serves `/metrics` when `METRICS_ENABLED` is set, behind
+/// `METRICS_TOKEN` and `METRICS_ALLOWED_IPS`.
+pub fn register(state: &AppState) -> Router<AppState> {
+    let config = state.config.load();
+    if !config.metrics_enabled {
+        return Router::new();
+    }
+    if config.metrics_token.is_none() && config.metrics_allowed_ips.is_empty() {
+        warn!(
+            "Metrics are enabled without METRICS_TOKEN or METRICS_ALLOWED_IPS; anyone can read them"
+        );
+    }
 
-    Router::new().route("/metrics", get(get_metrics))
+    Router::new()
+        .route("/metrics", get(get_metrics))
+        .route_layer(from_fn_with_state(state.clone(), metrics_middleware))
 }
 
-async fn get_metrics(Extension(db): Extension<DatabaseService>) -> impl IntoResponse {
-    let metrics_enabled = env::var("METRICS_ENABLED")
-        .unwrap_or_else(|_| "false".to_string())
-        .parse::<bool>()
-        .unwrap_or(false);
-
-    if !metrics_enabled {
-        return StatusCode::NOT_FOUND.into_response();
+async fn get_metrics(
+    State(db): State<Storage>,
+    State(metrics): State<Arc<Metrics>>,
+    State(tenants): State<Arc<Tenants>>,
+    State(db_health): State<Arc<DbHealth>>,
+) -> Result<Json<Value>, AppError> {
+    let uptime = metrics.uptime_secs();
+    let retention = metrics.retention();
+    let compaction = metrics.compaction();
+    let scrub = metrics.scrub();
+    let settings_cache = db.settings_cache_stats();
+    let batch_reads = db.batch_read_stats();
+    let batch_writes = db.batch_write_stats();
+    let queries = db.query_stats();
+    let driver = db.driver_stats();
+    let database = db_health.status();
+
+    let mut auth_successes = serde_json::Map::new();
+    let mut auth_failures = serde_json::Map::new();
+    for (outcome, count) in metrics.auth_outcomes() {
+        let counts = if outcome.is_success() {
+            &mut auth_successes
+        } else {
+            &mut auth_failures
+        };
+        counts.insert(outcome.as_str().to_string(), count.into());
     }
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_else(|_| std::time::Duration::from_secs(0))
-        .as_secs();
-
-    let start_time = *START_TIME.get().unwrap_or(&0);
-    let uptime = now - start_time;
+    let tenant_requests = metrics.tenant_requests();
+    let tenants: serde_json::Map<String, Value> = tenants
+        .all()
+        .map(|tenant| {
+            let cache = tenant.db.settings_cache_stats();
+            let batch_reads = tenant.db.batch_read_stats();
+            let batch_writes = tenant.db.batch_write_stats();
+            let stats = json!({
+                "requests": tenant_requests.get(&tenant.id).copied().unwrap_or(0),
+                "settings_cache_hits": cache.hits,
+                "settings_cache_misses": cache.misses,
+                "settings_cache_entries": cache.entries,
+                "settings_cache_bytes": cache.bytes,
+                "db_batch_read_mean_ms": batch_reads.mean_ms,
+                "db_batch_write_mean_ms": batch_writes.mean_ms,
+            });
+            (tenant.id.clone(), stats)
+        })
+        .collect();
 
     let user_counts = match get_user_counts(&db).await {
         Ok(counts) => counts,
@@ -52,15 +85,45 @@ async fn get_metrics(Extension(db): Extension<DatabaseService>) -> impl IntoResp
         }
     };
 
-    Json(json!({
+    Ok(Json(json!({
         "users_day": user_counts.day,
         "users_week": user_counts.week,
         "users_month": user_counts.month,
         "users_total": user_counts.total,
+        "retention_accounts_marked": retention.accounts_marked,
+        "retention_accounts_purged": retention.accounts_purged,
+        "retention_bytes_reclaimed": retention.bytes_reclaimed,
+        "history_versions_removed": compaction.versions_removed,
+        "history_bytes_reclaimed": compaction.bytes_reclaimed,
+        "checksum_mismatches": metrics.checksum_mismatches(),
+        "scrub_values_checked": scrub.checked,
+        "scrub_values_corrupt": scrub.corrupt,
+        "scrub_values_unreadable": scrub.unreadable,
+        "settings_cache_hits": settings_cache.hits,
+        "settings_cache_misses": settings_cache.misses,
+        "settings_cache_entries": settings_cache.entries,
+        "settings_cache_bytes": settings_cache.bytes,
+        "db_batch_reads": batch_reads.batches,
+        "db_batch_read_keys": batch_reads.keys,
+        "db_batch_read_mean_ms": batch_reads.mean_ms,
+        "db_batch_read_max_ms": batch_reads.max_ms,
+        "db_batch_writes": batch_writes.batches,
+        "db_batch_write_keys": batch_writes.keys,
+        "db_batch_write_mean_ms": batch_writes.mean_ms,
+        "db_batch_write_max_ms": batch_writes.max_ms,
+        "auth_successes": auth_successes,
+        "auth_failures": auth_failures,
+        "db_queries": queries,
+        "db_query_buckets_ms": QUERY_LATENCY_BUCKETS_MS,
+        "db_driver": driver,
+        "tenants": tenants,
+        "database_healthy": database.healthy,
+        "database_circuit_open": database.circuit_open,
+        "database_consecutive_failures": database.consecutive_failures,
+        "database_last_healthy_at": database.last_healthy_at,
         "uptime_seconds": uptime,
         "timestamp": chrono::Utc::now().timestamp()
-    }))
-    .into_response()
+    })))
 }
 
 #[derive(Default)]
@@ -71,7 +134,11 @@ struct UserCounts {
     day: u64,
 }
 
-async fn get_user_counts(db: &DatabaseService) -> Result<UserCounts, anyhow::Error> {
+/// User counts are only tracked in Scylla; the SQLite backend reports zeros.
+async fn get_user_counts(db: &Storage) -> Result<UserCounts, anyhow::Error> {
+    let Some(db) = db.scylla() else {
+        return Ok(UserCounts::default());
+    };
     let now = chrono::Utc::now().timestamp_millis();
     let day_ago = now - MS_PER_DAY;
     let week_ago = now - MS_PER_WEEK;
@@ -1,14 +1,42 @@
 use axum::Router;
+use axum::middleware::from_fn_with_state;
+use equicloud::cors::{self, RouteGroup};
 
+use crate::middleware::body_limit::{default_limit, limit_body};
+use crate::middleware::client_ip::resolve_client_ip;
+use crate::middleware::timeout::{default_timeout, with_timeout};
+use crate::state::AppState;
+
+pub mod admin;
 pub mod health;
 pub mod metrics;
+pub mod openapi;
 pub mod v1;
 pub mod v2;
+pub mod v3;
 
-pub fn register_routes() -> Router {
-    Router::new()
+pub fn register_routes(state: &AppState) -> Router<AppState> {
+    let config = state.config.load();
+    let small_routes = Router::new()
         .merge(health::register())
-        .merge(metrics::register())
-        .merge(v1::register())
-        .merge(v2::register())
+        .merge(metrics::register(state))
+        .merge(openapi::register(state));
+    let small = |routes| {
+        with_timeout(
+            limit_body(routes, &state.tenants, default_limit),
+            &state.tenants,
+            default_timeout,
+        )
+    };
+
+    let api = Router::new()
+        .merge(small(small_routes))
+        .merge(v1::register(state))
+        .merge(v2::register(state))
+        .merge(v3::register(state))
+        .layer(cors::layer(&config, RouteGroup::Api));
+    let admin = small(admin::register(state)).layer(cors::layer(&config, RouteGroup::Admin));
+
+    api.merge(admin)
+        .layer(from_fn_with_state(state.config.clone(), resolve_client_ip))
 }
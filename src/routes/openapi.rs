@@ -0,0 +1,131 @@
+use axum::{Json, Router, routing::get};
+use once_cell::sync::Lazy;
+use utoipa::openapi::schema::{KnownFormat, ObjectBuilder, Schema, SchemaFormat, Type};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::openapi::{OpenApi as OpenApiSpec, RefOr};
+use utoipa::{Modify, OpenApi, PartialSchema, ToSchema};
+use utoipa_swagger_ui::{Config, SwaggerUi};
+
+use super::{v1, v2, v3};
+use crate::state::AppState;
+
+const SPEC_PATH: &str = "/openapi.json";
+
+static SPEC: Lazy<OpenApiSpec> = Lazy::new(ApiDoc::openapi);
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Equicloud",
+        description = "Settings backup and data sync for Equicord.",
+        license(name = "BSD-3-Clause", identifier = "BSD-3-Clause")
+    ),
+    paths(
+        v1::delete::get_user_info,
+        v1::delete::delete_all_user_data,
+        v1::delete::restore_account,
+        v1::links::list_links,
+        v1::links::link_identity,
+        v1::links::unlink_identity,
+        v1::settings::head_settings,
+        v1::settings::get_settings,
+        v1::settings::put_settings,
+        v1::settings::delete_settings,
+        v1::settings::get_settings_diff,
+        v1::settings::export_settings,
+        v1::oauth::authorize::oauth_authorize,
+        v1::oauth::callback::oauth_callback,
+        v1::oauth::refresh::oauth_refresh,
+        v1::oauth::settings::oauth_settings,
+        v1::oauth::token::oauth_token,
+        v2::manifest::get_manifest,
+        v2::keys::list_keys,
+        v2::export::export_data,
+        v2::import::import_data,
+        v2::data::get_data,
+        v2::data::head_data,
+        v2::data::put_data,
+        v2::data::delete_data,
+        v2::data::rename_data,
+        v2::shares::create_share,
+        v2::shares::get_shared,
+        v2::batch::batch_get_data,
+        v2::batch::batch_put_data,
+        v2::batch::check_data,
+        v2::sync::delta_sync,
+        v2::sync_history::get_sync_history,
+        v2::uploads::create_upload,
+        v2::uploads::get_upload,
+        v2::uploads::put_upload_part,
+        v2::uploads::complete_upload,
+        v2::uploads::abort_upload,
+        v2::capabilities::get_capabilities,
+        v2::devices::list_devices,
+        v2::usage::get_usage,
+        v3::sync::paged_sync,
+        v3::sync::sync_downloads,
+    ),
+    modifiers(&TokenAuth),
+    tags(
+        (name = "account", description = "Service status, linked identities and account deletion"),
+        (name = "oauth", description = "Sign-in and session renewal"),
+        (name = "settings", description = "The v1 settings backup"),
+        (name = "data", description = "v2 per-key data sync and v3 paged sync")
+    )
+)]
+pub struct ApiDoc;
+
+/// Raw bytes in an `application/octet-stream` or `application/x-tar` body.
+pub struct Binary;
+
+impl PartialSchema for Binary {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .format(Some(SchemaFormat::KnownFormat(KnownFormat::Binary)))
+            .into()
+    }
+}
+
+impl ToSchema for Binary {}
+
+/// Registers the `token` scheme referenced by authenticated routes.
+struct TokenAuth;
+
+impl Modify for TokenAuth {
+    fn modify(&self, openapi: &mut OpenApiSpec) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "Authorization",
+                "Base64 of `{secret}:{user id}`, optionally prefixed with `Bearer `",
+            ))),
+        );
+    }
+}
+
+/// Documented paths under `prefix`, such as `/v1`, in order.
+pub fn endpoints(prefix: &str) -> Vec<&'static str> {
+    SPEC.paths
+        .paths
+        .keys()
+        .map(String::as_str)
+        .filter(|path| {
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .collect()
+}
+
+/// Serves the spec at `/openapi.json`, and a Swagger UI for it at `/docs`
+/// when `API_DOCS_ENABLED` is set.
+pub fn register(state: &AppState) -> Router<AppState> {
+    let router = Router::new().route(SPEC_PATH, get(|| async { Json(&*SPEC) }));
+
+    if state.config.load().api_docs_enabled {
+        router.merge(SwaggerUi::new("/docs").config(Config::from(SPEC_PATH)))
+    } else {
+        router
+    }
+}
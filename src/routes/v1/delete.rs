@@ -1,34 +1,150 @@
 use axum::{
-    Extension,
+    extract::State,
     http::StatusCode,
-    response::{IntoResponse, Json},
+    response::{IntoResponse, Json, Response},
 };
-use serde_json::json;
-use tracing::error;
+use serde::Serialize;
+use utoipa::ToSchema;
 
-use equicloud::DatabaseService;
+use equicloud::account_deletion;
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::scopes::Scope;
+use equicloud::{AccountPurge, Datastore, Event, EventBus, PendingDeletion};
 
+use crate::middleware::audit::AuditContext;
+use crate::middleware::auth::{RestoringUser, ScopedUser};
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Serialize, ToSchema)]
+pub struct ServiceInfo {
+    #[schema(example = "ok")]
+    status: &'static str,
+    /// Server time in seconds since the epoch.
+    timestamp: i64,
+    #[schema(example = "equicloud")]
+    service: &'static str,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1",
+    tag = "account",
+    responses((status = 200, description = "The service is up", body = ServiceInfo))
+)]
 pub async fn get_user_info() -> impl IntoResponse {
-    Json(json!({
-        "status": "ok",
-        "timestamp": chrono::Utc::now().timestamp(),
-        "service": "equicloud"
-    }))
+    Json(ServiceInfo {
+        status: "ok",
+        timestamp: chrono::Utc::now().timestamp(),
+        service: "equicloud",
+    })
 }
 
+/// Deletes the user's settings, all of their v2 data, any quota override
+/// and their stored refresh token, and unlinks the identities linked to the
+/// account. With `ACCOUNT_DELETION_GRACE_DAYS` set, the account is only
+/// scheduled for deletion and can be restored until then; otherwise it is
+/// deleted at once, reporting what was removed.
+#[utoipa::path(
+    delete,
+    path = "/v1",
+    tag = "account",
+    security(("token" = [])),
+    responses(
+        (status = 200, description = "Everything was deleted", body = AccountPurge),
+        (status = 202, description = "The account is scheduled for deletion", body = PendingDeletion),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+        (status = 403, description = "The token lacks the admin scope or is limited to a key prefix", body = ErrorBody),
+        (status = 410, description = "The account is already scheduled for deletion", body = ErrorBody)
+    )
+)]
 pub async fn delete_all_user_data(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
-) -> impl IntoResponse {
-    if let Err(e) = db.delete_user_settings(&user_id).await {
-        error!("Failed to delete user settings: {}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR;
-    }
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    ScopedUser(user_id, scopes): ScopedUser,
+    audit: AuditContext,
+) -> Result<Response, AppError> {
+    scopes.require_account(Scope::Admin)?;
+    let db = &tenant.db;
+    let config = tenant.config.load();
 
-    if let Err(e) = db.delete_all_data(&user_id).await {
-        error!("Failed to delete user data: {}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR;
+    if config.account_deletion_grace_days > 0 {
+        let result = account_deletion::schedule(db, &config, &user_id)
+            .await
+            .or_internal("Failed to schedule account deletion");
+        let detail = result
+            .as_ref()
+            .ok()
+            .map(|pending| format!("purge_at={}", pending.purge_at));
+        audit
+            .record(
+                db,
+                AuditActor::User,
+                Some(&user_id),
+                AuditAction::DeleteAllData,
+                detail,
+                result.is_ok(),
+            )
+            .await;
+        return Ok((StatusCode::ACCEPTED, Json(result?)).into_response());
     }
 
-    StatusCode::NO_CONTENT
+    let result = db
+        .purge_account(&user_id)
+        .await
+        .or_internal("Failed to delete user data");
+    audit
+        .record(
+            db,
+            AuditActor::User,
+            Some(&user_id),
+            AuditAction::DeleteAllData,
+            None,
+            result.is_ok(),
+        )
+        .await;
+    let purged = result?;
+
+    events.publish(Event::AccountDeleted { user_id });
+    Ok(Json(purged).into_response())
+}
+
+/// Cancels the scheduled deletion of the account, keeping everything it
+/// stores.
+#[utoipa::path(
+    post,
+    path = "/v1/restore",
+    tag = "account",
+    security(("token" = [])),
+    responses(
+        (status = 204, description = "The account was restored"),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+        (status = 403, description = "The token lacks the admin scope or is limited to a key prefix", body = ErrorBody),
+        (status = 404, description = "The account is not scheduled for deletion", body = ErrorBody)
+    )
+)]
+pub async fn restore_account(
+    CurrentTenant(tenant): CurrentTenant,
+    RestoringUser(user_id, scopes): RestoringUser,
+    audit: AuditContext,
+) -> Result<StatusCode, AppError> {
+    scopes.require_account(Scope::Admin)?;
+    let result = account_deletion::restore(&tenant.db, &user_id)
+        .await
+        .or_internal("Failed to restore account");
+    audit
+        .record(
+            &tenant.db,
+            AuditActor::User,
+            Some(&user_id),
+            AuditAction::RestoreAccount,
+            None,
+            matches!(result, Ok(true)),
+        )
+        .await;
+
+    if !result? {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,219 @@
+//! Linking sign-in identities to one account.
+//!
+//! Storage is keyed by the user id, so someone moving to a new Discord
+//! account would start over. Signed in with both, they can link the new
+//! identity to the old account; from then on its tokens are served from the
+//! old account's storage. Both sides must hold a session issued by signing
+//! in, not a permanent secret, so a link always proves control of both.
+
+use axum::{
+    Json,
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::constants::MAX_ACCOUNT_LINKS;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::scopes::Scope;
+use equicloud::utils::hash_user_id;
+use equicloud::{AccountLink, Datastore};
+
+use crate::middleware::audit::AuditContext;
+use crate::middleware::auth::{AuthUser, ScopedUser, verify_session};
+use crate::middleware::tenant::{CurrentTenant, TenantDb};
+
+#[derive(Deserialize, ToSchema)]
+pub struct LinkRequest {
+    /// Token of the identity to link, built like the `Authorization` header
+    /// from a session it signed in for.
+    token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AccountLinks {
+    /// Identities that sign in to this account besides its own.
+    links: Vec<AccountLink>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/links",
+    tag = "account",
+    security(("token" = [])),
+    responses(
+        (status = 200, description = "The linked identities", body = AccountLinks),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody)
+    )
+)]
+pub async fn list_links(
+    TenantDb(db): TenantDb,
+    AuthUser(user_id): AuthUser,
+) -> Result<Json<AccountLinks>, AppError> {
+    let links = db
+        .list_account_links(&user_id)
+        .await
+        .or_internal("Failed to list linked identities")?;
+    Ok(Json(AccountLinks { links }))
+}
+
+/// Links the identity of `token` to the caller's account. The identity must
+/// have nothing stored, since its own data would become unreachable.
+#[utoipa::path(
+    post,
+    path = "/v1/links",
+    tag = "account",
+    security(("token" = [])),
+    request_body = LinkRequest,
+    responses(
+        (status = 201, description = "The identity now signs in to this account", body = AccountLink),
+        (status = 400, description = "The identity is the account itself, or the account has too many links", body = ErrorBody),
+        (status = 401, description = "Either token is missing, invalid or not from signing in", body = ErrorBody),
+        (status = 403, description = "The identity is not allowed to sign in, or the token lacks the admin scope", body = ErrorBody),
+        (status = 409, description = "The identity is already linked or has data of its own", body = ErrorBody)
+    )
+)]
+pub async fn link_identity(
+    CurrentTenant(tenant): CurrentTenant,
+    ScopedUser(account_id, scopes): ScopedUser,
+    audit: AuditContext,
+    headers: HeaderMap,
+    Json(request): Json<LinkRequest>,
+) -> Result<(StatusCode, Json<AccountLink>), AppError> {
+    scopes.require_account(Scope::Admin)?;
+    let config = tenant.config.load();
+    let signed_in = headers
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|token| verify_session(&config, token));
+    if signed_in.is_none() {
+        return Err(AppError::Unauthorized(
+            "Sign in again to link identities".into(),
+        ));
+    }
+    let user_id = verify_session(&config, &request.token)
+        .ok_or_else(|| AppError::Unauthorized("Invalid token for the identity to link".into()))?;
+
+    if !config.user_allowed(&user_id) {
+        return Err(AppError::Forbidden("User is not whitelisted".into()));
+    }
+    if user_id == account_id {
+        return Err(AppError::BadRequest(
+            "Cannot link an account to itself".into(),
+        ));
+    }
+
+    let db = &tenant.db;
+    if db
+        .get_account_link(&user_id)
+        .await
+        .or_internal("Failed to resolve account")?
+        .is_some()
+    {
+        return Err(AppError::Conflict(
+            "Identity is already linked to an account".into(),
+        ));
+    }
+    if !db
+        .list_account_links(&user_id)
+        .await
+        .or_internal("Failed to list linked identities")?
+        .is_empty()
+    {
+        return Err(AppError::Conflict(
+            "Identity has linked identities of its own".into(),
+        ));
+    }
+    let usage = db
+        .get_storage_usage(&user_id)
+        .await
+        .or_internal("Failed to get storage usage")?;
+    if usage.settings_size.is_some() || usage.data_keys > 0 {
+        return Err(AppError::Conflict(
+            "Identity has data of its own; delete it first".into(),
+        ));
+    }
+
+    let links = db
+        .list_account_links(&account_id)
+        .await
+        .or_internal("Failed to list linked identities")?;
+    if links.len() >= MAX_ACCOUNT_LINKS {
+        return Err(AppError::BadRequest(format!(
+            "At most {} identities may be linked to an account",
+            MAX_ACCOUNT_LINKS
+        )));
+    }
+
+    let user = hash_user_id(&user_id);
+    let result = db
+        .save_account_link(&user_id, &account_id)
+        .await
+        .or_internal("Failed to link identity");
+    audit
+        .record(
+            db,
+            AuditActor::User,
+            Some(&account_id),
+            AuditAction::LinkAccount,
+            Some(user.clone()),
+            result.is_ok(),
+        )
+        .await;
+    result?;
+
+    let link = AccountLink {
+        user,
+        linked_at: chrono::Utc::now().timestamp_millis(),
+    };
+    Ok((StatusCode::CREATED, Json(link)))
+}
+
+/// Unlinks an identity, which from then on signs in to empty storage of its
+/// own.
+#[utoipa::path(
+    delete,
+    path = "/v1/links/{user_id}",
+    tag = "account",
+    security(("token" = [])),
+    params(("user_id" = String, Path, description = "Id of the linked identity, unhashed")),
+    responses(
+        (status = 204, description = "The identity was unlinked"),
+        (status = 404, description = "The identity is not linked to this account", body = ErrorBody)
+    )
+)]
+pub async fn unlink_identity(
+    TenantDb(db): TenantDb,
+    ScopedUser(account_id, scopes): ScopedUser,
+    audit: AuditContext,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    scopes.require_account(Scope::Admin)?;
+    let linked_to = db
+        .get_account_link(&user_id)
+        .await
+        .or_internal("Failed to resolve account")?;
+    if linked_to.as_deref() != Some(account_id.as_str()) {
+        return Err(AppError::NotFound);
+    }
+
+    let result = db
+        .delete_account_link(&user_id)
+        .await
+        .or_internal("Failed to unlink identity");
+    audit
+        .record(
+            &db,
+            AuditActor::User,
+            Some(&account_id),
+            AuditAction::UnlinkAccount,
+            Some(hash_user_id(&user_id)),
+            result.is_ok(),
+        )
+        .await;
+    result?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,32 +1,64 @@
 use axum::{
-    Router, middleware,
-    routing::{delete, get, head},
+    Router,
+    handler::Handler,
+    middleware::from_fn_with_state,
+    routing::{delete, get, head, post},
 };
 
+use crate::middleware::body_limit::{default_limit, limit_body, settings_limit};
+use crate::middleware::compression::response_compression;
+use crate::middleware::idempotency::replay_or_run;
+use crate::middleware::timeout::{default_timeout, with_timeout};
+use crate::state::AppState;
+
 pub mod delete;
+pub mod links;
 pub mod oauth;
 pub mod settings;
 
-pub fn register() -> Router {
+pub fn register(state: &AppState) -> Router<AppState> {
+    let tenants = &state.tenants;
+    let compression = || response_compression(&state.config.load());
     let public_routes = Router::new()
         .route("/v1", get(delete::get_user_info))
         .route("/v1/", get(delete::get_user_info))
+        .route(
+            "/v1/oauth/authorize",
+            get(oauth::authorize::oauth_authorize),
+        )
         .route("/v1/oauth/callback", get(oauth::callback::oauth_callback))
+        .route("/v1/oauth/refresh", post(oauth::refresh::oauth_refresh))
+        .route("/v1/oauth/token", post(oauth::token::oauth_token))
         .route("/v1/oauth/settings", get(oauth::settings::oauth_settings));
 
-    let auth_routes = Router::new()
+    let settings_routes = Router::new()
         .route(
             "/v1/settings",
             head(settings::head_settings)
-                .get(settings::get_settings)
-                .put(settings::put_settings)
+                .get(settings::get_settings.layer(compression()))
+                .put(settings::put_settings.layer(from_fn_with_state(state.clone(), replay_or_run)))
                 .delete(settings::delete_settings),
         )
+        .route(
+            "/v1/settings/diff",
+            get(settings::get_settings_diff.layer(compression())),
+        )
+        .route(
+            "/v1/settings/export",
+            get(settings::export_settings.layer(compression())),
+        );
+
+    let auth_routes = Router::new()
         .route("/v1", delete(delete::delete_all_user_data))
         .route("/v1/", delete(delete::delete_all_user_data))
-        .route_layer(middleware::from_fn(
-            crate::middleware::auth::auth_middleware,
-        ));
+        .route("/v1/restore", post(delete::restore_account))
+        .route(
+            "/v1/links",
+            get(links::list_links).post(links::link_identity),
+        )
+        .route("/v1/links/{user_id}", delete(links::unlink_identity));
 
-    public_routes.merge(auth_routes)
+    let routes = limit_body(public_routes.merge(auth_routes), tenants, default_limit)
+        .merge(limit_body(settings_routes, tenants, settings_limit));
+    with_timeout(routes, tenants, default_timeout)
 }
@@ -0,0 +1,92 @@
+use axum::{extract::Query, response::Json};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use equicloud::Datastore;
+use equicloud::constants::OAUTH_STATE_TTL_SECS;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::oauth::redirect::is_allowed;
+use equicloud::oauth::{OAuthProvider, OAuthRedirect, PkcePair, RedirectMode, issue_state};
+use reqwest::Url;
+
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Deserialize, IntoParams)]
+pub struct AuthorizeQuery {
+    /// Bind the state to a PKCE verifier kept on the server.
+    #[serde(default)]
+    pub pkce: bool,
+    /// Where the callback sends the browser once the user signed in, instead
+    /// of answering with JSON. Must be covered by `OAUTH_REDIRECT_ALLOWLIST`.
+    pub redirect: Option<String>,
+    /// How the callback hands the session to `redirect`: a one-time `code`
+    /// for `/v1/oauth/token` (the default) or the session in the fragment.
+    #[serde(default)]
+    pub redirect_mode: RedirectMode,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AuthorizeResponse {
+    /// Provider authorization URL to send the user to.
+    url: String,
+    /// Signed state echoed back to `/v1/oauth/callback`.
+    state: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/oauth/authorize",
+    tag = "oauth",
+    params(AuthorizeQuery),
+    responses(
+        (status = 200, description = "Where to send the user to sign in", body = AuthorizeResponse),
+        (status = 400, description = "The return URL is invalid or not allowed", body = ErrorBody),
+        (status = 502, description = "The provider could not be reached, or failed so often it is not being called; `provider_unavailable` comes with Retry-After", body = ErrorBody)
+    )
+)]
+pub async fn oauth_authorize(
+    CurrentTenant(tenant): CurrentTenant,
+    Query(params): Query<AuthorizeQuery>,
+) -> Result<Json<AuthorizeResponse>, AppError> {
+    let config = tenant.config.load();
+    let redirect = params
+        .redirect
+        .map(|uri| {
+            let allowed = Url::parse(&uri)
+                .is_ok_and(|url| is_allowed(&url, &config.oauth_redirect_allowlist));
+            if !allowed {
+                return Err(AppError::BadRequest("Return URL is not allowed".into()));
+            }
+            Ok(OAuthRedirect {
+                uri,
+                mode: params.redirect_mode,
+            })
+        })
+        .transpose()?;
+
+    let expires_at = chrono::Utc::now().timestamp_millis() + OAUTH_STATE_TTL_SECS * 1000;
+    let state = issue_state(config.oauth_state_secret.as_bytes(), expires_at);
+    let pkce = params.pkce.then(PkcePair::generate);
+
+    tenant
+        .db
+        .save_oauth_state(
+            &state,
+            pkce.as_ref().map(|p| p.verifier.as_str()),
+            redirect.as_ref(),
+            OAUTH_STATE_TTL_SECS as i32,
+        )
+        .await
+        .or_internal("Failed to start authorization")?;
+
+    let url = tenant
+        .provider
+        .authorize_url(
+            &state,
+            &tenant.config.load().redirect_uri(),
+            pkce.as_ref().map(|p| p.challenge.as_str()),
+        )
+        .await?;
+
+    Ok(Json(AuthorizeResponse { url, state }))
+}
@@ -1,126 +1,240 @@
-use axum::{extract::Query, response::Json};
-use reqwest;
-use serde::Deserialize;
-use serde_json::{Value, json};
-use tracing::{error, info};
-
-use equicloud::constants::{DISCORD_TOKEN_URL, DISCORD_USER_URL};
-use equicloud::utils::{CONFIG, error_response, get_user_secret, hash_user_id};
-
-#[derive(Deserialize)]
+use axum::{
+    extract::{Query, State},
+    http::{StatusCode, header::LOCATION},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use equicloud::constants::OAUTH_CODE_TTL_SECS;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::oauth::redirect::{code_key, issue_code, with_fragment, with_query};
+use equicloud::oauth::{
+    OAuthProvider, OAuthRedirect, ProviderError, RedirectMode, encrypt_token,
+    issue_scoped_session_secret, verify_state,
+};
+use equicloud::scopes::TokenScopes;
+use equicloud::user_secrets;
+use equicloud::utils::{Config, hash_user_id};
+use equicloud::{Datastore, Event, EventBus, OAuthCode, Storage, Tenant};
+
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Deserialize, IntoParams)]
 pub struct OAuthCallback {
     pub code: Option<String>,
+    pub state: Option<String>,
     pub error: Option<String>,
+    /// Comma-separated scopes to limit the session to, out of `read`,
+    /// `write`, `delete` and `admin`. Every scope if this and `prefix` are
+    /// left out, every scope but `admin` if only `prefix` is given.
+    pub scope: Option<String>,
+    /// Limit the session to data keys starting with this.
+    pub prefix: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct DiscordAccessTokenResult {
-    access_token: String,
+/// A newly issued session.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionResponse {
+    /// Secret to send, with the user id, in the `Authorization` header.
+    secret: String,
+    /// When the secret stops being accepted, in milliseconds.
+    expires_at: i64,
+    /// What the secret may do.
+    scopes: Vec<&'static str>,
+    /// The data keys the secret is limited to, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    /// The non-expiring secret derived with the server's `SECRET_PEPPER`,
+    /// to replace one the client kept from before; only sent with unscoped
+    /// sessions while `PERMANENT_SECRETS_ENABLED` and a pepper are set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    permanent_secret: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct DiscordUserResult {
-    id: String,
-}
-
-pub async fn oauth_callback(Query(params): Query<OAuthCallback>) -> Json<Value> {
+#[utoipa::path(
+    get,
+    path = "/v1/oauth/callback",
+    tag = "oauth",
+    params(OAuthCallback),
+    responses(
+        (status = 200, description = "The user signed in", body = SessionResponse),
+        (status = 302, description = "The user signed in and is sent back to the return URL given to `/v1/oauth/authorize`, with a `code` for `/v1/oauth/token` or the session in the fragment, and the `state`"),
+        (status = 400, description = "Missing or invalid code or state", body = ErrorBody),
+        (status = 403, description = "The user is not allowed to sign in", body = ErrorBody),
+        (status = 502, description = "The provider could not be reached, or failed so often it is not being called; `provider_unavailable` comes with Retry-After", body = ErrorBody)
+    )
+)]
+pub async fn oauth_callback(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    Query(params): Query<OAuthCallback>,
+) -> Result<Response, AppError> {
     if let Some(error) = params.error {
-        return Json(error_response(&error));
+        return Err(AppError::BadRequest(error));
     }
 
-    let code = match params.code {
-        Some(code) => code,
-        None => {
-            return Json(error_response("Missing code"));
-        }
-    };
+    let state = params
+        .state
+        .ok_or_else(|| AppError::BadRequest("Missing state".into()))?;
 
-    let redirect_uri = CONFIG.redirect_uri();
-
-    let client = reqwest::Client::new();
-
-    let grant_type = "authorization_code";
-    let scope = "identify";
-
-    let token_response = client
-        .post(DISCORD_TOKEN_URL)
-        .form(&[
-            ("client_id", CONFIG.discord_client_id.as_str()),
-            ("client_secret", CONFIG.discord_client_secret.as_str()),
-            ("grant_type", grant_type),
-            ("code", code.as_str()),
-            ("redirect_uri", redirect_uri.as_str()),
-            ("scope", scope),
-        ])
-        .send()
-        .await;
-
-    let token_response = match token_response {
-        Ok(response) => response,
-        Err(err) => {
-            error!("Failed to request access token: {}", err);
-            return Json(error_response("Failed to request access token"));
-        }
+    let scopes = match (&params.scope, &params.prefix) {
+        (None, None) => TokenScopes::full(),
+        (scope, prefix) => TokenScopes::from_request(
+            scope.as_deref().unwrap_or("read,write,delete"),
+            prefix.as_deref(),
+        )
+        .map_err(AppError::BadRequest)?,
     };
 
-    if !token_response.status().is_success() {
-        return Json(error_response("Invalid code"));
+    let config = tenant.config.load();
+    let now = chrono::Utc::now().timestamp_millis();
+    if !verify_state(config.oauth_state_secret.as_bytes(), &state, now) {
+        return Err(AppError::BadRequest("Invalid state".into()));
     }
 
-    let token_result: DiscordAccessTokenResult = match token_response.json().await {
-        Ok(result) => result,
-        Err(err) => {
-            error!("Failed to parse token response: {}", err);
-            return Json(error_response("Failed to parse token response"));
-        }
-    };
+    let pending = tenant
+        .db
+        .consume_oauth_state(&state)
+        .await
+        .or_internal("Failed to verify state")?
+        .ok_or_else(|| AppError::BadRequest("Invalid state".into()))?;
+
+    let code = params
+        .code
+        .ok_or_else(|| AppError::BadRequest("Missing code".into()))?;
+
+    let redirect_uri = config.redirect_uri();
+
+    let token_result = tenant
+        .provider
+        .exchange_code(&code, &redirect_uri, pending.code_verifier.as_deref())
+        .await
+        .map_err(|e| match e {
+            ProviderError::TokenRejected => AppError::BadRequest("Invalid code".into()),
+            e => e.into(),
+        })?;
+
+    let user_id = tenant
+        .provider
+        .fetch_user_id(&token_result.access_token)
+        .await?;
+
+    if !config.user_allowed(&user_id) {
+        return Err(AppError::Forbidden("User is not whitelisted".into()));
+    }
 
-    let user_response = client
-        .get(DISCORD_USER_URL)
-        .header(
-            "Authorization",
-            format!("Bearer {}", token_result.access_token),
-        )
-        .send()
-        .await;
-
-    let user_response = match user_response {
-        Ok(response) => response,
-        Err(err) => {
-            error!("Failed to request user: {}", err);
-            return Json(error_response("Failed to request user"));
-        }
-    };
+    let signed_up = is_new_user(&tenant.db, &user_id).await;
 
-    if !user_response.status().is_success() {
-        return Json(error_response("Failed to request user"));
+    if let Some(refresh_token) = &token_result.refresh_token {
+        store_refresh_token(&tenant, &user_id, refresh_token).await;
     }
 
-    let user_result: DiscordUserResult = match user_response.json().await {
-        Ok(result) => result,
-        Err(err) => {
-            error!("Failed to parse user response: {}", err);
-            return Json(error_response("Failed to parse user response"));
-        }
+    let response = match &pending.redirect {
+        None => Json(issue_session(&config, &user_id, &scopes)).into_response(),
+        Some(redirect) => redirect_back(&tenant, redirect, &user_id, &scopes, &state).await?,
     };
+    let user_hash = hash_user_id(&user_id);
 
-    let user_id = user_result.id;
+    info!("User {} authenticated successfully", &user_hash[..16]);
 
-    if let Some(allowed_users) = &CONFIG.discord_allowed_user_ids
-        && !allowed_users.is_empty()
-    {
-        let allowed_list: Vec<&str> = allowed_users.split(',').map(|s| s.trim()).collect();
-        if !allowed_list.contains(&user_id.as_str()) {
-            return Json(error_response("User is not whitelisted"));
-        }
+    if signed_up {
+        events.publish(Event::UserSignedUp { user_id });
     }
 
-    let secret = get_user_secret(&user_id);
-    let user_hash = hash_user_id(&user_id);
+    Ok(response)
+}
 
-    info!("User {} authenticated successfully", &user_hash[..16]);
+/// Sends the browser back to the client with a one-time code for
+/// `/v1/oauth/token`, or with the session in the fragment, which stays in
+/// the browser. The fragment leaves out the permanent secret, which is
+/// only handed out by the exchange since it never expires.
+async fn redirect_back(
+    tenant: &Tenant,
+    redirect: &OAuthRedirect,
+    user_id: &str,
+    scopes: &TokenScopes,
+    state: &str,
+) -> Result<Response, AppError> {
+    let location = match redirect.mode {
+        RedirectMode::Code => {
+            let code = issue_code();
+            let pending = OAuthCode {
+                user_id: user_id.to_string(),
+                scopes: scopes.encode(),
+            };
+            tenant
+                .db
+                .save_oauth_code(&code_key(&code), &pending, OAUTH_CODE_TTL_SECS)
+                .await
+                .or_internal("Failed to issue code")?;
+            with_query(&redirect.uri, &[("code", &code), ("state", state)])
+        }
+        RedirectMode::Fragment => {
+            let session = issue_session(&tenant.config.load(), user_id, scopes);
+            let scopes = session.scopes.join(",");
+            let expires_at = session.expires_at.to_string();
+            let mut params = vec![
+                ("secret", session.secret.as_str()),
+                ("expiresAt", expires_at.as_str()),
+                ("scopes", scopes.as_str()),
+            ];
+            if let Some(prefix) = &session.prefix {
+                params.push(("prefix", prefix));
+            }
+            params.push(("state", state));
+            with_fragment(&redirect.uri, &params)
+        }
+    };
+    let location = location.ok_or(AppError::Internal("Invalid return URL"))?;
+    Ok((StatusCode::FOUND, [(LOCATION, location)]).into_response())
+}
 
-    Json(json!({
-        "secret": secret
-    }))
+/// Whether `user_id` has never signed in before: no refresh token and nothing
+/// stored. Lookup failures count as a returning user so a flaky database does
+/// not announce signups twice.
+async fn is_new_user(db: &Storage, user_id: &str) -> bool {
+    let Ok(None) = db.get_refresh_token(user_id).await else {
+        return false;
+    };
+    db.get_storage_usage(user_id)
+        .await
+        .is_ok_and(|usage| usage.settings_size.is_none() && usage.data_keys == 0)
+}
+
+pub(super) async fn store_refresh_token(tenant: &Tenant, user_id: &str, refresh_token: &str) {
+    let key = tenant.config.load().token_encryption_key.clone();
+    let result = match encrypt_token(key.as_bytes(), refresh_token) {
+        Ok(encrypted) => tenant.db.save_refresh_token(user_id, &encrypted).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("Failed to store refresh token: {}", e);
+    }
+}
+
+pub(super) fn issue_session(
+    config: &Config,
+    user_id: &str,
+    scopes: &TokenScopes,
+) -> SessionResponse {
+    let expires_at = chrono::Utc::now().timestamp_millis() + config.session_ttl.as_millis() as i64;
+    let secret = issue_scoped_session_secret(
+        config.session_secret.as_bytes(),
+        user_id,
+        expires_at,
+        scopes,
+    );
+    let permanent_secret = (config.permanent_secrets_enabled && scopes.is_full())
+        .then(|| user_secrets::reissue(config, user_id))
+        .flatten();
+    SessionResponse {
+        secret,
+        expires_at,
+        scopes: scopes.names(),
+        prefix: scopes.prefix().map(str::to_string),
+        permanent_secret,
+    }
 }
@@ -1,2 +1,5 @@
+pub mod authorize;
 pub mod callback;
+pub mod refresh;
 pub mod settings;
+pub mod token;
@@ -0,0 +1,105 @@
+use axum::{http::HeaderMap, response::Json};
+use tracing::{info, warn};
+
+use equicloud::Datastore;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::oauth::{
+    OAuthProvider, ProviderError, decrypt_token, parse_token, verify_scoped_session_secret,
+};
+use equicloud::scopes::TokenScopes;
+use equicloud::utils::hash_user_id;
+
+use super::callback::{SessionResponse, issue_session, store_refresh_token};
+use crate::middleware::auth::verify_permanent_secret;
+use crate::middleware::tenant::CurrentTenant;
+
+fn unauthorized(message: &str) -> AppError {
+    AppError::Unauthorized(message.into())
+}
+
+/// Renews a session using the stored provider refresh token. The caller's
+/// current token may already be expired as long as its signature is valid;
+/// the provider re-confirming the identity is what authorizes the renewal.
+/// The renewed session keeps the scopes of the current one.
+#[utoipa::path(
+    post,
+    path = "/v1/oauth/refresh",
+    tag = "oauth",
+    security(("token" = [])),
+    responses(
+        (status = 200, description = "A renewed session", body = SessionResponse),
+        (status = 401, description = "The session cannot be renewed; sign in again", body = ErrorBody),
+        (status = 502, description = "The provider failed so often it is not being called; try again after Retry-After", body = ErrorBody)
+    )
+)]
+pub async fn oauth_refresh(
+    CurrentTenant(tenant): CurrentTenant,
+    headers: HeaderMap,
+) -> Result<Json<SessionResponse>, AppError> {
+    let (provided_secret, user_id) = headers
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(parse_token)
+        .ok_or_else(|| unauthorized("Missing or malformed token"))?;
+
+    let config = tenant.config.load();
+    let scopes = match verify_scoped_session_secret(
+        config.session_secret.as_bytes(),
+        &provided_secret,
+        &user_id,
+    ) {
+        Some((_, scopes)) => scopes,
+        None if verify_permanent_secret(&config, &provided_secret, &user_id) => TokenScopes::full(),
+        None => return Err(unauthorized("Invalid token")),
+    };
+
+    let encrypted = tenant
+        .db
+        .get_refresh_token(&user_id)
+        .await
+        .or_internal("Failed to load refresh token")?
+        .ok_or_else(|| unauthorized("Refresh token unavailable, sign in again"))?;
+
+    let refresh_token =
+        decrypt_token(config.token_encryption_key.as_bytes(), &encrypted).map_err(|e| {
+            warn!("Failed to decrypt refresh token: {}", e);
+            unauthorized("Refresh token unavailable, sign in again")
+        })?;
+
+    let token_result = tenant
+        .provider
+        .refresh(&refresh_token)
+        .await
+        .map_err(|e| match e {
+            ProviderError::TokenRejected => {
+                unauthorized("Refresh token was rejected, sign in again")
+            }
+            e @ ProviderError::Unavailable { .. } => e.into(),
+            e => unauthorized(e.message()),
+        })?;
+
+    let refreshed_user_id = tenant
+        .provider
+        .fetch_user_id(&token_result.access_token)
+        .await
+        .map_err(|e| match e {
+            ProviderError::Unavailable { .. } => e.into(),
+            e => unauthorized(e.message()),
+        })?;
+
+    if refreshed_user_id != user_id {
+        warn!("Refresh token resolved to a different user");
+        return Err(unauthorized("Invalid token"));
+    }
+
+    if let Some(refresh_token) = &token_result.refresh_token {
+        store_refresh_token(&tenant, &user_id, refresh_token).await;
+    }
+
+    let session = issue_session(&config, &user_id, &scopes);
+    let user_hash = hash_user_id(&user_id);
+
+    info!("User {} refreshed their session", &user_hash[..16]);
+
+    Ok(Json(session))
+}
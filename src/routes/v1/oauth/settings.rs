@@ -1,10 +1,30 @@
 use axum::response::Json;
-use equicloud::utils::CONFIG;
-use serde_json::{Value, json};
+use equicloud::oauth::OAuthProvider;
+use serde::Serialize;
+use utoipa::ToSchema;
 
-pub async fn oauth_settings() -> Json<Value> {
-    Json(json!({
-        "clientId": CONFIG.discord_client_id,
-        "redirectUri": CONFIG.redirect_uri()
-    }))
+use crate::middleware::tenant::CurrentTenant;
+
+/// What a client needs to build the sign-in flow itself.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthSettings {
+    #[schema(example = "discord")]
+    provider: &'static str,
+    client_id: String,
+    redirect_uri: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/oauth/settings",
+    tag = "oauth",
+    responses((status = 200, description = "The configured identity provider", body = OAuthSettings))
+)]
+pub async fn oauth_settings(CurrentTenant(tenant): CurrentTenant) -> Json<OAuthSettings> {
+    Json(OAuthSettings {
+        provider: tenant.provider.name(),
+        client_id: tenant.provider.client_id().to_string(),
+        redirect_uri: tenant.config.load().redirect_uri(),
+    })
 }
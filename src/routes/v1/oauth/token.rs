@@ -0,0 +1,51 @@
+use axum::response::Json;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use equicloud::Datastore;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::oauth::redirect::code_key;
+use equicloud::scopes::TokenScopes;
+
+use super::callback::{SessionResponse, issue_session};
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Deserialize, ToSchema)]
+pub struct TokenRequest {
+    /// The `code` the callback added to the return URL.
+    code: String,
+}
+
+/// Exchanges the one-time code from a redirected sign-in for the session.
+/// A code works once, and only shortly after the callback issued it.
+#[utoipa::path(
+    post,
+    path = "/v1/oauth/token",
+    tag = "oauth",
+    request_body = TokenRequest,
+    responses(
+        (status = 200, description = "The session of the user who signed in", body = SessionResponse),
+        (status = 400, description = "The code is unknown, expired or already used", body = ErrorBody),
+        (status = 403, description = "The user is no longer allowed to sign in", body = ErrorBody)
+    )
+)]
+pub async fn oauth_token(
+    CurrentTenant(tenant): CurrentTenant,
+    Json(request): Json<TokenRequest>,
+) -> Result<Json<SessionResponse>, AppError> {
+    let invalid = || AppError::BadRequest("Invalid code".into());
+    let pending = tenant
+        .db
+        .consume_oauth_code(&code_key(&request.code))
+        .await
+        .or_internal("Failed to verify code")?
+        .ok_or_else(invalid)?;
+    let scopes = TokenScopes::decode(&pending.scopes).ok_or_else(invalid)?;
+
+    let config = tenant.config.load();
+    if !config.user_allowed(&pending.user_id) {
+        return Err(AppError::Forbidden("User is not whitelisted".into()));
+    }
+
+    Ok(Json(issue_session(&config, &pending.user_id, &scopes)))
+}
@@ -1,130 +1,379 @@
 use axum::{
-    Extension,
     body::{Body, Bytes},
+    extract::{Query, State},
     http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tracing::error;
+use utoipa::{IntoParams, ToSchema};
 
-use equicloud::DatabaseService;
-use equicloud::utils::{CONFIG, error_response};
+use equicloud::EventBus;
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::constants::SETTINGS_DIFF_BLOCK_SIZE;
+use equicloud::content_encoding;
+use equicloud::delta::{self, Signature};
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::etag::{self, ETag};
+use equicloud::settings::{SettingsService, SettingsSince};
+use equicloud::validation::{OCTET_STREAM, decompress_settings, require_content_type};
 
-pub async fn head_settings(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
-    _headers: HeaderMap,
-) -> impl IntoResponse {
-    match db.get_settings_metadata(&user_id).await {
-        Ok(Some(written)) => {
-            let mut response_headers = HeaderMap::new();
-            if let Ok(etag_value) = written.parse() {
-                response_headers.insert("ETag", etag_value);
-            } else {
-                error!("Failed to parse ETag value: {}", written);
-            }
-            (StatusCode::NO_CONTENT, response_headers)
-        }
-        Ok(None) => (StatusCode::NOT_FOUND, HeaderMap::new()),
-        Err(e) => {
-            error!("Database error in head_settings: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, HeaderMap::new())
+use crate::middleware::audit::AuditContext;
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::CurrentTenant;
+use crate::routes::openapi::Binary;
+
+#[derive(Serialize, ToSchema)]
+pub struct SettingsWritten {
+    /// When the settings were written, in milliseconds; their ETag is this
+    /// value quoted.
+    written: i64,
+}
+
+fn insert_etag(headers: &mut HeaderMap, etag: &ETag) {
+    match etag.to_header() {
+        Some(value) => {
+            headers.insert("ETag", value);
         }
+        None => error!("Failed to parse ETag value: {}", etag),
     }
 }
 
+/// Describes the stored settings without the body, so a client can tell
+/// whether it needs them.
+#[utoipa::path(
+    head,
+    path = "/v1/settings",
+    tag = "settings",
+    security(("token" = [])),
+    params(
+        ("If-None-Match" = Option<String>, Header, description = "ETags of cached copies, or `*`"),
+        ("If-Modified-Since" = Option<String>, Header, description = "When the cached copy was written; ignored with If-None-Match")
+    ),
+    responses(
+        (status = 200, description = "Settings exist",
+            headers(
+                ("ETag" = String, description = "When the settings were written"),
+                ("Content-Length" = usize, description = "Size of the settings"),
+                ("Last-Modified" = String, description = "When the settings were written, as an HTTP date"),
+                ("X-Version" = i64, description = "When the settings were written, in milliseconds; the version `/v1/settings/diff` takes"),
+                ("X-Checksum" = String, description = "Checksum of the settings, in the instance's CHECKSUM_ALGORITHM")
+            )),
+        (status = 304, description = "The cached copy is current"),
+        (status = 404, description = "No settings stored")
+    )
+)]
+pub async fn head_settings(
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    // Settings are served from the cache, so reading them to measure and
+    // hash costs little more than their metadata would.
+    let service = SettingsService::new(&tenant);
+    let settings = service.get(&user_id).await?;
+
+    let etag = settings.etag();
+    let mut response_headers = HeaderMap::new();
+    insert_etag(&mut response_headers, &etag);
+    if let Some(v) = etag::last_modified(settings.written) {
+        response_headers.insert("Last-Modified", v);
+    }
+    if etag::fresh(&headers, &etag, settings.written) {
+        return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+    }
+
+    response_headers.insert("Content-Length", settings.value.len().into());
+    response_headers.insert("X-Version", settings.written.into());
+    if let Ok(v) = service.checksum(&settings.value).parse() {
+        response_headers.insert("X-Checksum", v);
+    }
+    // 200 rather than 204, which may not carry a Content-Length.
+    Ok((StatusCode::OK, response_headers).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/settings",
+    tag = "settings",
+    security(("token" = [])),
+    params(
+        ("If-None-Match" = Option<String>, Header, description = "ETags of cached copies, or `*`"),
+        ("If-Modified-Since" = Option<String>, Header, description = "When the cached copy was written; ignored with If-None-Match")
+    ),
+    responses(
+        (status = 200, description = "The stored settings", body = Binary,
+            content_type = "application/octet-stream",
+            headers(
+                ("ETag" = String, description = "When the settings were written"),
+                ("Last-Modified" = String, description = "When the settings were written, as an HTTP date")
+            )),
+        (status = 304, description = "The cached copy is current"),
+        (status = 404, description = "No settings stored", body = ErrorBody)
+    )
+)]
 pub async fn get_settings(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
     headers: HeaderMap,
-) -> impl IntoResponse {
-    match db.get_user_settings(&user_id).await {
-        Ok(Some((value, written))) => {
-            if let Some(if_none_match) = headers.get("if-none-match")
-                && if_none_match.to_str().unwrap_or("") == written
-            {
-                return (StatusCode::NOT_MODIFIED, HeaderMap::new(), Body::empty()).into_response();
-            }
+) -> Result<Response, AppError> {
+    let settings = SettingsService::new(&tenant).get(&user_id).await?;
+
+    let etag = settings.etag();
+    let mut response_headers = HeaderMap::new();
+    insert_etag(&mut response_headers, &etag);
+    if let Some(v) = etag::last_modified(settings.written) {
+        response_headers.insert("Last-Modified", v);
+    }
+
+    if etag::fresh(&headers, &etag, settings.written) {
+        return Ok((StatusCode::NOT_MODIFIED, response_headers, Body::empty()).into_response());
+    }
+
+    if let Ok(content_type) = "application/octet-stream".parse() {
+        response_headers.insert("Content-Type", content_type);
+    }
+
+    Ok((StatusCode::OK, response_headers, Body::from(settings.value)).into_response())
+}
 
-            let mut response_headers = HeaderMap::new();
+#[derive(Deserialize, IntoParams)]
+pub struct DiffQuery {
+    /// ETag of the version the client has.
+    from_etag: String,
+    #[serde(default)]
+    #[param(inline)]
+    format: DiffFormat,
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiffFormat {
+    /// A binary patch against the client's version, see `delta::apply`.
+    #[default]
+    Binary,
+    /// An RFC 6902 patch, for settings stored as JSON.
+    JsonPatch,
+}
+
+/// Returns what changed between a version of the settings the client still
+/// has and the current one, so it can catch up without downloading them all.
+/// Only the last `SETTINGS_HISTORY_VERSIONS` versions can be diffed from.
+#[utoipa::path(
+    get,
+    path = "/v1/settings/diff",
+    tag = "settings",
+    security(("token" = [])),
+    params(DiffQuery),
+    responses(
+        (status = 200, description = "A patch from `from_etag` to the current settings", body = Binary,
+            content_type = "application/octet-stream",
+            headers(
+                ("ETag" = String, description = "When the current settings were written"),
+                ("X-Patch-Block-Size" = usize, description = "Block size to apply a binary patch with")
+            )),
+        (status = 304, description = "`from_etag` is the current version"),
+        (status = 400, description = "`from_etag` is not a settings ETag, or JSON patch was asked of settings that are not JSON", body = ErrorBody),
+        (status = 404, description = "No settings stored, or the version is no longer kept; download the full settings instead", body = ErrorBody)
+    )
+)]
+pub async fn get_settings_diff(
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+    Query(query): Query<DiffQuery>,
+) -> Result<Response, AppError> {
+    let since = SettingsService::new(&tenant)
+        .since(&user_id, &query.from_etag)
+        .await?;
+
+    let mut response_headers = HeaderMap::new();
+    let (base, current) = match since {
+        SettingsSince::Unchanged(current) => {
+            insert_etag(&mut response_headers, &current.etag());
+            return Ok((StatusCode::NOT_MODIFIED, response_headers, Body::empty()).into_response());
+        }
+        SettingsSince::Changed { base, current } => {
+            insert_etag(&mut response_headers, &current.etag());
+            (base, current.value)
+        }
+    };
+
+    match query.format {
+        DiffFormat::Binary => {
+            let signature = Signature::of(&base, SETTINGS_DIFF_BLOCK_SIZE);
+            let patch =
+                delta::diff(&signature, &current).unwrap_or_else(|| delta::literal(&current));
+            response_headers.insert("X-Patch-Block-Size", SETTINGS_DIFF_BLOCK_SIZE.into());
             if let Ok(content_type) = "application/octet-stream".parse() {
                 response_headers.insert("Content-Type", content_type);
             }
-            if let Ok(etag_value) = written.parse() {
-                response_headers.insert("ETag", etag_value);
-            } else {
-                error!("Failed to parse ETag value: {}", written);
-            }
-
-            (StatusCode::OK, response_headers, Body::from(value)).into_response()
+            Ok((StatusCode::OK, response_headers, Body::from(patch)).into_response())
         }
-        Ok(None) => (StatusCode::NOT_FOUND, HeaderMap::new(), Body::empty()).into_response(),
-        Err(e) => {
-            error!("Database error in get_settings: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(error_response("Failed to retrieve settings")),
-            )
-                .into_response()
+        DiffFormat::JsonPatch => {
+            let parse = |bytes: &[u8]| serde_json::from_slice::<Value>(bytes).ok();
+            let (Some(old), Some(new)) = (parse(&base), parse(&current)) else {
+                return Err(AppError::BadRequest(
+                    "The settings are not JSON; use format=binary".into(),
+                ));
+            };
+            if let Ok(content_type) = "application/json-patch+json".parse() {
+                response_headers.insert("Content-Type", content_type);
+            }
+            let patch = serde_json::to_vec(&delta::json_patch(&old, &new))
+                .or_internal("Failed to encode patch")?;
+            Ok((StatusCode::OK, response_headers, Body::from(patch)).into_response())
         }
     }
 }
 
-pub async fn put_settings(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
-    headers: HeaderMap,
-    body: Bytes,
-) -> impl IntoResponse {
-    if headers.get("content-type").and_then(|h| h.to_str().ok()) != Some("application/octet-stream")
-    {
-        return (
-            StatusCode::UNSUPPORTED_MEDIA_TYPE,
-            axum::Json(error_response(
-                "Content type must be application/octet-stream",
-            )),
-        )
-            .into_response();
-    }
+#[derive(Deserialize, IntoParams)]
+pub struct ExportQuery {
+    #[serde(default)]
+    #[param(inline)]
+    format: ExportFormat,
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportFormat {
+    /// Decompressed and indented JSON, with keys sorted; settings that are
+    /// not JSON are sent as stored.
+    #[default]
+    Json,
+    /// The settings as stored.
+    Raw,
+}
 
-    let size_limit = CONFIG.max_backup_size_bytes;
+/// Downloads the settings to read without a client: compressed JSON, as
+/// clients store it, comes back decompressed and indented. Settings that are
+/// not JSON, or larger than `SETTINGS_JSON_MAX_SIZE_BYTES` once
+/// decompressed, are sent as stored.
+#[utoipa::path(
+    get,
+    path = "/v1/settings/export",
+    tag = "settings",
+    security(("token" = [])),
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "The settings as a download; `application/octet-stream` when sent as stored", body = Binary,
+            content_type = "application/json",
+            headers(
+                ("ETag" = String, description = "When the settings were written"),
+                ("Content-Disposition" = String, description = "`attachment` with a file name")
+            )),
+        (status = 404, description = "No settings stored", body = ErrorBody)
+    )
+)]
+pub async fn export_settings(
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, AppError> {
+    let settings = SettingsService::new(&tenant).get(&user_id).await?;
 
-    if body.len() > size_limit {
-        return (
-            StatusCode::PAYLOAD_TOO_LARGE,
-            axum::Json(error_response("Settings are too large")),
-        )
-            .into_response();
+    let mut response_headers = HeaderMap::new();
+    insert_etag(&mut response_headers, &settings.etag());
+    if let Some(v) = etag::last_modified(settings.written) {
+        response_headers.insert("Last-Modified", v);
     }
 
-    match db.save_user_settings(&user_id, body.to_vec()).await {
-        Ok(written) => (
-            StatusCode::OK,
-            axum::Json(json!({
-                "written": written
-            })),
-        )
-            .into_response(),
-        Err(e) => {
-            error!("Database error in put_settings: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(error_response("Failed to save settings")),
-            )
-                .into_response()
-        }
+    let json = match query.format {
+        ExportFormat::Json => pretty_json(
+            &settings.value,
+            tenant.config.load().settings_json_max_size_bytes,
+        ),
+        ExportFormat::Raw => None,
+    };
+    let (body, content_type, file_name) = match json {
+        Some(json) => (json, "application/json", "settings.json"),
+        None => (settings.value, "application/octet-stream", "settings.bin"),
+    };
+    if let Ok(v) = content_type.parse() {
+        response_headers.insert("Content-Type", v);
+    }
+    if let Ok(v) = format!("attachment; filename=\"{}\"", file_name).parse() {
+        response_headers.insert("Content-Disposition", v);
     }
+
+    Ok((StatusCode::OK, response_headers, Body::from(body)).into_response())
+}
+
+/// `value` decompressed and indented, if it is JSON of at most `max_size`
+/// bytes.
+fn pretty_json(value: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let json = decompress_settings(value, max_size)
+        .ok()
+        .filter(|json| json.len() <= max_size)?;
+    let parsed: Value = serde_json::from_slice(&json).ok()?;
+    serde_json::to_vec_pretty(&parsed).ok()
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/settings",
+    tag = "settings",
+    security(("token" = [])),
+    params(
+        ("If-Match" = Option<String>, Header, description = "Only save over these ETags, or `*` for any"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key"),
+        ("Content-Encoding" = Option<String>, Header, description = "`gzip`, `br` or `zstd` if the body is compressed")
+    ),
+    request_body(content = Binary, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Settings were saved", body = SettingsWritten),
+        (status = 400, description = "The body is empty or does not decode with its Content-Encoding", body = ErrorBody),
+        (status = 409, description = "The Idempotency-Key was used for a different request or is still in use", body = ErrorBody),
+        (status = 412, description = "The stored settings do not match If-Match", body = ErrorBody),
+        (status = 413, description = "Settings exceed MAX_BACKUP_SIZE_BYTES once decompressed", body = ErrorBody),
+        (status = 422, description = "With SETTINGS_JSON_VALIDATION, the settings are not JSON within SETTINGS_JSON_MAX_DEPTH and SETTINGS_JSON_MAX_SIZE_BYTES", body = ErrorBody),
+        (status = 415, description = "Body is not application/octet-stream, or its Content-Encoding is not supported", body = ErrorBody)
+    )
+)]
+pub async fn put_settings(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    AuthUser(user_id): AuthUser,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    require_content_type(&headers, OCTET_STREAM)?;
+
+    let body = content_encoding::decode_body(&headers, body)?;
+    let written = SettingsService::new(&tenant)
+        .save(&events, &user_id, body.to_vec(), &headers)
+        .await?;
+
+    Ok(axum::Json(SettingsWritten { written }).into_response())
 }
 
+#[utoipa::path(
+    delete,
+    path = "/v1/settings",
+    tag = "settings",
+    security(("token" = [])),
+    responses((status = 204, description = "Settings were deleted"))
+)]
 pub async fn delete_settings(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
-) -> impl IntoResponse {
-    match db.delete_user_settings(&user_id).await {
-        Ok(_) => StatusCode::NO_CONTENT,
-        Err(e) => {
-            error!("Database error in delete_settings: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        }
-    }
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    AuthUser(user_id): AuthUser,
+    audit: AuditContext,
+) -> Result<StatusCode, AppError> {
+    let result = SettingsService::new(&tenant)
+        .delete(&events, &user_id)
+        .await;
+    audit
+        .record(
+            &tenant.db,
+            AuditActor::User,
+            Some(&user_id),
+            AuditAction::DeleteSettings,
+            None,
+            result.is_ok(),
+        )
+        .await;
+    result?;
+
+    Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,55 @@
+//! Binary values: base64 strings in JSON, byte strings in CBOR.
+
+use base64::prelude::*;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serializer};
+use std::fmt;
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if !deserializer.is_human_readable() {
+        return deserializer.deserialize_byte_buf(BytesVisitor);
+    }
+    let s = String::deserialize(deserializer)?;
+    BASE64_STANDARD.decode(&s).map_err(serde::de::Error::custom)
+}
+
+pub fn serialize<S>(bytes: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if !serializer.is_human_readable() {
+        return serializer.serialize_bytes(bytes);
+    }
+    serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+}
+
+pub fn serialize_option<S>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match bytes {
+        Some(bytes) => serialize(bytes, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a byte string")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,542 @@
+use axum::{Json, extract::State};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::error;
+use utoipa::ToSchema;
+
+use equicloud::abuse::{self, Violation};
+use equicloud::checksum::{self, ChecksumError};
+use equicloud::constants::{DEFAULT_CHECK_INLINE_BYTES, MAX_BATCH_KEYS, MAX_CHECK_INLINE_BYTES};
+use equicloud::devices;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::integrity::{self, ChecksumStatus};
+use equicloud::key_limit::KeyAllowance;
+use equicloud::scopes::Scope;
+use equicloud::write_budget::WriteBudget;
+use equicloud::{DataManifestEntry, DataUpload, Datastore, Event, EventBus, Metrics};
+
+use super::dto::{BatchGetRequest, BatchPutRequest, CheckRequest};
+use crate::middleware::auth::{AuthUser, ReadingUser};
+use crate::middleware::device::RequestDevice;
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Serialize, ToSchema)]
+pub struct BatchGetResponse {
+    entries: Vec<BatchGetEntry>,
+    missing: Vec<String>,
+    errors: Vec<BatchError>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BatchGetEntry {
+    key: String,
+    #[serde(with = "super::base64_serde")]
+    #[schema(value_type = String, format = Byte)]
+    value: Vec<u8>,
+    version: i64,
+    checksum: String,
+    updated_at: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CheckResponse {
+    /// One per key checked, in the order they were sent.
+    results: Vec<CheckResult>,
+    errors: Vec<BatchError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    /// The client's copy is current.
+    Unchanged,
+    /// The key holds another value than the client's copy.
+    Changed,
+    /// The key was never stored, or was deleted.
+    Missing,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CheckResult {
+    key: String,
+    status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_bytes: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<i64>,
+    /// The value, if it changed and is no larger than `inline_max_bytes`.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "super::base64_serde::serialize_option"
+    )]
+    #[schema(value_type = Option<String>, format = Byte)]
+    value: Option<Vec<u8>>,
+}
+
+impl CheckResult {
+    fn missing(key: String) -> Self {
+        Self {
+            key,
+            status: CheckStatus::Missing,
+            version: None,
+            checksum: None,
+            size_bytes: None,
+            updated_at: None,
+            value: None,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BatchPutResponse {
+    saved: Vec<BatchPutResult>,
+    errors: Vec<BatchError>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BatchPutResult {
+    key: String,
+    version: i64,
+    checksum: String,
+    updated_at: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BatchError {
+    key: String,
+    error: String,
+}
+
+fn batch_too_large() -> AppError {
+    AppError::BadRequest(format!("Batch exceeds {} keys", MAX_BATCH_KEYS))
+}
+
+/// Reads up to `MAX_BATCH_KEYS` keys at once. Invalid keys are reported in
+/// `errors` and unknown ones in `missing`. Values that no longer match their
+/// checksum are withheld and reported in `errors` as well.
+#[utoipa::path(
+    post,
+    path = "/v2/data:batchGet",
+    tag = "data",
+    security(("token" = [])),
+    request_body = BatchGetRequest,
+    responses(
+        (status = 200, description = "The values that were found", body = BatchGetResponse,
+            headers(("X-Checksum-Status" = String,
+                description = "`verified`, or `mismatch` if any stored value no longer matches its checksum"))),
+        (status = 400, description = "Too many keys", body = ErrorBody),
+        (status = 403, description = "The token lacks the read scope, or a key is outside its prefix", body = ErrorBody)
+    )
+)]
+pub async fn batch_get_data(
+    CurrentTenant(tenant): CurrentTenant,
+    State(metrics): State<Arc<Metrics>>,
+    ReadingUser(user_id, scopes): ReadingUser,
+    Json(request): Json<BatchGetRequest>,
+) -> Result<(ChecksumStatus, Json<BatchGetResponse>), AppError> {
+    if request.keys.len() > MAX_BATCH_KEYS {
+        return Err(batch_too_large());
+    }
+    let db = &tenant.db;
+    let config = tenant.config.load();
+
+    let mut errors = Vec::new();
+    let mut seen = HashSet::with_capacity(request.keys.len());
+    let mut keys_to_fetch = Vec::with_capacity(request.keys.len());
+
+    for key in request.keys {
+        scopes.require_key(Scope::Read, &key)?;
+        if let Err(e) = config.namespaces.check_key(&key) {
+            errors.push(BatchError {
+                key,
+                error: e.message(),
+            });
+            continue;
+        }
+        if seen.insert(key.clone()) {
+            keys_to_fetch.push(key);
+        }
+    }
+
+    let entries = db
+        .get_data_keys(&user_id, &keys_to_fetch)
+        .await
+        .or_internal("Failed to get data")?;
+
+    let found: HashSet<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+    let missing: Vec<String> = keys_to_fetch
+        .iter()
+        .filter(|k| !found.contains(k.as_str()))
+        .cloned()
+        .collect();
+
+    let (entries, corrupt) = integrity::verify_all(db, &metrics, &user_id, entries).await;
+    let checksum_status = if corrupt.is_empty() {
+        ChecksumStatus::Verified
+    } else {
+        ChecksumStatus::Mismatch
+    };
+    errors.extend(corrupt.into_iter().map(|key| BatchError {
+        key,
+        error: "Stored value is corrupt".into(),
+    }));
+
+    let entries = entries
+        .into_iter()
+        .map(|e| BatchGetEntry {
+            key: e.key,
+            value: e.value,
+            version: e.version,
+            checksum: e.checksum,
+            updated_at: e.updated_at,
+        })
+        .collect();
+
+    Ok((
+        checksum_status,
+        Json(BatchGetResponse {
+            entries,
+            missing,
+            errors,
+        }),
+    ))
+}
+
+/// Checks up to `MAX_BATCH_KEYS` cached copies at once, each named by its
+/// key and checksum. Keys whose copy is current come back `unchanged`, keys
+/// no longer stored `missing` and the rest `changed`, with the value inline
+/// when it is no larger than `inline_max_bytes`; larger ones are fetched
+/// with `GET /v2/data/{key}`. Only the changed values are read. Values that
+/// no longer match their checksum are withheld and reported in `errors`.
+#[utoipa::path(
+    post,
+    path = "/v2/data:check",
+    tag = "data",
+    security(("token" = [])),
+    request_body = CheckRequest,
+    responses(
+        (status = 200, description = "Whether each copy is current", body = CheckResponse,
+            headers(("X-Checksum-Status" = String,
+                description = "`verified`, or `mismatch` if any stored value read no longer matches its checksum"))),
+        (status = 400, description = "Too many keys", body = ErrorBody),
+        (status = 403, description = "The token lacks the read scope, or a key is outside its prefix", body = ErrorBody)
+    )
+)]
+pub async fn check_data(
+    CurrentTenant(tenant): CurrentTenant,
+    State(metrics): State<Arc<Metrics>>,
+    ReadingUser(user_id, scopes): ReadingUser,
+    Json(request): Json<CheckRequest>,
+) -> Result<(ChecksumStatus, Json<CheckResponse>), AppError> {
+    if request.entries.len() > MAX_BATCH_KEYS {
+        return Err(batch_too_large());
+    }
+    let db = &tenant.db;
+    let config = tenant.config.load();
+    let inline_max = request
+        .inline_max_bytes
+        .unwrap_or(DEFAULT_CHECK_INLINE_BYTES)
+        .min(MAX_CHECK_INLINE_BYTES);
+
+    let manifest = db
+        .get_data_manifest(&user_id)
+        .await
+        .or_internal("Failed to get data")?;
+    let manifest: HashMap<&str, &DataManifestEntry> = manifest
+        .iter()
+        .filter(|e| !e.deleted)
+        .map(|e| (e.key.as_str(), e))
+        .collect();
+
+    let mut errors = Vec::new();
+    let mut results = Vec::with_capacity(request.entries.len());
+    let mut positions = HashMap::with_capacity(request.entries.len());
+    let mut keys_to_fetch = Vec::new();
+
+    for entry in request.entries {
+        scopes.require_key(Scope::Read, &entry.key)?;
+        if let Err(e) = config.namespaces.check_key(&entry.key) {
+            errors.push(BatchError {
+                key: entry.key,
+                error: e.message(),
+            });
+            continue;
+        }
+        if positions.contains_key(&entry.key) {
+            errors.push(BatchError {
+                key: entry.key,
+                error: "Duplicate key in batch".into(),
+            });
+            continue;
+        }
+
+        positions.insert(entry.key.clone(), results.len());
+        let Some(meta) = manifest.get(entry.key.as_str()) else {
+            results.push(CheckResult::missing(entry.key));
+            continue;
+        };
+        let unchanged = entry
+            .checksum
+            .as_deref()
+            .is_some_and(|c| checksum::same(c, &meta.checksum));
+        let status = if unchanged {
+            CheckStatus::Unchanged
+        } else {
+            if meta.size_bytes as usize <= inline_max {
+                keys_to_fetch.push(entry.key.clone());
+            }
+            CheckStatus::Changed
+        };
+        results.push(CheckResult {
+            key: entry.key,
+            status,
+            version: Some(meta.version),
+            checksum: Some(meta.checksum.clone()),
+            size_bytes: Some(meta.size_bytes),
+            updated_at: Some(meta.updated_at),
+            value: None,
+        });
+    }
+
+    let entries = db
+        .get_data_keys(&user_id, &keys_to_fetch)
+        .await
+        .or_internal("Failed to get data")?;
+    let (entries, corrupt) = integrity::verify_all(db, &metrics, &user_id, entries).await;
+    let checksum_status = if corrupt.is_empty() {
+        ChecksumStatus::Verified
+    } else {
+        ChecksumStatus::Mismatch
+    };
+
+    // The values read may be newer than the manifest, and keys deleted since
+    // are not read at all.
+    let mut fetched: HashMap<String, _> = entries.into_iter().map(|e| (e.key.clone(), e)).collect();
+    for key in &keys_to_fetch {
+        let result = &mut results[positions[key]];
+        match fetched.remove(key) {
+            Some(entry) => {
+                result.version = Some(entry.version);
+                result.checksum = Some(entry.checksum);
+                result.size_bytes = Some(entry.size_bytes);
+                result.updated_at = Some(entry.updated_at);
+                result.value = Some(entry.value);
+            }
+            None if !corrupt.contains(key) => *result = CheckResult::missing(key.clone()),
+            None => {}
+        }
+    }
+    results.retain(|result| !corrupt.contains(&result.key));
+    errors.extend(corrupt.into_iter().map(|key| BatchError {
+        key,
+        error: "Stored value is corrupt".into(),
+    }));
+
+    Ok((checksum_status, Json(CheckResponse { results, errors })))
+}
+
+/// Writes up to `MAX_BATCH_KEYS` keys at once. Entries are checked one by one
+/// and rejected entries are reported in `errors` without failing the rest.
+#[utoipa::path(
+    post,
+    path = "/v2/data:batchPut",
+    tag = "data",
+    security(("token" = [])),
+    params(
+        ("X-Device-Id" = Option<String>, Header, description = "Record this device as the saved keys' last writer"),
+        ("X-Client-Name" = Option<String>, Header, description = "Name to list the device under")
+    ),
+    request_body = BatchPutRequest,
+    responses(
+        (status = 200, description = "The entries that were saved", body = BatchPutResponse),
+        (status = 400, description = "Too many entries, or invalid device headers", body = ErrorBody),
+        (status = 429, description = "The user's DAILY_WRITE_LIMIT cannot cover the entries", body = ErrorBody)
+    )
+)]
+pub async fn batch_put_data(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    AuthUser(user_id): AuthUser,
+    RequestDevice(device): RequestDevice,
+    Json(request): Json<BatchPutRequest>,
+) -> Result<(Option<WriteBudget>, Json<BatchPutResponse>), AppError> {
+    if request.entries.len() > MAX_BATCH_KEYS {
+        return Err(batch_too_large());
+    }
+    let db = &tenant.db;
+    let config = tenant.config.load();
+    let budget = WriteBudget::spend(&tenant, &user_id, request.entries.len()).await?;
+
+    let server_manifest = db
+        .get_data_manifest(&user_id)
+        .await
+        .or_internal("Database error")?;
+
+    let server_map: HashMap<&str, &DataManifestEntry> = server_manifest
+        .iter()
+        .map(|e| (e.key.as_str(), e))
+        .collect();
+
+    let max_size = db
+        .storage_quota(&user_id)
+        .await
+        .or_internal("Database error")?;
+    let mut running_size: i64 = server_manifest.iter().map(|e| e.size_bytes as i64).sum();
+    let live_keys = server_manifest.iter().filter(|e| !e.deleted).count();
+    let mut allowance = KeyAllowance::new(config.max_keys_per_user, live_keys as u64);
+
+    let mut errors = Vec::new();
+    let mut seen = HashSet::with_capacity(request.entries.len());
+    let mut valid_entries: Vec<DataUpload> = Vec::with_capacity(request.entries.len());
+    let mut keys_to_check: Vec<String> = Vec::with_capacity(request.entries.len());
+    let mut over_quota = false;
+    let mut mismatched = false;
+
+    for entry in request.entries {
+        if let Err(e) = config.namespaces.check_key(&entry.key) {
+            errors.push(BatchError {
+                key: entry.key,
+                error: e.message(),
+            });
+            continue;
+        }
+
+        if !seen.insert(entry.key.clone()) {
+            errors.push(BatchError {
+                key: entry.key,
+                error: "Duplicate key in batch".into(),
+            });
+            continue;
+        }
+
+        // Batch entries carry no TTL of their own, only the namespace's.
+        let ttl_secs = match config
+            .namespaces
+            .validate_write(&entry.key, entry.value.len())
+            .and_then(|()| config.namespaces.resolve_ttl(&entry.key, None))
+        {
+            Ok(ttl_secs) => ttl_secs,
+            Err(e) => {
+                errors.push(BatchError {
+                    key: entry.key,
+                    error: e.message(),
+                });
+                continue;
+            }
+        };
+
+        let checksum = match checksum::verify_upload(
+            entry.checksum.as_deref(),
+            &entry.value,
+            config.checksum_algorithm,
+        ) {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                mismatched |= e == ChecksumError::Mismatch;
+                errors.push(BatchError {
+                    key: entry.key,
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let existing_size = server_map
+            .get(entry.key.as_str())
+            .map(|e| e.size_bytes as i64)
+            .unwrap_or(0);
+
+        let new_running = running_size - existing_size + entry.value.len() as i64;
+        if new_running > max_size {
+            over_quota = true;
+            errors.push(BatchError {
+                key: entry.key,
+                error: "Total storage limit exceeded".into(),
+            });
+            continue;
+        }
+
+        let exists = server_map
+            .get(entry.key.as_str())
+            .is_some_and(|e| !e.deleted);
+        if !exists && let Err(e) = allowance.take() {
+            errors.push(BatchError {
+                key: entry.key,
+                error: e.message(),
+            });
+            continue;
+        }
+
+        running_size = new_running;
+        keys_to_check.push(entry.key.clone());
+        valid_entries.push(DataUpload {
+            key: entry.key,
+            value: entry.value,
+            checksum,
+            ttl_secs,
+        });
+    }
+
+    if mismatched {
+        abuse::report(&tenant, &user_id, Violation::ChecksumMismatch).await;
+    }
+    if over_quota {
+        abuse::report(&tenant, &user_id, Violation::QuotaExceeded).await;
+        events.publish(Event::QuotaExceeded {
+            user_id: user_id.clone(),
+        });
+    }
+
+    let mut saved = Vec::with_capacity(valid_entries.len());
+
+    if !valid_entries.is_empty() {
+        let checksums: HashMap<String, String> = valid_entries
+            .iter()
+            .map(|u| (u.key.clone(), u.checksum.clone()))
+            .collect();
+
+        match db.save_data_keys_batch(&user_id, valid_entries).await {
+            Ok(written) => {
+                for (key, version, updated_at) in written {
+                    events.publish(Event::DataWritten {
+                        user_id: user_id.clone(),
+                        key: key.clone(),
+                        version,
+                    });
+                    if let Some(checksum) = checksums.get(&key) {
+                        saved.push(BatchPutResult {
+                            key,
+                            version,
+                            checksum: checksum.clone(),
+                            updated_at,
+                        });
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to save batch: {}", e);
+                for key in keys_to_check {
+                    errors.push(BatchError {
+                        key,
+                        error: "Failed to save".into(),
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(device) = &device
+        && !saved.is_empty()
+    {
+        let keys: Vec<String> = saved.iter().map(|s| s.key.clone()).collect();
+        let now = chrono::Utc::now().timestamp_millis();
+        devices::record_write(db, &user_id, device, &keys, now).await;
+    }
+
+    Ok((budget, Json(BatchPutResponse { saved, errors })))
+}
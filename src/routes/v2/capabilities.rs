@@ -0,0 +1,193 @@
+//! What this instance supports, so clients can adapt to self-hosted servers
+//! configured differently from the public one.
+
+use axum::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use equicloud::checksum::ChecksumAlgorithm;
+use equicloud::constants::{
+    MAX_BATCH_KEYS, MAX_IDEMPOTENCY_KEY_LEN, MAX_MANIFEST_PAGE_SIZE, MAX_OPEN_UPLOADS,
+    MAX_SHARE_TTL_SECS, MAX_SYNC_PAGE_KEYS, SYNC_PAGE_BYTES,
+};
+use equicloud::content_encoding::REQUEST_ENCODINGS;
+use equicloud::error::ErrorBody;
+use equicloud::namespaces::DATASTORE_PREFIX;
+
+use super::encoding::CBOR_CONTENT_TYPE;
+use crate::middleware::tenant::CurrentTenant;
+use crate::routes::openapi;
+
+#[derive(Serialize, ToSchema)]
+pub struct Capabilities {
+    #[schema(example = "equicloud")]
+    service: &'static str,
+    /// Version of the server build.
+    version: &'static str,
+    #[schema(example = json!(["v1", "v2", "v3"]))]
+    api_versions: Vec<&'static str>,
+    features: Features,
+    limits: Limits,
+    encodings: Encodings,
+    namespaces: Vec<NamespaceInfo>,
+    /// Paths served under each API version.
+    endpoints: Endpoints,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Features {
+    /// Keys under `dataStore/` can be read and written.
+    datastore: bool,
+    /// Large responses are compressed per `Accept-Encoding`.
+    response_compression: bool,
+    /// Sign-in through `/v1/oauth/authorize` is configured.
+    oauth: bool,
+    share_links: bool,
+    multipart_uploads: bool,
+    idempotency_keys: bool,
+    /// Keys can be moved with `POST /v2/data/{key}/rename`.
+    key_rename: bool,
+    /// `/v3/sync` hands out missing values page by page.
+    paged_sync: bool,
+    /// Cached copies can be checked in one request with `POST /v2/data:check`.
+    data_check: bool,
+    /// Push notifications over a WebSocket; not offered by this server.
+    websockets: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Limits {
+    /// Storage per user, settings and data combined.
+    max_backup_size_bytes: usize,
+    /// Largest value outside a namespace with its own limit.
+    max_key_size_bytes: usize,
+    /// Largest value any namespace accepts.
+    max_value_size_bytes: usize,
+    upload_part_size: usize,
+    max_open_uploads: usize,
+    max_batch_keys: usize,
+    max_manifest_page_size: usize,
+    /// Writes per user per day; 0 is unlimited.
+    daily_write_limit: u64,
+    /// Live data keys per user; 0 is unlimited.
+    max_keys_per_user: u64,
+    max_data_ttl_secs: u64,
+    max_share_ttl_secs: u64,
+    max_idempotency_key_len: usize,
+    /// Largest page of `/v3/sync/downloads`, unless one value is larger.
+    sync_page_bytes: usize,
+    sync_page_keys: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Encodings {
+    /// Bodies accepted and served by batch and sync.
+    content_types: Vec<&'static str>,
+    /// `Content-Encoding`s responses may be compressed with; empty when
+    /// compression is off.
+    response_compression: Vec<&'static str>,
+    /// Responses smaller than this are never compressed.
+    response_compression_min_size: u16,
+    /// `Content-Encoding`s uploads to `/v1/settings` and `/v2/data` may be
+    /// compressed with.
+    request_compression: Vec<&'static str>,
+    /// Algorithms a checksum may be sent with, as `<algorithm>:<hex>`.
+    #[schema(example = json!(["sha256", "xxh3"]))]
+    checksum_algorithms: Vec<&'static str>,
+    /// Algorithm of the checksum stored when an upload comes without one.
+    default_checksum_algorithm: &'static str,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NamespaceInfo {
+    prefix: String,
+    enabled: bool,
+    max_size_bytes: Option<usize>,
+    default_ttl_secs: Option<u64>,
+    max_ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Endpoints {
+    v1: Vec<&'static str>,
+    v2: Vec<&'static str>,
+    v3: Vec<&'static str>,
+}
+
+/// Describes the features, limits and encodings of this instance as
+/// currently configured. Needs no token.
+#[utoipa::path(
+    get,
+    path = "/v2/capabilities",
+    tag = "account",
+    responses(
+        (status = 200, description = "What this instance supports", body = Capabilities),
+        (status = 404, description = "Unknown tenant", body = ErrorBody)
+    )
+)]
+pub async fn get_capabilities(CurrentTenant(tenant): CurrentTenant) -> Json<Capabilities> {
+    let config = tenant.config.load();
+    let namespaces = &config.namespaces;
+
+    Json(Capabilities {
+        service: "equicloud",
+        version: env!("CARGO_PKG_VERSION"),
+        api_versions: vec!["v1", "v2", "v3"],
+        features: Features {
+            datastore: namespaces.is_enabled(DATASTORE_PREFIX),
+            response_compression: config.response_compression_enabled,
+            oauth: config.oauth_configured(),
+            share_links: true,
+            multipart_uploads: true,
+            idempotency_keys: true,
+            key_rename: true,
+            paged_sync: true,
+            data_check: true,
+            websockets: false,
+        },
+        limits: Limits {
+            max_backup_size_bytes: config.max_backup_size_bytes,
+            max_key_size_bytes: config.max_key_size_bytes,
+            max_value_size_bytes: namespaces.largest_max_size(),
+            upload_part_size: config.upload_part_size,
+            max_open_uploads: MAX_OPEN_UPLOADS,
+            max_batch_keys: MAX_BATCH_KEYS,
+            max_manifest_page_size: MAX_MANIFEST_PAGE_SIZE,
+            daily_write_limit: config.daily_write_limit,
+            max_keys_per_user: config.max_keys_per_user,
+            max_data_ttl_secs: config.max_data_ttl.as_secs(),
+            max_share_ttl_secs: MAX_SHARE_TTL_SECS,
+            max_idempotency_key_len: MAX_IDEMPOTENCY_KEY_LEN,
+            sync_page_bytes: SYNC_PAGE_BYTES,
+            sync_page_keys: MAX_SYNC_PAGE_KEYS,
+        },
+        encodings: Encodings {
+            content_types: vec!["application/json", CBOR_CONTENT_TYPE],
+            response_compression: if config.response_compression_enabled {
+                vec!["gzip", "br", "zstd"]
+            } else {
+                Vec::new()
+            },
+            response_compression_min_size: config.response_compression_min_size,
+            request_compression: REQUEST_ENCODINGS.to_vec(),
+            checksum_algorithms: ChecksumAlgorithm::ALL.iter().map(|a| a.name()).collect(),
+            default_checksum_algorithm: config.checksum_algorithm.name(),
+        },
+        namespaces: namespaces
+            .policies()
+            .iter()
+            .map(|p| NamespaceInfo {
+                prefix: p.prefix.clone(),
+                enabled: p.enabled,
+                max_size_bytes: p.max_size_bytes,
+                default_ttl_secs: p.default_ttl_secs,
+                max_ttl_secs: p.max_ttl_secs,
+            })
+            .collect(),
+        endpoints: Endpoints {
+            v1: openapi::endpoints("/v1"),
+            v2: openapi::endpoints("/v2"),
+            v3: openapi::endpoints("/v3"),
+        },
+    })
+}
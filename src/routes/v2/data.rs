@@ -1,180 +1,680 @@
 use axum::{
-    Extension, Json,
+    Json,
     body::{Body, Bytes},
-    extract::Path,
+    extract::{Path, State},
     http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use tracing::error;
+use serde::Serialize;
+use std::slice;
+use std::sync::Arc;
+use utoipa::ToSchema;
 
-use equicloud::utils::CONFIG;
-use equicloud::{DatabaseService, compute_checksum, validate_key};
+use equicloud::abuse::{self, Violation};
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::checksum::{self, ChecksumError};
+use equicloud::content_encoding;
+use equicloud::devices;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::etag::{self, ETag};
+use equicloud::integrity;
+use equicloud::key_limit;
+use equicloud::scopes::Scope;
+use equicloud::validation::{OCTET_STREAM, require_content_type};
+use equicloud::write_budget::WriteBudget;
+use equicloud::{
+    ByteRange, ConditionalWrite, DataManifestEntry, DataRead, Datastore, Event, EventBus, Metrics,
+    RenameOutcome, Storage, Tenant, parse_range,
+};
+
+use super::dto::RenameRequest;
+use crate::middleware::audit::AuditContext;
+use crate::middleware::auth::ScopedUser;
+use crate::middleware::device::RequestDevice;
+use crate::middleware::tenant::CurrentTenant;
+use crate::routes::openapi::Binary;
+
+#[derive(Serialize, ToSchema)]
+pub struct DataWritten {
+    pub(super) version: i64,
+    pub(super) checksum: String,
+    pub(super) updated_at: i64,
+    /// When the value expires, if it was written with a TTL.
+    pub(super) expires_at: Option<i64>,
+}
 
+#[utoipa::path(
+    get,
+    path = "/v2/data/{key}",
+    tag = "data",
+    security(("token" = [])),
+    params(
+        ("key" = String, Path, description = "Data key; may contain slashes"),
+        ("If-None-Match" = Option<String>, Header, description = "ETags of cached copies, or `*`"),
+        ("If-Modified-Since" = Option<String>, Header, description = "When the cached copy was written; ignored with If-None-Match"),
+        ("Range" = Option<String>, Header, description = "A single byte range"),
+        ("If-Range" = Option<String>, Header, description = "Only honour Range for this ETag")
+    ),
+    responses(
+        (status = 200, description = "The stored value", body = Binary,
+            content_type = "application/octet-stream",
+            headers(
+                ("ETag" = String, description = "Checksum of the value, quoted"),
+                ("Last-Modified" = String, description = "When the value was written, as an HTTP date"),
+                ("X-Version" = i64, description = "Version of the value"),
+                ("X-Checksum-Status" = String,
+                    description = "`verified`, or `mismatch` if the stored value no longer matches its checksum. Absent when a large value is streamed; it is checked as it is sent and cut off if it does not match")
+            )),
+        (status = 206, description = "Part of the stored value", body = Binary,
+            content_type = "application/octet-stream"),
+        (status = 304, description = "The cached copy is current"),
+        (status = 404, description = "The key was never stored", body = ErrorBody),
+        (status = 410, description = "The key was deleted; `deleted_at` says when", body = ErrorBody),
+        (status = 416, description = "The range lies outside the value")
+    )
+)]
 pub async fn get_data(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
+    CurrentTenant(tenant): CurrentTenant,
+    State(metrics): State<Arc<Metrics>>,
+    ScopedUser(user_id, scopes): ScopedUser,
     Path(key): Path<String>,
     headers: HeaderMap,
-) -> impl IntoResponse {
-    if let Err(e) = validate_key(&key) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({"error": e.message()})),
-        )
-            .into_response();
+) -> Result<Response, AppError> {
+    tenant.config.load().namespaces.check_key(&key)?;
+    scopes.require_key(Scope::Read, &key)?;
+    let db = tenant.db.clone();
+
+    let Some(read) = db
+        .open_data_key(&user_id, &key)
+        .await
+        .or_internal("Failed to get data")?
+    else {
+        return Err(missing_key(&db, &user_id, &key).await);
+    };
+
+    let etag = ETag::strong(read.entry().checksum.clone());
+    let updated_at = read.entry().updated_at;
+    let mut response_headers = HeaderMap::new();
+    if let Some(v) = etag.to_header() {
+        response_headers.insert("ETag", v);
+    }
+    if let Some(v) = etag::last_modified(updated_at) {
+        response_headers.insert("Last-Modified", v);
     }
 
-    if !CONFIG.datastore_enabled && key.starts_with("dataStore/") {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(serde_json::json!({"error": "DataStore sync is disabled"})),
-        )
-            .into_response();
+    if etag::fresh(&headers, &etag, updated_at) {
+        return Ok((StatusCode::NOT_MODIFIED, response_headers, Body::empty()).into_response());
+    }
+
+    if let Ok(v) = "application/octet-stream".parse() {
+        response_headers.insert("Content-Type", v);
     }
+    if let Ok(v) = read.entry().version.to_string().parse() {
+        response_headers.insert("X-Version", v);
+    }
+    if let Ok(v) = "bytes".parse() {
+        response_headers.insert("Accept-Ranges", v);
+    }
+
+    // Chunks are fetched as the client takes them, so a large download
+    // holds one chunk in memory rather than the whole value. Ranges are
+    // rare enough to be served from the whole value.
+    let entry = match read {
+        DataRead::Streamed { entry, chunks } if !headers.contains_key("range") => {
+            if let Ok(v) = entry.size_bytes.to_string().parse() {
+                response_headers.insert("Content-Length", v);
+            }
+            let chunks = integrity::verify_stream(db, metrics, user_id, entry, chunks);
+            return Ok(
+                (StatusCode::OK, response_headers, Body::from_stream(chunks)).into_response(),
+            );
+        }
+        read => read.into_entry().await.or_internal("Failed to get data")?,
+    };
 
-    let entry = match db.get_data_key(&user_id, &key).await {
-        Ok(Some(e)) => e,
-        Ok(None) => {
-            return (StatusCode::NOT_FOUND, HeaderMap::new(), Body::empty()).into_response();
+    // A corrupt value is still served, flagged, so the client can decide
+    // whether a damaged copy beats none.
+    let checksum_status = integrity::verify(&db, &metrics, &user_id, &entry).await;
+
+    let len = entry.value.len();
+    // A stale If-Range means the client's partial copy is of another version,
+    // so it gets the whole value instead. If-Range requires a strong match.
+    let range = match headers.get("range").and_then(|h| h.to_str().ok()) {
+        Some(range)
+            if headers.get("if-range").is_none_or(|v| {
+                v.to_str()
+                    .ok()
+                    .and_then(ETag::parse)
+                    .is_some_and(|t| t.strong_eq(&etag))
+            }) =>
+        {
+            parse_range(range, len)
         }
-        Err(e) => {
-            error!("Failed to get data key: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                HeaderMap::new(),
-                Body::empty(),
-            )
-                .into_response();
+        _ => ByteRange::Full,
+    };
+
+    let value = Bytes::from(entry.value);
+    let (status, body) = match range {
+        ByteRange::Full => (StatusCode::OK, value),
+        ByteRange::Partial(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+            if let Ok(v) = content_range.parse() {
+                response_headers.insert("Content-Range", v);
+            }
+            (StatusCode::PARTIAL_CONTENT, value.slice(range))
+        }
+        ByteRange::Unsatisfiable => {
+            if let Ok(v) = format!("bytes */{}", len).parse() {
+                response_headers.insert("Content-Range", v);
+            }
+            (StatusCode::RANGE_NOT_SATISFIABLE, Bytes::new())
         }
     };
 
-    if let Some(if_none_match) = headers.get("if-none-match")
-        && if_none_match.to_str().unwrap_or("") == entry.checksum
-    {
-        return (StatusCode::NOT_MODIFIED, HeaderMap::new(), Body::empty()).into_response();
+    Ok((status, checksum_status, response_headers, Body::from(body)).into_response())
+}
+
+/// Describes a value from its metadata alone, without reading it.
+#[utoipa::path(
+    head,
+    path = "/v2/data/{key}",
+    tag = "data",
+    security(("token" = [])),
+    params(
+        ("key" = String, Path, description = "Data key; may contain slashes"),
+        ("If-None-Match" = Option<String>, Header, description = "ETags of cached copies, or `*`"),
+        ("If-Modified-Since" = Option<String>, Header, description = "When the cached copy was written; ignored with If-None-Match")
+    ),
+    responses(
+        (status = 200, description = "The key exists",
+            headers(
+                ("ETag" = String, description = "Checksum of the value, quoted"),
+                ("Content-Length" = i32, description = "Size of the value"),
+                ("Last-Modified" = String, description = "When the value was written, as an HTTP date"),
+                ("X-Version" = i64, description = "Version of the value"),
+                ("X-Checksum" = String, description = "Checksum of the value as it was uploaded")
+            )),
+        (status = 304, description = "The cached copy is current"),
+        (status = 404, description = "The key was never stored"),
+        (status = 410, description = "The key was deleted")
+    )
+)]
+pub async fn head_data(
+    CurrentTenant(tenant): CurrentTenant,
+    ScopedUser(user_id, scopes): ScopedUser,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    tenant.config.load().namespaces.check_key(&key)?;
+    scopes.require_key(Scope::Read, &key)?;
+    let db = &tenant.db;
+
+    let meta = db
+        .get_data_meta(&user_id, &key)
+        .await
+        .or_internal("Failed to get data")?
+        .ok_or(AppError::NotFound)?;
+    if meta.deleted {
+        return Err(deleted(&meta));
     }
 
+    let etag = ETag::strong(meta.checksum.clone());
     let mut response_headers = HeaderMap::new();
-    if let Ok(v) = "application/octet-stream".parse() {
-        response_headers.insert("Content-Type", v);
-    }
-    if let Ok(v) = entry.checksum.parse() {
+    if let Some(v) = etag.to_header() {
         response_headers.insert("ETag", v);
     }
-    if let Ok(v) = entry.version.to_string().parse() {
-        response_headers.insert("X-Version", v);
+    if let Some(v) = etag::last_modified(meta.updated_at) {
+        response_headers.insert("Last-Modified", v);
+    }
+    if etag::fresh(&headers, &etag, meta.updated_at) {
+        return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
     }
 
-    (StatusCode::OK, response_headers, Body::from(entry.value)).into_response()
+    response_headers.insert("Content-Length", meta.size_bytes.into());
+    response_headers.insert("X-Version", meta.version.into());
+    if let Ok(v) = meta.checksum.parse() {
+        response_headers.insert("X-Checksum", v);
+    }
+    if let Ok(v) = "bytes".parse() {
+        response_headers.insert("Accept-Ranges", v);
+    }
+    Ok((StatusCode::OK, response_headers).into_response())
 }
 
-pub async fn put_data(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
-    Path(key): Path<String>,
-    headers: HeaderMap,
-    body: Bytes,
-) -> impl IntoResponse {
-    if let Err(e) = validate_key(&key) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({"error": e.message()})),
-        )
-            .into_response();
+/// Why a key could not be read: `Deleted` while its tombstone is kept,
+/// otherwise `NotFound`.
+async fn missing_key(db: &Storage, user_id: &str, key: &str) -> AppError {
+    match db
+        .get_data_meta(user_id, key)
+        .await
+        .or_internal("Failed to get data")
+    {
+        Ok(Some(meta)) if meta.deleted => deleted(&meta),
+        Ok(_) => AppError::NotFound,
+        Err(e) => e,
+    }
+}
+
+fn deleted(tombstone: &DataManifestEntry) -> AppError {
+    AppError::Deleted {
+        deleted_at: tombstone.deleted_at.unwrap_or(tombstone.updated_at),
     }
+}
 
-    if !CONFIG.datastore_enabled && key.starts_with("dataStore/") {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(serde_json::json!({"error": "DataStore sync is disabled"})),
-        )
-            .into_response();
+/// Checks an uploaded value against the checksum the client sent and returns
+/// the checksum to store with it. A mismatch counts as a violation.
+pub(crate) async fn verify_checksum(
+    tenant: &Tenant,
+    user_id: &str,
+    claimed: Option<&str>,
+    value: &[u8],
+) -> Result<String, ChecksumError> {
+    let default = tenant.config.load().checksum_algorithm;
+    report_mismatch(
+        tenant,
+        user_id,
+        checksum::verify_upload(claimed, value, default),
+    )
+    .await
+}
+
+async fn report_mismatch(
+    tenant: &Tenant,
+    user_id: &str,
+    result: Result<String, ChecksumError>,
+) -> Result<String, ChecksumError> {
+    if result == Err(ChecksumError::Mismatch) {
+        abuse::report(tenant, user_id, Violation::ChecksumMismatch).await;
     }
+    result
+}
 
-    if headers.get("content-type").and_then(|h| h.to_str().ok()) != Some("application/octet-stream")
+fn checksum_error(e: ChecksumError) -> AppError {
+    match e {
+        ChecksumError::Mismatch => AppError::ChecksumMismatch,
+        e => e.into(),
+    }
+}
+
+/// Headers that make a `PUT` conditional on the stored value.
+const PRECONDITION_HEADERS: [&str; 3] = ["if-match", "x-if-version", "if-unmodified-since"];
+
+/// Whether the stored value `current` (`None` if the key does not exist)
+/// satisfies every precondition header of the request.
+fn preconditions_hold(
+    headers: &HeaderMap,
+    current: Option<&DataManifestEntry>,
+) -> Result<bool, AppError> {
+    let tag = current.map(|meta| ETag::strong(meta.checksum.clone()));
+    if !etag::precondition_holds(headers, tag.as_ref()) {
+        return Ok(false);
+    }
+
+    if let Some(value) = headers.get("x-if-version") {
+        let expected = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<i64>().ok())
+            .filter(|v| *v >= 0)
+            .ok_or_else(|| AppError::BadRequest("Invalid X-If-Version header".into()))?;
+        if current.map_or(0, |meta| meta.version) != expected {
+            return Ok(false);
+        }
+    }
+
+    // Unparsable dates are ignored, as HTTP asks; HTTP dates have whole
+    // seconds.
+    let since = headers
+        .get("if-unmodified-since")
+        .and_then(|h| h.to_str().ok())
+        .and_then(etag::parse_http_date);
+    if let (Some(since), Some(meta)) = (since, current)
+        && meta.updated_at / 1000 > since
     {
-        return (
-            StatusCode::UNSUPPORTED_MEDIA_TYPE,
-            Json(serde_json::json!({"error": "Content type must be application/octet-stream"})),
-        )
-            .into_response();
+        return Ok(false);
     }
+    Ok(true)
+}
 
-    let max_size = if key.starts_with("dataStore/") {
-        CONFIG.max_datastore_key_size_bytes
-    } else {
-        CONFIG.max_key_size_bytes
+fn version_conflict(current: Option<&DataManifestEntry>) -> AppError {
+    AppError::VersionConflict {
+        version: current.map(|meta| meta.version),
+        checksum: current.map(|meta| meta.checksum.clone()),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/v2/data/{key}",
+    tag = "data",
+    security(("token" = [])),
+    params(
+        ("key" = String, Path, description = "Data key; may contain slashes"),
+        ("X-TTL-Seconds" = Option<u64>, Header, description = "Expire the value after this many seconds"),
+        ("If-Match" = Option<String>, Header, description = "Only overwrite these ETags, or `*` for any"),
+        ("X-If-Version" = Option<i64>, Header, description = "Only overwrite this version; 0 only writes a key that does not exist"),
+        ("If-Unmodified-Since" = Option<String>, Header, description = "Only overwrite a value last written at or before this HTTP date"),
+        ("X-Checksum" = Option<String>, Header, description = "Checksum of the body, after any Content-Encoding is undone, as `sha256:<hex>` or `xxh3:<hex>`; verified before writing and stored with the value"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key"),
+        ("Content-Encoding" = Option<String>, Header, description = "`gzip`, `br` or `zstd` if the body is compressed; the value is stored decompressed"),
+        ("X-Device-Id" = Option<String>, Header, description = "Record this device as the key's last writer"),
+        ("X-Client-Name" = Option<String>, Header, description = "Name to list the device under")
+    ),
+    request_body(content = Binary, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "The value was saved", body = DataWritten),
+        (status = 409, description = "The key no longer matches If-Match, X-If-Version or If-Unmodified-Since, with its current version and checksum; or the Idempotency-Key was used for a different request or is still in use", body = ErrorBody),
+        (status = 400, description = "Invalid key, TTL, checksum, X-If-Version or device headers, or a body that does not decode with its Content-Encoding", body = ErrorBody),
+        (status = 413, description = "The value or the user's total storage is too large, or the key would exceed MAX_KEYS_PER_USER", body = ErrorBody),
+        (status = 415, description = "Body is not application/octet-stream, or its Content-Encoding is not supported", body = ErrorBody),
+        (status = 422, description = "The body does not match X-Checksum; send it again", body = ErrorBody),
+        (status = 429, description = "The user's DAILY_WRITE_LIMIT is used up", body = ErrorBody)
+    )
+)]
+pub async fn put_data(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    ScopedUser(user_id, scopes): ScopedUser,
+    RequestDevice(device): RequestDevice,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    let config = tenant.config.load();
+    config.namespaces.check_key(&key)?;
+    scopes.require_key(Scope::Write, &key)?;
+    let db = &tenant.db;
+
+    let ttl_secs = match headers.get("x-ttl-seconds") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Some)
+            .ok_or_else(|| AppError::BadRequest("Invalid X-TTL-Seconds header".into()))?,
+        None => None,
     };
+    let ttl_secs = config.namespaces.resolve_ttl(&key, ttl_secs)?;
 
-    if body.len() > max_size {
-        let limit_mb = max_size / 1024 / 1024;
-        return (
-            StatusCode::PAYLOAD_TOO_LARGE,
-            Json(serde_json::json!({"error": format!("Value exceeds {}MB limit", limit_mb)})),
-        )
-            .into_response();
-    }
+    require_content_type(&headers, OCTET_STREAM)?;
+    let claimed = match headers.get("x-checksum") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| AppError::BadRequest("Invalid X-Checksum header".into()))?,
+        ),
+        None => None,
+    };
+    // Hashed while it streams in, with the algorithm of the checksum it will
+    // be checked against.
+    let algorithm =
+        checksum::upload_algorithm(claimed, config.checksum_algorithm).map_err(checksum_error)?;
+    let max_size = config.namespaces.max_size(&key);
+    let body = content_encoding::read_body(&headers, body, algorithm, max_size).await?;
 
-    let checksum = compute_checksum(&body);
+    config.namespaces.validate_write(&key, body.value.len())?;
 
-    match db
-        .save_data_key_with_quota_check(
-            &user_id,
-            &key,
-            body.into(),
-            &checksum,
-            CONFIG.max_backup_size_bytes as i64,
-        )
-        .await
+    // The version the write is conditional on, read with the stored value
+    // the preconditions were checked against.
+    let expected = if PRECONDITION_HEADERS
+        .iter()
+        .any(|h| headers.contains_key(*h))
     {
-        Ok(Some((version, updated_at))) => Json(serde_json::json!({
-            "version": version,
-            "checksum": checksum,
-            "updated_at": updated_at
-        }))
-        .into_response(),
-        Ok(None) => (
-            StatusCode::PAYLOAD_TOO_LARGE,
-            Json(serde_json::json!({"error": "Total storage limit exceeded"})),
-        )
-            .into_response(),
-        Err(e) => {
-            error!("Failed to save data key: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Failed to save data"})),
-            )
-                .into_response()
+        let stored = db
+            .get_data_meta(&user_id, &key)
+            .await
+            .or_internal("Failed to get data")?;
+        let live = stored.as_ref().filter(|meta| !meta.deleted);
+        if !preconditions_hold(&headers, live)? {
+            return Err(version_conflict(live));
         }
+        Some(stored.map(|meta| meta.version))
+    } else {
+        None
+    };
+
+    let verified = checksum::verify_streamed(claimed, body.checksum);
+    let checksum = report_mismatch(&tenant, &user_id, verified)
+        .await
+        .map_err(checksum_error)?;
+    key_limit::check_new_key(&tenant, &user_id, &key).await?;
+
+    let budget = WriteBudget::spend(&tenant, &user_id, 1).await?;
+
+    let written = match expected {
+        Some(expected) => {
+            db.save_data_key_if_version(&user_id, &key, body.value, &checksum, ttl_secs, expected)
+                .await
+        }
+        None => db
+            .save_data_key_with_quota_check(&user_id, &key, body.value, &checksum, ttl_secs)
+            .await
+            .map(|saved| match saved {
+                Some((version, updated_at)) => ConditionalWrite::Saved {
+                    version,
+                    updated_at,
+                },
+                None => ConditionalWrite::QuotaExceeded,
+            }),
+    }
+    .or_internal("Failed to save data")?;
+    let (version, updated_at) = match written {
+        ConditionalWrite::Saved {
+            version,
+            updated_at,
+        } => (version, updated_at),
+        ConditionalWrite::QuotaExceeded => {
+            abuse::report(&tenant, &user_id, Violation::QuotaExceeded).await;
+            events.publish(Event::QuotaExceeded { user_id });
+            return Err(AppError::QuotaExceeded);
+        }
+        ConditionalWrite::Changed => {
+            let stored = db
+                .get_data_meta(&user_id, &key)
+                .await
+                .or_internal("Failed to get data")?;
+            return Err(version_conflict(
+                stored.as_ref().filter(|meta| !meta.deleted),
+            ));
+        }
+    };
+
+    if let Some(device) = &device {
+        devices::record_write(db, &user_id, device, slice::from_ref(&key), updated_at).await;
     }
+    events.publish(Event::DataWritten {
+        user_id,
+        key,
+        version,
+    });
+
+    let expires_at = ttl_secs.map(|ttl| updated_at + ttl as i64 * 1000);
+
+    Ok((
+        budget,
+        Json(DataWritten {
+            version,
+            checksum,
+            updated_at,
+            expires_at,
+        }),
+    )
+        .into_response())
 }
 
+/// Deletes a key, leaving a tombstone so other devices sync the deletion.
+#[utoipa::path(
+    delete,
+    path = "/v2/data/{key}",
+    tag = "data",
+    security(("token" = [])),
+    params(
+        ("key" = String, Path, description = "Data key; may contain slashes"),
+        ("X-Device-Id" = Option<String>, Header, description = "Record this device as the key's last writer"),
+        ("X-Client-Name" = Option<String>, Header, description = "Name to list the device under")
+    ),
+    responses(
+        (status = 204, description = "The key was deleted or did not exist"),
+        (status = 400, description = "Invalid key or device headers", body = ErrorBody),
+        (status = 429, description = "The user's DAILY_WRITE_LIMIT is used up", body = ErrorBody)
+    )
+)]
 pub async fn delete_data(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    ScopedUser(user_id, scopes): ScopedUser,
+    RequestDevice(device): RequestDevice,
     Path(key): Path<String>,
-) -> impl IntoResponse {
-    if let Err(e) = validate_key(&key) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({"error": e.message()})),
+    audit: AuditContext,
+) -> Result<(Option<WriteBudget>, StatusCode), AppError> {
+    tenant.config.load().namespaces.check_key(&key)?;
+    scopes.require_key(Scope::Delete, &key)?;
+    let db = &tenant.db;
+    let budget = WriteBudget::spend(&tenant, &user_id, 1).await?;
+
+    let result = db
+        .delete_data_key(&user_id, &key)
+        .await
+        .or_internal("Failed to delete data");
+    audit
+        .record(
+            db,
+            AuditActor::User,
+            Some(&user_id),
+            AuditAction::DeleteDataKey,
+            Some(key.clone()),
+            result.is_ok(),
         )
-            .into_response();
+        .await;
+    let deleted = result?;
+
+    if let Some(version) = deleted {
+        if let Some(device) = &device {
+            let now = chrono::Utc::now().timestamp_millis();
+            devices::record_write(db, &user_id, device, slice::from_ref(&key), now).await;
+        }
+        events.publish(Event::DataDeleted {
+            user_id,
+            key,
+            version,
+        });
     }
 
-    if !CONFIG.datastore_enabled && key.starts_with("dataStore/") {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(serde_json::json!({"error": "DataStore sync is disabled"})),
-        )
-            .into_response();
+    Ok((budget, StatusCode::NO_CONTENT))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DataRenamed {
+    key: String,
+    /// Version of the value under its new name: its old version, or one past
+    /// whatever the new name held before.
+    version: i64,
+    updated_at: i64,
+    /// Version of the tombstone left under the old name.
+    tombstone_version: i64,
+}
+
+/// Moves a key's value, version and checksum to a new name and leaves a
+/// tombstone under the old one, so other devices sync both sides.
+#[utoipa::path(
+    post,
+    path = "/v2/data/{key}/rename",
+    tag = "data",
+    security(("token" = [])),
+    params(("key" = String, Path, description = "Data key; may contain slashes")),
+    request_body = RenameRequest,
+    responses(
+        (status = 200, description = "The key was renamed", body = DataRenamed),
+        (status = 400, description = "Invalid key, or the new name is the old one", body = ErrorBody),
+        (status = 404, description = "The key does not exist", body = ErrorBody),
+        (status = 409, description = "The new name holds a newer value, or either key changed during the rename", body = ErrorBody),
+        (status = 413, description = "The value is too large for the new name", body = ErrorBody),
+        (status = 429, description = "The user's DAILY_WRITE_LIMIT is used up", body = ErrorBody)
+    )
+)]
+pub async fn rename_data(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    ScopedUser(user_id, scopes): ScopedUser,
+    Path(path): Path<String>,
+    audit: AuditContext,
+    Json(request): Json<RenameRequest>,
+) -> Result<(Option<WriteBudget>, Json<DataRenamed>), AppError> {
+    // `/v2/data/{*key}` takes the whole path, so the action is its suffix.
+    let from = path.strip_suffix("/rename").ok_or(AppError::NotFound)?;
+    let config = tenant.config.load();
+    config.namespaces.check_key(from)?;
+    let to = request.to;
+    if to == from {
+        return Err(AppError::BadRequest(
+            "The new name is the same as the old one".into(),
+        ));
     }
+    scopes.require_key(Scope::Delete, from)?;
+    scopes.require_key(Scope::Write, &to)?;
+    let db = &tenant.db;
+
+    let source = db
+        .get_data_meta(&user_id, from)
+        .await
+        .or_internal("Failed to get data")?
+        .filter(|meta| !meta.deleted)
+        .ok_or(AppError::NotFound)?;
+    config
+        .namespaces
+        .validate_write(&to, source.size_bytes.max(0) as usize)?;
+
+    // Both the value and the tombstone are writes.
+    let budget = WriteBudget::spend(&tenant, &user_id, 2).await?;
+
+    let result = db
+        .rename_data_key(&user_id, from, &to)
+        .await
+        .or_internal("Failed to rename data");
+    audit
+        .record(
+            db,
+            AuditActor::User,
+            Some(&user_id),
+            AuditAction::RenameDataKey,
+            Some(format!("{} -> {}", from, to)),
+            matches!(result, Ok(RenameOutcome::Renamed { .. })),
+        )
+        .await;
 
-    match db.delete_data_key(&user_id, &key).await {
-        Ok(_) => StatusCode::NO_CONTENT.into_response(),
-        Err(e) => {
-            error!("Failed to delete data key: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    match result? {
+        RenameOutcome::Renamed {
+            version,
+            updated_at,
+            tombstone_version,
+        } => {
+            events.publish(Event::DataDeleted {
+                user_id: user_id.clone(),
+                key: from.to_string(),
+                version: tombstone_version,
+            });
+            events.publish(Event::DataWritten {
+                user_id,
+                key: to.clone(),
+                version,
+            });
+            Ok((
+                budget,
+                Json(DataRenamed {
+                    key: to,
+                    version,
+                    updated_at,
+                    tombstone_version,
+                }),
+            ))
         }
+        RenameOutcome::NotFound => Err(AppError::NotFound),
+        RenameOutcome::TargetNewer(target) => Err(AppError::Conflict(format!(
+            "{} holds a newer value (version {})",
+            target.key, target.version
+        ))),
+        RenameOutcome::Changed => Err(AppError::Conflict(
+            "The key changed during the rename, try again".into(),
+        )),
     }
 }
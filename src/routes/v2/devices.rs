@@ -0,0 +1,42 @@
+use axum::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use equicloud::Datastore;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+
+use super::dto::{Device, Versioned};
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::TenantDb;
+
+#[derive(Serialize, ToSchema)]
+pub struct DevicesResponse {
+    /// Most recently seen first.
+    devices: Vec<Device>,
+}
+
+/// The devices that have written or synced the user's data with
+/// `X-Device-Id`, and when each was last seen.
+#[utoipa::path(
+    get,
+    path = "/v2/devices",
+    tag = "account",
+    security(("token" = [])),
+    responses(
+        (status = 200, description = "The user's devices", body = Versioned<DevicesResponse>),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody)
+    )
+)]
+pub async fn list_devices(
+    TenantDb(db): TenantDb,
+    AuthUser(user_id): AuthUser,
+) -> Result<Json<Versioned<DevicesResponse>>, AppError> {
+    let devices = db
+        .list_devices(&user_id)
+        .await
+        .or_internal("Failed to list devices")?
+        .into_iter()
+        .map(Device::from)
+        .collect();
+    Ok(Json(Versioned::new(DevicesResponse { devices })))
+}
@@ -0,0 +1,241 @@
+//! The types `/v2` reads and writes on the wire.
+//!
+//! Requests refuse fields they do not know, so a misspelled option fails
+//! with 422 instead of being silently ignored. Responses carry their own
+//! copies of storage types, converted with `From`, so a column added to the
+//! database does not show up in the API until it is added here. Listings
+//! and syncs are sent in a [`Versioned`] envelope that names the version of
+//! these types.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use equicloud::delta::Signature;
+
+/// Raised when a response type changes in a way older clients would
+/// misread.
+pub const API_VERSION: u32 = 2;
+
+/// A response body with `api_version` alongside its own fields.
+#[derive(Serialize, ToSchema)]
+pub struct Versioned<T> {
+    api_version: u32,
+    #[serde(flatten)]
+    body: T,
+}
+
+impl<T> Versioned<T> {
+    pub fn new(body: T) -> Self {
+        Self {
+            api_version: API_VERSION,
+            body,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BatchGetRequest {
+    pub(crate) keys: Vec<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CheckRequest {
+    pub(crate) entries: Vec<CheckEntry>,
+    /// Largest value to send inline; larger ones that changed are only
+    /// described. Defaults to 64 KB, at most 1 MB.
+    #[serde(default)]
+    pub(crate) inline_max_bytes: Option<usize>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CheckEntry {
+    pub(crate) key: String,
+    /// Checksum of the client's copy, if it has one.
+    #[serde(default)]
+    pub(crate) checksum: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BatchPutRequest {
+    pub(crate) entries: Vec<BatchPutEntry>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BatchPutEntry {
+    pub(crate) key: String,
+    #[serde(with = "super::base64_serde")]
+    #[schema(value_type = String, format = Byte)]
+    pub(crate) value: Vec<u8>,
+    /// `sha256:<hex>` or `xxh3:<hex>`; bare hex is SHA-256.
+    #[serde(default)]
+    pub(crate) checksum: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SyncRequest {
+    pub(crate) client_manifest: Vec<ClientManifestEntry>,
+    #[serde(default)]
+    pub(crate) uploads: Vec<UploadEntry>,
+    #[serde(default)]
+    pub(crate) deletions: Vec<ClientDeletionEntry>,
+    #[serde(default)]
+    pub(crate) conflict_policy: ConflictPolicy,
+}
+
+/// What to do with an upload based on an older version than the server's
+/// when the contents differ.
+#[derive(Deserialize, ToSchema, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Keep the server value and report the conflict.
+    #[default]
+    ServerWins,
+    /// Overwrite the server value with the upload.
+    ClientWins,
+    /// Keep the server value and store the upload under `conflicts/{key}`.
+    RecordConflict,
+}
+
+/// The one request type that takes unknown fields: clients may send back
+/// the entries of a server manifest as they were received.
+#[derive(Deserialize, ToSchema)]
+pub struct ClientManifestEntry {
+    pub(crate) key: String,
+    pub(crate) version: i64,
+    pub(crate) checksum: String,
+    /// Block checksums of the client's copy. When given, a newer server
+    /// value may be sent as a `patch` against that copy.
+    #[serde(default)]
+    pub(crate) signature: Option<Signature>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ClientDeletionEntry {
+    pub(crate) key: String,
+    pub(crate) version: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UploadEntry {
+    pub(crate) key: String,
+    #[serde(with = "super::base64_serde")]
+    #[schema(value_type = String, format = Byte)]
+    pub(crate) value: Vec<u8>,
+    /// `sha256:<hex>` or `xxh3:<hex>`; bare hex is SHA-256.
+    #[serde(default)]
+    pub(crate) checksum: Option<String>,
+    #[serde(default)]
+    pub(crate) ttl: Option<u64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateUploadRequest {
+    /// Data key the value is written to.
+    pub(crate) key: String,
+    /// Size of the whole value in bytes.
+    pub(crate) size: u64,
+    /// Checksum of the whole value, checked when the upload completes;
+    /// `sha256:<hex>` or `xxh3:<hex>`.
+    #[serde(default)]
+    pub(crate) checksum: Option<String>,
+    /// Expire the written value after this many seconds.
+    #[serde(default)]
+    pub(crate) ttl_secs: Option<u64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RenameRequest {
+    /// New name of the key.
+    pub(crate) to: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ShareRequest {
+    /// How long the link works, one day by default and 30 days at most.
+    pub(crate) ttl_secs: Option<u64>,
+    /// Downloads allowed before the link stops working.
+    pub(crate) max_downloads: Option<u32>,
+}
+
+/// A key in the user's manifest.
+#[derive(Serialize, ToSchema)]
+pub struct ManifestEntry {
+    pub(crate) key: String,
+    pub(crate) version: i64,
+    pub(crate) checksum: String,
+    pub(crate) size_bytes: i32,
+    pub(crate) updated_at: i64,
+    pub(crate) deleted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deleted_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<i64>,
+    /// The device that last wrote the key, if it named itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) last_writer: Option<LastWriter>,
+}
+
+impl From<equicloud::DataManifestEntry> for ManifestEntry {
+    fn from(entry: equicloud::DataManifestEntry) -> Self {
+        Self {
+            key: entry.key,
+            version: entry.version,
+            checksum: entry.checksum,
+            size_bytes: entry.size_bytes,
+            updated_at: entry.updated_at,
+            deleted: entry.deleted,
+            deleted_at: entry.deleted_at,
+            expires_at: entry.expires_at,
+            last_writer: entry.last_writer.map(LastWriter::from),
+        }
+    }
+}
+
+/// The device that last wrote a key.
+#[derive(Serialize, ToSchema)]
+pub struct LastWriter {
+    device_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_name: Option<String>,
+    written_at: i64,
+}
+
+impl From<equicloud::LastWriter> for LastWriter {
+    fn from(writer: equicloud::LastWriter) -> Self {
+        Self {
+            device_id: writer.device_id,
+            client_name: writer.client_name,
+            written_at: writer.written_at,
+        }
+    }
+}
+
+/// A device that has written or synced the user's data.
+#[derive(Serialize, ToSchema)]
+pub struct Device {
+    device_id: String,
+    /// Its `X-Client-Name`, as last sent.
+    client_name: Option<String>,
+    last_seen_at: i64,
+}
+
+impl From<equicloud::Device> for Device {
+    fn from(device: equicloud::Device) -> Self {
+        Self {
+            device_id: device.device_id,
+            client_name: device.client_name,
+            last_seen_at: device.last_seen_at,
+        }
+    }
+}
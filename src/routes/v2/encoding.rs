@@ -0,0 +1,83 @@
+//! Request and response bodies in JSON or CBOR, chosen by `Content-Type`.
+//!
+//! CBOR carries binary values as byte strings instead of base64, which saves
+//! a third of the payload and the cost of encoding it.
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::error;
+
+use equicloud::error::AppError;
+
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+/// A body decoded from JSON or CBOR. Returned from a handler, the value is
+/// sent back in the encoding the request used.
+pub struct Negotiated<T> {
+    pub encoding: Encoding,
+    pub value: T,
+}
+
+impl<S, T> FromRequest<S> for Negotiated<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_cbor = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|h| h.trim() == CBOR_CONTENT_TYPE);
+
+        if !is_cbor {
+            let Json(value) = Json::<T>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self {
+                encoding: Encoding::Json,
+                value,
+            });
+        }
+
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let value = serde_cbor::from_slice(&body).map_err(|e| {
+            AppError::BadRequest(format!("Invalid CBOR body: {}", e)).into_response()
+        })?;
+        Ok(Self {
+            encoding: Encoding::Cbor,
+            value,
+        })
+    }
+}
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        match self.encoding {
+            Encoding::Json => Json(self.value).into_response(),
+            Encoding::Cbor => match serde_cbor::to_vec(&self.value) {
+                Ok(body) => ([(CONTENT_TYPE, CBOR_CONTENT_TYPE)], body).into_response(),
+                Err(e) => {
+                    error!("Failed to encode CBOR response: {}", e);
+                    AppError::Internal("Failed to encode response").into_response()
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::collections::VecDeque;
+use std::io;
+use tar::Builder;
+use tracing::error;
+
+use equicloud::archive::{
+    ArchiveDataEntry, ArchiveManifest, ArchiveSettingsEntry, MANIFEST_PATH, SETTINGS_PATH,
+    append_file, data_path,
+};
+use equicloud::error::{AppError, ResultExt};
+use equicloud::{DataManifestEntry, Datastore, Storage, compute_checksum};
+
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::CurrentTenant;
+use crate::routes::openapi::Binary;
+
+enum ExportStage {
+    Settings,
+    Data,
+    Manifest,
+    Done,
+}
+
+/// Archive generation state. Each step appends one file and hands the bytes
+/// written so far to the response stream, so at most one value is held in
+/// memory at a time.
+struct ExportState {
+    db: Storage,
+    user_id: String,
+    stage: ExportStage,
+    pending: VecDeque<DataManifestEntry>,
+    builder: Builder<Vec<u8>>,
+    manifest: ArchiveManifest,
+}
+
+impl ExportState {
+    fn take_chunk(&mut self) -> Bytes {
+        Bytes::from(std::mem::take(self.builder.get_mut()))
+    }
+
+    async fn next_chunk(&mut self) -> Option<io::Result<Bytes>> {
+        loop {
+            match self.stage {
+                ExportStage::Settings => {
+                    self.stage = ExportStage::Data;
+                    let (settings, written) = match self.db.get_user_settings(&self.user_id).await {
+                        Ok(Some(s)) => s,
+                        Ok(None) => continue,
+                        Err(e) => return Some(Err(self.fail(e))),
+                    };
+                    let updated_at = written.parse().unwrap_or(0);
+                    if let Err(e) =
+                        append_file(&mut self.builder, SETTINGS_PATH, &settings, updated_at)
+                    {
+                        return Some(Err(self.fail(e.into())));
+                    }
+                    self.manifest.settings = Some(ArchiveSettingsEntry {
+                        checksum: compute_checksum(&settings),
+                        size_bytes: settings.len() as i64,
+                        updated_at,
+                    });
+                    return Some(Ok(self.take_chunk()));
+                }
+                ExportStage::Data => {
+                    let Some(next) = self.pending.pop_front() else {
+                        self.stage = ExportStage::Manifest;
+                        continue;
+                    };
+                    let entry = match self.db.get_data_key(&self.user_id, &next.key).await {
+                        Ok(Some(e)) => e,
+                        Ok(None) => continue,
+                        Err(e) => return Some(Err(self.fail(e))),
+                    };
+                    if let Err(e) = append_file(
+                        &mut self.builder,
+                        &data_path(&entry.key),
+                        &entry.value,
+                        entry.updated_at,
+                    ) {
+                        return Some(Err(self.fail(e.into())));
+                    }
+                    self.manifest.entries.push(ArchiveDataEntry {
+                        key: entry.key,
+                        version: entry.version,
+                        checksum: entry.checksum,
+                        size_bytes: entry.size_bytes,
+                        updated_at: entry.updated_at,
+                        expires_at: None,
+                    });
+                    return Some(Ok(self.take_chunk()));
+                }
+                ExportStage::Manifest => {
+                    self.stage = ExportStage::Done;
+                    let result = serde_json::to_vec_pretty(&self.manifest)
+                        .map_err(io::Error::other)
+                        .and_then(|manifest| {
+                            append_file(
+                                &mut self.builder,
+                                MANIFEST_PATH,
+                                &manifest,
+                                self.manifest.exported_at,
+                            )
+                        })
+                        .and_then(|_| self.builder.finish());
+                    if let Err(e) = result {
+                        return Some(Err(self.fail(e.into())));
+                    }
+                    return Some(Ok(self.take_chunk()));
+                }
+                ExportStage::Done => return None,
+            }
+        }
+    }
+
+    fn fail(&mut self, e: anyhow::Error) -> io::Error {
+        error!("Failed to build export archive: {}", e);
+        self.stage = ExportStage::Done;
+        io::Error::other("Export failed")
+    }
+}
+
+/// Streams the user's settings and live data keys as a tar archive with a
+/// `manifest.json` describing its contents.
+#[utoipa::path(
+    get,
+    path = "/v2/export",
+    tag = "data",
+    security(("token" = [])),
+    responses((status = 200, description = "The archive", body = Binary,
+        content_type = "application/x-tar"))
+)]
+pub async fn export_data(
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+) -> Result<Response, AppError> {
+    let db = tenant.db.clone();
+    let config = tenant.config.load();
+    let manifest = db
+        .get_data_manifest(&user_id)
+        .await
+        .or_internal("Failed to export data")?;
+
+    let pending: VecDeque<DataManifestEntry> = manifest
+        .into_iter()
+        .filter(|e| !e.deleted)
+        .filter(|e| config.namespaces.is_enabled(&e.key))
+        .collect();
+
+    let state = ExportState {
+        db,
+        user_id,
+        stage: ExportStage::Settings,
+        pending,
+        builder: Builder::new(Vec::new()),
+        manifest: ArchiveManifest::new(chrono::Utc::now().timestamp_millis()),
+    };
+
+    let stream = futures::stream::unfold(state, |mut state| async move {
+        let chunk = state.next_chunk().await?;
+        Some((chunk, state))
+    });
+
+    let mut response_headers = HeaderMap::new();
+    if let Ok(v) = "application/x-tar".parse() {
+        response_headers.insert("Content-Type", v);
+    }
+    if let Ok(v) = "attachment; filename=\"equicloud-export.tar\"".parse() {
+        response_headers.insert("Content-Disposition", v);
+    }
+
+    Ok((StatusCode::OK, response_headers, Body::from_stream(stream)).into_response())
+}
@@ -0,0 +1,232 @@
+use axum::{Json, body::Bytes, extract::State, http::HeaderMap};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use tracing::error;
+use utoipa::ToSchema;
+
+use equicloud::abuse::{self, Violation};
+use equicloud::archive::read_archive;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::key_limit::KeyAllowance;
+use equicloud::validation;
+use equicloud::write_budget::WriteBudget;
+use equicloud::{DataUpload, Datastore, Event, EventBus, Storage};
+
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::CurrentTenant;
+use crate::routes::openapi::Binary;
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportResponse {
+    settings_written: Option<i64>,
+    restored: Vec<RestoredEntry>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RestoredEntry {
+    key: String,
+    version: i64,
+    checksum: String,
+}
+
+/// Restores an archive produced by `/v2/export`. The whole archive is staged
+/// and validated (checksums, keys, per-key and total quota, and
+/// `MAX_KEYS_PER_USER`) before anything is written, so a bad archive never
+/// leaves partial state behind. The data and settings are written together:
+/// if either write fails, the imported keys are put back as they were. Imported
+/// keys overwrite existing ones; keys absent from the archive are kept.
+#[utoipa::path(
+    post,
+    path = "/v2/import",
+    tag = "data",
+    security(("token" = [])),
+    request_body(content = Binary, content_type = "application/x-tar",
+        description = "An archive produced by /v2/export"),
+    responses(
+        (status = 200, description = "The archive was restored", body = ImportResponse),
+        (status = 400, description = "The archive is malformed", body = ErrorBody),
+        (status = 413, description = "The archive exceeds a size limit, the quota or MAX_KEYS_PER_USER", body = ErrorBody),
+        (status = 415, description = "Body is not application/x-tar", body = ErrorBody),
+        (status = 429, description = "The user's DAILY_WRITE_LIMIT cannot cover the entries and settings", body = ErrorBody)
+    )
+)]
+pub async fn import_data(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    AuthUser(user_id): AuthUser,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(Option<WriteBudget>, Json<ImportResponse>), AppError> {
+    validation::require_content_type(&headers, validation::TAR)?;
+
+    let db = &tenant.db;
+    let config = tenant.config.load();
+    let staged = read_archive(&body).map_err(|e| AppError::BadRequest(e.message()))?;
+
+    if let Some(settings) = &staged.settings {
+        validation::check_settings(settings, &config)?;
+    }
+
+    for (entry, value) in &staged.entries {
+        config
+            .namespaces
+            .validate_write(&entry.key, value.len())
+            .map_err(|e| match e {
+                AppError::PayloadTooLarge(message) => {
+                    AppError::PayloadTooLarge(format!("{}: {}", entry.key, message))
+                }
+                e => e,
+            })?;
+    }
+
+    let server_manifest = db
+        .get_data_manifest(&user_id)
+        .await
+        .or_internal("Database error")?;
+
+    let live_keys: HashSet<&str> = server_manifest
+        .iter()
+        .filter(|e| !e.deleted)
+        .map(|e| e.key.as_str())
+        .collect();
+    let mut allowance = KeyAllowance::new(config.max_keys_per_user, live_keys.len() as u64);
+    for (entry, _) in &staged.entries {
+        if !live_keys.contains(entry.key.as_str()) {
+            allowance.take()?;
+        }
+    }
+
+    let mut sizes: HashMap<&str, i64> = server_manifest
+        .iter()
+        .map(|e| (e.key.as_str(), e.size_bytes as i64))
+        .collect();
+    for (entry, value) in &staged.entries {
+        sizes.insert(entry.key.as_str(), value.len() as i64);
+    }
+    let total_size: i64 = sizes.values().sum();
+
+    let quota = db
+        .storage_quota(&user_id)
+        .await
+        .or_internal("Database error")?;
+    if total_size > quota {
+        abuse::report(&tenant, &user_id, Violation::QuotaExceeded).await;
+        events.publish(Event::QuotaExceeded { user_id });
+        return Err(AppError::QuotaExceeded);
+    }
+
+    let writes = staged.entries.len() + usize::from(staged.settings.is_some());
+    let budget = WriteBudget::spend(&tenant, &user_id, writes).await?;
+
+    let uploads = staged
+        .entries
+        .into_iter()
+        .map(|(entry, value)| {
+            Ok(DataUpload {
+                ttl_secs: config.namespaces.resolve_ttl(&entry.key, None)?,
+                key: entry.key,
+                value,
+                checksum: entry.checksum,
+            })
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+    let checksums: HashMap<String, String> = uploads
+        .iter()
+        .map(|u| (u.key.clone(), u.checksum.clone()))
+        .collect();
+
+    let keys: Vec<String> = uploads.iter().map(|u| u.key.clone()).collect();
+    let created: Vec<String> = keys
+        .iter()
+        .filter(|key| !live_keys.contains(key.as_str()))
+        .cloned()
+        .collect();
+    let expiries: HashMap<&str, i64> = server_manifest
+        .iter()
+        .filter_map(|e| Some((e.key.as_str(), e.expires_at?)))
+        .collect();
+    let now = chrono::Utc::now().timestamp_millis();
+    let previous = db
+        .get_data_keys(&user_id, &keys)
+        .await
+        .or_internal("Database error")?
+        .into_iter()
+        .map(|entry| DataUpload {
+            ttl_secs: expiries
+                .get(entry.key.as_str())
+                .map(|expires_at| ((expires_at - now) / 1000).max(1) as i32),
+            key: entry.key,
+            value: entry.value,
+            checksum: entry.checksum,
+        })
+        .collect();
+
+    let written = async {
+        let saved = db
+            .save_data_keys_batch(&user_id, uploads)
+            .await
+            .or_internal("Failed to import data")?;
+        let settings_written = match staged.settings {
+            Some(settings) => Some(
+                db.save_user_settings(&user_id, settings)
+                    .await
+                    .or_internal("Failed to import settings")?,
+            ),
+            None => None,
+        };
+        Ok::<_, AppError>((saved, settings_written))
+    }
+    .await;
+    let (saved, settings_written) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            restore(db, &user_id, previous, created).await;
+            return Err(e);
+        }
+    };
+
+    if let Some(written) = settings_written {
+        events.publish(Event::SettingsWritten {
+            user_id: user_id.clone(),
+            written,
+        });
+    }
+
+    let restored = saved
+        .into_iter()
+        .map(|(key, version, _)| {
+            events.publish(Event::DataWritten {
+                user_id: user_id.clone(),
+                key: key.clone(),
+                version,
+            });
+            let checksum = checksums.get(&key).cloned().unwrap_or_default();
+            RestoredEntry {
+                key,
+                version,
+                checksum,
+            }
+        })
+        .collect();
+
+    Ok((
+        budget,
+        Json(ImportResponse {
+            settings_written,
+            restored,
+        }),
+    ))
+}
+
+/// Undoes a failed import: keys it created are deleted again and the values
+/// it replaced are written back with what was left of their TTL.
+async fn restore(db: &Storage, user_id: &str, previous: Vec<DataUpload>, created: Vec<String>) {
+    if let Err(e) = db.save_data_keys_batch(user_id, previous).await {
+        error!("Failed to restore data after a failed import: {}", e);
+    }
+    for key in created {
+        if let Err(e) = db.delete_data_key(user_id, &key).await {
+            error!("Failed to remove {} after a failed import: {}", key, e);
+        }
+    }
+}
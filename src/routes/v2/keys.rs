@@ -0,0 +1,81 @@
+use axum::{Json, extract::Query};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use utoipa::{IntoParams, ToSchema};
+
+use equicloud::Datastore;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Deserialize, IntoParams)]
+pub struct KeysQuery {
+    /// Only list keys starting with this.
+    prefix: Option<String>,
+    /// Roll up keys that contain this after the prefix into
+    /// `common_prefixes`, e.g. `/` to browse one level at a time.
+    delimiter: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct KeysResponse {
+    keys: Vec<String>,
+    /// Distinct `prefix` + up to and including the first `delimiter` of the
+    /// keys that were rolled up.
+    common_prefixes: Vec<String>,
+}
+
+/// Lists the names of the user's live keys under a prefix, grouping deeper
+/// keys S3-style so a keyspace can be browsed like a directory tree.
+#[utoipa::path(
+    get,
+    path = "/v2/keys",
+    tag = "data",
+    security(("token" = [])),
+    params(KeysQuery),
+    responses(
+        (status = 200, description = "Keys and common prefixes, sorted", body = KeysResponse),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody)
+    )
+)]
+pub async fn list_keys(
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+    Query(query): Query<KeysQuery>,
+) -> Result<Json<KeysResponse>, AppError> {
+    let config = tenant.config.load();
+    let prefix = query.prefix.as_deref().unwrap_or("");
+    let delimiter = query.delimiter.as_deref().filter(|d| !d.is_empty());
+
+    let entries = tenant
+        .db
+        .get_data_manifest(&user_id)
+        .await
+        .or_internal("Failed to list keys")?;
+
+    let mut keys = Vec::new();
+    let mut common_prefixes = BTreeSet::new();
+    for entry in entries {
+        if entry.deleted
+            || !entry.key.starts_with(prefix)
+            || !config.namespaces.is_enabled(&entry.key)
+        {
+            continue;
+        }
+
+        let rest = &entry.key[prefix.len()..];
+        match delimiter.and_then(|d| rest.find(d).map(|i| i + d.len())) {
+            Some(end) => {
+                common_prefixes.insert(entry.key[..prefix.len() + end].to_string());
+            }
+            None => keys.push(entry.key),
+        }
+    }
+    keys.sort();
+
+    Ok(Json(KeysResponse {
+        keys,
+        common_prefixes: common_prefixes.into_iter().collect(),
+    }))
+}
@@ -1,46 +1,164 @@
-use axum::{Extension, Json, http::StatusCode, response::IntoResponse};
-use serde::Serialize;
-use tracing::error;
+use axum::{Json, extract::Query};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
-use equicloud::utils::CONFIG;
-use equicloud::{DataManifestEntry, DatabaseService};
+use equicloud::constants::MAX_MANIFEST_PAGE_SIZE;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::{DataManifestEntry, Datastore};
 
-#[derive(Serialize)]
+use super::dto::{ManifestEntry, Versioned};
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Deserialize, IntoParams)]
+pub struct ManifestQuery {
+    prefix: Option<String>,
+    updated_since: Option<i64>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    /// Leave out checksums and sizes.
+    #[serde(default)]
+    light: bool,
+    /// List deleted keys, marked `deleted`, so other devices can remove
+    /// them too. Defaults to true.
+    include_deleted: Option<bool>,
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct ManifestResponse {
-    entries: Vec<DataManifestEntry>,
+    entries: ManifestEntries,
     total_size: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+enum ManifestEntries {
+    Full(Vec<ManifestEntry>),
+    Light(Vec<LightManifestEntry>),
+}
+
+#[derive(Serialize, ToSchema)]
+struct LightManifestEntry {
+    key: String,
+    version: i64,
+    updated_at: i64,
+    deleted: bool,
 }
 
+/// Lists the user's keys. Without `limit` or `cursor` the whole manifest is
+/// returned; otherwise pages are read with Scylla paging and `next_cursor`
+/// is set while more remain. `updated_since` is applied after paging, so a
+/// page can hold fewer entries than `limit`. Full entries name the device
+/// that last wrote them, when it sent `X-Device-Id`.
+#[utoipa::path(
+    get,
+    path = "/v2/manifest",
+    tag = "data",
+    security(("token" = [])),
+    params(ManifestQuery),
+    responses(
+        (status = 200, description = "The user's keys", body = Versioned<ManifestResponse>),
+        (status = 400, description = "Invalid limit or cursor", body = ErrorBody)
+    )
+)]
 pub async fn get_manifest(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
-) -> impl IntoResponse {
-    let entries = match db.get_data_manifest(&user_id).await {
-        Ok(e) => e,
-        Err(e) => {
-            error!("Failed to get manifest: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Failed to get manifest"})),
-            )
-                .into_response();
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+    Query(query): Query<ManifestQuery>,
+) -> Result<Json<Versioned<ManifestResponse>>, AppError> {
+    let db = &tenant.db;
+    let prefix = query.prefix.as_deref().unwrap_or("");
+    let paged = query.limit.is_some() || query.cursor.is_some();
+
+    let (entries, next_cursor) = if paged {
+        let limit = query.limit.unwrap_or(MAX_MANIFEST_PAGE_SIZE);
+        if limit == 0 || limit > MAX_MANIFEST_PAGE_SIZE {
+            return Err(AppError::BadRequest(format!(
+                "limit must be between 1 and {}",
+                MAX_MANIFEST_PAGE_SIZE
+            )));
         }
+        let cursor = query
+            .cursor
+            .as_deref()
+            .map(|c| BASE64_URL_SAFE_NO_PAD.decode(c))
+            .transpose()
+            .map_err(|_| AppError::BadRequest("Invalid cursor".into()))?;
+
+        let page = db
+            .get_data_manifest_page(&user_id, prefix, limit as i32, cursor)
+            .await
+            .or_internal("Failed to get manifest")?;
+        (
+            page.entries,
+            page.next_cursor.map(|c| BASE64_URL_SAFE_NO_PAD.encode(c)),
+        )
+    } else {
+        let entries = db
+            .get_data_manifest(&user_id)
+            .await
+            .or_internal("Failed to get manifest")?;
+        (entries, None)
     };
 
-    let entries: Vec<DataManifestEntry> = if CONFIG.datastore_enabled {
-        entries
+    let config = tenant.config.load();
+    let entries: Vec<DataManifestEntry> = entries
+        .into_iter()
+        .filter(|e| config.namespaces.is_enabled(&e.key))
+        .collect();
+
+    let total_size = if paged {
+        db.get_user_total_size(&user_id)
+            .await
+            .or_internal("Failed to get manifest")?
     } else {
-        entries
-            .into_iter()
-            .filter(|e| !e.key.starts_with("dataStore/"))
-            .collect()
+        entries.iter().map(|e| e.size_bytes as i64).sum()
     };
 
-    let total_size: i64 = entries.iter().map(|e| e.size_bytes as i64).sum();
+    let entries: Vec<DataManifestEntry> = entries
+        .into_iter()
+        .filter(|e| e.key.starts_with(prefix))
+        .filter(|e| query.updated_since.is_none_or(|since| e.updated_at > since))
+        .filter(|e| query.include_deleted.unwrap_or(true) || !e.deleted)
+        .collect();
+
+    let entries = if query.light {
+        ManifestEntries::Light(
+            entries
+                .into_iter()
+                .map(|e| LightManifestEntry {
+                    key: e.key,
+                    version: e.version,
+                    updated_at: e.updated_at,
+                    deleted: e.deleted,
+                })
+                .collect(),
+        )
+    } else {
+        let mut writers = db
+            .get_last_writers(&user_id)
+            .await
+            .or_internal("Failed to get manifest")?;
+        // A writer older than the value wrote an earlier version; the last
+        // write came without X-Device-Id.
+        let entries = entries
+            .into_iter()
+            .map(|mut e| {
+                e.last_writer = writers
+                    .remove(&e.key)
+                    .filter(|writer| writer.written_at >= e.updated_at);
+                ManifestEntry::from(e)
+            })
+            .collect();
+        ManifestEntries::Full(entries)
+    };
 
-    Json(ManifestResponse {
+    Ok(Json(Versioned::new(ManifestResponse {
         entries,
         total_size,
-    })
-    .into_response()
+        next_cursor,
+    })))
 }
@@ -1,23 +1,112 @@
 use axum::{
-    Router, middleware,
-    routing::{get, post},
+    Router,
+    extract::{Request, State},
+    handler::Handler,
+    middleware::from_fn_with_state,
+    response::Response,
+    routing::{get, post, put},
 };
 
+use crate::middleware::body_limit::{
+    archive_limit, data_limit, default_limit, json_upload_limit, limit_body, upload_part_limit,
+};
+use crate::middleware::compression::response_compression;
+use crate::middleware::idempotency::replay_or_run;
+use crate::middleware::timeout::{bulk_timeout, default_timeout, with_timeout};
+use crate::state::AppState;
+
+pub(crate) mod base64_serde;
+pub mod batch;
+pub mod capabilities;
 pub mod data;
+pub mod devices;
+pub mod dto;
+pub(crate) mod encoding;
+pub mod export;
+pub mod import;
+pub mod keys;
 pub mod manifest;
+pub mod shares;
 pub mod sync;
+pub mod sync_history;
+pub mod uploads;
+pub mod usage;
+
+/// `/v2/data/{*key}` takes the whole path, so `POST` actions on a key are
+/// told apart by their suffix.
+async fn data_action(State(state): State<AppState>, request: Request) -> Response {
+    if request.uri().path().ends_with("/rename") {
+        data::rename_data.call(request, state).await
+    } else {
+        shares::create_share.call(request, state).await
+    }
+}
 
-pub fn register() -> Router {
-    Router::new()
+pub fn register(state: &AppState) -> Router<AppState> {
+    let tenants = &state.tenants;
+    let compression = || response_compression(&state.config.load());
+    let listing_routes = Router::new()
         .route("/v2/manifest", get(manifest::get_manifest))
+        .route("/v2/keys", get(keys::list_keys))
+        .route("/v2/capabilities", get(capabilities::get_capabilities))
+        .route("/v2/usage", get(usage::get_usage))
+        .route("/v2/devices", get(devices::list_devices))
+        .route("/v2/sync/history", get(sync_history::get_sync_history));
+
+    let export_routes = Router::new().route("/v2/export", get(export::export_data));
+
+    let data_routes = Router::new().route(
+        "/v2/data/{*key}",
+        get(data::get_data.layer(compression()))
+            .head(data::head_data)
+            .put(data::put_data.layer(from_fn_with_state(state.clone(), replay_or_run)))
+            .post(data_action)
+            .delete(data::delete_data),
+    );
+    let shared_routes = Router::new().route("/v2/shared/{token}", get(shares::get_shared));
+
+    let json_routes = Router::new()
+        .route("/v2/data:batchGet", post(batch::batch_get_data))
+        .route("/v2/data:batchPut", post(batch::batch_put_data))
+        .route("/v2/data:check", post(batch::check_data))
+        .route(
+            "/v2/sync",
+            post(
+                sync::delta_sync
+                    .layer(from_fn_with_state(state.clone(), replay_or_run))
+                    .layer(compression()),
+            ),
+        );
+
+    let import_routes = Router::new().route("/v2/import", post(import::import_data));
+
+    let upload_routes = Router::new()
+        .route("/v2/uploads", post(uploads::create_upload))
         .route(
-            "/v2/data/{*key}",
-            get(data::get_data)
-                .put(data::put_data)
-                .delete(data::delete_data),
-        )
-        .route("/v2/sync", post(sync::delta_sync))
-        .route_layer(middleware::from_fn(
-            crate::middleware::auth::auth_middleware,
-        ))
+            "/v2/uploads/{id}",
+            get(uploads::get_upload).delete(uploads::abort_upload),
+        );
+    let upload_part_routes = Router::new().route(
+        "/v2/uploads/{id}/parts/{part}",
+        put(uploads::put_upload_part),
+    );
+    let upload_complete_routes =
+        Router::new().route("/v2/uploads/{id}/complete", post(uploads::complete_upload));
+
+    let routes = limit_body(listing_routes, tenants, default_limit)
+        .merge(limit_body(data_routes, tenants, data_limit))
+        .merge(limit_body(shared_routes, tenants, default_limit))
+        .merge(limit_body(upload_routes, tenants, default_limit))
+        .merge(limit_body(upload_part_routes, tenants, upload_part_limit));
+    // Completing an upload assembles and writes the whole value.
+    let bulk_routes = limit_body(json_routes, tenants, json_upload_limit)
+        .merge(limit_body(export_routes, tenants, default_limit))
+        .merge(limit_body(import_routes, tenants, archive_limit))
+        .merge(limit_body(upload_complete_routes, tenants, default_limit));
+
+    with_timeout(routes, tenants, default_timeout).merge(with_timeout(
+        bulk_routes,
+        tenants,
+        bulk_timeout,
+    ))
 }
@@ -0,0 +1,196 @@
+//! Share links: a signed, expiring URL through which anyone can download
+//! one data key without the owner's token.
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+use equicloud::abuse;
+use equicloud::constants::{DEFAULT_SHARE_TTL_SECS, MAX_SHARE_TTL_SECS};
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::etag::ETag;
+use equicloud::integrity;
+use equicloud::share::{issue_share_token, new_share_id, verify_share_token};
+use equicloud::{DataShare, Datastore, Metrics};
+
+use super::dto::ShareRequest;
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::CurrentTenant;
+use crate::routes::openapi::Binary;
+
+#[derive(Serialize, ToSchema)]
+pub struct ShareCreated {
+    token: String,
+    /// Where the value can be downloaded, relative when `SERVER_FQDN` is
+    /// unset.
+    url: String,
+    expires_at: i64,
+    max_downloads: Option<u32>,
+}
+
+/// Creates a share link to the current value of a key. The link serves
+/// whatever the key holds when it is opened.
+#[utoipa::path(
+    post,
+    path = "/v2/data/{key}/share",
+    tag = "data",
+    security(("token" = [])),
+    params(("key" = String, Path, description = "Data key; may contain slashes")),
+    request_body = ShareRequest,
+    responses(
+        (status = 201, description = "The share link", body = ShareCreated),
+        (status = 400, description = "Invalid key, TTL or download limit", body = ErrorBody),
+        (status = 404, description = "The key does not exist", body = ErrorBody)
+    )
+)]
+pub async fn create_share(
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+    Path(path): Path<String>,
+    Json(request): Json<ShareRequest>,
+) -> Result<(StatusCode, Json<ShareCreated>), AppError> {
+    // `/v2/data/{*key}` takes the whole path, so the action is its suffix.
+    let key = path.strip_suffix("/share").ok_or(AppError::NotFound)?;
+    let config = tenant.config.load();
+    config.namespaces.check_key(key)?;
+
+    let ttl_secs = request.ttl_secs.unwrap_or(DEFAULT_SHARE_TTL_SECS);
+    if ttl_secs == 0 || ttl_secs > MAX_SHARE_TTL_SECS {
+        return Err(AppError::BadRequest(format!(
+            "ttl_secs must be between 1 and {}",
+            MAX_SHARE_TTL_SECS
+        )));
+    }
+    let max_downloads = match request.max_downloads {
+        Some(0) => {
+            return Err(AppError::BadRequest(
+                "max_downloads must be greater than zero".into(),
+            ));
+        }
+        Some(n) => Some(i32::try_from(n).unwrap_or(i32::MAX)),
+        None => None,
+    };
+
+    let db = &tenant.db;
+    if db
+        .get_data_key(&user_id, key)
+        .await
+        .or_internal("Failed to get data")?
+        .is_none()
+    {
+        return Err(AppError::NotFound);
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let share = DataShare {
+        id: new_share_id(),
+        user_id,
+        key: key.to_string(),
+        created_at: now,
+        expires_at: now + ttl_secs as i64 * 1000,
+        max_downloads,
+    };
+    db.save_share(&share)
+        .await
+        .or_internal("Failed to create share link")?;
+
+    let token = issue_share_token(
+        config.session_secret.as_bytes(),
+        &share.id,
+        share.expires_at,
+    );
+    Ok((
+        StatusCode::CREATED,
+        Json(ShareCreated {
+            url: config.share_url(&token),
+            token,
+            expires_at: share.expires_at,
+            max_downloads: request.max_downloads,
+        }),
+    ))
+}
+
+/// Downloads a shared value. Needs no token; the link is the credential.
+#[utoipa::path(
+    get,
+    path = "/v2/shared/{token}",
+    tag = "data",
+    params(("token" = String, Path, description = "Token of the share link")),
+    responses(
+        (status = 200, description = "The shared value", body = Binary,
+            content_type = "application/octet-stream"),
+        (status = 403, description = "The owner of the value is banned", body = ErrorBody),
+        (status = 404, description = "The link is invalid or the key no longer exists", body = ErrorBody),
+        (status = 410, description = "The link has expired or used up its downloads", body = ErrorBody)
+    )
+)]
+pub async fn get_shared(
+    CurrentTenant(tenant): CurrentTenant,
+    State(metrics): State<Arc<Metrics>>,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    let config = tenant.config.load();
+    let (id, expires_at) =
+        verify_share_token(config.session_secret.as_bytes(), &token).ok_or(AppError::NotFound)?;
+    if expires_at <= chrono::Utc::now().timestamp_millis() {
+        return Err(AppError::Gone("The share link has expired".into()));
+    }
+
+    let db = &tenant.db;
+    let share = db
+        .get_share(&id)
+        .await
+        .or_internal("Failed to get share link")?
+        .ok_or_else(|| AppError::Gone("The share link has expired".into()))?;
+
+    abuse::check_ban(db, &share.user_id)
+        .await
+        .map_err(|e| match e {
+            AppError::Banned { .. } => {
+                AppError::Forbidden("The owner of this value is banned".into())
+            }
+            e => e,
+        })?;
+    config.namespaces.check_key(&share.key)?;
+
+    let entry = db
+        .get_data_key(&share.user_id, &share.key)
+        .await
+        .or_internal("Failed to get data")?
+        .ok_or(AppError::NotFound)?;
+
+    if let Some(max) = share.max_downloads {
+        let downloads = db
+            .add_share_downloads(&share.id, 1)
+            .await
+            .or_internal("Failed to count download")?;
+        if downloads > max as i64 {
+            return Err(AppError::Gone(
+                "The share link has used up its downloads".into(),
+            ));
+        }
+    }
+
+    let checksum_status = integrity::verify(db, &metrics, &share.user_id, &entry).await;
+
+    let mut headers = HeaderMap::new();
+    if let Some(v) = ETag::strong(entry.checksum.clone()).to_header() {
+        headers.insert("ETag", v);
+    }
+    if let Ok(v) = "application/octet-stream".parse() {
+        headers.insert("Content-Type", v);
+    }
+    // Limits and expiry are enforced here, not by caches along the way.
+    if let Ok(v) = "no-store".parse() {
+        headers.insert("Cache-Control", v);
+    }
+
+    Ok((checksum_status, headers, Body::from(entry.value)).into_response())
+}
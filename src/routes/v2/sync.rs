@@ -1,104 +1,371 @@
-use axum::{Extension, Json, http::StatusCode, response::IntoResponse};
-use serde::{Deserialize, Serialize};
+use axum::extract::State;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
 use tracing::error;
+use utoipa::ToSchema;
+
+use equicloud::abuse::{self, Violation};
+use equicloud::audit::{AuditAction, AuditActor};
+use equicloud::checksum::{self, ChecksumError};
+use equicloud::constants::CONFLICT_KEY_PREFIX;
+use equicloud::delta;
+use equicloud::devices::{self, ClientDevice};
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::integrity::{self, ChecksumStatus};
+use equicloud::key_limit::KeyAllowance;
+use equicloud::scopes::{Scope, TokenScopes};
+use equicloud::stats;
+use equicloud::sync_cursor::PendingKey;
+use equicloud::sync_history;
+use equicloud::write_budget::WriteBudget;
+use equicloud::{
+    DataEntry, DataManifestEntry, DataUpload, Datastore, Event, EventBus, Metrics, SyncSession,
+    Tenant,
+};
+
+use super::dto::{ClientManifestEntry, ConflictPolicy, ManifestEntry, SyncRequest, Versioned};
+use super::encoding::Negotiated;
+use crate::middleware::audit::AuditContext;
+use crate::middleware::auth::ScopedUser;
+use crate::middleware::device::RequestDevice;
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Serialize, ToSchema)]
+pub struct SyncResponse {
+    pub(crate) server_manifest: Vec<ManifestEntry>,
+    pub(crate) downloads: Vec<DownloadEntry>,
+    pub(crate) uploaded: Vec<UploadResult>,
+    pub(crate) deleted: Vec<DeletedEntry>,
+    pub(crate) conflicts: Vec<SyncConflict>,
+    pub(crate) errors: Vec<SyncError>,
+}
 
-use equicloud::utils::CONFIG;
-use equicloud::{DataManifestEntry, DatabaseService, compute_checksum, validate_key};
+#[derive(Serialize, ToSchema)]
+pub struct SyncConflict {
+    pub(crate) key: String,
+    pub(crate) server_version: i64,
+    pub(crate) server_checksum: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) recorded_as: Option<String>,
+}
 
-#[derive(Deserialize)]
-pub struct SyncRequest {
-    client_manifest: Vec<ClientManifestEntry>,
-    #[serde(default)]
-    uploads: Vec<UploadEntry>,
+#[derive(Serialize, ToSchema)]
+pub struct DownloadEntry {
+    pub(crate) key: String,
+    /// The whole value; absent when `patch` is sent instead.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "super::base64_serde::serialize_option"
+    )]
+    #[schema(value_type = Option<String>, format = Byte)]
+    pub(crate) value: Option<Vec<u8>>,
+    /// Rebuilds the value from the client's copy whose checksum is
+    /// `base_checksum`, using the block size of the signature it sent.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "super::base64_serde::serialize_option"
+    )]
+    #[schema(value_type = Option<String>, format = Byte)]
+    pub(crate) patch: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) base_checksum: Option<String>,
+    pub(crate) version: i64,
+    pub(crate) checksum: String,
 }
 
-#[derive(Deserialize)]
-pub struct ClientManifestEntry {
-    key: String,
-    version: i64,
-    checksum: String,
+impl DownloadEntry {
+    /// Sends a patch against the client's copy when it has given a usable
+    /// signature and the patch is smaller than the value itself.
+    pub(crate) fn new(entry: DataEntry, client: Option<&ClientManifestEntry>) -> Self {
+        let patch = client.and_then(|client| {
+            let signature = client.signature.as_ref().filter(|s| s.is_valid())?;
+            let patch = delta::diff(signature, &entry.value)?;
+            (patch.len() < entry.value.len()).then(|| (patch, client.checksum.clone()))
+        });
+
+        let (value, patch, base_checksum) = match patch {
+            Some((patch, base_checksum)) => (None, Some(patch), Some(base_checksum)),
+            None => (Some(entry.value), None, None),
+        };
+        Self {
+            key: entry.key,
+            value,
+            patch,
+            base_checksum,
+            version: entry.version,
+            checksum: entry.checksum,
+        }
+    }
 }
 
-#[derive(Deserialize)]
-pub struct UploadEntry {
-    key: String,
-    #[serde(with = "base64_serde")]
-    value: Vec<u8>,
-    #[serde(default)]
-    checksum: Option<String>,
+#[derive(Serialize, ToSchema)]
+pub struct DeletedEntry {
+    pub(crate) key: String,
+    pub(crate) version: i64,
+    pub(crate) deleted_at: Option<i64>,
 }
 
-mod base64_serde {
-    use base64::prelude::*;
-    use serde::{Deserialize, Deserializer, Serializer};
+#[derive(Serialize, ToSchema)]
+pub struct UploadResult {
+    pub(crate) key: String,
+    pub(crate) version: i64,
+    pub(crate) checksum: String,
+}
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        BASE64_STANDARD.decode(&s).map_err(serde::de::Error::custom)
-    }
+#[derive(Serialize, ToSchema)]
+pub struct SyncError {
+    pub(crate) key: String,
+    pub(crate) error: String,
+}
 
-    pub fn serialize<S>(bytes: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+/// Reconciles the client's manifest with the server's: applies the client's
+/// deletions and uploads, and returns whatever the client is missing, as
+/// binary patches where the client sent signatures of its copies.
+///
+/// The request may be sent as `application/cbor`, with binary values as byte
+/// strings instead of base64; the response then comes back as CBOR too.
+#[utoipa::path(
+    post,
+    path = "/v2/sync",
+    tag = "data",
+    security(("token" = [])),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key"),
+        ("X-Device-Id" = Option<String>, Header, description = "Record this device as having synced, and as the last writer of the keys it uploaded or deleted"),
+        ("X-Client-Name" = Option<String>, Header, description = "Name to list the device under")
+    ),
+    request_body(content(
+        (SyncRequest = "application/json"),
+        (SyncRequest = "application/cbor")
+    )),
+    responses(
+        (status = 200, description = "The result of the sync", content(
+            (Versioned<SyncResponse> = "application/json"),
+            (Versioned<SyncResponse> = "application/cbor")
+        ), headers(("X-Checksum-Status" = String,
+            description = "`verified`, or `mismatch` if a download was withheld because its stored value no longer matches its checksum"))),
+        (status = 400, description = "Invalid device headers", body = ErrorBody),
+        (status = 409, description = "The Idempotency-Key was used for a different request or is still in use", body = ErrorBody),
+        (status = 429, description = "The user's DAILY_WRITE_LIMIT cannot cover the uploads and deletions", body = ErrorBody)
+    )
+)]
+pub async fn delta_sync(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    State(metrics): State<Arc<Metrics>>,
+    ScopedUser(user_id, scopes): ScopedUser,
+    RequestDevice(device): RequestDevice,
+    audit: AuditContext,
+    Negotiated {
+        encoding,
+        value: request,
+    }: Negotiated<SyncRequest>,
+) -> Result<
+    (
+        Option<WriteBudget>,
+        ChecksumStatus,
+        Negotiated<Versioned<SyncResponse>>,
+    ),
+    AppError,
+> {
+    check_scopes(&scopes, &request)?;
+    let writes = request.uploads.len() + request.deletions.len();
+    let budget = WriteBudget::spend(&tenant, &user_id, writes).await?;
+    let (checksum_status, response) = sync(
+        &tenant,
+        &events,
+        &metrics,
+        user_id.clone(),
+        &scopes,
+        &audit,
+        device.as_ref(),
+        request,
+    )
+    .await?;
+    if let Some(device) = &device {
+        record_device(&tenant, &user_id, device, &response).await;
     }
+    Ok((
+        budget,
+        checksum_status,
+        Negotiated {
+            encoding,
+            value: Versioned::new(response),
+        },
+    ))
 }
 
-#[derive(Serialize)]
-pub struct SyncResponse {
-    server_manifest: Vec<DataManifestEntry>,
-    downloads: Vec<DownloadEntry>,
-    uploaded: Vec<UploadResult>,
-    errors: Vec<SyncError>,
+/// Records that `device` synced, as the last writer of whatever the sync
+/// uploaded or deleted.
+pub(crate) async fn record_device(
+    tenant: &Tenant,
+    user_id: &str,
+    device: &ClientDevice,
+    response: &SyncResponse,
+) {
+    let keys: Vec<String> = response
+        .uploaded
+        .iter()
+        .map(|u| u.key.clone())
+        .chain(response.deleted.iter().map(|d| d.key.clone()))
+        .collect();
+    let now = chrono::Utc::now().timestamp_millis();
+    devices::record_write(&tenant.db, user_id, device, &keys, now).await;
 }
 
-#[derive(Serialize)]
-pub struct DownloadEntry {
-    key: String,
-    #[serde(with = "base64_serde")]
-    value: Vec<u8>,
-    version: i64,
-    checksum: String,
+/// Refuses a sync that would touch keys `scopes` does not allow, before
+/// any of its writes are paid for. Syncs always read.
+pub(crate) fn check_scopes(scopes: &TokenScopes, request: &SyncRequest) -> Result<(), AppError> {
+    scopes.require(Scope::Read)?;
+    for upload in &request.uploads {
+        scopes.require_key(Scope::Write, &upload.key)?;
+        if request.conflict_policy == ConflictPolicy::RecordConflict {
+            scopes.require_key(
+                Scope::Write,
+                &format!("{}{}", CONFLICT_KEY_PREFIX, upload.key),
+            )?;
+        }
+    }
+    for deletion in &request.deletions {
+        scopes.require_key(Scope::Delete, &deletion.key)?;
+    }
+    Ok(())
 }
 
-#[derive(Serialize)]
-pub struct UploadResult {
-    key: String,
-    version: i64,
-    checksum: String,
+/// Whether a sync sends the values the client is missing or only names
+/// them, for the client to fetch page by page.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Downloads {
+    Inline,
+    Deferred,
 }
 
-#[derive(Serialize)]
-pub struct SyncError {
-    key: String,
-    error: String,
+/// Runs a sync for `user_id` whose writes have already been paid for and
+/// whose keys have passed [`check_scopes`]; shared with the gRPC `Sync`
+/// stream. Keys outside `scopes` are left out of the server manifest. The
+/// sync is recorded in the user's history as made by `device`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn sync(
+    tenant: &Tenant,
+    events: &EventBus,
+    metrics: &Metrics,
+    user_id: String,
+    scopes: &TokenScopes,
+    audit: &AuditContext,
+    device: Option<&ClientDevice>,
+    request: SyncRequest,
+) -> Result<(ChecksumStatus, SyncResponse), AppError> {
+    let (checksum_status, response, _) = run_sync(
+        tenant,
+        events,
+        metrics,
+        user_id,
+        scopes,
+        audit,
+        device,
+        request,
+        Downloads::Inline,
+    )
+    .await?;
+    Ok((checksum_status, response))
 }
 
-pub async fn delta_sync(
-    Extension(db): Extension<DatabaseService>,
-    Extension(user_id): Extension<String>,
-    Json(request): Json<SyncRequest>,
-) -> impl IntoResponse {
-    let server_manifest = match db.get_data_manifest(&user_id).await {
-        Ok(m) => m,
-        Err(e) => {
-            error!("Failed to get manifest: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Database error"})),
-            )
-                .into_response();
-        }
-    };
+/// [`sync`] that, with [`Downloads::Deferred`], leaves `downloads` empty and
+/// returns the keys the client is missing instead, in key order.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run_sync(
+    tenant: &Tenant,
+    events: &EventBus,
+    metrics: &Metrics,
+    user_id: String,
+    scopes: &TokenScopes,
+    audit: &AuditContext,
+    device: Option<&ClientDevice>,
+    request: SyncRequest,
+    mode: Downloads,
+) -> Result<(ChecksumStatus, SyncResponse, Vec<PendingKey>), AppError> {
+    let started = Instant::now();
+    let started_at = chrono::Utc::now().timestamp_millis();
+    let db = &tenant.db;
+    let config = tenant.config.load();
+    let checksum_algorithm = config.checksum_algorithm;
+
+    let mut server_manifest = db
+        .get_data_manifest(&user_id)
+        .await
+        .or_internal("Database error")?;
+    server_manifest.retain(|entry| scopes.covers(&entry.key));
 
     let mut downloads = Vec::with_capacity(server_manifest.len());
     let mut uploaded = Vec::with_capacity(request.uploads.len());
+    let mut conflicts = Vec::new();
     let mut errors = Vec::new();
+    let mut deletions = 0;
+    let mut bytes_uploaded = 0;
+
+    let manifest_index: HashMap<String, usize> = server_manifest
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (e.key.clone(), i))
+        .collect();
+
+    for deletion in &request.deletions {
+        if let Err(e) = config.namespaces.check_key(&deletion.key) {
+            errors.push(SyncError {
+                key: deletion.key.clone(),
+                error: e.message(),
+            });
+            continue;
+        }
+
+        let Some(&index) = manifest_index.get(&deletion.key) else {
+            continue;
+        };
+        let entry = &mut server_manifest[index];
+        if entry.deleted || entry.version > deletion.version {
+            continue;
+        }
+
+        let result = db.delete_data_key(&user_id, &deletion.key).await;
+        audit
+            .record(
+                db,
+                AuditActor::User,
+                Some(&user_id),
+                AuditAction::DeleteDataKey,
+                Some(deletion.key.clone()),
+                result.is_ok(),
+            )
+            .await;
+
+        match result {
+            Ok(Some(version)) => {
+                deletions += 1;
+                let now = chrono::Utc::now().timestamp_millis();
+                entry.version = version;
+                entry.checksum = String::new();
+                entry.size_bytes = 0;
+                entry.updated_at = now;
+                entry.deleted = true;
+                entry.deleted_at = Some(now);
+                events.publish(Event::DataDeleted {
+                    user_id: user_id.clone(),
+                    key: deletion.key.clone(),
+                    version,
+                });
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to delete data key: {}", e);
+                errors.push(SyncError {
+                    key: deletion.key.clone(),
+                    error: "Failed to delete".into(),
+                });
+            }
+        }
+    }
 
     let server_map: HashMap<&str, &DataManifestEntry> = server_manifest
         .iter()
@@ -114,23 +381,50 @@ pub async fn delta_sync(
     let keys_to_download: Vec<String> = server_manifest
         .iter()
         .filter(|s| {
-            !client_map
-                .get(s.key.as_str())
-                .is_some_and(|c| c.version >= s.version && c.checksum == s.checksum)
+            !s.deleted
+                && !client_map.get(s.key.as_str()).is_some_and(|c| {
+                    c.version >= s.version && checksum::same(&c.checksum, &s.checksum)
+                })
         })
         .map(|s| s.key.clone())
         .collect();
 
-    if !keys_to_download.is_empty() {
+    let deleted: Vec<DeletedEntry> = server_manifest
+        .iter()
+        .filter(|s| s.deleted && client_map.contains_key(s.key.as_str()))
+        .map(|s| DeletedEntry {
+            key: s.key.clone(),
+            version: s.version,
+            deleted_at: s.deleted_at,
+        })
+        .collect();
+
+    let mut checksum_status = ChecksumStatus::Verified;
+    let mut pending = Vec::new();
+    if mode == Downloads::Deferred {
+        pending = keys_to_download
+            .into_iter()
+            .map(|key| PendingKey {
+                size_bytes: server_map.get(key.as_str()).map_or(0, |e| e.size_bytes),
+                key,
+            })
+            .collect();
+        pending.sort_by(|a: &PendingKey, b| a.key.cmp(&b.key));
+    } else if !keys_to_download.is_empty() {
         match db.get_data_keys(&user_id, &keys_to_download).await {
             Ok(entries) => {
+                let (entries, corrupt) =
+                    integrity::verify_all(db, metrics, &user_id, entries).await;
+                if !corrupt.is_empty() {
+                    checksum_status = ChecksumStatus::Mismatch;
+                }
+                errors.extend(corrupt.into_iter().map(|key| SyncError {
+                    key,
+                    error: "Stored value is corrupt".into(),
+                }));
                 for entry in entries {
-                    downloads.push(DownloadEntry {
-                        key: entry.key,
-                        value: entry.value,
-                        version: entry.version,
-                        checksum: entry.checksum,
-                    });
+                    let client = client_map.get(entry.key.as_str()).copied();
+                    downloads.push(DownloadEntry::new(entry, client));
                 }
             }
             Err(e) => {
@@ -146,68 +440,111 @@ pub async fn delta_sync(
     }
 
     let current_size: i64 = server_manifest.iter().map(|e| e.size_bytes as i64).sum();
-    let max_size = CONFIG.max_backup_size_bytes as i64;
+    let max_size = db
+        .storage_quota(&user_id)
+        .await
+        .or_internal("Database error")?;
     let mut running_size = current_size;
+    let max_keys = config.max_keys_per_user;
+    let live_keys = if max_keys > 0 && !request.uploads.is_empty() {
+        db.get_storage_usage(&user_id)
+            .await
+            .or_internal("Database error")?
+            .data_keys as u64
+    } else {
+        0
+    };
+    let mut allowance = KeyAllowance::new(max_keys, live_keys);
 
-    let mut valid_uploads: Vec<(String, Vec<u8>, String)> =
-        Vec::with_capacity(request.uploads.len());
+    let mut valid_uploads: Vec<DataUpload> = Vec::with_capacity(request.uploads.len());
     let mut keys_to_check: Vec<String> = Vec::with_capacity(request.uploads.len());
+    let mut over_quota = false;
+    let mut mismatched = false;
 
-    for upload in request.uploads {
-        if let Err(e) = validate_key(&upload.key) {
+    for mut upload in request.uploads {
+        if let Err(e) = config.namespaces.check_key(&upload.key) {
             errors.push(SyncError {
                 key: upload.key,
-                error: e.message().into(),
+                error: e.message(),
             });
             continue;
         }
 
-        if !CONFIG.datastore_enabled && upload.key.starts_with("dataStore/") {
-            errors.push(SyncError {
-                key: upload.key,
-                error: "DataStore sync is disabled".into(),
-            });
-            continue;
-        }
-
-        let key_max_size = if upload.key.starts_with("dataStore/") {
-            CONFIG.max_datastore_key_size_bytes
-        } else {
-            CONFIG.max_key_size_bytes
+        let ttl_secs = match config.namespaces.resolve_ttl(&upload.key, upload.ttl) {
+            Ok(ttl) => ttl,
+            Err(e) => {
+                errors.push(SyncError {
+                    key: upload.key,
+                    error: e.message(),
+                });
+                continue;
+            }
         };
 
-        if upload.value.len() > key_max_size {
-            let limit_mb = key_max_size / 1024 / 1024;
+        if let Err(e) = config
+            .namespaces
+            .validate_write(&upload.key, upload.value.len())
+        {
             errors.push(SyncError {
                 key: upload.key,
-                error: format!("Value exceeds {}MB limit", limit_mb),
+                error: e.message(),
             });
             continue;
         }
 
-        let checksum = match &upload.checksum {
-            Some(provided) => {
-                let computed = compute_checksum(&upload.value);
-                if computed != *provided {
-                    errors.push(SyncError {
-                        key: upload.key,
-                        error: "Checksum mismatch".into(),
-                    });
-                    continue;
-                }
-                computed
+        let checksum = match checksum::verify_upload(
+            upload.checksum.as_deref(),
+            &upload.value,
+            checksum_algorithm,
+        ) {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                mismatched |= e == ChecksumError::Mismatch;
+                errors.push(SyncError {
+                    key: upload.key,
+                    error: e.to_string(),
+                });
+                continue;
             }
-            None => compute_checksum(&upload.value),
         };
 
-        let dominated_by_server = server_map.get(upload.key.as_str()).is_some_and(|s| {
+        let dominated_by = server_map.get(upload.key.as_str()).filter(|s| {
             client_map
                 .get(upload.key.as_str())
                 .is_none_or(|c| c.version <= s.version)
         });
 
-        if dominated_by_server {
-            continue;
+        if let Some(server) = dominated_by {
+            if checksum::matches(&server.checksum, &upload.value) {
+                continue;
+            }
+
+            let mut conflict = SyncConflict {
+                key: upload.key.clone(),
+                server_version: server.version,
+                server_checksum: server.checksum.clone(),
+                recorded_as: None,
+            };
+            match request.conflict_policy {
+                ConflictPolicy::ServerWins => {
+                    conflicts.push(conflict);
+                    continue;
+                }
+                ConflictPolicy::ClientWins => {}
+                ConflictPolicy::RecordConflict => {
+                    let conflict_key = format!("{}{}", CONFLICT_KEY_PREFIX, upload.key);
+                    if let Err(e) = config.namespaces.check_key(&conflict_key) {
+                        errors.push(SyncError {
+                            key: upload.key,
+                            error: e.message(),
+                        });
+                        continue;
+                    }
+                    conflict.recorded_as = Some(conflict_key.clone());
+                    conflicts.push(conflict);
+                    upload.key = conflict_key;
+                }
+            }
         }
 
         let existing_size = server_map
@@ -217,6 +554,7 @@ pub async fn delta_sync(
 
         let new_running = running_size - existing_size + upload.value.len() as i64;
         if new_running > max_size {
+            over_quota = true;
             errors.push(SyncError {
                 key: upload.key,
                 error: "Total storage limit exceeded".into(),
@@ -224,52 +562,74 @@ pub async fn delta_sync(
             continue;
         }
 
+        let exists = server_map
+            .get(upload.key.as_str())
+            .is_some_and(|e| !e.deleted);
+        if !exists && let Err(e) = allowance.take() {
+            errors.push(SyncError {
+                key: upload.key,
+                error: e.message(),
+            });
+            continue;
+        }
+
         running_size = new_running;
         keys_to_check.push(upload.key.clone());
-        valid_uploads.push((upload.key, upload.value, checksum));
+        valid_uploads.push(DataUpload {
+            key: upload.key,
+            value: upload.value,
+            checksum,
+            ttl_secs,
+        });
+    }
+
+    if mismatched {
+        abuse::report(tenant, &user_id, Violation::ChecksumMismatch).await;
+    }
+    if over_quota {
+        abuse::report(tenant, &user_id, Violation::QuotaExceeded).await;
+        events.publish(Event::QuotaExceeded {
+            user_id: user_id.clone(),
+        });
     }
 
-    let mut updated_keys: HashMap<String, (i64, String, i32)> = HashMap::new();
+    let mut updated_keys: HashMap<String, (i64, String, i32, Option<i64>)> = HashMap::new();
 
     if !valid_uploads.is_empty() {
-        let upload_info: HashMap<String, (String, i32)> = valid_uploads
+        let upload_info: HashMap<String, (String, i32, Option<i32>)> = valid_uploads
             .iter()
-            .map(|(k, v, c)| (k.clone(), (c.clone(), v.len() as i32)))
+            .map(|u| {
+                (
+                    u.key.clone(),
+                    (u.checksum.clone(), u.value.len() as i32, u.ttl_secs),
+                )
+            })
             .collect();
 
-        match db.get_versions_batch(&user_id, &keys_to_check).await {
-            Ok(existing_versions) => {
-                match db
-                    .save_data_keys_batch(&user_id, valid_uploads, &existing_versions)
-                    .await
-                {
-                    Ok(saved) => {
-                        for (key, version, _) in saved {
-                            if let Some((checksum, size)) = upload_info.get(&key) {
-                                updated_keys
-                                    .insert(key.clone(), (version, checksum.clone(), *size));
-                                uploaded.push(UploadResult {
-                                    key,
-                                    version,
-                                    checksum: checksum.clone(),
-                                });
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to save batch: {}", e);
-                        for key in keys_to_check {
-                            errors.push(SyncError {
-                                key,
-                                error: "Failed to save".into(),
-                            });
-                        }
+        match db.save_data_keys_batch(&user_id, valid_uploads).await {
+            Ok(saved) => {
+                for (key, version, updated_at) in saved {
+                    events.publish(Event::DataWritten {
+                        user_id: user_id.clone(),
+                        key: key.clone(),
+                        version,
+                    });
+                    if let Some((checksum, size, ttl)) = upload_info.get(&key) {
+                        bytes_uploaded += *size as i64;
+                        let expires_at = ttl.map(|ttl| updated_at + ttl as i64 * 1000);
+                        updated_keys
+                            .insert(key.clone(), (version, checksum.clone(), *size, expires_at));
+                        uploaded.push(UploadResult {
+                            key,
+                            version,
+                            checksum: checksum.clone(),
+                        });
                     }
                 }
             }
             Err(e) => {
-                error!("Failed to get versions batch: {}", e);
-                for (key, _, _) in valid_uploads {
+                error!("Failed to save batch: {}", e);
+                for key in keys_to_check {
                     errors.push(SyncError {
                         key,
                         error: "Failed to save".into(),
@@ -286,33 +646,76 @@ pub async fn delta_sync(
         let mut manifest: Vec<DataManifestEntry> = server_manifest
             .into_iter()
             .map(|mut e| {
-                if let Some((version, checksum, size)) = updated_keys.remove(&e.key) {
+                if let Some((version, checksum, size, expires_at)) = updated_keys.remove(&e.key) {
                     e.version = version;
                     e.checksum = checksum;
                     e.size_bytes = size;
                     e.updated_at = now;
+                    e.deleted = false;
+                    e.deleted_at = None;
+                    e.expires_at = expires_at;
                 }
                 e
             })
             .collect();
 
-        for (key, (version, checksum, size_bytes)) in updated_keys {
+        for (key, (version, checksum, size_bytes, expires_at)) in updated_keys {
             manifest.push(DataManifestEntry {
                 key,
                 version,
                 checksum,
                 size_bytes,
                 updated_at: now,
+                deleted: false,
+                deleted_at: None,
+                expires_at,
+                last_writer: None,
             });
         }
         manifest
     };
 
-    Json(SyncResponse {
-        server_manifest: final_manifest,
-        downloads,
-        uploaded,
-        errors,
-    })
-    .into_response()
+    stats::count_sync(db).await;
+    let (download_count, bytes_downloaded) = match mode {
+        Downloads::Inline => (
+            downloads.len(),
+            downloads
+                .iter()
+                .map(|d| d.value.as_ref().or(d.patch.as_ref()).map_or(0, Vec::len) as i64)
+                .sum(),
+        ),
+        Downloads::Deferred => (
+            pending.len(),
+            pending.iter().map(|p| p.size_bytes as i64).sum(),
+        ),
+    };
+    let session = SyncSession {
+        started_at,
+        duration_ms: started.elapsed().as_millis() as i64,
+        device_id: device.map(|d| d.device_id.clone()),
+        client_name: device.and_then(|d| d.client_name.clone()),
+        uploads: uploaded.len() as u32,
+        downloads: download_count as u32,
+        deletions,
+        conflicts: conflicts.len() as u32,
+        errors: errors.len() as u32,
+        bytes_uploaded,
+        bytes_downloaded,
+    };
+    sync_history::record(db, &config, &user_id, &session).await;
+    Ok((
+        checksum_status,
+        SyncResponse {
+            server_manifest: final_manifest
+                .into_iter()
+                .map(ManifestEntry::from)
+                .collect(),
+            downloads,
+            uploaded,
+            deleted,
+            conflicts,
+            errors,
+        },
+        pending,
+    ))
 }
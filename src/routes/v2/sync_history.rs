@@ -0,0 +1,57 @@
+use axum::{Json, extract::Query};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use equicloud::constants::{DEFAULT_SYNC_HISTORY_SESSIONS, MAX_SYNC_HISTORY_SESSIONS};
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::{Datastore, SyncSession};
+
+use super::dto::Versioned;
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::TenantDb;
+
+#[derive(Deserialize, IntoParams)]
+pub struct SyncHistoryQuery {
+    /// How many syncs to return, 20 unless given.
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SyncHistoryResponse {
+    /// Newest first.
+    sessions: Vec<SyncSession>,
+}
+
+/// The user's recent syncs through `/v2/sync`, `/v3/sync` and gRPC, with
+/// what each moved and how long it took, for debugging a client's sync.
+/// Syncs are kept for `SYNC_HISTORY_RETENTION`.
+#[utoipa::path(
+    get,
+    path = "/v2/sync/history",
+    tag = "data",
+    security(("token" = [])),
+    params(SyncHistoryQuery),
+    responses(
+        (status = 200, description = "The user's recent syncs", body = Versioned<SyncHistoryResponse>),
+        (status = 400, description = "Invalid limit", body = ErrorBody),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody)
+    )
+)]
+pub async fn get_sync_history(
+    TenantDb(db): TenantDb,
+    AuthUser(user_id): AuthUser,
+    Query(query): Query<SyncHistoryQuery>,
+) -> Result<Json<Versioned<SyncHistoryResponse>>, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_SYNC_HISTORY_SESSIONS);
+    if limit == 0 || limit > MAX_SYNC_HISTORY_SESSIONS {
+        return Err(AppError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_SYNC_HISTORY_SESSIONS
+        )));
+    }
+    let sessions = db
+        .list_sync_sessions(&user_id, limit)
+        .await
+        .or_internal("Failed to list syncs")?;
+    Ok(Json(Versioned::new(SyncHistoryResponse { sessions })))
+}
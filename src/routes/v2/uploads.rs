@@ -0,0 +1,365 @@
+//! Multipart uploads, for values too large to send reliably in one request.
+//!
+//! A client creates an upload for a key and the size of the value, sends the
+//! value in `part_size` chunks in any order, resending only the ones that
+//! failed, and completes the upload to write the assembled value as
+//! `PUT /v2/data/{key}` would. Unfinished uploads expire after
+//! `UPLOAD_SESSION_TTL`.
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use equicloud::abuse::{self, Violation};
+use equicloud::checksum;
+use equicloud::constants::MAX_OPEN_UPLOADS;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+use equicloud::key_limit;
+use equicloud::validation::{OCTET_STREAM, require_content_type};
+use equicloud::write_budget::WriteBudget;
+use equicloud::{Datastore, Event, EventBus, Storage, UploadPart, UploadSession};
+
+use super::data::{DataWritten, verify_checksum};
+use super::dto::CreateUploadRequest;
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::{CurrentTenant, TenantDb};
+use crate::routes::openapi::Binary;
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadStatus {
+    id: String,
+    key: String,
+    size: i64,
+    /// Size of every part but the last.
+    part_size: i32,
+    part_count: i32,
+    expires_at: i64,
+    /// Parts received so far.
+    parts: Vec<UploadPartStatus>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadPartStatus {
+    part: i32,
+    size: i32,
+    checksum: String,
+}
+
+impl UploadStatus {
+    fn new(upload: UploadSession, parts: Vec<UploadPart>) -> Self {
+        Self {
+            part_count: upload.part_count(),
+            id: upload.id,
+            key: upload.key,
+            size: upload.size_bytes,
+            part_size: upload.part_size,
+            expires_at: upload.expires_at,
+            parts: parts
+                .into_iter()
+                .map(|part| UploadPartStatus {
+                    part: part.number,
+                    size: part.size_bytes,
+                    checksum: part.checksum,
+                })
+                .collect(),
+        }
+    }
+}
+
+async fn find_upload(db: &Storage, user_id: &str, id: &str) -> Result<UploadSession, AppError> {
+    db.get_upload(user_id, id)
+        .await
+        .or_internal("Failed to get upload")?
+        .ok_or(AppError::NotFound)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/uploads",
+    tag = "data",
+    security(("token" = [])),
+    request_body = CreateUploadRequest,
+    responses(
+        (status = 201, description = "The upload was created", body = UploadStatus),
+        (status = 400, description = "Invalid key, size or TTL, or too many open uploads", body = ErrorBody),
+        (status = 413, description = "The value or the user's total storage is too large, or the key would exceed MAX_KEYS_PER_USER", body = ErrorBody)
+    )
+)]
+pub async fn create_upload(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    AuthUser(user_id): AuthUser,
+    Json(request): Json<CreateUploadRequest>,
+) -> Result<(StatusCode, Json<UploadStatus>), AppError> {
+    let config = tenant.config.load();
+    config.namespaces.check_key(&request.key)?;
+    let ttl_secs = config
+        .namespaces
+        .resolve_ttl(&request.key, request.ttl_secs)?;
+    let db = &tenant.db;
+
+    if request.size == 0 {
+        return Err(AppError::BadRequest(
+            "Upload size must be greater than zero".into(),
+        ));
+    }
+    let size = usize::try_from(request.size).unwrap_or(usize::MAX);
+    config.namespaces.validate_write(&request.key, size)?;
+    let checksum = request
+        .checksum
+        .as_deref()
+        .map(checksum::normalize)
+        .transpose()?;
+
+    let quota = db
+        .storage_quota(&user_id)
+        .await
+        .or_internal("Failed to get quota")?;
+    if request.size as i64 > quota {
+        abuse::report(&tenant, &user_id, Violation::QuotaExceeded).await;
+        events.publish(Event::QuotaExceeded { user_id });
+        return Err(AppError::QuotaExceeded);
+    }
+
+    let open = db
+        .list_uploads(&user_id)
+        .await
+        .or_internal("Failed to list uploads")?;
+    if open.len() >= MAX_OPEN_UPLOADS {
+        return Err(AppError::BadRequest(format!(
+            "At most {} uploads may be open at once",
+            MAX_OPEN_UPLOADS
+        )));
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let upload = UploadSession {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        key: request.key,
+        size_bytes: request.size as i64,
+        part_size: config.upload_part_size as i32,
+        checksum,
+        ttl_secs,
+        created_at: now,
+        expires_at: now + config.upload_session_ttl.as_millis() as i64,
+    };
+    db.create_upload(&user_id, &upload)
+        .await
+        .or_internal("Failed to create upload")?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(UploadStatus::new(upload, Vec::new())),
+    ))
+}
+
+/// Lists the parts received so far, so an interrupted client knows which
+/// ones to send again.
+#[utoipa::path(
+    get,
+    path = "/v2/uploads/{id}",
+    tag = "data",
+    security(("token" = [])),
+    params(("id" = String, Path, description = "Upload id")),
+    responses(
+        (status = 200, description = "The upload and its received parts", body = UploadStatus),
+        (status = 404, description = "The upload does not exist or has expired", body = ErrorBody)
+    )
+)]
+pub async fn get_upload(
+    TenantDb(db): TenantDb,
+    AuthUser(user_id): AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<UploadStatus>, AppError> {
+    let upload = find_upload(&db, &user_id, &id).await?;
+    let parts = db
+        .list_upload_parts(&upload.id)
+        .await
+        .or_internal("Failed to list parts")?;
+    Ok(Json(UploadStatus::new(upload, parts)))
+}
+
+/// Stores one part, replacing an earlier copy of it. Every part but the last
+/// must be exactly `part_size` bytes.
+#[utoipa::path(
+    put,
+    path = "/v2/uploads/{id}/parts/{part}",
+    tag = "data",
+    security(("token" = [])),
+    params(
+        ("id" = String, Path, description = "Upload id"),
+        ("part" = i32, Path, description = "Part number, starting at 1"),
+        ("X-Checksum" = Option<String>, Header, description = "Checksum of the part as `sha256:<hex>` or `xxh3:<hex>`")
+    ),
+    request_body(content = Binary, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "The part was stored", body = UploadPartStatus),
+        (status = 400, description = "Wrong part number, size or checksum", body = ErrorBody),
+        (status = 404, description = "The upload does not exist or has expired", body = ErrorBody),
+        (status = 415, description = "Body is not application/octet-stream", body = ErrorBody)
+    )
+)]
+pub async fn put_upload_part(
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+    Path((id, number)): Path<(String, i32)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<UploadPartStatus>, AppError> {
+    require_content_type(&headers, OCTET_STREAM)?;
+
+    let db = &tenant.db;
+    let upload = find_upload(db, &user_id, &id).await?;
+    let expected = upload.part_len(number).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "Part number must be between 1 and {}",
+            upload.part_count()
+        ))
+    })?;
+    if body.len() as i64 != expected {
+        return Err(AppError::BadRequest(format!(
+            "Part {} must be {} bytes",
+            number, expected
+        )));
+    }
+
+    let claimed = headers.get("x-checksum").and_then(|h| h.to_str().ok());
+    let checksum = verify_checksum(&tenant, &user_id, claimed, &body).await?;
+
+    let size = body.len() as i32;
+    db.save_upload_part(&upload, number, body.into(), &checksum)
+        .await
+        .or_internal("Failed to save part")?;
+
+    Ok(Json(UploadPartStatus {
+        part: number,
+        size,
+        checksum,
+    }))
+}
+
+/// Assembles the parts and writes the value to the upload's key. The upload
+/// stays open when this fails, so missing or damaged parts can be sent again.
+#[utoipa::path(
+    post,
+    path = "/v2/uploads/{id}/complete",
+    tag = "data",
+    security(("token" = [])),
+    params(("id" = String, Path, description = "Upload id")),
+    responses(
+        (status = 200, description = "The value was saved", body = DataWritten),
+        (status = 400, description = "Parts are missing or damaged, or the value does not match its checksum", body = ErrorBody),
+        (status = 404, description = "The upload does not exist or has expired", body = ErrorBody),
+        (status = 413, description = "The user's total storage is too large", body = ErrorBody),
+        (status = 429, description = "The user's DAILY_WRITE_LIMIT is used up", body = ErrorBody)
+    )
+)]
+pub async fn complete_upload(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    AuthUser(user_id): AuthUser,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let db = &tenant.db;
+    let upload = find_upload(db, &user_id, &id).await?;
+    tenant.config.load().namespaces.check_key(&upload.key)?;
+
+    let parts = db
+        .list_upload_parts(&upload.id)
+        .await
+        .or_internal("Failed to list parts")?;
+    let missing: Vec<String> = (1..=upload.part_count())
+        .filter(|number| !parts.iter().any(|part| part.number == *number))
+        .map(|number| number.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(AppError::BadRequest(format!(
+            "Missing parts: {}",
+            missing.join(", ")
+        )));
+    }
+
+    let mut value = Vec::with_capacity(upload.size_bytes as usize);
+    for part in &parts {
+        let bytes = db
+            .get_upload_part(&upload.id, part.number)
+            .await
+            .or_internal("Failed to read part")?
+            .ok_or(AppError::Internal("Failed to read part"))?;
+        if !checksum::matches(&part.checksum, &bytes) {
+            return Err(AppError::BadRequest(format!(
+                "Part {} is damaged, upload it again",
+                part.number
+            )));
+        }
+        value.extend_from_slice(&bytes);
+    }
+
+    let checksum = verify_checksum(&tenant, &user_id, upload.checksum.as_deref(), &value).await?;
+    key_limit::check_new_key(&tenant, &user_id, &upload.key).await?;
+
+    let budget = WriteBudget::spend(&tenant, &user_id, 1).await?;
+    let saved = db
+        .save_data_key_with_quota_check(&user_id, &upload.key, value, &checksum, upload.ttl_secs)
+        .await
+        .or_internal("Failed to save data")?;
+    let Some((version, updated_at)) = saved else {
+        abuse::report(&tenant, &user_id, Violation::QuotaExceeded).await;
+        events.publish(Event::QuotaExceeded { user_id });
+        return Err(AppError::QuotaExceeded);
+    };
+
+    // Left behind, the parts expire with the upload.
+    if let Err(e) = db.delete_upload(&user_id, &upload.id).await {
+        warn!("Failed to delete completed upload: {}", e);
+    }
+
+    events.publish(Event::DataWritten {
+        user_id,
+        key: upload.key,
+        version,
+    });
+
+    let expires_at = upload.ttl_secs.map(|ttl| updated_at + ttl as i64 * 1000);
+
+    Ok((
+        budget,
+        Json(DataWritten {
+            version,
+            checksum,
+            updated_at,
+            expires_at,
+        }),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v2/uploads/{id}",
+    tag = "data",
+    security(("token" = [])),
+    params(("id" = String, Path, description = "Upload id")),
+    responses(
+        (status = 204, description = "The upload and its parts were dropped"),
+        (status = 404, description = "The upload does not exist or has expired", body = ErrorBody)
+    )
+)]
+pub async fn abort_upload(
+    TenantDb(db): TenantDb,
+    AuthUser(user_id): AuthUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let upload = find_upload(&db, &user_id, &id).await?;
+    db.delete_upload(&user_id, &upload.id)
+        .await
+        .or_internal("Failed to delete upload")?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,107 @@
+use axum::Json;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+use equicloud::Datastore;
+use equicloud::error::{AppError, ErrorBody, ResultExt};
+
+use crate::middleware::auth::AuthUser;
+use crate::middleware::tenant::CurrentTenant;
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageResponse {
+    /// Size of the v1 settings backup, 0 when none is stored.
+    settings_size_bytes: i64,
+    /// Live data keys, counted against `max_keys`.
+    data_keys: i64,
+    /// Live data keys the user may store; 0 is unlimited.
+    max_keys: u64,
+    data_size_bytes: i64,
+    /// Live keys by top-level prefix, sorted by prefix.
+    prefixes: Vec<PrefixUsage>,
+    /// Settings and data together.
+    used_bytes: i64,
+    quota_bytes: i64,
+    #[schema(example = 12.5)]
+    percent_used: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PrefixUsage {
+    /// Everything up to and including the first `/` of the keys; empty for
+    /// keys without one.
+    #[schema(example = "dataStore/")]
+    prefix: String,
+    keys: i64,
+    size_bytes: i64,
+}
+
+fn top_level_prefix(key: &str) -> &str {
+    key.find('/').map_or("", |i| &key[..=i])
+}
+
+/// How much of their quota the user has used, split into the settings
+/// backup and the data under each top-level key prefix.
+#[utoipa::path(
+    get,
+    path = "/v2/usage",
+    tag = "account",
+    security(("token" = [])),
+    responses(
+        (status = 200, description = "The user's storage usage", body = UsageResponse),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody)
+    )
+)]
+pub async fn get_usage(
+    CurrentTenant(tenant): CurrentTenant,
+    AuthUser(user_id): AuthUser,
+) -> Result<Json<UsageResponse>, AppError> {
+    let db = &tenant.db;
+    let settings_size_bytes = db
+        .get_user_settings(&user_id)
+        .await
+        .or_internal("Failed to retrieve settings")?
+        .map_or(0, |(settings, _)| settings.len() as i64);
+    let manifest = db
+        .get_data_manifest(&user_id)
+        .await
+        .or_internal("Failed to get manifest")?;
+    let quota_bytes = db
+        .storage_quota(&user_id)
+        .await
+        .or_internal("Failed to get storage quota")?;
+
+    let mut prefixes: BTreeMap<&str, PrefixUsage> = BTreeMap::new();
+    for entry in manifest.iter().filter(|e| !e.deleted) {
+        let prefix = top_level_prefix(&entry.key);
+        let usage = prefixes.entry(prefix).or_insert_with(|| PrefixUsage {
+            prefix: prefix.to_string(),
+            keys: 0,
+            size_bytes: 0,
+        });
+        usage.keys += 1;
+        usage.size_bytes += entry.size_bytes as i64;
+    }
+    let prefixes: Vec<PrefixUsage> = prefixes.into_values().collect();
+
+    let data_keys = prefixes.iter().map(|p| p.keys).sum();
+    let data_size_bytes = prefixes.iter().map(|p| p.size_bytes).sum();
+    let used_bytes = settings_size_bytes + data_size_bytes;
+    let percent_used = if quota_bytes > 0 {
+        used_bytes as f64 * 100.0 / quota_bytes as f64
+    } else {
+        100.0
+    };
+
+    Ok(Json(UsageResponse {
+        settings_size_bytes,
+        data_keys,
+        max_keys: tenant.config.load().max_keys_per_user,
+        data_size_bytes,
+        prefixes,
+        used_bytes,
+        quota_bytes,
+        percent_used,
+    }))
+}
@@ -0,0 +1,36 @@
+use axum::{Router, handler::Handler, middleware::from_fn_with_state, routing::post};
+
+use crate::middleware::body_limit::{default_limit, json_upload_limit, limit_body};
+use crate::middleware::compression::response_compression;
+use crate::middleware::idempotency::replay_or_run;
+use crate::middleware::timeout::{bulk_timeout, with_timeout};
+use crate::state::AppState;
+
+pub mod sync;
+
+pub fn register(state: &AppState) -> Router<AppState> {
+    let tenants = &state.tenants;
+    let compression = || response_compression(&state.config.load());
+    let sync_routes = Router::new().route(
+        "/v3/sync",
+        post(
+            sync::paged_sync
+                .layer(from_fn_with_state(state.clone(), replay_or_run))
+                .layer(compression()),
+        ),
+    );
+    let download_routes = Router::new().route(
+        "/v3/sync/downloads",
+        post(sync::sync_downloads.layer(compression())),
+    );
+
+    with_timeout(
+        limit_body(sync_routes, tenants, json_upload_limit).merge(limit_body(
+            download_routes,
+            tenants,
+            default_limit,
+        )),
+        tenants,
+        bulk_timeout,
+    )
+}
@@ -0,0 +1,225 @@
+use axum::{Json, extract::State};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+use utoipa::ToSchema;
+
+use equicloud::constants::{MAX_SYNC_PAGE_KEYS, SYNC_CURSOR_TTL_SECS, SYNC_PAGE_BYTES};
+use equicloud::error::{AppError, ErrorBody};
+use equicloud::integrity::{self, ChecksumStatus};
+use equicloud::sync_cursor::{issue_sync_cursor, page_len, verify_sync_cursor};
+use equicloud::write_budget::WriteBudget;
+use equicloud::{Datastore, EventBus, Metrics};
+
+use crate::middleware::audit::AuditContext;
+use crate::middleware::auth::ScopedUser;
+use crate::middleware::device::RequestDevice;
+use crate::middleware::tenant::CurrentTenant;
+use crate::routes::v2::dto::{ManifestEntry, SyncRequest};
+use crate::routes::v2::encoding::Negotiated;
+use crate::routes::v2::sync::{
+    DeletedEntry, DownloadEntry, Downloads, SyncConflict, SyncError, UploadResult, check_scopes,
+    record_device, run_sync,
+};
+
+#[derive(Serialize, ToSchema)]
+pub struct PagedSyncResponse {
+    server_manifest: Vec<ManifestEntry>,
+    /// How many values the client is missing, to fetch from
+    /// `/v3/sync/downloads`.
+    pending_downloads: usize,
+    /// Their total size when the sync ran.
+    pending_bytes: i64,
+    /// Fetches the first page of downloads; absent when nothing is missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+    uploaded: Vec<UploadResult>,
+    deleted: Vec<DeletedEntry>,
+    conflicts: Vec<SyncConflict>,
+    errors: Vec<SyncError>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DownloadPageRequest {
+    cursor: String,
+    /// Page size the client can hold, capped at the server's
+    /// `sync_page_bytes`. A value larger than this still comes alone.
+    #[serde(default)]
+    max_bytes: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DownloadPage {
+    downloads: Vec<DownloadEntry>,
+    /// Keys that were in the page but are corrupt or could not be read.
+    /// Keys deleted since the sync are left out without an error.
+    errors: Vec<SyncError>,
+    /// Fetches the next page; absent on the last one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// [`/v2/sync`](crate::routes::v2::sync::delta_sync) for large accounts:
+/// applies uploads and deletions the same way, but instead of sending every
+/// missing value in the response, returns a cursor the client follows to
+/// fetch them page by page. Values are never sent as patches.
+#[utoipa::path(
+    post,
+    path = "/v3/sync",
+    tag = "data",
+    security(("token" = [])),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key"),
+        ("X-Device-Id" = Option<String>, Header, description = "Record this device as having synced, and as the last writer of the keys it uploaded or deleted"),
+        ("X-Client-Name" = Option<String>, Header, description = "Name to list the device under")
+    ),
+    request_body(content(
+        (SyncRequest = "application/json"),
+        (SyncRequest = "application/cbor")
+    )),
+    responses(
+        (status = 200, description = "The result of the sync, without the downloads", content(
+            (PagedSyncResponse = "application/json"),
+            (PagedSyncResponse = "application/cbor")
+        )),
+        (status = 400, description = "Invalid device headers", body = ErrorBody),
+        (status = 409, description = "The Idempotency-Key was used for a different request or is still in use", body = ErrorBody),
+        (status = 429, description = "The user's DAILY_WRITE_LIMIT cannot cover the uploads and deletions", body = ErrorBody)
+    )
+)]
+pub async fn paged_sync(
+    CurrentTenant(tenant): CurrentTenant,
+    State(events): State<EventBus>,
+    State(metrics): State<Arc<Metrics>>,
+    ScopedUser(user_id, scopes): ScopedUser,
+    RequestDevice(device): RequestDevice,
+    audit: AuditContext,
+    Negotiated {
+        encoding,
+        value: request,
+    }: Negotiated<SyncRequest>,
+) -> Result<(Option<WriteBudget>, Negotiated<PagedSyncResponse>), AppError> {
+    check_scopes(&scopes, &request)?;
+    let writes = request.uploads.len() + request.deletions.len();
+    let budget = WriteBudget::spend(&tenant, &user_id, writes).await?;
+    let (_, response, pending) = run_sync(
+        &tenant,
+        &events,
+        &metrics,
+        user_id.clone(),
+        &scopes,
+        &audit,
+        device.as_ref(),
+        request,
+        Downloads::Deferred,
+    )
+    .await?;
+    if let Some(device) = &device {
+        record_device(&tenant, &user_id, device, &response).await;
+    }
+
+    let cursor = (!pending.is_empty()).then(|| {
+        let expires_at = chrono::Utc::now().timestamp_millis() + SYNC_CURSOR_TTL_SECS * 1000;
+        issue_sync_cursor(&tenant.config.load(), &user_id, expires_at, &pending)
+    });
+    Ok((
+        budget,
+        Negotiated {
+            encoding,
+            value: PagedSyncResponse {
+                server_manifest: response.server_manifest,
+                pending_downloads: pending.len(),
+                pending_bytes: pending.iter().map(|p| p.size_bytes as i64).sum(),
+                cursor,
+                uploaded: response.uploaded,
+                deleted: response.deleted,
+                conflicts: response.conflicts,
+                errors: response.errors,
+            },
+        },
+    ))
+}
+
+/// Fetches the next page of values a `/v3/sync` found the client missing.
+/// Each value is read when its page is fetched, so a key written since the
+/// sync comes back at its newer version.
+#[utoipa::path(
+    post,
+    path = "/v3/sync/downloads",
+    tag = "data",
+    security(("token" = [])),
+    request_body = DownloadPageRequest,
+    responses(
+        (status = 200, description = "One page of downloads", body = DownloadPage,
+            headers(("X-Checksum-Status" = String,
+                description = "`verified`, or `mismatch` if a download was withheld because its stored value no longer matches its checksum"))),
+        (status = 400, description = "The cursor is invalid or belongs to another user", body = ErrorBody),
+        (status = 410, description = "The cursor has expired; sync again", body = ErrorBody)
+    )
+)]
+pub async fn sync_downloads(
+    CurrentTenant(tenant): CurrentTenant,
+    State(metrics): State<Arc<Metrics>>,
+    ScopedUser(user_id, scopes): ScopedUser,
+    Json(request): Json<DownloadPageRequest>,
+) -> Result<(ChecksumStatus, Json<DownloadPage>), AppError> {
+    let config = tenant.config.load();
+    let (expires_at, pending) = verify_sync_cursor(&config, &request.cursor, &user_id)
+        .ok_or_else(|| AppError::BadRequest("Invalid sync cursor".into()))?;
+    if expires_at <= chrono::Utc::now().timestamp_millis() {
+        return Err(AppError::Gone("The sync cursor has expired".into()));
+    }
+
+    let max_bytes = request
+        .max_bytes
+        .map_or(SYNC_PAGE_BYTES, |max| max.min(SYNC_PAGE_BYTES));
+    let len = page_len(&pending, max_bytes, MAX_SYNC_PAGE_KEYS);
+    let (page, rest) = pending.split_at(len);
+    let keys: Vec<String> = page
+        .iter()
+        .filter(|p| scopes.covers(&p.key))
+        .map(|p| p.key.clone())
+        .collect();
+
+    let db = &tenant.db;
+    let mut checksum_status = ChecksumStatus::Verified;
+    let mut downloads = Vec::new();
+    let mut errors = Vec::new();
+    if !keys.is_empty() {
+        match db.get_data_keys(&user_id, &keys).await {
+            Ok(entries) => {
+                let (entries, corrupt) =
+                    integrity::verify_all(db, &metrics, &user_id, entries).await;
+                if !corrupt.is_empty() {
+                    checksum_status = ChecksumStatus::Mismatch;
+                }
+                errors.extend(corrupt.into_iter().map(|key| SyncError {
+                    key,
+                    error: "Stored value is corrupt".into(),
+                }));
+                downloads.extend(
+                    entries
+                        .into_iter()
+                        .map(|entry| DownloadEntry::new(entry, None)),
+                );
+            }
+            Err(e) => {
+                error!("Failed to get data keys: {}", e);
+                errors.extend(keys.into_iter().map(|key| SyncError {
+                    key,
+                    error: "Failed to download".into(),
+                }));
+            }
+        }
+    }
+
+    let cursor = (!rest.is_empty()).then(|| issue_sync_cursor(&config, &user_id, expires_at, rest));
+    Ok((
+        checksum_status,
+        Json(DownloadPage {
+            downloads,
+            errors,
+            cursor,
+        }),
+    ))
+}
@@ -0,0 +1,87 @@
+use axum::extract::FromRef;
+use std::sync::Arc;
+
+use equicloud::utils::ConfigHandle;
+use equicloud::{DbHealth, EventBus, Metrics, Storage, Tenants};
+
+/// Shared dependencies handed to every handler through `State`. Handlers
+/// extract only the pieces they need via the `FromRef` impls below.
+#[derive(Clone)]
+pub struct AppState {
+    /// Storage of the default tenant; handlers serving users take
+    /// `TenantDb` instead.
+    pub db: Storage,
+    /// Configuration of the default tenant.
+    pub config: ConfigHandle,
+    pub tenants: Arc<Tenants>,
+    pub metrics: Arc<Metrics>,
+    pub events: EventBus,
+    pub db_health: Arc<DbHealth>,
+}
+
+impl AppState {
+    /// State with only the default tenant, stored in `db` and configured by
+    /// the environment.
+    #[cfg(test)]
+    pub fn new(db: Storage) -> Self {
+        use equicloud::Tenant;
+        use equicloud::tenant::DEFAULT_TENANT;
+        use equicloud::utils::CONFIG;
+
+        Self::with_tenants(Tenants::new(Tenant::new(
+            DEFAULT_TENANT,
+            CONFIG.clone(),
+            db,
+        )))
+    }
+
+    pub fn with_tenants(tenants: Tenants) -> Self {
+        let default = tenants.default_tenant();
+        let config = default.config.clone();
+        let threshold = config.load().db_circuit_breaker_threshold;
+        Self {
+            db: default.db.clone(),
+            config,
+            tenants: Arc::new(tenants),
+            metrics: Arc::new(Metrics::new()),
+            events: EventBus::new(),
+            db_health: Arc::new(DbHealth::new(threshold)),
+        }
+    }
+}
+
+impl FromRef<AppState> for Storage {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Tenants> {
+    fn from_ref(state: &AppState) -> Self {
+        state.tenants.clone()
+    }
+}
+
+impl FromRef<AppState> for ConfigHandle {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Metrics> {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<DbHealth> {
+    fn from_ref(state: &AppState) -> Self {
+        state.db_health.clone()
+    }
+}
+
+impl FromRef<AppState> for EventBus {
+    fn from_ref(state: &AppState) -> Self {
+        state.events.clone()
+    }
+}
@@ -0,0 +1,550 @@
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{Request, StatusCode};
+use std::net::SocketAddr;
+
+use equicloud::api_keys::{
+    API_KEY_PREFIX, ApiKeyScope, format_api_key, hash_api_key_secret, new_api_key,
+};
+use equicloud::auth_events::AuthOutcome;
+use equicloud::dev::{DEV_USER_HEADER, SAMPLE_USERS, seed};
+use equicloud::oauth::redirect::code_key;
+use equicloud::oauth::{
+    OAuthRedirect, RedirectMode, issue_scoped_session_secret, issue_session_secret,
+};
+use equicloud::scopes::TokenScopes;
+use equicloud::tenant::DEFAULT_TENANT;
+use equicloud::utils::{CONFIG, ConfigHandle, get_user_secret, hash_user_id};
+use equicloud::{ApiKey, Datastore, OAuthCode, SqliteDatastore, Storage, Tenant, Tenants};
+
+use super::{TestApp, TestResponse, base64, token, token_with_secret};
+use crate::state::AppState;
+
+async fn get_settings(app: &TestApp, authorization: Option<&str>) -> TestResponse {
+    let mut request = Request::get("/v1/settings");
+    if let Some(authorization) = authorization {
+        request = request.header("authorization", authorization);
+    }
+    app.send(request.body(Body::empty()).unwrap()).await
+}
+
+/// [`get_settings`] from the peer `peer`, claiming to be forwarded for
+/// `forwarded_for`.
+async fn get_settings_from(
+    app: &TestApp,
+    authorization: &str,
+    peer: &str,
+    forwarded_for: &str,
+) -> TestResponse {
+    let mut request = Request::get("/v1/settings")
+        .header("authorization", authorization)
+        .header("x-forwarded-for", forwarded_for)
+        .body(Body::empty())
+        .unwrap();
+    let peer = SocketAddr::new(peer.parse().unwrap(), 4000);
+    request.extensions_mut().insert(ConnectInfo(peer));
+    app.send(request).await
+}
+
+#[tokio::test]
+async fn test_missing_token() {
+    let app = TestApp::new();
+    let response = get_settings(&app, None).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.error_code(), "unauthorized");
+}
+
+#[tokio::test]
+async fn test_malformed_token() {
+    let app = TestApp::new();
+    for header in ["not base64!", "Bearer bm8gY29sb24="] {
+        let response = get_settings(&app, Some(header)).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{}", header);
+    }
+}
+
+#[tokio::test]
+async fn test_wrong_secret() {
+    let app = TestApp::new();
+    let response = get_settings(&app, Some(&token_with_secret("deadbeef", "1"))).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_session_for_another_user() {
+    let app = TestApp::new();
+    let expires_at = chrono::Utc::now().timestamp_millis() + 60_000;
+    let secret = issue_session_secret(CONFIG.load().session_secret.as_bytes(), "1", expires_at);
+    let response = get_settings(&app, Some(&token_with_secret(&secret, "2"))).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_expired_session() {
+    let app = TestApp::new();
+    let expires_at = chrono::Utc::now().timestamp_millis() - 1;
+    let secret = issue_session_secret(CONFIG.load().session_secret.as_bytes(), "1", expires_at);
+    let response = get_settings(&app, Some(&token_with_secret(&secret, "1"))).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_permanent_secret() {
+    let app = TestApp::new();
+    let authorization = token_with_secret(&get_user_secret("1"), "1");
+    let response = get_settings(&app, Some(&format!("Bearer {}", authorization))).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_users_are_isolated() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+
+    let other = app.get("/v2/data/plugins/a", "2").await;
+    assert_eq!(other.status, StatusCode::NOT_FOUND);
+
+    let manifest = app.get("/v2/manifest", "2").await.json();
+    assert!(manifest["entries"].as_array().unwrap().is_empty());
+
+    let own = get_settings(&app, Some(&token("1"))).await;
+    assert_eq!(own.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_auth_outcomes_are_counted() {
+    let state = AppState::new(Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()));
+    let metrics = state.metrics.clone();
+    let app = TestApp::with_state(state);
+
+    get_settings(&app, None).await;
+    get_settings(&app, Some(&token_with_secret("deadbeef", "1"))).await;
+    get_settings(&app, Some(&token_with_secret("deadbeef", "1"))).await;
+    get_settings(&app, Some(&token_with_secret(&get_user_secret("1"), "1"))).await;
+    get_settings(&app, Some(&token("1"))).await;
+
+    let outcomes = metrics.auth_outcomes();
+    assert_eq!(outcomes.get(&AuthOutcome::MissingToken), Some(&1));
+    assert_eq!(outcomes.get(&AuthOutcome::InvalidToken), Some(&2));
+    assert_eq!(outcomes.get(&AuthOutcome::PermanentSecret), Some(&1));
+    assert_eq!(outcomes.get(&AuthOutcome::Session), Some(&1));
+}
+
+#[tokio::test]
+async fn test_failed_sign_ins_lock_out() {
+    let mut config = (*CONFIG.load()).clone();
+    config.auth_lockout_max_failures = 3;
+    config.auth_lockout_persist = true;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(
+        DEFAULT_TENANT,
+        ConfigHandle::new(config),
+        db.clone(),
+    ));
+    let state = AppState::with_tenants(tenants);
+    let metrics = state.metrics.clone();
+    let app = TestApp::with_state(state);
+
+    let wrong_secret = token_with_secret("deadbeef", "1");
+    for _ in 0..3 {
+        let wrong = get_settings_from(&app, &wrong_secret, "198.51.100.1", "").await;
+        assert_eq!(wrong.status, StatusCode::UNAUTHORIZED);
+    }
+
+    // Even the right secret is refused from that address until the lockout
+    // ends.
+    let locked = get_settings_from(&app, &token("1"), "198.51.100.1", "").await;
+    assert_eq!(locked.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(locked.error_code(), "locked_out");
+    assert_eq!(locked.header("retry-after"), Some("60"));
+    assert_eq!(
+        metrics.auth_outcomes().get(&AuthOutcome::LockedOut),
+        Some(&1)
+    );
+
+    let stored = db
+        .get_auth_lockout(&format!("user:{}@198.51.100.1", hash_user_id("1")))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.failures, 3);
+
+    // Whoever guessed cannot lock the user out elsewhere.
+    let elsewhere = get_settings_from(&app, &token("1"), "198.51.100.2", "").await;
+    assert_eq!(elsewhere.status, StatusCode::NOT_FOUND);
+
+    let other = get_settings(&app, Some(&token("2"))).await;
+    assert_eq!(other.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_address_lockouts_ignore_spoofed_forwarding() {
+    let mut config = (*CONFIG.load()).clone();
+    config.auth_lockout_max_failures = 0;
+    config.auth_lockout_ip_max_failures = 2;
+    let tenants = Tenants::new(Tenant::new(
+        DEFAULT_TENANT,
+        ConfigHandle::new(config),
+        Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()),
+    ));
+    let app = TestApp::with_state(AppState::with_tenants(tenants));
+
+    // The peer is no trusted proxy, so naming other clients does not get
+    // around the lockout of its address.
+    for (user, forwarded_for) in [("1", "203.0.113.1"), ("2", "203.0.113.2")] {
+        let authorization = token_with_secret("deadbeef", user);
+        let wrong = get_settings_from(&app, &authorization, "198.51.100.1", forwarded_for).await;
+        assert_eq!(wrong.status, StatusCode::UNAUTHORIZED);
+    }
+    let locked = get_settings_from(&app, &token("3"), "198.51.100.1", "203.0.113.3").await;
+    assert_eq!(locked.status, StatusCode::TOO_MANY_REQUESTS);
+
+    // Requests without a peer address are not counted by address.
+    let unaddressed = get_settings(&app, Some(&token("3"))).await;
+    assert_eq!(unaddressed.status, StatusCode::NOT_FOUND);
+}
+
+/// Saves a key for `user` and returns it and its `Authorization` header.
+async fn mint_api_key(app: &TestApp, user: &str, scope: ApiKeyScope) -> (ApiKey, String) {
+    let (id, secret) = new_api_key();
+    let key = ApiKey {
+        id,
+        name: "backup".into(),
+        user_id: user.into(),
+        user: hash_user_id(user),
+        key_hash: hash_api_key_secret(&secret),
+        scope,
+        created_at: chrono::Utc::now().timestamp_millis(),
+    };
+    app.db.save_api_key(&key).await.unwrap();
+    let header = format!("{}{}", API_KEY_PREFIX, format_api_key(&key.id, &secret));
+    (key, header)
+}
+
+async fn send_with(
+    app: &TestApp,
+    method: &str,
+    uri: &str,
+    authorization: &str,
+    body: &[u8],
+) -> TestResponse {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("authorization", authorization)
+        .header("content-type", "application/octet-stream")
+        .body(Body::from(body.to_vec()))
+        .unwrap();
+    app.send(request).await
+}
+
+#[tokio::test]
+async fn test_dev_user_header() {
+    let as_dev_user = || {
+        Request::get("/v1/settings")
+            .header(DEV_USER_HEADER, SAMPLE_USERS[0])
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = TestApp::new().send(as_dev_user()).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+
+    let mut config = (*CONFIG.load()).clone();
+    config.dev_mode = true;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    seed(&db, config.checksum_algorithm).await.unwrap();
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    let state = AppState::with_tenants(tenants);
+    let metrics = state.metrics.clone();
+    let app = TestApp::with_state(state);
+    let response = app.send(as_dev_user()).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(metrics.auth_outcomes().get(&AuthOutcome::DevUser), Some(&1));
+}
+
+#[tokio::test]
+async fn test_api_key_acts_as_its_user() {
+    let app = TestApp::new();
+    let (key, header) = mint_api_key(&app, "1", ApiKeyScope::ReadWrite).await;
+
+    let written = send_with(&app, "PUT", "/v2/data/plugins/a", &header, b"one").await;
+    assert!(written.status.is_success(), "{}", written.status);
+    let read = app.get("/v2/data/plugins/a", "1").await;
+    assert_eq!(&read.body[..], b"one");
+
+    let wrong = format!("{}{}", API_KEY_PREFIX, format_api_key(&key.id, "deadbeef"));
+    let refused = send_with(&app, "GET", "/v2/data/plugins/a", &wrong, b"").await;
+    assert_eq!(refused.status, StatusCode::UNAUTHORIZED);
+
+    app.db.delete_api_key(&key.id).await.unwrap();
+    let revoked = send_with(&app, "GET", "/v2/data/plugins/a", &header, b"").await;
+    assert_eq!(revoked.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_read_only_api_key_cannot_write() {
+    let state = AppState::new(Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()));
+    let metrics = state.metrics.clone();
+    let app = TestApp::with_state(state);
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    let (_, header) = mint_api_key(&app, "1", ApiKeyScope::ReadOnly).await;
+
+    let read = send_with(&app, "GET", "/v2/data/plugins/a", &header, b"").await;
+    assert_eq!(read.status, StatusCode::OK);
+    assert_eq!(&read.body[..], b"one");
+
+    for method in ["PUT", "DELETE"] {
+        let refused = send_with(&app, method, "/v2/data/plugins/a", &header, b"two").await;
+        assert_eq!(refused.status, StatusCode::FORBIDDEN, "{}", method);
+        assert_eq!(refused.error_code(), "forbidden");
+    }
+    assert_eq!(&app.get("/v2/data/plugins/a", "1").await.body[..], b"one");
+
+    let outcomes = metrics.auth_outcomes();
+    assert_eq!(outcomes.get(&AuthOutcome::ApiKey), Some(&1));
+    assert_eq!(outcomes.get(&AuthOutcome::OutOfScope), Some(&2));
+}
+
+/// A session token for `user` limited to `scopes` and `prefix`.
+fn scoped_token(user: &str, scopes: &str, prefix: Option<&str>) -> String {
+    let scopes = TokenScopes::from_request(scopes, prefix).unwrap();
+    let expires_at = chrono::Utc::now().timestamp_millis() + 60_000;
+    let secret = issue_scoped_session_secret(
+        CONFIG.load().session_secret.as_bytes(),
+        user,
+        expires_at,
+        &scopes,
+    );
+    token_with_secret(&secret, user)
+}
+
+#[tokio::test]
+async fn test_read_scope_cannot_write() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    let reader = scoped_token("1", "read", None);
+
+    let read = send_with(&app, "GET", "/v2/data/plugins/a", &reader, b"").await;
+    assert_eq!(&read.body[..], b"one");
+    assert_eq!(
+        get_settings(&app, Some(&reader)).await.status,
+        StatusCode::NOT_FOUND
+    );
+
+    for method in ["PUT", "DELETE"] {
+        let refused = send_with(&app, method, "/v2/data/plugins/a", &reader, b"two").await;
+        assert_eq!(refused.status, StatusCode::FORBIDDEN, "{}", method);
+    }
+    let purge = send_with(
+        &app,
+        "DELETE",
+        "/v1",
+        &scoped_token("1", "read,delete", None),
+        b"",
+    )
+    .await;
+    assert_eq!(purge.status, StatusCode::FORBIDDEN);
+    assert_eq!(&app.get("/v2/data/plugins/a", "1").await.body[..], b"one");
+}
+
+#[tokio::test]
+async fn test_prefix_scope_limits_keys() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/themes/a", "1", b"theme").await;
+    let limited = scoped_token("1", "read,write", Some("plugins/"));
+
+    let written = send_with(&app, "PUT", "/v2/data/plugins/a", &limited, b"one").await;
+    assert!(written.status.is_success(), "{}", written.status);
+    for method in ["GET", "PUT"] {
+        let refused = send_with(&app, method, "/v2/data/themes/a", &limited, b"two").await;
+        assert_eq!(refused.status, StatusCode::FORBIDDEN, "{}", method);
+    }
+    let manifest = send_with(&app, "GET", "/v2/manifest", &limited, b"").await;
+    assert_eq!(manifest.status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        get_settings(&app, Some(&limited)).await.status,
+        StatusCode::FORBIDDEN
+    );
+
+    let sync = |body: serde_json::Value| {
+        Request::post("/v2/sync")
+            .header("authorization", &limited)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let synced = app
+        .send(sync(serde_json::json!({"client_manifest": []})))
+        .await
+        .json();
+    let keys: Vec<&str> = synced["server_manifest"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(keys, ["plugins/a"]);
+
+    let outside = app
+        .send(sync(serde_json::json!({
+            "client_manifest": [],
+            "uploads": [{"key": "themes/b", "value": base64(b"two")}]
+        })))
+        .await;
+    assert_eq!(outside.status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        app.get("/v2/data/themes/b", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_prefix_scope_sends_idempotent_put() {
+    let state = AppState::new(Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()));
+    let metrics = state.metrics.clone();
+    let app = TestApp::with_state(state);
+    let limited = scoped_token("1", "read,write", Some("plugins/"));
+    let put = |key: &str, idempotency_key: &str| {
+        Request::put(format!("/v2/data/{}", key))
+            .header("authorization", &limited)
+            .header("content-type", "application/octet-stream")
+            .header("idempotency-key", idempotency_key)
+            .body(Body::from("one"))
+            .unwrap()
+    };
+
+    let written = app.send(put("plugins/a", "retry-1")).await;
+    assert_eq!(written.status, StatusCode::OK);
+    let replayed = app.send(put("plugins/a", "retry-1")).await;
+    assert_eq!(replayed.header("idempotent-replayed"), Some("true"));
+    // The middleware and the handler share one sign-in.
+    assert_eq!(metrics.auth_outcomes().get(&AuthOutcome::Session), Some(&2));
+
+    let outside = app.send(put("themes/a", "retry-2")).await;
+    assert_eq!(outside.status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_batch_reads_need_only_read_scope() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    app.put_bytes("/v2/data/themes/a", "1", b"theme").await;
+    let post = |uri: &str, authorization: &str, body: serde_json::Value| {
+        app.send(
+            Request::post(uri)
+                .header("authorization", authorization)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+
+    let (_, api_key) = mint_api_key(&app, "1", ApiKeyScope::ReadOnly).await;
+    let limited = scoped_token("1", "read", Some("plugins/"));
+    for reader in [api_key, scoped_token("1", "read", None), limited.clone()] {
+        let batch = post(
+            "/v2/data:batchGet",
+            &reader,
+            serde_json::json!({"keys": ["plugins/a"]}),
+        )
+        .await;
+        assert_eq!(batch.status, StatusCode::OK);
+        assert_eq!(batch.json()["entries"][0]["value"], base64(b"one"));
+        let check = post(
+            "/v2/data:check",
+            &reader,
+            serde_json::json!({"entries": [{"key": "plugins/a"}]}),
+        )
+        .await;
+        assert_eq!(check.status, StatusCode::OK);
+        assert_eq!(check.json()["results"][0]["status"], "changed");
+    }
+
+    let outside = post(
+        "/v2/data:batchGet",
+        &limited,
+        serde_json::json!({"keys": ["plugins/a", "themes/a"]}),
+    )
+    .await;
+    assert_eq!(outside.status, StatusCode::FORBIDDEN);
+    let outside = post(
+        "/v2/data:check",
+        &limited,
+        serde_json::json!({"entries": [{"key": "themes/a"}]}),
+    )
+    .await;
+    assert_eq!(outside.status, StatusCode::FORBIDDEN);
+
+    let writer = scoped_token("1", "write", None);
+    let refused = post(
+        "/v2/data:batchGet",
+        &writer,
+        serde_json::json!({"keys": ["plugins/a"]}),
+    )
+    .await;
+    assert_eq!(refused.status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_oauth_redirect_and_token_exchange() {
+    let mut config = (*CONFIG.load()).clone();
+    config.oauth_redirect_allowlist = vec!["https://app.example.com/auth".parse().unwrap()];
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    let app = TestApp::with_state(AppState::with_tenants(tenants));
+    let authorize = |query: &str| {
+        Request::get(format!("/v1/oauth/authorize?{}", query))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    for refused in [
+        "redirect=https://evil.example.com/auth",
+        "redirect=not-a-url",
+    ] {
+        let response = app.send(authorize(refused)).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", refused);
+    }
+    let started = app
+        .send(authorize(
+            "redirect=https%3A%2F%2Fapp.example.com%2Fauth%2Fdone&redirect_mode=fragment",
+        ))
+        .await;
+    assert_eq!(started.status, StatusCode::OK);
+    let state = started.json()["state"].as_str().unwrap().to_string();
+    let pending = app.db.consume_oauth_state(&state).await.unwrap().unwrap();
+    assert_eq!(
+        pending.redirect,
+        Some(OAuthRedirect {
+            uri: "https://app.example.com/auth/done".into(),
+            mode: RedirectMode::Fragment,
+        })
+    );
+
+    let scopes = TokenScopes::from_request("read", None).unwrap();
+    let pending = OAuthCode {
+        user_id: "1".into(),
+        scopes: scopes.encode(),
+    };
+    app.db
+        .save_oauth_code(&code_key("code"), &pending, 60)
+        .await
+        .unwrap();
+    let exchange = || {
+        Request::post("/v1/oauth/token")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"code": "code"}"#))
+            .unwrap()
+    };
+    let session = app.send(exchange()).await;
+    assert_eq!(session.status, StatusCode::OK);
+    let session = session.json();
+    assert_eq!(session["scopes"], serde_json::json!(["read"]));
+    assert!(session.get("prefix").is_none());
+    let authorization = token_with_secret(session["secret"].as_str().unwrap(), "1");
+    let settings = get_settings(&app, Some(&authorization)).await;
+    assert_eq!(settings.status, StatusCode::NOT_FOUND);
+
+    let reused = app.send(exchange()).await;
+    assert_eq!(reused.status, StatusCode::BAD_REQUEST);
+}
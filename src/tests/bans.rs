@@ -0,0 +1,68 @@
+use axum::http::StatusCode;
+use serde_json::json;
+
+use equicloud::tenant::DEFAULT_TENANT;
+use equicloud::utils::{CONFIG, ConfigHandle, hash_user_id};
+use equicloud::{Ban, Datastore, SqliteDatastore, Storage, Tenant, Tenants};
+
+use super::{TestApp, base64, token};
+use crate::state::AppState;
+
+#[tokio::test]
+async fn test_banned_user_is_refused() {
+    let app = TestApp::new();
+    let linked = app
+        .post_json("/v1/links", "1", &json!({"token": token("2")}))
+        .await;
+    assert_eq!(linked.status, StatusCode::CREATED);
+
+    let ban = Ban {
+        user: hash_user_id("1"),
+        reason: "spam".into(),
+        banned_at: chrono::Utc::now().timestamp_millis(),
+        expires_at: None,
+        automatic: false,
+    };
+    app.db.save_ban(&ban).await.unwrap();
+
+    for user in ["1", "2"] {
+        let refused = app.get("/v2/manifest", user).await;
+        assert_eq!(refused.status, StatusCode::FORBIDDEN);
+        assert_eq!(refused.error_code(), "banned");
+        assert_eq!(refused.json()["error"], "Banned: spam");
+        assert!(refused.header("retry-after").is_none());
+    }
+    assert_eq!(app.get("/v2/manifest", "3").await.status, StatusCode::OK);
+
+    app.db.delete_ban(&ban.user).await.unwrap();
+    assert_eq!(app.get("/v2/manifest", "1").await.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_repeated_violations_ban_temporarily() {
+    let mut config = (*CONFIG.load()).clone();
+    config.abuse_max_violations = 3;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    let app = TestApp::with_state(AppState::with_tenants(tenants));
+
+    let damaged = json!({"entries": [
+        {"key": "a", "value": base64(b"one"), "checksum": "0000"}
+    ]});
+    for _ in 0..3 {
+        let batch = app.post_json("/v2/data:batchPut", "1", &damaged).await;
+        assert_eq!(batch.status, StatusCode::OK);
+        assert_eq!(batch.json()["errors"][0]["error"], "Checksum mismatch");
+    }
+
+    let refused = app.get("/v2/manifest", "1").await;
+    assert_eq!(refused.status, StatusCode::FORBIDDEN);
+    assert_eq!(refused.error_code(), "banned");
+    assert!(refused.header("retry-after").is_some());
+
+    let bans = app.db.list_bans().await.unwrap();
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0].user, hash_user_id("1"));
+    assert!(bans[0].automatic);
+    assert_eq!(app.get("/v2/manifest", "2").await.status, StatusCode::OK);
+}
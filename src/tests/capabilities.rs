@@ -0,0 +1,71 @@
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use serde_json::{Value, json};
+
+use equicloud::checksum::ChecksumAlgorithm;
+use equicloud::tenant::DEFAULT_TENANT;
+use equicloud::utils::{CONFIG, ConfigHandle};
+use equicloud::{SqliteDatastore, Storage, Tenant, Tenants};
+
+use super::TestApp;
+use crate::state::AppState;
+
+async fn get_capabilities(app: &TestApp) -> Value {
+    let request = Request::builder()
+        .uri("/v2/capabilities")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.send(request).await;
+    assert_eq!(response.status, StatusCode::OK);
+    response.json()
+}
+
+#[tokio::test]
+async fn test_capabilities_follow_config() {
+    let mut config = (*CONFIG.load()).clone();
+    config.daily_write_limit = 5;
+    config.response_compression_enabled = false;
+    config.checksum_algorithm = ChecksumAlgorithm::Xxh3;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    let app = TestApp::with_state(AppState::with_tenants(tenants));
+
+    let capabilities = get_capabilities(&app).await;
+    assert_eq!(capabilities["api_versions"], json!(["v1", "v2", "v3"]));
+    assert_eq!(capabilities["features"]["share_links"], true);
+    assert_eq!(capabilities["features"]["response_compression"], false);
+    assert_eq!(capabilities["limits"]["daily_write_limit"], 5);
+    assert_eq!(capabilities["encodings"]["response_compression"], json!([]));
+    assert_eq!(
+        capabilities["encodings"]["checksum_algorithms"],
+        json!(["sha256", "xxh3"])
+    );
+    assert_eq!(
+        capabilities["encodings"]["default_checksum_algorithm"],
+        "xxh3"
+    );
+    assert!(
+        capabilities["namespaces"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|n| n["prefix"] == "dataStore/")
+    );
+
+    let v1 = capabilities["endpoints"]["v1"].as_array().unwrap();
+    let v2 = capabilities["endpoints"]["v2"].as_array().unwrap();
+    assert!(v1.contains(&"/v1/settings".into()));
+    assert!(v2.contains(&"/v2/sync".into()));
+    assert!(v2.contains(&"/v2/capabilities".into()));
+    assert!(!v1.iter().chain(v2).any(|p| p == "/health"));
+    assert_eq!(
+        capabilities["endpoints"]["v3"],
+        json!(["/v3/sync", "/v3/sync/downloads"])
+    );
+
+    let defaults = get_capabilities(&TestApp::new()).await;
+    assert_eq!(
+        defaults["features"]["response_compression"],
+        CONFIG.load().response_compression_enabled
+    );
+}
@@ -0,0 +1,82 @@
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+
+use equicloud::utils::{CONFIG, Config, ConfigHandle};
+use equicloud::{SqliteDatastore, Storage};
+
+use super::{TestApp, TestResponse, request};
+use crate::state::AppState;
+
+fn app_with(configure: impl FnOnce(&mut Config)) -> TestApp {
+    let mut config = (*CONFIG.load()).clone();
+    config.cors_allowed_origins = Some("https://app.example.com".into());
+    configure(&mut config);
+    let mut state = AppState::new(Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()));
+    state.config = ConfigHandle::new(config);
+    TestApp::with_state(state)
+}
+
+async fn preflight(app: &TestApp, uri: &str, origin: &str) -> TestResponse {
+    app.send(
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri(uri)
+            .header("origin", origin)
+            .header("access-control-request-method", "PUT")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_cors_api_routes() {
+    let app = app_with(|config| config.cors_allow_credentials = true);
+
+    let allowed = preflight(&app, "/v1/settings", "https://app.example.com").await;
+    assert_eq!(allowed.status, StatusCode::OK);
+    assert_eq!(
+        allowed.header("access-control-allow-origin"),
+        Some("https://app.example.com")
+    );
+    assert_eq!(allowed.header("access-control-max-age"), Some("600"));
+    assert_eq!(
+        allowed.header("access-control-allow-credentials"),
+        Some("true")
+    );
+
+    let other = preflight(&app, "/v1/settings", "https://evil.example.com").await;
+    assert_eq!(other.header("access-control-allow-origin"), None);
+
+    app.put_bytes("/v1/settings", "1", b"settings").await;
+    let fetched = app
+        .send(
+            request(Method::GET, "/v1/settings", "1")
+                .header("origin", "https://app.example.com")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let exposed = fetched.header("access-control-expose-headers").unwrap();
+    assert!(exposed.contains("etag") && exposed.contains("x-version"));
+}
+
+#[tokio::test]
+async fn test_cors_admin_routes() {
+    let app = app_with(|_| {});
+    let refused = preflight(&app, "/admin/config", "https://app.example.com").await;
+    assert_eq!(refused.header("access-control-allow-origin"), None);
+
+    let app = app_with(|config| {
+        config.cors_admin_allowed_origins = Some("https://admin.example.com".into());
+        config.cors_max_age = std::time::Duration::ZERO;
+    });
+    let allowed = preflight(&app, "/admin/config", "https://admin.example.com").await;
+    assert_eq!(
+        allowed.header("access-control-allow-origin"),
+        Some("https://admin.example.com")
+    );
+    assert_eq!(allowed.header("access-control-max-age"), None);
+    let refused = preflight(&app, "/admin/config", "https://app.example.com").await;
+    assert_eq!(refused.header("access-control-allow-origin"), None);
+}
@@ -0,0 +1,777 @@
+use axum::body::Body;
+use axum::http::{Method, StatusCode};
+use serde_json::json;
+
+use equicloud::checksum::ChecksumAlgorithm;
+use equicloud::tenant::DEFAULT_TENANT;
+use equicloud::utils::{CONFIG, ConfigHandle};
+use equicloud::{
+    DataUpload, Datastore, Event, SqliteDatastore, Storage, Tenant, Tenants, compute_checksum,
+};
+
+use super::{TestApp, base64, request};
+use crate::state::AppState;
+
+#[tokio::test]
+async fn test_data_round_trip() {
+    let app = TestApp::new();
+
+    let first = app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    assert_eq!(first.status, StatusCode::OK);
+    assert_eq!(first.json()["version"], 1);
+    assert_eq!(first.json()["checksum"], compute_checksum(b"one"));
+
+    let second = app.put_bytes("/v2/data/plugins/a", "1", b"two").await;
+    assert_eq!(second.json()["version"], 2);
+
+    let fetched = app.get("/v2/data/plugins/a", "1").await;
+    assert_eq!(fetched.status, StatusCode::OK);
+    assert_eq!(&fetched.body[..], b"two");
+    assert_eq!(fetched.header("x-version"), Some("2"));
+    assert_eq!(
+        fetched.header("etag"),
+        Some(format!("\"{}\"", compute_checksum(b"two")).as_str())
+    );
+
+    let deleted = app.delete("/v2/data/plugins/a", "1").await;
+    assert_eq!(deleted.status, StatusCode::NO_CONTENT);
+    let gone = app.get("/v2/data/plugins/a", "1").await;
+    assert_eq!(gone.status, StatusCode::GONE);
+    assert_eq!(gone.error_code(), "deleted");
+    assert!(gone.json()["deleted_at"].as_i64().unwrap() > 0);
+
+    let never = app.get("/v2/data/plugins/b", "1").await;
+    assert_eq!(never.status, StatusCode::NOT_FOUND);
+    assert_eq!(never.error_code(), "not_found");
+}
+
+#[tokio::test]
+async fn test_concurrent_writers_get_distinct_versions() {
+    let app = TestApp::new();
+    let writes = (0..8).map(|i| {
+        let body = format!("value {}", i);
+        let app = &app;
+        async move {
+            app.put_bytes("/v2/data/plugins/a", "1", body.as_bytes())
+                .await
+        }
+    });
+    let mut versions: Vec<i64> = futures::future::join_all(writes)
+        .await
+        .iter()
+        .map(|response| response.json()["version"].as_i64().unwrap())
+        .collect();
+    versions.sort_unstable();
+    assert_eq!(versions, (1..=8).collect::<Vec<_>>());
+
+    let batches = (0..4).map(|i| {
+        let upload = DataUpload {
+            key: "plugins/a".into(),
+            value: vec![i],
+            checksum: compute_checksum(&[i]),
+            ttl_secs: None,
+        };
+        app.db.save_data_keys_batch("1", vec![upload])
+    });
+    let mut versions: Vec<i64> = futures::future::try_join_all(batches)
+        .await
+        .unwrap()
+        .into_iter()
+        .flat_map(|saved| saved.into_iter().map(|(_, version, _)| version))
+        .collect();
+    versions.sort_unstable();
+    assert_eq!(versions, (9..=12).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn test_delete_leaves_tombstone_in_manifest() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    app.put_bytes("/v2/data/plugins/b", "1", b"two").await;
+    app.delete("/v2/data/plugins/a", "1").await;
+
+    let manifest = app.get("/v2/manifest", "1").await.json();
+    let entries = manifest["entries"].as_array().unwrap();
+    let a = entries.iter().find(|e| e["key"] == "plugins/a").unwrap();
+    let b = entries.iter().find(|e| e["key"] == "plugins/b").unwrap();
+    assert_eq!(a["deleted"], true);
+    assert_eq!(a["version"], 2);
+    assert_eq!(b["deleted"], false);
+    assert_eq!(manifest["total_size"], 3);
+
+    let live = app
+        .get("/v2/manifest?include_deleted=false", "1")
+        .await
+        .json();
+    let keys: Vec<_> = live["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(keys, ["plugins/b"]);
+}
+
+#[tokio::test]
+async fn test_data_head() {
+    let app = TestApp::new();
+    let head = |etag: Option<&str>| {
+        let mut builder = request(Method::HEAD, "/v2/data/plugins/a", "1");
+        if let Some(etag) = etag {
+            builder = builder.header("if-none-match", etag);
+        }
+        app.send(builder.body(Body::empty()).unwrap())
+    };
+    assert_eq!(head(None).await.status, StatusCode::NOT_FOUND);
+
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    app.put_bytes("/v2/data/plugins/a", "1", b"three").await;
+
+    let described = head(None).await;
+    assert_eq!(described.status, StatusCode::OK);
+    assert!(described.body.is_empty());
+    assert_eq!(described.header("content-length"), Some("5"));
+    assert_eq!(described.header("x-version"), Some("2"));
+    assert_eq!(
+        described.header("x-checksum"),
+        Some(compute_checksum(b"three").as_str())
+    );
+    assert!(described.header("last-modified").unwrap().ends_with(" GMT"));
+
+    let etag = described.header("etag").unwrap().to_string();
+    assert_eq!(head(Some(&etag)).await.status, StatusCode::NOT_MODIFIED);
+
+    app.delete("/v2/data/plugins/a", "1").await;
+    assert_eq!(head(None).await.status, StatusCode::GONE);
+}
+
+#[tokio::test]
+async fn test_list_keys() {
+    let app = TestApp::new();
+    for key in [
+        "plugins/a",
+        "plugins/b/x",
+        "plugins/b/y",
+        "plugins/c",
+        "themes/t",
+    ] {
+        app.put_bytes(&format!("/v2/data/{}", key), "1", b"v").await;
+    }
+    app.delete("/v2/data/plugins/c", "1").await;
+
+    let all = app.get("/v2/keys", "1").await.json();
+    assert_eq!(all["keys"].as_array().unwrap().len(), 4);
+    assert!(all["common_prefixes"].as_array().unwrap().is_empty());
+
+    let top = app.get("/v2/keys?delimiter=/", "1").await.json();
+    assert!(top["keys"].as_array().unwrap().is_empty());
+    assert_eq!(
+        top["common_prefixes"],
+        serde_json::json!(["plugins/", "themes/"])
+    );
+
+    let plugins = app
+        .get("/v2/keys?prefix=plugins/&delimiter=/", "1")
+        .await
+        .json();
+    assert_eq!(plugins["keys"], serde_json::json!(["plugins/a"]));
+    assert_eq!(
+        plugins["common_prefixes"],
+        serde_json::json!(["plugins/b/"])
+    );
+}
+
+#[tokio::test]
+async fn test_data_range_request() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"0123456789")
+        .await;
+
+    let partial = app
+        .send(
+            request(Method::GET, "/v2/data/plugins/a", "1")
+                .header("range", "bytes=2-5")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(partial.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(&partial.body[..], b"2345");
+    assert_eq!(partial.header("content-range"), Some("bytes 2-5/10"));
+
+    let unsatisfiable = app
+        .send(
+            request(Method::GET, "/v2/data/plugins/a", "1")
+                .header("range", "bytes=20-30")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(unsatisfiable.status, StatusCode::RANGE_NOT_SATISFIABLE);
+}
+
+#[tokio::test]
+async fn test_data_conditional_requests() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    let etag = app.get("/v2/data/plugins/a", "1").await.headers["etag"].clone();
+    let etag = etag.to_str().unwrap();
+
+    let cached = app
+        .send(
+            request(Method::GET, "/v2/data/plugins/a", "1")
+                .header("if-none-match", format!("\"other\", W/{}", etag))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(cached.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(cached.header("etag"), Some(etag));
+
+    let last_modified = cached.header("last-modified").unwrap();
+    for method in [Method::GET, Method::HEAD] {
+        let unmodified = app
+            .send(
+                request(method, "/v2/data/plugins/a", "1")
+                    .header("if-modified-since", last_modified)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(unmodified.status, StatusCode::NOT_MODIFIED);
+    }
+
+    let put = |if_match: &str| {
+        request(Method::PUT, "/v2/data/plugins/a", "1")
+            .header("content-type", "application/octet-stream")
+            .header("if-match", if_match)
+            .body(Body::from("two"))
+            .unwrap()
+    };
+    let stale = app.send(put("\"other\"")).await;
+    assert_eq!(stale.status, StatusCode::CONFLICT);
+    assert_eq!(stale.error_code(), "version_conflict");
+    let current = app.send(put(etag)).await;
+    assert_eq!(current.status, StatusCode::OK);
+    assert_eq!(current.json()["version"], 2);
+}
+
+#[tokio::test]
+async fn test_data_check() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    app.put_bytes("/v2/data/plugins/b", "1", b"two").await;
+    app.put_bytes("/v2/data/plugins/c", "1", b"three").await;
+    app.delete("/v2/data/plugins/c", "1").await;
+    app.put_bytes("/v2/data/plugins/d", "1", &[0; 2048]).await;
+
+    let check = app
+        .post_json(
+            "/v2/data:check",
+            "1",
+            &json!({
+                "entries": [
+                    {"key": "plugins/a", "checksum": format!("sha256:{}", compute_checksum(b"one"))},
+                    {"key": "plugins/b", "checksum": compute_checksum(b"old")},
+                    {"key": "plugins/c", "checksum": compute_checksum(b"three")},
+                    {"key": "plugins/d"},
+                    {"key": "plugins/e"},
+                    {"key": "plugins/a"},
+                    {"key": ""}
+                ],
+                "inline_max_bytes": 1024
+            }),
+        )
+        .await;
+    assert_eq!(check.status, StatusCode::OK);
+    assert_eq!(check.header("x-checksum-status"), Some("verified"));
+    let body = check.json();
+    let results = body["results"].as_array().unwrap();
+    let statuses: Vec<_> = results.iter().map(|r| r["status"].clone()).collect();
+    assert_eq!(
+        statuses,
+        ["unchanged", "changed", "missing", "changed", "missing"]
+    );
+    assert!(results[0].get("value").is_none());
+    assert_eq!(results[1]["value"], base64(b"two"));
+    assert_eq!(results[1]["version"], 1);
+    assert_eq!(results[1]["checksum"], compute_checksum(b"two"));
+    // Too large to send inline, so only described.
+    assert_eq!(results[3]["size_bytes"], 2048);
+    assert!(results[3].get("value").is_none());
+    assert_eq!(body["errors"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_data_version_preconditions() {
+    let app = TestApp::new();
+    let put = |name: &str, value: &str, body: &'static str| {
+        request(Method::PUT, "/v2/data/plugins/a", "1")
+            .header("content-type", "application/octet-stream")
+            .header(name, value)
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let created = app.send(put("x-if-version", "0", "one")).await;
+    assert_eq!(created.status, StatusCode::OK);
+    assert_eq!(created.json()["version"], 1);
+    let exists = app.send(put("x-if-version", "0", "two")).await;
+    assert_eq!(exists.status, StatusCode::CONFLICT);
+
+    let updated = app.send(put("x-if-version", "1", "two")).await;
+    assert_eq!(updated.status, StatusCode::OK);
+    assert_eq!(updated.json()["version"], 2);
+
+    let stale = app.send(put("x-if-version", "1", "three")).await;
+    assert_eq!(stale.status, StatusCode::CONFLICT);
+    assert_eq!(stale.error_code(), "version_conflict");
+    assert_eq!(stale.json()["current_version"], 2);
+    assert_eq!(stale.json()["current_checksum"], compute_checksum(b"two"));
+    assert_eq!(&app.get("/v2/data/plugins/a", "1").await.body[..], b"two");
+
+    let invalid = app.send(put("x-if-version", "-1", "three")).await;
+    assert_eq!(invalid.status, StatusCode::BAD_REQUEST);
+
+    let unmodified = app
+        .send(put(
+            "if-unmodified-since",
+            "Fri, 01 Jan 2100 00:00:00 GMT",
+            "three",
+        ))
+        .await;
+    assert_eq!(unmodified.status, StatusCode::OK);
+    let modified = app
+        .send(put(
+            "if-unmodified-since",
+            "Thu, 01 Jan 2015 00:00:00 GMT",
+            "four",
+        ))
+        .await;
+    assert_eq!(modified.status, StatusCode::CONFLICT);
+    assert_eq!(modified.json()["current_version"], 3);
+}
+
+#[tokio::test]
+async fn test_data_rename() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    app.put_bytes("/v2/data/plugins/a", "1", b"two").await;
+
+    let renamed = app
+        .post_json("/v2/data/plugins/a/rename", "1", &json!({"to": "themes/b"}))
+        .await;
+    assert_eq!(renamed.status, StatusCode::OK);
+    assert_eq!(renamed.json()["key"], "themes/b");
+    assert_eq!(renamed.json()["version"], 2);
+    assert_eq!(renamed.json()["tombstone_version"], 3);
+
+    let moved = app.get("/v2/data/themes/b", "1").await;
+    assert_eq!(&moved.body[..], b"two");
+    assert_eq!(moved.header("x-version"), Some("2"));
+    assert_eq!(
+        moved.header("etag"),
+        Some(format!("\"{}\"", compute_checksum(b"two")).as_str())
+    );
+    assert_eq!(
+        app.get("/v2/data/plugins/a", "1").await.status,
+        StatusCode::GONE
+    );
+    let manifest = app.get("/v2/manifest", "1").await.json();
+    let entries = manifest["entries"].as_array().unwrap();
+    let old = entries.iter().find(|e| e["key"] == "plugins/a").unwrap();
+    assert_eq!(old["deleted"], true);
+    assert_eq!(old["version"], 3);
+
+    // Renaming onto the tombstone goes past its version.
+    let back = app
+        .post_json("/v2/data/themes/b/rename", "1", &json!({"to": "plugins/a"}))
+        .await;
+    assert_eq!(back.status, StatusCode::OK);
+    assert_eq!(back.json()["version"], 4);
+
+    let missing = app
+        .post_json("/v2/data/themes/b/rename", "1", &json!({"to": "plugins/c"}))
+        .await;
+    assert_eq!(missing.status, StatusCode::NOT_FOUND);
+    let same = app
+        .post_json(
+            "/v2/data/plugins/a/rename",
+            "1",
+            &json!({"to": "plugins/a"}),
+        )
+        .await;
+    assert_eq!(same.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_data_rename_rejects_newer_target() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"old").await;
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    app.put_bytes("/v2/data/plugins/b", "1", b"new").await;
+
+    let rejected = app
+        .post_json(
+            "/v2/data/plugins/a/rename",
+            "1",
+            &json!({"to": "plugins/b"}),
+        )
+        .await;
+    assert_eq!(rejected.status, StatusCode::CONFLICT);
+    assert_eq!(&app.get("/v2/data/plugins/a", "1").await.body[..], b"old");
+    assert_eq!(&app.get("/v2/data/plugins/b", "1").await.body[..], b"new");
+
+    let replaced = app
+        .post_json(
+            "/v2/data/plugins/b/rename",
+            "1",
+            &json!({"to": "plugins/a"}),
+        )
+        .await;
+    assert_eq!(replaced.status, StatusCode::OK);
+    assert_eq!(replaced.json()["version"], 2);
+    assert_eq!(&app.get("/v2/data/plugins/a", "1").await.body[..], b"new");
+}
+
+#[tokio::test]
+async fn test_data_rejects_invalid_key() {
+    let app = TestApp::new();
+    let response = app.put_bytes("/v2/data/plugins/a$b", "1", b"one").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(response.error_code(), "invalid_key");
+}
+
+#[tokio::test]
+async fn test_data_quota_exceeded() {
+    let state = AppState::new(Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()));
+    let mut events = state.events.subscribe();
+    let app = TestApp::with_state(state);
+    app.db.set_quota_override("1", Some(8)).await.unwrap();
+
+    let fits = app.put_bytes("/v2/data/plugins/a", "1", b"12345").await;
+    assert_eq!(fits.status, StatusCode::OK);
+
+    let over = app.put_bytes("/v2/data/plugins/b", "1", b"12345").await;
+    assert_eq!(over.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(over.error_code(), "quota_exceeded");
+    let mut refused = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        if let Event::QuotaExceeded { user_id } = event {
+            refused.push(user_id);
+        }
+    }
+    assert_eq!(refused, ["1"]);
+
+    // Replacing a value only counts the difference in size.
+    let replaced = app.put_bytes("/v2/data/plugins/a", "1", b"12345678").await;
+    assert_eq!(replaced.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_data_ttl_is_reported() {
+    let app = TestApp::new();
+    let response = app
+        .send(
+            request(Method::PUT, "/v2/data/plugins/a", "1")
+                .header("content-type", "application/octet-stream")
+                .header("x-ttl-seconds", "60")
+                .body(Body::from("one"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    let body = response.json();
+    assert_eq!(
+        body["expires_at"].as_i64().unwrap(),
+        body["updated_at"].as_i64().unwrap() + 60_000
+    );
+
+    let invalid = app
+        .send(
+            request(Method::PUT, "/v2/data/plugins/a", "1")
+                .header("content-type", "application/octet-stream")
+                .header("x-ttl-seconds", "0")
+                .body(Body::from("one"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(invalid.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_daily_write_limit() {
+    let mut config = (*CONFIG.load()).clone();
+    config.daily_write_limit = 3;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    let app = TestApp::with_state(AppState::with_tenants(tenants));
+
+    let first = app.put_bytes("/v2/data/a", "1", b"one").await;
+    assert_eq!(first.status, StatusCode::OK);
+    assert_eq!(first.header("x-write-limit"), Some("3"));
+    assert_eq!(first.header("x-write-remaining"), Some("2"));
+    assert!(first.header("x-write-reset").is_some());
+
+    let batch = json!({"entries": [
+        {"key": "b", "value": base64(b"two")},
+        {"key": "c", "value": base64(b"three")}
+    ]});
+    let batch = app.post_json("/v2/data:batchPut", "1", &batch).await;
+    assert_eq!(batch.status, StatusCode::OK);
+    assert_eq!(batch.header("x-write-remaining"), Some("0"));
+
+    let over = app.delete("/v2/data/a", "1").await;
+    assert_eq!(over.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(over.error_code(), "write_limit_exceeded");
+    assert!(over.header("retry-after").is_some());
+    assert_eq!(app.get("/v2/data/a", "1").await.status, StatusCode::OK);
+
+    let sync = json!({"client_manifest": [], "uploads": [{"key": "d", "value": base64(b"4")}]});
+    let sync = app.post_json("/v2/sync", "1", &sync).await;
+    assert_eq!(sync.status, StatusCode::TOO_MANY_REQUESTS);
+    let read_only = app
+        .post_json("/v2/sync", "1", &json!({"client_manifest": []}))
+        .await;
+    assert_eq!(read_only.status, StatusCode::OK);
+
+    let other_user = app.put_bytes("/v2/data/a", "2", b"one").await;
+    assert_eq!(other_user.status, StatusCode::OK);
+
+    let archive = app.get("/v2/export", "2").await.body;
+    let import = app.import("1", &archive).await;
+    assert_eq!(import.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(import.error_code(), "write_limit_exceeded");
+    let import = app.import("3", &archive).await;
+    assert_eq!(import.status, StatusCode::OK);
+    assert_eq!(import.header("x-write-remaining"), Some("2"));
+}
+
+#[tokio::test]
+async fn test_max_keys_per_user() {
+    let mut config = (*CONFIG.load()).clone();
+    config.max_keys_per_user = 2;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    let app = TestApp::with_state(AppState::with_tenants(tenants));
+
+    assert_eq!(
+        app.put_bytes("/v2/data/a", "1", b"one").await.status,
+        StatusCode::OK
+    );
+    let batch = json!({"entries": [
+        {"key": "b", "value": base64(b"two")},
+        {"key": "c", "value": base64(b"three")}
+    ]});
+    let batch = app.post_json("/v2/data:batchPut", "1", &batch).await;
+    assert_eq!(batch.json()["errors"][0]["key"], "c");
+    assert_eq!(
+        batch.json()["errors"][0]["error"],
+        "At most 2 keys can be stored"
+    );
+
+    let over = app.put_bytes("/v2/data/c", "1", b"three").await;
+    assert_eq!(over.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(over.error_code(), "key_limit_exceeded");
+    // Overwriting a key creates none.
+    assert_eq!(
+        app.put_bytes("/v2/data/a", "1", b"uno").await.status,
+        StatusCode::OK
+    );
+
+    let sync = json!({"client_manifest": [], "uploads": [{"key": "d", "value": base64(b"four")}]});
+    let sync = app.post_json("/v2/sync", "1", &sync).await.json();
+    assert_eq!(sync["uploaded"], json!([]));
+    assert_eq!(sync["errors"][0]["key"], "d");
+
+    let usage = app.get("/v2/usage", "1").await.json();
+    assert_eq!(usage["data_keys"], 2);
+    assert_eq!(usage["max_keys"], 2);
+
+    app.put_bytes("/v2/data/a", "2", b"uno").await;
+    app.put_bytes("/v2/data/e", "2", b"five").await;
+    let archive = app.get("/v2/export", "2").await.body;
+    let import = app.import("1", &archive).await;
+    assert_eq!(import.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(import.error_code(), "key_limit_exceeded");
+    assert_eq!(
+        app.get("/v2/data/e", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+
+    // Deleting a key makes room for another.
+    app.delete("/v2/data/a", "1").await;
+    assert_eq!(
+        app.put_bytes("/v2/data/c", "1", b"three").await.status,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn test_failed_import_leaves_data_unchanged() {
+    let path = std::env::temp_dir().join(format!("equicloud-import-{}.db", uuid::Uuid::new_v4()));
+    let path = path.to_str().unwrap().to_string();
+    let db = Storage::Sqlite(SqliteDatastore::open(&path).unwrap());
+    let app = TestApp::with_state(AppState::new(db));
+
+    app.put_bytes("/v2/data/a", "1", b"old").await;
+    app.put_bytes("/v2/data/a", "2", b"new").await;
+    app.put_bytes("/v2/data/b", "2", b"two").await;
+    app.put_bytes("/v1/settings", "2", b"settings").await;
+    let archive = app.get("/v2/export", "2").await.body;
+
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute_batch(
+            "CREATE TRIGGER fail_settings BEFORE INSERT ON settings_history \
+             BEGIN SELECT RAISE(ABORT, 'disk full'); END;",
+        )
+        .unwrap();
+
+    let import = app.import("1", &archive).await;
+    assert_eq!(import.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(&app.get("/v2/data/a", "1").await.body[..], b"old");
+    // The key the import created is deleted again.
+    assert_eq!(app.get("/v2/data/b", "1").await.status, StatusCode::GONE);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_open_circuit_fails_fast() {
+    let state = AppState::new(Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()));
+    let health = state.db_health.clone();
+    let app = TestApp::with_state(state);
+    app.put_bytes("/v2/data/a", "1", b"one").await;
+
+    for _ in 0..CONFIG.load().db_circuit_breaker_threshold {
+        health.record_failure("connection refused".into());
+    }
+    let response = app.get("/v2/data/a", "1").await;
+    assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.error_code(), "database_unavailable");
+
+    let ready = app.get("/health/ready", "1").await;
+    assert_eq!(ready.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(ready.json()["reason"], "circuit open");
+
+    health.record_success();
+    assert_eq!(app.get("/v2/data/a", "1").await.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_corrupt_values_are_flagged_on_read() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/good", "1", b"intact").await;
+    // Stands in for a value that rotted after it was written.
+    let rotten = DataUpload {
+        key: "plugins/bad".into(),
+        value: b"rotten".to_vec(),
+        checksum: compute_checksum(b"pristine"),
+        ttl_secs: None,
+    };
+    app.db
+        .save_data_keys_batch("1", vec![rotten])
+        .await
+        .unwrap();
+
+    let good = app.get("/v2/data/plugins/good", "1").await;
+    assert_eq!(good.header("x-checksum-status"), Some("verified"));
+
+    let bad = app.get("/v2/data/plugins/bad", "1").await;
+    assert_eq!(bad.status, StatusCode::OK);
+    assert_eq!(bad.header("x-checksum-status"), Some("mismatch"));
+    assert_eq!(&bad.body[..], b"rotten");
+
+    let batch = app
+        .post_json(
+            "/v2/data:batchGet",
+            "1",
+            &json!({"keys": ["plugins/good", "plugins/bad"]}),
+        )
+        .await;
+    assert_eq!(batch.header("x-checksum-status"), Some("mismatch"));
+    assert_eq!(batch.json()["entries"].as_array().unwrap().len(), 1);
+    assert_eq!(batch.json()["errors"][0]["key"], "plugins/bad");
+
+    let sync = app
+        .post_json("/v2/sync", "1", &json!({"client_manifest": []}))
+        .await;
+    assert_eq!(sync.header("x-checksum-status"), Some("mismatch"));
+    assert_eq!(sync.json()["downloads"].as_array().unwrap().len(), 1);
+    assert_eq!(sync.json()["errors"][0]["error"], "Stored value is corrupt");
+
+    let recorded = app.db.list_corruption("1").await.unwrap();
+    assert_eq!(recorded.len(), 1);
+    assert_eq!(recorded[0].key, "plugins/bad");
+    assert_eq!(recorded[0].computed_checksum, compute_checksum(b"rotten"));
+}
+
+#[tokio::test]
+async fn test_checksum_algorithms() {
+    let mut config = (*CONFIG.load()).clone();
+    config.checksum_algorithm = ChecksumAlgorithm::Xxh3;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    let app = TestApp::with_state(AppState::with_tenants(tenants));
+
+    let put = |key: &'static str, body: &'static [u8], checksum: String| {
+        let request = request(Method::PUT, key, "1")
+            .header("content-type", "application/octet-stream")
+            .header("x-checksum", checksum)
+            .body(Body::from(body))
+            .unwrap();
+        app.send(request)
+    };
+
+    // Without a checksum the configured algorithm is used.
+    let xxh3 = ChecksumAlgorithm::Xxh3.compute(b"one");
+    let written = app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    assert_eq!(written.json()["checksum"], xxh3);
+    assert_eq!(
+        app.get("/v2/data/plugins/a", "1")
+            .await
+            .header("x-checksum-status"),
+        Some("verified")
+    );
+
+    // A client's algorithm is kept, with SHA-256 stored bare.
+    let sha256 = compute_checksum(b"two");
+    let written = put("/v2/data/plugins/b", b"two", format!("sha256:{}", sha256)).await;
+    assert_eq!(written.status, StatusCode::OK);
+    assert_eq!(written.json()["checksum"], sha256);
+    assert_eq!(
+        app.get("/v2/data/plugins/b", "1")
+            .await
+            .header("x-checksum-status"),
+        Some("verified")
+    );
+
+    let mismatch = put("/v2/data/plugins/c", b"three", xxh3.clone()).await;
+    assert_eq!(mismatch.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(mismatch.error_code(), "checksum_mismatch");
+    let unsupported = put("/v2/data/plugins/c", b"three", "md5:abcd".into()).await;
+    assert_eq!(unsupported.status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        app.get("/v2/data/plugins/c", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+
+    // A client manifest in another notation still matches.
+    let sync = app
+        .post_json(
+            "/v2/sync",
+            "1",
+            &json!({
+                "client_manifest": [
+                    { "key": "plugins/a", "version": 1, "checksum": xxh3 },
+                    { "key": "plugins/b", "version": 1, "checksum": format!("sha256:{}", sha256) }
+                ],
+                "uploads": [{ "key": "plugins/d", "value": base64(b"four"), "checksum": ChecksumAlgorithm::Xxh3.compute(b"four") }]
+            }),
+        )
+        .await;
+    assert_eq!(sync.status, StatusCode::OK);
+    assert_eq!(sync.json()["downloads"], json!([]));
+    assert_eq!(sync.json()["uploaded"][0]["key"], "plugins/d");
+}
@@ -0,0 +1,104 @@
+use axum::body::Body;
+use axum::http::{Method, StatusCode};
+use serde_json::{Value, json};
+
+use super::{TestApp, TestResponse, base64, request};
+
+async fn put_from(app: &TestApp, key: &str, device: &str, name: &str) -> TestResponse {
+    let request = request(Method::PUT, &format!("/v2/data/{}", key), "1")
+        .header("content-type", "application/octet-stream")
+        .header("x-device-id", device)
+        .header("x-client-name", name)
+        .body(Body::from("value"))
+        .unwrap();
+    app.send(request).await
+}
+
+fn last_writer<'a>(manifest: &'a Value, key: &str) -> &'a Value {
+    let entries = manifest["entries"].as_array().unwrap();
+    let entry = entries.iter().find(|e| e["key"] == key).unwrap();
+    &entry["last_writer"]
+}
+
+#[tokio::test]
+async fn test_writes_record_last_writer_and_devices() {
+    let app = TestApp::new();
+    assert_eq!(
+        put_from(&app, "a", "laptop", "Vencord on Laptop")
+            .await
+            .status,
+        StatusCode::OK
+    );
+    put_from(&app, "b", "laptop", "Vencord on Laptop").await;
+    put_from(&app, "b", "phone", "Vencord on Phone").await;
+    app.put_bytes("/v2/data/c", "1", b"anonymous").await;
+
+    let manifest = app.get("/v2/manifest", "1").await.json();
+    assert_eq!(last_writer(&manifest, "a")["device_id"], "laptop");
+    assert_eq!(
+        last_writer(&manifest, "a")["client_name"],
+        "Vencord on Laptop"
+    );
+    assert_eq!(last_writer(&manifest, "b")["device_id"], "phone");
+    assert!(last_writer(&manifest, "c").is_null());
+
+    // A write without X-Device-Id is not credited to the previous writer.
+    app.put_bytes("/v2/data/a", "1", b"anonymous").await;
+    let manifest = app.get("/v2/manifest", "1").await.json();
+    assert!(last_writer(&manifest, "a").is_null());
+
+    let devices = app.get("/v2/devices", "1").await;
+    assert_eq!(devices.status, StatusCode::OK);
+    let devices = devices.json()["devices"].clone();
+    let ids: Vec<&str> = devices
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| d["device_id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, ["phone", "laptop"]);
+    assert_eq!(devices[0]["client_name"], "Vencord on Phone");
+    assert!(devices[0]["last_seen_at"].as_i64().unwrap() > 0);
+
+    assert_eq!(
+        app.get("/v2/devices", "2").await.json(),
+        json!({"api_version": 2, "devices": []})
+    );
+}
+
+#[tokio::test]
+async fn test_sync_records_device() {
+    let app = TestApp::new();
+    let body = json!({
+        "client_manifest": [],
+        "uploads": [{"key": "synced", "value": base64(b"value")}]
+    });
+    let request = request(Method::POST, "/v2/sync", "1")
+        .header("content-type", "application/json")
+        .header("x-device-id", "desktop")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    assert_eq!(app.send(request).await.status, StatusCode::OK);
+
+    let manifest = app.get("/v2/manifest", "1").await.json();
+    assert_eq!(last_writer(&manifest, "synced")["device_id"], "desktop");
+    assert!(
+        last_writer(&manifest, "synced")
+            .get("client_name")
+            .is_none()
+    );
+
+    let devices = app.get("/v2/devices", "1").await.json();
+    assert_eq!(devices["devices"][0]["device_id"], "desktop");
+}
+
+#[tokio::test]
+async fn test_invalid_device_id_is_rejected() {
+    let app = TestApp::new();
+    let response = put_from(&app, "a", "not a device id", "Laptop").await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        app.get("/v2/data/a", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+}
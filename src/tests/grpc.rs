@@ -0,0 +1,202 @@
+use axum::body::Body;
+use axum::http::{Method, Request};
+use http_body_util::BodyExt;
+use tower::ServiceExt;
+
+use equicloud::{SqliteDatastore, Storage};
+
+use super::request;
+use crate::grpc::proto::Message;
+use crate::grpc::{self, proto};
+use crate::state::AppState;
+
+/// Frames `messages` as a gRPC request body.
+fn frames(messages: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for message in messages {
+        body.push(0);
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(message);
+    }
+    body
+}
+
+/// Calls `method` with `messages` and returns the `grpc-status` and the
+/// messages of the response.
+async fn call(method: &str, user: Option<&str>, messages: &[Vec<u8>]) -> (String, Vec<Vec<u8>>) {
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    call_on(&AppState::new(db), method, user, messages).await
+}
+
+async fn call_on(
+    state: &AppState,
+    method: &str,
+    user: Option<&str>,
+    messages: &[Vec<u8>],
+) -> (String, Vec<Vec<u8>>) {
+    let uri = format!("/equicloud.v1.Equicloud/{}", method);
+    let builder = match user {
+        Some(user) => request(Method::POST, &uri, user),
+        None => Request::builder().method(Method::POST).uri(&uri),
+    };
+    let request = builder
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(Body::from(frames(messages)))
+        .unwrap();
+    let response = grpc::router(state.clone()).oneshot(request).await.unwrap();
+    let headers = response.headers().clone();
+    let collected = response.into_body().collect().await.unwrap();
+    let status = collected
+        .trailers()
+        .and_then(|t| t.get("grpc-status"))
+        .or_else(|| headers.get("grpc-status"))
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let mut body = collected.to_bytes().to_vec();
+    let mut messages = Vec::new();
+    while body.len() >= 5 {
+        let len = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+        messages.push(body[5..5 + len].to_vec());
+        body.drain(..5 + len);
+    }
+    (status, messages)
+}
+
+#[tokio::test]
+async fn test_grpc_data_round_trip() {
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let state = AppState::new(db);
+
+    let put = proto::PutDataRequest {
+        key: "plugins/grpc".into(),
+        value: b"value".to_vec(),
+        ..Default::default()
+    };
+    let (status, messages) = call_on(&state, "PutData", Some("1"), &[put.encode_to_vec()]).await;
+    assert_eq!(status, "0");
+    let written = proto::DataWritten::decode(&messages[0]).unwrap();
+    assert_eq!(written.version, 1);
+
+    let get = proto::GetDataRequest {
+        key: "plugins/grpc".into(),
+    };
+    let (status, messages) = call_on(&state, "GetData", Some("1"), &[get.encode_to_vec()]).await;
+    assert_eq!(status, "0");
+    let entry = proto::DataEntry::decode(&messages[0]).unwrap();
+    assert_eq!(entry.value, b"value");
+    assert_eq!(entry.checksum, written.checksum);
+    assert!(entry.checksum_verified);
+
+    let (status, _) = call_on(&state, "GetData", Some("2"), &[get.encode_to_vec()]).await;
+    assert_eq!(status, "5");
+
+    let bad = proto::PutDataRequest {
+        key: "plugins/grpc".into(),
+        value: b"value".to_vec(),
+        checksum: "xxh3:0000000000000000".into(),
+        ..Default::default()
+    };
+    let (status, _) = call_on(&state, "PutData", Some("1"), &[bad.encode_to_vec()]).await;
+    assert_eq!(status, "3");
+}
+
+#[tokio::test]
+async fn test_grpc_requires_token() {
+    let get = proto::GetSettingsRequest {}.encode_to_vec();
+    let (status, messages) = call("GetSettings", None, &[get]).await;
+    assert_eq!(status, "16");
+    assert!(messages.is_empty());
+
+    let (status, _) = call("Unknown", Some("1"), &[]).await;
+    assert_eq!(status, "12");
+}
+
+#[tokio::test]
+async fn test_grpc_settings() {
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let state = AppState::new(db);
+
+    let put = proto::PutSettingsRequest {
+        value: b"settings".to_vec(),
+    };
+    let (status, messages) =
+        call_on(&state, "PutSettings", Some("1"), &[put.encode_to_vec()]).await;
+    assert_eq!(status, "0");
+    let written = proto::SettingsWritten::decode(&messages[0])
+        .unwrap()
+        .written;
+
+    let get = proto::GetSettingsRequest {}.encode_to_vec();
+    let (status, messages) = call_on(&state, "GetSettings", Some("1"), &[get]).await;
+    assert_eq!(status, "0");
+    let settings = proto::Settings::decode(&messages[0]).unwrap();
+    assert_eq!(settings.value, b"settings");
+    assert_eq!(settings.written, written);
+}
+
+#[tokio::test]
+async fn test_grpc_sync_stream() {
+    let first = proto::SyncRequest {
+        uploads: vec![proto::PutDataRequest {
+            key: "themes/a".into(),
+            value: b"a".to_vec(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let second = proto::SyncRequest {
+        client_manifest: vec![proto::ClientManifestEntry {
+            key: "themes/b".into(),
+            version: 1,
+            checksum: "stale".into(),
+        }],
+        deletions: vec![proto::Deletion {
+            key: "themes/a".into(),
+            version: 1,
+        }],
+        ..Default::default()
+    };
+
+    let (status, messages) = call(
+        "Sync",
+        Some("1"),
+        &[first.encode_to_vec(), second.encode_to_vec()],
+    )
+    .await;
+    assert_eq!(status, "0");
+    assert_eq!(messages.len(), 2);
+
+    let first = proto::SyncResponse::decode(&messages[0]).unwrap();
+    assert_eq!(first.uploaded.len(), 1);
+    assert_eq!(first.uploaded[0].key, "themes/a");
+    assert!(first.checksums_verified);
+
+    let second = proto::SyncResponse::decode(&messages[1]).unwrap();
+    let entry = &second.server_manifest[0];
+    assert_eq!(entry.key, "themes/a");
+    assert_eq!(entry.version, 2);
+    assert!(entry.deleted);
+    assert!(entry.deleted_at > 0);
+}
+
+#[test]
+fn test_proto_round_trip() {
+    let response = proto::SyncResponse {
+        server_manifest: vec![proto::ManifestEntry {
+            key: "k".into(),
+            version: 3,
+            size_bytes: -1,
+            ..Default::default()
+        }],
+        errors: vec![proto::SyncError::default()],
+        checksums_verified: true,
+        ..Default::default()
+    };
+    let decoded = proto::SyncResponse::decode(&response.encode_to_vec()).unwrap();
+    assert_eq!(decoded, response);
+
+    assert!(proto::DataEntry::decode(&[0x0a, 0x05, b'a']).is_err());
+}
@@ -0,0 +1,95 @@
+use axum::body::Body;
+use axum::http::{Method, StatusCode};
+use serde_json::{Value, json};
+
+use equicloud::Datastore;
+
+use super::{TestApp, TestResponse, base64, request};
+
+async fn put_with_key(
+    app: &TestApp,
+    uri: &str,
+    user: &str,
+    key: &str,
+    body: &[u8],
+) -> TestResponse {
+    let request = request(Method::PUT, uri, user)
+        .header("content-type", "application/octet-stream")
+        .header("idempotency-key", key)
+        .body(Body::from(body.to_vec()))
+        .unwrap();
+    app.send(request).await
+}
+
+async fn sync_with_key(app: &TestApp, user: &str, key: &str, body: &Value) -> TestResponse {
+    let request = request(Method::POST, "/v2/sync", user)
+        .header("content-type", "application/json")
+        .header("idempotency-key", key)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.send(request).await
+}
+
+#[tokio::test]
+async fn test_put_data_replays_response() {
+    let app = TestApp::new();
+
+    let first = put_with_key(&app, "/v2/data/plugins/a", "1", "retry-1", b"one").await;
+    assert_eq!(first.status, StatusCode::OK);
+    assert_eq!(first.json()["version"], 1);
+    assert_eq!(first.header("idempotent-replayed"), None);
+
+    let replayed = put_with_key(&app, "/v2/data/plugins/a", "1", "retry-1", b"one").await;
+    assert_eq!(replayed.status, StatusCode::OK);
+    assert_eq!(replayed.header("idempotent-replayed"), Some("true"));
+    assert_eq!(replayed.body, first.body);
+    assert_eq!(replayed.header("etag"), first.header("etag"));
+
+    // Only the first request was applied.
+    let written = put_with_key(&app, "/v2/data/plugins/a", "1", "retry-2", b"two").await;
+    assert_eq!(written.json()["version"], 2);
+
+    let reused = put_with_key(&app, "/v2/data/plugins/a", "1", "retry-1", b"three").await;
+    assert_eq!(reused.status, StatusCode::CONFLICT);
+    assert_eq!(&app.get("/v2/data/plugins/a", "1").await.body[..], b"two");
+
+    // Keys belong to one user.
+    let other = put_with_key(&app, "/v2/data/plugins/a", "2", "retry-1", b"one").await;
+    assert_eq!(other.status, StatusCode::OK);
+    assert_eq!(other.header("idempotent-replayed"), None);
+
+    let invalid = put_with_key(&app, "/v2/data/plugins/a", "1", "has space", b"one").await;
+    assert_eq!(invalid.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_settings_and_sync_replay_response() {
+    let app = TestApp::new();
+
+    let first = put_with_key(&app, "/v1/settings", "1", "settings-1", b"settings").await;
+    assert_eq!(first.status, StatusCode::OK);
+    let replayed = put_with_key(&app, "/v1/settings", "1", "settings-1", b"settings").await;
+    assert_eq!(replayed.header("idempotent-replayed"), Some("true"));
+    assert_eq!(replayed.json()["written"], first.json()["written"]);
+
+    let body = json!({
+        "client_manifest": [],
+        "uploads": [{ "key": "plugins/client", "value": base64(b"client") }]
+    });
+    let first = sync_with_key(&app, "1", "sync-1", &body).await;
+    assert_eq!(first.status, StatusCode::OK);
+    assert_eq!(first.json()["uploaded"][0]["version"], 1);
+
+    let replayed = sync_with_key(&app, "1", "sync-1", &body).await;
+    assert_eq!(replayed.status, StatusCode::OK);
+    assert_eq!(replayed.header("idempotent-replayed"), Some("true"));
+    assert_eq!(replayed.json()["uploaded"][0]["version"], 1);
+
+    let entry = app
+        .db
+        .get_data_key("1", "plugins/client")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.version, 1);
+}
@@ -0,0 +1,77 @@
+use axum::http::StatusCode;
+use serde_json::json;
+
+use equicloud::utils::{get_user_secret, hash_user_id};
+
+use super::{TestApp, token, token_with_secret};
+
+#[tokio::test]
+async fn test_linked_identity_shares_storage() {
+    let app = TestApp::new();
+    app.put_bytes("/v1/settings", "1", b"settings").await;
+
+    let linked = app
+        .post_json("/v1/links", "1", &json!({"token": token("2")}))
+        .await;
+    assert_eq!(linked.status, StatusCode::CREATED);
+    assert_eq!(linked.json()["user"], hash_user_id("2"));
+
+    let fetched = app.get("/v1/settings", "2").await;
+    assert_eq!(fetched.status, StatusCode::OK);
+    assert_eq!(&fetched.body[..], b"settings");
+
+    let saved = app.put_bytes("/v2/data/a", "2", b"one").await;
+    assert_eq!(saved.status, StatusCode::OK);
+    assert_eq!(app.get("/v2/data/a", "1").await.status, StatusCode::OK);
+
+    let listed = app.get("/v1/links", "2").await.json();
+    assert_eq!(listed["links"][0]["user"], hash_user_id("2"));
+
+    let unlinked = app.delete("/v1/links/2", "1").await;
+    assert_eq!(unlinked.status, StatusCode::NO_CONTENT);
+    assert_eq!(
+        app.get("/v1/settings", "2").await.status,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        app.delete("/v1/links/2", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_link_requires_proof_and_empty_identity() {
+    let app = TestApp::without_grace_period();
+
+    let permanent = token_with_secret(&get_user_secret("2"), "2");
+    let rejected = app
+        .post_json("/v1/links", "1", &json!({"token": permanent}))
+        .await;
+    assert_eq!(rejected.status, StatusCode::UNAUTHORIZED);
+
+    let itself = app
+        .post_json("/v1/links", "1", &json!({"token": token("1")}))
+        .await;
+    assert_eq!(itself.status, StatusCode::BAD_REQUEST);
+
+    app.put_bytes("/v1/settings", "3", b"settings").await;
+    let has_data = app
+        .post_json("/v1/links", "1", &json!({"token": token("3")}))
+        .await;
+    assert_eq!(has_data.status, StatusCode::CONFLICT);
+    assert_eq!(has_data.error_code(), "conflict");
+
+    let linked = app
+        .post_json("/v1/links", "1", &json!({"token": token("2")}))
+        .await;
+    assert_eq!(linked.status, StatusCode::CREATED);
+    let again = app
+        .post_json("/v1/links", "4", &json!({"token": token("2")}))
+        .await;
+    assert_eq!(again.status, StatusCode::CONFLICT);
+
+    let purged = app.delete("/v1", "2").await;
+    assert_eq!(purged.json()["linked_identities"], 1);
+    let listed = app.get("/v1/links", "1").await.json();
+    assert_eq!(listed["links"], json!([]));
+}
@@ -0,0 +1,99 @@
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{Request, StatusCode};
+use std::net::SocketAddr;
+
+use equicloud::ip_range::parse_ip_ranges;
+use equicloud::utils::{CONFIG, Config, ConfigHandle};
+use equicloud::{SqliteDatastore, Storage};
+
+use super::{TestApp, TestResponse};
+use crate::state::AppState;
+
+fn app_with(configure: impl FnOnce(&mut Config)) -> TestApp {
+    let mut config = (*CONFIG.load()).clone();
+    config.metrics_enabled = true;
+    configure(&mut config);
+    let mut state = AppState::new(Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap()));
+    state.config = ConfigHandle::new(config);
+    TestApp::with_state(state)
+}
+
+async fn get_metrics(app: &TestApp, peer: &str, token: Option<&str>) -> TestResponse {
+    get_metrics_forwarded(app, peer, token, None).await
+}
+
+/// [`get_metrics`] claiming to be forwarded for `forwarded_for`.
+async fn get_metrics_forwarded(
+    app: &TestApp,
+    peer: &str,
+    token: Option<&str>,
+    forwarded_for: Option<&str>,
+) -> TestResponse {
+    let mut request = Request::get("/metrics");
+    if let Some(forwarded_for) = forwarded_for {
+        request = request.header("x-forwarded-for", forwarded_for);
+    }
+    if let Some(token) = token {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    let mut request = request.body(Body::empty()).unwrap();
+    let peer: SocketAddr = peer.parse().unwrap();
+    request.extensions_mut().insert(ConnectInfo(peer));
+    app.send(request).await
+}
+
+#[tokio::test]
+async fn test_metrics_disabled_by_default() {
+    let app = TestApp::new();
+    let response = get_metrics(&app, "127.0.0.1:1234", None).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_metrics_token() {
+    let app = app_with(|config| config.metrics_token = Some("scrape".into()));
+
+    let missing = get_metrics(&app, "127.0.0.1:1234", None).await;
+    assert_eq!(missing.status, StatusCode::UNAUTHORIZED);
+    let wrong = get_metrics(&app, "127.0.0.1:1234", Some("guess")).await;
+    assert_eq!(wrong.status, StatusCode::UNAUTHORIZED);
+
+    let ok = get_metrics(&app, "127.0.0.1:1234", Some("scrape")).await;
+    assert_eq!(ok.status, StatusCode::OK);
+    assert!(ok.json()["uptime_seconds"].is_u64());
+}
+
+#[tokio::test]
+async fn test_metrics_allowlist() {
+    let app = app_with(|config| {
+        config.metrics_allowed_ips = parse_ip_ranges("10.0.0.0/8").unwrap();
+    });
+
+    let inside = get_metrics(&app, "10.1.2.3:9000", None).await;
+    assert_eq!(inside.status, StatusCode::OK);
+    let outside = get_metrics(&app, "192.168.0.1:9000", None).await;
+    assert_eq!(outside.status, StatusCode::FORBIDDEN);
+
+    let unknown_peer = app
+        .send(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(unknown_peer.status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_metrics_allowlist_behind_proxy() {
+    let app = app_with(|config| {
+        config.metrics_allowed_ips = parse_ip_ranges("10.0.0.0/8").unwrap();
+        config.trusted_proxies = parse_ip_ranges("192.168.0.1/32").unwrap();
+    });
+
+    let forwarded = get_metrics_forwarded(&app, "192.168.0.1:9000", None, Some("10.1.2.3")).await;
+    assert_eq!(forwarded.status, StatusCode::OK);
+    let outside = get_metrics_forwarded(&app, "192.168.0.1:9000", None, Some("203.0.113.7")).await;
+    assert_eq!(outside.status, StatusCode::FORBIDDEN);
+
+    // Only a trusted proxy may name the client.
+    let spoofed = get_metrics_forwarded(&app, "203.0.113.7:9000", None, Some("10.1.2.3")).await;
+    assert_eq!(spoofed.status, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,164 @@
+//! End-to-end tests that drive the full router in-process against the SQLite
+//! backend on an in-memory database.
+
+use axum::Router;
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use base64::prelude::*;
+use serde_json::Value;
+use tower::ServiceExt;
+
+use equicloud::oauth::issue_session_secret;
+use equicloud::tenant::DEFAULT_TENANT;
+use equicloud::utils::{CONFIG, ConfigHandle};
+use equicloud::{SqliteDatastore, Storage, Tenant, Tenants};
+
+use crate::routes;
+use crate::state::AppState;
+
+mod auth;
+mod bans;
+mod capabilities;
+mod cors;
+mod data;
+mod devices;
+mod grpc;
+mod idempotency;
+mod links;
+mod metrics;
+mod settings;
+mod shares;
+mod sync;
+mod tenant;
+mod uploads;
+mod usage;
+
+const SESSION_TTL_MS: i64 = 60 * 60 * 1000;
+
+pub struct TestApp {
+    router: Router,
+    pub db: Storage,
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).expect("response body is not JSON")
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// The `code` of an error response.
+    pub fn error_code(&self) -> String {
+        self.json()["code"].as_str().unwrap_or_default().to_string()
+    }
+}
+
+impl TestApp {
+    pub fn new() -> Self {
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        Self::with_state(AppState::new(db))
+    }
+
+    /// An app that deletes accounts at once rather than after
+    /// `ACCOUNT_DELETION_GRACE_DAYS`.
+    pub fn without_grace_period() -> Self {
+        let mut config = (*CONFIG.load()).clone();
+        config.account_deletion_grace_days = 0;
+        let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+        let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+        Self::with_state(AppState::with_tenants(tenants))
+    }
+
+    /// An app over `state`; `db` is the default tenant's storage.
+    pub fn with_state(state: AppState) -> Self {
+        let db = state.db.clone();
+        let router = routes::register_routes(&state).with_state(state);
+        Self { router, db }
+    }
+
+    pub async fn send(&self, request: Request<Body>) -> TestResponse {
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        TestResponse {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    pub async fn get(&self, uri: &str, user: &str) -> TestResponse {
+        self.send(request(Method::GET, uri, user).body(Body::empty()).unwrap())
+            .await
+    }
+
+    pub async fn delete(&self, uri: &str, user: &str) -> TestResponse {
+        self.send(
+            request(Method::DELETE, uri, user)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+    }
+
+    pub async fn put_bytes(&self, uri: &str, user: &str, body: &[u8]) -> TestResponse {
+        let request = request(Method::PUT, uri, user)
+            .header("content-type", "application/octet-stream")
+            .body(Body::from(body.to_vec()))
+            .unwrap();
+        self.send(request).await
+    }
+
+    pub async fn post_json(&self, uri: &str, user: &str, body: &Value) -> TestResponse {
+        let request = request(Method::POST, uri, user)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        self.send(request).await
+    }
+
+    /// Posts an archive from `/v2/export` to `/v2/import`.
+    pub async fn import(&self, user: &str, archive: &[u8]) -> TestResponse {
+        let request = request(Method::POST, "/v2/import", user)
+            .header("content-type", "application/x-tar")
+            .body(Body::from(archive.to_vec()))
+            .unwrap();
+        self.send(request).await
+    }
+}
+
+/// A request builder authenticated as `user`.
+pub fn request(method: Method, uri: &str, user: &str) -> axum::http::request::Builder {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("authorization", token(user))
+}
+
+/// A valid session token for `user`.
+pub fn token(user: &str) -> String {
+    let expires_at = chrono::Utc::now().timestamp_millis() + SESSION_TTL_MS;
+    token_with_secret(
+        &issue_session_secret(CONFIG.load().session_secret.as_bytes(), user, expires_at),
+        user,
+    )
+}
+
+pub fn token_with_secret(secret: &str, user: &str) -> String {
+    BASE64_STANDARD.encode(format!("{}:{}", secret, user))
+}
+
+pub fn base64(value: &[u8]) -> String {
+    BASE64_STANDARD.encode(value)
+}
@@ -0,0 +1,398 @@
+use axum::body::Body;
+use axum::http::{Method, StatusCode};
+use serde_json::json;
+use std::time::Duration;
+
+use equicloud::constants::SETTINGS_DIFF_BLOCK_SIZE;
+use equicloud::delta;
+use equicloud::tenant::DEFAULT_TENANT;
+use equicloud::utils::{CONFIG, ConfigHandle};
+use equicloud::{Datastore, SqliteDatastore, Storage, Tenant, Tenants};
+
+use super::{TestApp, request};
+use crate::state::AppState;
+
+#[tokio::test]
+async fn test_settings_round_trip() {
+    let app = TestApp::new();
+
+    let missing = app.get("/v1/settings", "1").await;
+    assert_eq!(missing.status, StatusCode::NOT_FOUND);
+
+    let saved = app.put_bytes("/v1/settings", "1", b"settings").await;
+    assert_eq!(saved.status, StatusCode::OK);
+    let written = saved.json()["written"].as_i64().unwrap();
+
+    let fetched = app.get("/v1/settings", "1").await;
+    assert_eq!(fetched.status, StatusCode::OK);
+    assert_eq!(&fetched.body[..], b"settings");
+    assert_eq!(
+        fetched.header("etag"),
+        Some(format!("\"{}\"", written).as_str())
+    );
+
+    let head = app
+        .send(
+            request(Method::HEAD, "/v1/settings", "1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(head.status, StatusCode::OK);
+    assert!(head.body.is_empty());
+    assert_eq!(head.header("etag"), fetched.header("etag"));
+    assert_eq!(head.header("content-length"), Some("8"));
+    assert_eq!(head.header("x-version"), Some(written.to_string().as_str()));
+    assert_eq!(
+        head.header("x-checksum"),
+        Some(equicloud::utils::compute_checksum(b"settings").as_str())
+    );
+    assert!(head.header("last-modified").unwrap().ends_with(" GMT"));
+
+    let deleted = app.delete("/v1/settings", "1").await;
+    assert_eq!(deleted.status, StatusCode::NO_CONTENT);
+    let gone = app.get("/v1/settings", "1").await;
+    assert_eq!(gone.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_settings_not_modified() {
+    let app = TestApp::new();
+    app.put_bytes("/v1/settings", "1", b"settings").await;
+    let etag = app.get("/v1/settings", "1").await.headers["etag"].clone();
+
+    let cached = app
+        .send(
+            request(Method::GET, "/v1/settings", "1")
+                .header("if-none-match", etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(cached.status, StatusCode::NOT_MODIFIED);
+    assert!(cached.body.is_empty());
+
+    for if_none_match in ["*", "\"0\", W/\"0\""] {
+        let response = app
+            .send(
+                request(Method::GET, "/v1/settings", "1")
+                    .header("if-none-match", if_none_match)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        let expected = if if_none_match == "*" {
+            StatusCode::NOT_MODIFIED
+        } else {
+            StatusCode::OK
+        };
+        assert_eq!(response.status, expected, "{}", if_none_match);
+    }
+}
+
+#[tokio::test]
+async fn test_settings_if_modified_since() {
+    let app = TestApp::new();
+    app.put_bytes("/v1/settings", "1", b"settings").await;
+    let fetched = app.get("/v1/settings", "1").await;
+    let last_modified = fetched.header("last-modified").unwrap().to_string();
+
+    let conditional = |method: Method, since: &str| {
+        request(method, "/v1/settings", "1")
+            .header("if-modified-since", since)
+            .body(Body::empty())
+            .unwrap()
+    };
+    for method in [Method::GET, Method::HEAD] {
+        let cached = app.send(conditional(method.clone(), &last_modified)).await;
+        assert_eq!(cached.status, StatusCode::NOT_MODIFIED, "{}", method);
+        assert_eq!(cached.header("last-modified"), Some(last_modified.as_str()));
+    }
+    let stale = app
+        .send(conditional(Method::GET, "Sun, 06 Nov 1994 08:49:37 GMT"))
+        .await;
+    assert_eq!(stale.status, StatusCode::OK);
+    assert_eq!(&stale.body[..], b"settings");
+
+    // If-None-Match wins over the date.
+    let mismatched = app
+        .send(
+            request(Method::GET, "/v1/settings", "1")
+                .header("if-none-match", "\"0\"")
+                .header("if-modified-since", &last_modified)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(mismatched.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_settings_if_match() {
+    let app = TestApp::new();
+    let put = |if_match: &'static str| {
+        request(Method::PUT, "/v1/settings", "1")
+            .header("content-type", "application/octet-stream")
+            .header("if-match", if_match)
+            .body(Body::from("settings"))
+            .unwrap()
+    };
+
+    let missing = app.send(put("*")).await;
+    assert_eq!(missing.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(missing.error_code(), "precondition_failed");
+
+    app.put_bytes("/v1/settings", "1", b"settings").await;
+    assert_eq!(app.send(put("*")).await.status, StatusCode::OK);
+    assert_eq!(
+        app.send(put("\"0\"")).await.status,
+        StatusCode::PRECONDITION_FAILED
+    );
+}
+
+#[tokio::test]
+async fn test_settings_require_octet_stream() {
+    let app = TestApp::new();
+    let response = app
+        .send(
+            request(Method::PUT, "/v1/settings", "1")
+                .header("content-type", "application/json")
+                .body(Body::from("{}"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(response.error_code(), "unsupported_media_type");
+
+    let empty = app.put_bytes("/v1/settings", "1", b"").await;
+    assert_eq!(empty.status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        app.get("/v1/settings", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_settings_json_validation() {
+    let mut config = (*CONFIG.load()).clone();
+    config.settings_json_validation = true;
+    config.settings_json_max_depth = 2;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    let app = TestApp::with_state(AppState::with_tenants(tenants));
+
+    let mut deflate = flate2::write::DeflateEncoder::new(Vec::new(), Default::default());
+    std::io::Write::write_all(&mut deflate, br#"{"plugins": {"a": true}}"#).unwrap();
+    let saved = app
+        .put_bytes("/v1/settings", "1", &deflate.finish().unwrap())
+        .await;
+    assert_eq!(saved.status, StatusCode::OK);
+
+    for bad in [&b"not json"[..], br#"{"a": {"b": {}}}"#] {
+        let refused = app.put_bytes("/v1/settings", "1", bad).await;
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(refused.error_code(), "invalid_settings");
+    }
+
+    // Without validation any bytes are stored.
+    let plain = TestApp::new();
+    let saved = plain.put_bytes("/v1/settings", "1", b"not json").await;
+    assert_eq!(saved.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_settings_export() {
+    let app = TestApp::new();
+    assert_eq!(
+        app.get("/v1/settings/export", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+
+    let mut deflate = flate2::write::DeflateEncoder::new(Vec::new(), Default::default());
+    std::io::Write::write_all(&mut deflate, br#"{"plugins":{"a":true}}"#).unwrap();
+    let stored = deflate.finish().unwrap();
+    app.put_bytes("/v1/settings", "1", &stored).await;
+
+    let export = app.get("/v1/settings/export?format=json", "1").await;
+    assert_eq!(export.status, StatusCode::OK);
+    assert_eq!(export.header("content-type"), Some("application/json"));
+    assert_eq!(
+        export.header("content-disposition"),
+        Some("attachment; filename=\"settings.json\"")
+    );
+    assert_eq!(
+        std::str::from_utf8(&export.body).unwrap(),
+        "{\n  \"plugins\": {\n    \"a\": true\n  }\n}"
+    );
+
+    let raw = app.get("/v1/settings/export?format=raw", "1").await;
+    assert_eq!(raw.header("content-type"), Some("application/octet-stream"));
+    assert_eq!(&raw.body[..], &stored[..]);
+
+    // Settings that are not JSON are sent as stored.
+    app.put_bytes("/v1/settings", "2", b"not json").await;
+    let opaque = app.get("/v1/settings/export", "2").await;
+    assert_eq!(
+        opaque.header("content-type"),
+        Some("application/octet-stream")
+    );
+    assert_eq!(&opaque.body[..], b"not json");
+}
+
+#[tokio::test]
+async fn test_delete_account_removes_everything() {
+    let app = TestApp::without_grace_period();
+    app.put_bytes("/v1/settings", "1", b"settings").await;
+    app.put_bytes("/v2/data/plugins/a", "1", b"value").await;
+
+    app.put_bytes("/v2/data/plugins/b", "1", b"value").await;
+    app.delete("/v2/data/plugins/b", "1").await;
+    app.db.set_quota_override("1", Some(1024)).await.unwrap();
+
+    let response = app.delete("/v1", "1").await;
+    assert_eq!(response.status, StatusCode::OK);
+    let purged = response.json();
+    assert_eq!(purged["settings"], true);
+    assert_eq!(purged["data_keys"], 1);
+    assert_eq!(purged["quota_override"], true);
+    assert_eq!(purged["refresh_token"], false);
+
+    assert_eq!(
+        app.get("/v1/settings", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        app.get("/v2/data/plugins/a", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+    let manifest = app.get("/v2/manifest", "1").await.json();
+    assert!(manifest["entries"].as_array().unwrap().is_empty());
+    assert_eq!(app.db.get_quota_override("1").await.unwrap(), None);
+
+    let again = app.delete("/v1", "1").await.json();
+    assert_eq!(again["settings"], false);
+    assert_eq!(again["data_keys"], 0);
+}
+
+#[tokio::test]
+async fn test_deleted_account_can_be_restored() {
+    let app = TestApp::new();
+    app.put_bytes("/v1/settings", "1", b"settings").await;
+
+    let not_pending = app
+        .send(
+            request(Method::POST, "/v1/restore", "1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(not_pending.status, StatusCode::NOT_FOUND);
+
+    let scheduled = app.delete("/v1", "1").await;
+    assert_eq!(scheduled.status, StatusCode::ACCEPTED);
+    let pending = scheduled.json();
+    assert_eq!(
+        pending["purge_at"].as_i64().unwrap() - pending["requested_at"].as_i64().unwrap(),
+        14 * 24 * 60 * 60 * 1000
+    );
+
+    let refused = app.get("/v1/settings", "1").await;
+    assert_eq!(refused.status, StatusCode::GONE);
+    assert_eq!(refused.error_code(), "gone");
+    assert_eq!(app.delete("/v1", "1").await.status, StatusCode::GONE);
+    assert_eq!(
+        app.get("/v1/settings", "2").await.status,
+        StatusCode::NOT_FOUND
+    );
+
+    let restored = app
+        .send(
+            request(Method::POST, "/v1/restore", "1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(restored.status, StatusCode::NO_CONTENT);
+    let fetched = app.get("/v1/settings", "1").await;
+    assert_eq!(fetched.status, StatusCode::OK);
+    assert_eq!(&fetched.body[..], b"settings");
+}
+
+#[tokio::test]
+async fn test_settings_diff() {
+    let app = TestApp::new();
+    let mut versions = Vec::new();
+    for n in 0..4 {
+        let settings =
+            json!({"plugins": {"a": {"enabled": true}, "n": n}, "padding": "x".repeat(10_000)});
+        let saved = app
+            .put_bytes("/v1/settings", "1", settings.to_string().as_bytes())
+            .await;
+        versions.push((saved.json()["written"].as_i64().unwrap(), settings));
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+    let (current, current_settings) = versions.last().unwrap();
+    let (from, from_settings) = &versions[1];
+
+    let binary = app
+        .get(&format!("/v1/settings/diff?from_etag=%22{}%22", from), "1")
+        .await;
+    assert_eq!(binary.status, StatusCode::OK);
+    assert_eq!(
+        binary.header("etag"),
+        Some(format!("\"{}\"", current).as_str())
+    );
+    assert_eq!(
+        binary.header("x-patch-block-size"),
+        Some(SETTINGS_DIFF_BLOCK_SIZE.to_string().as_str())
+    );
+    assert!(
+        binary.body.len() < 1024,
+        "patch is {} bytes",
+        binary.body.len()
+    );
+    let patched = delta::apply(
+        from_settings.to_string().as_bytes(),
+        SETTINGS_DIFF_BLOCK_SIZE,
+        &binary.body,
+    )
+    .unwrap();
+    assert_eq!(patched, current_settings.to_string().as_bytes());
+
+    let json_patch = app
+        .get(
+            &format!("/v1/settings/diff?from_etag={}&format=json-patch", from),
+            "1",
+        )
+        .await;
+    assert_eq!(json_patch.status, StatusCode::OK);
+    assert_eq!(
+        json_patch.header("content-type"),
+        Some("application/json-patch+json")
+    );
+    assert_eq!(
+        json_patch.json(),
+        json!([{"op": "replace", "path": "/plugins/n", "value": 3}])
+    );
+
+    let up_to_date = app
+        .get(&format!("/v1/settings/diff?from_etag={}", current), "1")
+        .await;
+    assert_eq!(up_to_date.status, StatusCode::NOT_MODIFIED);
+
+    let trimmed = app
+        .get(
+            &format!("/v1/settings/diff?from_etag={}", versions[0].0),
+            "1",
+        )
+        .await;
+    assert_eq!(trimmed.status, StatusCode::NOT_FOUND);
+    let bad = app.get("/v1/settings/diff?from_etag=W/abc", "1").await;
+    assert_eq!(bad.error_code(), "bad_request");
+
+    app.delete("/v1/settings", "1").await;
+    let deleted = app
+        .get(&format!("/v1/settings/diff?from_etag={}", from), "1")
+        .await;
+    assert_eq!(deleted.status, StatusCode::NOT_FOUND);
+}
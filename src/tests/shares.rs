@@ -0,0 +1,86 @@
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use serde_json::json;
+
+use equicloud::share::issue_share_token;
+use equicloud::utils::CONFIG;
+
+use super::{TestApp, TestResponse};
+
+async fn open(app: &TestApp, token: &str) -> TestResponse {
+    let request = Request::builder()
+        .uri(format!("/v2/shared/{}", token))
+        .body(Body::empty())
+        .unwrap();
+    app.send(request).await
+}
+
+#[tokio::test]
+async fn test_share_link_download_limit() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/exports/settings", "1", b"settings")
+        .await;
+
+    let created = app
+        .post_json(
+            "/v2/data/exports/settings/share",
+            "1",
+            &json!({"max_downloads": 2}),
+        )
+        .await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    let token = created.json()["token"].as_str().unwrap().to_string();
+    assert!(
+        created.json()["url"]
+            .as_str()
+            .unwrap()
+            .ends_with(&format!("/v2/shared/{}", token))
+    );
+
+    for _ in 0..2 {
+        let shared = open(&app, &token).await;
+        assert_eq!(shared.status, StatusCode::OK);
+        assert_eq!(&shared.body[..], b"settings");
+        assert_eq!(shared.header("cache-control"), Some("no-store"));
+    }
+    let used_up = open(&app, &token).await;
+    assert_eq!(used_up.status, StatusCode::GONE);
+    assert_eq!(used_up.error_code(), "gone");
+
+    let missing = app
+        .post_json("/v2/data/exports/other/share", "1", &json!({}))
+        .await;
+    assert_eq!(missing.status, StatusCode::NOT_FOUND);
+    let too_long = app
+        .post_json(
+            "/v2/data/exports/settings/share",
+            "1",
+            &json!({"ttl_secs": 365 * 24 * 60 * 60}),
+        )
+        .await;
+    assert_eq!(too_long.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_share_link_expiry_and_forgery() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/a", "1", b"one").await;
+    let created = app
+        .post_json("/v2/data/a/share", "1", &json!({"ttl_secs": 60}))
+        .await;
+    let token = created.json()["token"].as_str().unwrap().to_string();
+    assert_eq!(open(&app, &token).await.status, StatusCode::OK);
+
+    let id = token.split('.').next().unwrap();
+    let past = chrono::Utc::now().timestamp_millis() - 1000;
+    let expired = issue_share_token(CONFIG.load().session_secret.as_bytes(), id, past);
+    assert_eq!(open(&app, &expired).await.status, StatusCode::GONE);
+
+    let signature = token.rsplit('.').next().unwrap();
+    let extended = format!("{}.{}.{}", id, i64::MAX, signature);
+    assert_eq!(open(&app, &extended).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(open(&app, "garbage").await.status, StatusCode::NOT_FOUND);
+
+    app.delete("/v2/data/a", "1").await;
+    assert_eq!(open(&app, &token).await.status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,520 @@
+use axum::body::Body;
+use axum::http::{Method, StatusCode};
+use base64::prelude::*;
+use serde_cbor::Value as Cbor;
+use serde_json::{Value, json};
+
+use equicloud::delta::{self, Signature};
+use equicloud::{Datastore, compute_checksum};
+
+use super::{TestApp, base64, request};
+
+async fn sync(app: &TestApp, user: &str, request: Value) -> Value {
+    let response = app.post_json("/v2/sync", user, &request).await;
+    assert_eq!(response.status, StatusCode::OK);
+    response.json()
+}
+
+fn keys(entries: &Value) -> Vec<&str> {
+    entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["key"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_sync_uploads_and_downloads() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/server", "1", b"server")
+        .await;
+
+    let response = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [],
+            "uploads": [{ "key": "plugins/client", "value": base64(b"client") }]
+        }),
+    )
+    .await;
+
+    assert_eq!(keys(&response["downloads"]), ["plugins/server"]);
+    assert_eq!(response["downloads"][0]["value"], base64(b"server"));
+    assert_eq!(keys(&response["uploaded"]), ["plugins/client"]);
+    assert_eq!(response["uploaded"][0]["version"], 1);
+
+    let mut manifest = keys(&response["server_manifest"]);
+    manifest.sort();
+    assert_eq!(manifest, ["plugins/client", "plugins/server"]);
+
+    let stored = app.get("/v2/data/plugins/client", "1").await;
+    assert_eq!(&stored.body[..], b"client");
+}
+
+#[tokio::test]
+async fn test_sync_rejects_unknown_fields() {
+    let app = TestApp::new();
+    let response = sync(&app, "1", json!({ "client_manifest": [] })).await;
+    assert_eq!(response["api_version"], 2);
+
+    let misspelled = app
+        .post_json(
+            "/v2/sync",
+            "1",
+            &json!({ "client_manifest": [], "conflict-policy": "client-wins" }),
+        )
+        .await;
+    assert_eq!(misspelled.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let nested = app
+        .post_json(
+            "/v2/sync",
+            "1",
+            &json!({
+                "client_manifest": [],
+                "uploads": [{ "key": "a", "value": base64(b"a"), "ttl_secs": 60 }]
+            }),
+        )
+        .await;
+    assert_eq!(nested.status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn test_sync_skips_current_keys() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"value").await;
+
+    let response = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [{
+                "key": "plugins/a",
+                "version": 1,
+                "checksum": compute_checksum(b"value")
+            }]
+        }),
+    )
+    .await;
+    assert!(response["downloads"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_sync_rejects_checksum_mismatch() {
+    let app = TestApp::new();
+    let response = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [],
+            "uploads": [{
+                "key": "plugins/a",
+                "value": base64(b"value"),
+                "checksum": compute_checksum(b"other")
+            }]
+        }),
+    )
+    .await;
+
+    assert!(response["uploaded"].as_array().unwrap().is_empty());
+    assert_eq!(response["errors"][0]["key"], "plugins/a");
+    assert_eq!(response["errors"][0]["error"], "Checksum mismatch");
+    assert_eq!(
+        app.get("/v2/data/plugins/a", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_sync_quota_exceeded() {
+    let app = TestApp::new();
+    app.db.set_quota_override("1", Some(8)).await.unwrap();
+
+    let response = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [],
+            "uploads": [
+                { "key": "plugins/a", "value": base64(b"12345") },
+                { "key": "plugins/b", "value": base64(b"12345") }
+            ]
+        }),
+    )
+    .await;
+
+    assert_eq!(keys(&response["uploaded"]), ["plugins/a"]);
+    assert_eq!(response["errors"][0]["key"], "plugins/b");
+    assert_eq!(
+        response["errors"][0]["error"],
+        "Total storage limit exceeded"
+    );
+}
+
+#[tokio::test]
+async fn test_sync_dominated_upload_server_wins() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"server").await;
+
+    // The client never saw the server's version, so its upload is stale.
+    let response = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [],
+            "uploads": [{ "key": "plugins/a", "value": base64(b"client") }]
+        }),
+    )
+    .await;
+
+    assert!(response["uploaded"].as_array().unwrap().is_empty());
+    assert_eq!(response["conflicts"][0]["key"], "plugins/a");
+    assert_eq!(response["conflicts"][0]["server_version"], 1);
+    assert_eq!(
+        response["conflicts"][0]["server_checksum"],
+        compute_checksum(b"server")
+    );
+    let stored = app.get("/v2/data/plugins/a", "1").await;
+    assert_eq!(&stored.body[..], b"server");
+}
+
+#[tokio::test]
+async fn test_sync_dominated_upload_with_same_value_is_skipped() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"value").await;
+
+    let response = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [],
+            "uploads": [{ "key": "plugins/a", "value": base64(b"value") }]
+        }),
+    )
+    .await;
+
+    assert!(response["uploaded"].as_array().unwrap().is_empty());
+    assert!(response["conflicts"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_sync_dominated_upload_client_wins() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"server").await;
+
+    let response = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [],
+            "uploads": [{ "key": "plugins/a", "value": base64(b"client") }],
+            "conflict_policy": "client-wins"
+        }),
+    )
+    .await;
+
+    assert_eq!(keys(&response["uploaded"]), ["plugins/a"]);
+    assert_eq!(response["uploaded"][0]["version"], 2);
+    let stored = app.get("/v2/data/plugins/a", "1").await;
+    assert_eq!(&stored.body[..], b"client");
+}
+
+#[tokio::test]
+async fn test_sync_dominated_upload_record_conflict() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"server").await;
+
+    let response = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [],
+            "uploads": [{ "key": "plugins/a", "value": base64(b"client") }],
+            "conflict_policy": "record-conflict"
+        }),
+    )
+    .await;
+
+    assert_eq!(
+        response["conflicts"][0]["recorded_as"],
+        "conflicts/plugins/a"
+    );
+    assert_eq!(keys(&response["uploaded"]), ["conflicts/plugins/a"]);
+
+    let server = app.get("/v2/data/plugins/a", "1").await;
+    assert_eq!(&server.body[..], b"server");
+    let recorded = app.get("/v2/data/conflicts/plugins/a", "1").await;
+    assert_eq!(&recorded.body[..], b"client");
+}
+
+#[tokio::test]
+async fn test_sync_applies_deletions() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"one").await;
+    app.put_bytes("/v2/data/plugins/a", "1", b"two").await;
+
+    // A deletion based on an outdated version is ignored.
+    let stale = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [],
+            "deletions": [{ "key": "plugins/a", "version": 1 }]
+        }),
+    )
+    .await;
+    assert_eq!(stale["server_manifest"][0]["deleted"], false);
+
+    let current = sync(
+        &app,
+        "1",
+        json!({
+            "client_manifest": [{
+                "key": "plugins/a",
+                "version": 2,
+                "checksum": compute_checksum(b"two")
+            }],
+            "deletions": [{ "key": "plugins/a", "version": 2 }]
+        }),
+    )
+    .await;
+    assert_eq!(current["server_manifest"][0]["deleted"], true);
+    assert_eq!(keys(&current["deleted"]), ["plugins/a"]);
+    assert_eq!(
+        app.get("/v2/data/plugins/a", "1").await.status,
+        StatusCode::GONE
+    );
+}
+
+#[tokio::test]
+async fn test_sync_sends_patch_against_client_copy() {
+    let app = TestApp::new();
+    let old: Vec<u8> = (0..16 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+    let mut new = old.clone();
+    new[8000..8004].copy_from_slice(b"edit");
+    app.put_bytes("/v2/data/plugins/big", "1", &new).await;
+
+    let client_entry = |signature: Signature| {
+        json!({
+            "key": "plugins/big",
+            "version": 0,
+            "checksum": compute_checksum(&old),
+            "signature": signature
+        })
+    };
+
+    let response = sync(
+        &app,
+        "1",
+        json!({ "client_manifest": [client_entry(Signature::of(&old, 1024))] }),
+    )
+    .await;
+    let download = &response["downloads"][0];
+    assert!(download.get("value").is_none());
+    assert_eq!(download["base_checksum"], compute_checksum(&old));
+    assert_eq!(download["checksum"], compute_checksum(&new));
+    let patch = BASE64_STANDARD
+        .decode(download["patch"].as_str().unwrap())
+        .unwrap();
+    assert!(patch.len() < 2048);
+    assert_eq!(delta::apply(&old, 1024, &patch).unwrap(), new);
+
+    // An unusable block size falls back to the whole value.
+    let response = sync(
+        &app,
+        "1",
+        json!({ "client_manifest": [client_entry(Signature::of(&old, 1))] }),
+    )
+    .await;
+    let download = &response["downloads"][0];
+    assert!(download.get("patch").is_none());
+    assert_eq!(download["value"], base64(&new));
+}
+
+fn cbor_map(entries: Vec<(&str, Cbor)>) -> Cbor {
+    Cbor::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (Cbor::Text(key.into()), value))
+            .collect(),
+    )
+}
+
+fn cbor_field<'a>(value: &'a Cbor, key: &str) -> &'a Cbor {
+    match value {
+        Cbor::Map(map) => &map[&Cbor::Text(key.into())],
+        _ => panic!("not a CBOR map: {:?}", value),
+    }
+}
+
+#[tokio::test]
+async fn test_sync_in_cbor() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/server", "1", &[0, 255, 1])
+        .await;
+
+    let body = cbor_map(vec![
+        ("client_manifest", Cbor::Array(vec![])),
+        (
+            "uploads",
+            Cbor::Array(vec![cbor_map(vec![
+                ("key", Cbor::Text("plugins/client".into())),
+                ("value", Cbor::Bytes(vec![9, 0, 200])),
+            ])]),
+        ),
+    ]);
+    let response = app
+        .send(
+            request(Method::POST, "/v2/sync", "1")
+                .header("content-type", "application/cbor")
+                .body(Body::from(serde_cbor::to_vec(&body).unwrap()))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.header("content-type"), Some("application/cbor"));
+
+    let response: Cbor = serde_cbor::from_slice(&response.body).unwrap();
+    let Cbor::Array(downloads) = cbor_field(&response, "downloads") else {
+        panic!("downloads is not an array");
+    };
+    assert_eq!(
+        cbor_field(&downloads[0], "value"),
+        &Cbor::Bytes(vec![0, 255, 1])
+    );
+    let stored = app.get("/v2/data/plugins/client", "1").await;
+    assert_eq!(&stored.body[..], [9, 0, 200]);
+
+    let invalid = app
+        .send(
+            request(Method::POST, "/v2/sync", "1")
+                .header("content-type", "application/cbor")
+                .body(Body::from(&b"\xff"[..]))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(invalid.error_code(), "bad_request");
+}
+
+#[tokio::test]
+async fn test_paged_sync_downloads_in_pages() {
+    let app = TestApp::new();
+    for i in 0..5 {
+        app.put_bytes(&format!("/v2/data/plugins/{}", i), "1", &[b'a' + i; 40])
+            .await;
+    }
+
+    let response = app
+        .post_json("/v3/sync", "1", &json!({ "client_manifest": [] }))
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    let body = response.json();
+    assert_eq!(body["server_manifest"].as_array().unwrap().len(), 5);
+    assert_eq!(body["pending_downloads"], 5);
+    assert_eq!(body["pending_bytes"], 200);
+    assert!(body.get("downloads").is_none());
+
+    let mut cursor = body["cursor"].as_str().unwrap().to_string();
+    let mut downloaded = Vec::new();
+    let mut pages = 0;
+    loop {
+        let response = app
+            .post_json(
+                "/v3/sync/downloads",
+                "1",
+                &json!({ "cursor": cursor, "max_bytes": 100 }),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+        let page = response.json();
+        pages += 1;
+        for download in page["downloads"].as_array().unwrap() {
+            let key = download["key"].as_str().unwrap().to_string();
+            let value = BASE64_STANDARD
+                .decode(download["value"].as_str().unwrap())
+                .unwrap();
+            downloaded.push((key, value));
+        }
+        match page["cursor"].as_str() {
+            Some(next) => cursor = next.to_string(),
+            None => break,
+        }
+    }
+
+    assert_eq!(pages, 3);
+    let expected: Vec<_> = (0..5)
+        .map(|i| (format!("plugins/{}", i), vec![b'a' + i; 40]))
+        .collect();
+    assert_eq!(downloaded, expected);
+}
+
+#[tokio::test]
+async fn test_paged_sync_rejects_other_users_cursor() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/a", "1", b"a").await;
+
+    let body = app
+        .post_json("/v3/sync", "1", &json!({ "client_manifest": [] }))
+        .await
+        .json();
+    let cursor = body["cursor"].as_str().unwrap();
+
+    let response = app
+        .post_json("/v3/sync/downloads", "2", &json!({ "cursor": cursor }))
+        .await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+
+    let up_to_date = app
+        .post_json(
+            "/v3/sync",
+            "1",
+            &json!({ "client_manifest": body["server_manifest"] }),
+        )
+        .await
+        .json();
+    assert_eq!(up_to_date["pending_downloads"], 0);
+    assert!(up_to_date.get("cursor").is_none());
+}
+
+#[tokio::test]
+async fn test_sync_history() {
+    let app = TestApp::new();
+    app.put_bytes("/v2/data/plugins/server", "1", b"server")
+        .await;
+    let body = json!({
+        "client_manifest": [],
+        "uploads": [
+            { "key": "plugins/client", "value": base64(b"client") },
+            { "key": "plugins/bad", "value": base64(b"bad"), "checksum": "0000" }
+        ]
+    });
+    let synced = request(Method::POST, "/v2/sync", "1")
+        .header("content-type", "application/json")
+        .header("x-device-id", "desktop")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    assert_eq!(app.send(synced).await.status, StatusCode::OK);
+    sync(&app, "1", json!({ "client_manifest": [] })).await;
+
+    let history = app.get("/v2/sync/history", "1").await.json();
+    let sessions = history["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let first = &sessions[1];
+    assert_eq!(first["device_id"], "desktop");
+    assert_eq!(first["uploads"], 1);
+    assert_eq!(first["bytes_uploaded"], 6);
+    assert_eq!(first["downloads"], 1);
+    assert_eq!(first["bytes_downloaded"], 6);
+    assert_eq!(first["errors"], 1);
+    assert_eq!(sessions[0]["downloads"], 2);
+    assert!(sessions[0].get("device_id").is_none());
+
+    let limited = app.get("/v2/sync/history?limit=1", "1").await.json();
+    assert_eq!(limited["sessions"].as_array().unwrap().len(), 1);
+    let other = app.get("/v2/sync/history", "2").await.json();
+    assert!(other["sessions"].as_array().unwrap().is_empty());
+    let invalid = app.get("/v2/sync/history?limit=0", "1").await;
+    assert_eq!(invalid.status, StatusCode::BAD_REQUEST);
+}
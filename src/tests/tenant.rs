@@ -0,0 +1,120 @@
+use axum::body::Body;
+use axum::http::{Method, StatusCode};
+
+use equicloud::tenant::{DEFAULT_TENANT, TenantSpec};
+use equicloud::utils::{CONFIG, ConfigHandle};
+use equicloud::{SqliteDatastore, Storage, Tenant, Tenants};
+
+use super::{TestApp, TestResponse, request};
+use crate::state::AppState;
+
+/// The default tenant plus `acme`, served on `cloud.acme.org`, which only
+/// lets user 1 in and allows 4 bytes of settings.
+fn app() -> TestApp {
+    let spec = TenantSpec {
+        id: "acme".into(),
+        hosts: vec!["cloud.acme.org".into()],
+        allowed_user_ids: Some(vec!["1".into()]),
+        max_backup_size_bytes: Some(4),
+        ..Default::default()
+    };
+    let config = ConfigHandle::new(spec.apply(&CONFIG.load()).unwrap());
+
+    let default = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let acme =
+        Storage::Sqlite(SqliteDatastore::open_with_config(":memory:", config.clone()).unwrap());
+    let mut tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, CONFIG.clone(), default));
+    let hosts = spec.hosts.clone();
+    tenants.add(Tenant::from_spec(spec, config, acme), &hosts);
+    TestApp::with_state(AppState::with_tenants(tenants))
+}
+
+async fn send_to(
+    app: &TestApp,
+    method: Method,
+    uri: &str,
+    user: &str,
+    header: (&str, &str),
+    body: &[u8],
+) -> TestResponse {
+    let request = request(method, uri, user)
+        .header(header.0, header.1)
+        .header("content-type", "application/octet-stream")
+        .body(Body::from(body.to_vec()))
+        .unwrap();
+    app.send(request).await
+}
+
+#[tokio::test]
+async fn test_tenants_are_isolated() {
+    let app = app();
+    app.put_bytes("/v2/data/default-key", "1", b"v").await;
+    let response = send_to(
+        &app,
+        Method::PUT,
+        "/v2/data/acme-key",
+        "1",
+        ("x-tenant", "acme"),
+        b"v",
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::OK);
+
+    let keys = app.get("/v2/keys", "1").await.json();
+    assert_eq!(keys["keys"], serde_json::json!(["default-key"]));
+
+    for header in [("x-tenant", "acme"), ("host", "cloud.acme.org:443")] {
+        let response = send_to(&app, Method::GET, "/v2/keys", "1", header, b"").await;
+        assert_eq!(
+            response.json()["keys"],
+            serde_json::json!(["acme-key"]),
+            "{:?}",
+            header
+        );
+    }
+
+    let response = send_to(
+        &app,
+        Method::GET,
+        "/v2/keys",
+        "1",
+        ("x-tenant", "nope"),
+        b"",
+    )
+    .await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_tenant_overrides() {
+    let app = app();
+    let acme = ("x-tenant", "acme");
+
+    let response = send_to(&app, Method::GET, "/v2/keys", "2", acme, b"").await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+    assert_eq!(app.get("/v2/keys", "2").await.status, StatusCode::OK);
+
+    let response = send_to(&app, Method::PUT, "/v1/settings", "1", acme, b"12345").await;
+    assert_eq!(response.status, StatusCode::PAYLOAD_TOO_LARGE);
+    let response = app.put_bytes("/v1/settings", "1", b"12345").await;
+    assert_eq!(response.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_body_limit_follows_the_tenant() {
+    let app = app();
+    let put = |tenant: &str| {
+        request(Method::PUT, "/v1/settings", "1")
+            .header("x-tenant", tenant)
+            .header("content-type", "application/octet-stream")
+            .header("content-length", "5")
+            .body(Body::from(&b"12345"[..]))
+            .unwrap()
+    };
+
+    let response = app.send(put("acme")).await;
+    assert_eq!(response.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(response.json()["error"], "Request body exceeds 4 bytes");
+    let response = app.send(put(DEFAULT_TENANT)).await;
+    assert_eq!(response.status, StatusCode::OK);
+}
@@ -0,0 +1,150 @@
+use axum::body::Body;
+use axum::http::{Method, StatusCode};
+use serde_json::json;
+
+use equicloud::tenant::DEFAULT_TENANT;
+use equicloud::utils::{CONFIG, ConfigHandle};
+use equicloud::{Datastore, SqliteDatastore, Storage, Tenant, Tenants, compute_checksum};
+
+use super::{TestApp, request};
+use crate::state::AppState;
+
+/// An app whose uploads are split into 4-byte parts.
+fn app_with_small_parts() -> TestApp {
+    let mut config = (*CONFIG.load()).clone();
+    config.upload_part_size = 4;
+    let db = Storage::Sqlite(SqliteDatastore::open(":memory:").unwrap());
+    let tenants = Tenants::new(Tenant::new(DEFAULT_TENANT, ConfigHandle::new(config), db));
+    TestApp::with_state(AppState::with_tenants(tenants))
+}
+
+#[tokio::test]
+async fn test_multipart_upload() {
+    let app = app_with_small_parts();
+    let value = b"0123456789";
+
+    let created = app
+        .post_json(
+            "/v2/uploads",
+            "1",
+            &json!({"key": "plugins/big", "size": value.len(), "checksum": compute_checksum(value)}),
+        )
+        .await;
+    assert_eq!(created.status, StatusCode::CREATED);
+    let created = created.json();
+    assert_eq!(created["part_size"], 4);
+    assert_eq!(created["part_count"], 3);
+    let id = created["id"].as_str().unwrap();
+
+    // Parts may arrive in any order, and a resent part replaces the first copy.
+    let last = app
+        .put_bytes(&format!("/v2/uploads/{}/parts/3", id), "1", b"89")
+        .await;
+    assert_eq!(last.status, StatusCode::OK);
+    assert_eq!(last.json()["checksum"], compute_checksum(b"89"));
+    app.put_bytes(&format!("/v2/uploads/{}/parts/1", id), "1", b"xxxx")
+        .await;
+    app.put_bytes(&format!("/v2/uploads/{}/parts/1", id), "1", b"0123")
+        .await;
+
+    let incomplete = app
+        .post_json(&format!("/v2/uploads/{}/complete", id), "1", &json!({}))
+        .await;
+    assert_eq!(incomplete.status, StatusCode::BAD_REQUEST);
+    assert_eq!(incomplete.json()["error"], "Missing parts: 2");
+
+    let status = app.get(&format!("/v2/uploads/{}", id), "1").await.json();
+    let received: Vec<i64> = status["parts"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| p["part"].as_i64().unwrap())
+        .collect();
+    assert_eq!(received, [1, 3]);
+
+    let wrong_size = app
+        .put_bytes(&format!("/v2/uploads/{}/parts/2", id), "1", b"45")
+        .await;
+    assert_eq!(wrong_size.status, StatusCode::BAD_REQUEST);
+    let bad_checksum = app
+        .send(
+            request(Method::PUT, &format!("/v2/uploads/{}/parts/2", id), "1")
+                .header("content-type", "application/octet-stream")
+                .header("x-checksum", compute_checksum(b"nope"))
+                .body(Body::from("4567"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(bad_checksum.error_code(), "bad_request");
+    let out_of_range = app
+        .put_bytes(&format!("/v2/uploads/{}/parts/4", id), "1", b"x")
+        .await;
+    assert_eq!(out_of_range.status, StatusCode::BAD_REQUEST);
+
+    // Uploads belong to the user who created them.
+    let other_user = app
+        .put_bytes(&format!("/v2/uploads/{}/parts/2", id), "2", b"4567")
+        .await;
+    assert_eq!(other_user.status, StatusCode::NOT_FOUND);
+
+    app.put_bytes(&format!("/v2/uploads/{}/parts/2", id), "1", b"4567")
+        .await;
+    let completed = app
+        .post_json(&format!("/v2/uploads/{}/complete", id), "1", &json!({}))
+        .await;
+    assert_eq!(completed.status, StatusCode::OK);
+    assert_eq!(completed.json()["version"], 1);
+    assert_eq!(completed.json()["checksum"], compute_checksum(value));
+
+    let stored = app.get("/v2/data/plugins/big", "1").await;
+    assert_eq!(&stored.body[..], value);
+
+    let gone = app.get(&format!("/v2/uploads/{}", id), "1").await;
+    assert_eq!(gone.status, StatusCode::NOT_FOUND);
+    assert!(app.db.list_uploads("1").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_upload_checksum_mismatch_and_abort() {
+    let app = app_with_small_parts();
+
+    let created = app
+        .post_json(
+            "/v2/uploads",
+            "1",
+            &json!({"key": "plugins/a", "size": 4, "checksum": compute_checksum(b"abcd")}),
+        )
+        .await;
+    let id = created.json()["id"].as_str().unwrap().to_string();
+    app.put_bytes(&format!("/v2/uploads/{}/parts/1", id), "1", b"abce")
+        .await;
+
+    let mismatch = app
+        .post_json(&format!("/v2/uploads/{}/complete", id), "1", &json!({}))
+        .await;
+    assert_eq!(mismatch.status, StatusCode::BAD_REQUEST);
+    assert_eq!(mismatch.json()["error"], "Checksum mismatch");
+    assert_eq!(
+        app.get("/v2/data/plugins/a", "1").await.status,
+        StatusCode::NOT_FOUND
+    );
+
+    let aborted = app.delete(&format!("/v2/uploads/{}", id), "1").await;
+    assert_eq!(aborted.status, StatusCode::NO_CONTENT);
+    assert!(app.db.list_upload_parts(&id).await.unwrap().is_empty());
+    let again = app.delete(&format!("/v2/uploads/{}", id), "1").await;
+    assert_eq!(again.status, StatusCode::NOT_FOUND);
+
+    let empty = app
+        .post_json("/v2/uploads", "1", &json!({"key": "plugins/a", "size": 0}))
+        .await;
+    assert_eq!(empty.status, StatusCode::BAD_REQUEST);
+    let too_large = app
+        .post_json(
+            "/v2/uploads",
+            "1",
+            &json!({"key": "plugins/a", "size": CONFIG.load().max_key_size_bytes + 1}),
+        )
+        .await;
+    assert_eq!(too_large.status, StatusCode::PAYLOAD_TOO_LARGE);
+}
@@ -0,0 +1,44 @@
+use axum::http::StatusCode;
+use serde_json::json;
+
+use equicloud::utils::CONFIG;
+
+use super::TestApp;
+
+#[tokio::test]
+async fn test_usage_by_prefix() {
+    let app = TestApp::new();
+    app.put_bytes("/v1/settings", "1", b"settings").await;
+    app.put_bytes("/v2/data/themes/a", "1", b"12345").await;
+    app.put_bytes("/v2/data/themes/b/c", "1", b"123").await;
+    app.put_bytes("/v2/data/plugins/x", "1", b"1").await;
+    app.put_bytes("/v2/data/loose", "1", b"12").await;
+    app.put_bytes("/v2/data/plugins/deleted", "1", b"1234")
+        .await;
+    app.delete("/v2/data/plugins/deleted", "1").await;
+
+    let usage = app.get("/v2/usage", "1").await;
+    assert_eq!(usage.status, StatusCode::OK);
+    let usage = usage.json();
+    assert_eq!(
+        usage["prefixes"],
+        json!([
+            {"prefix": "", "keys": 1, "size_bytes": 2},
+            {"prefix": "plugins/", "keys": 1, "size_bytes": 1},
+            {"prefix": "themes/", "keys": 2, "size_bytes": 8}
+        ])
+    );
+    assert_eq!(usage["settings_size_bytes"], 8);
+    assert_eq!(usage["data_keys"], 4);
+    assert_eq!(usage["data_size_bytes"], 11);
+    assert_eq!(usage["used_bytes"], 19);
+
+    let quota = CONFIG.load().max_backup_size_bytes as f64;
+    assert_eq!(usage["quota_bytes"], quota as i64);
+    let percent = usage["percent_used"].as_f64().unwrap();
+    assert!((percent - 19.0 * 100.0 / quota).abs() < 1e-9);
+
+    let empty = app.get("/v2/usage", "2").await.json();
+    assert_eq!(empty["used_bytes"], 0);
+    assert_eq!(empty["prefixes"], json!([]));
+}